@@ -0,0 +1,192 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Measures the cost of connections opening and closing through a
+//! [`ChannelFilter`](tarpc::server::ChannelFilter).
+//!
+//! Channels connect in batches, each channel keyed by one of a fixed set of peers. The filter
+//! accepts them up to the per-key limit, and then every accepted channel disconnects, and the
+//! filter handles the disconnects, before the next batch connects. The filter is polled directly
+//! on the benchmark's thread, so the elapsed time is the CPU time the filter, and the channels it
+//! admits, cost.
+
+use futures::{
+    prelude::*,
+    task::{noop_waker_ref, Context, Poll},
+};
+use std::{
+    cell::Cell,
+    fmt,
+    pin::Pin,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tarpc::{
+    context::{AuthMethod, PeerIdentity},
+    server::{BaseChannel, Channel, Handler},
+    transport::channel,
+    ClientMessage, Response,
+};
+
+/// The connections to open and close.
+#[derive(Clone, Debug)]
+pub struct ChurnConfig {
+    /// The total number of channels that connect.
+    pub connections: usize,
+    /// The number of channels that connect before the accepted ones disconnect.
+    pub batch: usize,
+    /// The number of distinct peers the channels are keyed by.
+    pub keys: usize,
+    /// The most channels the filter accepts per key.
+    pub channels_per_key: u32,
+}
+
+impl Default for ChurnConfig {
+    fn default() -> Self {
+        ChurnConfig {
+            connections: 100_000,
+            batch: 100,
+            keys: 50,
+            channels_per_key: 1,
+        }
+    }
+}
+
+/// The results of a churn run.
+#[derive(Clone, Debug, Default)]
+pub struct ChurnReport {
+    /// The time taken to open and close every connection.
+    pub elapsed: Duration,
+    /// The number of channels the filter accepted.
+    pub accepted: usize,
+    /// The number of channels the filter rejected for exceeding the per-key limit.
+    pub rejected: usize,
+    /// The number of times the filter polled its listener.
+    pub listener_polls: usize,
+}
+
+impl ChurnReport {
+    /// Returns the number of connections handled per second.
+    pub fn connections_per_sec(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9;
+        if secs == 0.0 {
+            0.0
+        } else {
+            (self.accepted + self.rejected) as f64 / secs
+        }
+    }
+}
+
+impl fmt::Display for ChurnReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let connections = self.accepted + self.rejected;
+        write!(
+            f,
+            "{} connections ({} accepted, {} rejected) in {:?} ({:.0} connections/s), \
+             {:.2} listener polls per connection",
+            connections,
+            self.accepted,
+            self.rejected,
+            self.elapsed,
+            self.connections_per_sec(),
+            self.listener_polls as f64 / connections.max(1) as f64
+        )
+    }
+}
+
+/// Counts the polls of a stream.
+struct CountPolls<S> {
+    inner: S,
+    polls: Rc<Cell<usize>>,
+}
+
+impl<S: Stream + Unpin> Stream for CountPolls<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        self.polls.set(self.polls.get() + 1);
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+type BenchChannel = BaseChannel<(), (), channel::UnboundedChannel<ClientMessage<()>, Response<()>>>;
+
+/// Opens and closes channels through a filter, as configured, and reports how long it took.
+pub fn churn(config: &ChurnConfig) -> ChurnReport {
+    let peers: Vec<_> = (0..config.keys.max(1))
+        .map(|i| PeerIdentity::new(format!("peer{}", i), AuthMethod::Handshake))
+        .collect();
+    let (connect, listener) = futures::channel::mpsc::unbounded::<BenchChannel>();
+    let polls = Rc::new(Cell::new(0));
+    let filter = CountPolls {
+        inner: listener,
+        polls: polls.clone(),
+    }
+    .max_channels_per_key(config.channels_per_key, |channel| {
+        channel.peer_identity().unwrap().principal.clone()
+    });
+    futures::pin_mut!(filter);
+    let mut cx = Context::from_waker(noop_waker_ref());
+
+    let mut report = ChurnReport::default();
+    let mut accepted = vec![];
+    let start = Instant::now();
+    let mut connected = 0;
+    while connected < config.connections {
+        let batch = config.batch.max(1).min(config.connections - connected);
+        for i in connected..connected + batch {
+            let (_, server) = channel::unbounded();
+            let channel = BaseChannel::with_defaults(server)
+                .with_peer_identity(peers[i % peers.len()].clone());
+            connect.unbounded_send(channel).unwrap();
+        }
+        connected += batch;
+        // The filter yields at most the batch it was just sent.
+        for _ in 0..batch {
+            match filter.as_mut().poll_next(&mut cx) {
+                Poll::Ready(Some(channel)) => accepted.push(channel),
+                _ => break,
+            }
+        }
+        report.accepted += accepted.len();
+        // Everything accepted disconnects, and the filter handles the closures, before the next
+        // batch connects.
+        accepted.clear();
+        if filter.as_mut().poll_next(&mut cx).is_ready() {
+            unreachable!("no channels are connecting, and the listener is open");
+        }
+    }
+    // Let the filter see the listener close. Every channel it accepted has closed already.
+    drop(connect);
+    if let Poll::Ready(Some(_)) = filter.as_mut().poll_next(&mut cx) {
+        unreachable!("no channels are connecting, and the listener is closed");
+    }
+    report.elapsed = start.elapsed();
+    report.rejected = config.connections - report.accepted;
+    report.listener_polls = polls.get();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn churn_accepts_up_to_the_limit_per_batch() {
+        let report = churn(&ChurnConfig {
+            connections: 100,
+            batch: 10,
+            keys: 5,
+            channels_per_key: 1,
+        });
+        assert_eq!(report.accepted, 50);
+        assert_eq!(report.rejected, 50);
+        // Each connection is polled off the listener once, and each batch a few more times to find
+        // it empty, however many of its channels closed.
+        assert!(report.listener_polls >= 100);
+        assert!(report.listener_polls < 2 * 100);
+    }
+}
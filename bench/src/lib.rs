@@ -0,0 +1,309 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A load generator that measures tarpc's throughput and latency.
+//!
+//! [`run`] starts an echo server, connects a client to it, and then keeps
+//! [`concurrency`](Config::concurrency) requests in flight for the configured duration, timing
+//! each one. The resulting [`Report`] summarizes how many requests completed and how long they
+//! took. With [`spawn_workers`](Config::spawn_workers) set, each request is sent from its own
+//! task, so that comparing the two runs shows what callers on many threads cost the client.
+//!
+//! [`churn`](churn::churn) instead measures the cost of connections opening and closing at a high
+//! rate.
+
+pub mod churn;
+
+use futures::{
+    future::{self, Ready},
+    prelude::*,
+};
+use std::{
+    fmt, io,
+    time::{Duration, Instant},
+};
+use tarpc::{
+    client, context,
+    server::{self, Channel},
+    transport::channel,
+};
+
+/// The service driven by the benchmark. Echoing the payload back means that every request
+/// carries the configured payload size in both directions.
+#[tarpc::service]
+pub trait Bench {
+    async fn echo(payload: Vec<u8>) -> Vec<u8>;
+}
+
+/// Responds to every request with its payload.
+#[derive(Clone, Debug)]
+pub struct EchoServer;
+
+impl Bench for EchoServer {
+    type EchoFut = Ready<Vec<u8>>;
+
+    fn echo(self, _: context::Context, payload: Vec<u8>) -> Self::EchoFut {
+        future::ready(payload)
+    }
+}
+
+/// How the client reaches the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// An in-process channel, which measures the cost of tarpc itself, without serialization or
+    /// I/O.
+    InMemory,
+    /// A bincode transport over a TCP connection to localhost.
+    Tcp,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+/// The load to generate.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The number of requests kept in flight at once.
+    pub concurrency: usize,
+    /// The size of each request's payload, in bytes. Responses are the same size.
+    pub payload_size: usize,
+    /// How long to generate load for. Requests in flight when it elapses are waited on and
+    /// counted.
+    pub duration: Duration,
+    /// How the client reaches the server.
+    pub transport: Transport,
+    /// Whether each of the requests in flight is sent from its own spawned task, rather than all
+    /// from the task running the benchmark.
+    pub spawn_workers: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            concurrency: 32,
+            payload_size: 64,
+            duration: Duration::from_secs(10),
+            transport: Transport::InMemory,
+            spawn_workers: false,
+        }
+    }
+}
+
+/// Starts an echo server, generates the configured load against it, and reports the results.
+pub async fn run(config: &Config) -> io::Result<Report> {
+    // Each server is stopped when its handle is dropped, once the run is over.
+    match config.transport {
+        Transport::InMemory => {
+            let (tx, rx) = channel::unbounded();
+            let (server, _server) = server::BaseChannel::with_defaults(rx)
+                .respond_with(EchoServer.serve())
+                .execute()
+                .remote_handle();
+            tokio::spawn(server);
+            let client = BenchClient::new(client::Config::default(), tx).spawn()?;
+            drive(client, config).await
+        }
+        Transport::Tcp => {
+            let incoming = tarpc_bincode_transport::listen(&"127.0.0.1:0".parse().unwrap())?;
+            let addr = incoming.local_addr();
+            let (server, _server) = incoming
+                // Ignore accept errors.
+                .filter_map(|r| future::ready(r.ok()))
+                .map(server::BaseChannel::with_defaults)
+                .for_each_concurrent(None, |channel| {
+                    channel.respond_with(EchoServer.serve()).execute()
+                })
+                .remote_handle();
+            tokio::spawn(server);
+            let transport = tarpc_bincode_transport::connect(&addr).await?;
+            let client = BenchClient::new(client::Config::default(), transport).spawn()?;
+            drive(client, config).await
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Unsupported transport",
+        )),
+    }
+}
+
+/// Keeps `config.concurrency` requests in flight until the duration elapses.
+async fn drive(client: BenchClient, config: &Config) -> io::Result<Report> {
+    let payload = vec![0; config.payload_size];
+    let start = Instant::now();
+    let end = start + config.duration;
+    let workers = (0..config.concurrency.max(1)).map(|_| {
+        let worker = work(client.clone(), payload.clone(), end);
+        if config.spawn_workers {
+            let (worker, report) = worker.remote_handle();
+            tokio::spawn(worker);
+            report.left_future()
+        } else {
+            worker.right_future()
+        }
+    });
+    let workers = future::join_all(workers).await;
+
+    let mut report = Report::default();
+    for worker in workers {
+        report.latencies.extend(worker.latencies);
+        report.errors += worker.errors;
+    }
+    report.latencies.sort();
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Sends requests one after another until `end`, timing each one.
+async fn work(mut client: BenchClient, payload: Vec<u8>, end: Instant) -> Report {
+    let mut worker = Report::default();
+    while Instant::now() < end {
+        let sent = Instant::now();
+        match client.echo(context::current(), payload.clone()).await {
+            Ok(_) => worker.latencies.push(sent.elapsed()),
+            Err(_) => worker.errors += 1,
+        }
+    }
+    worker
+}
+
+/// The results of a run.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    /// The time from the first request being sent to the last response being received.
+    pub elapsed: Duration,
+    /// The number of requests that failed.
+    pub errors: u64,
+    /// The latency of each successful request, in ascending order.
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    /// Returns the number of requests that succeeded.
+    pub fn requests(&self) -> usize {
+        self.latencies.len()
+    }
+
+    /// Returns the number of requests that succeeded per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + f64::from(self.elapsed.subsec_nanos()) / 1e9;
+        if secs == 0.0 {
+            0.0
+        } else {
+            self.requests() as f64 / secs
+        }
+    }
+
+    /// Returns the latency that `percentile` percent of successful requests completed within,
+    /// or zero if none succeeded.
+    ///
+    /// # Panics
+    ///
+    /// If `percentile` isn't between 0 and 100.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        assert!(
+            (0.0..=100.0).contains(&percentile),
+            "percentile {} is not between 0 and 100",
+            percentile
+        );
+        if self.latencies.is_empty() {
+            return Duration::from_secs(0);
+        }
+        // The nearest-rank method: the smallest latency at least `percentile` percent of
+        // latencies are no greater than.
+        let rank = (percentile / 100.0 * self.latencies.len() as f64).ceil() as usize;
+        self.latencies[rank.max(1) - 1]
+    }
+
+    /// Returns the latency of the slowest successful request, or zero if none succeeded.
+    pub fn max(&self) -> Duration {
+        self.latencies.last().cloned().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests in {:?} ({:.0} requests/s), {} errors",
+            self.requests(),
+            self.elapsed,
+            self.throughput(),
+            self.errors
+        )?;
+        write!(
+            f,
+            "latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.max()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_use_nearest_rank() {
+        let report = Report {
+            elapsed: Duration::from_secs(2),
+            errors: 0,
+            latencies: (1..=10).map(Duration::from_millis).collect(),
+        };
+        assert_eq!(report.requests(), 10);
+        assert_eq!(report.throughput(), 5.0);
+        assert_eq!(report.percentile(0.0), Duration::from_millis(1));
+        assert_eq!(report.percentile(50.0), Duration::from_millis(5));
+        assert_eq!(report.percentile(91.0), Duration::from_millis(10));
+        assert_eq!(report.percentile(100.0), Duration::from_millis(10));
+        assert_eq!(report.max(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn empty_report_is_zero() {
+        let report = Report::default();
+        assert_eq!(report.throughput(), 0.0);
+        assert_eq!(report.percentile(99.0), Duration::from_secs(0));
+        assert_eq!(report.max(), Duration::from_secs(0));
+    }
+
+    #[tokio::test]
+    async fn runs_over_each_transport() -> io::Result<()> {
+        for &transport in &[Transport::InMemory, Transport::Tcp] {
+            let report = run(&Config {
+                concurrency: 4,
+                payload_size: 16,
+                duration: Duration::from_millis(50),
+                transport,
+                spawn_workers: false,
+            })
+            .await?;
+            assert!(
+                report.requests() > 0,
+                "{:?}: no requests completed",
+                transport
+            );
+            assert_eq!(report.errors, 0);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn runs_with_spawned_workers() -> io::Result<()> {
+        let report = run(&Config {
+            concurrency: 4,
+            payload_size: 16,
+            duration: Duration::from_millis(50),
+            transport: Transport::InMemory,
+            spawn_workers: true,
+        })
+        .await?;
+        assert!(report.requests() > 0);
+        assert_eq!(report.errors, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use bench::{
+    churn::{self, ChurnConfig},
+    Config, Transport,
+};
+use clap::{App, Arg};
+use std::{io, time::Duration};
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    env_logger::init();
+
+    let flags = App::new("tarpc bench")
+        .version("0.1")
+        .about("Measures the throughput and latency of an echo service.")
+        .arg(
+            Arg::with_name("concurrency")
+                .short("c")
+                .long("concurrency")
+                .value_name("NUMBER")
+                .help("Sets the number of requests kept in flight")
+                .default_value("32")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("payload_size")
+                .short("s")
+                .long("payload-size")
+                .value_name("BYTES")
+                .help("Sets the size of each request and response payload")
+                .default_value("64")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("duration")
+                .short("d")
+                .long("duration")
+                .value_name("SECONDS")
+                .help("Sets how long to generate load for")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("transport")
+                .short("t")
+                .long("transport")
+                .value_name("TRANSPORT")
+                .help("Sets how the client reaches the server")
+                .possible_values(&["memory", "tcp"])
+                .default_value("memory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("spawn_workers")
+                .long("spawn-workers")
+                .help("Sends each request in flight from its own task"),
+        )
+        .arg(
+            Arg::with_name("churn")
+                .long("churn")
+                .value_name("CONNECTIONS")
+                .help(
+                    "Instead of sending requests, opens and closes this many connections through \
+                     a per-peer channel filter",
+                )
+                .takes_value(true),
+        )
+        .get_matches();
+
+    if let Some(connections) = flags.value_of("churn") {
+        let connections = connections
+            .parse()
+            .unwrap_or_else(|e| panic!(r#"--churn value "{}" invalid: {}"#, connections, e));
+        let report = churn::churn(&ChurnConfig {
+            connections,
+            ..ChurnConfig::default()
+        });
+        println!("{}", report);
+        return Ok(());
+    }
+
+    let concurrency = flags.value_of("concurrency").unwrap();
+    let concurrency = concurrency
+        .parse()
+        .unwrap_or_else(|e| panic!(r#"--concurrency value "{}" invalid: {}"#, concurrency, e));
+    let payload_size = flags.value_of("payload_size").unwrap();
+    let payload_size = payload_size
+        .parse()
+        .unwrap_or_else(|e| panic!(r#"--payload-size value "{}" invalid: {}"#, payload_size, e));
+    let duration = flags.value_of("duration").unwrap();
+    let duration = duration
+        .parse()
+        .map(Duration::from_secs)
+        .unwrap_or_else(|e| panic!(r#"--duration value "{}" invalid: {}"#, duration, e));
+    let transport = match flags.value_of("transport").unwrap() {
+        "tcp" => Transport::Tcp,
+        _ => Transport::InMemory,
+    };
+
+    let report = bench::run(&Config {
+        concurrency,
+        payload_size,
+        duration,
+        transport,
+        spawn_workers: flags.is_present("spawn_workers"),
+    })
+    .await?;
+    println!("{}", report);
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A small pool of threads that serialize and deserialize large payloads, so that a
+//! multi-megabyte frame doesn't stall the reactor thread driving every other connection.
+
+use futures_legacy::sync::oneshot;
+use lazy_static::lazy_static;
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        mpsc::{self, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+/// The number of threads in the pool.
+const THREADS: usize = 4;
+/// The number of jobs queued for the pool's threads before more are run by their callers.
+const QUEUE: usize = 64;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+lazy_static! {
+    static ref POOL: Pool = Pool::new(THREADS, QUEUE);
+}
+
+/// Threads running jobs from a queue of their own.
+struct Pool {
+    /// Bounded, so a backlog of jobs can't grow without limit. Can be sent on from any thread
+    /// without locking it.
+    jobs: mpsc::SyncSender<Job>,
+}
+
+impl Pool {
+    fn new(threads: usize, queue: usize) -> Self {
+        let (jobs, rx) = mpsc::sync_channel::<Job>(queue);
+        // Only the pool's threads contend on the receiver, for the next job.
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("tarpc-bincode-blocking-{}", i))
+                .spawn(move || loop {
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => run_job(job),
+                        Err(_) => return,
+                    }
+                })
+                .expect("failed to spawn blocking pool thread");
+        }
+        Pool { jobs }
+    }
+
+    /// Queues `job` for the pool's threads, or runs it on the calling thread if the queue is
+    /// full.
+    fn spawn(&self, job: Job) {
+        match self.jobs.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => run_job(job),
+        }
+    }
+}
+
+/// Runs `job`. A panicking job cancels its receiver; the thread lives on to run others.
+fn run_job(job: Job) {
+    drop(panic::catch_unwind(AssertUnwindSafe(job)));
+}
+
+/// Runs `f` on the pool, returning a receiver of its result. The receiver is canceled if `f`
+/// panics.
+pub(crate) fn run<F, T>(f: F) -> oneshot::Receiver<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    POOL.spawn(Box::new(move || {
+        let _ = tx.send(f());
+    }));
+    rx
+}
+
+#[test]
+fn runs_jobs_off_thread() {
+    use futures_legacy::Future;
+
+    let caller = thread::current().id();
+    let worker = run(|| thread::current().id()).wait().unwrap();
+    assert_ne!(worker, caller);
+}
+
+#[test]
+fn runs_jobs_on_caller_when_queue_is_full() {
+    use futures_legacy::Future;
+
+    // A pool with no threads never takes a job off its queue, so the first job fills it.
+    let pool = Pool::new(0, 1);
+    pool.spawn(Box::new(|| {}));
+    let (tx, rx) = oneshot::channel();
+    pool.spawn(Box::new(move || drop(tx.send(thread::current().id()))));
+    assert_eq!(rx.wait().unwrap(), thread::current().id());
+}
@@ -0,0 +1,829 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Frames bincode messages with a big-endian `u32` length prefix.
+//!
+//! Messages sent back to back are serialized into one buffer, which is written with a single
+//! vectored write rather than one per message. The buffer is reused once written. Each message's
+//! length prefix is written from a buffer of its own, along with its payload, so the payload
+//! needn't be copied behind its prefix.
+//!
+//! The read buffer is sized to the frames the connection has been receiving: it grows as soon as
+//! a larger frame arrives, and shrinks slowly as frames get smaller. When a read finds nothing
+//! to read and the buffer is empty, a buffer larger than recent frames need is released, so that
+//! a connection that's idle after a burst of large messages doesn't hold onto the memory they
+//! took.
+//!
+//! If an offload threshold is set, frames larger than it are serialized and deserialized on a
+//! [blocking pool](crate::blocking) rather than on the task driving the transport. Items are
+//! serialized on the transport's task up to the threshold, and handed to the pool only once their
+//! encoding outgrows it, so that small items are never sized or serialized twice.
+//!
+//! A frame that can't be deserialized either ends the stream with a
+//! [`FrameError`](crate::FrameError), or is skipped, per the [`MalformedFrames`] policy. The
+//! answer to a skipped frame, if it has one, is written along with the next messages sent.
+
+use crate::{
+    blocking,
+    frame::{self, FrameError, MalformedFrames},
+    payload::{OverLimit, Spliced},
+};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_legacy::{
+    sync::oneshot, try_ready, Async, AsyncSink, Future, Poll, Sink, StartSend, Stream,
+};
+use iovec::IoVec;
+use rpc::Response;
+use serde::{Deserialize, Serialize};
+use std::{cmp, collections::VecDeque, fmt, io, marker::PhantomData, mem};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+pub(crate) const HEADER_LEN: usize = 4;
+/// The number of serialized batches buffered before `start_send` applies backpressure.
+const MAX_QUEUED_BATCHES: usize = 32;
+/// The size a batch grows to before it's queued to be written, even if more messages follow.
+const MAX_BATCH_LEN: usize = 64 * 1024;
+/// The most read buffer reserved at once, so that a bogus length prefix can't force a huge
+/// allocation before any of the frame arrives.
+const MAX_READ_RESERVE: usize = 64 * 1024;
+/// The least read buffer reserved at once, so that small frames are read a few at a time.
+const MIN_READ_RESERVE: usize = 256;
+
+/// A stream and sink of length-prefixed bincode frames over an I/O object.
+pub(crate) struct Framed<S, Item, SinkItem> {
+    io: S,
+    read_buf: BytesMut,
+    read_reserve: ReadReserve,
+    /// Batches waiting to be written.
+    batches: VecDeque<Batch>,
+    /// The messages sent since the last batch was queued.
+    batch: Batch,
+    /// The buffer of a written batch, kept to serialize a later one.
+    spare: Vec<u8>,
+    /// How to offload large payloads, if at all.
+    offload: Option<Offload<Item, SinkItem>>,
+    /// A large frame being deserialized on the blocking pool.
+    decoding: Option<oneshot::Receiver<bincode::Result<Result<Item, FrameError>>>>,
+    /// The frame being deserialized on the blocking pool, to answer if it's skipped.
+    decoding_frame: Bytes,
+    malformed_frames: MalformedFrames,
+    /// Returns the answer to the payload of a skipped frame, if any.
+    answer_malformed: fn(&[u8]) -> Option<Response<()>>,
+    /// The number of malformed frames skipped.
+    skipped_frames: u64,
+    /// Set once a malformed frame closes the stream.
+    closed: bool,
+    /// A large item being serialized on the blocking pool. Queued behind the messages already
+    /// serialized, and ahead of any sent after it.
+    encoding: Option<oneshot::Receiver<bincode::Result<Batch>>>,
+    ghost: PhantomData<SinkItem>,
+}
+
+/// (De)serializes payloads larger than a threshold on the blocking pool. The functions are set by
+/// [`Framed::set_offload_threshold`], which is the only place that requires items to be `Send`.
+struct Offload<Item, SinkItem> {
+    threshold: usize,
+    decode: fn(Bytes) -> oneshot::Receiver<bincode::Result<Result<Item, FrameError>>>,
+    encode: fn(SinkItem) -> oneshot::Receiver<bincode::Result<Batch>>,
+}
+
+/// Deserializes `frame` on the blocking pool.
+fn decode_on_pool<Item>(
+    frame: Bytes,
+) -> oneshot::Receiver<bincode::Result<Result<Item, FrameError>>>
+where
+    Item: for<'de> Deserialize<'de> + Send + 'static,
+{
+    blocking::run(move || Ok(frame::decode_payload(&frame[HEADER_LEN..])))
+}
+
+/// Serializes `item` into a batch of its own on the blocking pool.
+fn encode_on_pool<SinkItem>(item: SinkItem) -> oneshot::Receiver<bincode::Result<Batch>>
+where
+    SinkItem: Serialize + Send + 'static,
+{
+    blocking::run(move || {
+        let mut batch = Batch::new(vec![]);
+        batch.push(&item)?;
+        Ok(batch)
+    })
+}
+
+/// The read buffer reserved at once, sized to the frames recently read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ReadReserve(usize);
+
+impl Default for ReadReserve {
+    fn default() -> Self {
+        ReadReserve(MIN_READ_RESERVE)
+    }
+}
+
+impl ReadReserve {
+    /// Adjusts to a frame of `len` bytes: immediately if it's larger than the reserve, or an
+    /// eighth of the way toward it if smaller, so that one small frame among large ones doesn't
+    /// shrink the buffer only for the next large frame to grow it again.
+    fn observe(&mut self, len: usize) {
+        let len = cmp::min(cmp::max(len, MIN_READ_RESERVE), MAX_READ_RESERVE);
+        if len >= self.0 {
+            self.0 = len;
+        } else {
+            self.0 -= (self.0 - len + 7) / 8;
+        }
+    }
+
+    fn get(self) -> usize {
+        self.0
+    }
+}
+
+/// Serialized messages waiting to be written, each a length prefix followed by its payload. The
+/// prefixes are kept apart from the payloads, and offered to the writer between them.
+struct Batch {
+    spliced: Spliced,
+    /// How much of the serialized bytes was written.
+    written: usize,
+    remaining: usize,
+}
+
+impl Batch {
+    fn new(buf: Vec<u8>) -> Self {
+        Batch {
+            spliced: Spliced::new(buf),
+            written: 0,
+            remaining: 0,
+        }
+    }
+
+    /// Appends a frame holding `item`. Must not be called once writing has begun.
+    fn push<T: Serialize>(&mut self, item: &T) -> bincode::Result<()> {
+        self.push_within(item, usize::max_value())
+    }
+
+    /// Like [`push`](Batch::push), but fails with [`OverLimit`] if `item` is larger than `limit`
+    /// bytes, leaving the batch as it was.
+    fn push_within<T: Serialize>(&mut self, item: &T, limit: usize) -> bincode::Result<()> {
+        let start = self.spliced.buf.len();
+        let splices = self.spliced.splices.len();
+        let len_before = self.spliced.len();
+        if let Err(e) = self.spliced.serialize_within(item, limit) {
+            self.spliced.truncate(start, splices);
+            return Err(e);
+        }
+        let len = self.spliced.len() - len_before;
+        if len > u32::max_value() as usize {
+            self.spliced.truncate(start, splices);
+            return Err(Box::new(bincode::ErrorKind::SizeLimit));
+        }
+        // Small enough to be stored inline rather than allocated.
+        let header = Bytes::from(&(len as u32).to_be_bytes()[..]);
+        self.spliced.insert(splices, start, header);
+        self.remaining = self.spliced.len();
+        Ok(())
+    }
+
+    /// Returns the batch's buffer, emptied.
+    fn into_buf(self) -> Vec<u8> {
+        let mut buf = self.spliced.buf;
+        buf.clear();
+        buf
+    }
+}
+
+impl Buf for Batch {
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match self.spliced.splices.front() {
+            Some(&(offset, ref payload)) if offset == self.written => payload,
+            Some(&(offset, _)) => &self.spliced.buf[self.written..offset],
+            None => &self.spliced.buf[self.written..],
+        }
+    }
+
+    fn bytes_vec<'a>(&'a self, dst: &mut [&'a IoVec]) -> usize {
+        let mut n = 0;
+        let mut written = self.written;
+        let mut push = |chunk: &'a [u8]| {
+            if n < dst.len() && !chunk.is_empty() {
+                dst[n] = chunk.into();
+                n += 1;
+            }
+        };
+        for &(offset, ref payload) in &self.spliced.splices {
+            push(&self.spliced.buf[written..offset]);
+            push(payload);
+            written = offset;
+        }
+        push(&self.spliced.buf[written..]);
+        n
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        assert!(cnt <= self.remaining, "advanced past the end of the batch");
+        self.remaining -= cnt;
+        while cnt > 0 {
+            match self.spliced.splices.front_mut() {
+                Some(&mut (offset, ref mut payload)) if offset == self.written => {
+                    if cnt < payload.len() {
+                        payload.advance(cnt);
+                        return;
+                    }
+                    cnt -= payload.len();
+                    self.spliced.splices.pop_front();
+                }
+                next => {
+                    let end = next.map_or(self.spliced.buf.len(), |&mut (offset, _)| offset);
+                    let n = cmp::min(cnt, end - self.written);
+                    self.written += n;
+                    cnt -= n;
+                }
+            }
+        }
+    }
+}
+
+impl<S, Item, SinkItem> Framed<S, Item, SinkItem> {
+    pub(crate) fn new(io: S) -> Self {
+        Framed {
+            io,
+            read_buf: BytesMut::new(),
+            read_reserve: ReadReserve::default(),
+            batches: VecDeque::new(),
+            batch: Batch::new(vec![]),
+            spare: vec![],
+            offload: None,
+            decoding: None,
+            decoding_frame: Bytes::new(),
+            malformed_frames: MalformedFrames::default(),
+            answer_malformed: |_| None,
+            skipped_frames: 0,
+            closed: false,
+            encoding: None,
+            ghost: PhantomData,
+        }
+    }
+
+    pub(crate) fn set_malformed_frames(&mut self, policy: MalformedFrames) {
+        self.malformed_frames = policy;
+    }
+
+    pub(crate) fn set_answer_malformed(&mut self, answer: fn(&[u8]) -> Option<Response<()>>) {
+        self.answer_malformed = answer;
+    }
+
+    pub(crate) fn skipped_frames(&self) -> u64 {
+        self.skipped_frames
+    }
+
+    pub(crate) fn set_offload_threshold(&mut self, bytes: usize)
+    where
+        Item: for<'de> Deserialize<'de> + Send + 'static,
+        SinkItem: Serialize + Send + 'static,
+    {
+        self.offload = Some(Offload {
+            threshold: bytes,
+            decode: decode_on_pool::<Item>,
+            encode: encode_on_pool::<SinkItem>,
+        });
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.io
+    }
+
+    /// Skips a frame with `payload` that couldn't be decoded, answering it if it has an answer,
+    /// or returns the error that closes the stream, per the malformed frame policy.
+    fn reject(&mut self, payload: &[u8], error: FrameError) -> bincode::Result<()> {
+        match self.malformed_frames {
+            MalformedFrames::Skip => {
+                self.skipped_frames += 1;
+                if let Some(answer) = (self.answer_malformed)(payload) {
+                    self.batch.push(&answer)?;
+                }
+                Ok(())
+            }
+            _ => {
+                self.closed = true;
+                Err(Box::new(bincode::ErrorKind::Io(error.into())))
+            }
+        }
+    }
+
+    /// Queues the messages sent since the last batch to be written.
+    fn queue_batch(&mut self) {
+        if self.batch.remaining > 0 {
+            let batch = Batch::new(mem::replace(&mut self.spare, vec![]));
+            self.batches.push_back(mem::replace(&mut self.batch, batch));
+        }
+    }
+}
+
+impl<S: fmt::Debug, Item, SinkItem> fmt::Debug for Framed<S, Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Framed")
+            .field("io", &self.io)
+            .field("buffered_bytes", &self.read_buf.len())
+            .field("read_capacity", &self.read_buf.capacity())
+            .field("queued_batches", &self.batches.len())
+            .field("batched_bytes", &self.batch.remaining)
+            .field(
+                "offload_threshold",
+                &self.offload.as_ref().map(|offload| offload.threshold),
+            )
+            .field("malformed_frames", &self.malformed_frames)
+            .field("skipped_frames", &self.skipped_frames)
+            .finish()
+    }
+}
+
+/// Resolves the result of a job run on the blocking pool.
+fn poll_offloaded<T>(
+    job: &mut Option<oneshot::Receiver<bincode::Result<T>>>,
+) -> Poll<Option<T>, bincode::Error> {
+    let result = match job {
+        None => return Ok(Async::Ready(None)),
+        Some(rx) => match rx.poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result,
+            Err(oneshot::Canceled) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "(de)serialization panicked on the blocking pool",
+            )
+            .into()),
+        },
+    };
+    *job = None;
+    result.map(|item| Async::Ready(Some(item)))
+}
+
+impl<S, Item, SinkItem> Stream for Framed<S, Item, SinkItem>
+where
+    S: AsyncRead,
+    Item: for<'de> Deserialize<'de>,
+{
+    type Item = Item;
+    type Error = bincode::Error;
+
+    fn poll(&mut self) -> Poll<Option<Item>, bincode::Error> {
+        if self.closed {
+            return Ok(Async::Ready(None));
+        }
+        if let Some(decoded) = try_ready!(poll_offloaded(&mut self.decoding)) {
+            let frame = mem::replace(&mut self.decoding_frame, Bytes::new());
+            match decoded {
+                Ok(item) => return Ok(Async::Ready(Some(item))),
+                Err(e) => self.reject(&frame[HEADER_LEN..], e)?,
+            }
+        }
+        loop {
+            let needed = if self.read_buf.len() >= HEADER_LEN {
+                let mut header = [0; HEADER_LEN];
+                header.copy_from_slice(&self.read_buf[..HEADER_LEN]);
+                let frame_len = HEADER_LEN + u32::from_be_bytes(header) as usize;
+                if self.read_buf.len() >= frame_len {
+                    self.read_reserve.observe(frame_len);
+                    let frame = self.read_buf.split_to(frame_len);
+                    if let Some(ref offload) = self.offload {
+                        if frame_len - HEADER_LEN > offload.threshold {
+                            let frame = frame.freeze();
+                            self.decoding = Some((offload.decode)(frame.clone()));
+                            self.decoding_frame = frame;
+                            return self.poll();
+                        }
+                    }
+                    match frame::decode_payload(&frame[HEADER_LEN..]) {
+                        Ok(item) => return Ok(Async::Ready(Some(item))),
+                        Err(e) => {
+                            self.reject(&frame[HEADER_LEN..], e)?;
+                            continue;
+                        }
+                    }
+                }
+                frame_len - self.read_buf.len()
+            } else {
+                HEADER_LEN - self.read_buf.len()
+            };
+            if self.read_buf.remaining_mut() < needed {
+                let reserve = cmp::max(needed, self.read_reserve.get());
+                self.read_buf.reserve(cmp::min(reserve, MAX_READ_RESERVE));
+            }
+            match self.io.read_buf(&mut self.read_buf)? {
+                Async::Ready(0) if self.read_buf.is_empty() => return Ok(Async::Ready(None)),
+                Async::Ready(0) => {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                Async::Ready(_) => {}
+                Async::NotReady => {
+                    // Nothing is buffered, so a buffer sized for larger frames than the
+                    // connection now receives can be given back until more arrives.
+                    if self.read_buf.is_empty()
+                        && self.read_buf.capacity() > self.read_reserve.get() * 2
+                    {
+                        self.read_buf = BytesMut::new();
+                    }
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+    }
+}
+
+impl<S, Item, SinkItem> Sink for Framed<S, Item, SinkItem>
+where
+    S: AsyncWrite,
+    SinkItem: Serialize,
+{
+    type SinkItem = SinkItem;
+    type SinkError = bincode::Error;
+
+    fn start_send(&mut self, item: SinkItem) -> StartSend<SinkItem, bincode::Error> {
+        if self.batches.len() >= MAX_QUEUED_BATCHES || self.encoding.is_some() {
+            self.poll_complete()?;
+            if self.batches.len() >= MAX_QUEUED_BATCHES || self.encoding.is_some() {
+                return Ok(AsyncSink::NotReady(item));
+            }
+        }
+        match self.offload {
+            Some(Offload {
+                threshold, encode, ..
+            }) => match self.batch.push_within(&item, threshold) {
+                Ok(()) => {}
+                Err(ref e) if OverLimit::is(e) => {
+                    // Messages sent before this one are written before it.
+                    self.queue_batch();
+                    self.encoding = Some(encode(item));
+                    return Ok(AsyncSink::Ready);
+                }
+                Err(e) => return Err(e),
+            },
+            None => self.batch.push(&item)?,
+        }
+        if self.batch.remaining >= MAX_BATCH_LEN {
+            self.queue_batch();
+        }
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), bincode::Error> {
+        self.queue_batch();
+        loop {
+            while let Some(batch) = self.batches.front_mut() {
+                if try_ready!(self.io.write_buf(batch)) == 0 {
+                    return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+                }
+                if !batch.has_remaining() {
+                    let buf = self.batches.pop_front().unwrap().into_buf();
+                    // Don't hold onto the buffer of an unusually large batch.
+                    if buf.capacity() <= MAX_BATCH_LEN * 2 {
+                        if self.batch.remaining == 0 {
+                            self.batch = Batch::new(buf);
+                        } else {
+                            self.spare = buf;
+                        }
+                    }
+                }
+            }
+            match poll_offloaded(&mut self.encoding)? {
+                Async::Ready(Some(batch)) => self.batches.push_back(batch),
+                Async::Ready(None) => break,
+                Async::NotReady => {
+                    // Flush what was written ahead of the item still being serialized.
+                    self.io.poll_flush()?;
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+        try_ready!(self.io.poll_flush());
+        Ok(Async::Ready(()))
+    }
+
+    fn close(&mut self) -> Poll<(), bincode::Error> {
+        try_ready!(self.poll_complete());
+        Ok(self.io.shutdown()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use std::io::Cursor;
+
+    /// Writes at most `limit` bytes of the slices offered per call, as a vectored write would,
+    /// counting the calls and those that were offered more than one slice to write.
+    struct Trickle {
+        written: Vec<u8>,
+        limit: usize,
+        writes: usize,
+        vectored_writes: usize,
+    }
+
+    impl Trickle {
+        fn new(limit: usize) -> Self {
+            Trickle {
+                written: vec![],
+                limit,
+                writes: 0,
+                vectored_writes: 0,
+            }
+        }
+    }
+
+    impl io::Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = cmp::min(buf.len(), self.limit);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+
+        fn write_buf<B: Buf>(&mut self, buf: &mut B) -> Poll<usize, io::Error> {
+            let empty: &IoVec = (&[][..]).into();
+            let mut slices = [empty; 64];
+            let n = buf.bytes_vec(&mut slices);
+            if n > 1 {
+                self.vectored_writes += 1;
+            }
+            self.writes += 1;
+            let mut written = 0;
+            for slice in &slices[..n] {
+                let len = cmp::min(slice.len(), self.limit - written);
+                self.written.extend_from_slice(&slice[..len]);
+                written += len;
+                if len < slice.len() {
+                    break;
+                }
+            }
+            buf.advance(written);
+            Ok(Async::Ready(written))
+        }
+    }
+
+    #[test]
+    fn batches_messages_into_one_write() {
+        let mut framed = Framed::<_, String, String>::new(Trickle::new(usize::max_value()));
+        assert!(framed.start_send("hello".into()).unwrap().is_ready());
+        assert!(framed.start_send("hi".into()).unwrap().is_ready());
+        assert!(framed.poll_complete().unwrap().is_ready());
+
+        assert_eq!(
+            &framed.io.written[..],
+            &b"\x00\x00\x00\x0d\x05\x00\x00\x00\x00\x00\x00\x00hello\
+               \x00\x00\x00\x0a\x02\x00\x00\x00\x00\x00\x00\x00hi"[..]
+        );
+        // The prefixes and payloads are offered together.
+        assert_eq!(framed.io.writes, 1);
+        assert_eq!(framed.io.vectored_writes, 1);
+
+        // The written batch's buffer is reused for the next.
+        assert!(framed.batch.spliced.buf.capacity() > 0);
+    }
+
+    #[test]
+    fn writes_prefix_and_payload_together() {
+        let mut framed = Framed::<_, String, String>::new(Trickle::new(6));
+        assert!(framed.start_send("hello".into()).unwrap().is_ready());
+        assert!(framed.poll_complete().unwrap().is_ready());
+
+        assert_eq!(
+            framed.io.written,
+            b"\x00\x00\x00\x0d\x05\x00\x00\x00\x00\x00\x00\x00hello"
+        );
+        // The first write is offered both the prefix and the payload; later writes resume
+        // partway through the payload.
+        assert_eq!(framed.io.vectored_writes, 1);
+    }
+
+    #[test]
+    fn resumes_partial_writes() {
+        let mut framed = Framed::<_, String, String>::new(Trickle::new(6));
+        assert!(framed.start_send("hello".into()).unwrap().is_ready());
+        assert!(framed.poll_complete().unwrap().is_ready());
+
+        assert_eq!(
+            framed.io.written,
+            b"\x00\x00\x00\x0d\x05\x00\x00\x00\x00\x00\x00\x00hello"
+        );
+        assert_eq!(framed.io.writes, 3);
+    }
+
+    #[test]
+    fn reads_frames_and_rejects_truncated_ones() {
+        let bytes: &[u8] = b"\x00\x00\x00\x0d\x05\x00\x00\x00\x00\x00\x00\x00hello\x00\x00";
+        let mut framed = Framed::<_, String, String>::new(Cursor::new(bytes));
+        assert_eq!(framed.poll().unwrap(), Async::Ready(Some("hello".into())));
+        assert!(framed.poll().is_err());
+    }
+
+    /// A frame claiming a string longer than itself, between two valid frames.
+    const MALFORMED: &[u8] = b"\x00\x00\x00\x0d\x05\x00\x00\x00\x00\x00\x00\x00hello\
+                               \x00\x00\x00\x0d\xff\x00\x00\x00\x00\x00\x00\x00hello\
+                               \x00\x00\x00\x0a\x02\x00\x00\x00\x00\x00\x00\x00hi";
+
+    #[test]
+    fn malformed_frame_closes_stream() {
+        let mut framed = Framed::<_, String, String>::new(Cursor::new(MALFORMED));
+        assert_eq!(framed.poll().unwrap(), Async::Ready(Some("hello".into())));
+        match *framed.poll().unwrap_err() {
+            bincode::ErrorKind::Io(ref e) => {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                assert_matches!(
+                    FrameError::from_io(e),
+                    Some(FrameError::Malformed { len: 13, .. })
+                );
+            }
+            ref e => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(framed.poll().unwrap(), Async::Ready(None));
+    }
+
+    #[test]
+    fn skipped_requests_are_answered() {
+        use crate::Envelope;
+        use rpc::{context, ClientMessage, Request};
+
+        let request = |id, message: u8, one_way| {
+            let mut request = Request::new(context::current(), id, message);
+            request.one_way = one_way;
+            let mut batch = Batch::new(vec![]);
+            batch.push(&ClientMessage::Request(request)).unwrap();
+            batch.collect::<Vec<u8>>()
+        };
+        // Neither request's message is a valid `bool`, but only the two-way one is answered.
+        let mut bytes = request(7, 2, false);
+        bytes.extend(request(8, 2, true));
+        bytes.extend(request(9, 1, false));
+        let mut framed = Framed::<_, ClientMessage<bool>, Response<()>>::new(Cursor::new(bytes));
+        framed.set_malformed_frames(MalformedFrames::Skip);
+        framed.set_answer_malformed(ClientMessage::<bool>::answer_malformed);
+        assert_matches!(
+            framed.poll().unwrap(),
+            Async::Ready(Some(ClientMessage::Request(Request {
+                id: 9,
+                message: true,
+                ..
+            })))
+        );
+        assert_eq!(framed.skipped_frames(), 2);
+
+        let written = mem::replace(&mut framed.batch, Batch::new(vec![])).collect::<Vec<u8>>();
+        let (answer, len) = frame::decode_frame::<Response<()>>(&written).unwrap();
+        assert_eq!(len, written.len());
+        assert_eq!(answer.request_id, 7);
+        assert_matches!(answer.message, Err(ref e) if e.kind == io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_frames_can_be_skipped() {
+        for &threshold in &[None, Some(0)] {
+            let mut framed = Framed::<_, String, String>::new(Cursor::new(MALFORMED));
+            framed.set_malformed_frames(MalformedFrames::Skip);
+            if let Some(threshold) = threshold {
+                framed.set_offload_threshold(threshold);
+            }
+            let frames = Stream::wait(&mut framed)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(frames, vec!["hello".to_string(), "hi".into()]);
+            assert_eq!(framed.skipped_frames(), 1);
+        }
+    }
+
+    /// Reads one chunk per call, and then would block.
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl io::Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = match self.0.front_mut() {
+                Some(chunk) => chunk,
+                None => return Err(io::ErrorKind::WouldBlock.into()),
+            };
+            let n = cmp::min(buf.len(), chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            chunk.drain(..n);
+            if chunk.is_empty() {
+                self.0.pop_front();
+            }
+            Ok(n)
+        }
+    }
+
+    impl AsyncRead for Chunks {}
+
+    #[test]
+    fn read_reserve_grows_fast_and_shrinks_slowly() {
+        let mut reserve = ReadReserve::default();
+        reserve.observe(10);
+        assert_eq!(reserve.get(), MIN_READ_RESERVE);
+        reserve.observe(10_000);
+        assert_eq!(reserve.get(), 10_000);
+        reserve.observe(10);
+        assert_eq!(reserve.get(), 10_000 - (10_000 - MIN_READ_RESERVE + 7) / 8);
+        for _ in 0..100 {
+            reserve.observe(10);
+        }
+        assert_eq!(reserve.get(), MIN_READ_RESERVE);
+        reserve.observe(usize::max_value());
+        assert_eq!(reserve.get(), MAX_READ_RESERVE);
+    }
+
+    #[test]
+    fn releases_read_buffer_once_frames_shrink() {
+        let frame = |item: &String| {
+            let mut batch = Batch::new(vec![]);
+            batch.push(item).unwrap();
+            batch.collect::<Vec<u8>>()
+        };
+        let large = "x".repeat(16 * 1024);
+        let mut chunks = VecDeque::new();
+        chunks.push_back(frame(&large));
+        for _ in 0..100 {
+            chunks.push_back(frame(&"hi".to_string()));
+        }
+        let mut framed = Framed::<_, String, String>::new(Chunks(chunks));
+
+        assert_eq!(framed.poll().unwrap(), Async::Ready(Some(large)));
+        let mut smalls = 0;
+        while let Async::Ready(item) = framed.poll().unwrap() {
+            assert_eq!(item.as_ref().map(String::as_str), Some("hi"));
+            smalls += 1;
+        }
+        assert_eq!(smalls, 100);
+        // The buffer sized for the large frame was released once the reads caught up.
+        assert_eq!(framed.read_buf.capacity(), BytesMut::new().capacity());
+    }
+
+    #[test]
+    fn writes_preserialized_payloads() {
+        use crate::Preserialized;
+
+        let mut framed =
+            Framed::<_, (), (u32, Preserialized)>::new(Trickle::new(usize::max_value()));
+        let payload = Preserialized::new(&"hello".to_string()).unwrap();
+        assert!(framed.start_send((7, payload.clone())).unwrap().is_ready());
+        assert!(framed.poll_complete().unwrap().is_ready());
+
+        let mut framed = Framed::<_, (u32, Preserialized), ()>::new(Cursor::new(framed.io.written));
+        let (id, received) = match framed.poll().unwrap() {
+            Async::Ready(Some(item)) => item,
+            poll => panic!("expected an item, got {:?}", poll),
+        };
+        assert_eq!((id, &received), (7, &payload));
+        assert_eq!(received.decode::<String>().unwrap(), "hello");
+    }
+
+    #[test]
+    fn stops_serializing_items_over_the_limit() {
+        let mut batch = Batch::new(vec![]);
+        batch.push_within(&"hi".to_string(), 10).unwrap();
+        let len = batch.remaining;
+        assert!(OverLimit::is(
+            &batch.push_within(&"x".repeat(100), 10).unwrap_err()
+        ));
+        // The batch is left as it was.
+        assert_eq!(batch.remaining, len);
+        assert_eq!(batch.spliced.len(), len);
+        assert_eq!(batch.spliced.splices.len(), 1);
+    }
+
+    #[test]
+    fn offloads_large_payloads() {
+        let mut framed = Framed::<_, String, String>::new(Cursor::new(vec![]));
+        framed.set_offload_threshold(4);
+        let framed = framed
+            .send_all(futures_legacy::stream::iter_ok::<_, bincode::Error>(vec![
+                "hello".to_string(),
+                "hi".to_string(),
+            ]))
+            .wait()
+            .unwrap()
+            .0;
+        // The large item is written in order, ahead of the small one sent after it.
+        let bytes = framed.io.into_inner();
+        assert_eq!(
+            &bytes[..],
+            &b"\x00\x00\x00\x0d\x05\x00\x00\x00\x00\x00\x00\x00hello\
+               \x00\x00\x00\x0a\x02\x00\x00\x00\x00\x00\x00\x00hi"[..]
+        );
+
+        let mut framed = Framed::<_, String, String>::new(Cursor::new(bytes));
+        framed.set_offload_threshold(4);
+        let items: Vec<String> = Stream::wait(framed).collect::<Result<_, _>>().unwrap();
+        assert_eq!(items, ["hello", "hi"]);
+    }
+}
@@ -0,0 +1,913 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Decodes captured traffic into readable messages, for debugging interop and corruption issues.
+//!
+//! Decoding doesn't need the service's request and response types. The envelope of a message,
+//! e.g. a request's context and ID, or a response's request ID and error, is the same for every
+//! service, and the RPC a message is for is identified by the index of its variant in the
+//! service's request or response enum, which a [schema](rpc::schema::Service) resolves to the
+//! RPC's name. The arguments and outputs of RPCs are shown as the bytes they were encoded to.
+//!
+//! [`decode_stream`] decodes the bytes one end of a connection sent, e.g. as read off a socket,
+//! and [`decode_pcap`] decodes every connection to a server in a packet capture.
+//!
+//! ```ignore
+//! let capture = fs::read("tarpc.pcap")?;
+//! for record in decode_pcap(&capture, 8080, Some(&WORLD_SCHEMA))? {
+//!     println!("{}", record);
+//! }
+//! ```
+
+use crate::frame::{self, FrameError};
+use rpc::{context, schema::Service, ServerError};
+use serde::de::DeserializeOwned;
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The number of bytes of an RPC's arguments or output that are shown.
+const SHOWN_BYTES: usize = 64;
+
+/// Which end of a connection sent a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Peer {
+    /// The client, which sends [`ClientMessage`](rpc::ClientMessage)s.
+    Client,
+    /// The server, which sends [`Response`](rpc::Response)s.
+    Server,
+}
+
+/// The RPC a message is for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Rpc {
+    /// The index of the RPC's variant in the service's request and response enums.
+    pub id: u32,
+    /// The name of the RPC, if the schema decoded against has an RPC with the ID.
+    pub name: Option<String>,
+}
+
+impl Rpc {
+    fn resolve(id: u32, schema: Option<&Service>) -> Self {
+        let method = schema.and_then(|schema| schema.methods.iter().find(|method| method.id == id));
+        Rpc {
+            id,
+            name: method.map(|method| method.name.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Rpc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "{}", name),
+            None => write!(f, "#{}", self.id),
+        }
+    }
+}
+
+/// A decoded message.
+#[derive(Debug)]
+pub enum Message {
+    /// A request, sent by a client.
+    Request {
+        /// The context of the request, including its deadline and trace context.
+        context: context::Context,
+        /// The ID of the request, unique among the requests sent over the connection.
+        request_id: u64,
+        /// The RPC the request invokes.
+        rpc: Rpc,
+        /// The bincode encoding of the RPC's arguments.
+        args: Vec<u8>,
+        /// Whether the request is one-way.
+        one_way: bool,
+    },
+    /// A cancellation of a request, sent by a client.
+    Cancel {
+        /// The trace context of the cancellation.
+        trace_context: trace::Context,
+        /// The ID of the request canceled.
+        request_id: u64,
+    },
+    /// A response, sent by a server.
+    Response {
+        /// The ID of the request responded to.
+        request_id: u64,
+        /// The RPC the response is for.
+        rpc: Rpc,
+        /// The bincode encoding of the RPC's output.
+        output: Vec<u8>,
+        /// Whether more responses to the same request follow.
+        partial: bool,
+    },
+    /// A response reporting that a request failed, sent by a server.
+    Error {
+        /// The ID of the request responded to.
+        request_id: u64,
+        /// Why the request failed.
+        error: ServerError,
+        /// Whether more responses to the same request follow.
+        partial: bool,
+    },
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Message::Request {
+                context,
+                request_id,
+                rpc,
+                args,
+                one_way,
+            } => {
+                write!(f, "request {} {}", request_id, rpc)?;
+                if *one_way {
+                    write!(f, " (one-way)")?;
+                }
+                write!(
+                    f,
+                    ": trace {}, deadline {}, args {}",
+                    context.trace_id(),
+                    humantime::format_rfc3339_seconds(context.deadline),
+                    Bytes(args)
+                )
+            }
+            Message::Cancel {
+                trace_context,
+                request_id,
+            } => write!(f, "cancel {}: trace {}", request_id, trace_context.trace_id),
+            Message::Response {
+                request_id,
+                rpc,
+                output,
+                partial,
+            } => {
+                write!(f, "response {} {}", request_id, rpc)?;
+                if *partial {
+                    write!(f, " (partial)")?;
+                }
+                write!(f, ": output {}", Bytes(output))
+            }
+            Message::Error {
+                request_id,
+                error,
+                partial,
+            } => {
+                write!(f, "error {}", request_id)?;
+                if *partial {
+                    write!(f, " (partial)")?;
+                }
+                write!(f, ": {:?}", error.kind)?;
+                if let Some(ref detail) = error.detail {
+                    write!(f, ", {:?}", detail)?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "unknown message"),
+        }
+    }
+}
+
+/// Shows encoded bytes in hex, up to [`SHOWN_BYTES`] of them.
+struct Bytes<'a>(&'a [u8]);
+
+impl fmt::Display for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({} bytes)", self.0.len())?;
+        if !self.0.is_empty() {
+            write!(f, " ")?;
+        }
+        for byte in self.0.iter().take(SHOWN_BYTES) {
+            write!(f, "{:02x}", byte)?;
+        }
+        if self.0.len() > SHOWN_BYTES {
+            write!(f, "...")?;
+        }
+        Ok(())
+    }
+}
+
+/// A frame decoded from captured traffic.
+#[derive(Debug)]
+pub struct Record {
+    /// When the packet that completed the frame was captured, for frames decoded from a capture.
+    pub timestamp: Option<SystemTime>,
+    /// The addresses of the client and the server, for frames decoded from a capture.
+    pub connection: Option<(SocketAddr, SocketAddr)>,
+    /// Which end of the connection sent the frame.
+    pub sender: Peer,
+    /// The message in the frame, or why it couldn't be decoded.
+    pub message: Result<Message, FrameError>,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(timestamp) = self.timestamp {
+            write!(f, "{} ", humantime::format_rfc3339_micros(timestamp))?;
+        }
+        if let Some((client, server)) = self.connection {
+            match self.sender {
+                Peer::Client => write!(f, "{} -> {} ", client, server)?,
+                Peer::Server => write!(f, "{} <- {} ", client, server)?,
+            }
+        }
+        match self.message {
+            Ok(ref message) => write!(f, "{}", message),
+            Err(ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Decodes the payload of a frame sent by `sender`, resolving the RPC the message is for against
+/// `schema`, if given.
+pub fn decode_message(
+    payload: &[u8],
+    sender: Peer,
+    schema: Option<&Service>,
+) -> Result<Message, FrameError> {
+    let mut rest = payload;
+    let message = match sender {
+        Peer::Client => decode_client_message(&mut rest, schema),
+        Peer::Server => decode_response(&mut rest, schema),
+    };
+    message.map_err(|error| FrameError::Malformed {
+        len: payload.len(),
+        error,
+    })
+}
+
+/// Decodes a `ClientMessage`, which is encoded as its variant index followed by its fields. A
+/// request is encoded as the index of the version of its layout, followed by its fields.
+fn decode_client_message(rest: &mut &[u8], schema: Option<&Service>) -> bincode::Result<Message> {
+    match read::<u32>(rest)? {
+        0 => match read::<u32>(rest)? {
+            0 => {
+                let context = read(rest)?;
+                let request_id = read(rest)?;
+                let rpc = Rpc::resolve(read(rest)?, schema);
+                let (args, one_way) = split_flag(rest)?;
+                Ok(Message::Request {
+                    context,
+                    request_id,
+                    rpc,
+                    args,
+                    one_way,
+                })
+            }
+            version => Err(unknown_variant("Request", version)),
+        },
+        1 => Ok(Message::Cancel {
+            trace_context: read(rest)?,
+            request_id: read(rest)?,
+        }),
+        variant => Err(unknown_variant("ClientMessage", variant)),
+    }
+}
+
+/// Decodes a `Response`, which is encoded as the index of the version of its layout, followed by
+/// its fields. Its message is a `Result`, encoded as its variant index followed by the response or
+/// error.
+fn decode_response(rest: &mut &[u8], schema: Option<&Service>) -> bincode::Result<Message> {
+    match read::<u32>(rest)? {
+        0 => {}
+        version => return Err(unknown_variant("Response", version)),
+    }
+    let request_id = read(rest)?;
+    match read::<u32>(rest)? {
+        0 => {
+            let rpc = Rpc::resolve(read(rest)?, schema);
+            let (output, partial) = split_flag(rest)?;
+            Ok(Message::Response {
+                request_id,
+                rpc,
+                output,
+                partial,
+            })
+        }
+        1 => Ok(Message::Error {
+            request_id,
+            error: read(rest)?,
+            partial: read(rest)?,
+        }),
+        variant => Err(unknown_variant("Result", variant)),
+    }
+}
+
+fn read<T: DeserializeOwned>(rest: &mut &[u8]) -> bincode::Result<T> {
+    bincode::deserialize_from(rest)
+}
+
+/// Splits the fields of an RPC's variant, whose encoding depends on the RPC, from the flag that
+/// follows them at the end of the frame, and decodes the flag.
+fn split_flag(rest: &[u8]) -> bincode::Result<(Vec<u8>, bool)> {
+    let flag_len = bincode::serialized_size(&false)? as usize;
+    if rest.len() < flag_len {
+        return Err(Box::new(bincode::ErrorKind::Io(
+            io::ErrorKind::UnexpectedEof.into(),
+        )));
+    }
+    let (fields, flag) = rest.split_at(rest.len() - flag_len);
+    Ok((fields.to_vec(), bincode::deserialize(flag)?))
+}
+
+fn unknown_variant(ty: &str, variant: u32) -> bincode::Error {
+    Box::new(bincode::ErrorKind::Custom(format!(
+        "unknown {} variant {}",
+        ty, variant
+    )))
+}
+
+/// Decodes the bytes sent by one end of a connection, from its start, into frames. If the bytes
+/// end partway through a frame, the last record reports the frame as incomplete.
+pub fn decode_stream(bytes: &[u8], sender: Peer, schema: Option<&Service>) -> Vec<Record> {
+    let mut records = vec![];
+    let mut frames = Frames::new(sender, None);
+    frames.push(bytes, None, schema, &mut records);
+    frames.finish(None, &mut records);
+    records
+}
+
+/// The bytes sent by one end of a connection, split into frames as they arrive.
+#[derive(Debug)]
+struct Frames {
+    sender: Peer,
+    connection: Option<(SocketAddr, SocketAddr)>,
+    /// The bytes of the frame that has yet to arrive in full.
+    buf: Vec<u8>,
+}
+
+impl Frames {
+    fn new(sender: Peer, connection: Option<(SocketAddr, SocketAddr)>) -> Self {
+        Frames {
+            sender,
+            connection,
+            buf: vec![],
+        }
+    }
+
+    /// Decodes the frames that `bytes` complete.
+    fn push(
+        &mut self,
+        bytes: &[u8],
+        timestamp: Option<SystemTime>,
+        schema: Option<&Service>,
+        records: &mut Vec<Record>,
+    ) {
+        self.buf.extend_from_slice(bytes);
+        let mut start = 0;
+        while let Ok((payload, len)) = frame::split_frame(&self.buf[start..]) {
+            records.push(Record {
+                timestamp,
+                connection: self.connection,
+                sender: self.sender,
+                message: decode_message(payload, self.sender, schema),
+            });
+            start += len;
+        }
+        self.buf.drain(..start);
+    }
+
+    /// Reports the frame cut off by the end of the bytes, if any.
+    fn finish(self, timestamp: Option<SystemTime>, records: &mut Vec<Record>) {
+        if self.buf.is_empty() {
+            return;
+        }
+        if let Err(error) = frame::split_frame(&self.buf) {
+            records.push(Record {
+                timestamp,
+                connection: self.connection,
+                sender: self.sender,
+                message: Err(error),
+            });
+        }
+    }
+}
+
+/// Decodes the connections to `server_port` in a capture in the pcap format, as written by
+/// `tcpdump -w`, returning the frames sent in either direction in the order they were captured.
+///
+/// Each connection is reassembled from its TCP segments, so the capture should include the
+/// start of every connection of interest, and whole packets: the frames of a connection that
+/// started before the capture did can't be found, and a packet cut off by the snapshot length
+/// corrupts the rest of its connection. Captures of Ethernet, loopback, and Linux "any"
+/// interfaces are supported, but not the pcapng format.
+pub fn decode_pcap(
+    capture: &[u8],
+    server_port: u16,
+    schema: Option<&Service>,
+) -> io::Result<Vec<Record>> {
+    let capture = Pcap::new(capture)?;
+    let mut flows: BTreeMap<(SocketAddr, SocketAddr), Flow> = BTreeMap::new();
+    let mut records = vec![];
+    for packet in capture.packets() {
+        let segment = match capture.ip_packet(packet.data).and_then(Segment::parse) {
+            Some(segment) => segment,
+            None => continue,
+        };
+        let (sender, connection) = if segment.dst.port() == server_port {
+            (Peer::Client, (segment.src, segment.dst))
+        } else if segment.src.port() == server_port {
+            (Peer::Server, (segment.dst, segment.src))
+        } else {
+            continue;
+        };
+        flows
+            .entry((segment.src, segment.dst))
+            .or_insert_with(|| Flow::new(sender, connection))
+            .segment(&segment, packet.timestamp, schema, &mut records);
+    }
+    for (_, flow) in flows {
+        flow.frames.finish(flow.last_seen, &mut records);
+    }
+    Ok(records)
+}
+
+/// One direction of a TCP connection, reassembled from its segments.
+#[derive(Debug)]
+struct Flow {
+    frames: Frames,
+    /// The sequence number of the first byte of the stream.
+    start: Option<u32>,
+    /// The number of bytes of the stream passed on to `frames`.
+    delivered: u32,
+    /// Segments that arrived ahead of bytes missing before them, by offset into the stream.
+    pending: BTreeMap<u32, Vec<u8>>,
+    last_seen: Option<SystemTime>,
+}
+
+impl Flow {
+    fn new(sender: Peer, connection: (SocketAddr, SocketAddr)) -> Self {
+        Flow {
+            frames: Frames::new(sender, Some(connection)),
+            start: None,
+            delivered: 0,
+            pending: BTreeMap::new(),
+            last_seen: None,
+        }
+    }
+
+    fn segment(
+        &mut self,
+        segment: &Segment,
+        timestamp: SystemTime,
+        schema: Option<&Service>,
+        records: &mut Vec<Record>,
+    ) {
+        self.last_seen = Some(timestamp);
+        // A SYN takes up a sequence number before the stream's first byte.
+        let seq = if segment.syn {
+            let seq = segment.seq.wrapping_add(1);
+            self.start = Some(seq);
+            seq
+        } else {
+            segment.seq
+        };
+        let start = *self.start.get_or_insert(seq);
+        let offset = seq.wrapping_sub(start);
+        let end = offset.wrapping_add(segment.payload.len() as u32);
+        if segment.payload.is_empty() || end <= self.delivered {
+            return;
+        }
+        let pending = self.pending.entry(offset).or_default();
+        if pending.len() < segment.payload.len() {
+            *pending = segment.payload.to_vec();
+        }
+        while let Some(offset) = self.pending.keys().next().cloned() {
+            if offset > self.delivered {
+                break;
+            }
+            let bytes = self.pending.remove(&offset).unwrap();
+            // Retransmitted segments can overlap bytes already delivered.
+            let overlap = (self.delivered - offset) as usize;
+            if overlap < bytes.len() {
+                self.frames
+                    .push(&bytes[overlap..], Some(timestamp), schema, records);
+                self.delivered += (bytes.len() - overlap) as u32;
+            }
+        }
+    }
+}
+
+/// A TCP segment.
+#[derive(Debug)]
+struct Segment<'a> {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    syn: bool,
+    payload: &'a [u8],
+}
+
+impl<'a> Segment<'a> {
+    const TCP: u8 = 6;
+
+    /// Parses the TCP segment in an IP packet, if it holds one.
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let (src, dst, tcp) = match packet.first()? >> 4 {
+            4 => {
+                if packet.len() < 20 {
+                    return None;
+                }
+                let header_len = usize::from(packet[0] & 0xf) * 4;
+                let total_len = usize::from(be16(packet, 2)?).min(packet.len());
+                let fragment = be16(packet, 6)?;
+                // Fragments of a segment are ignored; TCP avoids fragmentation.
+                if *packet.get(9)? != Self::TCP || fragment & 0x3fff != 0 {
+                    return None;
+                }
+                let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+                let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+                let tcp = packet.get(header_len..total_len)?;
+                (IpAddr::V4(src), IpAddr::V4(dst), tcp)
+            }
+            6 => {
+                const HEADER_LEN: usize = 40;
+                let payload_len = usize::from(be16(packet, 4)?);
+                if *packet.get(6)? != Self::TCP || packet.len() < HEADER_LEN {
+                    return None;
+                }
+                let mut src = [0; 16];
+                src.copy_from_slice(&packet[8..24]);
+                let mut dst = [0; 16];
+                dst.copy_from_slice(&packet[24..40]);
+                let end = (HEADER_LEN + payload_len).min(packet.len());
+                let tcp = &packet[HEADER_LEN..end];
+                (
+                    IpAddr::V6(Ipv6Addr::from(src)),
+                    IpAddr::V6(Ipv6Addr::from(dst)),
+                    tcp,
+                )
+            }
+            _ => return None,
+        };
+        let header_len = usize::from(*tcp.get(12)? >> 4) * 4;
+        Some(Segment {
+            src: SocketAddr::new(src, be16(tcp, 0)?),
+            dst: SocketAddr::new(dst, be16(tcp, 2)?),
+            seq: u32_at(tcp, 4, false)?,
+            syn: tcp[13] & 0x02 != 0,
+            payload: tcp.get(header_len..)?,
+        })
+    }
+}
+
+/// A packet capture in the pcap format.
+#[derive(Debug)]
+struct Pcap<'a> {
+    bytes: &'a [u8],
+    little_endian: bool,
+    nanos: bool,
+    link_type: u32,
+}
+
+/// A captured packet.
+#[derive(Debug)]
+struct Packet<'a> {
+    timestamp: SystemTime,
+    data: &'a [u8],
+}
+
+impl<'a> Pcap<'a> {
+    const HEADER_LEN: usize = 24;
+    const RECORD_HEADER_LEN: usize = 16;
+    const LINKTYPE_NULL: u32 = 0;
+    const LINKTYPE_ETHERNET: u32 = 1;
+    const LINKTYPE_RAW: u32 = 101;
+    const LINKTYPE_LOOP: u32 = 108;
+    const LINKTYPE_LINUX_SLL: u32 = 113;
+    const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+    fn new(bytes: &'a [u8]) -> io::Result<Self> {
+        let invalid = |error: &str| io::Error::new(io::ErrorKind::InvalidData, error.to_string());
+        let magic = u32_at(bytes, 0, false).ok_or_else(|| invalid("not a pcap capture"))?;
+        let (little_endian, nanos) = match magic {
+            0xa1b2_c3d4 => (false, false),
+            0xd4c3_b2a1 => (true, false),
+            0xa1b2_3c4d => (false, true),
+            0x4d3c_b2a1 => (true, true),
+            0x0a0d_0d0a => {
+                return Err(invalid("pcapng captures aren't supported; convert to pcap"))
+            }
+            _ => return Err(invalid("not a pcap capture")),
+        };
+        let link_type = u32_at(bytes, 20, little_endian)
+            .ok_or_else(|| invalid("the capture ends within its header"))?;
+        match link_type {
+            Self::LINKTYPE_NULL
+            | Self::LINKTYPE_ETHERNET
+            | Self::LINKTYPE_RAW
+            | Self::LINKTYPE_LOOP
+            | Self::LINKTYPE_LINUX_SLL
+            | Self::LINKTYPE_LINUX_SLL2 => {}
+            _ => {
+                return Err(invalid(&format!(
+                    "captures of link type {} aren't supported",
+                    link_type
+                )))
+            }
+        }
+        Ok(Pcap {
+            bytes,
+            little_endian,
+            nanos,
+            link_type,
+        })
+    }
+
+    /// Returns the captured packets, stopping at a packet cut off by the end of the capture.
+    fn packets(&self) -> impl Iterator<Item = Packet<'a>> + '_ {
+        let mut offset = Self::HEADER_LEN;
+        std::iter::from_fn(move || {
+            let secs = u32_at(self.bytes, offset, self.little_endian)?;
+            let fraction = u32_at(self.bytes, offset + 4, self.little_endian)?;
+            let len = u32_at(self.bytes, offset + 8, self.little_endian)? as usize;
+            let start = offset + Self::RECORD_HEADER_LEN;
+            let data = self.bytes.get(start..start + len)?;
+            offset = start + len;
+            let fraction = if self.nanos {
+                Duration::from_nanos(fraction.into())
+            } else {
+                Duration::from_micros(fraction.into())
+            };
+            Some(Packet {
+                timestamp: UNIX_EPOCH + Duration::from_secs(secs.into()) + fraction,
+                data,
+            })
+        })
+    }
+
+    /// Strips the link-layer header from a packet, returning the IP packet it holds, if any.
+    fn ip_packet(&self, data: &'a [u8]) -> Option<&'a [u8]> {
+        const IPV4: u16 = 0x0800;
+        const IPV6: u16 = 0x86dd;
+        const VLAN: u16 = 0x8100;
+        let (ether_type, offset) = match self.link_type {
+            // The IP version is checked when the packet is parsed.
+            Self::LINKTYPE_NULL | Self::LINKTYPE_LOOP => return data.get(4..),
+            Self::LINKTYPE_RAW => return Some(data),
+            Self::LINKTYPE_ETHERNET => {
+                let mut offset = 12;
+                while be16(data, offset)? == VLAN {
+                    offset += 4;
+                }
+                (be16(data, offset)?, offset + 2)
+            }
+            Self::LINKTYPE_LINUX_SLL => (be16(data, 14)?, 16),
+            Self::LINKTYPE_LINUX_SLL2 => (be16(data, 0)?, 20),
+            _ => return None,
+        };
+        match ether_type {
+            IPV4 | IPV6 => data.get(offset..),
+            _ => None,
+        }
+    }
+}
+
+fn be16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a big-endian `u32`, or a little-endian one if `little_endian` is set.
+fn u32_at(bytes: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+    Some(if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use rpc::{
+        schema::{Method, MethodKind},
+        ClientMessage, Request, Response,
+    };
+    use serde::Serialize;
+
+    fn schema() -> Service {
+        let method = |name: &'static str, wire_name: &'static str, id| Method {
+            name: name.into(),
+            wire_name: wire_name.into(),
+            id,
+            kind: MethodKind::Unary,
+            args: vec![].into(),
+            output: "String".into(),
+            error: None,
+            since: None,
+            removed: None,
+        };
+        Service {
+            name: "World".into(),
+            methods: vec![method("hello", "Hello", 0), method("goodbye", "Goodbye", 1)].into(),
+        }
+    }
+
+    /// Frames the bincode encoding of `message`. The requests and responses of the service are
+    /// encoded as tuples of their variant index and fields, as bincode encodes enums.
+    fn frame(message: impl Serialize) -> Vec<u8> {
+        let payload = bincode::serialize(&message).unwrap();
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend(payload);
+        frame
+    }
+
+    fn display(records: &[Record]) -> Vec<String> {
+        records.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn decodes_client_messages() {
+        let mut context = context::current();
+        // Deadlines are sent in whole seconds.
+        context.deadline = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let trace_context = trace::Context::new_root();
+        let mut bytes = frame(ClientMessage::Request(Request::new(
+            context.clone(),
+            7,
+            (1u32, "Tim"),
+        )));
+        let mut one_way = Request::new(context.clone(), 8, (5u32, ()));
+        one_way.one_way = true;
+        bytes.extend(frame(ClientMessage::Request(one_way)));
+        bytes.extend(frame(ClientMessage::<()>::Cancel {
+            trace_context,
+            request_id: 7,
+        }));
+        // A request has no second version.
+        bytes.extend(frame((0u32, 1u32)));
+        let records = decode_stream(&bytes, Peer::Client, Some(&schema()));
+        assert_eq!(
+            display(&records[..3]),
+            vec![
+                format!(
+                    "request 7 goodbye: trace {}, deadline 2001-09-09T01:46:40Z, \
+                     args (11 bytes) 030000000000000054696d",
+                    context.trace_id(),
+                ),
+                format!(
+                    "request 8 #5 (one-way): trace {}, deadline 2001-09-09T01:46:40Z, \
+                     args (0 bytes)",
+                    context.trace_id(),
+                ),
+                format!("cancel 7: trace {}", trace_context.trace_id),
+            ]
+        );
+        assert_matches!(
+            records[3].message,
+            Err(FrameError::Malformed { len: 8, .. })
+        );
+        assert_matches!(
+            records[0].message,
+            Ok(Message::Request {
+                request_id: 7,
+                rpc: Rpc { id: 1, .. },
+                one_way: false,
+                ..
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_responses() {
+        let mut partial = Response::new(7, Ok((1u32, "Bye")));
+        partial.partial = true;
+        let mut bytes = frame(partial);
+        bytes.extend(frame(Response::<()>::new(
+            8,
+            Err(ServerError::new(
+                io::ErrorKind::TimedOut,
+                Some("late".into()),
+            )),
+        )));
+        // A `Result` has no third variant.
+        bytes.extend(frame((0u32, 9u64, 2u32)));
+        let incomplete = frame(Response::new(10, Ok((0u32, "Hi"))));
+        bytes.extend(&incomplete[..incomplete.len() - 3]);
+        let records = decode_stream(&bytes, Peer::Server, Some(&schema()));
+        assert_eq!(
+            display(&records[..2]),
+            vec![
+                "response 7 goodbye (partial): output (11 bytes) 0300000000000000427965",
+                r#"error 8: TimedOut, "late""#,
+            ]
+        );
+        assert_matches!(
+            records[2].message,
+            Err(FrameError::Malformed { len: 16, .. })
+        );
+        assert_matches!(
+            records[3].message,
+            Err(FrameError::Incomplete { needed: 3 })
+        );
+        assert_eq!(records.len(), 4);
+    }
+
+    /// Returns an Ethernet frame holding a TCP segment.
+    fn packet(src: SocketAddr, dst: SocketAddr, seq: u32, syn: bool, payload: &[u8]) -> Vec<u8> {
+        let ip = |addr: SocketAddr| match addr.ip() {
+            IpAddr::V4(ip) => ip.octets(),
+            IpAddr::V6(_) => unreachable!(),
+        };
+        let total_len = (20 + 20 + payload.len()) as u16;
+        let mut packet = vec![0; 12];
+        packet.extend(&[0x08, 0x00]);
+        packet.extend(&[0x45, 0]);
+        packet.extend(&total_len.to_be_bytes());
+        packet.extend(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        packet.extend(&ip(src));
+        packet.extend(&ip(dst));
+        packet.extend(&src.port().to_be_bytes());
+        packet.extend(&dst.port().to_be_bytes());
+        packet.extend(&seq.to_be_bytes());
+        packet.extend(&[0, 0, 0, 0, 0x50, if syn { 0x02 } else { 0x18 }, 0xff, 0xff]);
+        packet.extend(&[0, 0, 0, 0]);
+        packet.extend(payload);
+        // Ethernet pads short frames.
+        packet.resize(packet.len().max(60), 0);
+        packet
+    }
+
+    /// Returns a little-endian pcap capture of Ethernet frames, captured a second apart.
+    fn pcap(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut capture = vec![];
+        capture.extend(&0xa1b2_c3d4_u32.to_le_bytes());
+        capture.extend(&[2, 0, 4, 0]);
+        capture.extend(&[0; 8]);
+        capture.extend(&65535_u32.to_le_bytes());
+        capture.extend(&1_u32.to_le_bytes());
+        for (secs, packet) in packets.iter().enumerate() {
+            capture.extend(&(secs as u32).to_le_bytes());
+            capture.extend(&500_u32.to_le_bytes());
+            capture.extend(&(packet.len() as u32).to_le_bytes());
+            capture.extend(&(packet.len() as u32).to_le_bytes());
+            capture.extend(packet);
+        }
+        capture
+    }
+
+    #[test]
+    fn decodes_pcap_captures() -> io::Result<()> {
+        let client: SocketAddr = "10.0.0.1:50000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+        let other: SocketAddr = "10.0.0.3:9000".parse().unwrap();
+        let request = frame(ClientMessage::Request(Request::new(
+            context::current(),
+            7,
+            (0u32, "Tim"),
+        )));
+        let response = frame(Response::new(7, Ok((0u32, "Hello, Tim!"))));
+        let (first, second) = request.split_at(10);
+        let capture = pcap(&[
+            packet(client, server, 999, true, &[]),
+            packet(server, client, 4999, true, &[]),
+            // Out of order, and then retransmitted.
+            packet(client, server, 1010, false, second),
+            packet(client, other, 1, false, &response),
+            packet(client, server, 1000, false, first),
+            packet(client, server, 1000, false, first),
+            packet(server, client, 5000, false, &response[..4]),
+            packet(server, client, 5000, false, &response),
+        ]);
+
+        let records = decode_pcap(&capture, 8080, Some(&schema()))?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].connection, Some((client, server)));
+        assert_eq!(records[0].sender, Peer::Client);
+        assert_eq!(
+            records[0].timestamp,
+            Some(UNIX_EPOCH + Duration::from_secs(4) + Duration::from_micros(500))
+        );
+        assert_matches!(
+            records[0].message,
+            Ok(Message::Request { request_id: 7, rpc: Rpc { id: 0, ref name }, .. })
+                if name.as_ref().map(String::as_str) == Some("hello")
+        );
+        assert_eq!(
+            records[1].to_string(),
+            "1970-01-01T00:00:07.000500Z 10.0.0.1:50000 <- 10.0.0.2:8080 response 7 hello: \
+             output (19 bytes) 0b0000000000000048656c6c6f2c2054696d21"
+        );
+
+        assert_eq!(
+            decode_pcap(b"\x0a\x0d\x0d\x0a", 8080, None)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+}
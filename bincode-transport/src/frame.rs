@@ -0,0 +1,215 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use crate::{
+    codec::HEADER_LEN,
+    dump::{self, Peer},
+};
+use rpc::{ClientMessage, Response, ServerError};
+use serde::de::DeserializeOwned;
+use std::{error::Error, fmt, io};
+
+/// What a transport does with a frame it reads but can't decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MalformedFrames {
+    /// The transport's stream yields a [`FrameError`] and then ends, closing the channel reading
+    /// from it. The default.
+    Close,
+    /// The transport discards the frame and reads the next, keeping count of the frames it
+    /// discards. A server's transport answers a request it discards with an `InvalidData` error,
+    /// if it can read the request's ID, so that its client needn't wait out its deadline.
+    Skip,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl Default for MalformedFrames {
+    fn default() -> Self {
+        MalformedFrames::Close
+    }
+}
+
+/// The messages a transport reads: a server's reads [`ClientMessage`]s, and a client's reads
+/// [`Response`]s.
+pub trait Envelope {
+    /// Returns the response to a message that couldn't be decoded from `payload`, if any.
+    fn answer_malformed(payload: &[u8]) -> Option<Response<()>>;
+}
+
+/// Answers requests that can't be decoded, but whose IDs can be read, unless they're one-way.
+impl<T> Envelope for ClientMessage<T> {
+    fn answer_malformed(payload: &[u8]) -> Option<Response<()>> {
+        match dump::decode_message(payload, Peer::Client, None) {
+            Ok(dump::Message::Request {
+                request_id,
+                one_way: false,
+                ..
+            }) => Some(Response::new(
+                request_id,
+                Err(ServerError::new(
+                    io::ErrorKind::InvalidData,
+                    Some("The request couldn't be decoded.".into()),
+                )),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Responses are never answered.
+impl<T> Envelope for Response<T> {
+    fn answer_malformed(_: &[u8]) -> Option<Response<()>> {
+        None
+    }
+}
+
+/// Why a frame couldn't be decoded.
+///
+/// A transport's stream returns frame errors as [`io::Error`]s, from which
+/// [`FrameError::from_io`] recovers them.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The bytes end before the frame does.
+    Incomplete {
+        /// The number of bytes missing, as far as is known. If the length prefix itself is
+        /// incomplete, only the bytes missing from it are counted.
+        needed: usize,
+    },
+    /// The frame's payload isn't a valid encoding of the expected message.
+    Malformed {
+        /// The length of the payload, excluding the length prefix.
+        len: usize,
+        /// Why the payload couldn't be deserialized.
+        error: bincode::Error,
+    },
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl FrameError {
+    /// Returns the frame error that caused `error`, if any.
+    pub fn from_io(error: &io::Error) -> Option<&FrameError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Incomplete { needed } => {
+                write!(f, "incomplete frame: {} more bytes needed", needed)
+            }
+            FrameError::Malformed { len, error } => {
+                write!(f, "malformed frame of {} bytes: {}", len, error)
+            }
+            _ => write!(f, "invalid frame"),
+        }
+    }
+}
+
+impl Error for FrameError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FrameError::Malformed { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(error: FrameError) -> io::Error {
+        let kind = match error {
+            FrameError::Incomplete { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// Decodes the frame at the start of `bytes`, a big-endian `u32` length prefix followed by that
+/// many bytes of bincode, returning the message and the length of the frame, prefix included.
+///
+/// This is the parser a transport reads messages with, so it's the entry point for fuzzing it.
+/// It returns an error, rather than panicking or allocating without bound, whatever the bytes.
+/// A server's transport reads [`ClientMessage`](rpc::ClientMessage)s, and a client's reads
+/// [`Response`](rpc::Response)s:
+///
+/// ```ignore
+/// fuzz_target!(|data: &[u8]| {
+///     let _ = decode_frame::<ClientMessage<String>>(data);
+/// });
+/// ```
+pub fn decode_frame<T: DeserializeOwned>(bytes: &[u8]) -> Result<(T, usize), FrameError> {
+    let (payload, frame_len) = split_frame(bytes)?;
+    Ok((decode_payload(payload)?, frame_len))
+}
+
+/// Splits the payload of the frame at the start of `bytes` from its length prefix, returning the
+/// payload and the length of the frame, prefix included.
+pub(crate) fn split_frame(bytes: &[u8]) -> Result<(&[u8], usize), FrameError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(FrameError::Incomplete {
+            needed: HEADER_LEN - bytes.len(),
+        });
+    }
+    let mut header = [0; HEADER_LEN];
+    header.copy_from_slice(&bytes[..HEADER_LEN]);
+    let frame_len = HEADER_LEN + u32::from_be_bytes(header) as usize;
+    if bytes.len() < frame_len {
+        return Err(FrameError::Incomplete {
+            needed: frame_len - bytes.len(),
+        });
+    }
+    Ok((&bytes[HEADER_LEN..frame_len], frame_len))
+}
+
+/// Decodes the payload of a frame.
+pub(crate) fn decode_payload<T: DeserializeOwned>(payload: &[u8]) -> Result<T, FrameError> {
+    bincode::deserialize(payload).map_err(|error| FrameError::Malformed {
+        len: payload.len(),
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn decodes_frames() {
+        let bytes = b"\x00\x00\x00\x0d\x05\x00\x00\x00\x00\x00\x00\x00hello\x00";
+        assert_matches!(
+            decode_frame::<String>(bytes),
+            Ok((ref s, 17)) if s == "hello"
+        );
+        assert_matches!(
+            decode_frame::<String>(&bytes[..2]),
+            Err(FrameError::Incomplete { needed: 2 })
+        );
+        assert_matches!(
+            decode_frame::<String>(&bytes[..10]),
+            Err(FrameError::Incomplete { needed: 7 })
+        );
+        // The string is longer than the frame.
+        let bytes = b"\x00\x00\x00\x0d\xff\x00\x00\x00\x00\x00\x00\x00hello";
+        assert_matches!(
+            decode_frame::<String>(bytes),
+            Err(FrameError::Malformed { len: 13, .. })
+        );
+    }
+
+    #[test]
+    fn frame_errors_survive_io_errors() {
+        let error = io::Error::from(FrameError::Incomplete { needed: 1 });
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        assert_matches!(
+            FrameError::from_io(&error),
+            Some(FrameError::Incomplete { needed: 1 })
+        );
+        assert!(FrameError::from_io(&io::ErrorKind::InvalidData.into()).is_none());
+    }
+}
@@ -8,7 +8,23 @@
 
 #![deny(missing_docs, missing_debug_implementations)]
 
-use async_bincode::{AsyncBincodeStream, AsyncDestination};
+mod blocking;
+mod codec;
+pub mod dump;
+mod frame;
+mod payload;
+pub mod record;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+#[cfg(feature = "tls")]
+pub mod tls;
+
+pub use frame::{decode_frame, Envelope, FrameError, MalformedFrames};
+pub use payload::Preserialized;
+#[cfg(feature = "ssh")]
+pub use ssh::{connect_via_ssh, SshConfig};
+
+use codec::Framed;
 use futures::{compat::*, prelude::*, ready};
 use pin_utils::unsafe_pinned;
 use serde::{Deserialize, Serialize};
@@ -26,13 +42,40 @@ use tokio_tcp::{TcpListener, TcpStream};
 /// A transport that serializes to, and deserializes from, a [`TcpStream`].
 #[derive(Debug)]
 pub struct Transport<S, Item, SinkItem> {
-    inner: Compat01As03Sink<AsyncBincodeStream<S, Item, SinkItem, AsyncDestination>, SinkItem>,
+    inner: Compat01As03Sink<Framed<S, Item, SinkItem>, SinkItem>,
 }
 
 impl<S, Item, SinkItem> Transport<S, Item, SinkItem> {
-    unsafe_pinned!(
-        inner: Compat01As03Sink<AsyncBincodeStream<S, Item, SinkItem, AsyncDestination>, SinkItem>
-    );
+    unsafe_pinned!(inner: Compat01As03Sink<Framed<S, Item, SinkItem>, SinkItem>);
+
+    /// Returns the transport with payloads larger than `bytes` serialized and deserialized on a
+    /// blocking thread pool, rather than on the task driving the transport. By default, every
+    /// payload is (de)serialized on the transport's task.
+    pub fn with_offload_threshold(mut self, bytes: usize) -> Self
+    where
+        Item: for<'de> Deserialize<'de> + Send + 'static,
+        SinkItem: Serialize + Send + 'static,
+    {
+        self.inner.get_mut().set_offload_threshold(bytes);
+        self
+    }
+
+    /// Returns the transport with frames it can't decode handled per `policy`. Defaults to
+    /// [`MalformedFrames::Close`].
+    pub fn with_malformed_frames(mut self, policy: MalformedFrames) -> Self
+    where
+        Item: Envelope,
+    {
+        let framed = self.inner.get_mut();
+        framed.set_malformed_frames(policy);
+        framed.set_answer_malformed(Item::answer_malformed);
+        self
+    }
+
+    /// Returns the number of frames skipped because they couldn't be decoded.
+    pub fn skipped_frames(&self) -> u64 {
+        self.inner.get_ref().skipped_frames()
+    }
 }
 
 impl<S, Item, SinkItem> Stream for Transport<S, Item, SinkItem>
@@ -47,9 +90,11 @@ where
             Poll::Pending => Poll::Pending,
             Poll::Ready(None) => Poll::Ready(None),
             Poll::Ready(Some(Ok(next))) => Poll::Ready(Some(Ok(next))),
-            Poll::Ready(Some(Err(e))) => {
-                Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::Other, e))))
-            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(match *e {
+                // Keeps I/O errors, including frame errors, intact.
+                bincode::ErrorKind::Io(e) => e,
+                e => io::Error::new(io::ErrorKind::Other, e),
+            }))),
         }
     }
 }
@@ -108,6 +153,10 @@ impl<T, Item, SinkItem> AsRef<T> for Transport<T, Item, SinkItem> {
     }
 }
 
+/// The [codec](rpc::codec::Codec) the transport serializes payloads with, for checking that
+/// payload types [roundtrip](rpc::codec::roundtrip).
+pub use rpc::codec::Bincode;
+
 /// Returns a new bincode transport that reads from and writes to `io`.
 pub fn new<Item, SinkItem>(io: TcpStream) -> Transport<TcpStream, Item, SinkItem>
 where
@@ -120,7 +169,7 @@ where
 impl<S, Item, SinkItem> From<S> for Transport<S, Item, SinkItem> {
     fn from(inner: S) -> Self {
         Transport {
-            inner: Compat01As03Sink::new(AsyncBincodeStream::from(inner).for_async()),
+            inner: Compat01As03Sink::new(Framed::new(inner)),
         }
     }
 }
@@ -142,14 +191,35 @@ where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    let listener = TcpListener::bind(addr)?;
-    let local_addr = listener.local_addr()?;
-    let incoming = listener.incoming().compat();
-    Ok(Incoming {
-        incoming,
-        local_addr,
-        ghost: PhantomData,
-    })
+    Incoming::new(TcpListener::bind(addr)?)
+}
+
+/// Listens on `addr` with `shards` sockets bound with `SO_REUSEPORT`, wrapping accepted
+/// connections in bincode transports.
+///
+/// The kernel spreads incoming connections across the sockets, so that each can be driven by its
+/// own task, with its own accept loop and channel filter, rather than funneling every connection
+/// through one, e.g. by [`run_sharded`](rpc::server::shard::run_sharded). Note that per-key channel
+/// limits then apply per shard. If `addr` has port 0, every shard listens on the port chosen for
+/// the first.
+#[cfg(unix)]
+pub fn listen_sharded<Item, SinkItem>(
+    addr: &SocketAddr,
+    shards: usize,
+) -> io::Result<Vec<Incoming<Item, SinkItem>>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    rpc::server::shard::bind_reuseport(addr, shards)?
+        .into_iter()
+        .map(|listener| {
+            Incoming::new(TcpListener::from_std(
+                listener,
+                &tokio_reactor::Handle::default(),
+            )?)
+        })
+        .collect()
 }
 
 /// A [`TcpListener`] that wraps connections in bincode transports.
@@ -163,6 +233,15 @@ pub struct Incoming<Item, SinkItem> {
 impl<Item, SinkItem> Incoming<Item, SinkItem> {
     unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
 
+    fn new(listener: TcpListener) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        Ok(Incoming {
+            incoming: listener.incoming().compat(),
+            local_addr,
+            ghost: PhantomData,
+        })
+    }
+
     /// Returns the address being listened on.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
@@ -235,4 +314,22 @@ mod tests {
             )
         );
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn sharded_listeners_accept_on_one_port() {
+        use futures::{executor::block_on, stream, StreamExt};
+
+        let mut shards =
+            super::listen_sharded::<String, String>(&"127.0.0.1:0".parse().unwrap(), 2).unwrap();
+        assert_eq!(shards.len(), 2);
+        let addr = shards[0].local_addr();
+        assert_eq!(shards[1].local_addr(), addr);
+
+        let _client = std::net::TcpStream::connect(addr).unwrap();
+        let second = shards.pop().unwrap();
+        let first = shards.pop().unwrap();
+        let mut incoming = stream::select(first, second);
+        assert_matches!(block_on(incoming.next()), Some(Ok(_)));
+    }
 }
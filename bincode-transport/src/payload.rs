@@ -0,0 +1,281 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Payloads that were serialized ahead of time, and the buffer that frames are serialized into.
+
+use bytes::Bytes;
+use serde::{
+    de::{self, Deserialize, Deserializer, Unexpected, Visitor},
+    Serialize, Serializer,
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Write},
+};
+
+/// A value already serialized as bincode, which a handler can return in place of the value.
+///
+/// A proxy that forwards responses it received from another server can respond with the bytes it
+/// received, rather than deserializing them only to serialize them again. It's encoded as a byte
+/// string, length-prefixed in bincode and hex-encoded in human-readable formats such as JSON, so
+/// a peer reads it back as a `Preserialized`, and [decodes](Preserialized::decode) the value from
+/// it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preserialized(Bytes);
+
+impl Preserialized {
+    /// Serializes `value` once, so that it can be written any number of times without being
+    /// serialized again.
+    pub fn new<T: Serialize>(value: &T) -> bincode::Result<Self> {
+        Ok(Preserialized(bincode::serialize(value)?.into()))
+    }
+
+    /// Deserializes the value the bytes encode.
+    pub fn decode<'a, T: Deserialize<'a>>(&'a self) -> bincode::Result<T> {
+        bincode::deserialize(&self.0)
+    }
+
+    /// Returns the serialized bytes.
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.0
+    }
+
+    /// Returns the serialized bytes.
+    pub fn into_bytes(self) -> Bytes {
+        self.0
+    }
+}
+
+impl From<Bytes> for Preserialized {
+    /// Wraps bytes that are the bincode encoding of some value.
+    fn from(bytes: Bytes) -> Self {
+        Preserialized(bytes)
+    }
+}
+
+impl Serialize for Preserialized {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            // Human-readable formats would otherwise write the bytes as an array of numbers.
+            serializer.collect_str(&Hex(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Preserialized {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(PreserializedVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(PreserializedVisitor)
+        }
+    }
+}
+
+struct PreserializedVisitor;
+
+impl<'de> Visitor<'de> for PreserializedVisitor {
+    type Value = Preserialized;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bincode-encoded bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Preserialized, E> {
+        Ok(Preserialized(Bytes::from(bytes)))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Preserialized, E> {
+        Ok(Preserialized(bytes.into()))
+    }
+
+    fn visit_str<E: de::Error>(self, hex: &str) -> Result<Preserialized, E> {
+        from_hex(hex)
+            .map(|bytes| Preserialized(bytes.into()))
+            .ok_or_else(|| E::invalid_value(Unexpected::Str(hex), &self))
+    }
+}
+
+/// Formats bytes as lowercase hex.
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let digit = |c: u8| (c as char).to_digit(16);
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Some((digit(pair[0])? << 4 | digit(pair[1])?) as u8))
+        .collect()
+}
+
+/// Serialized bytes, and the bytes to write between them: the length prefixes of frames.
+#[derive(Debug, Default)]
+pub(crate) struct Spliced {
+    pub(crate) buf: Vec<u8>,
+    /// The unwritten payloads, in order, each with the offset in `buf` at which it belongs.
+    pub(crate) splices: VecDeque<(usize, Bytes)>,
+    /// The length of `buf` and every payload.
+    len: usize,
+    /// The length past which serializing fails with [`OverLimit`], if any.
+    limit: Option<usize>,
+}
+
+impl Spliced {
+    pub(crate) fn new(buf: Vec<u8>) -> Self {
+        Spliced {
+            len: buf.len(),
+            buf,
+            splices: VecDeque::new(),
+            limit: None,
+        }
+    }
+
+    /// Returns the length of the serialized bytes and the payloads spliced between them.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    /// Inserts `payload` as the `index`th of the payloads, to be written at `offset` in `buf`.
+    /// The payloads must stay in order of offset.
+    pub(crate) fn insert(&mut self, index: usize, offset: usize, payload: Bytes) {
+        self.len += payload.len();
+        self.splices.insert(index, (offset, payload));
+    }
+
+    /// Removes everything after the first `len` serialized bytes and `splices` payloads.
+    pub(crate) fn truncate(&mut self, len: usize, splices: usize) {
+        while self.splices.len() > splices {
+            let (_, payload) = self.splices.pop_back().unwrap();
+            self.len -= payload.len();
+        }
+        self.len -= self.buf.len() - len;
+        self.buf.truncate(len);
+    }
+
+    /// Appends the encoding of `item`.
+    pub(crate) fn serialize<T: Serialize>(&mut self, item: &T) -> bincode::Result<()> {
+        bincode::serialize_into(SplicedWriter(self), item)
+    }
+
+    /// Like [`serialize`](Spliced::serialize), but fails with [`OverLimit`] as soon as the encoding
+    /// grows past `limit` bytes, having serialized no more than that of it.
+    pub(crate) fn serialize_within<T: Serialize>(
+        &mut self,
+        item: &T,
+        limit: usize,
+    ) -> bincode::Result<()> {
+        self.limit = Some(self.len.saturating_add(limit));
+        let result = self.serialize(item);
+        self.limit = None;
+        result
+    }
+}
+
+/// The error serializing an item whose encoding grew past the limit given to
+/// [`Spliced::serialize_within`].
+#[derive(Debug)]
+pub(crate) struct OverLimit;
+
+impl fmt::Display for OverLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("serialized item is over the limit")
+    }
+}
+
+impl std::error::Error for OverLimit {}
+
+impl OverLimit {
+    /// Returns true if `error` is an `OverLimit`.
+    pub(crate) fn is(error: &bincode::Error) -> bool {
+        match **error {
+            bincode::ErrorKind::Io(ref e) => e.get_ref().map_or(false, |e| e.is::<OverLimit>()),
+            _ => false,
+        }
+    }
+}
+
+/// Appends to the item being serialized.
+struct SplicedWriter<'a>(&'a mut Spliced);
+
+impl Write for SplicedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.0.limit {
+            Some(limit) if self.0.len + buf.len() > limit => {
+                Err(io::Error::new(io::ErrorKind::Other, OverLimit))
+            }
+            _ => {
+                self.0.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preserialized_round_trips() {
+        let payload = Preserialized::new(&(1u64, "x".repeat(64))).unwrap();
+        let encoded = bincode::serialize(&(7u32, payload.clone())).unwrap();
+        let (id, decoded): (u32, Preserialized) = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(id, 7);
+        assert_eq!(decoded, payload);
+        assert_eq!(
+            decoded.decode::<(u64, String)>().unwrap(),
+            (1, "x".repeat(64))
+        );
+        // It's encoded as a byte string, so peers can also read it as plain bytes.
+        let (_, bytes): (u32, Vec<u8>) = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(&bytes[..], &payload.as_bytes()[..]);
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = [0x00, 0x7f, 0xa5, 0xff];
+        let hex = Hex(&bytes).to_string();
+        assert_eq!(hex, "007fa5ff");
+        assert_eq!(from_hex(&hex).unwrap(), bytes);
+        assert_eq!(from_hex("7"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+
+    #[test]
+    fn truncate_drops_later_splices() {
+        let mut spliced = Spliced::default();
+        spliced.serialize(&1u64).unwrap();
+        spliced.serialize(&2u64).unwrap();
+        spliced.insert(0, 16, Bytes::from(vec![0; 64]));
+        spliced.truncate(8, 0);
+        assert!(spliced.splices.is_empty());
+        assert_eq!(spliced.len(), 8);
+        assert_eq!(spliced.buf, bincode::serialize(&1u64).unwrap());
+    }
+}
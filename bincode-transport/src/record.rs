@@ -0,0 +1,548 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Records the frames a transport sends and receives, and replays them to a peer.
+//!
+//! A [`Recorder`] wraps any transport whose frames are serializable, writing each frame, as
+//! bincode, along with when it was sent or received. [`read_recording`] reads the frames back,
+//! and a [`Replay`] transport stands in for the peer of the recorded transport, feeding it the
+//! frames of one direction. Replaying a client's received frames to a new client reproduces the
+//! responses of the server it talked to, without the server; replaying a server's received
+//! frames to a new server reproduces the requests of the clients it served. Frames are replayed
+//! as far apart as they were recorded, so timeouts fire as they did; tests replaying against a
+//! [virtual clock](rpc::testing::MockTime) needn't wait for them.
+//!
+//! ```ignore
+//! let recording = File::create("client.rec")?;
+//! let transport = Recorder::new(tarpc_bincode_transport::connect(&addr).await?, recording);
+//! // ... use the client, then later:
+//! let recording = read_recording(File::open("client.rec")?)?;
+//! let client = Client::new(config, Replay::new(&recording, Direction::Received)?).spawn()?;
+//! ```
+
+use futures::{
+    prelude::*,
+    task::{Context, Poll, Waker},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use rpc::runtime::{self, DefaultRuntime, Timer};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The direction a recorded frame traveled, relative to the recorded transport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// The frame was sent through the transport's sink.
+    Sent,
+    /// The frame was received from the transport's stream.
+    Received,
+}
+
+impl Direction {
+    fn to_u8(self) -> u8 {
+        match self {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        }
+    }
+
+    fn from_u8(direction: u8) -> io::Result<Self> {
+        match direction {
+            0 => Ok(Direction::Sent),
+            1 => Ok(Direction::Received),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid frame direction {}", direction),
+            )),
+        }
+    }
+}
+
+/// A frame read from a recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedFrame {
+    /// The time from the recorder being created to the frame being sent or received.
+    pub elapsed: Duration,
+    /// Whether the frame was sent or received.
+    pub direction: Direction,
+    /// The bincode encoding of the frame.
+    pub frame: Vec<u8>,
+}
+
+impl RecordedFrame {
+    /// Deserializes the frame.
+    pub fn decode<T: DeserializeOwned>(&self) -> io::Result<T> {
+        bincode::deserialize(&self.frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Reads the frames recorded by a [`Recorder`], in the order they were sent and received. A
+/// frame cut off by the recording ending is ignored.
+pub fn read_recording<R: Read>(reader: R) -> io::Result<Vec<RecordedFrame>> {
+    let mut reader = io::BufReader::new(reader);
+    let mut frames = vec![];
+    loop {
+        let (elapsed, direction, frame): (Duration, u8, Vec<u8>) =
+            match bincode::deserialize_from(&mut reader) {
+                Ok(record) => record,
+                Err(e) => match *e {
+                    bincode::ErrorKind::Io(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Ok(frames)
+                    }
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+                },
+            };
+        frames.push(RecordedFrame {
+            elapsed,
+            direction: Direction::from_u8(direction)?,
+            frame,
+        });
+    }
+}
+
+/// Wraps a transport, recording every frame it sends and receives to a writer.
+///
+/// Frames are written as they pass through, and the writer is flushed whenever the transport is,
+/// so the writer should be buffered, and shouldn't block for long: a file is fine, but a slow
+/// network connection stalls the transport.
+///
+/// A frame that can't be recorded still passes through, and the error is returned by the next
+/// poll of the transport's stream or sink. Nothing more is recorded after, so the recording ends
+/// at the last frame recorded whole.
+pub struct Recorder<T, W> {
+    inner: T,
+    writer: W,
+    timer: Arc<dyn Timer>,
+    start: Instant,
+    /// The error recording a frame failed with, until it's returned.
+    error: Option<io::Error>,
+    /// Whether recording a frame failed.
+    failed: bool,
+}
+
+impl<T, W> Recorder<T, W> {
+    unsafe_pinned!(inner: T);
+    unsafe_unpinned!(error: Option<io::Error>);
+
+    /// Returns a transport that records the frames of `transport` to `writer`, timing them with
+    /// the [default runtime](DefaultRuntime).
+    pub fn new(transport: T, writer: W) -> Self {
+        Self::with_timer(transport, writer, Arc::new(DefaultRuntime::default()))
+    }
+
+    /// Returns a transport that records the frames of `transport` to `writer`, timing them with
+    /// `timer`.
+    pub fn with_timer(transport: T, writer: W, timer: Arc<dyn Timer>) -> Self {
+        Recorder {
+            inner: transport,
+            writer,
+            start: timer.now(),
+            timer,
+            error: None,
+            failed: false,
+        }
+    }
+
+    /// Returns the wrapped transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the writer the frames are recorded to.
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    /// Records a frame, unless recording already failed.
+    fn record<F: Serialize>(self: Pin<&mut Self>, direction: Direction, frame: &F)
+    where
+        W: Write,
+    {
+        // Safe because none of the fields used are pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        if this.failed {
+            return;
+        }
+        let to_io_error = |e| io::Error::new(io::ErrorKind::Other, e);
+        let elapsed = this.timer.now() - this.start;
+        let recorded = bincode::serialize(frame)
+            .and_then(|frame| {
+                bincode::serialize_into(&mut this.writer, &(elapsed, direction.to_u8(), frame))
+            })
+            .map_err(to_io_error);
+        if let Err(e) = recorded {
+            this.failed = true;
+            this.error = Some(e);
+        }
+    }
+
+    /// Returns the error recording a frame failed with, if it's yet to be returned.
+    fn take_error(self: Pin<&mut Self>) -> io::Result<()> {
+        match self.error().take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the writer, with mutable access to it, while the transport remains pinned.
+    fn writer_mut(self: Pin<&mut Self>) -> &mut W {
+        // Safe because the writer isn't pinned.
+        unsafe { &mut self.get_unchecked_mut().writer }
+    }
+}
+
+impl<T, W, Item> Stream for Recorder<T, W>
+where
+    T: Stream<Item = io::Result<Item>>,
+    W: Write,
+    Item: Serialize,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+        self.as_mut().take_error()?;
+        let next = futures::ready!(self.as_mut().inner().poll_next(cx));
+        if let Some(Ok(ref item)) = next {
+            self.record(Direction::Received, item);
+        }
+        Poll::Ready(next)
+    }
+}
+
+impl<T, W, SinkItem> Sink<SinkItem> for Recorder<T, W>
+where
+    T: Sink<SinkItem, Error = io::Error>,
+    W: Write,
+    SinkItem: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.as_mut().take_error()?;
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        self.as_mut().record(Direction::Sent, &item);
+        self.inner().start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.as_mut().take_error()?;
+        futures::ready!(self.as_mut().inner().poll_flush(cx))?;
+        Poll::Ready(self.writer_mut().flush())
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.as_mut().take_error()?;
+        futures::ready!(self.as_mut().inner().poll_close(cx))?;
+        Poll::Ready(self.writer_mut().flush())
+    }
+}
+
+impl<T: fmt::Debug, W> fmt::Debug for Recorder<T, W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("inner", &self.inner)
+            .field("start", &self.start)
+            .field("failed", &self.failed)
+            .finish()
+    }
+}
+
+/// A transport that plays the peer of a recorded transport, yielding the frames the recorded
+/// transport sent or received in one direction.
+///
+/// Each frame is yielded once as many frames have been sent to the replay as the recorded
+/// transport had traveling the other way before it, so a replayed response isn't received before
+/// the request it answers is sent. It's yielded as long after the frame recorded just before it
+/// as it was recorded after that frame: after the frame it waits for is sent, or after the frame
+/// yielded before it. Frames sent to the replay are otherwise discarded. Once every frame is
+/// yielded, the replay's stream ends, closing the connection.
+pub struct Replay<Item> {
+    frames: VecDeque<ReplayedFrame<Item>>,
+    /// When each frame sent to the replay that a frame waits for was sent.
+    sent: Vec<Instant>,
+    /// The number of frames sent to the replay.
+    sent_count: usize,
+    /// The most frames any frame waits for.
+    waits_for: usize,
+    /// When the last frame was yielded, or the replay was first polled.
+    yielded: Option<Instant>,
+    timer: Arc<dyn Timer>,
+    /// Wakes the replay when the next frame is due.
+    delay: Option<runtime::Delay>,
+    /// Woken when a frame is sent.
+    waker: Option<Waker>,
+}
+
+/// A frame to be yielded by a [`Replay`].
+struct ReplayedFrame<Item> {
+    /// The number of frames sent before the frame was recorded.
+    after: usize,
+    /// Whether the frame recorded just before it was sent, rather than yielded, by the replay.
+    follows_sent: bool,
+    /// How long after the frame recorded just before it the frame was recorded.
+    delay: Duration,
+    item: Item,
+}
+
+impl<Item: DeserializeOwned> Replay<Item> {
+    /// Returns a transport yielding the frames of `recording` that traveled in `direction`,
+    /// timing them with the [default runtime](DefaultRuntime).
+    pub fn new(recording: &[RecordedFrame], direction: Direction) -> io::Result<Self> {
+        Self::with_timer(recording, direction, Arc::new(DefaultRuntime::default()))
+    }
+
+    /// Returns a transport yielding the frames of `recording` that traveled in `direction`,
+    /// timing them with `timer`.
+    pub fn with_timer(
+        recording: &[RecordedFrame],
+        direction: Direction,
+        timer: Arc<dyn Timer>,
+    ) -> io::Result<Self> {
+        let mut frames = VecDeque::new();
+        let mut other = 0;
+        let mut previous: Option<&RecordedFrame> = None;
+        for frame in recording {
+            if frame.direction == direction {
+                frames.push_back(ReplayedFrame {
+                    after: other,
+                    follows_sent: previous.map_or(false, |p| p.direction != direction),
+                    delay: previous.map_or(frame.elapsed, |p| {
+                        frame.elapsed.checked_sub(p.elapsed).unwrap_or_default()
+                    }),
+                    item: frame.decode()?,
+                });
+            } else {
+                other += 1;
+            }
+            previous = Some(frame);
+        }
+        Ok(Replay {
+            waits_for: frames.back().map_or(0, |frame| frame.after),
+            frames,
+            sent: vec![],
+            sent_count: 0,
+            yielded: None,
+            timer,
+            delay: None,
+            waker: None,
+        })
+    }
+}
+
+impl<Item> Replay<Item> {
+    /// Returns the number of frames yet to be yielded.
+    pub fn remaining(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+impl<Item> Unpin for Replay<Item> {}
+
+impl<Item> Stream for Replay<Item> {
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+        let this = &mut *self;
+        let now = this.timer.now();
+        let yielded = *this.yielded.get_or_insert(now);
+        let due = match this.frames.front() {
+            None => return Poll::Ready(None),
+            Some(frame) if frame.after > this.sent_count => {
+                this.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            Some(frame) if frame.follows_sent => this.sent[frame.after - 1] + frame.delay,
+            Some(frame) => yielded + frame.delay,
+        };
+        if now < due {
+            let timer = &this.timer;
+            let delay = this.delay.get_or_insert_with(|| timer.delay(due));
+            futures::ready!(Pin::new(delay).poll(cx));
+        }
+        this.delay = None;
+        this.yielded = Some(this.timer.now());
+        Poll::Ready(this.frames.pop_front().map(|frame| Ok(frame.item)))
+    }
+}
+
+impl<Item, SinkItem> Sink<SinkItem> for Replay<Item> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, _: SinkItem) -> io::Result<()> {
+        if self.sent_count < self.waits_for {
+            let now = self.timer.now();
+            self.sent.push(now);
+        }
+        self.sent_count += 1;
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Item> fmt::Debug for Replay<Item> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Replay")
+            .field("remaining", &self.frames.len())
+            .field("sent", &self.sent_count)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures::{executor::block_on, task::noop_waker_ref};
+    use rpc::{testing::MockTime, transport::channel};
+
+    #[test]
+    fn replays_recorded_frames_in_causal_order_and_time() -> io::Result<()> {
+        let ms = Duration::from_millis;
+        let mut time = MockTime::new();
+        let (client, mut server) = channel::unbounded::<u32, String>();
+        let timer = time.timer();
+        let mut client = time.enter(|| Recorder::with_timer(client, vec![], timer));
+        time.block_on(client.send("one".to_string()))?;
+        time.advance(ms(10));
+        time.block_on(async {
+            server.send(1).await?;
+            server.send(11).await?;
+            assert_eq!(client.next().await.unwrap()?, 1);
+            assert_eq!(client.next().await.unwrap()?, 11);
+            Ok::<_, io::Error>(())
+        })?;
+        time.advance(ms(5));
+        time.block_on(client.send("two".to_string()))?;
+        time.advance(ms(20));
+        time.block_on(async {
+            server.send(2).await?;
+            assert_eq!(client.next().await.unwrap()?, 2);
+            Ok::<_, io::Error>(())
+        })?;
+
+        let recording = read_recording(&client.writer()[..])?;
+        use Direction::*;
+        let frames: Vec<_> = recording
+            .iter()
+            .map(|frame| (frame.direction, frame.elapsed))
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                (Sent, ms(0)),
+                (Received, ms(10)),
+                (Received, ms(10)),
+                (Sent, ms(15)),
+                (Received, ms(35))
+            ]
+        );
+        assert_eq!(recording[0].decode::<String>()?, "one");
+
+        let mut time = MockTime::new();
+        let timer = time.timer();
+        let mut replay = time.enter(|| Replay::<u32>::with_timer(&recording, Received, timer))?;
+        assert_eq!(replay.remaining(), 3);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(time.enter(|| replay.poll_next_unpin(&mut cx)).is_pending());
+        // Timers fire on the millisecond tick after they're due.
+        let assert_next = |time: &mut MockTime, replay: &mut Replay<u32>, frame: u32, after| {
+            let start = time.now();
+            assert_eq!(time.block_on(replay.next()).unwrap()?, frame);
+            let took = time.now() - start;
+            assert!(took >= after && took <= after + ms(1), "{:?}", took);
+            Ok::<_, io::Error>(())
+        };
+        time.advance(ms(100));
+        time.block_on(replay.send("one".to_string()))?;
+        assert_next(&mut time, &mut replay, 1, ms(10))?;
+        assert_next(&mut time, &mut replay, 11, ms(0))?;
+        // The response to "two" waits for it to be sent.
+        assert!(time.enter(|| replay.poll_next_unpin(&mut cx)).is_pending());
+        time.advance(ms(3));
+        time.block_on(replay.send("two".to_string()))?;
+        assert_next(&mut time, &mut replay, 2, ms(20))?;
+        assert!(time.block_on(replay.next()).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_recording_ends_at_last_whole_frame() -> io::Result<()> {
+        let (transport, _peer) = channel::unbounded::<u32, u32>();
+        let mut recorder = Recorder::new(transport, vec![]);
+        block_on(async {
+            recorder.send(1).await?;
+            recorder.send(2).await
+        })?;
+        let written = recorder.writer();
+        let recording = read_recording(&written[..written.len() - 1])?;
+        assert_eq!(recording.len(), 1);
+        assert_eq!(recording[0].decode::<u32>()?, 1);
+        Ok(())
+    }
+
+    /// A writer whose writes all fail.
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_errors_are_returned_without_losing_frames() -> io::Result<()> {
+        let (client, mut server) = channel::unbounded::<u32, u32>();
+        let mut client = Recorder::new(client, FullDisk);
+        block_on(async {
+            server.send(1).await?;
+            assert_eq!(client.next().await.unwrap()?, 1);
+            assert_matches!(
+                client.next().await,
+                Some(Err(ref e)) if e.to_string() == "disk full"
+            );
+            // Nothing more is recorded, so nothing more fails.
+            client.send(2).await?;
+            assert_eq!(server.next().await.unwrap()?, 2);
+
+            let (client, mut server) = channel::unbounded::<u32, u32>();
+            let mut client = Recorder::new(client, FullDisk);
+            assert_matches!(
+                client.send(1).await,
+                Err(ref e) if e.to_string() == "disk full"
+            );
+            assert_eq!(server.next().await.unwrap()?, 1);
+            Ok(())
+        })
+    }
+}
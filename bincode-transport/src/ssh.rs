@@ -0,0 +1,229 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Connects to services over SSH, for reaching machines whose services' ports aren't exposed.
+//!
+//! [`connect_via_ssh`] runs the system's `ssh` client with `-W`, which logs in to the SSH server
+//! and opens a `direct-tcpip` channel from it to the service, forwarding the client's standard
+//! input and output over the channel. The transport reads from and writes to the client, so
+//! requests reach the service as if from the SSH server:
+//!
+//! ```ignore
+//! let transport = connect_via_ssh(
+//!     "ops@bastion.example.com",
+//!     &SshConfig::default(),
+//!     "localhost:5000",
+//! )
+//! .await?;
+//! let mut client = WorldClient::new(client::Config::default(), transport).spawn()?;
+//! ```
+//!
+//! Authentication, host keys, and the rest of the connection are up to `ssh`, configured as
+//! usual by `~/.ssh/config`, with keys from `ssh-agent`, and so on, or by [`SshConfig`]. `ssh`
+//! reads passwords and passphrases from the terminal, if there is one, rather than from the
+//! tunnel; set `BatchMode` to fail instead of asking. Its errors are written to standard error.
+
+use crate::Transport;
+use futures_legacy::Poll;
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_process::{Child, ChildStdin, ChildStdout, CommandExt};
+
+/// How to run the `ssh` client. The defaults leave everything to the user's ssh configuration.
+#[derive(Clone, Debug)]
+pub struct SshConfig {
+    /// The `ssh` client to run. Defaults to the `ssh` on the `PATH`.
+    pub program: PathBuf,
+    /// The user to log in as.
+    pub user: Option<String>,
+    /// The port the SSH server listens on.
+    pub port: Option<u16>,
+    /// The private key to authenticate with.
+    pub identity_file: Option<PathBuf>,
+    /// The configuration file to read, instead of `~/.ssh/config`.
+    pub config_file: Option<PathBuf>,
+    /// Other options, as `ssh_config` keywords and their values, e.g.
+    /// `("ConnectTimeout", "10")`.
+    pub options: Vec<(String, String)>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        SshConfig {
+            program: "ssh".into(),
+            user: None,
+            port: None,
+            identity_file: None,
+            config_file: None,
+            options: vec![],
+            _non_exhaustive: (),
+        }
+    }
+}
+
+impl SshConfig {
+    /// Returns the arguments that make `ssh` log in to `host` and forward its standard input
+    /// and output to `remote_addr`.
+    fn args(&self, host: &str, remote_addr: &str) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![];
+        if let Some(ref user) = self.user {
+            args.extend(vec!["-l".into(), user.into()]);
+        }
+        if let Some(port) = self.port {
+            args.extend(vec!["-p".into(), port.to_string().into()]);
+        }
+        if let Some(ref identity_file) = self.identity_file {
+            args.extend(vec!["-i".into(), identity_file.into()]);
+        }
+        if let Some(ref config_file) = self.config_file {
+            args.extend(vec!["-F".into(), config_file.into()]);
+        }
+        for (keyword, value) in &self.options {
+            args.extend(vec!["-o".into(), format!("{}={}", keyword, value).into()]);
+        }
+        // `-W` implies `ExitOnForwardFailure`, so `ssh` exits if the channel can't be opened.
+        args.extend(vec!["-W".into(), remote_addr.into()]);
+        // Keeps a host starting with `-` from being read as an option.
+        args.extend(vec!["--".into(), host.into()]);
+        args
+    }
+}
+
+/// The standard input and output of an `ssh` client forwarding them to a service. The client
+/// is killed when the stream is dropped.
+#[derive(Debug)]
+pub struct SshStream {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl SshStream {
+    fn spawn(command: &mut Command) -> io::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn_async()?;
+        let stdin = child.stdin().take().unwrap();
+        let stdout = child.stdout().take().unwrap();
+        Ok(SshStream {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    /// Returns the process ID of the `ssh` client.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+}
+
+impl Read for SshStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl AsyncRead for SshStream {}
+
+impl Write for SshStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.flush()
+    }
+}
+
+impl AsyncWrite for SshStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.stdin.shutdown()
+    }
+}
+
+/// Logs in to the SSH server `host`, e.g. `bastion` or `ops@10.0.0.7`, and connects through it
+/// to `remote_addr`, an address as seen from the server, e.g. `localhost:5000`, wrapping the
+/// connection in a bincode transport.
+///
+/// The transport is returned once `ssh` starts. If it then fails to log in or to reach
+/// `remote_addr`, it exits, and the transport's stream ends.
+pub async fn connect_via_ssh<Item, SinkItem>(
+    host: &str,
+    ssh_config: &SshConfig,
+    remote_addr: &str,
+) -> io::Result<Transport<SshStream, Item, SinkItem>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let mut command = Command::new(&ssh_config.program);
+    command.args(ssh_config.args(host, remote_addr));
+    Ok(Transport::from(SshStream::spawn(&mut command)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SshConfig, SshStream};
+    use crate::Transport;
+    use futures::{executor::block_on, prelude::*};
+    use std::{ffi::OsString, io, process::Command};
+
+    #[test]
+    fn ssh_forwards_stdio_to_the_remote_addr() {
+        let args = SshConfig::default().args("bastion", "localhost:5000");
+        assert_eq!(args, ["-W", "localhost:5000", "--", "bastion"]);
+
+        let config = SshConfig {
+            user: Some("ops".into()),
+            port: Some(2222),
+            identity_file: Some("/keys/ops".into()),
+            options: vec![("BatchMode".into(), "yes".into())],
+            ..SshConfig::default()
+        };
+        let args: Vec<OsString> = config.args("bastion", "[::1]:5000");
+        assert_eq!(
+            args,
+            [
+                "-l",
+                "ops",
+                "-p",
+                "2222",
+                "-i",
+                "/keys/ops",
+                "-o",
+                "BatchMode=yes",
+                "-W",
+                "[::1]:5000",
+                "--",
+                "bastion"
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transport_runs_over_the_clients_stdio() -> io::Result<()> {
+        // `cat` stands in for an ssh client connected to an echo server.
+        let stream = SshStream::spawn(&mut Command::new("cat"))?;
+        let mut transport = Transport::<_, String, String>::from(stream);
+        block_on(async {
+            transport.send("Test one, check check.".into()).await?;
+            let echoed = transport.next().await.unwrap()?;
+            assert_eq!(echoed, "Test one, check check.");
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,543 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serves and connects over mutual TLS, with server certificates rotated while the server runs.
+//!
+//! A [`CertResolver`] holds the certificate the server presents, and [`server_config`] builds a
+//! rustls config that presents it and requires clients to present certificates signed by one of
+//! the given roots. The resolver swaps in a new certificate when [told to](CertResolver::set),
+//! or when the files it was loaded from change, if [watched](CertResolver::watch); connections
+//! accepted after a swap are served with the new certificate, and connections already open
+//! keep theirs:
+//!
+//! ```ignore
+//! let stats = Stats::new();
+//! let resolver = Arc::new(
+//!     CertResolver::from_files("/etc/tarpc/cert.pem", "/etc/tarpc/key.pem")?.with_stats(&stats),
+//! );
+//! let _watch = resolver.watch(Duration::from_secs(30))?;
+//! let acceptor = TlsAcceptor::from(Arc::new(tls::server_config(resolver, client_roots)));
+//! let connections = TcpListener::bind(&addr)?
+//!     .incoming()
+//!     .compat()
+//!     .and_then(|conn| tls::accept(&acceptor, conn))
+//!     .filter_map(|conn| async { conn.ok() })
+//!     .map(|(transport, peer)| {
+//!         server::Config::default()
+//!             .channel(transport)
+//!             .with_peer_identity(peer)
+//!     });
+//! ```
+//!
+//! Clients present their own certificate and verify the server's with a [`client_config`], and
+//! [`connect`] with it:
+//!
+//! ```ignore
+//! let config = tls::client_config(client_chain, client_key, server_roots)?;
+//! let connector = TlsConnector::from(Arc::new(config));
+//! let (transport, server) = tls::connect(&connector, &addr, "tarpc.example.com").await?;
+//! let client = client::new(client::Config::default(), transport).spawn()?;
+//! ```
+//!
+//! The serial of the certificate being served is kept in the server [`Stats`]' info, as
+//! [`CERT_SERIAL_INFO`], and each peer's certificate becomes its [`PeerIdentity`].
+
+use crate::Transport;
+use futures::compat::*;
+use rpc::{
+    context::{AuthMethod, PeerIdentity},
+    event::{Event, EventSink, LogSink},
+    server::Stats,
+};
+use rustls::{
+    internal::pemfile,
+    sign::{self, CertifiedKey},
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, ClientHello, PrivateKey,
+    ResolvesServerCert, RootCertStore, ServerConfig, Session,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::{client, server, webpki::DNSNameRef, TlsAcceptor, TlsConnector};
+use tokio_tcp::TcpStream;
+
+/// The key of the [server info](Stats::info) holding the serial of the certificate being
+/// served, in hex.
+pub const CERT_SERIAL_INFO: &str = "tls_cert_serial";
+
+/// Resolves the certificate a server presents, which can be swapped while the server runs.
+#[derive(Debug)]
+pub struct CertResolver {
+    active: RwLock<ActiveCert>,
+    /// The files the certificate was loaded from, if any.
+    files: Option<(PathBuf, PathBuf)>,
+    stats: Option<Stats>,
+    events: Arc<dyn EventSink>,
+}
+
+struct ActiveCert {
+    key: CertifiedKey,
+    serial: String,
+    /// When the files were last modified, when the certificate was loaded from them.
+    modified: Option<(SystemTime, SystemTime)>,
+}
+
+impl fmt::Debug for ActiveCert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ActiveCert")
+            .field("serial", &self.serial)
+            .finish()
+    }
+}
+
+impl CertResolver {
+    /// Returns a resolver presenting the certificate `chain`, leaf first, signed by `key`.
+    pub fn new(chain: Vec<Certificate>, key: PrivateKey) -> io::Result<Self> {
+        Ok(CertResolver {
+            active: RwLock::new(ActiveCert::new(chain, key, None)?),
+            files: None,
+            stats: None,
+            events: Arc::new(LogSink),
+        })
+    }
+
+    /// Returns a resolver presenting the certificate chain in the PEM file `cert_path`, signed by
+    /// the PKCS #8 or RSA key in the PEM file `key_path`. [`reload`](CertResolver::reload)
+    /// reads the files again.
+    pub fn from_files(
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> io::Result<Self> {
+        let files = (cert_path.into(), key_path.into());
+        let active = read_files(&files.0, &files.1)?;
+        Ok(CertResolver {
+            active: RwLock::new(active),
+            files: Some(files),
+            stats: None,
+            events: Arc::new(LogSink),
+        })
+    }
+
+    /// Keeps the serial of the certificate being served in the info of `stats`, as
+    /// [`CERT_SERIAL_INFO`].
+    pub fn with_stats(mut self, stats: &Stats) -> Self {
+        stats.set_info(CERT_SERIAL_INFO, self.serial());
+        self.stats = Some(stats.clone());
+        self
+    }
+
+    /// Reports the failed reloads of [watched](CertResolver::watch) files to `events`, rather
+    /// than logging them.
+    pub fn with_events(mut self, events: Arc<dyn EventSink>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Returns the serial of the certificate being served, in hex.
+    pub fn serial(&self) -> String {
+        self.active.read().unwrap().serial.clone()
+    }
+
+    /// Serves new connections with the certificate `chain`, leaf first, signed by `key`. If the
+    /// resolver was loaded from files, it's served until they next change.
+    pub fn set(&self, chain: Vec<Certificate>, key: PrivateKey) -> io::Result<()> {
+        let modified = self.active.read().unwrap().modified;
+        self.activate(ActiveCert::new(chain, key, modified)?);
+        Ok(())
+    }
+
+    /// Reads the certificate and key files again, if they changed since they were last read,
+    /// and serves new connections with the certificate they hold. Returns whether they changed.
+    ///
+    /// If the files can't be read or don't hold a valid certificate and key, e.g. because
+    /// they're being rewritten, the certificate being served is kept.
+    pub fn reload(&self) -> io::Result<bool> {
+        let (cert_path, key_path) = match self.files {
+            Some((ref cert_path, ref key_path)) => (cert_path, key_path),
+            None => return Ok(false),
+        };
+        let modified = Some(modified(cert_path, key_path)?);
+        if modified == self.active.read().unwrap().modified {
+            return Ok(false);
+        }
+        self.activate(read_files(cert_path, key_path)?);
+        Ok(true)
+    }
+
+    /// Checks every `interval` whether the certificate and key files changed, and if so,
+    /// [reloads](CertResolver::reload) them. Watching stops as soon as the returned handle is
+    /// dropped.
+    ///
+    /// Failed reloads are [reported](CertResolver::with_events) and retried at the next check.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> io::Result<Watch> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let resolver = self.clone();
+        let thread = thread::Builder::new()
+            .name("tarpc-tls-watch".into())
+            .spawn(move || {
+                // Nothing is ever sent; dropping the sender wakes the thread to stop.
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(error) = resolver.reload() {
+                        resolver
+                            .events
+                            .event(&Event::CertReloadFailed { error: &error });
+                    }
+                }
+            })?;
+        Ok(Watch {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    fn activate(&self, active: ActiveCert) {
+        if let Some(ref stats) = self.stats {
+            stats.set_info(CERT_SERIAL_INFO, active.serial.clone());
+        }
+        *self.active.write().unwrap() = active;
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.active.read().unwrap().key.clone())
+    }
+}
+
+impl ActiveCert {
+    fn new(
+        chain: Vec<Certificate>,
+        key: PrivateKey,
+        modified: Option<(SystemTime, SystemTime)>,
+    ) -> io::Result<Self> {
+        let serial = match chain.first() {
+            Some(leaf) => serial(leaf)?,
+            None => return Err(invalid_data("The certificate chain is empty.")),
+        };
+        let key = sign::any_supported_type(&key)
+            .map_err(|()| invalid_data("The private key isn't of a supported type."))?;
+        Ok(ActiveCert {
+            key: CertifiedKey::new(chain, Arc::new(key)),
+            serial,
+            modified,
+        })
+    }
+}
+
+/// Stops [watching](CertResolver::watch) certificate files when dropped.
+#[derive(Debug)]
+pub struct Watch {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Watch {
+    /// Waits for the watching thread to exit, and so to release the resolver, which it does at
+    /// once unless it's reloading.
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Returns a server config that presents the certificates of `resolver` and requires clients to
+/// present certificates signed by one of `client_roots`.
+pub fn server_config(resolver: Arc<CertResolver>, client_roots: RootCertStore) -> ServerConfig {
+    let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(client_roots));
+    config.cert_resolver = resolver;
+    config
+}
+
+/// Returns a client config that presents the certificate `chain`, leaf first, signed by `key`,
+/// and requires servers to present certificates signed by one of `server_roots`.
+pub fn client_config(
+    chain: Vec<Certificate>,
+    key: PrivateKey,
+    server_roots: RootCertStore,
+) -> io::Result<ClientConfig> {
+    let mut config = ClientConfig::new();
+    config.root_store = server_roots;
+    config
+        .set_single_client_cert(chain, key)
+        .map_err(|e| invalid_data(&format!("Invalid client certificate: {}", e)))?;
+    Ok(config)
+}
+
+/// Completes the TLS handshake of `conn` with `acceptor`, returning a bincode transport over the
+/// TLS stream and the identity of the client, from the certificate it presented.
+///
+/// The identity's principal is the certificate's subject, and its `serial` attribute is the
+/// certificate's serial, in hex.
+pub async fn accept<Item, SinkItem>(
+    acceptor: &TlsAcceptor,
+    conn: TcpStream,
+) -> io::Result<(
+    Transport<server::TlsStream<TcpStream>, Item, SinkItem>,
+    PeerIdentity,
+)>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let stream = acceptor.accept(conn).compat().await?;
+    let identity = match stream.get_ref().1.get_peer_certificates() {
+        Some(ref certs) if !certs.is_empty() => peer_identity(&certs[0])?,
+        // Unreachable with a config that requires client certificates.
+        _ => return Err(invalid_data("The client presented no certificate.")),
+    };
+    Ok((Transport::from(stream), identity))
+}
+
+/// Connects to `addr` and completes a TLS handshake with `connector`, verifying that the server's
+/// certificate is valid for `domain`. Returns a bincode transport over the TLS stream and the
+/// identity of the server, from its certificate, like [`accept`]'s of the client.
+pub async fn connect<Item, SinkItem>(
+    connector: &TlsConnector,
+    addr: &SocketAddr,
+    domain: &str,
+) -> io::Result<(
+    Transport<client::TlsStream<TcpStream>, Item, SinkItem>,
+    PeerIdentity,
+)>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    let domain = DNSNameRef::try_from_ascii_str(domain)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid server domain."))?;
+    let conn = TcpStream::connect(addr).compat().await?;
+    let stream = connector.connect(domain, conn).compat().await?;
+    let identity = match stream.get_ref().1.get_peer_certificates() {
+        Some(ref certs) if !certs.is_empty() => peer_identity(&certs[0])?,
+        // Unreachable: the handshake fails unless the server presents a valid certificate.
+        _ => return Err(invalid_data("The server presented no certificate.")),
+    };
+    Ok((Transport::from(stream), identity))
+}
+
+/// Returns the identity of the holder of `cert`.
+fn peer_identity(cert: &Certificate) -> io::Result<PeerIdentity> {
+    let (_, parsed) = parse(cert)?;
+    let tbs = &parsed.tbs_certificate;
+    Ok(
+        PeerIdentity::new(tbs.subject.to_string(), AuthMethod::TlsCertificate)
+            .with_attribute("serial", tbs.serial.to_str_radix(16)),
+    )
+}
+
+/// Returns the serial of `cert`, in hex.
+fn serial(cert: &Certificate) -> io::Result<String> {
+    let (_, parsed) = parse(cert)?;
+    Ok(parsed.tbs_certificate.serial.to_str_radix(16))
+}
+
+fn parse(cert: &Certificate) -> io::Result<(&[u8], x509_parser::X509Certificate)> {
+    x509_parser::parse_x509_der(&cert.0)
+        .map_err(|_| invalid_data("The certificate isn't valid DER."))
+}
+
+fn read_files(cert_path: &Path, key_path: &Path) -> io::Result<ActiveCert> {
+    // Read before the files, so that a change made while they're read is seen by the next reload.
+    let modified = modified(cert_path, key_path)?;
+    let chain = pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|()| invalid_data("The certificate file isn't valid PEM."))?;
+    let key = read_key(key_path)?;
+    ActiveCert::new(chain, key, Some(modified))
+}
+
+fn read_key(key_path: &Path) -> io::Result<PrivateKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|()| invalid_data("The key file isn't valid PEM."))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|()| invalid_data("The key file isn't valid PEM."))?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| invalid_data("The key file holds no private key."))
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> io::Result<(SystemTime, SystemTime)> {
+    Ok((
+        fs::metadata(cert_path)?.modified()?,
+        fs::metadata(key_path)?.modified()?,
+    ))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, client_config, connect, server_config, CertResolver, CERT_SERIAL_INFO};
+    use crate::Transport;
+    use futures::{compat::*, executor::block_on, future, prelude::*};
+    use rpc::{context::PeerIdentity, server::Stats};
+    use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+    use std::{fs, io, net::SocketAddr, path::Path, sync::Arc, time::Duration};
+    use tokio_rustls::{TlsAcceptor, TlsConnector};
+    use tokio_tcp::{TcpListener, TcpStream};
+
+    /// Returns a self-signed certificate with `serial`, as PEM, and its key.
+    fn cert(serial: u64) -> (String, String) {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".into()]);
+        params.serial_number = Some(serial);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (
+            cert.serialize_pem().unwrap(),
+            cert.serialize_private_key_pem(),
+        )
+    }
+
+    /// Returns a certificate authority and the roots that trust it.
+    fn ca() -> (rcgen::Certificate, RootCertStore) {
+        let mut params = rcgen::CertificateParams::new(vec![]);
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = rcgen::Certificate::from_params(params).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        (ca, roots)
+    }
+
+    /// Returns a certificate for `name` with `serial`, signed by `ca`, and its key.
+    fn signed_cert(
+        ca: &rcgen::Certificate,
+        name: &str,
+        serial: u64,
+    ) -> (Vec<Certificate>, PrivateKey) {
+        let mut params = rcgen::CertificateParams::new(vec![name.into()]);
+        params.serial_number = Some(serial);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        (
+            vec![Certificate(cert.serialize_der_with_signer(ca).unwrap())],
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    /// Connects to the listener at `addr` and accepts the connection from `incoming`, returning
+    /// the server's view of the client and the client's view of the server.
+    fn handshake(
+        incoming: &mut (impl Stream<Item = io::Result<TcpStream>> + Unpin),
+        addr: &SocketAddr,
+        acceptor: &TlsAcceptor,
+        connector: &TlsConnector,
+    ) -> (io::Result<PeerIdentity>, io::Result<PeerIdentity>) {
+        let server = async {
+            let conn = incoming.next().await.unwrap()?;
+            let (_, client): (Transport<_, (), ()>, _) = accept(acceptor, conn).await?;
+            Ok::<_, io::Error>(client)
+        };
+        let client = async {
+            let (_, server): (Transport<_, (), ()>, _) =
+                connect(connector, addr, "localhost").await?;
+            Ok::<_, io::Error>(server)
+        };
+        block_on(future::join(server, client))
+    }
+
+    #[test]
+    fn authenticates_both_ends() {
+        let (ca, roots) = ca();
+        let (chain, key) = signed_cert(&ca, "localhost", 1);
+        let resolver = Arc::new(CertResolver::new(chain, key).unwrap());
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(resolver.clone(), roots.clone())));
+        let (chain, key) = signed_cert(&ca, "client", 0xc1);
+        let connector =
+            TlsConnector::from(Arc::new(client_config(chain, key, roots.clone()).unwrap()));
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut incoming = listener.incoming().compat();
+
+        let (client, server) = handshake(&mut incoming, &addr, &acceptor, &connector);
+        let (client, server) = (client.unwrap(), server.unwrap());
+        assert_eq!(client.principal, "CN=client");
+        assert_eq!(client.attribute("serial"), Some("c1"));
+        assert_eq!(server.principal, "CN=localhost");
+        assert_eq!(server.attribute("serial"), Some("1"));
+
+        // New connections are served with the new certificate.
+        let (chain, key) = signed_cert(&ca, "localhost", 2);
+        resolver.set(chain, key).unwrap();
+        let (client, server) = handshake(&mut incoming, &addr, &acceptor, &connector);
+        assert_eq!(client.unwrap().attribute("serial"), Some("c1"));
+        assert_eq!(server.unwrap().attribute("serial"), Some("2"));
+
+        // Clients without a certificate are rejected.
+        let mut anonymous = ClientConfig::new();
+        anonymous.root_store = roots;
+        let anonymous = TlsConnector::from(Arc::new(anonymous));
+        let (client, _) = handshake(&mut incoming, &addr, &acceptor, &anonymous);
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn dropping_the_watch_releases_the_resolver() {
+        let (ca, _) = ca();
+        let (chain, key) = signed_cert(&ca, "localhost", 1);
+        let resolver = Arc::new(CertResolver::new(chain, key).unwrap());
+
+        let watch = resolver.watch(Duration::from_secs(3600)).unwrap();
+        assert_eq!(Arc::strong_count(&resolver), 2);
+        drop(watch);
+        assert_eq!(Arc::strong_count(&resolver), 1);
+    }
+
+    /// Writes `contents` to `path`, modified `version` seconds after the epoch, so that each
+    /// version is told apart however coarse the file system's modification times are.
+    fn write(path: &Path, contents: &str, version: i64) {
+        fs::write(path, contents).unwrap();
+        filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(version, 0)).unwrap();
+    }
+
+    #[test]
+    fn reloads_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        let (cert_pem, key_pem) = cert(0x1234);
+        write(&cert_path, &cert_pem, 1);
+        write(&key_path, &key_pem, 1);
+
+        let stats = Stats::new();
+        let resolver = CertResolver::from_files(&cert_path, &key_path)
+            .unwrap()
+            .with_stats(&stats);
+        assert_eq!(resolver.serial(), "1234");
+        assert!(!resolver.reload().unwrap());
+
+        let (cert_pem, key_pem) = cert(0xabcd);
+        write(&cert_path, &cert_pem, 2);
+        write(&key_path, &key_pem, 2);
+        assert!(resolver.reload().unwrap());
+        assert_eq!(resolver.serial(), "abcd");
+        assert_eq!(stats.info()[CERT_SERIAL_INFO], "abcd");
+
+        // A broken certificate is rejected, and the last good one kept.
+        write(&cert_path, "not a certificate", 3);
+        assert!(resolver.reload().is_err());
+        assert_eq!(resolver.serial(), "abcd");
+    }
+}
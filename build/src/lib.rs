@@ -0,0 +1,316 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#![deny(missing_docs, missing_debug_implementations)]
+
+//! Generates tarpc services from service definitions kept outside of Rust source, so that the
+//! definitions can be shared with other repos and other languages.
+//!
+//! A service definition file contains one or more service traits, written in the syntax accepted
+//! by the `tarpc::service` macro:
+//!
+//! ```text
+//! /// Greets people.
+//! trait World {
+//!     #[id = 0]
+//!     async fn hello(name: String) -> String;
+//!     #[id = 1]
+//!     #[oneway]
+//!     async fn wave(name: String);
+//! }
+//! ```
+//!
+//! To generate a service, compile its definition in a build script:
+//!
+//! ```no_run
+//! fn main() -> std::io::Result<()> {
+//!     tarpc_build::compile("world.tarpc")
+//! }
+//! ```
+//!
+//! And then include the generated code, which expands to the same items as the `tarpc::service`
+//! macro:
+//!
+//! ```ignore
+//! include!(concat!(env!("OUT_DIR"), "/world.rs"));
+//! ```
+//!
+//! Services can also be generated from a [schema](rpc::schema::Service), e.g. one deserialized from
+//! JSON, with [`Config::compile_schema`].
+//!
+//! The [`typescript`] and [`python`] modules generate clients in other languages from schemas,
+//! for web frontends and scripts.
+
+pub mod python;
+pub mod typescript;
+
+use proc_macro2::TokenStream;
+use quote::quote;
+use rpc::schema;
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+use syn::{parse_quote, punctuated::Punctuated, token::Comma, Item, Visibility};
+
+/// Settings that control the generated code.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Whether the generated request and response enums derive serde traits. If `None`, defers to
+    /// the `serde1` feature of tarpc.
+    pub derive_serde: Option<bool>,
+    /// Whether to emit a schema const for each service.
+    pub schema: bool,
+    /// The directory in which to write the generated code. Defaults to `OUT_DIR`.
+    pub out_dir: Option<PathBuf>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            derive_serde: None,
+            schema: false,
+            out_dir: None,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+/// Generates the services defined in the file at `path` with the default config. See
+/// [`Config::compile`].
+pub fn compile(path: impl AsRef<Path>) -> io::Result<()> {
+    Config::default().compile(path)
+}
+
+impl Config {
+    /// Generates the services defined in the file at `path`, writing them to a file in the output
+    /// directory named after `path`, e.g. `world.tarpc` generates `world.rs`.
+    pub fn compile(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        println!("cargo:rerun-if-changed={}", path.display());
+
+        let definitions = fs::read_to_string(path)?;
+        let code = self.generate(&definitions)?;
+        let file_stem = path.file_stem().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file", path.display()),
+            )
+        })?;
+        self.write(Path::new(file_stem).with_extension("rs"), &code)
+    }
+
+    /// Generates the service described by `schema`, writing it to a file in the output directory
+    /// named after the service, e.g. `FileStore` generates `file_store.rs`.
+    pub fn compile_schema(&self, schema: &schema::Service) -> io::Result<()> {
+        let code = self.generate(&schema.to_string())?;
+        self.write(format!("{}.rs", camel_to_snake(&schema.name)), &code)
+    }
+
+    /// Returns the code generated for the services defined in `definitions`.
+    ///
+    /// Service traits without a visibility are made `pub`, so that the generated code can be
+    /// included in a module of its own.
+    pub fn generate(&self, definitions: &str) -> io::Result<String> {
+        let file = syn::parse_file(definitions)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if !file.attrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "service definitions cannot have inner attributes",
+            ));
+        }
+
+        let mut options = Punctuated::<TokenStream, Comma>::new();
+        if let Some(derive_serde) = self.derive_serde {
+            options.push(quote!(derive_serde = #derive_serde));
+        }
+        if self.schema {
+            options.push(quote!(schema = true));
+        }
+
+        let mut code = TokenStream::new();
+        for item in file.items {
+            match item {
+                Item::Trait(mut service) => {
+                    if let Visibility::Inherited = service.vis {
+                        service.vis = parse_quote!(pub);
+                    }
+                    code.extend(quote! {
+                        #[tarpc::service(#options)]
+                        #service
+                    });
+                }
+                item => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("expected a service trait, found `{}`", quote!(#item)),
+                    ))
+                }
+            }
+        }
+        Ok(code.to_string())
+    }
+
+    fn write(&self, file_name: impl AsRef<Path>, code: &str) -> io::Result<()> {
+        let out_dir = match self.out_dir {
+            Some(ref out_dir) => out_dir.clone(),
+            None => env::var_os("OUT_DIR").map(PathBuf::from).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "OUT_DIR is not set; set Config::out_dir when not running in a build script",
+                )
+            })?,
+        };
+        fs::write(out_dir.join(file_name), code)
+    }
+}
+
+/// Converts a CamelCase name to snake_case, keeping a run of capitals together as one word, as in
+/// `HTTPServer` to `http_server`.
+fn camel_to_snake(ident_str: &str) -> String {
+    let mut snake = String::new();
+    let mut chars = ident_str.chars().peekable();
+    let mut last_char_was_lowercase = false;
+    while let Some(c) = chars.next() {
+        let next_is_lowercase = chars.peek().map_or(false, |c| c.is_lowercase());
+        if c.is_uppercase()
+            && !snake.is_empty()
+            && (last_char_was_lowercase || next_is_lowercase)
+            && !snake.ends_with('_')
+        {
+            snake.push('_');
+        }
+        last_char_was_lowercase = c.is_lowercase() || c.is_numeric();
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{camel_to_snake, Config};
+    use rpc::schema;
+    use std::{borrow::Cow, fs, io};
+
+    const DEFINITIONS: &str = r#"
+        /// Greets people.
+        trait World {
+            #[id = 0]
+            async fn hello(name: String) -> String;
+            #[id = 1]
+            #[oneway]
+            async fn wave(name: String);
+        }
+
+        pub(crate) trait Echo {
+            async fn echo(s: String) -> String;
+        }
+    "#;
+
+    fn assert_same_tokens(code: &str, expected: &str) {
+        let strip = |s: &str| s.split_whitespace().collect::<String>();
+        assert_eq!(strip(code), strip(expected));
+    }
+
+    #[test]
+    fn generate() -> io::Result<()> {
+        let code = Config::default().generate(DEFINITIONS)?;
+        assert_same_tokens(
+            &code,
+            r#"
+            #[tarpc::service()]
+            #[doc = " Greets people."]
+            pub trait World {
+                #[id = 0]
+                async fn hello(name: String) -> String;
+                #[id = 1]
+                #[oneway]
+                async fn wave(name: String);
+            }
+
+            #[tarpc::service()]
+            pub(crate) trait Echo {
+                async fn echo(s: String) -> String;
+            }
+            "#,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn generate_options() -> io::Result<()> {
+        let config = Config {
+            derive_serde: Some(false),
+            schema: true,
+            ..Config::default()
+        };
+        let code = config.generate("trait Echo { async fn echo(s: String) -> String; }")?;
+        assert_same_tokens(
+            &code,
+            "#[tarpc::service(derive_serde = false, schema = true)]
+             pub trait Echo { async fn echo(s: String) -> String; }",
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn generate_rejects_non_traits() {
+        let err = Config::default().generate("struct World;").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compile_schema() -> io::Result<()> {
+        let out_dir = std::env::temp_dir().join(format!("tarpc-build-{}", std::process::id()));
+        fs::create_dir_all(&out_dir)?;
+        let config = Config {
+            out_dir: Some(out_dir.clone()),
+            ..Config::default()
+        };
+        config.compile_schema(&schema::Service {
+            name: Cow::Borrowed("FileStore"),
+            methods: Cow::Owned(vec![schema::Method {
+                name: Cow::Borrowed("read"),
+                wire_name: Cow::Borrowed("Read"),
+                id: 0,
+                kind: schema::MethodKind::Unary,
+                args: Cow::Owned(vec![schema::Arg {
+                    name: Cow::Borrowed("path"),
+                    ty: Cow::Borrowed("String"),
+                }]),
+                output: Cow::Borrowed("Vec<u8>"),
+                error: None,
+                since: None,
+                removed: None,
+            }]),
+        })?;
+
+        assert_same_tokens(
+            &fs::read_to_string(out_dir.join("file_store.rs"))?,
+            "#[tarpc::service()]
+             pub trait FileStore {
+                 #[id = 0] async fn read(path: String) -> Vec<u8>;
+             }",
+        );
+        fs::remove_dir_all(out_dir)
+    }
+
+    #[test]
+    fn camel_to_snake_basic() {
+        assert_eq!(camel_to_snake("FileStore"), "file_store");
+        assert_eq!(camel_to_snake("World"), "world");
+    }
+
+    #[test]
+    fn camel_to_snake_acronyms() {
+        assert_eq!(camel_to_snake("HTTPServer"), "http_server");
+        assert_eq!(camel_to_snake("UserAPI"), "user_api");
+        assert_eq!(camel_to_snake("V2Api"), "v2_api");
+    }
+}
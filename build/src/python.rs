@@ -0,0 +1,472 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Generates asyncio Python clients, so that scripts can call services without an HTTP gateway.
+//!
+//! The client is generated from the service's [schema](schema::Service), and speaks to servers
+//! on the JSON transport, over TCP, or over any other asyncio stream, like TLS, that a `Channel`
+//! is constructed from. It needs Python 3.7 or later, and nothing outside the standard library:
+//!
+//! ```text
+//! client = await WorldClient.connect("localhost", 5000)
+//! print(await client.hello("Tim"))
+//! async for n in client.count(3):
+//!     print(n)
+//! await client.close()
+//! ```
+//!
+//! Each RPC is a method taking the RPC's arguments, and a keyword-only `timeout` in
+//! seconds. Unary RPCs return their output, streaming RPCs return async iterators, and one-way
+//! RPCs return once the request is sent. Calls that fail raise an `RpcError` naming the
+//! [`io::ErrorKind`](std::io::ErrorKind) of the failure, and RPCs declared with `#[throws]` raise
+//! a `Thrown` holding the error they threw. A streaming call is canceled when its iterator is
+//! closed before the stream ends.
+//!
+//! Types from the standard library are annotated with the Python types JSON decodes them to;
+//! any other type, which the schema doesn't describe, is declared as an alias of `Any`.
+//! Arguments are sent under their names in the schema, so a service that sets `wire_case` must
+//! rename them to match the names on the wire. Arguments named after Python keywords get a
+//! trailing underscore.
+
+use rpc::schema::{self, MethodKind, Type};
+use std::{collections::BTreeSet, fmt::Write};
+
+/// The code shared by all generated clients: framing, errors, and request bookkeeping.
+const RUNTIME: &str = r#"
+import asyncio
+import itertools
+import json
+import math
+import random
+import struct
+import time
+from typing import Any, AsyncIterator, Dict, List, Optional, Tuple
+
+# The kinds of errors a call can fail with, named as in Rust's `std::io::ErrorKind`.
+ERROR_KINDS = [
+    "NotFound",
+    "PermissionDenied",
+    "ConnectionRefused",
+    "ConnectionReset",
+    "ConnectionAborted",
+    "NotConnected",
+    "AddrInUse",
+    "AddrNotAvailable",
+    "BrokenPipe",
+    "AlreadyExists",
+    "WouldBlock",
+    "InvalidInput",
+    "InvalidData",
+    "TimedOut",
+    "WriteZero",
+    "Interrupted",
+    "Other",
+    "UnexpectedEof",
+]
+
+# How long the server has to respond to a call, in seconds, unless the call says otherwise.
+DEFAULT_TIMEOUT = 10.0
+
+
+class RpcError(Exception):
+    """A call that failed, e.g. because its deadline passed or the server was overloaded."""
+
+    def __init__(self, kind: str, detail: Optional[str]) -> None:
+        super().__init__(kind if detail is None else "{}: {}".format(kind, detail))
+        self.kind = kind
+        self.detail = detail
+
+
+class Thrown(Exception):
+    """The error thrown by an RPC declared with `#[throws]`."""
+
+    def __init__(self, error: Any) -> None:
+        super().__init__("the RPC threw {!r}".format(error))
+        self.error = error
+
+
+def _trace_context() -> Dict[str, Any]:
+    """Returns the trace context of a new root span."""
+    return {
+        "trace_id": random.getrandbits(128),
+        "span_id": random.getrandbits(64),
+        "parent_id": None,
+        "sampled": True,
+    }
+
+
+class Channel:
+    """Sends requests to a server on the JSON transport, and matches responses to them.
+
+    Each message is JSON, prefixed by its length as a 4-byte big-endian integer.
+    """
+
+    def __init__(self, reader: asyncio.StreamReader, writer: asyncio.StreamWriter) -> None:
+        self._reader = reader
+        self._writer = writer
+        self._ids = itertools.count()
+        self._in_flight = {}  # type: Dict[int, asyncio.Queue]
+        self._closed = None  # type: Optional[RpcError]
+        self._receiving = asyncio.ensure_future(self._receive())
+
+    @classmethod
+    async def connect(cls, host: str, port: int) -> "Channel":
+        """Connects to the server at `host:port`."""
+        reader, writer = await asyncio.open_connection(host, port)
+        return cls(reader, writer)
+
+    async def close(self) -> None:
+        """Closes the connection, failing the calls in flight."""
+        self._writer.close()
+        self._receiving.cancel()
+        try:
+            await self._receiving
+        except asyncio.CancelledError:
+            pass
+
+    async def call(self, message: Any, timeout: float) -> Any:
+        responses = self._responses(message, timeout)
+        try:
+            async for response, _ in responses:
+                return response
+        finally:
+            await responses.aclose()
+
+    async def stream(self, message: Any, timeout: float, variant: str) -> AsyncIterator[Any]:
+        responses = self._responses(message, timeout)
+        try:
+            async for response, partial in responses:
+                # The last response ends the stream.
+                if not partial:
+                    return
+                yield response[variant]
+        finally:
+            await responses.aclose()
+
+    async def notify(self, message: Any, timeout: float) -> None:
+        if self._closed is not None:
+            raise self._closed
+        self._send_request(next(self._ids), message, True, timeout)
+        await self._writer.drain()
+
+    async def _responses(self, message: Any, timeout: float) -> AsyncIterator[Tuple[Any, bool]]:
+        if self._closed is not None:
+            raise self._closed
+        request_id = next(self._ids)
+        queue = asyncio.Queue()  # type: asyncio.Queue
+        self._in_flight[request_id] = queue
+        finished = False
+        try:
+            self._send_request(request_id, message, False, timeout)
+            await self._writer.drain()
+            deadline = time.monotonic() + timeout
+            while True:
+                try:
+                    response = await asyncio.wait_for(queue.get(), deadline - time.monotonic())
+                except asyncio.TimeoutError:
+                    raise RpcError("TimedOut", "the deadline passed") from None
+                if response is None:
+                    finished = True
+                    raise self._closed
+                result = response["message"]
+                if "Err" in result:
+                    finished = True
+                    error = result["Err"]
+                    kind = error["kind"]
+                    raise RpcError(
+                        ERROR_KINDS[kind] if kind < len(ERROR_KINDS) else "Other",
+                        error.get("detail"),
+                    )
+                partial = response["partial"]
+                finished = not partial
+                yield result["Ok"], partial
+                if finished:
+                    return
+        finally:
+            del self._in_flight[request_id]
+            if not finished and self._closed is None:
+                self._send({"Cancel": {"trace_context": _trace_context(), "request_id": request_id}})
+
+    def _send_request(self, request_id: int, message: Any, one_way: bool, timeout: float) -> None:
+        self._send(
+            {
+                "Request": {
+                    "V1": {
+                        "context": {
+                            "deadline": math.ceil(time.time() + timeout),
+                            "trace_context": _trace_context(),
+                            "metadata": {},
+                            "baggage": {},
+                            "time_remaining": None,
+                            "_non_exhaustive": None,
+                        },
+                        "id": request_id,
+                        "message": message,
+                        "one_way": one_way,
+                    }
+                }
+            }
+        )
+
+    def _send(self, message: Any) -> None:
+        frame = json.dumps(message).encode()
+        self._writer.write(struct.pack(">I", len(frame)) + frame)
+
+    async def _receive(self) -> None:
+        try:
+            while True:
+                (length,) = struct.unpack(">I", await self._reader.readexactly(4))
+                response = json.loads(await self._reader.readexactly(length))["V1"]
+                queue = self._in_flight.get(response["request_id"])
+                if queue is not None:
+                    queue.put_nowait(response)
+        except (asyncio.IncompleteReadError, ConnectionError):
+            pass
+        finally:
+            self._closed = RpcError("ConnectionReset", "the connection closed")
+            for queue in self._in_flight.values():
+                queue.put_nowait(None)
+"#;
+
+/// Names that can't be Python parameters, and `timeout`, which every RPC method takes.
+const RESERVED: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "self", "timeout",
+    "try", "while", "with", "yield",
+];
+
+/// Returns the Python client of the service described by `service`.
+pub fn generate(service: &schema::Service) -> String {
+    let mut named = BTreeSet::new();
+    let mut methods = String::new();
+    for method in service.methods.iter().filter(|m| m.removed.is_none()) {
+        let types: Vec<Type> = method.args.iter().map(|arg| Type::parse(&arg.ty)).collect();
+        // Trailing optional arguments default to `None`, which the server reads as `None`.
+        let required = types
+            .iter()
+            .rposition(|ty| match ty {
+                Type::Option(_) => false,
+                _ => true,
+            })
+            .map_or(0, |last| last + 1);
+        let mut params = vec!["self".to_string()];
+        let mut fields = vec![];
+        for (i, (arg, ty)) in method.args.iter().zip(&types).enumerate() {
+            let param = if RESERVED.contains(&&*arg.name) {
+                format!("{}_", arg.name)
+            } else {
+                arg.name.to_string()
+            };
+            let default = if i >= required { " = None" } else { "" };
+            params.push(format!("{}: {}{}", param, py_type(ty, &mut named), default));
+            fields.push(format!("{:?}: {}", &*arg.name, param));
+        }
+        params.push("*".into());
+        params.push("timeout: float = DEFAULT_TIMEOUT".into());
+        let params = params.join(", ");
+        let request = format!("{{{:?}: {{{}}}}}", &*method.wire_name, fields.join(", "));
+        let output = py_type(&Type::parse(&method.output), &mut named);
+        let since = match method.since {
+            Some(since) => format!("\n\n        Added in version {}.\n        ", since),
+            None => String::new(),
+        };
+
+        match method.kind {
+            MethodKind::Unary => {
+                write!(
+                    methods,
+                    "\n    async def {}({}) -> {}:\n        \
+                     \"\"\"Calls `{}`.{}\"\"\"\n        \
+                     response = await self.channel.call({}, timeout)\n",
+                    method.name, params, output, method.name, since, request
+                )
+                .unwrap();
+                match method.error {
+                    Some(ref error) => {
+                        py_type(&Type::parse(error), &mut named);
+                        write!(
+                            methods,
+                            "        result = response[{:?}]\n        \
+                             if \"Err\" in result:\n            \
+                             raise Thrown(result[\"Err\"])\n        \
+                             return result[\"Ok\"]\n",
+                            &*method.wire_name
+                        )
+                        .unwrap();
+                    }
+                    None => writeln!(methods, "        return response[{:?}]", &*method.wire_name)
+                        .unwrap(),
+                }
+            }
+            MethodKind::Streaming => {
+                write!(
+                    methods,
+                    "\n    def {}({}) -> AsyncIterator[{}]:\n        \
+                     \"\"\"Streams the items of `{}`.{}\"\"\"\n        \
+                     return self.channel.stream({}, timeout, {:?})\n",
+                    method.name, params, output, method.name, since, request, &*method.wire_name
+                )
+                .unwrap();
+            }
+            MethodKind::OneWay => {
+                write!(
+                    methods,
+                    "\n    async def {}({}) -> None:\n        \
+                     \"\"\"Sends `{}`, without waiting for the server to handle it.{}\"\"\"\n        \
+                     await self.channel.notify({}, timeout)\n",
+                    method.name, params, method.name, since, request
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    let mut code = format!(
+        "# Generated by tarpc-build from the schema of the `{}` service. Do not edit.\n\
+         \"\"\"A client of the `{}` service.\"\"\"\n",
+        service.name, service.name
+    );
+    code.push_str(RUNTIME);
+    if !named.is_empty() {
+        code.push_str("\n# Types defined by the service, which its schema doesn't describe.\n");
+        for name in &named {
+            writeln!(code, "{} = Any", name).unwrap();
+        }
+    }
+    write!(
+        code,
+        "\n\nclass {0}Client:\n    \
+         \"\"\"A client of the `{0}` service.\"\"\"\n\n    \
+         def __init__(self, channel: Channel) -> None:\n        \
+         self.channel = channel\n\n    \
+         @classmethod\n    \
+         async def connect(cls, host: str, port: int) -> \"{0}Client\":\n        \
+         \"\"\"Connects to the server at `host:port`.\"\"\"\n        \
+         return cls(await Channel.connect(host, port))\n\n    \
+         async def close(self) -> None:\n        \
+         \"\"\"Closes the connection, failing the calls in flight.\"\"\"\n        \
+         await self.channel.close()\n{1}",
+        service.name, methods
+    )
+    .unwrap();
+    code
+}
+
+/// Returns the Python type that JSON decodes values of type `ty` to, adding the names of types
+/// the schema doesn't describe to `named`.
+fn py_type(ty: &Type, named: &mut BTreeSet<String>) -> String {
+    match ty {
+        Type::Unit => "None".into(),
+        Type::Bool => "bool".into(),
+        Type::Integer { .. } => "int".into(),
+        Type::Float { .. } => "float".into(),
+        Type::Char | Type::String => "str".into(),
+        Type::Option(ty) => format!("Optional[{}]", py_type(ty, named)),
+        Type::List(ty) | Type::Array(ty, _) => format!("List[{}]", py_type(ty, named)),
+        // Tuples are JSON arrays, which decode as lists.
+        Type::Tuple(tys) => {
+            for ty in tys {
+                py_type(ty, named);
+            }
+            "List[Any]".into()
+        }
+        Type::Map(_, value) => format!("Dict[str, {}]", py_type(value, named)),
+        // Paths are joined with underscores, so that types of the same name in different modules
+        // don't collide.
+        Type::Named { name, args } if args.is_empty() => {
+            let name = name.replace("::", "_");
+            named.insert(name.clone());
+            name
+        }
+        Type::Named { .. } => "Any".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate;
+    use rpc::schema::{Arg, Method, MethodKind, Service};
+    use std::borrow::Cow;
+
+    #[test]
+    fn generate_client() {
+        let method = |name, wire_name, kind, args, output, error: Option<&'static str>| Method {
+            name: Cow::Borrowed(name),
+            wire_name: Cow::Borrowed(wire_name),
+            id: 0,
+            kind,
+            args: Cow::Owned(args),
+            output: Cow::Borrowed(output),
+            error: error.map(Cow::Borrowed),
+            since: None,
+            removed: None,
+        };
+        let arg = |name, ty| Arg {
+            name: Cow::Borrowed(name),
+            ty: Cow::Borrowed(ty),
+        };
+        let service = Service {
+            name: Cow::Borrowed("Users"),
+            methods: Cow::Owned(vec![
+                method(
+                    "get_user",
+                    "GetUser",
+                    MethodKind::Unary,
+                    vec![arg("id", "u64"), arg("fields", "Option<Vec<String>>")],
+                    "model::User",
+                    Some("String"),
+                ),
+                method(
+                    "list",
+                    "List",
+                    MethodKind::Streaming,
+                    vec![arg("from", "Option<u64>"), arg("limit", "u32")],
+                    "User",
+                    None,
+                ),
+                method(
+                    "log",
+                    "Log",
+                    MethodKind::OneWay,
+                    vec![arg("lines", "HashMap<String, (u8, bool)>")],
+                    "()",
+                    None,
+                ),
+                Method {
+                    removed: Some(2),
+                    ..method("ping", "Ping", MethodKind::Unary, vec![], "()", None)
+                },
+            ]),
+        };
+
+        let code = generate(&service);
+        for expected in &[
+            "User = Any",
+            "model_User = Any",
+            "    async def get_user(self, id: int, fields: Optional[List[str]] = None, *, \
+             timeout: float = DEFAULT_TIMEOUT) -> model_User:",
+            "response = await self.channel.call({\"GetUser\": {\"id\": id, \"fields\": fields}}, \
+             timeout)",
+            "            raise Thrown(result[\"Err\"])",
+            "    def list(self, from_: Optional[int], limit: int, *, \
+             timeout: float = DEFAULT_TIMEOUT) -> AsyncIterator[User]:",
+            "        return self.channel.stream({\"List\": {\"from\": from_, \"limit\": limit}}, \
+             timeout, \"List\")",
+            "    async def log(self, lines: Dict[str, List[Any]], *, \
+             timeout: float = DEFAULT_TIMEOUT) -> None:",
+            "class UsersClient:",
+        ] {
+            assert!(
+                code.contains(expected),
+                "expected `{}` in:\n{}",
+                expected,
+                code
+            );
+        }
+        assert!(!code.contains("Ping"));
+    }
+}
@@ -0,0 +1,628 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Generates typed TypeScript clients, so that web frontends stay in sync with the Rust service.
+//!
+//! The client is generated from the service's [schema](schema::Service), and speaks tarpc's
+//! JSON messages, as the JSON transport does, one message per frame of a `Transport`. The
+//! generated `webSocketTransport` sends each message as a text frame of a WebSocket, so the
+//! server must read and write one JSON message per frame:
+//!
+//! ```text
+//! const client = new WorldClient(webSocketTransport(new WebSocket("wss://example.com/world")));
+//! console.log(await client.hello({ name: "Tim" }));
+//! for await (const n of client.count({ to: 3 })) {
+//!     console.log(n);
+//! }
+//! ```
+//!
+//! Each RPC gets an interface of its arguments and a type alias of its output, e.g.
+//! `HelloRequest` and `HelloResponse`. Unary RPCs return promises, streaming RPCs return async
+//! iterators that cancel the call when the loop over them ends early, and one-way RPCs return once
+//! the request is sent. Calls that fail reject with an `RpcError` naming the
+//! [`io::ErrorKind`](std::io::ErrorKind) of the failure, and RPCs declared with `#[throws]` reject
+//! with a `Thrown` holding the error they threw.
+//!
+//! Types from the standard library are mapped to their JSON representation; any other type,
+//! which the schema doesn't describe, is declared as an alias of `unknown`, to be replaced by
+//! hand if needed. Arguments are named as in the schema, so a service that sets `wire_case` must
+//! rename them to match the names on the wire. Integers are JavaScript numbers, which lose
+//! precision above 2^53.
+
+use rpc::schema::{self, snake_to_camel, MethodKind, Type};
+use std::{collections::BTreeSet, fmt::Write};
+
+/// The code shared by all generated clients: transports, errors, and request bookkeeping.
+const RUNTIME: &str = r#"
+/** Carries tarpc's JSON messages to and from a server, one message per frame. */
+export interface Transport {
+  /** Sends one message. */
+  send(message: string): void;
+  /** Registers the handlers of the messages received and of the connection closing. */
+  listen(onMessage: (message: string) => void, onClose: () => void): void;
+}
+
+/**
+ * Returns a transport that sends each message as a text frame of `socket`. Messages sent before
+ * the socket opens are queued.
+ */
+export function webSocketTransport(socket: WebSocket): Transport {
+  const queued: string[] = [];
+  socket.addEventListener("open", () => {
+    for (const message of queued.splice(0)) {
+      socket.send(message);
+    }
+  });
+  return {
+    send(message: string): void {
+      if (socket.readyState === WebSocket.CONNECTING) {
+        queued.push(message);
+      } else {
+        socket.send(message);
+      }
+    },
+    listen(onMessage: (message: string) => void, onClose: () => void): void {
+      socket.addEventListener("message", (event) => onMessage(String(event.data)));
+      socket.addEventListener("close", () => onClose());
+    },
+  };
+}
+
+/** The kinds of errors a call can fail with, named as in Rust's `std::io::ErrorKind`. */
+const ERROR_KINDS = [
+  "NotFound",
+  "PermissionDenied",
+  "ConnectionRefused",
+  "ConnectionReset",
+  "ConnectionAborted",
+  "NotConnected",
+  "AddrInUse",
+  "AddrNotAvailable",
+  "BrokenPipe",
+  "AlreadyExists",
+  "WouldBlock",
+  "InvalidInput",
+  "InvalidData",
+  "TimedOut",
+  "WriteZero",
+  "Interrupted",
+  "Other",
+  "UnexpectedEof",
+];
+
+/** A call that failed, e.g. because its deadline passed or the server was overloaded. */
+export class RpcError extends Error {
+  constructor(readonly kind: string, readonly detail: string | null) {
+    super(detail === null ? kind : `${kind}: ${detail}`);
+  }
+}
+
+/** The error thrown by an RPC declared with `#[throws]`. */
+export class Thrown<E> extends Error {
+  constructor(readonly error: E) {
+    super(`the RPC threw ${JSON.stringify(error)}`);
+  }
+}
+
+/** Options for one call. */
+export interface CallOptions {
+  /** How long the server has to respond, in milliseconds. Defaults to 10 seconds. */
+  timeoutMs?: number;
+  /** Cancels the call when aborted. */
+  signal?: AbortSignal;
+}
+
+const DEFAULT_TIMEOUT_MS = 10000;
+
+interface ServerError {
+  kind: number;
+  detail: string | null;
+}
+
+interface Pending {
+  /** Receives each response to the call, the last of which isn't partial. */
+  receive(message: { Ok: any } | { Err: ServerError }, partial: boolean): void;
+  fail(error: Error): void;
+}
+
+interface InFlight {
+  pending: Pending;
+  timer: ReturnType<typeof setTimeout>;
+}
+
+interface StreamState {
+  items: any[];
+  done: boolean;
+  error: Error | null;
+  wake: (() => void) | null;
+}
+
+function randomId(): number {
+  return Math.floor(Math.random() * Number.MAX_SAFE_INTEGER);
+}
+
+/** Returns the trace context of a new root span. */
+function traceContext(): object {
+  return { trace_id: randomId(), span_id: randomId(), parent_id: null, sampled: true };
+}
+
+function serverError(error: ServerError): RpcError {
+  return new RpcError(ERROR_KINDS[error.kind] || "Other", error.detail);
+}
+
+/** Sends requests over a transport, and matches responses to their requests. */
+class Channel {
+  private nextId = 0;
+  private closed = false;
+  private readonly inFlight = new Map<number, InFlight>();
+
+  constructor(private readonly transport: Transport) {
+    transport.listen(
+      (message) => this.receive(JSON.parse(message).V1),
+      () => {
+        this.closed = true;
+        for (const id of Array.from(this.inFlight.keys())) {
+          this.finish(id, new RpcError("ConnectionReset", "the connection closed"));
+        }
+      },
+    );
+  }
+
+  call(message: object, options: CallOptions): Promise<any> {
+    return new Promise((resolve, reject) => {
+      this.start(message, options, {
+        receive(message, partial) {
+          if ("Err" in message) {
+            reject(serverError(message.Err));
+          } else if (!partial) {
+            resolve(message.Ok);
+          }
+        },
+        fail: reject,
+      });
+    });
+  }
+
+  stream(message: object, options: CallOptions): AsyncIterableIterator<any> {
+    const state: StreamState = { items: [], done: false, error: null, wake: null };
+    const wake = () => {
+      if (state.wake !== null) {
+        state.wake();
+        state.wake = null;
+      }
+    };
+    const id = this.start(message, options, {
+      receive(message, partial) {
+        if ("Err" in message) {
+          state.error = serverError(message.Err);
+        } else if (partial) {
+          state.items.push(message.Ok);
+        } else {
+          // The last response ends the stream.
+          state.done = true;
+        }
+        wake();
+      },
+      fail(error) {
+        state.error = error;
+        wake();
+      },
+    });
+    const channel = this;
+    return (async function* () {
+      try {
+        while (true) {
+          if (state.items.length > 0) {
+            yield state.items.shift();
+          } else if (state.error !== null) {
+            throw state.error;
+          } else if (state.done) {
+            return;
+          } else {
+            await new Promise<void>((resolve) => (state.wake = () => resolve()));
+          }
+        }
+      } finally {
+        // Cancels the call if the loop over the stream ended early.
+        channel.cancel(id);
+      }
+    })();
+  }
+
+  notify(message: object, options: CallOptions): void {
+    if (this.closed) {
+      throw new RpcError("ConnectionReset", "the connection closed");
+    }
+    this.send(this.nextId++, message, true, options);
+  }
+
+  private start(message: object, options: CallOptions, pending: Pending): number {
+    const id = this.nextId++;
+    if (this.closed) {
+      pending.fail(new RpcError("ConnectionReset", "the connection closed"));
+      return id;
+    }
+    const timeoutMs = this.send(id, message, false, options);
+    const timer = setTimeout(
+      () => this.cancel(id, new RpcError("TimedOut", "the deadline passed")),
+      timeoutMs,
+    );
+    this.inFlight.set(id, { pending, timer });
+    if (options.signal) {
+      options.signal.addEventListener("abort", () =>
+        this.cancel(id, new RpcError("Interrupted", "the call was canceled")),
+      );
+    }
+    return id;
+  }
+
+  private send(id: number, message: object, oneWay: boolean, options: CallOptions): number {
+    const timeoutMs = options.timeoutMs !== undefined ? options.timeoutMs : DEFAULT_TIMEOUT_MS;
+    this.transport.send(
+      JSON.stringify({
+        Request: {
+          V1: {
+            context: {
+              deadline: Math.ceil((Date.now() + timeoutMs) / 1000),
+              trace_context: traceContext(),
+              metadata: {},
+              baggage: {},
+              time_remaining: null,
+              _non_exhaustive: null,
+            },
+            id,
+            message,
+            one_way: oneWay,
+          },
+        },
+      }),
+    );
+    return timeoutMs;
+  }
+
+  private cancel(id: number, error: Error = new RpcError("Interrupted", "the call was canceled")) {
+    if (this.finish(id, error)) {
+      this.sendCancel(id);
+    }
+  }
+
+  private sendCancel(id: number) {
+    if (!this.closed) {
+      this.transport.send(
+        JSON.stringify({ Cancel: { trace_context: traceContext(), request_id: id } }),
+      );
+    }
+  }
+
+  /** Stops waiting for responses to the call, failing it with `error`. */
+  private finish(id: number, error: Error): boolean {
+    const inFlight = this.inFlight.get(id);
+    if (inFlight === undefined) {
+      return false;
+    }
+    this.inFlight.delete(id);
+    clearTimeout(inFlight.timer);
+    inFlight.pending.fail(error);
+    return true;
+  }
+
+  private receive(response: { request_id: number; message: any; partial: boolean }) {
+    const inFlight = this.inFlight.get(response.request_id);
+    if (inFlight === undefined) {
+      return;
+    }
+    const partial = response.partial && !("Err" in response.message);
+    if (!partial) {
+      this.inFlight.delete(response.request_id);
+      clearTimeout(inFlight.timer);
+    }
+    inFlight.pending.receive(response.message, partial);
+  }
+}
+"#;
+
+/// Returns the TypeScript client of the service described by `service`.
+pub fn generate(service: &schema::Service) -> String {
+    let mut named = BTreeSet::new();
+    let mut types = String::new();
+    let mut methods = String::new();
+    let mut requests = vec![];
+    let mut responses = vec![];
+    for method in service.methods.iter().filter(|m| m.removed.is_none()) {
+        let camel = snake_to_camel(&method.name);
+        let request = format!("{}Request", camel);
+        let response = format!("{}Response", camel);
+        let wire_name = &method.wire_name;
+
+        let mut doc = String::new();
+        if let Some(since) = method.since {
+            writeln!(doc, "  /** @since version {} */", since).unwrap();
+        }
+        writeln!(types, "\n/** The arguments of `{}`. */", method.name).unwrap();
+        if method.args.is_empty() {
+            writeln!(types, "export interface {} {{}}", request).unwrap();
+        } else {
+            writeln!(types, "export interface {} {{", request).unwrap();
+            for arg in method.args.iter() {
+                let ty = Type::parse(&arg.ty);
+                let optional = match ty {
+                    // Missing optional arguments are deserialized as `None`.
+                    Type::Option(_) => "?",
+                    _ => "",
+                };
+                let ty = ts_type(&ty, &mut named);
+                writeln!(types, "  {}{}: {};", arg.name, optional, ty).unwrap();
+            }
+            writeln!(types, "}}").unwrap();
+        }
+        if method.kind != MethodKind::OneWay {
+            let output = ts_type(&Type::parse(&method.output), &mut named);
+            let doc_output = match method.kind {
+                MethodKind::Streaming => "Each item streamed by",
+                _ => "The output of",
+            };
+            writeln!(types, "\n/** {} `{}`. */", doc_output, method.name).unwrap();
+            writeln!(types, "export type {} = {};", response, output).unwrap();
+        }
+        // RPCs without arguments can be called without them.
+        let args = if method.args.is_empty() {
+            format!("args: {} = {{}}", request)
+        } else {
+            format!("args: {}", request)
+        };
+        requests.push(format!("{{ {}: {} }}", wire_name, request));
+
+        let fn_name = lower_first(&camel);
+        match method.kind {
+            MethodKind::Unary => {
+                let unwrap = match method.error {
+                    Some(ref error) => {
+                        let error = ts_type(&Type::parse(error), &mut named);
+                        responses.push(format!(
+                            "{{ {}: {{ Ok: {} }} | {{ Err: {} }} }}",
+                            wire_name, response, error
+                        ));
+                        format!(
+                            "{{\n        const result = response.{};\n        \
+                                 if (\"Err\" in result) {{\n          \
+                                 throw new Thrown<{}>(result.Err);\n        }}\n        \
+                                 return result.Ok;\n      }}",
+                            wire_name, error
+                        )
+                    }
+                    None => {
+                        responses.push(format!("{{ {}: {} }}", wire_name, response));
+                        format!("response.{}", wire_name)
+                    }
+                };
+                write!(
+                    methods,
+                    "\n{}  {}({}, options: CallOptions = {{}}): Promise<{}> {{\n    \
+                     return this.channel\n      \
+                     .call({{ {}: args }}, options)\n      \
+                     .then((response) => {});\n  }}\n",
+                    doc, fn_name, args, response, wire_name, unwrap
+                )
+                .unwrap();
+            }
+            MethodKind::Streaming => {
+                responses.push(format!("{{ {}: {} | null }}", wire_name, response));
+                write!(
+                    methods,
+                    "\n{}  async *{}(\n    {},\n    options: CallOptions = {{}},\n  ): \
+                     AsyncIterableIterator<{}> {{\n    \
+                     for await (const response of this.channel.stream({{ {}: args }}, options)) \
+                     {{\n      yield response.{};\n    }}\n  }}\n",
+                    doc, fn_name, args, response, wire_name, wire_name
+                )
+                .unwrap();
+            }
+            MethodKind::OneWay => {
+                write!(
+                    methods,
+                    "\n{}  {}({}, options: CallOptions = {{}}): void {{\n    \
+                     this.channel.notify({{ {}: args }}, options);\n  }}\n",
+                    doc, fn_name, args, wire_name
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    let mut code = format!(
+        "// Generated by tarpc-build from the schema of the `{}` service. Do not edit.\n",
+        service.name
+    );
+    code.push_str(RUNTIME);
+    if !named.is_empty() {
+        code.push_str("\n// Types defined by the service, which its schema doesn't describe.\n");
+        for name in &named {
+            writeln!(code, "export type {} = unknown;", name).unwrap();
+        }
+    }
+    code.push_str(&types);
+    let union = |variants: Vec<String>| {
+        if variants.is_empty() {
+            "never".to_string()
+        } else {
+            variants.join("\n  | ")
+        }
+    };
+    writeln!(
+        code,
+        "\n/** The requests of the `{}` service, as sent on the wire. */",
+        service.name
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "export type {}WireRequest =\n  | {};",
+        service.name,
+        union(requests)
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "\n/** The responses of the `{}` service, as sent on the wire. */",
+        service.name
+    )
+    .unwrap();
+    writeln!(
+        code,
+        "export type {}WireResponse =\n  | {};",
+        service.name,
+        union(responses)
+    )
+    .unwrap();
+    write!(
+        code,
+        "\n/** A client of the `{0}` service. */\nexport class {0}Client {{\n  \
+         private readonly channel: Channel;\n\n  \
+         constructor(transport: Transport) {{\n    \
+         this.channel = new Channel(transport);\n  }}\n{1}}}\n",
+        service.name, methods
+    )
+    .unwrap();
+    code
+}
+
+/// Returns the TypeScript type of the JSON that values of type `ty` serialize to, adding the
+/// names of types the schema doesn't describe to `named`.
+fn ts_type(ty: &Type, named: &mut BTreeSet<String>) -> String {
+    match ty {
+        Type::Unit => "null".into(),
+        Type::Bool => "boolean".into(),
+        Type::Integer { .. } | Type::Float { .. } => "number".into(),
+        Type::Char | Type::String => "string".into(),
+        Type::Option(ty) => format!("{} | null", ts_type(ty, named)),
+        Type::List(ty) | Type::Array(ty, _) => format!("Array<{}>", ts_type(ty, named)),
+        Type::Tuple(tys) => format!(
+            "[{}]",
+            tys.iter()
+                .map(|ty| ts_type(ty, named))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Type::Map(_, value) => format!("{{ [key: string]: {} }}", ts_type(value, named)),
+        Type::Named { name, args } if is_result(name) && args.len() == 2 => format!(
+            "{{ Ok: {} }} | {{ Err: {} }}",
+            ts_type(&args[0], named),
+            ts_type(&args[1], named)
+        ),
+        // Paths are joined with underscores, so that types of the same name in different modules
+        // don't collide.
+        Type::Named { name, args } if args.is_empty() => {
+            let name = name.replace("::", "_");
+            named.insert(name.clone());
+            name
+        }
+        Type::Named { .. } => "unknown".into(),
+    }
+}
+
+/// Returns whether the type at `path` is std's `Result`, as far as its name tells.
+fn is_result(path: &str) -> bool {
+    path == "Result" || path.ends_with("::Result")
+}
+
+fn lower_first(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate;
+    use rpc::schema::{Arg, Method, MethodKind, Service};
+    use std::borrow::Cow;
+
+    #[test]
+    fn generate_client() {
+        let method = |name, wire_name, kind, args, output, error: Option<&'static str>| Method {
+            name: Cow::Borrowed(name),
+            wire_name: Cow::Borrowed(wire_name),
+            id: 0,
+            kind,
+            args: Cow::Borrowed(args),
+            output: Cow::Borrowed(output),
+            error: error.map(Cow::Borrowed),
+            since: None,
+            removed: None,
+        };
+        let service = Service {
+            name: Cow::Borrowed("Users"),
+            methods: Cow::Owned(vec![
+                method(
+                    "get_user",
+                    "GetUser",
+                    MethodKind::Unary,
+                    &[
+                        Arg {
+                            name: Cow::Borrowed("id"),
+                            ty: Cow::Borrowed("u64"),
+                        },
+                        Arg {
+                            name: Cow::Borrowed("fields"),
+                            ty: Cow::Borrowed("Option<Vec<String>>"),
+                        },
+                    ],
+                    "model::User",
+                    Some("String"),
+                ),
+                method("list", "List", MethodKind::Streaming, &[], "User", None),
+                method(
+                    "log",
+                    "Log",
+                    MethodKind::OneWay,
+                    &[Arg {
+                        name: Cow::Borrowed("lines"),
+                        ty: Cow::Borrowed("HashMap<String, (u8, bool)>"),
+                    }],
+                    "()",
+                    None,
+                ),
+                Method {
+                    removed: Some(2),
+                    ..method("ping", "Ping", MethodKind::Unary, &[], "()", None)
+                },
+            ]),
+        };
+
+        let code = generate(&service);
+        for expected in &[
+            "export type User = unknown;",
+            "export type model_User = unknown;",
+            "export interface GetUserRequest {\n  id: number;\n  fields?: Array<string> | null;\n}",
+            "export type GetUserResponse = model_User;",
+            "export type ListResponse = User;",
+            "export interface ListRequest {}",
+            "  async *list(\n    args: ListRequest = {},",
+            "  lines: { [key: string]: [number, boolean] };",
+            "  getUser(args: GetUserRequest, options: CallOptions = {}): Promise<GetUserResponse> {",
+            ".call({ GetUser: args }, options)",
+            "throw new Thrown<string>(result.Err);",
+            "  async *list(",
+            "  ): AsyncIterableIterator<ListResponse> {",
+            "this.channel.stream({ List: args }, options)",
+            "  log(args: LogRequest, options: CallOptions = {}): void {",
+            "  | { GetUser: GetUserRequest }\n  | { List: ListRequest }\n  | { Log: LogRequest };",
+            "  | { GetUser: { Ok: GetUserResponse } | { Err: string } }\n  \
+             | { List: ListResponse | null };",
+            "export class UsersClient {",
+        ] {
+            assert!(
+                code.contains(expected),
+                "expected `{}` in:\n{}",
+                expected,
+                code
+            );
+        }
+        assert!(!code.contains("Ping"));
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Exposes tarpc services to [Cap'n Proto RPC](https://capnproto.org/rpc.html) clients.
+//!
+//! A [`Bridge`] is a capability that forwards each call made on it to a tarpc service over a
+//! [client channel](rpc::client::Channel). For each method of the Cap'n Proto interface, the
+//! bridge is given a function that reads the method's params into the tarpc service's request,
+//! and one that writes the tarpc service's response into the method's results:
+//!
+//! ```ignore
+//! let greeter: greeter::Client = Bridge::new(channel)
+//!     .method(
+//!         greeter::Client::TYPE_ID,
+//!         0,
+//!         |params| {
+//!             let params = params.get_as::<greeter::say_hello_params::Reader>()?;
+//!             Ok(GreeterRequest::Hello { name: params.get_name()?.to_string() })
+//!         },
+//!         |response, results| match response {
+//!             GreeterResponse::Hello(message) => {
+//!                 let mut results = results.init_as::<greeter::say_hello_results::Builder>();
+//!                 results.set_message(&message);
+//!                 Ok(())
+//!             }
+//!             _ => unreachable!(),
+//!         },
+//!     )
+//!     .into_client();
+//! let (reader, writer) = stream.split();
+//! let network = twoparty::VatNetwork::new(reader, writer, Side::Server, Default::default());
+//! RpcSystem::new(Box::new(network), Some(greeter.client)).await?;
+//! ```
+//!
+//! The bridge maps the rest of a call between the two:
+//!
+//! - **Promises**: each call returns a promise that resolves once the tarpc service responds.
+//!   Promises are pipelined as usual: calls made on the results of a call that's in flight are
+//!   queued by the RPC system until it resolves.
+//! - **Cancellation**: a call the client cancels, by dropping its promise, or whose connection
+//!   closes, is dropped, which cancels the tarpc request.
+//! - **Errors**: a tarpc request that fails fails the call, with the Cap'n Proto error type for
+//!   its [`io::ErrorKind`], e.g. `Overloaded` for requests the server sheds. Calls to methods the
+//!   bridge wasn't given fail as `Unimplemented`. RPCs declared with `#[throws]` can write the
+//!   errors they throw into their results, or fail the call with them, when writing their
+//!   responses.
+//!
+//! Cap'n Proto calls carry no deadline, so requests get the default
+//! [context](rpc::context::current)'s. Cap'n Proto methods return a single result, so streaming
+//! RPCs can't be bridged; a streaming interface would pass a callback capability instead.
+//!
+//! The RPC system is single-threaded, so the bridge is served on the thread the RPC system runs
+//! on, while the channel's dispatch runs wherever the channel was spawned.
+
+#![deny(missing_docs, missing_debug_implementations)]
+
+use capnp::{
+    any_pointer,
+    capability::{Params, Promise, Results},
+    private::capability::ServerHook,
+};
+use rpc::{client, context};
+use std::{collections::HashMap, fmt, io, rc::Rc};
+
+/// How one Cap'n Proto method is forwarded to the tarpc service.
+struct Method<Req, Resp> {
+    request: Box<dyn Fn(any_pointer::Reader<'_>) -> capnp::Result<Req>>,
+    response: Box<dyn Fn(Resp, any_pointer::Builder<'_>) -> capnp::Result<()>>,
+}
+
+/// Exposes a tarpc service as a Cap'n Proto capability.
+pub struct Bridge<Req, Resp> {
+    channel: client::Channel<Req, Resp>,
+    /// The methods, keyed by the ID of their interface and their ordinal within it.
+    methods: HashMap<(u64, u16), Rc<Method<Req, Resp>>>,
+}
+
+impl<Req, Resp> Bridge<Req, Resp> {
+    /// Returns a bridge that forwards calls over `channel`. It has no methods until they're
+    /// added with [`method`](Bridge::method).
+    pub fn new(channel: client::Channel<Req, Resp>) -> Self {
+        Bridge {
+            channel,
+            methods: HashMap::new(),
+        }
+    }
+
+    /// Serves the method with ordinal `method_id` of the interface with ID `interface_id`,
+    /// reading its params with `request` and writing the tarpc service's response into its
+    /// results with `response`.
+    ///
+    /// An interface's ID is the `TYPE_ID` of its generated client, and a method's ordinal is its
+    /// `@n` in the schema.
+    pub fn method<F, G>(
+        mut self,
+        interface_id: u64,
+        method_id: u16,
+        request: F,
+        response: G,
+    ) -> Self
+    where
+        F: Fn(any_pointer::Reader<'_>) -> capnp::Result<Req> + 'static,
+        G: Fn(Resp, any_pointer::Builder<'_>) -> capnp::Result<()> + 'static,
+    {
+        let method = Method {
+            request: Box::new(request),
+            response: Box::new(response),
+        };
+        self.methods
+            .insert((interface_id, method_id), Rc::new(method));
+        self
+    }
+}
+
+impl<Req, Resp> Bridge<Req, Resp>
+where
+    Req: 'static,
+    Resp: 'static,
+{
+    /// Returns a capability that serves calls with the bridge. Convert it to the interface's
+    /// generated client with `FromClientHook::new(client.hook)`.
+    pub fn into_client(self) -> capnp::capability::Client {
+        capnp_rpc::Server::new_client(Box::new(self))
+    }
+}
+
+impl<Req, Resp> capnp::capability::Server for Bridge<Req, Resp>
+where
+    Req: 'static,
+    Resp: 'static,
+{
+    fn dispatch_call(
+        &mut self,
+        interface_id: u64,
+        method_id: u16,
+        params: Params<any_pointer::Owned>,
+        mut results: Results<any_pointer::Owned>,
+    ) -> Promise<(), capnp::Error> {
+        let method = match self.methods.get(&(interface_id, method_id)) {
+            Some(method) => method.clone(),
+            None => {
+                return Promise::err(capnp::Error::unimplemented(format!(
+                    "method {} of interface {:#x} isn't bridged",
+                    method_id, interface_id
+                )))
+            }
+        };
+        let request = match params.get().and_then(|params| (method.request)(params)) {
+            Ok(request) => request,
+            Err(e) => return Promise::err(e),
+        };
+        let mut channel = self.channel.clone();
+        Promise::from_future(async move {
+            let response = channel
+                .call(context::current(), request)
+                .await
+                .map_err(error)?;
+            (method.response)(response, results.get())
+        })
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Bridge<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Bridge")
+            .field("methods", &self.methods.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Returns the Cap'n Proto error for a failed tarpc request.
+fn error(e: io::Error) -> capnp::Error {
+    let description = e.to_string();
+    match e.kind() {
+        // Servers shed load with WouldBlock.
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+            capnp::Error::overloaded(description)
+        }
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe => capnp::Error::disconnected(description),
+        _ => capnp::Error::failed(description),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{self, Ready};
+    use rpc::{
+        server::{BaseChannel, Channel},
+        transport::channel,
+    };
+
+    const INTERFACE_ID: u64 = 0xa2b4_2e1f_5ad0_1c3e;
+
+    #[tarpc::service(derive_serde = false)]
+    trait Greeter {
+        async fn hello(name: String) -> String;
+    }
+
+    #[derive(Clone)]
+    struct GreeterServer;
+
+    impl Greeter for GreeterServer {
+        type HelloFut = Ready<String>;
+
+        fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+            future::ready(format!("Hello, {}!", name))
+        }
+    }
+
+    fn bridge() -> io::Result<capnp::capability::Client> {
+        let (client_transport, server_transport) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .respond_with(GreeterServer.serve())
+                .execute(),
+        );
+        let channel = client::new(client::Config::default(), client_transport).spawn()?;
+        Ok(Bridge::new(channel)
+            .method(
+                INTERFACE_ID,
+                0,
+                |params| {
+                    let name = params.get_as::<capnp::text::Reader>()?;
+                    Ok(GreeterRequest::Hello {
+                        name: name.to_string(),
+                    })
+                },
+                |response, results| match response {
+                    GreeterResponse::Hello(message) => results.set_as(&*message),
+                    _ => unreachable!(),
+                },
+            )
+            .into_client())
+    }
+
+    #[tokio::test]
+    async fn forwards_calls() -> io::Result<()> {
+        let client = bridge()?;
+
+        let mut request =
+            client.new_call::<any_pointer::Owned, any_pointer::Owned>(INTERFACE_ID, 0, None);
+        request.get().set_as("Ada").unwrap();
+        let response = request.send().promise.await.unwrap();
+        let message = response
+            .get()
+            .unwrap()
+            .get_as::<capnp::text::Reader>()
+            .unwrap();
+        assert_eq!(message, "Hello, Ada!");
+
+        let request =
+            client.new_call::<any_pointer::Owned, any_pointer::Owned>(INTERFACE_ID, 1, None);
+        let e = request.send().promise.await.err().unwrap();
+        assert_eq!(e.kind, capnp::ErrorKind::Unimplemented);
+        Ok(())
+    }
+}
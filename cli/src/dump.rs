@@ -0,0 +1,75 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use clap::{App, Arg, ArgGroup};
+use std::{fs, io};
+use tarpc_bincode_transport::dump::{self, Peer};
+
+fn main() -> io::Result<()> {
+    let flags = App::new("tarpc dump")
+        .version("0.1")
+        .about("Decodes captured traffic of the bincode transport into readable messages.")
+        .arg(
+            Arg::with_name("input")
+                .value_name("FILE")
+                .help("The capture to decode")
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("p")
+                .long("port")
+                .value_name("PORT")
+                .help("Decodes FILE as a pcap capture of the connections to the server on PORT")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sender")
+                .long("sender")
+                .value_name("PEER")
+                .help("Decodes FILE as the raw bytes sent by one end of a connection")
+                .possible_values(&["client", "server"])
+                .takes_value(true),
+        )
+        .group(
+            ArgGroup::with_name("format")
+                .args(&["port", "sender"])
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("schema")
+                .long("schema")
+                .value_name("FILE")
+                .help("Names RPCs per the service's schema, serialized to JSON, read from FILE")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let schema = match flags.value_of("schema") {
+        Some(schema) => Some(cli::read_schema(schema)?),
+        None => None,
+    };
+    let input = fs::read(flags.value_of("input").unwrap())?;
+    let records = match flags.value_of("port") {
+        Some(port) => {
+            let port = port
+                .parse()
+                .unwrap_or_else(|e| panic!(r#"--port value "{}" invalid: {}"#, port, e));
+            dump::decode_pcap(&input, port, schema.as_ref())?
+        }
+        None => {
+            let sender = match flags.value_of("sender").unwrap() {
+                "client" => Peer::Client,
+                _ => Peer::Server,
+            };
+            dump::decode_stream(&input, sender, schema.as_ref())
+        }
+    };
+    for record in records {
+        println!("{}", record);
+    }
+    Ok(())
+}
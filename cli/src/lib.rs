@@ -0,0 +1,245 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Calls the RPCs of a service with JSON arguments, without code generated for the service.
+//!
+//! A service's [schema](tarpc::schema::Service), serialized to JSON, describes its RPCs. The
+//! `service` macro emits the schema with `schema = true`, and the service's own code can write it
+//! out, e.g. with `serde_json::to_writer(file, &WORLD_SCHEMA)`.
+//! [`request`] builds the JSON request to an RPC from the schema and a JSON object of arguments,
+//! [`invoke`] sends it over a client channel, and [`response`] unwraps the JSON responses the
+//! server sends back. The server must speak JSON, e.g. over the JSON transport, and derive
+//! serde for its requests and responses, as the `service` macro does by default.
+
+use futures::prelude::*;
+use serde_json::{Map, Value};
+use std::{fs, io, path::Path};
+use tarpc::{
+    client, context,
+    schema::{Method, MethodKind, Service},
+    ClientMessage, Response, Transport,
+};
+
+/// Reads a service's schema, serialized to JSON, from the file at `path`.
+pub fn read_schema(path: impl AsRef<Path>) -> io::Result<Service> {
+    let path = path.as_ref();
+    let file = fs::File::open(path)?;
+    serde_json::from_reader(io::BufReader::new(file)).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a service schema: {}", path.display(), e),
+        )
+    })
+}
+
+/// Returns the request that invokes `method` with `args`, a JSON object keyed by argument name.
+/// `null` stands in for an RPC that takes no arguments.
+///
+/// The arguments are sent as given, so their names must match the names on the wire, which
+/// differ from the names in the schema if the service sets `wire_case`.
+pub fn request(method: &Method, args: Value) -> io::Result<Value> {
+    if let Some(removed) = method.removed {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} was removed in version {}", method.name, removed),
+        ));
+    }
+    let args = match args {
+        Value::Null => Value::Object(Map::new()),
+        Value::Object(args) => Value::Object(args),
+        args => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the arguments of {} must be a JSON object keyed by argument name, not {}",
+                    method.name, args
+                ),
+            ))
+        }
+    };
+    let mut request = Map::new();
+    request.insert(method.wire_name.to_string(), args);
+    Ok(Value::Object(request))
+}
+
+/// Unwraps the output of `method` from the server's `response` to it.
+pub fn response(method: &Method, response: Value) -> io::Result<Value> {
+    match response {
+        Value::Object(mut response) if response.len() == 1 => {
+            if let Some(output) = response.remove(&*method.wire_name) {
+                return Ok(output);
+            }
+            Err(unexpected_response(method, Value::Object(response)))
+        }
+        response => Err(unexpected_response(method, response)),
+    }
+}
+
+fn unexpected_response(method: &Method, response: Value) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("expected a response to {}, got {}", method.name, response),
+    )
+}
+
+/// Invokes `method` with `args`, calling `output` with each output the server responds with:
+/// one for a unary RPC, any number for a streaming RPC, and none for a one-way RPC.
+pub async fn invoke(
+    channel: &mut client::Channel<Value, Value>,
+    ctx: context::Context,
+    method: &Method,
+    args: Value,
+    mut output: impl FnMut(Value),
+) -> io::Result<()> {
+    let request = request(method, args)?;
+    match method.kind {
+        MethodKind::Unary => output(response(method, channel.call(ctx, request).await?)?),
+        MethodKind::OneWay => channel.notify(ctx, request).await?,
+        MethodKind::Streaming => {
+            let mut responses = channel.call_stream(ctx, request).await?;
+            while let Some(next) = responses.next().await {
+                output(response(method, next?)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns a client channel that speaks JSON over `transport`, spawning its dispatch on the
+/// default executor.
+pub fn connect<C>(config: client::Config, transport: C) -> io::Result<client::Channel<Value, Value>>
+where
+    C: Transport<ClientMessage<Value>, Response<Value>> + Send + 'static,
+{
+    client::new(config, transport).spawn()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use serde_json::json;
+    use std::time::Duration;
+    use tarpc::{
+        server::{self, Channel},
+        transport::channel,
+    };
+
+    #[tarpc::service(schema = true)]
+    trait Greeter {
+        async fn hello(name: String) -> String;
+        #[name = "Wave"]
+        async fn greet_all() -> u32;
+        #[stream]
+        async fn count(to: u32) -> u32;
+        #[oneway]
+        async fn log(line: String);
+    }
+
+    #[derive(Clone)]
+    struct GreeterServer;
+
+    impl Greeter for GreeterServer {
+        type HelloFut = future::Ready<String>;
+
+        fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+            future::ready(format!("Hello, {}!", name))
+        }
+
+        type GreetAllFut = future::Ready<u32>;
+
+        fn greet_all(self, _: context::Context) -> Self::GreetAllFut {
+            future::ready(7)
+        }
+
+        type CountStream = stream::Iter<std::ops::RangeInclusive<u32>>;
+
+        fn count(self, _: context::Context, to: u32) -> Self::CountStream {
+            stream::iter(1..=to)
+        }
+
+        type LogFut = future::Ready<()>;
+
+        fn log(self, _: context::Context, _: String) -> Self::LogFut {
+            future::ready(())
+        }
+    }
+
+    fn method(name: &str) -> &'static Method {
+        GREETER_SCHEMA.method(name).unwrap()
+    }
+
+    /// Round-trips `value` through JSON, as the JSON transport would.
+    fn through_json<T: serde::Serialize, U: serde::de::DeserializeOwned>(
+        value: T,
+    ) -> io::Result<U> {
+        serde_json::from_slice(&serde_json::to_vec(&value)?).map_err(io::Error::from)
+    }
+
+    #[test]
+    fn requests_are_keyed_by_wire_name() -> io::Result<()> {
+        assert_eq!(
+            request(method("hello"), json!({"name": "Tim"}))?,
+            json!({"Hello": {"name": "Tim"}})
+        );
+        assert_eq!(
+            request(method("greet_all"), Value::Null)?,
+            json!({"Wave": {}})
+        );
+        assert_eq!(
+            request(method("hello"), json!(["Tim"])).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(response(method("greet_all"), json!({"Wave": 7}))?, json!(7));
+        assert_eq!(
+            response(method("hello"), json!({"Wave": 7}))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invokes_every_kind_of_rpc() -> io::Result<()> {
+        let (client_transport, server_transport) = channel::unbounded();
+        tokio::spawn(
+            server::BaseChannel::with_defaults(server_transport)
+                .respond_with(GreeterServer.serve())
+                .execute(),
+        );
+        // The client speaks JSON, and the server its own types.
+        let client_transport = client_transport
+            .with(|message: ClientMessage<Value>| future::ready(through_json(message)))
+            .map(|response: io::Result<Response<GreeterResponse>>| through_json(response?));
+        let mut channel = connect(client::Config::default(), client_transport)?;
+
+        let mut outputs = vec![];
+        let mut ctx = context::current();
+        ctx.deadline += Duration::from_secs(10);
+        let calls = [
+            ("hello", json!({"name": "Tim"})),
+            ("greet_all", Value::Null),
+            ("count", json!({"to": 3})),
+            ("log", json!({"line": "ignored"})),
+        ];
+        for (name, args) in calls.iter() {
+            invoke(
+                &mut channel,
+                ctx.clone(),
+                method(name),
+                args.clone(),
+                |output| outputs.push(output),
+            )
+            .await?;
+        }
+        assert_eq!(
+            outputs,
+            vec![json!("Hello, Tim!"), json!(7), json!(1), json!(2), json!(3)]
+        );
+        Ok(())
+    }
+}
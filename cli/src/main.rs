@@ -0,0 +1,212 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::{
+    io, process,
+    time::{Duration, SystemTime},
+};
+use tarpc::{client, context, schema, wire};
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    env_logger::init();
+
+    let schema = Arg::with_name("schema")
+        .long("schema")
+        .value_name("FILE")
+        .help("Reads the service's schema, serialized to JSON, from FILE")
+        .required(true)
+        .takes_value(true);
+    let flags = App::new("tarpc cli")
+        .version("0.1")
+        .about(
+            "Lists the RPCs of a service, calls them with JSON arguments, checks new versions for \
+             breaking changes, and generates clients and docs for it.",
+        )
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Prints the service's definition")
+                .arg(schema.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("openapi")
+                .about("Prints the OpenAPI document describing the service's HTTP gateway")
+                .arg(schema.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("typescript")
+                .about("Prints a TypeScript client of the service")
+                .arg(schema.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("python")
+                .about("Prints an asyncio Python client of the service")
+                .arg(schema.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("call")
+                .about("Calls an RPC over the JSON transport, printing each output")
+                .arg(schema)
+                .arg(
+                    Arg::with_name("server_addr")
+                        .long("server_addr")
+                        .value_name("ADDRESS")
+                        .help("Sets the server address to connect to.")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .short("t")
+                        .long("timeout")
+                        .value_name("SECONDS")
+                        .help("Sets how long to wait for the server to respond")
+                        .default_value("10")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("method")
+                        .value_name("RPC")
+                        .help("The name of the RPC to call")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .value_name("JSON")
+                        .help(
+                            "The arguments of the RPC, as a JSON object keyed by argument name, \
+                             e.g. '{\"name\": \"Tim\"}'",
+                        )
+                        .default_value("{}"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("compat")
+                .about(
+                    "Prints the changes between two versions of a service that break peers \
+                     built against the old version, exiting with status 1 if there are any",
+                )
+                .arg(
+                    Arg::with_name("old")
+                        .long("old")
+                        .value_name("FILE")
+                        .help("Reads the old version's schema, serialized to JSON, from FILE")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .value_name("FILE")
+                        .help("Reads the new version's schema, serialized to JSON, from FILE")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(SubCommand::with_name("conformance").about(
+            "Prints the wire protocol's conformance vectors as JSON, with their encodings by the \
+             bincode transport, in hex, and by the JSON transport",
+        ))
+        .get_matches();
+
+    match flags.subcommand() {
+        ("list", Some(flags)) => {
+            println!("{}", cli::read_schema(flags.value_of("schema").unwrap())?);
+            Ok(())
+        }
+        ("openapi", Some(flags)) => {
+            let service = cli::read_schema(flags.value_of("schema").unwrap())?;
+            let document = tarpc_gateway::openapi::document(&service);
+            println!("{}", serde_json::to_string_pretty(&document)?);
+            Ok(())
+        }
+        ("typescript", Some(flags)) => {
+            let service = cli::read_schema(flags.value_of("schema").unwrap())?;
+            print!("{}", tarpc_build::typescript::generate(&service));
+            Ok(())
+        }
+        ("python", Some(flags)) => {
+            let service = cli::read_schema(flags.value_of("schema").unwrap())?;
+            print!("{}", tarpc_build::python::generate(&service));
+            Ok(())
+        }
+        ("call", Some(flags)) => call(flags).await,
+        ("compat", Some(flags)) => compat(flags),
+        ("conformance", Some(_)) => conformance(),
+        _ => unreachable!("clap requires a subcommand"),
+    }
+}
+
+fn compat(flags: &ArgMatches<'_>) -> io::Result<()> {
+    let old = cli::read_schema(flags.value_of("old").unwrap())?;
+    let new = cli::read_schema(flags.value_of("new").unwrap())?;
+    let changes = schema::breaking_changes(&old, &new);
+    for change in &changes {
+        println!("{}", change);
+    }
+    if !changes.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn conformance() -> io::Result<()> {
+    let mut vectors = vec![];
+    for vector in wire::vectors() {
+        let bincode: String = vector
+            .encode::<tarpc_bincode_transport::Bincode>()?
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        // Kept as a string, since trace IDs don't fit in the numbers most JSON parsers read.
+        let json = String::from_utf8(vector.encode::<tarpc_json_transport::Json>()?)
+            .expect("serde_json writes UTF-8");
+        vectors.push(serde_json::json!({
+            "name": vector.name,
+            "description": vector.description,
+            "bincode": bincode,
+            "json": json,
+        }));
+    }
+    let document = serde_json::json!({"version": wire::VERSION, "vectors": vectors});
+    println!("{}", serde_json::to_string_pretty(&document)?);
+    Ok(())
+}
+
+async fn call(flags: &ArgMatches<'_>) -> io::Result<()> {
+    let service = cli::read_schema(flags.value_of("schema").unwrap())?;
+    let name = flags.value_of("method").unwrap();
+    let method = service.method(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no RPC named {}", service.name, name),
+        )
+    })?;
+    let args = flags.value_of("args").unwrap();
+    let args = serde_json::from_str(args)
+        .unwrap_or_else(|e| panic!(r#"arguments "{}" invalid: {}"#, args, e));
+
+    let server_addr = flags.value_of("server_addr").unwrap();
+    let server_addr = server_addr
+        .parse()
+        .unwrap_or_else(|e| panic!(r#"--server_addr value "{}" invalid: {}"#, server_addr, e));
+    let timeout = flags.value_of("timeout").unwrap();
+    let timeout = timeout
+        .parse()
+        .unwrap_or_else(|e| panic!(r#"--timeout value "{}" invalid: {}"#, timeout, e));
+
+    let transport = tarpc_json_transport::connect(&server_addr).await?;
+    let mut channel = cli::connect(client::Config::default(), transport)?;
+
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + Duration::from_secs(timeout);
+    cli::invoke(&mut channel, ctx, method, args, |output| {
+        println!("{}", output)
+    })
+    .await
+}
@@ -0,0 +1,1068 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serves tarpc services on the [D-Bus](https://www.freedesktop.org/wiki/Software/dbus/) session
+//! and system buses, so that Linux system components, and tools like `busctl`, can call them.
+//!
+//! A [`Transport`] translates between the method calls made on an object exported on the bus
+//! and tarpc's messages. It reads [`MethodCall`]s from, and writes [`Reply`]s to, a stream and
+//! sink, so it works with any D-Bus library whose connection can be adapted to them, e.g. by
+//! forwarding the calls made on an object path:
+//!
+//! ```ignore
+//! connection.request_name("com.example.Greeter", false, true, false)?;
+//! let transport = dbus::Transport::<_, GreeterRequest, GreeterResponse>::new(
+//!     object_calls(&connection, "/com/example/Greeter"),
+//!     "com.example.Greeter",
+//! );
+//! BaseChannel::with_defaults(transport)
+//!     .respond_with(GreeterServer.serve())
+//!     .execute()
+//!     .await;
+//! ```
+//!
+//! after which
+//!
+//! ```text
+//! $ busctl --user call com.example.Greeter /com/example/Greeter com.example.Greeter Hello s Ada
+//! s "Hello, Ada!"
+//! ```
+//!
+//! The service's RPCs are the methods of the interface the transport is given, named in
+//! UpperCamelCase, as is D-Bus convention: `get_user` is `GetUser`. The transport also answers
+//! `org.freedesktop.DBus.Peer.Ping`, and `org.freedesktop.DBus.Introspectable.Introspect` with
+//! the interface [generated](introspect) from the service's [schema](rpc::schema), which
+//! requires the service be declared with `#[tarpc::service(schema = true)]`.
+//!
+//! Arguments and outputs are mapped to D-Bus types by their types in the service definition:
+//!
+//! - `bool`, integers, floats, and strings are D-Bus's basic types, e.g. `u32` is `u` and
+//!   `String` is `s`. D-Bus has no signed byte nor single-precision float, so `i8` is `n` and
+//!   `f32` is `d`.
+//! - Lists and arrays are arrays, and so is `Option`, of zero elements or one.
+//! - Tuples are structs.
+//! - Maps whose keys are basic types are dicts.
+//! - Any other type, like a struct of the service's, or a 128-bit integer, is a string holding
+//!   the value as JSON.
+//!
+//! A streaming RPC returns an array of every item it streamed, of which there can be at most
+//! [`MAX_ITEMS`](json::MAX_ITEMS); longer streams, and outputs that fail to serialize, fail with
+//! `org.freedesktop.DBus.Error.Failed`. A one-way RPC returns nothing, at once. Errors thrown by
+//! RPCs declared with `#[throws]` are returned as D-Bus errors named `<interface>.Error`, whose
+//! message is the error as JSON. Requests the server fails get the standard D-Bus error for
+//! their [`io::ErrorKind`], e.g. `org.freedesktop.DBus.Error.Timeout` for requests whose
+//! deadline passed.
+//!
+//! D-Bus calls carry no deadline, so requests get the default [context](rpc::context::current)'s,
+//! with the unique bus name of the caller in the [`SENDER`] metadata entry. The bus
+//! authenticates callers, so services can ask it for the caller's user ID to authorize them.
+
+#![deny(missing_docs, missing_debug_implementations)]
+
+use futures::prelude::*;
+use pin_utils::unsafe_pinned;
+use rpc::{
+    context,
+    schema::{snake_to_camel, Introspect, Method, MethodKind, Type},
+    transport::adapter::{
+        json::{self, Items},
+        Adapter, Protocol, Queue,
+    },
+    ClientMessage, Response, ServerError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Number, Value as Json};
+use std::{
+    f64, fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The metadata entry holding the unique bus name of the connection that made a call, e.g.
+/// `:1.42`.
+pub const SENDER: &str = "dbus-sender";
+
+const INTROSPECTABLE: &str = "org.freedesktop.DBus.Introspectable";
+const PEER: &str = "org.freedesktop.DBus.Peer";
+
+/// A value of a D-Bus type.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    /// `b`.
+    Bool(bool),
+    /// `y`.
+    Byte(u8),
+    /// `n`.
+    Int16(i16),
+    /// `q`.
+    UInt16(u16),
+    /// `i`.
+    Int32(i32),
+    /// `u`.
+    UInt32(u32),
+    /// `x`.
+    Int64(i64),
+    /// `t`.
+    UInt64(u64),
+    /// `d`.
+    Double(f64),
+    /// `s`, or an object path or signature.
+    String(String),
+    /// An array of any type other than a dict entry.
+    Array(Vec<Value>),
+    /// An array of dict entries, in order.
+    Dict(Vec<(Value, Value)>),
+    /// A struct.
+    Struct(Vec<Value>),
+    /// `v`.
+    Variant(Box<Value>),
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+/// A method call made on the exported object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MethodCall {
+    /// The unique bus name of the connection that made the call.
+    pub sender: Option<String>,
+    /// The call's serial number, which its reply refers to.
+    pub serial: u32,
+    /// The object path the method was called on.
+    pub path: String,
+    /// The interface of the method, if the caller named it.
+    pub interface: Option<String>,
+    /// The name of the method.
+    pub member: String,
+    /// The call's arguments.
+    pub args: Vec<Value>,
+    /// Whether the caller set the `NO_REPLY_EXPECTED` flag.
+    pub no_reply: bool,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl MethodCall {
+    /// Returns a call, expecting a reply, of the method `member` of `interface`, on the root
+    /// object.
+    pub fn new(
+        serial: u32,
+        interface: impl Into<String>,
+        member: impl Into<String>,
+        args: Vec<Value>,
+    ) -> Self {
+        MethodCall {
+            sender: None,
+            serial,
+            path: "/".into(),
+            interface: Some(interface.into()),
+            member: member.into(),
+            args,
+            no_reply: false,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+/// A reply to a method call, to send on the bus.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply {
+    /// The unique bus name of the connection that made the call.
+    pub destination: Option<String>,
+    /// The serial number of the call.
+    pub reply_serial: u32,
+    /// What the reply holds.
+    pub body: Body,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+/// What a [`Reply`] holds.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Body {
+    /// The method returned.
+    Return {
+        /// The signature of the values returned, e.g. `as` for an array of strings.
+        signature: String,
+        /// The values returned: one, or none if the signature is empty.
+        args: Vec<Value>,
+    },
+    /// The method failed.
+    Error {
+        /// The name of the error, e.g. `org.freedesktop.DBus.Error.InvalidArgs`.
+        name: String,
+        /// A message describing the error.
+        message: String,
+    },
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl Body {
+    fn empty() -> Self {
+        Body::Return {
+            signature: String::new(),
+            args: vec![],
+        }
+    }
+
+    /// Returns one of the errors D-Bus defines, e.g. `InvalidArgs`.
+    fn error(name: &str, message: String) -> Self {
+        Body::Error {
+            name: format!("org.freedesktop.DBus.Error.{}", name),
+            message,
+        }
+    }
+}
+
+/// A transport that serves method calls made on an object exported on the bus as tarpc
+/// requests.
+pub struct Transport<T, Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    inner: Adapter<T, DBus<Req, Resp>>,
+}
+
+/// Translates between D-Bus method calls and tarpc's messages.
+struct DBus<Req, Resp> {
+    interface: String,
+    ghost: PhantomData<fn(Resp) -> Req>,
+}
+
+/// A call awaiting its response.
+struct Call {
+    sender: Option<String>,
+    serial: u32,
+    method: &'static Method,
+    /// The items streamed so far, for a streaming RPC.
+    items: Items,
+}
+
+impl<T, Req, Resp> Transport<T, Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    unsafe_pinned!(inner: Adapter<T, DBus<Req, Resp>>);
+
+    /// Returns a transport that serves the calls `inner` yields as methods of `interface`,
+    /// e.g. `com.example.Greeter`, and writes their replies to `inner`.
+    pub fn new(inner: T, interface: impl Into<String>) -> Self {
+        let protocol = DBus {
+            interface: interface.into(),
+            ghost: PhantomData,
+        };
+        Transport {
+            inner: Adapter::new(inner, protocol),
+        }
+    }
+
+    /// Returns the underlying transport.
+    pub fn get_ref(&self) -> &T {
+        self.inner.get_ref()
+    }
+
+    /// Returns the interface the service's RPCs are methods of.
+    pub fn interface(&self) -> &str {
+        &self.inner.protocol().interface
+    }
+}
+
+impl<Req, Resp> Protocol for DBus<Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    type Req = Req;
+    type Resp = Resp;
+    type Incoming = MethodCall;
+    type Outgoing = Reply;
+    type Call = Call;
+
+    fn receive(&mut self, call: MethodCall, queue: &mut Queue<Self>) -> io::Result<()> {
+        let MethodCall {
+            sender,
+            serial,
+            interface,
+            member,
+            args,
+            no_reply,
+            ..
+        } = call;
+        let ours = interface.as_ref().map_or(true, |i| *i == self.interface);
+        let reply = |queue: &mut Queue<Self>, body| {
+            if !no_reply {
+                queue.reply(Reply {
+                    destination: sender.clone(),
+                    reply_serial: serial,
+                    body,
+                    _non_exhaustive: (),
+                });
+            }
+        };
+
+        let method = Req::METHODS.iter().find(|method| {
+            ours && method.removed.is_none()
+                && (snake_to_camel(&method.name) == member || method.name == member)
+        });
+        let method = match (method, interface.as_ref().map(|i| &i[..]), &member[..]) {
+            (Some(method), _, _) => method,
+            (None, None, "Introspect") | (None, Some(INTROSPECTABLE), "Introspect") => {
+                let xml = introspect::<Req>(&self.interface);
+                reply(
+                    queue,
+                    Body::Return {
+                        signature: "s".into(),
+                        args: vec![Value::String(xml)],
+                    },
+                );
+                return Ok(());
+            }
+            (None, None, "Ping") | (None, Some(PEER), "Ping") => {
+                reply(queue, Body::empty());
+                return Ok(());
+            }
+            (None, Some(interface), _)
+                if !ours && interface != INTROSPECTABLE && interface != PEER =>
+            {
+                reply(
+                    queue,
+                    Body::error(
+                        "UnknownInterface",
+                        format!("no interface named `{}`", interface),
+                    ),
+                );
+                return Ok(());
+            }
+            (None, _, _) => {
+                reply(
+                    queue,
+                    Body::error("UnknownMethod", format!("no method named `{}`", member)),
+                );
+                return Ok(());
+            }
+        };
+
+        if args.len() != method.args.len() {
+            reply(
+                queue,
+                Body::error(
+                    "InvalidArgs",
+                    format!(
+                        "`{}` takes {} arguments, got {}",
+                        member,
+                        method.args.len(),
+                        args.len()
+                    ),
+                ),
+            );
+            return Ok(());
+        }
+        let args = args
+            .into_iter()
+            .zip(method.args.iter())
+            .map(|(value, arg)| {
+                to_json(value, &Type::parse(&arg.ty))
+                    .map_err(|e| format!("invalid argument `{}`: {}", arg.name, e))
+            })
+            .collect::<Result<Vec<_>, _>>();
+        let message = args.and_then(|args| {
+            let mut message = Map::new();
+            message.insert(method.wire_name.to_string(), Json::Array(args));
+            // The arguments are positional, since their names on the wire depend on the
+            // service's `wire_case`. Struct variants can be read from arrays in JSON text, but
+            // not from `Value`s.
+            serde_json::from_str(&Json::Object(message).to_string())
+                .map_err(|e| format!("invalid arguments: {}", e))
+        });
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                reply(queue, Body::error("InvalidArgs", e));
+                return Ok(());
+            }
+        };
+
+        let mut ctx = context::current();
+        if let Some(ref sender) = sender {
+            ctx.metadata.insert(SENDER.into(), sender.clone());
+        }
+        if method.kind == MethodKind::OneWay {
+            // Nothing's returned, so there's nothing to wait for.
+            reply(queue, Body::empty());
+            queue.request(ctx, message, None);
+        } else if no_reply {
+            queue.request(ctx, message, None);
+        } else {
+            let call = Call {
+                sender,
+                serial,
+                method,
+                items: Items::default(),
+            };
+            queue.request(ctx, message, Some(call));
+        }
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        call: &mut Call,
+        response: Response<Resp>,
+        queue: &mut Queue<Self>,
+    ) -> io::Result<()> {
+        let method = call.method;
+        let result = match response.message {
+            // Streamed items are sent as `Some(item)`.
+            Ok(item) if response.partial => {
+                call.items.push(method, item);
+                return Ok(());
+            }
+            Ok(_) if method.kind == MethodKind::Streaming => call.items.finish().map(Ok),
+            Ok(output) => {
+                json::output(method, output).and_then(|output| json::result(method, output))
+            }
+            Err(e) => {
+                queue.reply(call.reply(server_error(e)));
+                return Ok(());
+            }
+        };
+        let ty = Type::parse(&method.output);
+        let body = match result {
+            Ok(Ok(items)) if method.kind == MethodKind::Streaming => {
+                call.method_return(items, &Type::List(Box::new(ty)))
+            }
+            Ok(Ok(output)) => call.method_return(output, &ty),
+            Ok(Err(thrown)) => Body::Error {
+                name: format!("{}.Error", self.interface),
+                message: thrown.to_string(),
+            },
+            Err(e) => Body::error(
+                "Failed",
+                format!(
+                    "the response to `{}` failed to serialize: {}",
+                    method.name, e
+                ),
+            ),
+        };
+        queue.reply(call.reply(body));
+        Ok(())
+    }
+}
+
+impl Call {
+    fn reply(&self, body: Body) -> Reply {
+        Reply {
+            destination: self.sender.clone(),
+            reply_serial: self.serial,
+            body,
+            _non_exhaustive: (),
+        }
+    }
+
+    /// Returns `output`, of type `ty`, as the method's return value.
+    fn method_return(&self, output: Json, ty: &Type) -> Body {
+        if *ty == Type::Unit {
+            return Body::empty();
+        }
+        match from_json(output, ty) {
+            Ok(value) => Body::Return {
+                signature: signature(ty),
+                args: vec![value],
+            },
+            Err(e) => Body::error(
+                "Failed",
+                format!("the response to `{}` is invalid: {}", self.method.name, e),
+            ),
+        }
+    }
+}
+
+impl<T, Req, Resp> Stream for Transport<T, Req, Resp>
+where
+    T: Stream<Item = io::Result<MethodCall>> + Sink<Reply, Error = io::Error>,
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    type Item = io::Result<ClientMessage<Req>>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<ClientMessage<Req>>>> {
+        self.inner().poll_next(cx)
+    }
+}
+
+impl<T, Req, Resp> Sink<Response<Resp>> for Transport<T, Req, Resp>
+where
+    T: Sink<Reply, Error = io::Error>,
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, response: Response<Resp>) -> io::Result<()> {
+        self.inner().start_send(response)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<Req, Resp> fmt::Debug for DBus<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DBus")
+            .field("interface", &self.interface)
+            .finish()
+    }
+}
+
+impl<T, Req, Resp> fmt::Debug for Transport<T, Req, Resp>
+where
+    T: fmt::Debug,
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transport")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+/// Returns the D-Bus introspection data of an object serving the service `Req` as
+/// `interface`, as the [`Transport`] serves it.
+pub fn introspect<Req: Introspect>(interface: &str) -> String {
+    let mut xml = String::from(
+        "<!DOCTYPE node PUBLIC \"-//freedesktop//DTD D-BUS Object Introspection 1.0//EN\"\n \
+         \"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd\">\n\
+         <node>\n",
+    );
+    xml.push_str(&format!(
+        "  <interface name=\"{}\">\n    <method name=\"Introspect\">\n      \
+         <arg name=\"xml_data\" type=\"s\" direction=\"out\"/>\n    </method>\n  </interface>\n",
+        INTROSPECTABLE
+    ));
+    xml.push_str(&format!(
+        "  <interface name=\"{}\">\n    <method name=\"Ping\"/>\n  </interface>\n",
+        PEER
+    ));
+    xml.push_str(&format!("  <interface name=\"{}\">\n", interface));
+    for method in Req::METHODS
+        .iter()
+        .filter(|method| method.removed.is_none())
+    {
+        xml.push_str(&format!(
+            "    <method name=\"{}\">\n",
+            snake_to_camel(&method.name)
+        ));
+        for arg in method.args.iter() {
+            xml.push_str(&format!(
+                "      <arg name=\"{}\" type=\"{}\" direction=\"in\"/>\n",
+                arg.name,
+                signature(&Type::parse(&arg.ty))
+            ));
+        }
+        let output = Type::parse(&method.output);
+        match method.kind {
+            MethodKind::Unary if output == Type::Unit => {}
+            MethodKind::Unary => xml.push_str(&format!(
+                "      <arg name=\"output\" type=\"{}\" direction=\"out\"/>\n",
+                signature(&output)
+            )),
+            MethodKind::Streaming => xml.push_str(&format!(
+                "      <arg name=\"items\" type=\"a{}\" direction=\"out\"/>\n",
+                signature(&output)
+            )),
+            MethodKind::OneWay => xml.push_str(
+                "      <annotation name=\"org.freedesktop.DBus.Method.NoReply\" value=\"true\"/>\n",
+            ),
+        }
+        xml.push_str("    </method>\n");
+    }
+    xml.push_str("  </interface>\n</node>\n");
+    xml
+}
+
+/// Returns the D-Bus error for a request the server failed.
+fn server_error(ServerError { kind, detail, .. }: ServerError) -> Body {
+    let name = match kind {
+        io::ErrorKind::PermissionDenied => "AccessDenied",
+        io::ErrorKind::TimedOut => "Timeout",
+        // Servers shed load with WouldBlock.
+        io::ErrorKind::WouldBlock => "LimitsExceeded",
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => "InvalidArgs",
+        io::ErrorKind::AlreadyExists => "FileExists",
+        io::ErrorKind::NotFound => "FileNotFound",
+        _ => "Failed",
+    };
+    Body::error(name, detail.unwrap_or_else(|| format!("{:?}", kind)))
+}
+
+/// Returns whether values of `ty` are of a D-Bus basic type that can key a dict.
+fn is_basic(ty: &Type) -> bool {
+    match *ty {
+        Type::Bool | Type::Char | Type::String => true,
+        Type::Integer { bits, .. } => bits <= 64,
+        _ => false,
+    }
+}
+
+/// Returns the D-Bus signature of values of `ty`.
+fn signature(ty: &Type) -> String {
+    match *ty {
+        Type::Bool => "b".into(),
+        Type::Integer { signed, bits } => match (signed, bits) {
+            (false, 8) => "y",
+            (true, 8) | (true, 16) => "n",
+            (false, 16) => "q",
+            (true, 32) => "i",
+            (false, 32) => "u",
+            (true, 64) => "x",
+            (false, 64) => "t",
+            _ => "s",
+        }
+        .into(),
+        Type::Float { .. } => "d".into(),
+        Type::Option(ref ty) | Type::List(ref ty) | Type::Array(ref ty, _) => {
+            format!("a{}", signature(ty))
+        }
+        Type::Tuple(ref tys) => format!("({})", tys.iter().map(signature).collect::<String>()),
+        Type::Map(ref key, ref value) if is_basic(key) => {
+            format!("a{{{}{}}}", signature(key), signature(value))
+        }
+        _ => "s".into(),
+    }
+}
+
+/// Converts an argument of type `ty` to the JSON serde_json deserializes it from.
+fn to_json(value: Value, ty: &Type) -> Result<Json, String> {
+    let mismatch = |value: &Value| Err(format!("expected `{}`, got {:?}", signature(ty), value));
+    Ok(match (ty, value) {
+        (_, Value::Variant(value)) => to_json(*value, ty)?,
+        (Type::Bool, Value::Bool(b)) => Json::Bool(b),
+        (Type::Integer { bits, .. }, value) if *bits <= 64 => {
+            let n: Number = match value {
+                Value::Byte(n) => n.into(),
+                Value::Int16(n) => n.into(),
+                Value::UInt16(n) => n.into(),
+                Value::Int32(n) => n.into(),
+                Value::UInt32(n) => n.into(),
+                Value::Int64(n) => n.into(),
+                Value::UInt64(n) => n.into(),
+                value => return mismatch(&value),
+            };
+            // Values out of the type's range fail to deserialize.
+            Json::Number(n)
+        }
+        (Type::Float { .. }, Value::Double(d)) => Number::from_f64(d)
+            .map(Json::Number)
+            .ok_or_else(|| format!("{} isn't a finite number", d))?,
+        (Type::Char, Value::String(s)) | (Type::String, Value::String(s)) => Json::String(s),
+        (Type::Option(ty), Value::Array(values)) => {
+            let mut values = values.into_iter();
+            match (values.next(), values.next()) {
+                (None, _) => Json::Null,
+                (Some(value), None) => to_json(value, ty)?,
+                (Some(_), Some(_)) => return Err("expected an array of at most one value".into()),
+            }
+        }
+        (Type::List(ty), Value::Array(values)) | (Type::Array(ty, _), Value::Array(values)) => {
+            Json::Array(
+                values
+                    .into_iter()
+                    .map(|value| to_json(value, ty))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        (Type::Tuple(tys), Value::Struct(values)) if tys.len() == values.len() => Json::Array(
+            values
+                .into_iter()
+                .zip(tys)
+                .map(|(value, ty)| to_json(value, ty))
+                .collect::<Result<_, _>>()?,
+        ),
+        (Type::Map(key, value), Value::Dict(entries)) if is_basic(key) => Json::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| {
+                    // JSON keys are strings, which serde_json parses as the key type.
+                    let k = match to_json(k, key)? {
+                        Json::String(k) => k,
+                        k => k.to_string(),
+                    };
+                    Ok((k, to_json(v, value)?))
+                })
+                .collect::<Result<_, String>>()?,
+        ),
+        (ty, Value::String(json)) if signature(ty) == "s" => {
+            serde_json::from_str(&json).map_err(|e| format!("expected JSON: {}", e))?
+        }
+        (_, value) => return mismatch(&value),
+    })
+}
+
+/// Converts an output of type `ty`, as serialized by serde_json, to a D-Bus value.
+fn from_json(json: Json, ty: &Type) -> Result<Value, String> {
+    Ok(match (ty, json) {
+        (Type::Bool, Json::Bool(b)) => Value::Bool(b),
+        (&Type::Integer { signed, bits }, Json::Number(ref n)) if bits <= 64 => {
+            // The output was serialized from a value of the type, so it's in range.
+            match (signed, bits) {
+                (false, 8) => n.as_u64().map(|n| Value::Byte(n as u8)),
+                (true, 8) | (true, 16) => n.as_i64().map(|n| Value::Int16(n as i16)),
+                (false, 16) => n.as_u64().map(|n| Value::UInt16(n as u16)),
+                (true, 32) => n.as_i64().map(|n| Value::Int32(n as i32)),
+                (false, 32) => n.as_u64().map(|n| Value::UInt32(n as u32)),
+                (true, _) => n.as_i64().map(Value::Int64),
+                (false, _) => n.as_u64().map(Value::UInt64),
+            }
+            .ok_or_else(|| format!("{} isn't a `{}`", n, signature(ty)))?
+        }
+        (Type::Float { .. }, Json::Number(n)) => Value::Double(n.as_f64().unwrap_or(f64::NAN)),
+        // serde_json serializes NaN and the infinities as null.
+        (Type::Float { .. }, Json::Null) => Value::Double(f64::NAN),
+        (Type::Char, Json::String(s)) | (Type::String, Json::String(s)) => Value::String(s),
+        (Type::Option(_), Json::Null) => Value::Array(vec![]),
+        (Type::Option(ty), json) => Value::Array(vec![from_json(json, ty)?]),
+        (Type::List(ty), Json::Array(values)) | (Type::Array(ty, _), Json::Array(values)) => {
+            Value::Array(
+                values
+                    .into_iter()
+                    .map(|json| from_json(json, ty))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        (Type::Tuple(tys), Json::Array(values)) if tys.len() == values.len() => Value::Struct(
+            values
+                .into_iter()
+                .zip(tys)
+                .map(|(json, ty)| from_json(json, ty))
+                .collect::<Result<_, _>>()?,
+        ),
+        (Type::Map(key, value), Json::Object(entries)) if is_basic(key) => Value::Dict(
+            entries
+                .into_iter()
+                .map(|(k, v)| {
+                    // serde_json serializes keys that aren't strings as their JSON.
+                    let k = match **key {
+                        Type::Char | Type::String => Json::String(k),
+                        _ => serde_json::from_str(&k).map_err(|e| e.to_string())?,
+                    };
+                    Ok((from_json(k, key)?, from_json(v, value)?))
+                })
+                .collect::<Result<_, String>>()?,
+        ),
+        (ty, json) if signature(ty) == "s" => Value::String(json.to_string()),
+        (ty, json) => return Err(format!("expected `{}`, got {}", signature(ty), json)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        future::{self, Ready},
+        stream::Iter,
+    };
+    use rpc::{
+        server::{BaseChannel, Channel},
+        transport::channel::{self, UnboundedChannel},
+    };
+    use std::{collections::BTreeMap, ops::RangeInclusive};
+
+    const INTERFACE: &str = "com.example.Greeter";
+
+    #[tarpc::service(schema = true)]
+    trait Greeter {
+        async fn hello(name: String) -> String;
+        #[throws(String)]
+        async fn divide(dividend: u32, divisor: u32) -> u32;
+        #[stream]
+        async fn count(to: u32) -> u32;
+        #[oneway]
+        async fn log(line: String);
+        async fn tally(scores: BTreeMap<String, u8>) -> Option<(String, u32)>;
+    }
+
+    #[derive(Clone)]
+    struct GreeterServer;
+
+    impl Greeter for GreeterServer {
+        type HelloFut = Ready<String>;
+
+        fn hello(self, ctx: context::Context, name: String) -> Self::HelloFut {
+            let sender = ctx.metadata.get(SENDER).map_or("nobody", |s| &**s);
+            future::ready(format!("Hello, {}, from {}!", name, sender))
+        }
+
+        type DivideFut = Ready<Result<u32, String>>;
+
+        fn divide(self, _: context::Context, dividend: u32, divisor: u32) -> Self::DivideFut {
+            future::ready(
+                dividend
+                    .checked_div(divisor)
+                    .ok_or_else(|| "division by zero".to_string()),
+            )
+        }
+
+        type CountStream = Iter<RangeInclusive<u32>>;
+
+        fn count(self, _: context::Context, to: u32) -> Self::CountStream {
+            stream::iter(1..=to)
+        }
+
+        type LogFut = Ready<()>;
+
+        fn log(self, _: context::Context, _: String) -> Self::LogFut {
+            future::ready(())
+        }
+
+        type TallyFut = Ready<Option<(String, u32)>>;
+
+        fn tally(self, _: context::Context, scores: BTreeMap<String, u8>) -> Self::TallyFut {
+            let total = scores.values().map(|&score| u32::from(score)).sum();
+            future::ready(scores.keys().next().map(|first| (first.clone(), total)))
+        }
+    }
+
+    /// Serves the greeter on an in-memory bus connection, returning the caller's end.
+    fn serve() -> UnboundedChannel<Reply, MethodCall> {
+        let (bus, object) = channel::unbounded();
+        let transport = Transport::<_, GreeterRequest, GreeterResponse>::new(object, INTERFACE);
+        tokio::spawn(
+            BaseChannel::with_defaults(transport)
+                .respond_with(GreeterServer.serve())
+                .execute(),
+        );
+        bus
+    }
+
+    async fn call(
+        bus: &mut UnboundedChannel<Reply, MethodCall>,
+        call: MethodCall,
+    ) -> io::Result<Body> {
+        let serial = call.serial;
+        bus.send(call).await?;
+        let reply = bus.next().await.unwrap()?;
+        assert_eq!(reply.reply_serial, serial);
+        Ok(reply.body)
+    }
+
+    fn returned(signature: &str, value: Value) -> Body {
+        Body::Return {
+            signature: signature.into(),
+            args: vec![value],
+        }
+    }
+
+    fn error_name(body: Body) -> String {
+        match body {
+            Body::Error { name, .. } => name,
+            body => panic!("expected an error, got {:?}", body),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_rpcs_as_methods() -> io::Result<()> {
+        let mut bus = serve();
+
+        let mut hello = MethodCall::new(1, INTERFACE, "Hello", vec![Value::String("Ada".into())]);
+        hello.sender = Some(":1.42".into());
+        assert_eq!(
+            call(&mut bus, hello).await?,
+            returned("s", Value::String("Hello, Ada, from :1.42!".into()))
+        );
+
+        // The interface is optional, and arguments can be wrapped in variants.
+        let mut count = MethodCall::new(
+            2,
+            INTERFACE,
+            "Count",
+            vec![Value::Variant(Box::new(Value::UInt32(2)))],
+        );
+        count.interface = None;
+        assert_eq!(
+            call(&mut bus, count).await?,
+            returned("au", Value::Array(vec![Value::UInt32(1), Value::UInt32(2)]))
+        );
+
+        let divide = MethodCall::new(3, INTERFACE, "Divide", vec![Value::UInt32(1); 2]);
+        assert_eq!(
+            call(&mut bus, divide).await?,
+            returned("u", Value::UInt32(1))
+        );
+        let divide = MethodCall::new(
+            4,
+            INTERFACE,
+            "Divide",
+            vec![Value::UInt32(1), Value::UInt32(0)],
+        );
+        assert_eq!(
+            call(&mut bus, divide).await?,
+            Body::Error {
+                name: "com.example.Greeter.Error".into(),
+                message: "\"division by zero\"".into(),
+            }
+        );
+
+        let log = MethodCall::new(5, INTERFACE, "Log", vec![Value::String("hi".into())]);
+        assert_eq!(call(&mut bus, log).await?, Body::empty());
+
+        let scores = Value::Dict(vec![
+            (Value::String("a".into()), Value::Byte(2)),
+            (Value::String("b".into()), Value::Byte(3)),
+        ]);
+        let tally = MethodCall::new(6, INTERFACE, "Tally", vec![scores]);
+        assert_eq!(
+            call(&mut bus, tally).await?,
+            returned(
+                "a(su)",
+                Value::Array(vec![Value::Struct(vec![
+                    Value::String("a".into()),
+                    Value::UInt32(5)
+                ])])
+            )
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn long_streams_are_rejected() -> io::Result<()> {
+        let mut bus = serve();
+
+        let to = Value::UInt32(json::MAX_ITEMS as u32 + 1);
+        let count = MethodCall::new(1, INTERFACE, "Count", vec![to]);
+        assert_eq!(
+            error_name(call(&mut bus, count).await?),
+            "org.freedesktop.DBus.Error.Failed"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_calls() -> io::Result<()> {
+        let mut bus = serve();
+
+        let unknown = MethodCall::new(1, INTERFACE, "Goodbye", vec![]);
+        assert_eq!(
+            error_name(call(&mut bus, unknown).await?),
+            "org.freedesktop.DBus.Error.UnknownMethod"
+        );
+        let unknown = MethodCall::new(2, "com.example.Other", "Hello", vec![]);
+        assert_eq!(
+            error_name(call(&mut bus, unknown).await?),
+            "org.freedesktop.DBus.Error.UnknownInterface"
+        );
+        let missing = MethodCall::new(3, INTERFACE, "Hello", vec![]);
+        assert_eq!(
+            error_name(call(&mut bus, missing).await?),
+            "org.freedesktop.DBus.Error.InvalidArgs"
+        );
+        let mistyped = MethodCall::new(4, INTERFACE, "Count", vec![Value::String("2".into())]);
+        assert_eq!(
+            error_name(call(&mut bus, mistyped).await?),
+            "org.freedesktop.DBus.Error.InvalidArgs"
+        );
+        let out_of_range = Value::Dict(vec![(Value::String("a".into()), Value::UInt32(256))]);
+        let out_of_range = MethodCall::new(5, INTERFACE, "Tally", vec![out_of_range]);
+        assert_eq!(
+            error_name(call(&mut bus, out_of_range).await?),
+            "org.freedesktop.DBus.Error.InvalidArgs"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn answers_standard_interfaces() -> io::Result<()> {
+        let mut bus = serve();
+
+        let ping = MethodCall::new(1, PEER, "Ping", vec![]);
+        assert_eq!(call(&mut bus, ping).await?, Body::empty());
+
+        let introspect = MethodCall::new(2, INTROSPECTABLE, "Introspect", vec![]);
+        let xml = match call(&mut bus, introspect).await? {
+            Body::Return { mut args, .. } => args.pop(),
+            body => panic!("expected a return, got {:?}", body),
+        };
+        assert_eq!(xml, Some(Value::String(introspect_greeter())));
+        Ok(())
+    }
+
+    fn introspect_greeter() -> String {
+        introspect::<GreeterRequest>(INTERFACE)
+    }
+
+    #[test]
+    fn introspection_describes_the_interface() {
+        let xml = introspect_greeter();
+        let interface = &xml[xml.find(INTERFACE).unwrap() - "<interface name=\"".len()..];
+        assert_eq!(
+            interface,
+            r#"<interface name="com.example.Greeter">
+    <method name="Hello">
+      <arg name="name" type="s" direction="in"/>
+      <arg name="output" type="s" direction="out"/>
+    </method>
+    <method name="Divide">
+      <arg name="dividend" type="u" direction="in"/>
+      <arg name="divisor" type="u" direction="in"/>
+      <arg name="output" type="u" direction="out"/>
+    </method>
+    <method name="Count">
+      <arg name="to" type="u" direction="in"/>
+      <arg name="items" type="au" direction="out"/>
+    </method>
+    <method name="Log">
+      <arg name="line" type="s" direction="in"/>
+      <annotation name="org.freedesktop.DBus.Method.NoReply" value="true"/>
+    </method>
+    <method name="Tally">
+      <arg name="scores" type="a{sy}" direction="in"/>
+      <arg name="output" type="a(su)" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#
+        );
+    }
+
+    #[test]
+    fn maps_types_to_signatures() {
+        let signatures: Vec<_> = [
+            "bool",
+            "i8",
+            "u64",
+            "u128",
+            "f32",
+            "&'static str",
+            "Vec<u8>",
+            "[(u16, char); 4]",
+            "HashMap<u32, Vec<String>>",
+            "HashMap<(u32, u32), String>",
+            "User",
+        ]
+        .iter()
+        .map(|ty| signature(&Type::parse(ty)))
+        .collect();
+        assert_eq!(
+            signatures,
+            ["b", "n", "t", "s", "d", "s", "ay", "a(qs)", "a{uas}", "s", "s"]
+        );
+    }
+}
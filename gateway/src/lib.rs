@@ -0,0 +1,740 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Exposes tarpc services to web clients and curl as JSON over HTTP.
+//!
+//! A [`Gateway`] serves HTTP, forwarding each call to a tarpc service over a
+//! [client channel](rpc::client::Channel). Its routes come from the service definition, through
+//! the [`Introspect`] impl the `service` macro generates for the service's requests: an RPC is
+//! called by `POST /{Service}/{rpc}` with its arguments as a JSON object keyed by argument name,
+//! e.g.
+//!
+//! ```text
+//! curl -d '{"name": "Tim"}' http://localhost:8080/World/hello
+//! ```
+//!
+//! The arguments are deserialized as in tarpc's own JSON requests, so their names are the names
+//! on the wire, which differ from the names in the service definition if the service sets
+//! `wire_case`. An empty body stands in for an RPC that takes no arguments.
+//!
+//! A unary RPC responds with its output as JSON. A streaming RPC responds with each item it
+//! streams on its own line, as [newline-delimited JSON](http://ndjson.org), and a one-way RPC
+//! responds with `202 Accepted` once the request is sent.
+//!
+//! A call's deadline can be set with the [`TIMEOUT_HEADER`], e.g. `tarpc-timeout: 500ms`;
+//! calls without one get the default [context](rpc::context::current)'s deadline.
+//!
+//! Calls that fail respond with `{"error": {"message": ...}}` and a status code that matches
+//! the failure:
+//!
+//! | Failure                                  | Status                       |
+//! |------------------------------------------|------------------------------|
+//! | Unknown RPC                              | 404 Not Found                |
+//! | Method other than `POST`                 | 405 Method Not Allowed       |
+//! | Malformed arguments or timeout           | 400 Bad Request              |
+//! | Body larger than the [maximum]           | 413 Payload Too Large        |
+//! | Error thrown by an RPC with `#[throws]`  | 422 Unprocessable Entity     |
+//! | Request rejected as unauthenticated      | 401 Unauthorized             |
+//! | Request that failed in tarpc otherwise   | see [`status_code`]          |
+//!
+//! The error thrown by an RPC is the `data` of the error object. Requests that fail in tarpc
+//! include the name of their [`io::ErrorKind`] as its `kind`. A streaming RPC that fails after
+//! it starts streaming can't change its status, so it ends the stream with the error object.
+//!
+//! [maximum]: Gateway::max_body_size
+//!
+//! [`openapi::document`] describes the gateway's endpoints as an OpenAPI document.
+//!
+//! The [`upgrade`] module serves tarpc itself from a hyper server, over connections upgraded
+//! from HTTP/1.1.
+
+#![deny(missing_docs, missing_debug_implementations)]
+
+pub mod openapi;
+pub mod upgrade;
+
+use futures::{future, prelude::*, stream};
+use http::{header, HeaderMap, HeaderValue, Method as HttpMethod, StatusCode};
+use http_body::Body as HttpBody;
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Chunk,
+};
+use rpc::{
+    client, context,
+    error::{Classify, ErrorKind},
+    schema::{Introspect, Method, MethodKind},
+    transport::adapter::json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+/// The header that sets a call's timeout, as a duration like `500ms` or `2s`.
+pub const TIMEOUT_HEADER: &str = "tarpc-timeout";
+
+/// The largest request body a [`Gateway`] reads by default, in bytes.
+pub const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+/// Returns the HTTP status of a call whose request failed with an error of kind `kind`.
+///
+/// | Kind                                             | Status                    |
+/// |--------------------------------------------------|---------------------------|
+/// | `TimedOut`                                       | 504 Gateway Timeout       |
+/// | `NotFound`                                       | 404 Not Found             |
+/// | `PermissionDenied`, except as unauthenticated    | 403 Forbidden             |
+/// | `InvalidInput`, `InvalidData`                    | 400 Bad Request           |
+/// | `AlreadyExists`                                  | 409 Conflict              |
+/// | `WouldBlock`, which servers shed load with       | 429 Too Many Requests     |
+/// | `ConnectionRefused`, `ConnectionReset`, etc.     | 502 Bad Gateway           |
+/// | Anything else                                    | 500 Internal Server Error |
+pub fn status_code(kind: io::ErrorKind) -> StatusCode {
+    match kind {
+        io::ErrorKind::TimedOut => StatusCode::GATEWAY_TIMEOUT,
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => StatusCode::BAD_REQUEST,
+        io::ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        io::ErrorKind::WouldBlock => StatusCode::TOO_MANY_REQUESTS,
+        io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::NotConnected
+        | io::ErrorKind::BrokenPipe => StatusCode::BAD_GATEWAY,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Serves a tarpc service to HTTP clients.
+pub struct Gateway<Req, Resp> {
+    service: String,
+    channel: client::Channel<Req, Resp>,
+    allow_origin: Option<HeaderValue>,
+    max_body_size: usize,
+    ghost: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<Req, Resp> Gateway<Req, Resp> {
+    /// Returns a gateway that serves the service named `service`, the name of its trait, by
+    /// forwarding calls over `channel`.
+    pub fn new(service: impl Into<String>, channel: client::Channel<Req, Resp>) -> Self {
+        Gateway {
+            service: service.into(),
+            channel,
+            allow_origin: None,
+            max_body_size: MAX_BODY_SIZE,
+            ghost: PhantomData,
+        }
+    }
+
+    /// Allows browsers to call the service from pages served by `origin`, e.g.
+    /// `https://example.com`, or from any page if `origin` is `*`, by answering CORS preflight
+    /// requests and marking responses with `Access-Control-Allow-Origin`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `origin` isn't a valid header value.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.allow_origin = Some(HeaderValue::from_str(origin).expect("invalid origin"));
+        self
+    }
+
+    /// Sets the largest request body the gateway reads, in bytes. Calls with larger bodies
+    /// respond with `413 Payload Too Large`. Defaults to [`MAX_BODY_SIZE`].
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.max_body_size = bytes;
+        self
+    }
+}
+
+impl<Req, Resp> Gateway<Req, Resp>
+where
+    Req: Introspect + DeserializeOwned + Send + 'static,
+    Resp: Serialize + Send + 'static,
+{
+    /// Handles one HTTP request, for serving the gateway from an existing HTTP server.
+    pub fn handle(
+        &self,
+        request: hyper::Request<Body>,
+    ) -> impl Future<Output = hyper::Response<ResponseBody>> + Send + 'static {
+        let call = self.call(request);
+        let allow_origin = self.allow_origin.clone();
+        async move {
+            let mut response = match call {
+                Ok(call) => call.await,
+                Err(response) => response,
+            };
+            if let Some(allow_origin) = allow_origin {
+                response
+                    .headers_mut()
+                    .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            }
+            response
+        }
+    }
+
+    /// Checks the call can be forwarded, returning a future that forwards it.
+    fn call(
+        &self,
+        request: hyper::Request<Body>,
+    ) -> Result<
+        impl Future<Output = hyper::Response<ResponseBody>> + Send + 'static,
+        hyper::Response<ResponseBody>,
+    > {
+        if self.allow_origin.is_some() && request.method() == HttpMethod::OPTIONS {
+            let mut response = empty(StatusCode::NO_CONTENT);
+            let headers = response.headers_mut();
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                HeaderValue::from_static("POST"),
+            );
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::from_static("content-type, tarpc-timeout"),
+            );
+            return Err(response);
+        }
+        let mut path = request.uri().path().trim_start_matches('/').splitn(2, '/');
+        let method = match (path.next(), path.next()) {
+            (Some(service), Some(name)) if service == self.service => Req::METHODS
+                .iter()
+                .find(|method| method.name == name && method.removed.is_none()),
+            _ => None,
+        };
+        let method = method.ok_or_else(|| {
+            error(
+                StatusCode::NOT_FOUND,
+                format!("no RPC at {}", request.uri().path()),
+            )
+        })?;
+        if request.method() != HttpMethod::POST {
+            let mut response = error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                format!("call {} with POST", method.name),
+            );
+            response
+                .headers_mut()
+                .insert(header::ALLOW, HeaderValue::from_static("POST"));
+            return Err(response);
+        }
+        let mut ctx = context::current();
+        if let Some(timeout) = request.headers().get(TIMEOUT_HEADER) {
+            let timeout = timeout
+                .to_str()
+                .ok()
+                .and_then(|timeout| humantime::parse_duration(timeout).ok())
+                .ok_or_else(|| {
+                    error(
+                        StatusCode::BAD_REQUEST,
+                        format!("invalid {} {:?}", TIMEOUT_HEADER, timeout),
+                    )
+                })?;
+            ctx.deadline = SystemTime::now().checked_add(timeout).ok_or_else(|| {
+                error(
+                    StatusCode::BAD_REQUEST,
+                    format!("{} {:?} is too long", TIMEOUT_HEADER, timeout),
+                )
+            })?;
+        }
+        let channel = self.channel.clone();
+        let body = request.into_body();
+        Ok(forward(channel, ctx, method, body, self.max_body_size)
+            .map(|response| response.unwrap_or_else(|response| response)))
+    }
+
+    /// Serves the gateway over HTTP on `addr`, returning a future that runs the server.
+    pub fn serve(self, addr: &SocketAddr) -> io::Result<Server> {
+        let gateway = Arc::new(self);
+        let make_service = make_service_fn(move |_: &AddrStream| {
+            let gateway = gateway.clone();
+            future::ok::<_, io::Error>(service_fn(move |request| {
+                gateway.handle(request).map(Ok::<_, io::Error>)
+            }))
+        });
+        let server = hyper::Server::try_bind(addr)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            .serve(make_service);
+        Ok(Server {
+            local_addr: server.local_addr(),
+            server: server
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .boxed(),
+        })
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Gateway<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Gateway")
+            .field("service", &self.service)
+            .field("allow_origin", &self.allow_origin)
+            .field("max_body_size", &self.max_body_size)
+            .finish()
+    }
+}
+
+/// Calls `method` with the arguments in `body`.
+async fn forward<Req, Resp>(
+    mut channel: client::Channel<Req, Resp>,
+    ctx: context::Context,
+    method: &'static Method,
+    mut body: Body,
+    max_body_size: usize,
+) -> Result<hyper::Response<ResponseBody>, hyper::Response<ResponseBody>>
+where
+    Req: DeserializeOwned,
+    Resp: Serialize + Send + 'static,
+{
+    let mut bytes = vec![];
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| error(StatusCode::BAD_REQUEST, e.to_string()))?;
+        if bytes.len() + chunk.len() > max_body_size {
+            return Err(error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("the body is larger than {} bytes", max_body_size),
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    let args = if bytes.iter().all(u8::is_ascii_whitespace) {
+        Value::Object(Map::new())
+    } else {
+        serde_json::from_slice(&bytes).map_err(|e| {
+            error(
+                StatusCode::BAD_REQUEST,
+                format!("the body isn't JSON: {}", e),
+            )
+        })?
+    };
+    if !args.is_object() {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "the arguments of {} must be a JSON object keyed by argument name",
+                method.name
+            ),
+        ));
+    }
+    let mut request = Map::new();
+    request.insert(method.wire_name.to_string(), args);
+    let request = serde_json::from_value(Value::Object(request)).map_err(|e| {
+        error(
+            StatusCode::BAD_REQUEST,
+            format!("invalid arguments to {}: {}", method.name, e),
+        )
+    })?;
+
+    match method.kind {
+        MethodKind::OneWay => {
+            channel.notify(ctx, request).await.map_err(failed)?;
+            Ok(empty(StatusCode::ACCEPTED))
+        }
+        MethodKind::Unary => {
+            let response = channel.call(ctx, request).await.map_err(failed)?;
+            let result = json::output(method, response)
+                .and_then(|output| json::result(method, output))
+                .map_err(internal)?;
+            match result {
+                Ok(output) => Ok(json_response(output, "application/json")),
+                Err(thrown) => {
+                    let mut response = json_response(
+                        json!({
+                            "error": {
+                                "message": format!("{} failed", method.name),
+                                "data": thrown,
+                            }
+                        }),
+                        "application/json",
+                    );
+                    *response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+                    Err(response)
+                }
+            }
+        }
+        MethodKind::Streaming => {
+            let responses = channel.call_stream(ctx, request).await.map_err(failed)?;
+            let lines = responses.map(move |response| {
+                let line = match response {
+                    Ok(response) => json::output(method, response)
+                        .unwrap_or_else(|e| error_object(None, e.to_string())),
+                    Err(e) => error_object(Some(e.kind()), e.to_string()),
+                };
+                let mut line = line.to_string().into_bytes();
+                line.push(b'\n');
+                line
+            });
+            let mut response = hyper::Response::new(ResponseBody::new(lines));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/x-ndjson"),
+            );
+            Ok(response)
+        }
+    }
+}
+
+fn error_object(kind: Option<io::ErrorKind>, message: String) -> Value {
+    let mut error = Map::new();
+    if let Some(kind) = kind {
+        error.insert("kind".into(), format!("{:?}", kind).into());
+    }
+    error.insert("message".into(), message.into());
+    json!({ "error": error })
+}
+
+fn error(status: StatusCode, message: String) -> hyper::Response<ResponseBody> {
+    let mut response = json_response(error_object(None, message), "application/json");
+    *response.status_mut() = status;
+    response
+}
+
+fn failed(e: io::Error) -> hyper::Response<ResponseBody> {
+    let mut response = json_response(
+        error_object(Some(e.kind()), e.to_string()),
+        "application/json",
+    );
+    *response.status_mut() = if e.classify() == ErrorKind::Unauthenticated {
+        StatusCode::UNAUTHORIZED
+    } else {
+        status_code(e.kind())
+    };
+    response
+}
+
+fn internal(e: serde_json::Error) -> hyper::Response<ResponseBody> {
+    error(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("the response failed to serialize: {}", e),
+    )
+}
+
+fn json_response(value: Value, content_type: &'static str) -> hyper::Response<ResponseBody> {
+    let body = value.to_string().into_bytes();
+    let mut response = hyper::Response::new(ResponseBody::new(stream::once(future::ready(body))));
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+fn empty(status: StatusCode) -> hyper::Response<ResponseBody> {
+    let mut response = hyper::Response::new(ResponseBody::new(stream::empty()));
+    *response.status_mut() = status;
+    response
+}
+
+/// The body of a gateway response.
+pub struct ResponseBody {
+    chunks: Pin<Box<dyn Stream<Item = Vec<u8>> + Send>>,
+}
+
+impl ResponseBody {
+    fn new(chunks: impl Stream<Item = Vec<u8>> + Send + 'static) -> Self {
+        ResponseBody {
+            chunks: chunks.boxed(),
+        }
+    }
+}
+
+impl HttpBody for ResponseBody {
+    type Data = Chunk;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Chunk>>> {
+        self.chunks
+            .poll_next_unpin(cx)
+            .map(|chunk| chunk.map(|chunk| Ok(Chunk::from(chunk))))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<io::Result<Option<HeaderMap>>> {
+        Poll::Ready(Ok(None))
+    }
+}
+
+impl fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseBody").finish()
+    }
+}
+
+/// An HTTP server for a [`Gateway`]. Resolves once the server stops, which it only does if it
+/// fails.
+#[must_use = "futures do nothing unless polled"]
+pub struct Server {
+    local_addr: SocketAddr,
+    server: Pin<Box<dyn Future<Output = io::Result<()>> + Send>>,
+}
+
+impl Server {
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Future for Server {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.server.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("local_addr", &self.local_addr)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::Ready;
+    use rpc::{
+        error::{unauthenticated, UNAUTHENTICATED},
+        server::{BaseChannel, Channel},
+        transport::channel,
+    };
+    use std::ops::RangeInclusive;
+
+    #[tarpc::service]
+    trait Greeter {
+        async fn hello(name: String) -> String;
+        #[throws(String)]
+        async fn divide(dividend: u32, divisor: u32) -> u32;
+        #[stream]
+        async fn count(to: u32) -> u32;
+        #[oneway]
+        async fn log(line: String);
+        async fn hang();
+    }
+
+    #[derive(Clone)]
+    struct GreeterServer;
+
+    impl Greeter for GreeterServer {
+        type HelloFut = Ready<String>;
+
+        fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+            future::ready(format!("Hello, {}!", name))
+        }
+
+        type DivideFut = Ready<Result<u32, String>>;
+
+        fn divide(self, _: context::Context, dividend: u32, divisor: u32) -> Self::DivideFut {
+            future::ready(
+                dividend
+                    .checked_div(divisor)
+                    .ok_or_else(|| "division by zero".to_string()),
+            )
+        }
+
+        type CountStream = stream::Iter<RangeInclusive<u32>>;
+
+        fn count(self, _: context::Context, to: u32) -> Self::CountStream {
+            stream::iter(1..=to)
+        }
+
+        type LogFut = Ready<()>;
+
+        fn log(self, _: context::Context, _: String) -> Self::LogFut {
+            future::ready(())
+        }
+
+        type HangFut = future::Pending<()>;
+
+        fn hang(self, _: context::Context) -> Self::HangFut {
+            future::pending()
+        }
+    }
+
+    /// Serves the greeter over HTTP, returning its address.
+    fn serve() -> io::Result<SocketAddr> {
+        let (client_transport, server_transport) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .respond_with(GreeterServer.serve())
+                .execute(),
+        );
+        let channel = client::new(client::Config::default(), client_transport).spawn()?;
+        let server = Gateway::new("Greeter", channel)
+            .allow_origin("*")
+            .max_body_size(64)
+            .serve(&([127, 0, 0, 1], 0).into())?;
+        let addr = server.local_addr();
+        tokio::spawn(server.map(|result| result.unwrap()));
+        Ok(addr)
+    }
+
+    /// Sends a request to `path`, returning the response's status and body.
+    async fn send(
+        addr: SocketAddr,
+        method: HttpMethod,
+        path: &str,
+        body: &str,
+        timeout: Option<&str>,
+    ) -> (StatusCode, String) {
+        let mut request = hyper::Request::builder();
+        request
+            .method(method)
+            .uri(format!("http://{}/{}", addr, path));
+        if let Some(timeout) = timeout {
+            request.header(TIMEOUT_HEADER, timeout);
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let response = hyper::Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let mut body = response.into_body();
+        let mut bytes = vec![];
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        (status, String::from_utf8(bytes).unwrap())
+    }
+
+    async fn post(addr: SocketAddr, path: &str, body: &str) -> (StatusCode, Value) {
+        let (status, body) = send(addr, HttpMethod::POST, path, body, None).await;
+        let body = if body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(&body).unwrap()
+        };
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn calls_every_kind_of_rpc() -> io::Result<()> {
+        let addr = serve()?;
+
+        assert_eq!(
+            post(addr, "Greeter/hello", r#"{"name": "Tim"}"#).await,
+            (StatusCode::OK, json!("Hello, Tim!"))
+        );
+        assert_eq!(
+            post(addr, "Greeter/divide", r#"{"dividend": 6, "divisor": 3}"#).await,
+            (StatusCode::OK, json!(2))
+        );
+        assert_eq!(
+            post(addr, "Greeter/divide", r#"{"dividend": 6, "divisor": 0}"#).await,
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                json!({"error": {"message": "divide failed", "data": "division by zero"}})
+            )
+        );
+        assert_eq!(
+            send(
+                addr,
+                HttpMethod::POST,
+                "Greeter/count",
+                r#"{"to": 3}"#,
+                None
+            )
+            .await,
+            (StatusCode::OK, "1\n2\n3\n".to_string())
+        );
+        assert_eq!(
+            post(addr, "Greeter/log", r#"{"line": "ignored"}"#).await,
+            (StatusCode::ACCEPTED, Value::Null)
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn maps_errors_to_status_codes() -> io::Result<()> {
+        let addr = serve()?;
+
+        let name = format!(r#"{{"name": "{}"}}"#, "a".repeat(64));
+        let status = |addr, method, path, body, timeout| async move {
+            send(addr, method, path, body, timeout).await.0
+        };
+        assert_eq!(
+            status(addr, HttpMethod::POST, "Greeter/wave", "", None).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(addr, HttpMethod::POST, "World/hello", "", None).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(addr, HttpMethod::GET, "Greeter/hello", "", None).await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+        assert_eq!(
+            status(addr, HttpMethod::OPTIONS, "Greeter/hello", "", None).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status(addr, HttpMethod::POST, "Greeter/hello", "{", None).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(
+                addr,
+                HttpMethod::POST,
+                "Greeter/hello",
+                r#"{"name": 7}"#,
+                None
+            )
+            .await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(addr, HttpMethod::POST, "Greeter/hello", "[]", None).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            status(addr, HttpMethod::POST, "Greeter/hello", &name, None).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        // The call never completes, so only its deadline can end it.
+        assert_eq!(
+            status(addr, HttpMethod::POST, "Greeter/hang", "", Some("1ns")).await,
+            StatusCode::GATEWAY_TIMEOUT
+        );
+        assert_eq!(
+            status(addr, HttpMethod::POST, "Greeter/hang", "", Some("soon")).await,
+            StatusCode::BAD_REQUEST
+        );
+        // Past the end of time.
+        assert_eq!(
+            status(
+                addr,
+                HttpMethod::POST,
+                "Greeter/hang",
+                "",
+                Some("500000000000y")
+            )
+            .await,
+            StatusCode::BAD_REQUEST
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unauthenticated_rejections_are_unauthorized() {
+        let unauthenticated = unauthenticated(UNAUTHENTICATED);
+        assert_eq!(failed(unauthenticated).status(), StatusCode::UNAUTHORIZED);
+        let forbidden = io::Error::new(io::ErrorKind::PermissionDenied, "Not an admin.");
+        assert_eq!(failed(forbidden).status(), StatusCode::FORBIDDEN);
+    }
+}
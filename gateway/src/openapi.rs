@@ -0,0 +1,344 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Describes a gateway's endpoints as an [OpenAPI 3](https://swagger.io/specification/)
+//! document, for publishing API docs and generating clients in other languages.
+//!
+//! The document is generated from the service's [schema](rpc::schema::Service), which names the
+//! types of RPCs but doesn't describe the types defined by the service itself. Types from the
+//! standard library are described in full; any other type gets a component schema of its name
+//! that accepts any value, to be filled in by hand if needed.
+//!
+//! Arguments are named as in the schema, so a service that sets `wire_case` must rename them to
+//! match the names on the wire.
+
+use crate::TIMEOUT_HEADER;
+use rpc::schema::{MethodKind, Service, Type};
+use serde_json::{json, Map, Value};
+
+/// Returns the OpenAPI document describing a gateway for `service`, as JSON.
+///
+/// The document's version is the latest version of the service named by an RPC's `#[since]` or
+/// `#[removed]`, or 1 if none are.
+pub fn document(service: &Service) -> Value {
+    let mut schemas = Map::new();
+    schemas.insert(
+        "Error".into(),
+        json!({
+            "type": "object",
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "properties": {
+                        "kind": {
+                            "type": "string",
+                            "description": "The kind of I/O error, for calls that failed in tarpc",
+                        },
+                        "message": {"type": "string"},
+                    },
+                    "required": ["message"],
+                },
+            },
+            "required": ["error"],
+        }),
+    );
+
+    let mut paths = Map::new();
+    for method in service.methods.iter().filter(|m| m.removed.is_none()) {
+        let mut properties = Map::new();
+        let mut required = vec![];
+        for arg in method.args.iter() {
+            let ty = Type::parse(&arg.ty);
+            match ty {
+                // Missing optional arguments are deserialized as `None`.
+                Type::Option(_) => {}
+                _ => required.push(Value::from(&*arg.name)),
+            }
+            properties.insert(arg.name.to_string(), schema(&ty, &mut schemas));
+        }
+        let mut args = json!({"type": "object", "properties": properties});
+        if !required.is_empty() {
+            args["required"] = Value::Array(required);
+        }
+
+        let output = schema(&Type::parse(&method.output), &mut schemas);
+        let mut responses = Map::new();
+        match method.kind {
+            MethodKind::Unary => {
+                responses.insert(
+                    "200".into(),
+                    json!({
+                        "description": "The RPC's output",
+                        "content": {"application/json": {"schema": output}},
+                    }),
+                );
+            }
+            MethodKind::Streaming => {
+                responses.insert(
+                    "200".into(),
+                    json!({
+                        "description": "The items the RPC streams, one per line. A stream that \
+                                        fails ends with an error object",
+                        "content": {"application/x-ndjson": {"schema": output}},
+                    }),
+                );
+            }
+            MethodKind::OneWay => {
+                responses.insert("202".into(), json!({"description": "The request was sent"}));
+            }
+        }
+        if let Some(ref error) = method.error {
+            let error = schema(&Type::parse(error), &mut schemas);
+            responses.insert(
+                "422".into(),
+                json!({
+                    "description": "The error the RPC threw",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "properties": {
+                                    "error": {
+                                        "type": "object",
+                                        "properties": {
+                                            "message": {"type": "string"},
+                                            "data": error,
+                                        },
+                                        "required": ["message", "data"],
+                                    },
+                                },
+                                "required": ["error"],
+                            },
+                        },
+                    },
+                }),
+            );
+        }
+        responses.insert(
+            "default".into(),
+            json!({"$ref": "#/components/responses/Error"}),
+        );
+
+        let mut operation = json!({
+            "operationId": &*method.name,
+            "parameters": [{"$ref": "#/components/parameters/Timeout"}],
+            "requestBody": {
+                "required": !method.args.is_empty(),
+                "content": {"application/json": {"schema": args}},
+            },
+            "responses": responses,
+        });
+        if let Some(since) = method.since {
+            operation["description"] = format!("Added in version {}.", since).into();
+        }
+        paths.insert(
+            format!("/{}/{}", service.name, method.name),
+            json!({ "post": operation }),
+        );
+    }
+
+    let version = service
+        .methods
+        .iter()
+        .flat_map(|method| method.since.into_iter().chain(method.removed))
+        .max()
+        .unwrap_or(1);
+    json!({
+        "openapi": "3.0.3",
+        "info": {"title": &*service.name, "version": version.to_string()},
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "parameters": {
+                "Timeout": {
+                    "name": TIMEOUT_HEADER,
+                    "in": "header",
+                    "description": "How long the call may take, as a duration like `500ms` or \
+                                    `2s`",
+                    "schema": {"type": "string"},
+                },
+            },
+            "responses": {
+                "Error": {
+                    "description": "The call failed",
+                    "content": {
+                        "application/json": {"schema": {"$ref": "#/components/schemas/Error"}},
+                    },
+                },
+            },
+        },
+    })
+}
+
+/// Returns the schema of values of type `ty`, adding the schemas of named types to `schemas`.
+fn schema(ty: &Type, schemas: &mut Map<String, Value>) -> Value {
+    match ty {
+        Type::Unit => json!({"nullable": true, "enum": [null]}),
+        Type::Bool => json!({"type": "boolean"}),
+        Type::Integer { signed, bits } => {
+            let mut schema = json!({
+                "type": "integer",
+                "format": if *bits <= 32 && *signed { "int32" } else { "int64" },
+            });
+            if !signed {
+                schema["minimum"] = 0.into();
+            }
+            schema
+        }
+        Type::Float { bits } => json!({
+            "type": "number",
+            "format": if *bits == 32 { "float" } else { "double" },
+        }),
+        Type::Char => json!({"type": "string", "minLength": 1, "maxLength": 1}),
+        Type::String => json!({"type": "string"}),
+        Type::Option(ty) => {
+            let schema = schema(ty, schemas);
+            match schema {
+                Value::Object(mut schema) if !schema.contains_key("$ref") => {
+                    schema.insert("nullable".into(), true.into());
+                    Value::Object(schema)
+                }
+                schema => json!({"allOf": [schema], "nullable": true}),
+            }
+        }
+        Type::List(ty) => json!({"type": "array", "items": schema(ty, schemas)}),
+        Type::Array(ty, len) => json!({
+            "type": "array",
+            "items": schema(ty, schemas),
+            "minItems": len,
+            "maxItems": len,
+        }),
+        Type::Tuple(tys) => {
+            let mut items: Vec<Value> = vec![];
+            for ty in tys {
+                let schema = schema(ty, schemas);
+                if !items.contains(&schema) {
+                    items.push(schema);
+                }
+            }
+            let items = if items.len() == 1 {
+                items.remove(0)
+            } else {
+                json!({ "oneOf": items })
+            };
+            json!({
+                "type": "array",
+                "items": items,
+                "minItems": tys.len(),
+                "maxItems": tys.len(),
+            })
+        }
+        Type::Map(_, value) => json!({
+            "type": "object",
+            "additionalProperties": schema(value, schemas),
+        }),
+        // Paths are joined with dots, so that types of the same name in different modules don't
+        // collide.
+        Type::Named { name, args } if args.is_empty() => {
+            let key = name.replace("::", ".");
+            let reference = format!("#/components/schemas/{}", key);
+            schemas.entry(key).or_insert_with(|| {
+                json!({
+                    "description": format!(
+                        "The Rust type `{}`, which the service's schema doesn't describe",
+                        name
+                    ),
+                })
+            });
+            json!({ "$ref": reference })
+        }
+        Type::Named { name, .. } => json!({
+            "description": format!(
+                "A Rust type `{}<...>`, which the service's schema doesn't describe",
+                name
+            ),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::document;
+    use rpc::schema::{Arg, Method, MethodKind, Service};
+    use serde_json::json;
+    use std::borrow::Cow;
+
+    #[test]
+    fn describes_each_rpc() {
+        let method = |name, kind, args, output, error: Option<&'static str>| Method {
+            name: Cow::Borrowed(name),
+            wire_name: Cow::Borrowed(name),
+            id: 0,
+            kind,
+            args: Cow::Borrowed(args),
+            output: Cow::Borrowed(output),
+            error: error.map(Cow::Borrowed),
+            since: None,
+            removed: None,
+        };
+        let service = Service {
+            name: Cow::Borrowed("Users"),
+            methods: Cow::Owned(vec![
+                method(
+                    "get",
+                    MethodKind::Unary,
+                    &[
+                        Arg {
+                            name: Cow::Borrowed("id"),
+                            ty: Cow::Borrowed("u32"),
+                        },
+                        Arg {
+                            name: Cow::Borrowed("fields"),
+                            ty: Cow::Borrowed("Option<Vec<String>>"),
+                        },
+                    ],
+                    "model::User",
+                    Some("String"),
+                ),
+                method("list", MethodKind::Streaming, &[], "User", None),
+                Method {
+                    removed: Some(2),
+                    ..method("ping", MethodKind::OneWay, &[], "()", None)
+                },
+            ]),
+        };
+
+        let document = document(&service);
+        assert_eq!(document["info"]["version"], json!("2"));
+        assert!(document["paths"]["/Users/ping"].is_null());
+
+        let get = &document["paths"]["/Users/get"]["post"];
+        assert_eq!(
+            get["requestBody"]["content"]["application/json"]["schema"],
+            json!({
+                "type": "object",
+                "properties": {
+                    "id": {"type": "integer", "format": "int64", "minimum": 0},
+                    "fields": {"type": "array", "items": {"type": "string"}, "nullable": true},
+                },
+                "required": ["id"],
+            })
+        );
+        assert_eq!(
+            get["responses"]["200"]["content"]["application/json"]["schema"],
+            json!({"$ref": "#/components/schemas/model.User"})
+        );
+        assert_eq!(
+            get["responses"]["422"]["content"]["application/json"]["schema"]["properties"]["error"]
+                ["properties"]["data"],
+            json!({"type": "string"})
+        );
+
+        let list = &document["paths"]["/Users/list"]["post"];
+        assert_eq!(list["requestBody"]["required"], json!(false));
+        assert_eq!(
+            list["responses"]["200"]["content"]["application/x-ndjson"]["schema"],
+            json!({"$ref": "#/components/schemas/User"})
+        );
+        assert!(document["components"]["schemas"]["User"].is_object());
+        assert!(document["components"]["schemas"]["model.User"].is_object());
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serves tarpc from an existing hyper server, over connections upgraded from HTTP/1.1.
+//!
+//! A client switches a connection to tarpc with an `Upgrade: tarpc` request, or a `CONNECT`
+//! request, so that one port, e.g. the HTTPS port of a web app, can serve both the app and
+//! tarpc, reusing the app's TLS termination. The app's handler passes such requests to an
+//! [`Acceptor`], which responds to them, and hands the upgraded connections, as transports, to
+//! an [`Incoming`] stream, to be served like those of any other listener:
+//!
+//! ```ignore
+//! let (acceptor, incoming) = upgrade::incoming::<Bincode, _, _>();
+//! tokio::spawn(
+//!     server::new(server::Config::default())
+//!         .incoming(incoming.filter_map(|transport| future::ready(transport.ok())))
+//!         .respond_with(HelloServer.serve()),
+//! );
+//!
+//! let make_service = make_service_fn(move |_: &AddrStream| {
+//!     let acceptor = acceptor.clone();
+//!     future::ok::<_, io::Error>(service_fn(move |request| {
+//!         if upgrade::is_upgrade(&request) {
+//!             future::ok(acceptor.accept(request)).boxed()
+//!         } else {
+//!             web_app(request).boxed()
+//!         }
+//!     }))
+//! });
+//! ```
+//!
+//! Clients [`connect`] with a hyper client, which can be configured for TLS. Messages are framed
+//! by length, as in the bincode and JSON transports, and serialized with a
+//! [`Codec`](rpc::codec::Codec), like [`Bincode`](rpc::codec::Bincode) or
+//! [`Json`](rpc::codec::Json).
+
+use bytes::Bytes;
+use futures::{
+    channel::mpsc,
+    prelude::*,
+    ready,
+    stream::{FusedStream, FuturesUnordered},
+};
+use http::{header, HeaderValue, Method, StatusCode, Uri};
+use hyper::{
+    client::connect::Connect,
+    upgrade::{OnUpgrade, Upgraded},
+    Body,
+};
+use rpc::codec::Codec;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio_codec::{Framed, LengthDelimitedCodec};
+
+/// The protocol named by the `Upgrade` header of requests that switch to tarpc.
+pub const PROTOCOL: &str = "tarpc";
+
+/// Returns whether `request` asks to switch its connection to tarpc, either with an `Upgrade`
+/// header naming [`PROTOCOL`], or as a `CONNECT` request.
+pub fn is_upgrade(request: &hyper::Request<Body>) -> bool {
+    if request.method() == Method::CONNECT {
+        return true;
+    }
+    let names = |name| {
+        request
+            .headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    names(header::CONNECTION)
+        .iter()
+        .any(|option| option.eq_ignore_ascii_case("upgrade"))
+        && names(header::UPGRADE)
+            .iter()
+            .any(|protocol| protocol.eq_ignore_ascii_case(PROTOCOL))
+}
+
+/// Returns an acceptor of requests to switch to tarpc, and the stream of transports over the
+/// connections it switches.
+pub fn incoming<C, Item, SinkItem>() -> (Acceptor, Incoming<C, Item, SinkItem>) {
+    let (upgrades, accepted) = mpsc::unbounded();
+    (
+        Acceptor { upgrades },
+        Incoming {
+            accepted: accepted.fuse(),
+            upgrading: FuturesUnordered::new(),
+            ghost: PhantomData,
+        },
+    )
+}
+
+/// Switches connections to tarpc. Returned by [`incoming`].
+#[derive(Clone, Debug)]
+pub struct Acceptor {
+    upgrades: mpsc::UnboundedSender<OnUpgrade>,
+}
+
+impl Acceptor {
+    /// Returns the response to a request that [asks to switch](is_upgrade) to tarpc. Once the
+    /// response is sent, the connection is switched, and its transport is yielded by the
+    /// [`Incoming`] stream.
+    ///
+    /// Requests that don't ask to switch are answered with `400 Bad Request`, and requests made
+    /// once the stream is dropped with `503 Service Unavailable`.
+    pub fn accept(&self, request: hyper::Request<Body>) -> hyper::Response<Body> {
+        if !is_upgrade(&request) {
+            return status(StatusCode::BAD_REQUEST);
+        }
+        let connect = request.method() == Method::CONNECT;
+        if self
+            .upgrades
+            .unbounded_send(request.into_body().on_upgrade())
+            .is_err()
+        {
+            return status(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        if connect {
+            return status(StatusCode::OK);
+        }
+        let mut response = status(StatusCode::SWITCHING_PROTOCOLS);
+        let headers = response.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static(PROTOCOL));
+        response
+    }
+}
+
+fn status(status: StatusCode) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// The transports over the connections switched by an [`Acceptor`]. Returned by [`incoming`].
+///
+/// Yields an error for each connection that closed before it switched, and ends once every
+/// acceptor is dropped.
+#[must_use = "streams do nothing unless polled"]
+pub struct Incoming<C, Item, SinkItem> {
+    accepted: stream::Fuse<mpsc::UnboundedReceiver<OnUpgrade>>,
+    upgrading: FuturesUnordered<OnUpgrade>,
+    ghost: PhantomData<fn(C, SinkItem) -> Item>,
+}
+
+impl<C, Item, SinkItem> Stream for Incoming<C, Item, SinkItem> {
+    type Item = io::Result<Transport<C, Item, SinkItem>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        while let Poll::Ready(Some(upgrade)) = self.accepted.poll_next_unpin(cx) {
+            self.upgrading.push(upgrade);
+        }
+        match ready!(self.upgrading.poll_next_unpin(cx)) {
+            Some(upgraded) => Poll::Ready(Some(
+                upgraded
+                    .map(Transport::new)
+                    .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e)),
+            )),
+            None if self.accepted.is_terminated() => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<C, Item, SinkItem> fmt::Debug for Incoming<C, Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Incoming")
+            .field("upgrading", &self.upgrading.len())
+            .finish()
+    }
+}
+
+/// Switches a connection to the server at `uri` to tarpc, returning a transport over it.
+///
+/// The connection is made by `client`, so it's made over TLS if the client is configured for
+/// it and `uri` is an `https` URI.
+pub async fn connect<C, Item, SinkItem, Conn>(
+    client: &hyper::Client<Conn>,
+    uri: Uri,
+) -> io::Result<Transport<C, Item, SinkItem>>
+where
+    Conn: Connect + Sync + 'static,
+{
+    let mut request = hyper::Request::builder();
+    request
+        .uri(uri)
+        .header(header::CONNECTION, "upgrade")
+        .header(header::UPGRADE, PROTOCOL);
+    let request = request
+        .body(Body::empty())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionRefused, e))?;
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the server didn't switch to tarpc, responding {}",
+                response.status()
+            ),
+        ));
+    }
+    let upgraded = response
+        .into_body()
+        .on_upgrade()
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::ConnectionAborted, e))?;
+    Ok(Transport::new(upgraded))
+}
+
+/// A transport over a connection switched to tarpc, serializing messages with `C`.
+pub struct Transport<C, Item, SinkItem> {
+    inner: Framed<Upgraded, LengthDelimitedCodec>,
+    ghost: PhantomData<fn(C, SinkItem) -> Item>,
+}
+
+impl<C, Item, SinkItem> Transport<C, Item, SinkItem> {
+    fn new(upgraded: Upgraded) -> Self {
+        Transport {
+            inner: Framed::new(upgraded, LengthDelimitedCodec::new()),
+            ghost: PhantomData,
+        }
+    }
+}
+
+impl<C, Item, SinkItem> Stream for Transport<C, Item, SinkItem>
+where
+    C: Codec,
+    Item: DeserializeOwned,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Item>>> {
+        let frame = ready!(self.inner.poll_next_unpin(cx));
+        Poll::Ready(frame.map(|frame| C::decode(&frame?)))
+    }
+}
+
+impl<C, Item, SinkItem> Sink<SinkItem> for Transport<C, Item, SinkItem>
+where
+    C: Codec,
+    SinkItem: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        let frame = Bytes::from(C::encode(&item)?);
+        Pin::new(&mut self.inner).start_send(frame)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<C, Item, SinkItem> fmt::Debug for Transport<C, Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transport").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::{self, Ready};
+    use hyper::{
+        server::conn::AddrStream,
+        service::{make_service_fn, service_fn},
+    };
+    use rpc::{
+        client,
+        codec::Json,
+        context,
+        server::{self, Handler},
+    };
+
+    #[tarpc::service]
+    trait World {
+        async fn hello(name: String) -> String;
+    }
+
+    #[derive(Clone)]
+    struct HelloServer;
+
+    impl World for HelloServer {
+        type HelloFut = Ready<String>;
+
+        fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+            future::ready(format!("Hello, {}!", name))
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_tarpc_and_http_on_one_port() -> io::Result<()> {
+        let (acceptor, incoming) = incoming::<Json, _, _>();
+        tokio::spawn(
+            server::new(server::Config::default())
+                .incoming(incoming.filter_map(|transport| future::ready(transport.ok())))
+                .respond_with(HelloServer.serve()),
+        );
+        let make_service = make_service_fn(move |_: &AddrStream| {
+            let acceptor = acceptor.clone();
+            future::ok::<_, io::Error>(service_fn(move |request| {
+                future::ok::<_, io::Error>(if is_upgrade(&request) {
+                    acceptor.accept(request)
+                } else {
+                    hyper::Response::new(Body::from("a web app"))
+                })
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server.map(|result| result.unwrap()));
+
+        let http = hyper::Client::new();
+        let uri: Uri = format!("http://{}/", addr).parse().unwrap();
+        let transport = connect::<Json, _, _, _>(&http, uri.clone()).await?;
+        let mut client = WorldClient::new(client::Config::default(), transport).spawn()?;
+        assert_eq!(
+            client.hello(context::current(), "Tim".into()).await?,
+            "Hello, Tim!"
+        );
+
+        let response = http.get(uri).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[test]
+    fn detects_upgrades() {
+        let request = |method, headers: &[(&str, &str)]| {
+            let mut request = hyper::Request::builder();
+            request.method(method).uri("/");
+            for (name, value) in headers {
+                request.header(*name, *value);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        assert!(is_upgrade(&request(
+            Method::GET,
+            &[("connection", "keep-alive, Upgrade"), ("upgrade", "tarpc")]
+        )));
+        assert!(is_upgrade(&request(Method::CONNECT, &[])));
+        assert!(!is_upgrade(&request(
+            Method::GET,
+            &[("connection", "upgrade"), ("upgrade", "websocket")]
+        )));
+        assert!(!is_upgrade(&request(Method::GET, &[("upgrade", "tarpc")])));
+    }
+}
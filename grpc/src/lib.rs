@@ -0,0 +1,839 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Exposes tarpc services to gRPC clients.
+//!
+//! A [`Bridge`] serves gRPC over HTTP/2, forwarding each call to a tarpc service over a
+//! [client channel](rpc::client::Channel). The gRPC service's messages are
+//! [prost](https://docs.rs/prost) types, and for each of its methods, the bridge is given a
+//! function that translates the method's request message into the tarpc service's request, and
+//! one that translates the tarpc service's response into the method's response message:
+//!
+//! ```ignore
+//! let server = Bridge::new("helloworld.Greeter", channel)
+//!     .unary(
+//!         "SayHello",
+//!         |request: HelloRequest| GreeterRequest::Hello { name: request.name },
+//!         |response| match response {
+//!             GreeterResponse::Hello(message) => Ok(HelloReply { message }),
+//!             _ => unreachable!(),
+//!         },
+//!     )
+//!     .serve(&addr)?;
+//! server.await?;
+//! ```
+//!
+//! The bridge maps the rest of a call between the two:
+//!
+//! - **Deadlines**: a call's `grpc-timeout` sets the deadline of the tarpc request. Calls without
+//!   one get the default [context](rpc::context::current)'s deadline, since every tarpc request
+//!   has one.
+//! - **Cancellation**: a call the gRPC client cancels, or whose connection closes, is dropped,
+//!   which cancels the tarpc request.
+//! - **Errors**: a tarpc request that fails ends the call with the [`Code`] for its
+//!   [`io::ErrorKind`], e.g. [`DeadlineExceeded`](Code::DeadlineExceeded) for requests that
+//!   time out. RPCs declared with `#[throws]` can map the errors they throw to any [`Status`]
+//!   when translating their responses. Calls whose request message can't be decoded end with
+//!   [`InvalidArgument`](Code::InvalidArgument), and those whose message is larger than the
+//!   bridge's [maximum](Bridge::max_message_size) with
+//!   [`ResourceExhausted`](Code::ResourceExhausted).
+//!
+//! tarpc RPCs take a single request and respond with either one response or a stream of them,
+//! so gRPC's unary and server-streaming methods can be bridged, but its client-streaming and
+//! bidirectional-streaming methods can't. Compressed messages aren't supported.
+//!
+//! The tarpc service can also use the gRPC service's messages directly, as args and outputs
+//! wrapped in a [`Proto`], which is serialized as the message's protobuf encoding; see the
+//! [`message`] module.
+
+#![deny(missing_docs, missing_debug_implementations)]
+
+pub mod message;
+
+pub use message::Proto;
+
+use futures::{future, prelude::*, stream};
+use http::{header::HeaderValue, HeaderMap};
+use http_body::Body as HttpBody;
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Chunk,
+};
+use rpc::{
+    client, context,
+    error::{Classify, ErrorKind},
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+/// The status codes gRPC calls end with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Code {
+    /// The call succeeded.
+    Ok = 0,
+    /// The call was canceled, typically by the caller.
+    Cancelled = 1,
+    /// An error without a more specific code.
+    Unknown = 2,
+    /// The request was invalid.
+    InvalidArgument = 3,
+    /// The deadline passed before the call completed.
+    DeadlineExceeded = 4,
+    /// A requested entity wasn't found.
+    NotFound = 5,
+    /// An entity the call tried to create already exists.
+    AlreadyExists = 6,
+    /// The caller isn't permitted to make the call.
+    PermissionDenied = 7,
+    /// A resource, like the server's capacity, was exhausted.
+    ResourceExhausted = 8,
+    /// The system isn't in a state the call requires.
+    FailedPrecondition = 9,
+    /// The call was aborted, e.g. by a concurrency conflict.
+    Aborted = 10,
+    /// The call went past a valid range.
+    OutOfRange = 11,
+    /// The method isn't implemented by the server.
+    Unimplemented = 12,
+    /// An invariant of the server or the protocol was broken.
+    Internal = 13,
+    /// The service is unavailable; the call may succeed if retried.
+    Unavailable = 14,
+    /// Data was lost or corrupted.
+    DataLoss = 15,
+    /// The caller isn't authenticated.
+    Unauthenticated = 16,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl From<io::ErrorKind> for Code {
+    fn from(kind: io::ErrorKind) -> Code {
+        match kind {
+            io::ErrorKind::TimedOut => Code::DeadlineExceeded,
+            io::ErrorKind::NotFound => Code::NotFound,
+            io::ErrorKind::PermissionDenied => Code::PermissionDenied,
+            io::ErrorKind::AlreadyExists => Code::AlreadyExists,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Code::InvalidArgument,
+            // Servers shed load with WouldBlock.
+            io::ErrorKind::WouldBlock => Code::ResourceExhausted,
+            io::ErrorKind::Interrupted => Code::Cancelled,
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe => Code::Unavailable,
+            _ => Code::Unknown,
+        }
+    }
+}
+
+/// The status a gRPC call ends with.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Status {
+    /// The status code.
+    pub code: Code,
+    /// A description of the error, for developers.
+    pub message: String,
+}
+
+impl Status {
+    /// Returns a status with the given code and message.
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Status {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Returns the trailers that end a call with this status.
+    fn trailers(&self) -> HeaderMap {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from(self.code as u32));
+        if !self.message.is_empty() {
+            let message = percent_encode(&self.message);
+            trailers.insert(
+                "grpc-message",
+                HeaderValue::from_str(&message).expect("percent-encoded messages are valid"),
+            );
+        }
+        trailers
+    }
+}
+
+/// Maps requests rejected as [unauthenticated](rpc::error::ErrorKind::Unauthenticated) to
+/// `Unauthenticated`, and other errors by their kind.
+impl From<io::Error> for Status {
+    fn from(e: io::Error) -> Self {
+        let code = if e.classify() == ErrorKind::Unauthenticated {
+            Code::Unauthenticated
+        } else {
+            Code::from(e.kind())
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+/// Percent-encodes the characters gRPC doesn't allow in `grpc-message` as is.
+fn percent_encode(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// The largest request message a [`Bridge`] accepts by default, in bytes: gRPC's default.
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+type Messages = Pin<Box<dyn Stream<Item = Result<Vec<u8>, Status>> + Send>>;
+
+/// How one gRPC method is forwarded to the tarpc service.
+struct Route<Req, Resp> {
+    streaming: bool,
+    request: Decode<Req>,
+    response: Encode<Resp>,
+}
+
+type Decode<Req> = Box<dyn Fn(&[u8]) -> Result<Req, Status> + Send + Sync>;
+type Encode<Resp> = Box<dyn Fn(Resp) -> Result<Vec<u8>, Status> + Send + Sync>;
+
+/// Exposes a tarpc service as a gRPC service.
+pub struct Bridge<Req, Resp> {
+    service: String,
+    channel: client::Channel<Req, Resp>,
+    routes: HashMap<String, Arc<Route<Req, Resp>>>,
+    max_message_size: usize,
+}
+
+impl<Req, Resp> Bridge<Req, Resp> {
+    /// Returns a bridge that serves the gRPC service named `service`, fully qualified by its
+    /// package, e.g. `helloworld.Greeter`, by forwarding calls over `channel`. It has no methods
+    /// until they're added with [`unary`](Bridge::unary) and
+    /// [`server_streaming`](Bridge::server_streaming).
+    pub fn new(service: impl Into<String>, channel: client::Channel<Req, Resp>) -> Self {
+        Bridge {
+            service: service.into(),
+            channel,
+            routes: HashMap::new(),
+            max_message_size: MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Sets the largest request message the bridge accepts, in bytes. Calls with larger
+    /// messages end with [`ResourceExhausted`](Code::ResourceExhausted). Defaults to
+    /// [`MAX_MESSAGE_SIZE`].
+    pub fn max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    /// Serves the unary method named `method`, translating its request message with `request`
+    /// and the tarpc service's response with `response`.
+    pub fn unary<M, R, F, G>(self, method: &str, request: F, response: G) -> Self
+    where
+        M: prost::Message + Default,
+        R: prost::Message,
+        F: Fn(M) -> Req + Send + Sync + 'static,
+        G: Fn(Resp) -> Result<R, Status> + Send + Sync + 'static,
+    {
+        self.route(method, false, request, response)
+    }
+
+    /// Serves the server-streaming method named `method`, translating its request message with
+    /// `request`, and each item the tarpc service streams with `response`.
+    pub fn server_streaming<M, R, F, G>(self, method: &str, request: F, response: G) -> Self
+    where
+        M: prost::Message + Default,
+        R: prost::Message,
+        F: Fn(M) -> Req + Send + Sync + 'static,
+        G: Fn(Resp) -> Result<R, Status> + Send + Sync + 'static,
+    {
+        self.route(method, true, request, response)
+    }
+
+    fn route<M, R, F, G>(mut self, method: &str, streaming: bool, request: F, response: G) -> Self
+    where
+        M: prost::Message + Default,
+        R: prost::Message,
+        F: Fn(M) -> Req + Send + Sync + 'static,
+        G: Fn(Resp) -> Result<R, Status> + Send + Sync + 'static,
+    {
+        let route = Route {
+            streaming,
+            request: Box::new(move |message| match M::decode(message) {
+                Ok(message) => Ok(request(message)),
+                Err(e) => Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("the request message is invalid: {}", e),
+                )),
+            }),
+            response: Box::new(move |message| {
+                let message = response(message)?;
+                let mut encoded = Vec::with_capacity(message.encoded_len());
+                message
+                    .encode(&mut encoded)
+                    .map_err(|e| Status::new(Code::Internal, e.to_string()))?;
+                Ok(encoded)
+            }),
+        };
+        self.routes.insert(method.to_string(), Arc::new(route));
+        self
+    }
+}
+
+impl<Req, Resp> Bridge<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    /// Handles one gRPC call, for serving the bridge from an existing HTTP/2 server.
+    pub fn handle(
+        &self,
+        request: hyper::Request<Body>,
+    ) -> impl Future<Output = hyper::Response<ResponseBody>> + Send + 'static {
+        let call = self.call(request);
+        async move {
+            let messages = match call {
+                Ok(call) => call.await.unwrap_or_else(|status| error(status)),
+                Err(status) => error(status),
+            };
+            let mut response = hyper::Response::new(ResponseBody {
+                messages: Some(messages),
+                status: None,
+            });
+            response.headers_mut().insert(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("application/grpc"),
+            );
+            response
+        }
+    }
+
+    /// Checks the call can be forwarded, returning a future that forwards it.
+    fn call(
+        &self,
+        request: hyper::Request<Body>,
+    ) -> Result<impl Future<Output = Result<Messages, Status>> + Send + 'static, Status> {
+        let mut path = request.uri().path().trim_start_matches('/').splitn(2, '/');
+        let route = match (path.next(), path.next()) {
+            (Some(service), Some(method)) if service == self.service => self.routes.get(method),
+            _ => None,
+        };
+        let route = route.cloned().ok_or_else(|| {
+            Status::new(
+                Code::Unimplemented,
+                format!("unknown method {}", request.uri().path()),
+            )
+        })?;
+        let mut ctx = context::current();
+        if let Some(timeout) = request.headers().get("grpc-timeout") {
+            let timeout = parse_timeout(timeout).ok_or_else(|| {
+                Status::new(
+                    Code::InvalidArgument,
+                    format!("invalid grpc-timeout {:?}", timeout),
+                )
+            })?;
+            ctx.deadline = SystemTime::now() + timeout;
+        }
+        let mut channel = self.channel.clone();
+        let max_message_size = self.max_message_size;
+
+        Ok(async move {
+            let mut body = request.into_body();
+            let mut bytes = vec![];
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.map_err(|e| Status::new(Code::Internal, e.to_string()))?;
+                // The message is framed by a 5-byte prefix.
+                if bytes.len() + chunk.len() > max_message_size + 5 {
+                    return Err(Status::new(
+                        Code::ResourceExhausted,
+                        format!(
+                            "the request message is larger than {} bytes",
+                            max_message_size
+                        ),
+                    ));
+                }
+                bytes.extend_from_slice(&chunk);
+            }
+            let message = (route.request)(unframe(&bytes)?)?;
+            if !route.streaming {
+                let response = channel.call(ctx, message).await?;
+                let response = (route.response)(response)?;
+                return Ok(stream::once(future::ready(Ok(response))).boxed());
+            }
+            let responses = channel.call_stream(ctx, message).await?;
+            Ok(responses
+                .map(move |response| (route.response)(response?))
+                .boxed())
+        })
+    }
+
+    /// Serves the bridge over HTTP/2 on `addr`, returning a future that runs the server.
+    pub fn serve(self, addr: &SocketAddr) -> io::Result<Server> {
+        let bridge = Arc::new(self);
+        let make_service = make_service_fn(move |_: &AddrStream| {
+            let bridge = bridge.clone();
+            future::ok::<_, io::Error>(service_fn(move |request| {
+                bridge.handle(request).map(Ok::<_, io::Error>)
+            }))
+        });
+        let server = hyper::Server::try_bind(addr)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            .http2_only(true)
+            .serve(make_service);
+        Ok(Server {
+            local_addr: server.local_addr(),
+            server: server
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .boxed(),
+        })
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Bridge<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Bridge")
+            .field("service", &self.service)
+            .field("methods", &self.routes.keys().collect::<Vec<_>>())
+            .field("max_message_size", &self.max_message_size)
+            .finish()
+    }
+}
+
+fn error(status: Status) -> Messages {
+    stream::once(future::ready(Err(status))).boxed()
+}
+
+/// Parses a `grpc-timeout`: a positive integer of at most 8 digits, followed by a unit.
+fn parse_timeout(timeout: &HeaderValue) -> Option<Duration> {
+    let timeout = timeout.to_str().ok()?;
+    if timeout.len() < 2 || timeout.len() > 9 {
+        return None;
+    }
+    let (value, unit) = timeout.split_at(timeout.len() - 1);
+    if !value.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let value: u64 = value.parse().ok()?;
+    Some(match unit {
+        "H" => Duration::from_secs(value * 60 * 60),
+        "M" => Duration::from_secs(value * 60),
+        "S" => Duration::from_secs(value),
+        "m" => Duration::from_millis(value),
+        "u" => Duration::from_micros(value),
+        "n" => Duration::from_nanos(value),
+        _ => return None,
+    })
+}
+
+/// Returns the single message framed in a request body.
+fn unframe(body: &[u8]) -> Result<&[u8], Status> {
+    if body.len() < 5 {
+        return Err(Status::new(
+            Code::InvalidArgument,
+            "expected a request message",
+        ));
+    }
+    if body[0] != 0 {
+        return Err(Status::new(
+            Code::Unimplemented,
+            "compressed messages aren't supported",
+        ));
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]);
+    match usize::try_from(len) {
+        Ok(len) if len == body.len() - 5 => Ok(&body[5..]),
+        _ => Err(Status::new(
+            Code::InvalidArgument,
+            "expected exactly one request message",
+        )),
+    }
+}
+
+/// Frames a message for a response body.
+fn frame(message: Vec<u8>) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(message.len() + 5);
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend(message);
+    framed
+}
+
+/// The body of a gRPC response: the response messages, followed by trailers with the call's
+/// status.
+pub struct ResponseBody {
+    messages: Option<Messages>,
+    status: Option<Status>,
+}
+
+impl HttpBody for ResponseBody {
+    type Data = Chunk;
+    type Error = io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<Chunk>>> {
+        let messages = match self.messages {
+            Some(ref mut messages) => messages,
+            None => return Poll::Ready(None),
+        };
+        let status = match messages.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(message))) => {
+                return Poll::Ready(Some(Ok(Chunk::from(frame(message)))))
+            }
+            Poll::Ready(Some(Err(status))) => status,
+            Poll::Ready(None) => Status::new(Code::Ok, ""),
+            Poll::Pending => return Poll::Pending,
+        };
+        self.messages = None;
+        self.status = Some(status);
+        Poll::Ready(None)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<io::Result<Option<HeaderMap>>> {
+        Poll::Ready(Ok(self.status.take().map(|status| status.trailers())))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.messages.is_none() && self.status.is_none()
+    }
+}
+
+impl fmt::Debug for ResponseBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseBody")
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+/// A gRPC server for a [`Bridge`]. Resolves once the server stops, which it only does if it
+/// fails.
+#[must_use = "futures do nothing unless polled"]
+pub struct Server {
+    local_addr: SocketAddr,
+    server: Pin<Box<dyn Future<Output = io::Result<()>> + Send>>,
+}
+
+impl Server {
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Future for Server {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.server.as_mut().poll(cx)
+    }
+}
+
+impl fmt::Debug for Server {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("local_addr", &self.local_addr)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::Ready;
+    use rpc::{
+        error::{unauthenticated, UNAUTHENTICATED},
+        server::{BaseChannel, Channel},
+        transport::channel,
+    };
+    use std::{ops::RangeInclusive, time::UNIX_EPOCH};
+
+    #[tarpc::service(derive_serde = false)]
+    trait Greeter {
+        async fn hello(name: String) -> String;
+        #[stream]
+        async fn count(to: u32) -> u32;
+        async fn deadline() -> u64;
+        async fn hang();
+    }
+
+    #[derive(Clone)]
+    struct GreeterServer;
+
+    impl Greeter for GreeterServer {
+        type HelloFut = Ready<String>;
+
+        fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+            future::ready(format!("Hello, {}!", name))
+        }
+
+        type CountStream = stream::Iter<RangeInclusive<u32>>;
+
+        fn count(self, _: context::Context, to: u32) -> Self::CountStream {
+            stream::iter(1..=to)
+        }
+
+        type DeadlineFut = Ready<u64>;
+
+        /// Returns the request's deadline, in nanoseconds since the Unix epoch.
+        fn deadline(self, ctx: context::Context) -> Self::DeadlineFut {
+            let deadline = ctx.deadline.duration_since(UNIX_EPOCH).unwrap();
+            future::ready(deadline.as_nanos() as u64)
+        }
+
+        type HangFut = future::Pending<()>;
+
+        fn hang(self, _: context::Context) -> Self::HangFut {
+            future::pending()
+        }
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct HelloRequest {
+        #[prost(string, tag = "1")]
+        name: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct HelloReply {
+        #[prost(string, tag = "1")]
+        message: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Number {
+        #[prost(uint64, tag = "1")]
+        value: u64,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Empty {}
+
+    /// Serves the greeter over gRPC, returning its address.
+    fn serve() -> io::Result<SocketAddr> {
+        let (client_transport, server_transport) = channel::unbounded();
+        tokio::spawn(
+            BaseChannel::with_defaults(server_transport)
+                .respond_with(GreeterServer.serve())
+                .execute(),
+        );
+        let channel = client::new(client::Config::default(), client_transport).spawn()?;
+        let server = Bridge::new("helloworld.Greeter", channel)
+            .unary(
+                "SayHello",
+                |request: HelloRequest| GreeterRequest::Hello { name: request.name },
+                |response| match response {
+                    GreeterResponse::Hello(message) => Ok(HelloReply { message }),
+                    _ => unreachable!(),
+                },
+            )
+            .server_streaming(
+                "Count",
+                |to: Number| GreeterRequest::Count {
+                    to: to.value as u32,
+                },
+                |response| match response {
+                    GreeterResponse::Count(Some(n)) => Ok(Number { value: n.into() }),
+                    _ => unreachable!(),
+                },
+            )
+            .unary(
+                "Deadline",
+                |_: Empty| GreeterRequest::Deadline {},
+                |response| match response {
+                    GreeterResponse::Deadline(nanos) => Ok(Number { value: nanos }),
+                    _ => unreachable!(),
+                },
+            )
+            .unary("Hang", |_: Empty| GreeterRequest::Hang {}, |_| Ok(Empty {}))
+            .serve(&([127, 0, 0, 1], 0).into())?;
+        let addr = server.local_addr();
+        tokio::spawn(server.map(|result| result.unwrap()));
+        Ok(addr)
+    }
+
+    /// Calls `method` with `message`, returning the response messages and the call's status.
+    async fn call<M: prost::Message, R: prost::Message + Default>(
+        addr: SocketAddr,
+        method: &str,
+        message: M,
+        timeout: Option<&str>,
+    ) -> (Vec<R>, Status) {
+        let mut encoded = vec![];
+        message.encode(&mut encoded).unwrap();
+        let mut request = hyper::Request::post(format!("http://{}/{}", addr, method));
+        request
+            .header("content-type", "application/grpc")
+            .header("te", "trailers");
+        if let Some(timeout) = timeout {
+            request.header("grpc-timeout", timeout);
+        }
+        let request = request.body(Body::from(frame(encoded))).unwrap();
+        let client = hyper::Client::builder().http2_only(true).build_http();
+        let mut body = client.request(request).await.unwrap().into_body();
+
+        let mut data = vec![];
+        while let Some(chunk) = body.next().await {
+            data.extend_from_slice(&chunk.unwrap());
+        }
+        let mut messages = vec![];
+        let mut data = &data[..];
+        while !data.is_empty() {
+            let len = u32::from_be_bytes([data[1], data[2], data[3], data[4]]) as usize;
+            messages.push(R::decode(&data[5..5 + len]).unwrap());
+            data = &data[5 + len..];
+        }
+        let trailers = body.trailers().await.unwrap().unwrap();
+        let code: u32 = trailers["grpc-status"].to_str().unwrap().parse().unwrap();
+        let code = [
+            Code::Ok,
+            Code::InvalidArgument,
+            Code::DeadlineExceeded,
+            Code::ResourceExhausted,
+            Code::Unimplemented,
+        ]
+        .iter()
+        .cloned()
+        .find(|known| *known as u32 == code)
+        .unwrap_or(Code::Unknown);
+        let message = trailers
+            .get("grpc-message")
+            .map_or("", |message| message.to_str().unwrap());
+        (messages, Status::new(code, message))
+    }
+
+    #[tokio::test]
+    async fn calls_unary_and_streaming_methods() -> io::Result<()> {
+        let addr = serve()?;
+
+        let (replies, status) = call::<_, HelloReply>(
+            addr,
+            "helloworld.Greeter/SayHello",
+            HelloRequest { name: "Tim".into() },
+            None,
+        )
+        .await;
+        assert_eq!(status.code, Code::Ok);
+        assert_eq!(
+            replies,
+            vec![HelloReply {
+                message: "Hello, Tim!".into()
+            }]
+        );
+
+        let (numbers, status) =
+            call::<_, Number>(addr, "helloworld.Greeter/Count", Number { value: 3 }, None).await;
+        assert_eq!(status.code, Code::Ok);
+        assert_eq!(
+            numbers.iter().map(|n| n.value).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+
+        let (_, status) = call::<_, Empty>(addr, "helloworld.Greeter/Wave", Empty {}, None).await;
+        assert_eq!(status.code, Code::Unimplemented);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn maps_timeouts_to_deadlines() -> io::Result<()> {
+        let addr = serve()?;
+
+        let before = SystemTime::now();
+        let (deadlines, status) =
+            call::<_, Number>(addr, "helloworld.Greeter/Deadline", Empty {}, Some("1H")).await;
+        let after = SystemTime::now();
+        assert_eq!(status.code, Code::Ok);
+        let deadline = UNIX_EPOCH + Duration::from_nanos(deadlines[0].value);
+        let hour = Duration::from_secs(60 * 60);
+        assert!(before + hour <= deadline && deadline <= after + hour);
+
+        // The call never completes, so only its deadline can end it.
+        let (replies, status) =
+            call::<_, Empty>(addr, "helloworld.Greeter/Hang", Empty {}, Some("1n")).await;
+        assert_eq!(status.code, Code::DeadlineExceeded);
+        assert!(replies.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_and_oversized_messages() -> io::Result<()> {
+        let addr = serve()?;
+
+        // A number where a string should be.
+        let (_, status) = call::<_, HelloReply>(
+            addr,
+            "helloworld.Greeter/SayHello",
+            Number { value: 3 },
+            None,
+        )
+        .await;
+        assert_eq!(status.code, Code::InvalidArgument);
+
+        let name = "a".repeat(MAX_MESSAGE_SIZE);
+        let (_, status) = call::<_, HelloReply>(
+            addr,
+            "helloworld.Greeter/SayHello",
+            HelloRequest { name },
+            None,
+        )
+        .await;
+        assert_eq!(status.code, Code::ResourceExhausted);
+        Ok(())
+    }
+
+    #[test]
+    fn parses_timeouts() {
+        let parse = |timeout| parse_timeout(&HeaderValue::from_static(timeout));
+        assert_eq!(parse("10S"), Some(Duration::from_secs(10)));
+        assert_eq!(parse("2M"), Some(Duration::from_secs(120)));
+        assert_eq!(parse("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse("7u"), Some(Duration::from_micros(7)));
+        assert_eq!(parse("99999999n"), Some(Duration::from_nanos(99_999_999)));
+        assert_eq!(parse("123456789n"), None);
+        assert_eq!(parse("S"), None);
+        assert_eq!(parse("-1S"), None);
+        assert_eq!(parse("10s"), None);
+    }
+
+    #[test]
+    fn unauthenticated_rejections_map_to_unauthenticated() {
+        let unauthenticated = unauthenticated(UNAUTHENTICATED);
+        assert_eq!(Status::from(unauthenticated).code, Code::Unauthenticated);
+        let forbidden = io::Error::new(io::ErrorKind::PermissionDenied, "Not an admin.");
+        assert_eq!(Status::from(forbidden).code, Code::PermissionDenied);
+    }
+
+    #[test]
+    fn percent_encodes_messages() {
+        assert_eq!(percent_encode("100% done"), "100%25 done");
+        assert_eq!(percent_encode("naïve\n"), "na%C3%AFve%0A");
+    }
+}
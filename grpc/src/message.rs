@@ -0,0 +1,149 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Lets protobuf messages generated by [prost](https://docs.rs/prost), including those of
+//! [tonic](https://docs.rs/tonic) services, be used as the args and outputs of tarpc RPCs.
+//!
+//! tarpc serializes RPCs with serde, which prost's types don't implement. Wrapped in a
+//! [`Proto`], they're serialized as their protobuf encoding, so a service moving from gRPC to
+//! tarpc keeps its message types, and a tarpc service moving to gRPC, or bridged to it, can
+//! declare its RPCs with the messages its gRPC clients use:
+//!
+//! ```ignore
+//! #[tarpc::service]
+//! trait Users {
+//!     async fn get(request: Proto<GetUserRequest>) -> Proto<User>;
+//!     async fn updated_at(id: Proto<String>) -> Proto<prost_types::Timestamp>;
+//! }
+//! ```
+//!
+//! The well-known types work the same way: prost implements the wrapper types, like
+//! `google.protobuf.StringValue`, for the standard library types they wrap, and `prost-types`
+//! implements the rest, like `Timestamp` and `Any`.
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+/// A protobuf message, serialized by serde as its protobuf encoding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Proto<M>(pub M);
+
+impl<M> Proto<M> {
+    /// Returns the wrapped message.
+    pub fn into_inner(self) -> M {
+        self.0
+    }
+}
+
+impl<M> From<M> for Proto<M> {
+    fn from(message: M) -> Self {
+        Proto(message)
+    }
+}
+
+impl<M> Deref for Proto<M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        &self.0
+    }
+}
+
+impl<M> DerefMut for Proto<M> {
+    fn deref_mut(&mut self) -> &mut M {
+        &mut self.0
+    }
+}
+
+impl<M: prost::Message> Serialize for Proto<M> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut encoded = Vec::with_capacity(self.0.encoded_len());
+        self.0
+            .encode(&mut encoded)
+            .map_err(serde::ser::Error::custom)?;
+        serializer.serialize_bytes(&encoded)
+    }
+}
+
+impl<'de, M: prost::Message + Default> Deserialize<'de> for Proto<M> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(ProtoVisitor(PhantomData))
+    }
+}
+
+struct ProtoVisitor<M>(PhantomData<fn() -> M>);
+
+impl<'de, M: prost::Message + Default> Visitor<'de> for ProtoVisitor<M> {
+    type Value = Proto<M>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the protobuf encoding of a message")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Proto<M>, E> {
+        M::decode(bytes).map(Proto).map_err(E::custom)
+    }
+
+    // Formats without a bytes type, like JSON, serialize bytes as a sequence.
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Proto<M>, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        self.visit_bytes(&bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Proto;
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct User {
+        #[prost(uint64, tag = "1")]
+        id: u64,
+        #[prost(string, tag = "2")]
+        name: String,
+        #[prost(string, repeated, tag = "3")]
+        emails: Vec<String>,
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let user = Proto(User {
+            id: 7,
+            name: "Ada".into(),
+            emails: vec!["ada@example.com".into()],
+        });
+
+        let encoded = bincode::serialize(&user).unwrap();
+        assert_eq!(bincode::deserialize::<Proto<User>>(&encoded).unwrap(), user);
+
+        let encoded = serde_json::to_string(&user).unwrap();
+        assert_eq!(serde_json::from_str::<Proto<User>>(&encoded).unwrap(), user);
+
+        // Well-known wrapper types.
+        let name = Proto(String::from("Ada"));
+        let encoded = serde_json::to_string(&name).unwrap();
+        assert_eq!(encoded, "[10,3,65,100,97]");
+        assert_eq!(
+            serde_json::from_str::<Proto<String>>(&encoded).unwrap(),
+            name
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_encodings() {
+        assert!(serde_json::from_str::<Proto<User>>("[10,200]").is_err());
+    }
+}
@@ -0,0 +1,767 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serves tarpc services to [JSON-RPC 2.0](https://www.jsonrpc.org/specification) clients.
+//!
+//! A [`Transport`] translates between JSON-RPC messages, sent as text frames by the client, and
+//! tarpc's messages, so that any tarpc server can serve JSON-RPC clients by listening with
+//! [`listen`] alongside, or instead of, the JSON transport:
+//!
+//! ```ignore
+//! let incoming = jsonrpc::listen::<WorldRequest, WorldResponse>(&addr)?;
+//! server::new(server::Config::default())
+//!     .incoming(incoming)
+//!     .respond_with(HelloServer.serve())
+//!     .await;
+//! ```
+//!
+//! A request's `method` is the name of an RPC in the service definition, and its `params` are
+//! the RPC's arguments, as an object keyed by argument name. The arguments are deserialized
+//! as in tarpc's own JSON requests, so their names are the names on the wire, which differ from
+//! the names in the service definition if the service sets `wire_case`. Requests without an
+//! `id` are notifications, which the server handles like one-way requests. Batches are
+//! supported, and answered once every request in them is.
+//!
+//! A unary RPC's `result` is its output; a streaming RPC's is an array of every item it
+//! streamed, of which there can be at most [`MAX_ITEMS`](json::MAX_ITEMS). Errors use the codes
+//! reserved by the specification for malformed requests, unknown methods and responses that
+//! can't be sent, [`SERVER_ERROR`] for requests the server failed, and [`APPLICATION_ERROR`] for
+//! errors thrown by RPCs declared with `#[throws]`.
+//!
+//! Requests are given the default [context](rpc::context::current), since JSON-RPC has no way
+//! to send a deadline or trace context, nor to cancel requests.
+
+use futures::{compat::*, prelude::*, ready};
+use pin_utils::unsafe_pinned;
+use rpc::{
+    context,
+    schema::{Introspect, Method, MethodKind},
+    transport::adapter::{
+        json::{self, Items},
+        Adapter, Protocol, Queue,
+    },
+    ClientMessage, Response, ServerError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::codec::{Framed, LinesCodec};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_tcp::{TcpListener, TcpStream};
+
+/// Invalid JSON was received.
+pub const PARSE_ERROR: i64 = -32700;
+/// The JSON received isn't a valid request.
+pub const INVALID_REQUEST: i64 = -32600;
+/// The service has no RPC of the requested name.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The RPC's arguments are missing or of the wrong types.
+pub const INVALID_PARAMS: i64 = -32602;
+/// The RPC's response couldn't be serialized, or a streaming RPC streamed more than
+/// [`MAX_ITEMS`](json::MAX_ITEMS) items.
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The server failed the request, e.g. because its deadline passed. The error's `data` is an
+/// object whose `kind` is the name of the [`io::ErrorKind`] the server failed it with.
+pub const SERVER_ERROR: i64 = -32000;
+/// The RPC threw an error. The error's `data` is the error thrown.
+pub const APPLICATION_ERROR: i64 = -32001;
+
+/// The longest line a [`Lines`] transport reads, in bytes.
+pub const MAX_LINE_LENGTH: usize = 8 * 1024 * 1024;
+
+/// A TCP connection over which JSON-RPC messages are sent one per line.
+pub type Lines<S> = Compat01As03Sink<Framed<S, LinesCodec>, String>;
+
+/// A transport that speaks JSON-RPC to the client over a transport of text frames, and tarpc's
+/// messages to the server.
+pub struct Transport<T, Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    inner: Adapter<T, JsonRpc<Req, Resp>>,
+}
+
+/// Translates between JSON-RPC messages and tarpc's.
+struct JsonRpc<Req, Resp> {
+    /// The batches not yet answered.
+    batches: HashMap<u64, Batch>,
+    next_batch_id: u64,
+    ghost: PhantomData<fn(Resp) -> Req>,
+}
+
+/// A request awaiting its response.
+struct Call {
+    id: Value,
+    method: &'static Method,
+    batch: Option<u64>,
+    /// The items streamed so far, for a streaming RPC.
+    items: Items,
+}
+
+/// A batch awaiting responses to some of its requests.
+struct Batch {
+    pending: usize,
+    replies: Vec<Value>,
+}
+
+impl<T, Req, Resp> Transport<T, Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    unsafe_pinned!(inner: Adapter<T, JsonRpc<Req, Resp>>);
+
+    /// Returns a transport that speaks JSON-RPC over `inner`.
+    pub fn new(inner: T) -> Self {
+        let protocol = JsonRpc {
+            batches: HashMap::new(),
+            next_batch_id: 0,
+            ghost: PhantomData,
+        };
+        Transport {
+            inner: Adapter::new(inner, protocol),
+        }
+    }
+
+    /// Returns the underlying transport.
+    pub fn get_ref(&self) -> &T {
+        self.inner.get_ref()
+    }
+}
+
+impl<Req, Resp> JsonRpc<Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    /// Queues `reply` to the request `id` of `batch`, unless it's a notification.
+    fn reply(
+        &mut self,
+        queue: &mut Queue<Self>,
+        id: Option<Value>,
+        batch: Option<u64>,
+        reply: Map<String, Value>,
+    ) {
+        let id = match id {
+            Some(id) => id,
+            None => return,
+        };
+        let mut message = Map::new();
+        message.insert("jsonrpc".into(), "2.0".into());
+        message.extend(reply);
+        message.insert("id".into(), id);
+        let message = Value::Object(message);
+        match batch {
+            Some(batch) => self.batches.get_mut(&batch).unwrap().replies.push(message),
+            None => queue.reply(message.to_string()),
+        }
+    }
+
+    fn error(
+        &mut self,
+        queue: &mut Queue<Self>,
+        id: Option<Value>,
+        batch: Option<u64>,
+        code: i64,
+        message: String,
+        data: Option<Value>,
+    ) {
+        let mut error = Map::new();
+        error.insert("code".into(), code.into());
+        error.insert("message".into(), message.into());
+        if let Some(data) = data {
+            error.insert("data".into(), data);
+        }
+        let mut reply = Map::new();
+        reply.insert("error".into(), Value::Object(error));
+        self.reply(queue, id, batch, reply);
+    }
+
+    /// Sends the batch's replies if every request in it has been answered.
+    fn finish_batch(&mut self, queue: &mut Queue<Self>, batch: u64) {
+        if self.batches[&batch].pending > 0 {
+            return;
+        }
+        let replies = self.batches.remove(&batch).unwrap().replies;
+        // A batch of notifications gets no reply at all.
+        if !replies.is_empty() {
+            queue.reply(Value::Array(replies).to_string());
+        }
+    }
+
+    /// Handles a single request, which may be part of a batch.
+    fn request(&mut self, queue: &mut Queue<Self>, request: Value, batch: Option<u64>) {
+        let mut request = match request {
+            Value::Object(request) => request,
+            request => {
+                return self.error(
+                    queue,
+                    Some(Value::Null),
+                    batch,
+                    INVALID_REQUEST,
+                    format!("expected a request object, got {}", request),
+                    None,
+                )
+            }
+        };
+        let id = request.remove("id");
+        match id {
+            None | Some(Value::Null) | Some(Value::Number(_)) | Some(Value::String(_)) => {}
+            Some(_) => {
+                return self.error(
+                    queue,
+                    Some(Value::Null),
+                    batch,
+                    INVALID_REQUEST,
+                    "a request's id must be a string, a number, or null".into(),
+                    None,
+                )
+            }
+        }
+        if request.get("jsonrpc") != Some(&json!("2.0")) {
+            return self.error(
+                queue,
+                Some(id.unwrap_or(Value::Null)),
+                batch,
+                INVALID_REQUEST,
+                r#"a request's jsonrpc must be "2.0""#.into(),
+                None,
+            );
+        }
+        let name = match request.remove("method") {
+            Some(Value::String(name)) => name,
+            _ => {
+                return self.error(
+                    queue,
+                    Some(id.unwrap_or(Value::Null)),
+                    batch,
+                    INVALID_REQUEST,
+                    "a request's method must be a string".into(),
+                    None,
+                )
+            }
+        };
+        let method = match Req::METHODS
+            .iter()
+            .find(|method| method.name == name && method.removed.is_none())
+        {
+            Some(method) => method,
+            None => {
+                return self.error(
+                    queue,
+                    id,
+                    batch,
+                    METHOD_NOT_FOUND,
+                    format!("no RPC named `{}`", name),
+                    None,
+                )
+            }
+        };
+        let params = match request.remove("params") {
+            None | Some(Value::Null) => Value::Object(Map::new()),
+            Some(Value::Object(params)) => Value::Object(params),
+            Some(_) => {
+                return self.error(
+                    queue,
+                    id,
+                    batch,
+                    INVALID_PARAMS,
+                    format!(
+                        "the arguments of `{}` must be passed by name, as an object",
+                        method.name
+                    ),
+                    None,
+                )
+            }
+        };
+        let mut message = Map::new();
+        message.insert(method.wire_name.to_string(), params);
+        let message = match serde_json::from_value(Value::Object(message)) {
+            Ok(message) => message,
+            Err(e) => {
+                return self.error(
+                    queue,
+                    id,
+                    batch,
+                    INVALID_PARAMS,
+                    format!("invalid arguments to `{}`: {}", method.name, e),
+                    None,
+                )
+            }
+        };
+
+        let call = id.map(|id| {
+            if let Some(batch) = batch {
+                self.batches.get_mut(&batch).unwrap().pending += 1;
+            }
+            Call {
+                id,
+                method,
+                batch,
+                items: Items::default(),
+            }
+        });
+        queue.request(context::current(), message, call);
+    }
+
+    fn finish_call(&mut self, queue: &mut Queue<Self>, batch: Option<u64>) {
+        if let Some(batch) = batch {
+            self.batches.get_mut(&batch).unwrap().pending -= 1;
+            self.finish_batch(queue, batch);
+        }
+    }
+}
+
+impl<Req, Resp> Protocol for JsonRpc<Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    type Req = Req;
+    type Resp = Resp;
+    type Incoming = String;
+    type Outgoing = String;
+    type Call = Call;
+
+    fn receive(&mut self, frame: String, queue: &mut Queue<Self>) -> io::Result<()> {
+        if frame.trim().is_empty() {
+            return Ok(());
+        }
+        let message = match serde_json::from_str(&frame) {
+            Ok(message) => message,
+            Err(e) => {
+                self.error(
+                    queue,
+                    Some(Value::Null),
+                    None,
+                    PARSE_ERROR,
+                    e.to_string(),
+                    None,
+                );
+                return Ok(());
+            }
+        };
+        match message {
+            Value::Array(ref requests) if requests.is_empty() => self.error(
+                queue,
+                Some(Value::Null),
+                None,
+                INVALID_REQUEST,
+                "a batch must contain at least one request".into(),
+                None,
+            ),
+            Value::Array(requests) => {
+                let batch = self.next_batch_id;
+                self.next_batch_id += 1;
+                self.batches.insert(
+                    batch,
+                    Batch {
+                        pending: 0,
+                        replies: vec![],
+                    },
+                );
+                for request in requests {
+                    self.request(queue, request, Some(batch));
+                }
+                self.finish_batch(queue, batch);
+            }
+            request => self.request(queue, request, None),
+        }
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        call: &mut Call,
+        response: Response<Resp>,
+        queue: &mut Queue<Self>,
+    ) -> io::Result<()> {
+        let method = call.method;
+        let result = match response.message {
+            // Streamed items are sent as `Some(item)`.
+            Ok(item) if response.partial => {
+                call.items.push(method, item);
+                return Ok(());
+            }
+            Ok(_) if method.kind == MethodKind::Streaming => call.items.finish().map(Ok),
+            Ok(output) => {
+                json::output(method, output).and_then(|output| json::result(method, output))
+            }
+            Err(ServerError { kind, detail, .. }) => {
+                let message = detail.unwrap_or_else(|| format!("{:?}", kind));
+                let data = json!({ "kind": format!("{:?}", kind) });
+                let id = Some(call.id.clone());
+                self.error(queue, id, call.batch, SERVER_ERROR, message, Some(data));
+                self.finish_call(queue, call.batch);
+                return Ok(());
+            }
+        };
+        let id = Some(call.id.clone());
+        match result {
+            Ok(Ok(result)) => {
+                let mut reply = Map::new();
+                reply.insert("result".into(), result);
+                self.reply(queue, id, call.batch, reply);
+            }
+            Ok(Err(thrown)) => self.error(
+                queue,
+                id,
+                call.batch,
+                APPLICATION_ERROR,
+                format!("`{}` failed", method.name),
+                Some(thrown),
+            ),
+            Err(e) => self.error(
+                queue,
+                id,
+                call.batch,
+                INTERNAL_ERROR,
+                format!(
+                    "the response to `{}` failed to serialize: {}",
+                    method.name, e
+                ),
+                None,
+            ),
+        }
+        self.finish_call(queue, call.batch);
+        Ok(())
+    }
+}
+
+impl<T, Req, Resp> Stream for Transport<T, Req, Resp>
+where
+    T: Stream<Item = io::Result<String>> + Sink<String, Error = io::Error>,
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    type Item = io::Result<ClientMessage<Req>>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<ClientMessage<Req>>>> {
+        self.inner().poll_next(cx)
+    }
+}
+
+impl<T, Req, Resp> Sink<Response<Resp>> for Transport<T, Req, Resp>
+where
+    T: Sink<String, Error = io::Error>,
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, response: Response<Resp>) -> io::Result<()> {
+        self.inner().start_send(response)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<Req, Resp> fmt::Debug for JsonRpc<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("JsonRpc")
+            .field("batches", &self.batches.len())
+            .finish()
+    }
+}
+
+impl<T, Req, Resp> fmt::Debug for Transport<T, Req, Resp>
+where
+    T: fmt::Debug,
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transport")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<Req, Resp> Transport<Lines<TcpStream>, Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    /// Returns the peer address of the underlying TcpStream.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().get_ref().get_ref().peer_addr()
+    }
+
+    /// Returns the local address of the underlying TcpStream.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().get_ref().get_ref().local_addr()
+    }
+}
+
+impl<S, Req, Resp> From<S> for Transport<Lines<S>, Req, Resp>
+where
+    S: AsyncWrite + AsyncRead,
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    fn from(io: S) -> Self {
+        Transport::new(Compat01As03Sink::new(Framed::new(
+            io,
+            LinesCodec::new_with_max_length(MAX_LINE_LENGTH),
+        )))
+    }
+}
+
+/// Returns a new JSON-RPC transport that reads requests from, and writes replies to, `io`, one
+/// per line.
+pub fn new<Req, Resp>(io: TcpStream) -> Transport<Lines<TcpStream>, Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    Transport::from(io)
+}
+
+/// Listens on `addr`, wrapping accepted connections in JSON-RPC transports.
+pub fn listen<Req, Resp>(addr: &SocketAddr) -> io::Result<Incoming<Req, Resp>> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    Ok(Incoming {
+        incoming: listener.incoming().compat(),
+        local_addr,
+        ghost: PhantomData,
+    })
+}
+
+/// A [`TcpListener`] that wraps connections in JSON-RPC transports.
+#[derive(Debug)]
+pub struct Incoming<Req, Resp> {
+    incoming: Compat01As03<tokio_tcp::Incoming>,
+    local_addr: SocketAddr,
+    ghost: PhantomData<fn(Resp) -> Req>,
+}
+
+impl<Req, Resp> Incoming<Req, Resp> {
+    unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
+
+    /// Returns the address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl<Req, Resp> Stream for Incoming<Req, Resp>
+where
+    Req: Introspect + DeserializeOwned,
+    Resp: Serialize,
+{
+    type Item = io::Result<Transport<Lines<TcpStream>, Req, Resp>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = ready!(self.incoming().poll_next(cx)?);
+        Poll::Ready(next.map(|conn| Ok(new(conn))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        executor::LocalPool,
+        future::{self, Ready},
+        stream::{self, Iter},
+        task::LocalSpawnExt,
+    };
+    use rpc::{
+        server::{BaseChannel, Channel},
+        transport::channel,
+    };
+    use std::ops::RangeInclusive;
+
+    #[tarpc::service(schema = true)]
+    trait Greeter {
+        async fn hello(name: String) -> String;
+        #[throws(String)]
+        async fn divide(dividend: u32, divisor: u32) -> u32;
+        #[stream]
+        async fn count(to: u32) -> u32;
+        #[oneway]
+        async fn log(line: String);
+    }
+
+    #[derive(Clone)]
+    struct GreeterServer;
+
+    impl Greeter for GreeterServer {
+        type HelloFut = Ready<String>;
+
+        fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+            future::ready(format!("Hello, {}!", name))
+        }
+
+        type DivideFut = Ready<Result<u32, String>>;
+
+        fn divide(self, _: context::Context, dividend: u32, divisor: u32) -> Self::DivideFut {
+            future::ready(
+                dividend
+                    .checked_div(divisor)
+                    .ok_or_else(|| "division by zero".to_string()),
+            )
+        }
+
+        type CountStream = Iter<RangeInclusive<u32>>;
+
+        fn count(self, _: context::Context, to: u32) -> Self::CountStream {
+            stream::iter(1..=to)
+        }
+
+        type LogFut = Ready<()>;
+
+        fn log(self, _: context::Context, _: String) -> Self::LogFut {
+            future::ready(())
+        }
+    }
+
+    /// Sends `frames` to a greeter served over JSON-RPC, and returns the first `replies` replies.
+    fn exchange(frames: Vec<String>, replies: usize) -> Vec<Value> {
+        let (mut client, server) = channel::unbounded();
+        let server = Transport::<_, GreeterRequest, GreeterResponse>::new(server);
+        let mut pool = LocalPool::new();
+        pool.spawner()
+            .spawn_local(
+                BaseChannel::with_defaults(server)
+                    .respond_with(GreeterServer.serve())
+                    .try_for_each_concurrent(None, |handler| handler.map(Ok))
+                    .map(|_| ()),
+            )
+            .unwrap();
+        pool.run_until(async move {
+            for frame in frames {
+                client.send(frame).await.unwrap();
+            }
+            let mut received = vec![];
+            for _ in 0..replies {
+                let reply: String = client.next().await.unwrap().unwrap();
+                received.push(serde_json::from_str(&reply).unwrap());
+            }
+            // Every reply expected has arrived, and there are no more.
+            assert!(client.next().now_or_never().is_none());
+            received
+        })
+    }
+
+    #[test]
+    fn serves_every_kind_of_rpc() {
+        let requests = [
+            json!({"jsonrpc": "2.0", "method": "hello", "params": {"name": "Tim"}, "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "log", "params": {"line": "ignored"}}),
+            json!({"jsonrpc": "2.0", "method": "count", "params": {"to": 3}, "id": "c"}),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "divide",
+                "params": {"dividend": 1, "divisor": 0},
+                "id": 2,
+            }),
+        ];
+        let mut replies = exchange(requests.iter().map(Value::to_string).collect(), 3);
+        replies.sort_by_key(|reply| reply["id"].to_string());
+        assert_eq!(
+            replies,
+            vec![
+                json!({"jsonrpc": "2.0", "result": [1, 2, 3], "id": "c"}),
+                json!({"jsonrpc": "2.0", "result": "Hello, Tim!", "id": 1}),
+                json!({
+                    "jsonrpc": "2.0",
+                    "error": {
+                        "code": APPLICATION_ERROR,
+                        "message": "`divide` failed",
+                        "data": "division by zero",
+                    },
+                    "id": 2,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn batches_are_answered_together() {
+        let batch = json!([
+            {"jsonrpc": "2.0", "method": "hello", "params": {"name": "Tim"}, "id": 1},
+            {"jsonrpc": "2.0", "method": "log", "params": {"line": "ignored"}},
+            {"jsonrpc": "2.0", "method": "nope", "id": 2},
+        ]);
+        let notifications = json!([{"jsonrpc": "2.0", "method": "log", "params": {"line": "hi"}}]);
+        let mut replies = exchange(vec![batch.to_string(), notifications.to_string()], 1);
+        let replies = replies.pop().unwrap();
+        let mut replies = replies.as_array().unwrap().clone();
+        replies.sort_by_key(|reply| reply["id"].to_string());
+        assert_eq!(
+            replies,
+            vec![
+                json!({"jsonrpc": "2.0", "result": "Hello, Tim!", "id": 1}),
+                json!({
+                    "jsonrpc": "2.0",
+                    "error": {"code": METHOD_NOT_FOUND, "message": "no RPC named `nope`"},
+                    "id": 2,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_streams_are_rejected() {
+        let to = json::MAX_ITEMS + 1;
+        let request = json!({"jsonrpc": "2.0", "method": "count", "params": {"to": to}, "id": 1});
+        let replies = exchange(vec![request.to_string()], 1);
+        assert_eq!(replies[0]["error"]["code"], json!(INTERNAL_ERROR));
+        assert_eq!(replies[0]["id"], json!(1));
+    }
+
+    #[test]
+    fn malformed_requests_are_rejected() {
+        let requests = vec![
+            "{".to_string(),
+            "[]".to_string(),
+            json!({"method": "hello", "id": 1}).to_string(),
+            json!({"jsonrpc": "2.0", "method": "hello", "params": ["Tim"], "id": 2}).to_string(),
+            json!({"jsonrpc": "2.0", "method": "hello", "params": {"name": 7}, "id": 3})
+                .to_string(),
+            // Notifications get no reply, even if they're invalid.
+            json!({"jsonrpc": "2.0", "method": "nope"}).to_string(),
+        ];
+        let replies = exchange(requests, 5);
+        let errors: Vec<_> = replies
+            .iter()
+            .map(|reply| (reply["error"]["code"].clone(), reply["id"].clone()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (json!(PARSE_ERROR), Value::Null),
+                (json!(INVALID_REQUEST), Value::Null),
+                (json!(INVALID_REQUEST), json!(1)),
+                (json!(INVALID_PARAMS), json!(2)),
+                (json!(INVALID_PARAMS), json!(3)),
+            ]
+        );
+    }
+}
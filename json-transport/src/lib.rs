@@ -5,6 +5,8 @@
 // https://opensource.org/licenses/MIT.
 
 //! A TCP [`Transport`] that serializes as JSON.
+//!
+//! The [`jsonrpc`] module serves the same services to JSON-RPC 2.0 clients.
 
 #![deny(missing_docs)]
 
@@ -24,6 +26,8 @@ use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_serde_json::*;
 use tokio_tcp::{TcpListener, TcpStream};
 
+pub mod jsonrpc;
+
 /// A transport that serializes to, and deserializes from, a [`TcpStream`].
 pub struct Transport<S: AsyncWrite, Item, SinkItem> {
     inner: Compat01As03Sink<
@@ -119,6 +123,10 @@ impl<Item, SinkItem> Transport<TcpStream, Item, SinkItem> {
     }
 }
 
+/// The [codec](rpc::codec::Codec) the transport serializes payloads with, for checking that
+/// payload types [roundtrip](rpc::codec::roundtrip).
+pub use rpc::codec::Json;
+
 /// Returns a new JSON transport that reads from and writes to `io`.
 pub fn new<Item, SinkItem>(io: TcpStream) -> Transport<TcpStream, Item, SinkItem>
 where
@@ -158,14 +166,35 @@ where
     Item: for<'de> Deserialize<'de>,
     SinkItem: Serialize,
 {
-    let listener = TcpListener::bind(addr)?;
-    let local_addr = listener.local_addr()?;
-    let incoming = listener.incoming().compat();
-    Ok(Incoming {
-        incoming,
-        local_addr,
-        ghost: PhantomData,
-    })
+    Incoming::new(TcpListener::bind(addr)?)
+}
+
+/// Listens on `addr` with `shards` sockets bound with `SO_REUSEPORT`, wrapping accepted
+/// connections in JSON transports.
+///
+/// The kernel spreads incoming connections across the sockets, so that each can be driven by its
+/// own task, with its own accept loop and channel filter, rather than funneling every connection
+/// through one, e.g. by [`run_sharded`](rpc::server::shard::run_sharded). Note that per-key channel
+/// limits then apply per shard. If `addr` has port 0, every shard listens on the port chosen for
+/// the first.
+#[cfg(unix)]
+pub fn listen_sharded<Item, SinkItem>(
+    addr: &SocketAddr,
+    shards: usize,
+) -> io::Result<Vec<Incoming<Item, SinkItem>>>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    rpc::server::shard::bind_reuseport(addr, shards)?
+        .into_iter()
+        .map(|listener| {
+            Incoming::new(TcpListener::from_std(
+                listener,
+                &tokio_reactor::Handle::default(),
+            )?)
+        })
+        .collect()
 }
 
 /// A [`TcpListener`] that wraps connections in JSON transports.
@@ -179,6 +208,15 @@ pub struct Incoming<Item, SinkItem> {
 impl<Item, SinkItem> Incoming<Item, SinkItem> {
     unsafe_pinned!(incoming: Compat01As03<tokio_tcp::Incoming>);
 
+    fn new(listener: TcpListener) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        Ok(Incoming {
+            incoming: listener.incoming().compat(),
+            local_addr,
+            ghost: PhantomData,
+        })
+    }
+
     /// Returns the address being listened on.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
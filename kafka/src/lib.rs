@@ -0,0 +1,513 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serves tarpc requests consumed from Kafka topics, producing the responses to the topics the
+//! requests name, so that callers that batch or queue their requests can use the same service
+//! as online callers.
+//!
+//! A [`Transport`] reads requests from a stream of consumed [`Record`]s and writes responses to
+//! a sink of records to produce, so it works with any Kafka client whose consumer and producer
+//! can be adapted to them. It's served like any other transport:
+//!
+//! ```ignore
+//! let transport = kafka::Transport::<_, _, Bincode, WorldRequest, WorldResponse>::new(
+//!     consumer.start().map(|message| Ok(record_from(message?))),
+//!     producer.sink_map_err(to_io_error).with(|record| future::ok(message_from(record))),
+//! );
+//! BaseChannel::with_defaults(transport)
+//!     .respond_with(HelloServer.serve())
+//!     .execute()
+//!     .await;
+//! ```
+//!
+//! A request record's payload is the service's request, e.g. a `WorldRequest`, serialized with
+//! the [`Codec`] `C`, and its headers say how to handle it:
+//!
+//! - [`REPLY_TOPIC`] names the topic to produce the response to. Requests without one are
+//!   handled like one-way requests, and get no response.
+//! - [`CORRELATION_ID`], if set, is copied to the response, so the caller can match them.
+//! - [`DEADLINE`], if set, is the request's deadline, in milliseconds since the Unix epoch.
+//!   Requests without one get the default [context](rpc::context::current)'s deadline, counted
+//!   from when they're consumed.
+//! - Any other header whose value is UTF-8 is added to the request's
+//!   [metadata](rpc::context::Context::metadata).
+//!
+//! A response record's payload is a `Result<Resp, ServerError>`, serialized with `C`, and it has
+//! the request's key, so that it goes to the same partition of the reply topic as the responses
+//! to other requests of that key. A streaming RPC produces a record per item it streams, each
+//! with a [`PARTIAL`] header, followed by one without.
+//!
+//! Records are handed to the server as they're consumed, and responses are produced as the
+//! server sends them, so whether a request is handled at least or at most once depends on when
+//! the consumer commits its offsets.
+
+#![deny(missing_docs, missing_debug_implementations)]
+
+use futures::prelude::*;
+use log::warn;
+use pin_utils::unsafe_pinned;
+use rpc::{
+    codec::Codec,
+    context,
+    transport::adapter::{Adapter, Duplex, Protocol, Queue},
+    ClientMessage, Response, ServerError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+    str,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The header naming the topic a request's response is produced to.
+pub const REPLY_TOPIC: &str = "reply-topic";
+/// The header identifying a request, which is copied to its response.
+pub const CORRELATION_ID: &str = "correlation-id";
+/// The header holding a request's deadline, in milliseconds since the Unix epoch.
+pub const DEADLINE: &str = "deadline";
+/// The header marking the responses that precede a streaming RPC's final response.
+pub const PARTIAL: &str = "partial";
+
+/// A Kafka record, either consumed or to be produced.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// The topic of the record.
+    pub topic: String,
+    /// The record's key, which picks the partition it's produced to.
+    pub key: Option<Vec<u8>>,
+    /// The record's headers, in order. Names can repeat.
+    pub headers: Vec<(String, Vec<u8>)>,
+    /// The record's value.
+    pub payload: Vec<u8>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Record {
+    /// Returns a record without a key or headers.
+    pub fn new(topic: impl Into<String>, payload: Vec<u8>) -> Self {
+        Record {
+            topic: topic.into(),
+            key: None,
+            headers: vec![],
+            payload,
+            _non_exhaustive: (),
+        }
+    }
+
+    /// Returns the value of the last header named `name`, if any.
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .rev()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| &value[..])
+    }
+}
+
+/// A transport that reads requests from consumed records, and writes responses as records to
+/// produce.
+pub struct Transport<Consumer, Producer, C, Req, Resp>
+where
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    inner: Adapter<Duplex<Consumer, Producer>, Kafka<C, Req, Resp>>,
+}
+
+/// Translates between Kafka records and tarpc's messages.
+struct Kafka<C, Req, Resp> {
+    ghost: PhantomData<fn(C, Resp) -> Req>,
+}
+
+/// Where the response to a request is produced.
+struct Call {
+    topic: String,
+    key: Option<Vec<u8>>,
+    correlation_id: Option<Vec<u8>>,
+}
+
+impl Call {
+    fn reply(&self, payload: Vec<u8>, partial: bool) -> Record {
+        let mut reply = Record::new(self.topic.clone(), payload);
+        reply.key = self.key.clone();
+        if let Some(ref correlation_id) = self.correlation_id {
+            reply
+                .headers
+                .push((CORRELATION_ID.into(), correlation_id.clone()));
+        }
+        if partial {
+            reply.headers.push((PARTIAL.into(), b"true".to_vec()));
+        }
+        reply
+    }
+}
+
+impl<Consumer, Producer, C, Req, Resp> Transport<Consumer, Producer, C, Req, Resp>
+where
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    unsafe_pinned!(inner: Adapter<Duplex<Consumer, Producer>, Kafka<C, Req, Resp>>);
+
+    /// Returns a transport that reads requests from the records `consumer` yields, and writes
+    /// responses to `producer`.
+    pub fn new(consumer: Consumer, producer: Producer) -> Self {
+        let protocol = Kafka { ghost: PhantomData };
+        Transport {
+            inner: Adapter::new(Duplex::new(consumer, producer), protocol),
+        }
+    }
+
+    /// Returns the consumer requests are read from.
+    pub fn get_consumer(&self) -> &Consumer {
+        self.inner.get_ref().get_stream()
+    }
+
+    /// Returns the producer responses are written to.
+    pub fn get_producer(&self) -> &Producer {
+        self.inner.get_ref().get_sink()
+    }
+}
+
+impl<C, Req, Resp> Kafka<C, Req, Resp>
+where
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    /// Answers a request the server won't see with an error, unless it's one-way.
+    fn reject(
+        queue: &mut Queue<Self>,
+        call: Option<Call>,
+        topic: &str,
+        kind: io::ErrorKind,
+        detail: String,
+    ) -> io::Result<()> {
+        match call {
+            Some(call) => {
+                let error: Result<Resp, ServerError> = Err(ServerError::new(kind, Some(detail)));
+                queue.reply(call.reply(C::encode(&error)?, false));
+            }
+            None => warn!("Dropping a one-way request from {}: {}", topic, detail),
+        }
+        Ok(())
+    }
+}
+
+impl<C, Req, Resp> Protocol for Kafka<C, Req, Resp>
+where
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    type Req = Req;
+    type Resp = Resp;
+    type Incoming = Record;
+    type Outgoing = Record;
+    type Call = Call;
+
+    fn receive(&mut self, record: Record, queue: &mut Queue<Self>) -> io::Result<()> {
+        let call = match record.header(REPLY_TOPIC).map(str::from_utf8) {
+            Some(Ok(topic)) => Some(Call {
+                topic: topic.to_string(),
+                key: record.key.clone(),
+                correlation_id: record.header(CORRELATION_ID).map(<[u8]>::to_vec),
+            }),
+            Some(Err(e)) => {
+                warn!(
+                    "Dropping a record from {}, whose reply topic is invalid: {}",
+                    record.topic, e
+                );
+                return Ok(());
+            }
+            None => None,
+        };
+        let mut ctx = context::current();
+        if let Some(deadline) = record.header(DEADLINE) {
+            match str::from_utf8(deadline)
+                .ok()
+                .and_then(|deadline| deadline.parse().ok())
+            {
+                Some(millis) => ctx.deadline = UNIX_EPOCH + Duration::from_millis(millis),
+                None => {
+                    return Self::reject(
+                        queue,
+                        call,
+                        &record.topic,
+                        io::ErrorKind::InvalidInput,
+                        format!("the {} header isn't a number of milliseconds", DEADLINE),
+                    )
+                }
+            }
+        }
+        for (name, value) in &record.headers {
+            if [REPLY_TOPIC, CORRELATION_ID, DEADLINE].contains(&&**name) {
+                continue;
+            }
+            if let Ok(value) = str::from_utf8(value) {
+                ctx.metadata.insert(name.clone(), value.to_string());
+            }
+        }
+        let message = match C::decode(&record.payload) {
+            Ok(message) => message,
+            Err(e) => {
+                return Self::reject(
+                    queue,
+                    call,
+                    &record.topic,
+                    io::ErrorKind::InvalidData,
+                    format!("the request is invalid: {}", e),
+                )
+            }
+        };
+        queue.request(ctx, message, call);
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        call: &mut Call,
+        response: Response<Resp>,
+        queue: &mut Queue<Self>,
+    ) -> io::Result<()> {
+        let payload = match C::encode(&response.message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                let error: Result<Resp, ServerError> = Err(ServerError::new(
+                    io::ErrorKind::InvalidData,
+                    Some(format!("the response failed to serialize: {}", e)),
+                ));
+                C::encode(&error)?
+            }
+        };
+        queue.reply(call.reply(payload, response.partial));
+        Ok(())
+    }
+}
+
+impl<Consumer, Producer, C, Req, Resp> Stream for Transport<Consumer, Producer, C, Req, Resp>
+where
+    Consumer: Stream<Item = io::Result<Record>>,
+    Producer: Sink<Record, Error = io::Error>,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    type Item = io::Result<ClientMessage<Req>>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<ClientMessage<Req>>>> {
+        self.inner().poll_next(cx)
+    }
+}
+
+impl<Consumer, Producer, C, Req, Resp> Sink<Response<Resp>>
+    for Transport<Consumer, Producer, C, Req, Resp>
+where
+    Producer: Sink<Record, Error = io::Error>,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, response: Response<Resp>) -> io::Result<()> {
+        self.inner().start_send(response)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<C, Req, Resp> fmt::Debug for Kafka<C, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Kafka").finish()
+    }
+}
+
+impl<Consumer, Producer, C, Req, Resp> fmt::Debug for Transport<Consumer, Producer, C, Req, Resp>
+where
+    Consumer: fmt::Debug,
+    Producer: fmt::Debug,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transport")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+/// Returns the value of the [`DEADLINE`] header for `deadline`, for callers producing requests.
+pub fn deadline_header(deadline: SystemTime) -> Vec<u8> {
+    let millis = deadline
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    millis.to_string().into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        channel::mpsc,
+        future::{self, Ready},
+        stream::Iter,
+    };
+    use rpc::{
+        codec::Json,
+        server::{BaseChannel, Channel},
+    };
+    use std::ops::RangeInclusive;
+
+    #[tarpc::service(derive(Debug, PartialEq))]
+    trait World {
+        async fn hello(name: String) -> String;
+        #[stream]
+        async fn count(to: u32) -> u32;
+    }
+
+    #[derive(Clone)]
+    struct HelloServer;
+
+    impl World for HelloServer {
+        type HelloFut = Ready<String>;
+
+        fn hello(self, ctx: context::Context, name: String) -> Self::HelloFut {
+            let greeting = ctx.metadata.get("greeting").map_or("Hello", |g| &**g);
+            future::ready(format!("{}, {}!", greeting, name))
+        }
+
+        type CountStream = Iter<RangeInclusive<u32>>;
+
+        fn count(self, _: context::Context, to: u32) -> Self::CountStream {
+            stream::iter(1..=to)
+        }
+    }
+
+    /// Serves the world service over in-memory topics, returning the request topic's producer
+    /// and the consumer of every reply topic.
+    fn serve() -> (
+        mpsc::UnboundedSender<Record>,
+        mpsc::UnboundedReceiver<Record>,
+    ) {
+        let (requests, consumer) = mpsc::unbounded();
+        let (producer, replies) = mpsc::unbounded();
+        let transport = Transport::<_, _, Json, WorldRequest, WorldResponse>::new(
+            consumer.map(Ok),
+            producer.sink_map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        );
+        tokio::spawn(
+            BaseChannel::with_defaults(transport)
+                .respond_with(HelloServer.serve())
+                .execute(),
+        );
+        (requests, replies)
+    }
+
+    fn request(request: &WorldRequest, correlation_id: &str) -> Record {
+        let mut record = Record::new("world", Json::encode(request).unwrap());
+        record.key = Some(b"key".to_vec());
+        record.headers = vec![
+            (REPLY_TOPIC.into(), b"replies".to_vec()),
+            (CORRELATION_ID.into(), correlation_id.as_bytes().to_vec()),
+        ];
+        record
+    }
+
+    fn output(reply: &Record) -> Result<WorldResponse, ServerError> {
+        Json::decode(&reply.payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_requests() -> io::Result<()> {
+        let (requests, mut replies) = serve();
+
+        let mut hello = request(&WorldRequest::Hello { name: "Ada".into() }, "1");
+        hello.headers.push(("greeting".into(), b"Hi".to_vec()));
+        hello.headers.push((
+            DEADLINE.into(),
+            deadline_header(context::current().deadline),
+        ));
+        requests.unbounded_send(hello).unwrap();
+        let reply = replies.next().await.unwrap();
+        assert_eq!(reply.topic, "replies");
+        assert_eq!(reply.key, Some(b"key".to_vec()));
+        assert_eq!(reply.header(CORRELATION_ID), Some(&b"1"[..]));
+        assert_eq!(reply.header(PARTIAL), None);
+        assert_eq!(output(&reply), Ok(WorldResponse::Hello("Hi, Ada!".into())));
+
+        // One-way requests get no reply.
+        let mut one_way = request(&WorldRequest::Hello { name: "Bo".into() }, "2");
+        one_way.headers.clear();
+        requests.unbounded_send(one_way).unwrap();
+
+        requests
+            .unbounded_send(request(&WorldRequest::Count { to: 2 }, "3"))
+            .unwrap();
+        let replies: Vec<_> = replies.take(3).collect().await;
+        for reply in &replies {
+            assert_eq!(reply.header(CORRELATION_ID), Some(&b"3"[..]));
+        }
+        let partial: Vec<_> = replies
+            .iter()
+            .map(|r| r.header(PARTIAL).is_some())
+            .collect();
+        assert_eq!(partial, [true, true, false]);
+        let outputs: Vec<_> = replies.iter().map(output).collect();
+        assert_eq!(
+            outputs,
+            [
+                Ok(WorldResponse::Count(Some(1))),
+                Ok(WorldResponse::Count(Some(2))),
+                Ok(WorldResponse::Count(None)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_requests() -> io::Result<()> {
+        let (requests, mut replies) = serve();
+
+        let mut invalid = request(&WorldRequest::Count { to: 1 }, "1");
+        invalid.payload = b"{}".to_vec();
+        requests.unbounded_send(invalid).unwrap();
+        let reply = replies.next().await.unwrap();
+        assert_eq!(reply.header(CORRELATION_ID), Some(&b"1"[..]));
+        assert_eq!(output(&reply).unwrap_err().kind, io::ErrorKind::InvalidData);
+
+        let mut late = request(&WorldRequest::Hello { name: "Ada".into() }, "2");
+        late.headers.push((DEADLINE.into(), b"soon".to_vec()));
+        requests.unbounded_send(late).unwrap();
+        let reply = replies.next().await.unwrap();
+        assert_eq!(
+            output(&reply).unwrap_err().kind,
+            io::ErrorKind::InvalidInput
+        );
+        Ok(())
+    }
+}
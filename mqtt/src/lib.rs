@@ -0,0 +1,470 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serves tarpc requests published to MQTT topics, publishing the responses to the topics the
+//! requests name, so that devices behind an MQTT broker can call tarpc services.
+//!
+//! A [`Transport`] reads requests from a stream of the messages [`Publish`]ed to the topics the
+//! server subscribes to, and writes responses to a sink of messages to publish, so it works with
+//! any MQTT client whose subscription and publisher can be adapted to them. It's served like
+//! any other transport:
+//!
+//! ```ignore
+//! client.subscribe("devices/+/world", QoS::AtLeastOnce).await?;
+//! let transport = mqtt::Transport::<_, _, Bincode, WorldRequest, WorldResponse>::new(
+//!     notifications.filter_map(|packet| future::ready(publish_from(packet))),
+//!     client.sink_map_err(to_io_error).with(|publish| future::ok(request_from(publish))),
+//! );
+//! BaseChannel::with_defaults(transport)
+//!     .respond_with(HelloServer.serve())
+//!     .execute()
+//!     .await;
+//! ```
+//!
+//! A request's payload is the service's request, e.g. a `WorldRequest`, serialized with the
+//! [`Codec`] `C`. The rest of the request is given by the properties MQTT 5 defines for
+//! request/response:
+//!
+//! - The response topic names the topic to publish the response to. Requests without one are
+//!   handled like one-way requests, and get no response.
+//! - The correlation data, if set, is copied to the response, so the device can match them.
+//! - The message expiry interval, if set, is the time the request has to complete, from when
+//!   it's received. Requests without one get the default [context](rpc::context::current)'s
+//!   deadline.
+//! - The user properties are added to the request's [metadata](rpc::context::Context::metadata).
+//!
+//! A response's payload is a `Result<Resp, ServerError>`, serialized with `C`. It's published
+//! with the request's QoS, and, if the request had an expiry interval, expires when the request's
+//! deadline passes. A streaming RPC publishes a response per item it streams, each with a
+//! [`PARTIAL`] user property, followed by one without.
+//!
+//! MQTT 3.1.1 messages have no properties, so devices that speak it can only make one-way
+//! requests, unless the client adapter derives the properties from the topic, e.g. by taking
+//! the response topic and correlation data from the topic's last levels.
+
+#![deny(missing_docs, missing_debug_implementations)]
+
+use futures::prelude::*;
+use log::warn;
+use pin_utils::unsafe_pinned;
+use rpc::{
+    codec::Codec,
+    context,
+    transport::adapter::{Adapter, Duplex, Protocol, Queue},
+    ClientMessage, Response, ServerError,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+/// The user property marking the responses that precede a streaming RPC's final response.
+pub const PARTIAL: &str = "partial";
+
+/// The delivery guarantee of a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum QoS {
+    /// The message is delivered at most once.
+    AtMostOnce = 0,
+    /// The message is delivered at least once.
+    AtLeastOnce = 1,
+    /// The message is delivered exactly once.
+    ExactlyOnce = 2,
+}
+
+/// An MQTT application message, either received or to be published.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publish {
+    /// The topic the message is published to.
+    pub topic: String,
+    /// The message's delivery guarantee.
+    pub qos: QoS,
+    /// The message's payload.
+    pub payload: Vec<u8>,
+    /// The topic to publish the response to, if the message is a request.
+    pub response_topic: Option<String>,
+    /// Identifies the request the message is, or responds to.
+    pub correlation_data: Option<Vec<u8>>,
+    /// How long the broker keeps the message for subscribers it hasn't yet delivered it to.
+    pub message_expiry_interval: Option<Duration>,
+    /// The message's user properties, in order. Names can repeat.
+    pub user_properties: Vec<(String, String)>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Publish {
+    /// Returns an at-most-once message without properties.
+    pub fn new(topic: impl Into<String>, payload: Vec<u8>) -> Self {
+        Publish {
+            topic: topic.into(),
+            qos: QoS::AtMostOnce,
+            payload,
+            response_topic: None,
+            correlation_data: None,
+            message_expiry_interval: None,
+            user_properties: vec![],
+            _non_exhaustive: (),
+        }
+    }
+
+    /// Returns the value of the last user property named `name`, if any.
+    pub fn user_property(&self, name: &str) -> Option<&str> {
+        self.user_properties
+            .iter()
+            .rev()
+            .find(|(property, _)| property == name)
+            .map(|(_, value)| &value[..])
+    }
+}
+
+/// A transport that reads requests from received messages, and writes responses as messages to
+/// publish.
+pub struct Transport<Incoming, Outgoing, C, Req, Resp>
+where
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    inner: Adapter<Duplex<Incoming, Outgoing>, Mqtt<C, Req, Resp>>,
+}
+
+/// Translates between MQTT messages and tarpc's.
+struct Mqtt<C, Req, Resp> {
+    ghost: PhantomData<fn(C, Resp) -> Req>,
+}
+
+/// Where the response to a request is published.
+struct Call {
+    topic: String,
+    qos: QoS,
+    correlation_data: Option<Vec<u8>>,
+    /// The request's deadline, if the request expires.
+    expires: Option<SystemTime>,
+}
+
+impl Call {
+    fn reply(&self, payload: Vec<u8>, partial: bool) -> Publish {
+        let mut reply = Publish::new(self.topic.clone(), payload);
+        reply.qos = self.qos;
+        reply.correlation_data = self.correlation_data.clone();
+        if let Some(expires) = self.expires {
+            // A reply that's too late expires immediately.
+            reply.message_expiry_interval = Some(
+                expires
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default(),
+            );
+        }
+        if partial {
+            reply.user_properties.push((PARTIAL.into(), "true".into()));
+        }
+        reply
+    }
+}
+
+impl<Incoming, Outgoing, C, Req, Resp> Transport<Incoming, Outgoing, C, Req, Resp>
+where
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    unsafe_pinned!(inner: Adapter<Duplex<Incoming, Outgoing>, Mqtt<C, Req, Resp>>);
+
+    /// Returns a transport that reads requests from the messages `incoming` yields, and
+    /// publishes responses to `outgoing`.
+    pub fn new(incoming: Incoming, outgoing: Outgoing) -> Self {
+        let protocol = Mqtt { ghost: PhantomData };
+        Transport {
+            inner: Adapter::new(Duplex::new(incoming, outgoing), protocol),
+        }
+    }
+
+    /// Returns the stream requests are read from.
+    pub fn get_incoming(&self) -> &Incoming {
+        self.inner.get_ref().get_stream()
+    }
+
+    /// Returns the sink responses are written to.
+    pub fn get_outgoing(&self) -> &Outgoing {
+        self.inner.get_ref().get_sink()
+    }
+}
+
+impl<C, Req, Resp> Protocol for Mqtt<C, Req, Resp>
+where
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    type Req = Req;
+    type Resp = Resp;
+    type Incoming = Publish;
+    type Outgoing = Publish;
+    type Call = Call;
+
+    fn receive(&mut self, publish: Publish, queue: &mut Queue<Self>) -> io::Result<()> {
+        let Publish {
+            topic,
+            qos,
+            payload,
+            response_topic,
+            correlation_data,
+            message_expiry_interval,
+            user_properties,
+            ..
+        } = publish;
+        let mut ctx = context::current();
+        if let Some(interval) = message_expiry_interval {
+            ctx.deadline = SystemTime::now() + interval;
+        }
+        ctx.metadata.extend(user_properties);
+        let call = response_topic.map(|response_topic| Call {
+            topic: response_topic,
+            qos,
+            correlation_data,
+            expires: message_expiry_interval.map(|_| ctx.deadline),
+        });
+        let message = match C::decode(&payload) {
+            Ok(message) => message,
+            Err(e) => {
+                let detail = format!("the request is invalid: {}", e);
+                match call {
+                    Some(call) => {
+                        let error: Result<Resp, ServerError> =
+                            Err(ServerError::new(io::ErrorKind::InvalidData, Some(detail)));
+                        queue.reply(call.reply(C::encode(&error)?, false));
+                    }
+                    None => warn!("Dropping a one-way request from {}: {}", topic, detail),
+                }
+                return Ok(());
+            }
+        };
+        queue.request(ctx, message, call);
+        Ok(())
+    }
+
+    fn respond(
+        &mut self,
+        call: &mut Call,
+        response: Response<Resp>,
+        queue: &mut Queue<Self>,
+    ) -> io::Result<()> {
+        let payload = match C::encode(&response.message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                let error: Result<Resp, ServerError> = Err(ServerError::new(
+                    io::ErrorKind::InvalidData,
+                    Some(format!("the response failed to serialize: {}", e)),
+                ));
+                C::encode(&error)?
+            }
+        };
+        queue.reply(call.reply(payload, response.partial));
+        Ok(())
+    }
+}
+
+impl<Incoming, Outgoing, C, Req, Resp> Stream for Transport<Incoming, Outgoing, C, Req, Resp>
+where
+    Incoming: Stream<Item = io::Result<Publish>>,
+    Outgoing: Sink<Publish, Error = io::Error>,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    type Item = io::Result<ClientMessage<Req>>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<ClientMessage<Req>>>> {
+        self.inner().poll_next(cx)
+    }
+}
+
+impl<Incoming, Outgoing, C, Req, Resp> Sink<Response<Resp>>
+    for Transport<Incoming, Outgoing, C, Req, Resp>
+where
+    Outgoing: Sink<Publish, Error = io::Error>,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, response: Response<Resp>) -> io::Result<()> {
+        self.inner().start_send(response)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<C, Req, Resp> fmt::Debug for Mqtt<C, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mqtt").finish()
+    }
+}
+
+impl<Incoming, Outgoing, C, Req, Resp> fmt::Debug for Transport<Incoming, Outgoing, C, Req, Resp>
+where
+    Incoming: fmt::Debug,
+    Outgoing: fmt::Debug,
+    C: Codec,
+    Req: DeserializeOwned,
+    Resp: Serialize,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Transport")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        channel::mpsc,
+        future::{self, Ready},
+        stream::Iter,
+    };
+    use rpc::{
+        codec::Json,
+        server::{BaseChannel, Channel},
+    };
+    use std::ops::RangeInclusive;
+
+    #[tarpc::service(derive(Debug, PartialEq))]
+    trait World {
+        async fn hello(name: String) -> String;
+        #[stream]
+        async fn count(to: u32) -> u32;
+    }
+
+    #[derive(Clone)]
+    struct HelloServer;
+
+    impl World for HelloServer {
+        type HelloFut = Ready<String>;
+
+        fn hello(self, ctx: context::Context, name: String) -> Self::HelloFut {
+            let greeting = ctx.metadata.get("greeting").map_or("Hello", |g| &**g);
+            future::ready(format!("{}, {}!", greeting, name))
+        }
+
+        type CountStream = Iter<RangeInclusive<u32>>;
+
+        fn count(self, _: context::Context, to: u32) -> Self::CountStream {
+            stream::iter(1..=to)
+        }
+    }
+
+    /// Serves the world service over an in-memory broker, returning the sender of requests and
+    /// the receiver of responses.
+    fn serve() -> (
+        mpsc::UnboundedSender<Publish>,
+        mpsc::UnboundedReceiver<Publish>,
+    ) {
+        let (requests, incoming) = mpsc::unbounded();
+        let (outgoing, replies) = mpsc::unbounded();
+        let transport = Transport::<_, _, Json, WorldRequest, WorldResponse>::new(
+            incoming.map(Ok),
+            outgoing.sink_map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        );
+        tokio::spawn(
+            BaseChannel::with_defaults(transport)
+                .respond_with(HelloServer.serve())
+                .execute(),
+        );
+        (requests, replies)
+    }
+
+    fn request(request: &WorldRequest, correlation_data: &str) -> Publish {
+        let mut publish = Publish::new("devices/7/world", Json::encode(request).unwrap());
+        publish.qos = QoS::AtLeastOnce;
+        publish.response_topic = Some("devices/7/world/replies".into());
+        publish.correlation_data = Some(correlation_data.as_bytes().to_vec());
+        publish
+    }
+
+    fn output(reply: &Publish) -> Result<WorldResponse, ServerError> {
+        Json::decode(&reply.payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_requests() -> io::Result<()> {
+        let (requests, mut replies) = serve();
+
+        let mut hello = request(&WorldRequest::Hello { name: "Ada".into() }, "1");
+        hello.user_properties.push(("greeting".into(), "Hi".into()));
+        hello.message_expiry_interval = Some(Duration::from_secs(30));
+        requests.unbounded_send(hello).unwrap();
+        let reply = replies.next().await.unwrap();
+        assert_eq!(reply.topic, "devices/7/world/replies");
+        assert_eq!(reply.qos, QoS::AtLeastOnce);
+        assert_eq!(reply.correlation_data, Some(b"1".to_vec()));
+        assert!(reply.message_expiry_interval.unwrap() <= Duration::from_secs(30));
+        assert_eq!(reply.user_property(PARTIAL), None);
+        assert_eq!(output(&reply), Ok(WorldResponse::Hello("Hi, Ada!".into())));
+
+        // One-way requests get no reply.
+        let mut one_way = request(&WorldRequest::Hello { name: "Bo".into() }, "2");
+        one_way.response_topic = None;
+        requests.unbounded_send(one_way).unwrap();
+
+        requests
+            .unbounded_send(request(&WorldRequest::Count { to: 2 }, "3"))
+            .unwrap();
+        let replies: Vec<_> = replies.take(3).collect().await;
+        for reply in &replies {
+            assert_eq!(reply.correlation_data, Some(b"3".to_vec()));
+            assert_eq!(reply.message_expiry_interval, None);
+        }
+        let partial: Vec<_> = replies
+            .iter()
+            .map(|r| r.user_property(PARTIAL).is_some())
+            .collect();
+        assert_eq!(partial, [true, true, false]);
+        let outputs: Vec<_> = replies.iter().map(output).collect();
+        assert_eq!(
+            outputs,
+            [
+                Ok(WorldResponse::Count(Some(1))),
+                Ok(WorldResponse::Count(Some(2))),
+                Ok(WorldResponse::Count(None)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_requests() -> io::Result<()> {
+        let (requests, mut replies) = serve();
+
+        let mut invalid = request(&WorldRequest::Count { to: 1 }, "1");
+        invalid.payload = b"{}".to_vec();
+        requests.unbounded_send(invalid).unwrap();
+        let reply = replies.next().await.unwrap();
+        assert_eq!(reply.correlation_data, Some(b"1".to_vec()));
+        assert_eq!(output(&reply).unwrap_err().kind, io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
@@ -12,19 +12,25 @@ extern crate quote;
 extern crate syn;
 
 use proc_macro::TokenStream;
-use proc_macro2::TokenStream as TokenStream2;
+use proc_macro2::{Span, TokenStream as TokenStream2};
 use quote::quote;
 use syn::{
     braced, parenthesized,
     parse::{Parse, ParseStream},
-    parse_macro_input,
+    parse_macro_input, parse_quote,
     punctuated::Punctuated,
     spanned::Spanned,
     token::Comma,
-    ArgCaptured, Attribute, FnArg, Ident, Lit, LitBool, MetaNameValue, Pat, ReturnType, Token,
+    ArgCaptured, Attribute, FnArg, GenericArgument, Ident, ImplItem, Item, ItemImpl, Lit, LitBool,
+    LitInt, LitStr, Meta, MetaNameValue, Pat, Path, PathArguments, ReturnType, Token, Type,
     Visibility,
 };
 
+use std::collections::{HashMap, HashSet};
+
+/// Explicit RPC ids must be less than this, to keep the generated enums reasonably small.
+const MAX_RPC_ID: u64 = 1024;
+
 struct Service {
     attrs: Vec<Attribute>,
     vis: Visibility,
@@ -36,7 +42,103 @@ struct RpcMethod {
     attrs: Vec<Attribute>,
     ident: Ident,
     args: Punctuated<ArgCaptured, Comma>,
+    /// For streaming RPCs, the type of each streamed item.
     output: ReturnType,
+    /// Set by `#[oneway]`: the client doesn't wait for, and the server doesn't send, a response.
+    one_way: bool,
+    /// Set by `#[stream]`: the server responds with any number of outputs.
+    stream: bool,
+    /// Set by `#[run_inline]`: the server runs the handler on the channel's task instead of
+    /// spawning it.
+    inline: bool,
+    /// Set by `#[id = N]`: the position of the method's variants in the request and response
+    /// enums, which is how positional formats like bincode identify the method.
+    id: Option<LitInt>,
+    /// Set by `#[name = "..."]`: the name of the method's variants on the wire, for formats that
+    /// identify the method by name, like JSON.
+    wire_name: Option<LitStr>,
+    /// Set by `#[throws(E)]`: the service responds with `Result<output, E>`, and the client stub
+    /// separates errors returned by the service from errors of the RPC itself.
+    throws: Option<Type>,
+    /// The args marked `#[redact]`, whose values are hidden from the request's `Debug` output.
+    redacted: HashSet<Ident>,
+    /// Set by `#[since(N)]`: the first version of the service with the method. Defaults to 1.
+    since: Option<LitInt>,
+    /// Set by `#[removed(N)]`: the first version of the service without the method.
+    removed: Option<LitInt>,
+}
+
+impl RpcMethod {
+    fn wire_name(&self) -> String {
+        match self.wire_name {
+            Some(ref wire_name) => wire_name.value(),
+            None => snake_to_camel(&self.ident.to_string()),
+        }
+    }
+}
+
+/// The item the `service` attribute is attached to: a service trait, or a module of related
+/// service traits and the types they share.
+enum ServiceInput {
+    Service(Service),
+    Module {
+        attrs: Vec<Attribute>,
+        vis: Visibility,
+        ident: Ident,
+        items: Vec<ModuleItem>,
+    },
+}
+
+enum ModuleItem {
+    Service(Service),
+    Item(Item),
+}
+
+/// Returns whether the next item in `input` is a trait.
+fn peek_trait(input: ParseStream) -> bool {
+    let fork = input.fork();
+    fork.call(Attribute::parse_outer).is_ok()
+        && fork.parse::<Visibility>().is_ok()
+        && fork.peek(Token![trait])
+}
+
+impl Parse for ServiceInput {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if peek_trait(input) {
+            return Ok(ServiceInput::Service(input.parse()?));
+        }
+        let attrs = input.call(Attribute::parse_outer)?;
+        let vis = input.parse()?;
+        let mod_token = input.parse::<Token![mod]>().map_err(|e| {
+            syn::Error::new(e.span(), "expected a service trait or a module of services")
+        })?;
+        let ident = input.parse()?;
+        let content;
+        braced!(content in input);
+        let mut items = vec![];
+        while !content.is_empty() {
+            if peek_trait(&content) {
+                items.push(ModuleItem::Service(content.parse()?));
+            } else {
+                items.push(ModuleItem::Item(content.parse()?));
+            }
+        }
+        if !items.iter().any(|item| match item {
+            ModuleItem::Service(_) => true,
+            ModuleItem::Item(_) => false,
+        }) {
+            return Err(syn::Error::new(
+                mod_token.span,
+                "module does not contain any service traits",
+            ));
+        }
+        Ok(ServiceInput::Module {
+            attrs,
+            vis,
+            ident,
+            items,
+        })
+    }
 }
 
 impl Parse for Service {
@@ -52,12 +154,12 @@ impl Parse for Service {
             rpcs.push(content.parse()?);
         }
         for rpc in &rpcs {
-            if rpc.ident == "new" {
+            if rpc.ident == "new" || rpc.ident == "from" {
                 return Err(syn::Error::new(
                     rpc.ident.span(),
                     format!(
-                        "method name conflicts with generated fn `{}Client::new`",
-                        ident
+                        "method name conflicts with generated fn `{}Client::{}`",
+                        ident, rpc.ident
                     ),
                 ));
             }
@@ -68,6 +170,55 @@ impl Parse for Service {
                 ));
             }
         }
+        // Each rpc generates request and response variants and a trait associated type named
+        // after the camel case of its name.
+        let mut camel_case_names = HashMap::new();
+        for rpc in &rpcs {
+            let camel_case_name = snake_to_camel(&rpc.ident.to_string());
+            if let Some(other) = camel_case_names.insert(camel_case_name.clone(), &rpc.ident) {
+                let message = if *other == rpc.ident {
+                    format!("duplicate method `{}`", rpc.ident)
+                } else {
+                    format!(
+                        "method `{}` conflicts with method `{}`: both generate the request \
+                         variant `{}`",
+                        rpc.ident, other, camel_case_name
+                    )
+                };
+                return Err(syn::Error::new(rpc.ident.span(), message));
+            }
+        }
+        if rpcs.iter().any(|rpc| rpc.id.is_some()) {
+            if let Some(rpc) = rpcs.iter().find(|rpc| rpc.id.is_none()) {
+                return Err(syn::Error::new(
+                    rpc.ident.span(),
+                    "if any method has an `#[id]`, all methods must",
+                ));
+            }
+        }
+        let mut ids = HashSet::new();
+        let mut wire_names = HashSet::new();
+        for rpc in &rpcs {
+            if let Some(ref id) = rpc.id {
+                if !ids.insert(id.value()) {
+                    return Err(syn::Error::new(
+                        id.span(),
+                        format!("duplicate method id {}", id.value()),
+                    ));
+                }
+            }
+            let wire_name = rpc.wire_name();
+            if !wire_names.insert(wire_name.clone()) {
+                let span = match rpc.wire_name {
+                    Some(ref lit) => lit.span(),
+                    None => rpc.ident.span(),
+                };
+                return Err(syn::Error::new(
+                    span,
+                    format!("duplicate method wire name `{}`", wire_name),
+                ));
+            }
+        }
         Ok(Service {
             attrs,
             vis,
@@ -79,14 +230,88 @@ impl Parse for Service {
 
 impl Parse for RpcMethod {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        let attrs = input.call(Attribute::parse_outer)?;
+        let mut attrs = input.call(Attribute::parse_outer)?;
+        let one_way = take_flag(&mut attrs, "oneway")?;
+        let stream = take_flag(&mut attrs, "stream")?;
+        let inline = take_flag(&mut attrs, "run_inline")?;
+        let throws = take_arg::<Type>(&mut attrs, "throws", "Type")?;
+        let since = take_arg::<LitInt>(&mut attrs, "since", "version")?;
+        let removed = take_arg::<LitInt>(&mut attrs, "removed", "version")?;
+        if let Some(ref since) = since {
+            if since.value() == 0 {
+                return Err(syn::Error::new(since.span(), "versions start at 1"));
+            }
+        }
+        if let Some(ref removed) = removed {
+            if removed.value() <= since.as_ref().map_or(1, |since| since.value()) {
+                return Err(syn::Error::new(
+                    removed.span(),
+                    "a method must be removed in a later version than it was added",
+                ));
+            }
+        }
+        let id = match take_name_value(&mut attrs, "id")? {
+            None => None,
+            Some(Lit::Int(id)) => {
+                if id.value() >= MAX_RPC_ID {
+                    return Err(syn::Error::new(
+                        id.span(),
+                        format!("method ids must be less than {}", MAX_RPC_ID),
+                    ));
+                }
+                Some(id)
+            }
+            Some(lit) => {
+                return Err(syn::Error::new(
+                    lit.span(),
+                    "`#[id]` expects an integer literal",
+                ))
+            }
+        };
+        let wire_name = match take_name_value(&mut attrs, "name")? {
+            None => None,
+            Some(Lit::Str(name)) => Some(name),
+            Some(lit) => {
+                return Err(syn::Error::new(
+                    lit.span(),
+                    "`#[name]` expects a string literal",
+                ))
+            }
+        };
         input.parse::<Token![async]>()?;
         input.parse::<Token![fn]>()?;
-        let ident = input.parse()?;
+        let ident: Ident = input.parse()?;
         let content;
         parenthesized!(content in input);
-        let args: Punctuated<FnArg, Comma> = content.parse_terminated(FnArg::parse)?;
-        let args = args
+        // Args can be marked `#[redact]`, which syn doesn't parse as part of the arg.
+        let mut redacted = HashSet::new();
+        let mut fn_args = Punctuated::<FnArg, Comma>::new();
+        while !content.is_empty() {
+            let mut attrs = content.call(Attribute::parse_outer)?;
+            let redact = take_flag(&mut attrs, "redact")?;
+            if let Some(attr) = attrs.first() {
+                return Err(syn::Error::new(
+                    attr.span(),
+                    "only `#[redact]` is supported on RPC args",
+                ));
+            }
+            let arg: FnArg = content.parse()?;
+            if redact {
+                if let FnArg::Captured(ArgCaptured {
+                    pat: Pat::Ident(ref pat),
+                    ..
+                }) = arg
+                {
+                    redacted.insert(pat.ident.clone());
+                }
+            }
+            fn_args.push_value(arg);
+            if content.is_empty() {
+                break;
+            }
+            fn_args.push_punct(content.parse()?);
+        }
+        let args: Punctuated<ArgCaptured, Comma> = fn_args
             .into_iter()
             .map(|arg| match arg {
                 FnArg::Captured(captured) => match captured.pat {
@@ -110,52 +335,498 @@ impl Parse for RpcMethod {
                 )),
             })
             .collect::<Result<_, _>>()?;
-        let output = input.parse()?;
+        let output: ReturnType = input.parse()?;
         input.parse::<Token![;]>()?;
 
+        if ident.to_string().starts_with("r#") {
+            return Err(syn::Error::new(
+                ident.span(),
+                "raw identifiers aren't supported as method names",
+            ));
+        }
+        let mut arg_names = HashSet::new();
+        for arg in &args {
+            let arg_name = match arg.pat {
+                Pat::Ident(ref pat) => &pat.ident,
+                _ => unreachable!(),
+            };
+            // The generated fns have parameters with these names.
+            if arg_name == "ctx" || arg_name == "context" || arg_name == "sink" {
+                return Err(syn::Error::new(
+                    arg_name.span(),
+                    format!("`{}` is reserved for use by generated code", arg_name),
+                ));
+            }
+            if !arg_names.insert(arg_name.to_string()) {
+                return Err(syn::Error::new(
+                    arg_name.span(),
+                    format!("duplicate argument `{}`", arg_name),
+                ));
+            }
+            if let Some((span, message)) = unsupported_type(&arg.ty) {
+                return Err(syn::Error::new(span, message));
+            }
+        }
+        if let ReturnType::Type(_, ref ty) = output {
+            if let Some((span, message)) = unsupported_type(ty) {
+                return Err(syn::Error::new(span, message));
+            }
+        }
+
+        if one_way && stream {
+            return Err(syn::Error::new(
+                ident.span(),
+                "one-way RPCs cannot stream responses",
+            ));
+        }
+        if let Some(ref throws) = throws {
+            if one_way || stream {
+                return Err(syn::Error::new(
+                    throws.span(),
+                    "`#[throws]` cannot be combined with `#[oneway]` or `#[stream]`",
+                ));
+            }
+        }
+        if one_way {
+            match output {
+                ReturnType::Default => {}
+                ReturnType::Type(_, ref ty) if is_unit(ty) => {}
+                ReturnType::Type(_, ref ty) => {
+                    return Err(syn::Error::new(
+                        ty.span(),
+                        "one-way RPCs cannot return a value",
+                    ))
+                }
+            }
+        }
+
         Ok(RpcMethod {
             attrs,
             ident,
             args,
             output,
+            one_way,
+            stream,
+            inline,
+            id,
+            wire_name,
+            throws,
+            redacted,
+            since,
+            removed,
         })
     }
 }
 
-// If `derive_serde` meta item is not present, defaults to cfg!(feature = "serde1").
-// `derive_serde` can only be true when serde1 is enabled.
-struct DeriveSerde(bool);
+/// Removes the attribute `#[name = lit]` from `attrs`, returning its value if it was present.
+fn take_name_value(attrs: &mut Vec<Attribute>, name: &str) -> syn::Result<Option<Lit>> {
+    let mut found = None;
+    let mut result = Ok(());
+    attrs.retain(|attr| {
+        if !attr.path.is_ident(name) {
+            return true;
+        }
+        match attr.parse_meta() {
+            _ if found.is_some() => {
+                result = Err(syn::Error::new(
+                    attr.path.span(),
+                    format!("duplicate `#[{}]` attribute", name),
+                ));
+            }
+            Ok(Meta::NameValue(MetaNameValue { lit, .. })) => found = Some(lit),
+            _ => {
+                result = Err(syn::Error::new(
+                    attr.span(),
+                    format!("expected `#[{} = ...]`", name),
+                ));
+            }
+        }
+        false
+    });
+    result.map(|()| found)
+}
+
+/// Removes the attribute `#[name(arg)]` from `attrs`, returning its arg if it was present.
+/// `expected` describes the arg in errors.
+fn take_arg<T: Parse>(
+    attrs: &mut Vec<Attribute>,
+    name: &str,
+    expected: &str,
+) -> syn::Result<Option<T>> {
+    let mut found = None;
+    let mut result = Ok(());
+    attrs.retain(|attr| {
+        if !attr.path.is_ident(name) {
+            return true;
+        }
+        let parse_arg = |input: ParseStream| {
+            let content;
+            parenthesized!(content in input);
+            content.parse::<T>()
+        };
+        match syn::parse::Parser::parse2(parse_arg, attr.tts.clone()) {
+            _ if found.is_some() => {
+                result = Err(syn::Error::new(
+                    attr.path.span(),
+                    format!("duplicate `#[{}]` attribute", name),
+                ));
+            }
+            Ok(arg) => found = Some(arg),
+            Err(_) => {
+                result = Err(syn::Error::new(
+                    attr.span(),
+                    format!("expected `#[{}({})]`", name, expected),
+                ));
+            }
+        }
+        false
+    });
+    result.map(|()| found)
+}
+
+/// Removes the marker attribute `#[name]` from `attrs`, returning whether it was present.
+fn take_flag(attrs: &mut Vec<Attribute>, name: &str) -> syn::Result<bool> {
+    let mut found = false;
+    let mut result = Ok(());
+    attrs.retain(|attr| {
+        if !attr.path.is_ident(name) {
+            return true;
+        }
+        if !attr.tts.is_empty() {
+            result = Err(syn::Error::new(
+                attr.tts.span(),
+                format!("`#[{}]` does not take any arguments", name),
+            ));
+        } else if found {
+            result = Err(syn::Error::new(
+                attr.path.span(),
+                format!("duplicate `#[{}]` attribute", name),
+            ));
+        }
+        found = true;
+        false
+    });
+    result.map(|()| found)
+}
+
+/// Returns the span of, and an error describing, the first part of `ty` that can't be a field of
+/// the generated request and response enums.
+fn unsupported_type(ty: &Type) -> Option<(Span, &'static str)> {
+    match ty {
+        Type::ImplTrait(ty) => Some((ty.span(), "`impl Trait` isn't supported in RPC types")),
+        Type::Infer(ty) => Some((ty.span(), "RPC types must be explicit")),
+        Type::Reference(reference) => match reference.lifetime {
+            Some(ref lifetime) if lifetime.ident == "static" => unsupported_type(&reference.elem),
+            _ => Some((
+                reference.span(),
+                "references in RPC types must have the `'static` lifetime",
+            )),
+        },
+        Type::Slice(slice) => unsupported_type(&slice.elem),
+        Type::Array(array) => unsupported_type(&array.elem),
+        Type::Paren(paren) => unsupported_type(&paren.elem),
+        Type::Group(group) => unsupported_type(&group.elem),
+        Type::Tuple(tuple) => tuple.elems.iter().find_map(unsupported_type),
+        Type::Path(path) => {
+            if path.qself.is_some() || path.path.segments.first().unwrap().value().ident == "Self" {
+                return Some((path.span(), "`Self` isn't supported in RPC types"));
+            }
+            path.path
+                .segments
+                .iter()
+                .filter_map(|segment| match segment.arguments {
+                    PathArguments::AngleBracketed(ref args) => Some(args),
+                    _ => None,
+                })
+                .flat_map(|args| args.args.iter())
+                .find_map(|arg| match arg {
+                    GenericArgument::Type(ty) => unsupported_type(ty),
+                    GenericArgument::Lifetime(lifetime) if lifetime.ident != "static" => {
+                        Some((lifetime.span(), "lifetimes in RPC types must be `'static`"))
+                    }
+                    _ => None,
+                })
+        }
+        _ => None,
+    }
+}
+
+fn is_unit(ty: &Type) -> bool {
+    match ty {
+        Type::Tuple(tuple) => tuple.elems.is_empty(),
+        Type::Paren(paren) => is_unit(&paren.elem),
+        _ => false,
+    }
+}
+
+/// Options passed to the macro, e.g. `#[tarpc::service(derive_serde = false, schema = true)]`.
+struct Options {
+    // If `derive_serde` meta item is not present, defaults to cfg!(feature = "serde1").
+    // `derive_serde` can only be true when serde1 is enabled.
+    derive_serde: bool,
+    /// Whether to emit a const describing the service.
+    schema: bool,
+    /// Whether to emit a mock client for tests.
+    mock: bool,
+    /// Set by `from_fn = true`: the option's span, if the service can be implemented by a
+    /// closure. Only services with a single rpc can be.
+    from_fn: Option<Span>,
+    /// Whether the server hashes each request's unredacted args for the audit log, which requires
+    /// them to implement `Hash`.
+    args_hash: bool,
+    /// Whether RPC methods take `self: Arc<Self>` rather than `self`, so that services needn't be
+    /// `Clone`.
+    arc_self: bool,
+    /// Whether the client can prepare requests to send many times, which requires the request
+    /// enum to implement `Clone`.
+    prepared: bool,
+    /// Whether to only emit the request and response enums, which then depend on neither tarpc
+    /// nor std, so that `no_std` peers can share them.
+    types_only: bool,
+    /// Set by `derive(...)`: the traits derived for the request and response enums, besides the
+    /// serde traits. Defaults to `Debug`.
+    derives: Punctuated<Path, Comma>,
+    /// Set by `serde(...)`: the serde container attributes of the request and response enums.
+    serde_attrs: Vec<TokenStream2>,
+    /// Set by `wire_case = "..."`: the case of rpc and argument names on the wire. Defaults to the
+    /// names of the generated variants and fields.
+    wire_case: Option<(Span, WireCase)>,
+    /// Set by `vis = "..."`: the visibility of the generated items. Defaults to the visibility of
+    /// the service trait.
+    vis: Option<Visibility>,
+    /// Set by `version = N`: the current version of the service. Defaults to the latest version
+    /// named by a `#[since]` or `#[removed]` attribute.
+    version: Option<LitInt>,
+    /// Set by `client = "..."`, etc.: the names of the generated items.
+    client: Option<Ident>,
+    request: Option<Ident>,
+    response: Option<Ident>,
+    serve: Option<Ident>,
+}
 
-impl Parse for DeriveSerde {
+impl Parse for Options {
     fn parse(input: ParseStream) -> syn::Result<Self> {
-        if input.is_empty() {
-            return Ok(DeriveSerde(cfg!(feature = "serde1")));
-        }
-        match input.parse::<MetaNameValue>()? {
-            MetaNameValue {
-                ref ident, ref lit, ..
-            } if ident == "derive_serde" => match lit {
-                Lit::Bool(LitBool { value: true, .. }) if cfg!(feature = "serde1") => {
-                    Ok(DeriveSerde(true))
+        let mut options = Options {
+            derive_serde: cfg!(feature = "serde1"),
+            schema: false,
+            mock: false,
+            from_fn: None,
+            args_hash: false,
+            arc_self: false,
+            prepared: false,
+            types_only: false,
+            derives: parse_quote!(Debug),
+            serde_attrs: vec![],
+            wire_case: None,
+            vis: None,
+            version: None,
+            client: None,
+            request: None,
+            response: None,
+            serve: None,
+        };
+        let mut serde_span = None;
+        // The options that configure items other than the request and response enums.
+        let mut item_options = vec![];
+        while !input.is_empty() {
+            let ident: Ident = input.parse()?;
+            if ident == "derive" {
+                let content;
+                parenthesized!(content in input);
+                options.derives = content.parse_terminated(Path::parse_mod_style)?;
+            } else if ident == "serde" {
+                let content;
+                parenthesized!(content in input);
+                options.serde_attrs.push(content.parse()?);
+                serde_span = Some(ident.span());
+            } else {
+                input.parse::<Token![=]>()?;
+                let lit: Lit = input.parse()?;
+                if ident == "version" {
+                    match lit {
+                        Lit::Int(ref version) if version.value() > 0 => {
+                            options.version = Some(version.clone())
+                        }
+                        lit => {
+                            return Err(syn::Error::new(
+                                lit.span(),
+                                "`version` expects a positive integer",
+                            ))
+                        }
+                    }
+                } else if ident == "wire_case" {
+                    let case = match lit {
+                        Lit::Str(ref case) if case.value() == "camelCase" => WireCase::Camel,
+                        Lit::Str(ref case) if case.value() == "snake_case" => WireCase::Snake,
+                        lit => {
+                            return Err(syn::Error::new(
+                                lit.span(),
+                                "`wire_case` expects \"camelCase\" or \"snake_case\"",
+                            ))
+                        }
+                    };
+                    options.wire_case = Some((ident.span(), case));
+                } else if ident == "vis" {
+                    options.vis = Some(parse_lit_str(&ident, &lit)?);
+                } else if ident == "client" {
+                    options.client = Some(parse_lit_str(&ident, &lit)?);
+                    item_options.push(ident);
+                } else if ident == "request" {
+                    options.request = Some(parse_lit_str(&ident, &lit)?);
+                } else if ident == "response" {
+                    options.response = Some(parse_lit_str(&ident, &lit)?);
+                } else if ident == "serve" {
+                    options.serve = Some(parse_lit_str(&ident, &lit)?);
+                    item_options.push(ident);
+                } else {
+                    let value = match lit {
+                        Lit::Bool(LitBool { value, .. }) => value,
+                        lit => {
+                            return Err(syn::Error::new(
+                                lit.span(),
+                                format!("`{}` expects a value of type `bool`", ident),
+                            ))
+                        }
+                    };
+                    if ident == "derive_serde" {
+                        if value && !cfg!(feature = "serde1") {
+                            return Err(syn::Error::new(
+                                lit.span(),
+                                "To enable serde, first enable the `serde1` feature of tarpc",
+                            ));
+                        }
+                        options.derive_serde = value;
+                    } else if ident == "types_only" {
+                        options.types_only = value;
+                    } else {
+                        if ident == "schema" {
+                            options.schema = value;
+                        } else if ident == "mock" {
+                            options.mock = value;
+                        } else if ident == "from_fn" {
+                            options.from_fn = if value { Some(ident.span()) } else { None };
+                        } else if ident == "args_hash" {
+                            options.args_hash = value;
+                        } else if ident == "arc_self" {
+                            options.arc_self = value;
+                        } else if ident == "prepared" {
+                            options.prepared = value;
+                        } else {
+                            return Err(syn::Error::new(
+                            ident.span(),
+                            "tarpc::service only supports the meta items `derive_serde = {bool}`, \
+                             `schema = {bool}`, `mock = {bool}`, `from_fn = {bool}`, \
+                             `args_hash = {bool}`, `arc_self = {bool}`, `prepared = {bool}`, \
+                             `types_only = {bool}`, \
+                             `version = {int}`, `derive(...)`, `serde(...)`, \
+                             `wire_case = \"...\"`, `vis = \"...\"`, \
+                             `client = \"...\"`, `request = \"...\"`, `response = \"...\"`, \
+                             and `serve = \"...\"`",
+                            ));
+                        }
+                        if value {
+                            item_options.push(ident);
+                        }
+                    }
                 }
-                Lit::Bool(LitBool { value: true, .. }) => Err(syn::Error::new(
-                    lit.span(),
-                    "To enable serde, first enable the `serde1` feature of tarpc",
-                )),
-                Lit::Bool(LitBool { value: false, .. }) => Ok(DeriveSerde(false)),
-                lit => Err(syn::Error::new(
-                    lit.span(),
-                    "`derive_serde` expects a value of type `bool`",
-                )),
-            },
-            MetaNameValue { ident, .. } => Err(syn::Error::new(
+            }
+            if input.is_empty() {
+                break;
+            }
+            input.parse::<Token![,]>()?;
+        }
+        if options.types_only {
+            if let Some(ident) = item_options.first() {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    format!("`{}` has no effect when `types_only = true`", ident),
+                ));
+            }
+        }
+        if let Some(span) = serde_span {
+            if !options.derive_serde {
+                return Err(syn::Error::new(
+                    span,
+                    "`serde(...)` has no effect when `derive_serde = false`",
+                ));
+            }
+        }
+        if let Some((span, _)) = options.wire_case {
+            if !options.derive_serde {
+                return Err(syn::Error::new(
+                    span,
+                    "`wire_case` has no effect when `derive_serde = false`",
+                ));
+            }
+        }
+        if options.prepared && !options.derive_serde {
+            let ident = item_options
+                .iter()
+                .find(|ident| *ident == "prepared")
+                .unwrap();
+            return Err(syn::Error::new(
                 ident.span(),
-                "tarpc::service only supports one meta item, `derive_serde = {bool}`",
-            )),
+                "`prepared = true` requires `derive_serde`, to serialize prepared requests",
+            ));
+        }
+        Ok(options)
+    }
+}
+
+/// The case of names on the wire, for formats that identify rpcs and arguments by name.
+#[derive(Clone, Copy)]
+enum WireCase {
+    /// E.g. `getUser` and `userId`, as is idiomatic in JavaScript.
+    Camel,
+    /// E.g. `get_user` and `user_id`, matching the names in Rust.
+    Snake,
+}
+
+impl WireCase {
+    /// Converts a snake case name to this case.
+    fn convert(self, snake_case: &str) -> String {
+        match self {
+            WireCase::Camel => {
+                let camel = snake_to_camel(snake_case);
+                let mut chars = camel.chars();
+                match chars.next() {
+                    Some(first) => first.to_lowercase().chain(chars).collect(),
+                    None => camel,
+                }
+            }
+            WireCase::Snake => camel_to_screaming_snake(&snake_to_camel(snake_case)).to_lowercase(),
         }
     }
 }
 
+/// Parses the string literal value of the option `ident`, e.g. `vis = "pub(crate)"`.
+fn parse_lit_str<T: Parse>(ident: &Ident, lit: &Lit) -> syn::Result<T> {
+    match lit {
+        Lit::Str(lit) => lit.parse(),
+        lit => Err(syn::Error::new(
+            lit.span(),
+            format!("`{}` expects a string literal", ident),
+        )),
+    }
+}
+
+/// The items generated for version negotiation, which are empty for unversioned services.
+#[derive(Default)]
+struct Versioned {
+    name_arm: TokenStream2,
+    response_name_arm: TokenStream2,
+    serve_arm: TokenStream2,
+    fut_variant: TokenStream2,
+    poll_arm: TokenStream2,
+    server_field: TokenStream2,
+    server_init: TokenStream2,
+    server_impl: TokenStream2,
+    reject_fn: TokenStream2,
+    client_field: TokenStream2,
+    client_init: TokenStream2,
+    client_impl: TokenStream2,
+}
+
 /// Generates:
 /// - service trait
 /// - serve fn
@@ -163,236 +834,1640 @@ impl Parse for DeriveSerde {
 /// - new_stub client factory fn
 /// - Request and Response enums
 /// - ResponseFut Future
+///
+/// Attached to a module, generates these items for each service trait in the module.
 #[proc_macro_attribute]
 pub fn service(attr: TokenStream, input: TokenStream) -> TokenStream {
-    let derive_serde = parse_macro_input!(attr as DeriveSerde);
+    let options = parse_macro_input!(attr as Options);
+
+    match parse_macro_input!(input as ServiceInput) {
+        ServiceInput::Service(service) => expand_service(&options, service).into(),
+        ServiceInput::Module {
+            attrs,
+            vis,
+            ident,
+            items,
+        } => {
+            if let Some(name) = options
+                .client
+                .as_ref()
+                .or_else(|| options.request.as_ref())
+                .or_else(|| options.response.as_ref())
+                .or_else(|| options.serve.as_ref())
+            {
+                return syn::Error::new(
+                    name.span(),
+                    "the names of generated items can't be set for a module of services",
+                )
+                .to_compile_error()
+                .into();
+            }
+            let items = items.into_iter().map(|item| match item {
+                ModuleItem::Service(service) => expand_service(&options, service),
+                ModuleItem::Item(item) => quote!(#item),
+            });
+            quote! {
+                #( #attrs )*
+                #vis mod #ident {
+                    #( #items )*
+                }
+            }
+            .into()
+        }
+    }
+}
 
+fn expand_service(options: &Options, service: Service) -> TokenStream2 {
     let Service {
         attrs,
-        vis,
+        vis: trait_vis,
         ident,
         rpcs,
-    } = parse_macro_input!(input as Service);
-    let vis_repeated = std::iter::repeat(vis.clone());
+    } = service;
+    let vis = options.vis.clone().unwrap_or_else(|| trait_vis.clone());
+
+    // The current version of a versioned service. Versioned services append a variant for version
+    // negotiation to the request and response enums, after the rpcs' variants, so that versioning
+    // a service doesn't move its rpcs' variants.
+    let latest_version = rpcs
+        .iter()
+        .flat_map(|rpc| rpc.since.iter().chain(rpc.removed.iter()))
+        .max_by_key(|version| version.value());
+    let version = match (&options.version, latest_version) {
+        (Some(version), Some(latest)) if latest.value() > version.value() => {
+            return syn::Error::new(
+                latest.span(),
+                format!(
+                    "version {} is newer than the service's `version`",
+                    latest.value()
+                ),
+            )
+            .to_compile_error();
+        }
+        (Some(version), _) => Some(version.value() as u32),
+        (None, latest) => latest.map(|latest| latest.value() as u32),
+    };
 
     let camel_case_fn_names: Vec<String> = rpcs
         .iter()
         .map(|rpc| snake_to_camel(&rpc.ident.to_string()))
         .collect();
+    // The values returned by the service, which for rpcs that throw are results.
     let outputs: &Vec<TokenStream2> = &rpcs
         .iter()
-        .map(|rpc| match rpc.output {
-            ReturnType::Type(_, ref ty) => quote!(#ty),
-            ReturnType::Default => quote!(()),
+        .map(|rpc| {
+            let output = match rpc.output {
+                ReturnType::Type(_, ref ty) => quote!(#ty),
+                ReturnType::Default => quote!(()),
+            };
+            match rpc.throws {
+                Some(ref error) => quote!(core::result::Result<#output, #error>),
+                None => output,
+            }
         })
         .collect();
-    let future_types: Vec<Ident> = camel_case_fn_names
+    // The associated type of the service trait returned by each rpc: a future for unary rpcs,
+    // and a stream for streaming rpcs.
+    let assoc_types: Vec<Ident> = rpcs
         .iter()
-        .map(|name| Ident::new(&format!("{}Fut", name), ident.span()))
+        .zip(camel_case_fn_names.iter())
+        .map(|(rpc, name)| {
+            let suffix = if rpc.stream { "Stream" } else { "Fut" };
+            Ident::new(&format!("{}{}", name, suffix), ident.span())
+        })
         .collect();
     let camel_case_idents: &Vec<Ident> = &rpcs
         .iter()
         .zip(camel_case_fn_names.iter())
         .map(|(rpc, name)| Ident::new(name, rpc.ident.span()))
         .collect();
-    let camel_case_idents2 = camel_case_idents;
 
     let args: &Vec<&Punctuated<ArgCaptured, Comma>> = &rpcs.iter().map(|rpc| &rpc.args).collect();
     let arg_vars: &Vec<Punctuated<&Pat, Comma>> = &args
         .iter()
         .map(|args| args.iter().map(|arg| &arg.pat).collect())
         .collect();
-    let arg_vars2 = arg_vars;
-    let method_names: &Vec<&Ident> = &rpcs.iter().map(|rpc| &rpc.ident).collect();
-    let method_attrs: Vec<_> = rpcs.iter().map(|rpc| &rpc.attrs).collect();
-
-    let types_and_fns = rpcs
-        .iter()
-        .zip(future_types.iter())
-        .zip(outputs.iter())
-        .map(
-            |(
-                (
-                    RpcMethod {
-                        attrs, ident, args, ..
-                    },
-                    future_type,
-                ),
-                output,
-            )| {
-                let ty_doc = format!("The response future returned by {}.", ident);
-                quote! {
-                    #[doc = #ty_doc]
-                    type #future_type: std::future::Future<Output = #output>;
-
-                    #( #attrs )*
-                    fn #ident(self, context: tarpc::context::Context, #args) -> Self::#future_type;
-                }
-            },
-        );
-
-    let service_name_repeated = std::iter::repeat(ident.clone());
-    let service_name_repeated2 = service_name_repeated.clone();
-
-    let client_ident = Ident::new(&format!("{}Client", ident), ident.span());
-    let request_ident = Ident::new(&format!("{}Request", ident), ident.span());
-    let request_ident_repeated = std::iter::repeat(request_ident.clone());
-    let request_ident_repeated2 = request_ident_repeated.clone();
-    let response_ident = Ident::new(&format!("{}Response", ident), ident.span());
-    let response_ident_repeated = std::iter::repeat(response_ident.clone());
-    let response_ident_repeated2 = response_ident_repeated.clone();
-    let response_fut_name = format!("{}ResponseFut", ident);
-    let response_fut_ident = Ident::new(&response_fut_name, ident.span());
-    let response_fut_ident_repeated = std::iter::repeat(response_fut_ident.clone());
-    let response_fut_ident_repeated2 = response_fut_ident_repeated.clone();
-    let server_ident = Ident::new(&format!("Serve{}", ident), ident.span());
 
-    let derive_serialize = if derive_serde.0 {
-        quote!(#[derive(serde::Serialize, serde::Deserialize)])
+    let receiver = if options.arc_self {
+        quote!(self: std::sync::Arc<Self>)
     } else {
-        quote!()
+        quote!(self)
     };
+    let receiver = &receiver;
+    let types_and_fns = rpcs.iter().zip(assoc_types.iter()).zip(outputs.iter()).map(
+        |(
+            (
+                RpcMethod {
+                    attrs,
+                    ident,
+                    args,
+                    stream,
+                    ..
+                },
+                assoc_type,
+            ),
+            output,
+        )| {
+            let ty = if *stream {
+                quote!(tarpc::futures::Stream<Item = #output>)
+            } else {
+                quote!(std::future::Future<Output = #output>)
+            };
+            let ty_doc = if *stream {
+                format!("The response stream returned by {}.", ident)
+            } else {
+                format!("The response future returned by {}.", ident)
+            };
+            quote! {
+                #[doc = #ty_doc]
+                type #assoc_type: #ty;
 
-    let tokens = quote! {
-        #( #attrs )*
-        #vis trait #ident: Clone {
-            #( #types_and_fns )*
-
-            /// Returns a serving function to use with tarpc::server::Server.
-            fn serve(self) -> #server_ident<Self> {
-                #server_ident { service: self }
+                #( #attrs )*
+                fn #ident(#receiver, context: tarpc::context::Context, #args) -> Self::#assoc_type;
             }
-        }
-
-        #[derive(Clone)]
-        #vis struct #server_ident<S> {
-            service: S,
-        }
+        },
+    );
 
-        impl<S> tarpc::server::Serve<#request_ident> for #server_ident<S>
-            where S: #ident
-        {
-            type Resp = #response_ident;
-            type Fut = #response_fut_ident<S>;
+    let client_ident = options
+        .client
+        .clone()
+        .unwrap_or_else(|| Ident::new(&format!("{}Client", ident), ident.span()));
+    let request_ident = options
+        .request
+        .clone()
+        .unwrap_or_else(|| Ident::new(&format!("{}Request", ident), ident.span()));
+    let response_ident = options
+        .response
+        .clone()
+        .unwrap_or_else(|| Ident::new(&format!("{}Response", ident), ident.span()));
+    let service_name = ident.to_string();
+    let response_fut_name = format!("{}ResponseFut", ident);
+    let response_fut_ident = Ident::new(&response_fut_name, ident.span());
+    let server_ident = options
+        .serve
+        .clone()
+        .unwrap_or_else(|| Ident::new(&format!("Serve{}", ident), ident.span()));
 
-            fn serve(self, ctx: tarpc::context::Context, req: #request_ident) -> Self::Fut {
-                match req {
-                    #(
-                        #request_ident_repeated::#camel_case_idents{ #arg_vars } => {
-                            #response_fut_ident_repeated2::#camel_case_idents2(
-                                #service_name_repeated2::#method_names(
-                                    self.service, ctx, #arg_vars2))
-                        }
-                    )*
-                }
+    // Streaming rpcs respond with `Some(item)` for each item, and then `None`.
+    let response_types: &Vec<TokenStream2> = &rpcs
+        .iter()
+        .zip(outputs.iter())
+        .map(|(rpc, output)| {
+            if rpc.stream {
+                quote!(Option<#output>)
+            } else {
+                quote!(#output)
             }
-        }
-
-        /// The request sent over the wire from the client to the server.
-        #[derive(Debug)]
-        #derive_serialize
-        #vis enum #request_ident {
-            #( #camel_case_idents{ #args } ),*
-        }
-
-        /// The response sent over the wire from the server to the client.
-        #[derive(Debug)]
-        #derive_serialize
-        #vis enum #response_ident {
-            #( #camel_case_idents(#outputs) ),*
-        }
-
-        /// A future resolving to a server response.
-        #vis enum #response_fut_ident<S: #ident> {
-            #( #camel_case_idents(<S as #service_name_repeated>::#future_types) ),*
-        }
-
-        impl<S: #ident> std::fmt::Debug for #response_fut_ident<S> {
-            fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
-                fmt.debug_struct(#response_fut_name).finish()
+        })
+        .collect();
+    let response_fut_types: &Vec<TokenStream2> = &rpcs
+        .iter()
+        .zip(assoc_types.iter())
+        .map(|(rpc, assoc_type)| {
+            if rpc.stream {
+                quote! {
+                    tarpc::server::ForwardStream<<S as #ident>::#assoc_type, #response_ident>
+                }
+            } else {
+                quote!(<S as #ident>::#assoc_type)
             }
-        }
-
-        impl<S: #ident> std::future::Future for #response_fut_ident<S> {
-            type Output = #response_ident;
-
-            fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>)
-                -> std::task::Poll<#response_ident>
-            {
-                unsafe {
-                    match std::pin::Pin::get_unchecked_mut(self) {
-                        #(
-                            #response_fut_ident_repeated::#camel_case_idents(resp) =>
-                                std::pin::Pin::new_unchecked(resp)
-                                    .poll(cx)
-                                    .map(#response_ident_repeated::#camel_case_idents2),
-                        )*
-                    }
+        })
+        .collect();
+    let response_fut_docs = rpcs
+        .iter()
+        .map(|rpc| format!("The response future of `{}`.", rpc.ident));
+    let server_doc = format!(
+        "A [`Serve`](tarpc::server::Serve) impl that handles requests with a `{}` service.",
+        ident
+    );
+    let serve_arms = rpcs
+        .iter()
+        .zip(camel_case_idents.iter())
+        .zip(arg_vars.iter())
+        .map(|((rpc, camel_case_ident), arg_vars)| {
+            let method_name = &rpc.ident;
+            let response = quote!(#ident::#method_name(self.service, ctx, #arg_vars));
+            let response = if rpc.stream {
+                quote! {
+                    tarpc::server::ForwardStream::new(
+                        #response, sink, #response_ident::#camel_case_ident)
+                }
+            } else {
+                response
+            };
+            quote! {
+                #request_ident::#camel_case_ident{ #arg_vars } => {
+                    #response_fut_ident::#camel_case_ident(#response)
                 }
             }
-        }
+        });
+    let poll_arms = rpcs
+        .iter()
+        .zip(camel_case_idents.iter())
+        .map(|(rpc, camel_case_ident)| {
+            if rpc.stream {
+                quote! {
+                    #response_fut_ident::#camel_case_ident(resp) =>
+                        std::pin::Pin::new_unchecked(resp).poll(cx),
+                }
+            } else {
+                quote! {
+                    #response_fut_ident::#camel_case_ident(resp) =>
+                        std::pin::Pin::new_unchecked(resp)
+                            .poll(cx)
+                            .map(#response_ident::#camel_case_ident),
+                }
+            }
+        });
 
-        #[allow(unused)]
-        #[derive(Clone, Debug)]
-        /// The client stub that makes RPC calls to the server. Exposes a Future interface.
-        #vis struct #client_ident<C = tarpc::client::Channel<#request_ident, #response_ident>>(C);
+    let service_ident = &ident;
+    let prepare_fns = rpcs
+        .iter()
+        .zip(camel_case_idents.iter())
+        .filter(|(rpc, _)| options.prepared && !rpc.stream)
+        .map(|(rpc, camel_case_ident)| {
+            let RpcMethod { ident, args, .. } = rpc;
+            let arg_vars: Punctuated<&Pat, Comma> = args.iter().map(|arg| &arg.pat).collect();
+            let prepare_ident = Ident::new(&format!("prepare_{}", ident), ident.span());
+            let prepare_doc = format!(
+                "Prepares a `{}` request, to be sent any number of times by [`{}_prepared`]\
+                 (Self::{0}_prepared) while its arguments are serialized only once.",
+                ident
+            );
+            quote! {
+                #[allow(unused)]
+                #[doc = #prepare_doc]
+                #vis fn #prepare_ident(#args) -> tarpc::client::Prepared<#request_ident> {
+                    tarpc::client::Prepared::new(#request_ident::#camel_case_ident { #arg_vars })
+                }
+            }
+        });
+    let client_methods = rpcs
+        .iter()
+        .zip(camel_case_idents.iter())
+        .zip(outputs.iter())
+        .map(|((rpc, camel_case_ident), output)| {
+            let RpcMethod {
+                attrs,
+                ident,
+                args,
+                one_way,
+                stream,
+                ..
+            } = rpc;
+            let arg_vars: Punctuated<&Pat, Comma> = args.iter().map(|arg| &arg.pat).collect();
+            // Rpcs added or removed in some version fail without being sent if the negotiated
+            // version doesn't have them.
+            let bounds: Vec<TokenStream2> = rpc
+                .since
+                .iter()
+                .map(|since| {
+                    let since = since.value() as u32;
+                    quote!(#since <= version)
+                })
+                .chain(rpc.removed.iter().map(|removed| {
+                    let removed = removed.value() as u32;
+                    quote!(version < #removed)
+                }))
+                .collect();
+            let bounds = &bounds;
+            let send = |call: TokenStream2| match version {
+                Some(service_version) if !bounds.is_empty() => {
+                    let unavailable = format!(
+                        "`{}` is not available in version {{}} of {}",
+                        ident, service_ident
+                    );
+                    (
+                        quote! {
+                            let version = self.1.unwrap_or(#service_version);
+                            let resp = if #( #bounds )&&* { Some(#call) } else { None };
+                        },
+                        quote! {
+                            let resp = resp.ok_or_else(|| std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                format!(#unavailable, version)))?;
+                        },
+                    )
+                }
+                _ => (quote!(let resp = #call;), quote!()),
+            };
+            let method = if *stream {
+                let (send_resp, await_resp) =
+                    send(quote!(tarpc::Client::call_stream(&mut self.0, ctx, request)));
+                quote! {
+                    #[allow(unused)]
+                    #( #attrs )*
+                    #vis fn #ident(&mut self, ctx: tarpc::context::Context, #args)
+                        -> impl std::future::Future<Output = std::io::Result<
+                            impl tarpc::futures::Stream<Item = std::io::Result<#output>> + '_>> + '_ {
+                        let request = #request_ident::#camel_case_ident { #arg_vars };
+                        #send_resp
+                        async move {
+                            #await_resp
+                            let stream = resp.await?;
+                            Ok(tarpc::futures::TryStreamExt::map_ok(stream, |resp| match resp {
+                                #response_ident::#camel_case_ident(Some(msg)) => msg,
+                                _ => unreachable!(),
+                            }))
+                        }
+                    }
+                }
+            } else if *one_way {
+                let (send_resp, await_resp) =
+                    send(quote!(tarpc::Client::notify(&mut self.0, ctx, request)));
+                quote! {
+                    #[allow(unused)]
+                    #( #attrs )*
+                    #vis fn #ident(&mut self, ctx: tarpc::context::Context, #args)
+                        -> impl std::future::Future<Output = std::io::Result<()>> + '_ {
+                        let request = #request_ident::#camel_case_ident { #arg_vars };
+                        #send_resp
+                        async move {
+                            #await_resp
+                            resp.await
+                        }
+                    }
+                }
+            } else if let Some(ref error) = rpc.throws {
+                let output = match rpc.output {
+                    ReturnType::Type(_, ref ty) => quote!(#ty),
+                    ReturnType::Default => quote!(()),
+                };
+                let (send_resp, await_resp) =
+                    send(quote!(tarpc::Client::call(&mut self.0, ctx, request)));
+                quote! {
+                    #[allow(unused)]
+                    #( #attrs )*
+                    #vis fn #ident(&mut self, ctx: tarpc::context::Context, #args)
+                        -> impl std::future::Future<Output = std::result::Result<
+                            #output, tarpc::client::CallError<#error>>> + '_ {
+                        let request = #request_ident::#camel_case_ident { #arg_vars };
+                        #send_resp
+                        async move {
+                            #await_resp
+                            match resp.await? {
+                                #response_ident::#camel_case_ident(msg) =>
+                                    msg.map_err(tarpc::client::CallError::Service),
+                                _ => unreachable!(),
+                            }
+                        }
+                    }
+                }
+            } else {
+                let (send_resp, await_resp) =
+                    send(quote!(tarpc::Client::call(&mut self.0, ctx, request)));
+                quote! {
+                    #[allow(unused)]
+                    #( #attrs )*
+                    #vis fn #ident(&mut self, ctx: tarpc::context::Context, #args)
+                        -> impl std::future::Future<Output = std::io::Result<#output>> + '_ {
+                        let request = #request_ident::#camel_case_ident { #arg_vars };
+                        #send_resp
+                        async move {
+                            #await_resp
+                            match resp.await? {
+                                #response_ident::#camel_case_ident(msg) => std::result::Result::Ok(msg),
+                                _ => unreachable!(),
+                            }
+                        }
+                    }
+                }
+            };
+            // Prepared requests are cloned each time they're sent, so they need a Clone request
+            // type. Streaming rpcs can't be prepared.
+            if !options.prepared || *stream {
+                return method;
+            }
+            let prepared_ident = Ident::new(&format!("{}_prepared", ident), ident.span());
+            let prepared_doc = format!(
+                "Sends a `{}` request prepared by [`prepare_{0}`](Self::prepare_{0}), failing \
+                 without sending it if it was prepared for another rpc.",
+                ident
+            );
+            let mismatch = format!("the prepared request is not a `{}` request", ident);
+            let call_prepared = if *one_way {
+                quote!(notify_prepared)
+            } else {
+                quote!(call_prepared)
+            };
+            let (send_resp, await_resp) = send(quote! {
+                if mismatch {
+                    None
+                } else {
+                    Some(tarpc::Client::#call_prepared(&mut self.0, ctx, prepared))
+                }
+            });
+            let (output, respond) = if *one_way {
+                (quote!(std::io::Result<()>), quote!(resp.await))
+            } else if let Some(ref error) = rpc.throws {
+                let output = match rpc.output {
+                    ReturnType::Type(_, ref ty) => quote!(#ty),
+                    ReturnType::Default => quote!(()),
+                };
+                (
+                    quote!(std::result::Result<#output, tarpc::client::CallError<#error>>),
+                    quote! {
+                        match resp.await? {
+                            #response_ident::#camel_case_ident(msg) =>
+                                msg.map_err(tarpc::client::CallError::Service),
+                            _ => unreachable!(),
+                        }
+                    },
+                )
+            } else {
+                (
+                    quote!(std::io::Result<#output>),
+                    quote! {
+                        match resp.await? {
+                            #response_ident::#camel_case_ident(msg) => std::result::Result::Ok(msg),
+                            _ => unreachable!(),
+                        }
+                    },
+                )
+            };
+            quote! {
+                #method
 
-        impl<C> From<C> for #client_ident<C>
-            where for <'a> C: tarpc::Client<'a, #request_ident, Response = #response_ident>
-        {
-            fn from(client: C) -> Self {
-                #client_ident(client)
+                #[allow(unused)]
+                #[doc = #prepared_doc]
+                #vis fn #prepared_ident(
+                    &mut self,
+                    ctx: tarpc::context::Context,
+                    prepared: &tarpc::client::Prepared<#request_ident>,
+                ) -> impl std::future::Future<Output = #output> + '_ {
+                    #[allow(unreachable_patterns)]
+                    let mismatch = match prepared.message() {
+                        #request_ident::#camel_case_ident { .. } => false,
+                        _ => true,
+                    };
+                    #send_resp
+                    async move {
+                        #await_resp
+                        let resp = resp.ok_or_else(|| std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput, #mismatch))?;
+                        #respond
+                    }
+                }
+            }
+        });
+
+    let derive_serialize = if options.derive_serde {
+        quote!(#[derive(serde::Serialize, serde::Deserialize)])
+    } else {
+        quote!()
+    };
+    let derives = &options.derives;
+    let serde_attrs = &options.serde_attrs;
+
+    if !options.derive_serde {
+        if let Some(wire_name) = rpcs.iter().find_map(|rpc| rpc.wire_name.as_ref()) {
+            return syn::Error::new(
+                wire_name.span(),
+                "`#[name]` has no effect when `derive_serde = false`",
+            )
+            .to_compile_error();
+        }
+    }
+    let wire_case = options.wire_case.map(|(_, case)| case);
+    let wire_names: &Vec<String> = &rpcs
+        .iter()
+        .map(|rpc| match (&rpc.wire_name, wire_case) {
+            (None, Some(case)) => case.convert(&rpc.ident.to_string()),
+            _ => rpc.wire_name(),
+        })
+        .collect();
+    let wire_name_attrs: &Vec<TokenStream2> = &rpcs
+        .iter()
+        .zip(wire_names.iter())
+        .map(|(rpc, wire_name)| {
+            if rpc.wire_name.is_some() || wire_case.is_some() {
+                quote!(#[serde(rename = #wire_name)])
+            } else {
+                quote!()
+            }
+        })
+        .collect();
+    // Doc comments on an rpc also document its request and response variants.
+    let doc_attrs: &Vec<Vec<&Attribute>> = &rpcs
+        .iter()
+        .map(|rpc| {
+            rpc.attrs
+                .iter()
+                .filter(|attr| attr.path.is_ident("doc"))
+                .collect()
+        })
+        .collect();
+
+    // The rpc whose variants are at each position of the request and response enums: explicit
+    // ids if given, else source order. Positions without an rpc hold uninhabited variants, so
+    // that the remaining variants keep their positions.
+    let slots: Vec<Option<usize>> = if rpcs.iter().any(|rpc| rpc.id.is_some()) {
+        let ids: Vec<usize> = rpcs
+            .iter()
+            .map(|rpc| rpc.id.as_ref().unwrap().value() as usize)
+            .collect();
+        let mut slots = vec![None; ids.iter().max().map_or(0, |max| max + 1)];
+        for (rpc, id) in ids.into_iter().enumerate() {
+            slots[id] = Some(rpc);
+        }
+        slots
+    } else {
+        (0..rpcs.len()).map(Some).collect()
+    };
+    // Positions without an rpc.
+    let reserved_slots: &Vec<usize> = &slots
+        .iter()
+        .enumerate()
+        .filter(|&(_, rpc)| rpc.is_none())
+        .map(|(slot, _)| slot)
+        .collect();
+    let reserved_ident = |slot: usize| Ident::new(&format!("__Reserved{}", slot), ident.span());
+    // The uninhabited type of reserved variants, which services with `types_only` define
+    // themselves, so as not to depend on tarpc.
+    let reserved_ty_ident = Ident::new(&format!("__{}Reserved", ident), ident.span());
+    let reserved_ty = if options.types_only {
+        quote!(#reserved_ty_ident)
+    } else {
+        quote!(tarpc::Reserved)
+    };
+    let reserved_ty = &reserved_ty;
+    let request_variants = slots.iter().enumerate().map(|(slot, rpc)| match *rpc {
+        Some(rpc) => {
+            let wire_name_attr = &wire_name_attrs[rpc];
+            let doc_attrs = &doc_attrs[rpc];
+            let camel_case_ident = &camel_case_idents[rpc];
+            let fields = args[rpc].iter().map(|arg| {
+                let pat = &arg.pat;
+                let field_doc =
+                    format!("The `{}` argument of `{}`.", quote!(#pat), rpcs[rpc].ident);
+                let field_name = quote!(#pat).to_string();
+                let rename = match wire_case {
+                    Some(case) if case.convert(&field_name) != field_name => {
+                        let wire_name = case.convert(&field_name);
+                        quote!(#[serde(rename = #wire_name)])
+                    }
+                    _ => quote!(),
+                };
+                quote!(#[doc = #field_doc] #rename #arg)
+            });
+            quote!(#( #doc_attrs )* #wire_name_attr #camel_case_ident{ #( #fields ),* })
+        }
+        None => {
+            let reserved_ident = reserved_ident(slot);
+            quote!(#[doc(hidden)] #reserved_ident(#reserved_ty))
+        }
+    });
+    // Version negotiation comes after every rpc, so it takes a position no rpc ever had.
+    let request_variants = request_variants.chain(version.map(|_| {
+        quote!(
+            #[doc(hidden)]
+            __Negotiate {
+                min_version: u32,
+                max_version: u32
+            }
+        )
+    }));
+    let response_variants = slots.iter().enumerate().map(|(slot, rpc)| match *rpc {
+        Some(rpc) => {
+            let wire_name_attr = &wire_name_attrs[rpc];
+            let doc_attrs = &doc_attrs[rpc];
+            let camel_case_ident = &camel_case_idents[rpc];
+            let response_type = &response_types[rpc];
+            quote!(#( #doc_attrs )* #wire_name_attr #camel_case_ident(#response_type))
+        }
+        None => {
+            let reserved_ident = reserved_ident(slot);
+            quote!(#[doc(hidden)] #reserved_ident(#reserved_ty))
+        }
+    });
+    let response_variants =
+        response_variants.chain(version.map(|_| quote!(#[doc(hidden)] __Negotiate(Option<u32>))));
+    let reserved_arms = reserved_slots.iter().map(|&slot| {
+        let reserved_ident = reserved_ident(slot);
+        quote!(#request_ident::#reserved_ident(reserved) => match reserved {},)
+    });
+
+    let mut ids = vec![0; rpcs.len()];
+    for (slot, rpc) in slots.iter().enumerate() {
+        if let Some(rpc) = *rpc {
+            ids[rpc] = slot as u32;
+        }
+    }
+    let methods = rpcs
+        .iter()
+        .zip(wire_names.iter())
+        .zip(ids)
+        .map(|((rpc, wire_name), id)| {
+            let name = rpc.ident.to_string();
+            let kind = if rpc.stream {
+                quote!(Streaming)
+            } else if rpc.one_way {
+                quote!(OneWay)
+            } else {
+                quote!(Unary)
+            };
+            let arg_names = rpc.args.iter().map(|arg| {
+                let pat = &arg.pat;
+                quote!(#pat).to_string()
+            });
+            let arg_types = rpc.args.iter().map(|arg| type_string(&arg.ty));
+            let output = match rpc.output {
+                ReturnType::Type(_, ref ty) => type_string(ty),
+                ReturnType::Default => "()".to_string(),
+            };
+            let error = match rpc.throws {
+                Some(ref error) => {
+                    let error = type_string(error);
+                    quote!(Some(std::borrow::Cow::Borrowed(#error)))
+                }
+                None => quote!(None),
+            };
+            let since = match rpc.since {
+                Some(ref since) => {
+                    let since = since.value() as u32;
+                    quote!(Some(#since))
+                }
+                None => quote!(None),
+            };
+            let removed = match rpc.removed {
+                Some(ref removed) => {
+                    let removed = removed.value() as u32;
+                    quote!(Some(#removed))
+                }
+                None => quote!(None),
+            };
+            quote! {
+                tarpc::schema::Method {
+                    name: std::borrow::Cow::Borrowed(#name),
+                    wire_name: std::borrow::Cow::Borrowed(#wire_name),
+                    id: #id,
+                    kind: tarpc::schema::MethodKind::#kind,
+                    args: std::borrow::Cow::Borrowed(&[
+                        #(
+                            tarpc::schema::Arg {
+                                name: std::borrow::Cow::Borrowed(#arg_names),
+                                ty: std::borrow::Cow::Borrowed(#arg_types),
+                            }
+                        ),*
+                    ]),
+                    output: std::borrow::Cow::Borrowed(#output),
+                    error: #error,
+                    since: #since,
+                    removed: #removed,
+                }
+            }
+        });
+    let method_name_arms =
+        rpcs.iter()
+            .zip(camel_case_idents.iter())
+            .map(|(rpc, camel_case_ident)| {
+                let name = rpc.ident.to_string();
+                quote!(#request_ident::#camel_case_ident { .. } => #name,)
+            });
+    let inline_patterns: Vec<TokenStream2> = rpcs
+        .iter()
+        .zip(camel_case_idents.iter())
+        .filter(|(rpc, _)| rpc.inline)
+        .map(|(_, camel_case_ident)| quote!(#request_ident::#camel_case_ident { .. }))
+        .collect();
+    let is_inline = if inline_patterns.is_empty() {
+        quote!()
+    } else {
+        quote! {
+            fn is_inline(&self, req: &#request_ident) -> bool {
+                match req {
+                    #( #inline_patterns )|* => true,
+                    _ => false,
+                }
+            }
+        }
+    };
+    let reserved_name_arms = reserved_slots.iter().map(|&slot| {
+        let reserved_ident = reserved_ident(slot);
+        quote!(#request_ident::#reserved_ident(reserved) => match *reserved {},)
+    });
+    let response_name_arms =
+        rpcs.iter()
+            .zip(camel_case_idents.iter())
+            .map(|(rpc, camel_case_ident)| {
+                let name = rpc.ident.to_string();
+                quote!(#response_ident::#camel_case_ident(_) => #name,)
+            });
+    let reserved_response_name_arms = reserved_slots.iter().map(|&slot| {
+        let reserved_ident = reserved_ident(slot);
+        quote!(#response_ident::#reserved_ident(reserved) => match *reserved {},)
+    });
+
+    // If any args are redacted, the request's Debug impl is generated rather than derived.
+    let is_debug = |path: &Path| path.segments.last().unwrap().value().ident == "Debug";
+    let redacted_debug =
+        rpcs.iter().any(|rpc| !rpc.redacted.is_empty()) && derives.iter().any(is_debug);
+    // Requests are hashed for the audit log through their args' Hash impls, leaving out redacted
+    // args.
+    let args_hash = if options.args_hash {
+        let hash_arms = rpcs
+            .iter()
+            .zip(camel_case_idents.iter())
+            .map(|(rpc, camel_case_ident)| {
+                let arg_idents: Vec<&Ident> = rpc
+                    .args
+                    .iter()
+                    .map(|arg| match arg.pat {
+                        Pat::Ident(ref pat) => &pat.ident,
+                        _ => unreachable!(),
+                    })
+                    .filter(|arg_ident| !rpc.redacted.contains(*arg_ident))
+                    .collect();
+                let name = rpc.ident.to_string();
+                quote! {
+                    #request_ident::#camel_case_ident { #( #arg_idents, )* .. } => {
+                        let mut hasher = tarpc::server::audit::ArgsHasher::new(#name);
+                        #( hasher.arg(#arg_idents); )*
+                        Some(hasher.finish())
+                    }
+                }
+            });
+        quote! {
+            fn args_hash(&self, req: &#request_ident) -> Option<u64> {
+                match req {
+                    #( #hash_arms )*
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                }
+            }
+        }
+    } else {
+        quote!()
+    };
+    // Payloads are captured through their Debug impls, which omit redacted args.
+    // Requests and responses count against the server's buffer limit at the length of their
+    // encodings.
+    let size_methods = if options.derive_serde {
+        quote! {
+            fn request_size(req: &#request_ident) -> usize {
+                tarpc::server::encoded_size(req)
+            }
+
+            fn response_size(resp: &#response_ident) -> usize {
+                tarpc::server::encoded_size(resp)
+            }
+        }
+    } else {
+        quote!()
+    };
+    let debug_methods = if derives.iter().any(is_debug) {
+        quote! {
+            fn debug_request(&self, req: &#request_ident) -> Option<String> {
+                Some(format!("{:?}", req))
+            }
+
+            fn debug_response(&self, resp: &#response_ident) -> Option<String> {
+                Some(format!("{:?}", resp))
+            }
+        }
+    } else {
+        quote!()
+    };
+    let request_derives: Punctuated<&Path, Comma> = derives
+        .iter()
+        .filter(|path| !redacted_debug || !is_debug(path))
+        .collect();
+    let request_debug = if redacted_debug {
+        let debug_arms =
+            rpcs.iter()
+                .zip(camel_case_idents.iter())
+                .map(|(rpc, camel_case_ident)| {
+                    let arg_idents: &Vec<&Ident> = &rpc
+                        .args
+                        .iter()
+                        .map(|arg| match arg.pat {
+                            Pat::Ident(ref pat) => &pat.ident,
+                            _ => unreachable!(),
+                        })
+                        .collect();
+                    let fields = arg_idents.iter().map(|arg_ident| {
+                        let name = arg_ident.to_string();
+                        if rpc.redacted.contains(*arg_ident) {
+                            quote!(.field(#name, &format_args!("<redacted>")))
+                        } else {
+                            quote!(.field(#name, #arg_ident))
+                        }
+                    });
+                    let arg_pats = arg_idents.iter().map(|arg_ident| {
+                        if rpc.redacted.contains(*arg_ident) {
+                            quote!(#arg_ident: _)
+                        } else {
+                            quote!(#arg_ident)
+                        }
+                    });
+                    let variant_name = camel_case_ident.to_string();
+                    quote! {
+                        #request_ident::#camel_case_ident { #( #arg_pats ),* } => {
+                            fmt.debug_struct(#variant_name) #( #fields )* .finish()
+                        }
+                    }
+                });
+        let reserved_arms = reserved_slots.iter().map(|&slot| {
+            let reserved_ident = reserved_ident(slot);
+            quote!(#request_ident::#reserved_ident(reserved) => match *reserved {},)
+        });
+        let negotiate_debug_arm = if version.is_some() {
+            quote! {
+                #request_ident::__Negotiate { min_version, max_version } => {
+                    fmt.debug_struct("__Negotiate")
+                        .field("min_version", min_version)
+                        .field("max_version", max_version)
+                        .finish()
+                }
+            }
+        } else {
+            quote!()
+        };
+        quote! {
+            impl core::fmt::Debug for #request_ident {
+                fn fmt(&self, fmt: &mut core::fmt::Formatter) -> core::fmt::Result {
+                    match self {
+                        #( #debug_arms )*
+                        #negotiate_debug_arm
+                        #( #reserved_arms )*
+                    }
+                }
             }
         }
+    } else {
+        quote!()
+    };
+
+    if options.types_only {
+        let reserved_ty_def = if reserved_slots.is_empty() {
+            quote!()
+        } else {
+            quote! {
+                #[doc(hidden)]
+                #[derive(#derives)]
+                #derive_serialize
+                #vis enum #reserved_ty_ident {}
+            }
+        };
+        return quote! {
+            /// The request sent over the wire from the client to the server.
+            #[derive(#request_derives)]
+            #derive_serialize
+            #( #[serde(#serde_attrs)] )*
+            #vis enum #request_ident {
+                #( #request_variants ),*
+            }
+
+            /// The response sent over the wire from the server to the client.
+            #[derive(#derives)]
+            #derive_serialize
+            #( #[serde(#serde_attrs)] )*
+            #vis enum #response_ident {
+                #( #response_variants ),*
+            }
+
+            #request_debug
+
+            #reserved_ty_def
+        };
+    }
+
+    let schema = if options.schema {
+        let schema_ident = Ident::new(
+            &format!("{}_SCHEMA", camel_to_screaming_snake(&ident.to_string())),
+            ident.span(),
+        );
+        let schema_doc = format!("Describes the `{}` service.", ident);
+        quote! {
+            #[doc = #schema_doc]
+            #vis const #schema_ident: tarpc::schema::Service = tarpc::schema::Service {
+                name: std::borrow::Cow::Borrowed(#service_name),
+                methods: std::borrow::Cow::Borrowed(
+                    <#request_ident as tarpc::schema::Introspect>::METHODS),
+            };
+        }
+    } else {
+        quote!()
+    };
+
+    let mock = if options.mock && cfg!(feature = "client") {
+        let mock_ident = Ident::new(&format!("Mock{}", client_ident), ident.span());
+        let method_idents: &Vec<&Ident> = &rpcs.iter().map(|rpc| &rpc.ident).collect();
+        let method_names = rpcs.iter().map(|rpc| rpc.ident.to_string());
+        let arg_tuple_types: &Vec<TokenStream2> = &args
+            .iter()
+            .map(|args| {
+                let types = args.iter().map(|arg| &arg.ty);
+                quote!((#( #types, )*))
+            })
+            .collect();
+        // Streaming rpcs respond with the list of streamed items.
+        let mock_outputs: &Vec<TokenStream2> = &rpcs
+            .iter()
+            .zip(outputs.iter())
+            .map(|(rpc, output)| {
+                if rpc.stream {
+                    quote!(Vec<#output>)
+                } else {
+                    quote!(#output)
+                }
+            })
+            .collect();
+        let expect_fns = rpcs
+            .iter()
+            .zip(arg_tuple_types.iter())
+            .zip(mock_outputs.iter())
+            .map(|((rpc, args), output)| {
+                let method_ident = &rpc.ident;
+                let expect_ident = Ident::new(&format!("expect_{}", rpc.ident), rpc.ident.span());
+                let expect_doc = format!(
+                    "Adds an expectation for calls to `{}`. Calls are answered by the first \
+                     matching expectation, in the order they were added.",
+                    rpc.ident
+                );
+                quote! {
+                    #[doc = #expect_doc]
+                    #vis fn #expect_ident(&mut self)
+                        -> &mut tarpc::client::mock::Expectation<#args, #output>
+                    {
+                        self.#method_ident.expect()
+                    }
+                }
+            });
+        let expectations_types = arg_tuple_types
+            .iter()
+            .zip(mock_outputs.iter())
+            .map(|(args, output)| quote!(tarpc::client::mock::Expectations<#args, #output>));
+        let mock_arm = |rpc: usize, response: TokenStream2| {
+            let method_ident = method_idents[rpc];
+            let camel_case_ident = &camel_case_idents[rpc];
+            let arg_vars = &arg_vars[rpc];
+            let arg_tuple = arg_vars.iter();
+            quote! {
+                #request_ident::#camel_case_ident{ #arg_vars } => {
+                    self.#method_ident.call(ctx, (#( #arg_tuple, )*)).map(#response)
+                }
+            }
+        };
+        let call_arms = rpcs
+            .iter()
+            .enumerate()
+            .filter(|(_, rpc)| !rpc.one_way && !rpc.stream)
+            .map(|(i, _)| {
+                let camel_case_ident = &camel_case_idents[i];
+                mock_arm(i, quote!(#response_ident::#camel_case_ident))
+            });
+        // Mock clients support every version their caller does.
+        let negotiate_arm = if version.is_some() {
+            quote! {
+                #request_ident::__Negotiate { max_version, .. } => {
+                    Ok(#response_ident::__Negotiate(Some(max_version)))
+                }
+            }
+        } else {
+            quote!()
+        };
+        let notify_arms = rpcs
+            .iter()
+            .enumerate()
+            .filter(|(_, rpc)| rpc.one_way)
+            .map(|(i, _)| mock_arm(i, quote!(|()| ())));
+        let call_stream_arms =
+            rpcs.iter()
+                .enumerate()
+                .filter(|(_, rpc)| rpc.stream)
+                .map(|(i, _)| {
+                    let camel_case_ident = &camel_case_idents[i];
+                    mock_arm(
+                        i,
+                        quote! {
+                            |items| tarpc::futures::stream::iter(
+                                items
+                                    .into_iter()
+                                    .map(|item| Ok(#response_ident::#camel_case_ident(Some(item))))
+                                    .collect::<Vec<_>>())
+                        },
+                    )
+                });
+        let mock_doc = format!(
+            "A mock of [`{}`] that answers requests with expectations instead of sending them to a \
+             server. Wrap it with `{}::from` to pass it to code that uses a client.",
+            client_ident, client_ident
+        );
+        quote! {
+            #[doc = #mock_doc]
+            #[derive(Debug)]
+            #vis struct #mock_ident {
+                #( #method_idents: #expectations_types, )*
+            }
+
+            impl Default for #mock_ident {
+                fn default() -> Self {
+                    #mock_ident {
+                        #( #method_idents: tarpc::client::mock::Expectations::new(#method_names), )*
+                    }
+                }
+            }
 
-        impl #client_ident {
-            /// Returns a new client stub that sends requests over the given transport.
-            #vis fn new<T>(config: tarpc::client::Config, transport: T)
-                -> tarpc::client::NewClient<
-                    Self,
-                    tarpc::client::channel::RequestDispatch<#request_ident, #response_ident, T>>
+            impl #mock_ident {
+                /// Returns a new mock client with no expectations.
+                #vis fn new() -> Self {
+                    Self::default()
+                }
+
+                #( #expect_fns )*
+            }
+
+            impl<'a> tarpc::Client<'a, #request_ident> for #mock_ident {
+                type Response = #response_ident;
+                type Future = tarpc::futures::future::Ready<std::io::Result<#response_ident>>;
+                type NotifyFuture = tarpc::futures::future::Ready<std::io::Result<()>>;
+                type ResponseStream = tarpc::futures::stream::Iter<
+                    std::vec::IntoIter<std::io::Result<#response_ident>>>;
+                type StreamFuture = tarpc::futures::future::Ready<std::io::Result<Self::ResponseStream>>;
+
+                #[allow(unreachable_patterns, unused_variables)]
+                fn call(&'a mut self, ctx: tarpc::context::Context, request: #request_ident)
+                    -> Self::Future
+                {
+                    tarpc::futures::future::ready(match request {
+                        #( #call_arms )*
+                        #negotiate_arm
+                        _ => panic!("`call` was passed a one-way or streaming request"),
+                    })
+                }
+
+                #[allow(unreachable_patterns, unused_variables)]
+                fn notify(&'a mut self, ctx: tarpc::context::Context, request: #request_ident)
+                    -> Self::NotifyFuture
+                {
+                    tarpc::futures::future::ready(match request {
+                        #( #notify_arms )*
+                        _ => panic!("`notify` was passed a request that isn't one-way"),
+                    })
+                }
+
+                #[allow(unreachable_patterns, unused_variables)]
+                fn call_stream(&'a mut self, ctx: tarpc::context::Context, request: #request_ident)
+                    -> Self::StreamFuture
+                {
+                    tarpc::futures::future::ready(match request {
+                        #( #call_stream_arms )*
+                        _ => panic!("`call_stream` was passed a request that isn't streaming"),
+                    })
+                }
+            }
+        }
+    } else {
+        quote!()
+    };
+
+    // With `arc_self`, the serving struct holds an `Arc` of the service, which is cloned for each
+    // request instead of the service itself.
+    let (supertrait, self_ty, service_ty) = if options.arc_self {
+        (
+            quote!(Sized),
+            quote!(std::sync::Arc<Self>),
+            quote!(std::sync::Arc<S>),
+        )
+    } else {
+        (quote!(Clone), quote!(Self), quote!(S))
+    };
+
+    // Services with a single rpc can opt into being implemented by a closure.
+    let from_fn = match (options.from_fn, &rpcs[..]) {
+        (Some(_), [rpc]) => Some(rpc),
+        (Some(span), _) => {
+            return syn::Error::new(span, "`from_fn` requires a service with a single rpc")
+                .to_compile_error();
+        }
+        (None, _) => None,
+    };
+    let from_fn = if let Some(rpc) = from_fn {
+        let fn_ident = Ident::new(&format!("{}Fn", ident), ident.span());
+        let fn_doc = format!(
+            "A `{}` service that handles requests by calling a closure. Create one with \
+             [`{}::from_fn`].",
+            ident, fn_ident
+        );
+        let from_fn_doc = format!(
+            "Returns a `{}` service that handles `{}` requests by calling `f` with the request \
+             context and the arguments of the rpc.",
+            ident, rpc.ident
+        );
+        let method_ident = &rpc.ident;
+        let assoc_type = &assoc_types[0];
+        let output = &outputs[0];
+        let arg_types = rpc.args.iter().map(|arg| &arg.ty);
+        let arg_vars = &arg_vars[0];
+        let args = &rpc.args;
+        let ret_bound = if rpc.stream {
+            quote!(tarpc::futures::Stream<Item = #output>)
+        } else {
+            quote!(std::future::Future<Output = #output>)
+        };
+        quote! {
+            #[doc = #fn_doc]
+            #[derive(Clone)]
+            #vis struct #fn_ident<F>(F);
+
+            impl<F> #fn_ident<F> {
+                #[doc = #from_fn_doc]
+                #vis fn from_fn(f: F) -> Self {
+                    #fn_ident(f)
+                }
+            }
+
+            impl<F> std::fmt::Debug for #fn_ident<F> {
+                fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    fmt.debug_struct(stringify!(#fn_ident)).finish()
+                }
+            }
+
+            impl<F, R> #ident for #fn_ident<F>
             where
-                T: tarpc::Transport<tarpc::ClientMessage<#request_ident>, tarpc::Response<#response_ident>>
+                F: Fn(tarpc::context::Context, #( #arg_types ),*) -> R + #supertrait,
+                R: #ret_bound,
             {
-                let new_client = tarpc::client::new(config, transport);
-                tarpc::client::NewClient {
-                    client: #client_ident(new_client.client),
-                    dispatch: new_client.dispatch,
+                type #assoc_type = R;
+
+                fn #method_ident(#receiver, context: tarpc::context::Context, #args) -> R {
+                    (self.0)(context, #arg_vars)
+                }
+            }
+        }
+    } else {
+        quote!()
+    };
+
+    // Versioned services answer version negotiation, and their clients remember the negotiated
+    // version.
+    let versioned = if let Some(version) = version {
+        let min_version_doc = format!(
+            "Sets the oldest version of `{}` that clients can negotiate. Defaults to 1.",
+            ident
+        );
+        let version_doc = format!("The current version of the `{}` service.", ident);
+        let negotiate_doc = format!(
+            "Negotiates the newest version of `{}` supported by both the client and server, which \
+             determines the RPCs the client can call. Until a version is negotiated, the client \
+             assumes the server supports version {}.",
+            ident, version
+        );
+        let negotiate_error = format!("no version of {} is supported by the server", service_name);
+        // Removed rpcs are served as long as the server supports a version that has them.
+        let removed_arms: Vec<TokenStream2> = rpcs
+            .iter()
+            .zip(camel_case_idents.iter())
+            .filter_map(|(rpc, camel_case_ident)| {
+                let removed = rpc.removed.as_ref()?.value() as u32;
+                let unavailable = format!(
+                    "`{}` was removed in version {} of {}",
+                    rpc.ident, removed, service_name
+                );
+                Some(quote! {
+                    #request_ident::#camel_case_ident { .. } if self.min_version >= #removed => {
+                        Some(tarpc::ServerError::new(
+                            std::io::ErrorKind::InvalidInput,
+                            Some(#unavailable.into()),
+                        ))
+                    }
+                })
+            })
+            .collect();
+        let reject_fn = if removed_arms.is_empty() {
+            quote!()
+        } else {
+            quote! {
+                fn reject(&self, req: &#request_ident) -> Option<tarpc::ServerError> {
+                    match req {
+                        #( #removed_arms )*
+                        _ => None,
+                    }
                 }
             }
+        };
+        Versioned {
+            name_arm: quote!(#request_ident::__Negotiate { .. } => "__negotiate",),
+            response_name_arm: quote!(#response_ident::__Negotiate(_) => "__negotiate",),
+            serve_arm: quote! {
+                #request_ident::__Negotiate { min_version, max_version } => {
+                    let version = std::cmp::min(max_version, #version);
+                    #response_fut_ident::__Negotiate(
+                        if version >= std::cmp::max(min_version, self.min_version) {
+                            Some(version)
+                        } else {
+                            None
+                        })
+                }
+            },
+            fut_variant: quote!(#[doc(hidden)] __Negotiate(Option<u32>),),
+            poll_arm: quote! {
+                #response_fut_ident::__Negotiate(version) =>
+                    std::task::Poll::Ready(#response_ident::__Negotiate(*version)),
+            },
+            server_field: quote!(min_version: u32,),
+            server_init: quote!(min_version: 1,),
+            server_impl: quote! {
+                impl<S> #server_ident<S> {
+                    #[doc = #min_version_doc]
+                    #vis fn min_version(mut self, min_version: u32) -> Self {
+                        self.min_version = min_version;
+                        self
+                    }
+                }
+            },
+            reject_fn,
+            client_field: quote!(, Option<u32>),
+            client_init: quote!(, None),
+            client_impl: quote! {
+                impl #client_ident {
+                    #[doc = #version_doc]
+                    #vis const VERSION: u32 = #version;
+                }
+
+                impl<C> #client_ident<C> {
+                    /// Returns the negotiated version, if any.
+                    #vis fn version(&self) -> Option<u32> {
+                        self.1
+                    }
+                }
 
+                impl<C> #client_ident<C>
+                    where for<'a> C: tarpc::Client<'a, #request_ident, Response = #response_ident>
+                {
+                    #[doc = #negotiate_doc]
+                    #vis fn negotiate(&mut self, ctx: tarpc::context::Context)
+                        -> impl std::future::Future<Output = std::io::Result<u32>> + '_ {
+                        let request = #request_ident::__Negotiate {
+                            min_version: 1,
+                            max_version: #version,
+                        };
+                        let #client_ident(client, negotiated) = self;
+                        let resp = tarpc::Client::call(client, ctx, request);
+                        async move {
+                            match resp.await? {
+                                #response_ident::__Negotiate(Some(version)) => {
+                                    *negotiated = Some(version);
+                                    Ok(version)
+                                }
+                                #response_ident::__Negotiate(None) => Err(std::io::Error::new(
+                                    std::io::ErrorKind::InvalidData,
+                                    #negotiate_error,
+                                )),
+                                _ => unreachable!(),
+                            }
+                        }
+                    }
+                }
+            },
         }
+    } else {
+        Versioned::default()
+    };
+    let Versioned {
+        name_arm: negotiate_name_arm,
+        response_name_arm: negotiate_response_name_arm,
+        serve_arm: negotiate_serve_arm,
+        fut_variant: negotiate_fut_variant,
+        poll_arm: negotiate_poll_arm,
+        server_field,
+        server_init,
+        server_impl,
+        reject_fn,
+        client_field,
+        client_init,
+        client_impl,
+    } = versioned;
 
-        impl<C> #client_ident<C>
-            where for<'a> C: tarpc::Client<'a, #request_ident, Response = #response_ident>
-        {
-            #(
-                #[allow(unused)]
-                #( #method_attrs )*
-                #vis_repeated fn #method_names(&mut self, ctx: tarpc::context::Context, #args)
-                    -> impl std::future::Future<Output = std::io::Result<#outputs>> + '_ {
-                    let request = #request_ident_repeated2::#camel_case_idents { #arg_vars };
-                    let resp = tarpc::Client::call(&mut self.0, ctx, request);
-                    async move {
-                        match resp.await? {
-                            #response_ident_repeated2::#camel_case_idents2(msg) => std::result::Result::Ok(msg),
-                            _ => unreachable!(),
+    // Client-only and server-only builds of tarpc leave out the half of the service they don't
+    // have the modules for.
+    let (serve_fn, server_items) = if cfg!(feature = "server") {
+        let serve_fn = quote! {
+            /// Returns a serving function to use with tarpc::server::Server.
+            fn serve(#receiver) -> #server_ident<#self_ty> {
+                #server_ident { service: self, #server_init }
+            }
+        };
+        let server_items = quote! {
+            #[doc = #server_doc]
+            #[derive(Clone)]
+            #vis struct #server_ident<S> {
+                service: S,
+                #server_field
+            }
+
+            #server_impl
+
+            impl<S> tarpc::server::Serve<#request_ident> for #server_ident<#service_ty>
+                where S: #ident
+            {
+                type Resp = #response_ident;
+                type Fut = #response_fut_ident<S>;
+
+                fn serve(self, ctx: tarpc::context::Context, req: #request_ident) -> Self::Fut {
+                    self.serve_with_sink(ctx, req, tarpc::server::ResponseSink::disconnected())
+                }
+
+                #[allow(unused_variables)]
+                fn serve_with_sink(
+                    self,
+                    ctx: tarpc::context::Context,
+                    req: #request_ident,
+                    sink: tarpc::server::ResponseSink<#response_ident>,
+                ) -> Self::Fut {
+                    match req {
+                        #( #serve_arms )*
+                        #negotiate_serve_arm
+                        #( #reserved_arms )*
+                    }
+                }
+
+                fn method_name(&self, req: &#request_ident) -> Option<&'static str> {
+                    Some(tarpc::schema::Introspect::method_name(req))
+                }
+
+                fn service_name(&self) -> Option<&'static str> {
+                    Some(#service_name)
+                }
+
+                #is_inline
+
+                #reject_fn
+
+                #args_hash
+
+                #size_methods
+
+                #debug_methods
+            }
+
+            /// A future resolving to a server response.
+            #vis enum #response_fut_ident<S: #ident> {
+                #( #[doc = #response_fut_docs] #camel_case_idents(#response_fut_types), )*
+                #negotiate_fut_variant
+            }
+
+            impl<S: #ident> std::fmt::Debug for #response_fut_ident<S> {
+                fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+                    fmt.debug_struct(#response_fut_name).finish()
+                }
+            }
+
+            impl<S: #ident> std::future::Future for #response_fut_ident<S> {
+                type Output = #response_ident;
+
+                fn poll(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+                    -> std::task::Poll<#response_ident>
+                {
+                    unsafe {
+                        match std::pin::Pin::get_unchecked_mut(self) {
+                            #( #poll_arms )*
+                            #negotiate_poll_arm
                         }
                     }
                 }
-            )*
+            }
+        };
+        (serve_fn, server_items)
+    } else {
+        (quote!(), quote!())
+    };
+    let client_items = if cfg!(feature = "client") {
+        quote! {
+            #[allow(unused)]
+            #[derive(Clone, Debug)]
+            /// The client stub that makes RPC calls to the server. Exposes a Future interface.
+            #vis struct #client_ident<C = tarpc::client::Channel<#request_ident, #response_ident>>(C #client_field);
+
+            impl<C> From<C> for #client_ident<C>
+                where for <'a> C: tarpc::Client<'a, #request_ident, Response = #response_ident>
+            {
+                fn from(client: C) -> Self {
+                    #client_ident(client #client_init)
+                }
+            }
+
+            impl #client_ident {
+                /// Returns a new client stub that sends requests over the given transport.
+                #vis fn new<T>(config: tarpc::client::Config, transport: T)
+                    -> tarpc::client::NewClient<
+                        Self,
+                        tarpc::client::channel::RequestDispatch<#request_ident, #response_ident, T>>
+                where
+                    T: tarpc::Transport<tarpc::ClientMessage<#request_ident>, tarpc::Response<#response_ident>>
+                {
+                    let new_client = tarpc::client::new(config, transport);
+                    let channel = new_client
+                        .client
+                        .with_method_names(<#request_ident as tarpc::schema::Introspect>::method_name);
+                    tarpc::client::NewClient {
+                        client: #client_ident(channel #client_init),
+                        dispatch: new_client.dispatch,
+                    }
+                }
+
+                #( #prepare_fns )*
+            }
+
+            impl<C> #client_ident<C>
+                where for<'a> C: tarpc::Client<'a, #request_ident, Response = #response_ident>
+            {
+                #( #client_methods )*
+            }
+
+            #client_impl
         }
+    } else {
+        quote!()
     };
 
-    tokens.into()
+    quote! {
+        #( #attrs )*
+        #trait_vis trait #ident: #supertrait {
+            #( #types_and_fns )*
+
+            #serve_fn
+        }
+
+        #server_items
+
+        /// The request sent over the wire from the client to the server.
+        #[derive(#request_derives)]
+        #derive_serialize
+        #( #[serde(#serde_attrs)] )*
+        #vis enum #request_ident {
+            #( #request_variants ),*
+        }
+
+        /// The response sent over the wire from the server to the client.
+        #[derive(#derives)]
+        #derive_serialize
+        #( #[serde(#serde_attrs)] )*
+        #vis enum #response_ident {
+            #( #response_variants ),*
+        }
+
+        #client_items
+
+        #request_debug
+
+        impl tarpc::schema::Introspect for #request_ident {
+            const METHODS: &'static [tarpc::schema::Method] = &[ #( #methods ),* ];
+
+            fn method_name(&self) -> &'static str {
+                match self {
+                    #( #method_name_arms )*
+                    #negotiate_name_arm
+                    #( #reserved_name_arms )*
+                }
+            }
+        }
+
+        impl tarpc::schema::IntrospectResponse for #response_ident {
+            fn method_name(&self) -> &'static str {
+                match self {
+                    #( #response_name_arms )*
+                    #negotiate_response_name_arm
+                    #( #reserved_response_name_arms )*
+                }
+            }
+        }
+
+        #schema
+
+        #mock
+
+        #from_fn
+    }
 }
 
+/// Rewrites each `async fn` in a service impl into a fn returning a boxed future, and defines the
+/// future's associated type, e.g.
+///
+/// ```ignore
+/// async fn hello(self, _: context::Context, name: String) -> String { ... }
+/// ```
+///
+/// expands to
+///
+/// ```ignore
+/// type HelloFut = Pin<Box<dyn Future<Output = String> + Send>>;
+/// fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+///     Box::pin(async move { ... })
+/// }
+/// ```
+///
+/// With `#[tarpc::server(local)]`, the futures needn't be `Send`, for services served on the
+/// current thread with `respond_with_local`.
+#[proc_macro_attribute]
+pub fn server(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let local = match parse_macro_input!(attr as Option<Ident>) {
+        None => false,
+        Some(ref ident) if ident == "local" => true,
+        Some(ident) => {
+            return syn::Error::new(
+                ident.span(),
+                "tarpc::server only supports the argument `local`",
+            )
+            .to_compile_error()
+            .into();
+        }
+    };
+    let send = if local { quote!() } else { quote!(+ Send) };
+    let mut item = parse_macro_input!(input as ItemImpl);
+
+    let defined_types: HashSet<String> = item
+        .items
+        .iter()
+        .filter_map(|item| match item {
+            ImplItem::Type(ty) => Some(ty.ident.to_string()),
+            _ => None,
+        })
+        .collect();
+    let mut fut_types = Vec::<ImplItem>::new();
+    for impl_item in &mut item.items {
+        let method = match impl_item {
+            ImplItem::Method(method) if method.sig.asyncness.is_some() => method,
+            _ => continue,
+        };
+        let output = match method.sig.decl.output {
+            ReturnType::Type(_, ref ty) => quote!(#ty),
+            ReturnType::Default => quote!(()),
+        };
+        let fut_ident = Ident::new(
+            &format!("{}Fut", snake_to_camel(&method.sig.ident.to_string())),
+            method.sig.ident.span(),
+        );
+        if !defined_types.contains(&fut_ident.to_string()) {
+            fut_types.push(parse_quote! {
+                type #fut_ident = std::pin::Pin<Box<
+                    dyn std::future::Future<Output = #output> #send>>;
+            });
+        }
+        let block = &method.block;
+        method.sig.asyncness = None;
+        method.sig.decl.output = parse_quote!(-> Self::#fut_ident);
+        // Annotating the output lets `?` and `return` in the body infer their types.
+        method.block = parse_quote!({
+            Box::pin(async move {
+                let output: #output = #block;
+                output
+            })
+        });
+    }
+    item.items.extend(fut_types);
+
+    quote!(#item).into()
+}
+
+/// Prints a type as it would be written by hand, e.g. `Vec<u8>` rather than `Vec < u8 >`.
+fn type_string(ty: &Type) -> String {
+    let mut s = quote!(#ty).to_string();
+    for (from, to) in &[
+        (" :: ", "::"),
+        (":: ", "::"),
+        (" <", "<"),
+        ("< ", "<"),
+        (" >", ">"),
+        (" ,", ","),
+        ("( ", "("),
+        (" )", ")"),
+        ("[ ", "["),
+        (" ]", "]"),
+        (" ;", ";"),
+        ("& ", "&"),
+    ] {
+        s = s.replace(from, to);
+    }
+    s
+}
+
+fn camel_to_screaming_snake(ident_str: &str) -> String {
+    let mut snake = String::new();
+    let mut chars = ident_str.chars().peekable();
+    let mut last_char_was_lowercase = false;
+    while let Some(c) = chars.next() {
+        let next_is_lowercase = chars.peek().map_or(false, |c| c.is_lowercase());
+        if c.is_uppercase()
+            && !snake.is_empty()
+            && (last_char_was_lowercase || next_is_lowercase)
+            && !snake.ends_with('_')
+        {
+            snake.push('_');
+        }
+        last_char_was_lowercase = c.is_lowercase() || c.is_numeric();
+        snake.extend(c.to_uppercase());
+    }
+    snake
+}
+
+/// Keep in sync with `tarpc::schema::snake_to_camel`, which names RPCs in schemas the way the
+/// generated variants are named; the macro can't depend on tarpc itself.
 fn snake_to_camel(ident_str: &str) -> String {
     let mut camel_ty = String::new();
-    let chars = ident_str.chars();
-
     let mut last_char_was_underscore = true;
-    for c in chars {
+    for c in ident_str.chars() {
         match c {
             '_' => last_char_was_underscore = true,
             c if last_char_was_underscore => {
@@ -402,31 +2477,148 @@ fn snake_to_camel(ident_str: &str) -> String {
             c => camel_ty.extend(c.to_lowercase()),
         }
     }
-
     camel_ty
 }
 
 #[test]
-fn snake_to_camel_basic() {
-    assert_eq!(snake_to_camel("abc_def"), "AbcDef");
+fn snake_to_camel_matches_schema() {
+    for name in &[
+        "abc_def", "abc_def_", "_abc_def", "abc__def", "aBc_dEf", "x",
+    ] {
+        assert_eq!(snake_to_camel(name), tarpc::schema::snake_to_camel(name));
+    }
 }
 
 #[test]
-fn snake_to_camel_underscore_suffix() {
-    assert_eq!(snake_to_camel("abc_def_"), "AbcDef");
+fn camel_to_screaming_snake_basic() {
+    assert_eq!(camel_to_screaming_snake("HelloWorld"), "HELLO_WORLD");
 }
 
 #[test]
-fn snake_to_camel_underscore_prefix() {
-    assert_eq!(snake_to_camel("_abc_def"), "AbcDef");
+fn camel_to_screaming_snake_acronym() {
+    assert_eq!(camel_to_screaming_snake("HTTPServer"), "HTTP_SERVER");
 }
 
 #[test]
-fn snake_to_camel_underscore_consecutive() {
-    assert_eq!(snake_to_camel("abc__def"), "AbcDef");
+fn camel_to_screaming_snake_digits_and_underscores() {
+    assert_eq!(camel_to_screaming_snake("V2Api"), "V2_API");
+    assert_eq!(camel_to_screaming_snake("My_Service"), "MY_SERVICE");
+}
+
+#[cfg(test)]
+fn service_error(service: &str) -> String {
+    match syn::parse_str::<Service>(service) {
+        Ok(_) => panic!("expected an error parsing {}", service),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn duplicate_method() {
+    assert_eq!(
+        service_error("trait S { async fn foo(); async fn foo(x: i32); }"),
+        "duplicate method `foo`"
+    );
+    assert_eq!(
+        service_error("trait S { async fn foo_bar(); async fn foo__bar(); }"),
+        "method `foo__bar` conflicts with method `foo_bar`: both generate the request variant \
+         `FooBar`"
+    );
+}
+
+#[test]
+fn reserved_names() {
+    assert_eq!(
+        service_error("trait S { async fn from(); }"),
+        "method name conflicts with generated fn `SClient::from`"
+    );
+    assert_eq!(
+        service_error("trait S { async fn r#type(); }"),
+        "raw identifiers aren't supported as method names"
+    );
+    assert_eq!(
+        service_error("trait S { async fn foo(ctx: u64); }"),
+        "`ctx` is reserved for use by generated code"
+    );
+    assert_eq!(
+        service_error("trait S { async fn foo(x: u64, x: u64); }"),
+        "duplicate argument `x`"
+    );
+}
+
+#[test]
+fn unsupported_types() {
+    assert_eq!(
+        service_error("trait S { async fn foo(x: &str); }"),
+        "references in RPC types must have the `'static` lifetime"
+    );
+    assert_eq!(
+        service_error("trait S { async fn foo(x: Vec<impl Clone>); }"),
+        "`impl Trait` isn't supported in RPC types"
+    );
+    assert_eq!(
+        service_error("trait S { async fn foo() -> Cow<'a, str>; }"),
+        "lifetimes in RPC types must be `'static`"
+    );
+    assert_eq!(
+        service_error("trait S { async fn foo() -> Option<Self::Item>; }"),
+        "`Self` isn't supported in RPC types"
+    );
+    assert!(syn::parse_str::<Service>(
+        "trait S { async fn foo(x: &'static str, y: [Option<(u8, String)>; 2]) -> Cow<'static, str>; }"
+    )
+    .is_ok());
+}
+
+#[test]
+fn invalid_versions() {
+    assert_eq!(
+        service_error("trait S { #[since(0)] async fn foo(); }"),
+        "versions start at 1"
+    );
+    assert_eq!(
+        service_error("trait S { #[since(2)] #[removed(2)] async fn foo(); }"),
+        "a method must be removed in a later version than it was added"
+    );
+    assert_eq!(
+        service_error("trait S { #[removed(1)] async fn foo(); }"),
+        "a method must be removed in a later version than it was added"
+    );
+}
+
+#[test]
+fn module_without_services() {
+    match syn::parse_str::<ServiceInput>("mod api { struct Page; }") {
+        Ok(_) => panic!("expected an error parsing a module without services"),
+        Err(e) => assert_eq!(e.to_string(), "module does not contain any service traits"),
+    }
+}
+
+#[test]
+fn types_only_options() {
+    let error = match syn::parse_str::<Options>("types_only = true, mock = true") {
+        Ok(_) => panic!("expected an error parsing options"),
+        Err(e) => e.to_string(),
+    };
+    assert_eq!(error, "`mock` has no effect when `types_only = true`");
+    assert!(syn::parse_str::<Options>("types_only = true, mock = false").is_ok());
+}
+
+#[test]
+fn prepared_requires_serde() {
+    let error = match syn::parse_str::<Options>("derive_serde = false, prepared = true") {
+        Ok(_) => panic!("expected an error parsing options"),
+        Err(e) => e.to_string(),
+    };
+    assert_eq!(
+        error,
+        "`prepared = true` requires `derive_serde`, to serialize prepared requests"
+    );
 }
 
 #[test]
-fn snake_to_camel_capital_in_middle() {
-    assert_eq!(snake_to_camel("aBc_dEf"), "AbcDef");
+fn wire_case_convert() {
+    assert_eq!(WireCase::Camel.convert("get_user_id"), "getUserId");
+    assert_eq!(WireCase::Camel.convert("hello"), "hello");
+    assert_eq!(WireCase::Snake.convert("get_user_id"), "get_user_id");
 }
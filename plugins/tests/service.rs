@@ -49,5 +49,353 @@ fn syntax() {
         async fn no_arg_implicit_return_error();
         #[doc = "attr"]
         async fn one_arg_implicit_return_error(foo: String);
+        #[oneway]
+        async fn one_way(foo: String);
+        #[oneway]
+        #[doc = "attr"]
+        async fn one_way_explicit_unit() -> ();
+        #[stream]
+        async fn stream(foo: String) -> String;
+        #[stream]
+        #[doc = "attr"]
+        async fn stream_tuple() -> (String, u64);
+        #[throws(String)]
+        async fn throws(foo: String) -> i32;
+        #[doc = "attr"]
+        #[throws(std::vec::Vec<u8>)]
+        async fn throws_no_return();
+    }
+}
+
+#[test]
+fn explicit_ids() {
+    #[tarpc::service(derive_serde = false)]
+    trait Ids {
+        #[id = 3]
+        async fn three();
+        #[id = 0]
+        async fn zero() -> String;
+        #[doc = "attr"]
+        #[id = 1]
+        async fn one(s: String);
+    }
+}
+
+#[test]
+#[allow(dead_code)]
+fn schema() {
+    use tarpc::schema::MethodKind;
+
+    #[tarpc::service(schema = true)]
+    trait FileStore {
+        async fn read(path: String, range: Option<(u64, u64)>) -> Vec<u8>;
+        #[oneway]
+        async fn touch(path: String);
+        #[stream]
+        async fn list(prefix: &'static str) -> std::path::PathBuf;
+    }
+
+    assert_eq!(FILE_STORE_SCHEMA.name, "FileStore");
+    let read = FILE_STORE_SCHEMA.method("read").unwrap();
+    assert_eq!(read.id, 0);
+    assert_eq!(read.wire_name, "Read");
+    assert_eq!(read.kind, MethodKind::Unary);
+    assert_eq!(read.args[1].name, "range");
+    assert_eq!(read.args[1].ty, "Option<(u64, u64)>");
+    assert_eq!(read.output, "Vec<u8>");
+    assert_eq!(FILE_STORE_SCHEMA.methods[1].kind, MethodKind::OneWay);
+    assert_eq!(FILE_STORE_SCHEMA.methods[1].output, "()");
+    assert_eq!(
+        FILE_STORE_SCHEMA.methods[2].to_string(),
+        "#[id = 2] #[stream] async fn list(prefix: &'static str) -> std::path::PathBuf;"
+    );
+}
+
+#[test]
+#[allow(dead_code)]
+fn redact() {
+    #[tarpc::service(derive_serde = false)]
+    trait Auth {
+        async fn login(user: String, #[redact] password: String) -> bool;
+    }
+
+    let request = AuthRequest::Login {
+        user: "tim".into(),
+        password: "hunter2".into(),
+    };
+    assert_eq!(
+        format!("{:?}", request),
+        r#"Login { user: "tim", password: <redacted> }"#
+    );
+}
+
+#[test]
+#[allow(dead_code)]
+fn introspect() {
+    use tarpc::schema::{Introspect, MethodKind};
+
+    #[tarpc::service(derive_serde = false)]
+    trait Kv {
+        #[id = 1]
+        async fn get(key: String) -> Option<String>;
+        #[id = 0]
+        #[oneway]
+        async fn put(key: String, value: String);
+    }
+
+    let names: Vec<_> = KvRequest::METHODS.iter().map(|m| &*m.name).collect();
+    assert_eq!(names, ["get", "put"]);
+    let put = KvRequest::Put {
+        key: "k".into(),
+        value: "v".into(),
+    };
+    assert_eq!(put.method_name(), "put");
+    let method = put.method().unwrap();
+    assert_eq!(method.id, 0);
+    assert_eq!(method.kind, MethodKind::OneWay);
+    assert_eq!(method.args[1].ty, "String");
+    let get = KvRequest::Get { key: "k".into() };
+    assert_eq!(get.method_name(), "get");
+    assert_eq!(get.method().unwrap().id, 1);
+}
+
+#[test]
+#[allow(dead_code)]
+fn custom_derives() {
+    #[tarpc::service(derive_serde = false, derive(Clone, PartialEq, std::hash::Hash))]
+    trait Derives {
+        async fn add(x: i32, y: i32) -> i32;
     }
+
+    let request = DerivesRequest::Add { x: 1, y: 2 };
+    assert!(request.clone() == request);
+    assert!(DerivesResponse::Add(3) != DerivesResponse::Add(4));
+
+    #[tarpc::service(derive_serde = false, derive())]
+    trait NoDerives {
+        async fn add(x: i32, y: i32) -> i32;
+    }
+}
+
+#[cfg(feature = "serde1")]
+#[test]
+fn serde_attrs() {
+    #[tarpc::service(
+        derive(Clone),
+        serde(rename_all = "snake_case"),
+        serde(deny_unknown_fields)
+    )]
+    trait SerdeAttrs {
+        async fn add(x: i32, y: i32) -> i32;
+    }
+}
+
+mod renamed {
+    #[tarpc::service(
+        vis = "pub(crate)",
+        client = "Stub",
+        request = "Req",
+        response = "Resp",
+        serve = "Server"
+    )]
+    pub trait Renamed {
+        async fn hello(name: String) -> String;
+    }
+}
+
+#[test]
+fn renamed() {
+    use futures::future::{ready, Ready};
+    use renamed::{Renamed, Req, Resp, Server, Stub};
+    use tarpc::server::Serve;
+
+    #[derive(Clone)]
+    struct HelloServer;
+
+    impl Renamed for HelloServer {
+        type HelloFut = Ready<String>;
+        fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+            ready(name)
+        }
+    }
+
+    let server: Server<HelloServer> = HelloServer.serve();
+    let resp = server.serve(context::current(), Req::Hello { name: "hi".into() });
+    assert!(match futures::executor::block_on(resp) {
+        Resp::Hello(name) => name == "hi",
+    });
+    let _: Option<Stub> = None;
+}
+
+/// The items generated for a service are documented by the doc comments of its rpcs.
+#[deny(missing_docs)]
+pub mod documented {
+    /// Greets people.
+    #[tarpc::service(schema = true, mock = true)]
+    pub trait Greeter {
+        /// Says hello.
+        async fn hello(name: String) -> String;
+        /// Waves at someone.
+        #[oneway]
+        async fn wave(name: String);
+        /// Lists the people greeted so far.
+        #[stream]
+        async fn greeted() -> String;
+    }
+}
+
+#[test]
+#[allow(dead_code)]
+fn versioned() {
+    use tarpc::schema::Introspect;
+
+    #[tarpc::service(version = 4, schema = true, mock = true)]
+    trait Accounts {
+        async fn login(user: String, #[redact] password: String) -> bool;
+        #[since(2)]
+        #[stream]
+        async fn list() -> String;
+        #[removed(3)]
+        #[oneway]
+        async fn logout(user: String);
+    }
+
+    assert_eq!(AccountsClient::VERSION, 4);
+    let login = ACCOUNTS_SCHEMA.method("login").unwrap();
+    assert_eq!(login.id, 0);
+    assert_eq!(login.since, None);
+    assert_eq!(ACCOUNTS_SCHEMA.method("list").unwrap().since, Some(2));
+    assert_eq!(
+        ACCOUNTS_SCHEMA.methods[2].to_string(),
+        "#[id = 2] #[removed(3)] #[oneway] async fn logout(user: String);"
+    );
+
+    let negotiate = AccountsRequest::__Negotiate {
+        min_version: 1,
+        max_version: 4,
+    };
+    assert_eq!(negotiate.method_name(), "__negotiate");
+    assert!(negotiate.method().is_none());
+
+    let mut client = AccountsClient::from(MockAccountsClient::new());
+    assert_eq!(
+        futures::executor::block_on(client.negotiate(context::current())).unwrap(),
+        4
+    );
+    assert_eq!(client.version(), Some(4));
+    let err =
+        futures::executor::block_on(client.logout(context::current(), "tim".into())).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "`logout` is not available in version 4 of Accounts"
+    );
+}
+
+#[test]
+#[allow(dead_code)]
+fn module() {
+    #[tarpc::service(schema = true)]
+    mod family {
+        #[derive(Debug)]
+        #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+        pub struct Page {
+            pub offset: u64,
+        }
+
+        pub trait Data {
+            async fn list(page: Page) -> Vec<String>;
+        }
+
+        pub trait Health {
+            async fn healthy() -> bool;
+        }
+    }
+
+    assert_eq!(family::DATA_SCHEMA.methods[0].args[0].ty, "Page");
+    assert_eq!(family::HEALTH_SCHEMA.name, "Health");
+}
+
+#[test]
+#[allow(dead_code)]
+fn from_fn() {
+    use futures::{executor::block_on, stream, StreamExt};
+    use std::sync::Arc;
+    use tarpc::server::Serve;
+
+    #[tarpc::service(derive_serde = false, from_fn = true)]
+    trait Greet {
+        async fn greet(name: String) -> String;
+    }
+
+    let greeter = GreetFn::from_fn(|_, name| async move { format!("Hello, {}!", name) });
+    let resp = greeter.serve().serve(
+        context::current(),
+        GreetRequest::Greet { name: "Tim".into() },
+    );
+    assert!(match block_on(resp) {
+        GreetResponse::Greet(greeting) => greeting == "Hello, Tim!",
+    });
+
+    #[tarpc::service(derive_serde = false, from_fn = true, arc_self = true)]
+    trait Count {
+        #[stream]
+        async fn count(n: u32) -> u32;
+    }
+
+    let counter = CountFn::from_fn(|_, n| stream::iter(0..n));
+    let items: Vec<u32> = block_on(Arc::new(counter).count(context::current(), 3).collect());
+    assert_eq!(items, [0, 1, 2]);
+}
+
+/// Peers that can't depend on tarpc share the request and response types of a service.
+mod types_only {
+    #[tarpc::service(types_only = true, derive(Debug, Clone, PartialEq))]
+    pub trait Sensor {
+        #[id = 2]
+        async fn read(#[redact] key: u64) -> i32;
+        #[id = 0]
+        #[throws(u8)]
+        async fn calibrate(offset: i32);
+    }
+}
+
+#[test]
+fn types_only() {
+    use types_only::{SensorRequest, SensorResponse};
+
+    let request = SensorRequest::Read { key: 7 };
+    assert_eq!(request.clone(), request);
+    assert_eq!(format!("{:?}", request), "Read { key: <redacted> }");
+    assert_eq!(
+        SensorResponse::Calibrate(Err(3)),
+        SensorResponse::Calibrate(Err(3))
+    );
+}
+
+#[cfg(feature = "serde1")]
+#[test]
+fn wire_case() -> serde_json::Result<()> {
+    use tarpc::schema::Introspect;
+
+    #[tarpc::service(wire_case = "camelCase")]
+    trait Users {
+        async fn get_user(user_id: u64, include_email: bool) -> String;
+        #[name = "remove"]
+        async fn delete_user(user_id: u64);
+    }
+
+    let request = UsersRequest::GetUser {
+        user_id: 1,
+        include_email: true,
+    };
+    assert_eq!(
+        serde_json::to_string(&request)?,
+        r#"{"getUser":{"userId":1,"includeEmail":true}}"#
+    );
+    assert_eq!(
+        serde_json::to_string(&UsersRequest::DeleteUser { user_id: 2 })?,
+        r#"{"remove":{"userId":2}}"#
+    );
+    assert_eq!(UsersRequest::METHODS[0].wire_name, "getUser");
+    Ok(())
 }
@@ -0,0 +1,184 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serializes messages and frames them for byte-oriented links.
+//!
+//! An [`Encode`] and [`Decode`] pair is a serialization format, such as bincode, which the
+//! peers of a connection agree on. [`encode_frame`] and [`decode_frame`] frame a message the
+//! way tarpc's bincode and JSON transports do: prefixed with its length, as a 4-byte big-endian
+//! integer. Firmware reading from a UART or a socket appends what it reads to a buffer and
+//! calls [`decode_frame`] until it returns a message, then drops the bytes it consumed.
+
+use alloc::vec::Vec;
+use core::{convert::TryFrom, fmt};
+
+/// The length of the prefix of a frame, which holds the length of the message that follows.
+pub const LENGTH_PREFIX: usize = 4;
+
+/// Serializes messages of type `T`.
+pub trait Encode<T> {
+    /// The error serializing a message.
+    type Error;
+
+    /// Appends the serialization of `item` to `dst`.
+    fn encode(&mut self, item: &T, dst: &mut Vec<u8>) -> Result<(), Self::Error>;
+}
+
+/// Deserializes messages of type `T`.
+pub trait Decode<T> {
+    /// The error deserializing a message.
+    type Error;
+
+    /// Deserializes a message from all of `src`.
+    fn decode(&mut self, src: &[u8]) -> Result<T, Self::Error>;
+}
+
+/// An error framing a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError<E> {
+    /// The message is longer than a frame's prefix can hold, or than the decoder accepts.
+    TooLong(usize),
+    /// The codec failed to serialize or deserialize the message.
+    Codec(E),
+}
+
+impl<E: fmt::Display> fmt::Display for FrameError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::TooLong(len) => write!(f, "a message of {} bytes is too long", len),
+            FrameError::Codec(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Appends `item`, serialized by `codec` and prefixed with its length, to `dst`.
+pub fn encode_frame<C, T>(
+    codec: &mut C,
+    item: &T,
+    dst: &mut Vec<u8>,
+) -> Result<(), FrameError<C::Error>>
+where
+    C: Encode<T>,
+{
+    let start = dst.len();
+    dst.extend_from_slice(&[0; LENGTH_PREFIX]);
+    if let Err(e) = codec.encode(item, dst) {
+        dst.truncate(start);
+        return Err(FrameError::Codec(e));
+    }
+    let len = dst.len() - start - LENGTH_PREFIX;
+    match u32::try_from(len) {
+        Ok(prefix) => {
+            dst[start..start + LENGTH_PREFIX].copy_from_slice(&prefix.to_be_bytes());
+            Ok(())
+        }
+        Err(_) => {
+            dst.truncate(start);
+            Err(FrameError::TooLong(len))
+        }
+    }
+}
+
+/// Decodes the frame at the start of `src` with `codec`, returning the message and the number
+/// of bytes it took up, or `None` if `src` doesn't hold a whole frame yet.
+///
+/// Frames whose messages are longer than `max_len` fail with [`TooLong`](FrameError::TooLong)
+/// as soon as their prefix is read, so a reader with a fixed buffer can reject them before
+/// buffering them.
+pub fn decode_frame<C, T>(
+    codec: &mut C,
+    src: &[u8],
+    max_len: usize,
+) -> Result<Option<(T, usize)>, FrameError<C::Error>>
+where
+    C: Decode<T>,
+{
+    if src.len() < LENGTH_PREFIX {
+        return Ok(None);
+    }
+    let mut prefix = [0; LENGTH_PREFIX];
+    prefix.copy_from_slice(&src[..LENGTH_PREFIX]);
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > max_len {
+        return Err(FrameError::TooLong(len));
+    }
+    let end = LENGTH_PREFIX + len;
+    if src.len() < end {
+        return Ok(None);
+    }
+    let item = codec
+        .decode(&src[LENGTH_PREFIX..end])
+        .map_err(FrameError::Codec)?;
+    Ok(Some((item, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Serializes byte strings as themselves.
+    #[derive(Debug)]
+    struct Raw;
+
+    impl Encode<Vec<u8>> for Raw {
+        type Error = ();
+
+        fn encode(&mut self, item: &Vec<u8>, dst: &mut Vec<u8>) -> Result<(), ()> {
+            dst.extend_from_slice(item);
+            Ok(())
+        }
+    }
+
+    impl Decode<Vec<u8>> for Raw {
+        type Error = ();
+
+        fn decode(&mut self, src: &[u8]) -> Result<Vec<u8>, ()> {
+            if src.is_empty() {
+                return Err(());
+            }
+            Ok(src.to_vec())
+        }
+    }
+
+    #[test]
+    fn frames_roundtrip() {
+        let mut buf = vec![];
+        encode_frame(&mut Raw, &b"hello".to_vec(), &mut buf).unwrap();
+        encode_frame(&mut Raw, &b"world".to_vec(), &mut buf).unwrap();
+        assert_eq!(&buf[..9], b"\0\0\0\x05hello");
+
+        let (first, len) = decode_frame(&mut Raw, &buf, 16).unwrap().unwrap();
+        assert_eq!(first, b"hello");
+        let (second, _) = decode_frame(&mut Raw, &buf[len..], 16).unwrap().unwrap();
+        assert_eq!(second, b"world");
+    }
+
+    #[test]
+    fn partial_frames_need_more_bytes() {
+        let mut buf = vec![];
+        encode_frame(&mut Raw, &b"hello".to_vec(), &mut buf).unwrap();
+        for len in 0..buf.len() {
+            assert_eq!(decode_frame(&mut Raw, &buf[..len], 16), Ok(None));
+        }
+    }
+
+    #[test]
+    fn long_frames_are_rejected_from_their_prefix() {
+        assert_eq!(
+            decode_frame::<_, Vec<u8>>(&mut Raw, b"\0\0\x01\0", 16),
+            Err(FrameError::TooLong(256))
+        );
+    }
+
+    #[test]
+    fn codec_errors() {
+        assert_eq!(
+            decode_frame::<_, Vec<u8>>(&mut Raw, b"\0\0\0\0", 16),
+            Err(FrameError::Codec(()))
+        );
+    }
+}
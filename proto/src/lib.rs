@@ -0,0 +1,412 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+#![no_std]
+#![deny(missing_docs, missing_debug_implementations)]
+
+//! The messages tarpc clients and servers exchange, for peers without std, such as embedded
+//! firmware.
+//!
+//! The types here are the envelopes of the protocol: a [`ClientMessage`] carries a
+//! [`Request`] or a cancellation from client to server, and a [`Response`] carries a request's
+//! outcome back. With the `serde1` feature, they serialize exactly as `tarpc-lib`'s messages
+//! of the same names do, so a peer that builds frames with this crate talks to tarpc clients
+//! and servers over any transport that uses the same format. The [`codec`] module frames
+//! messages for byte-oriented links.
+//!
+//! The crate needs only `alloc`. Transports, executors, deadlines measured against a clock,
+//! and everything else that needs std stay in `tarpc-lib` and the transport crates, which
+//! convert their messages to and from these.
+
+extern crate alloc;
+
+pub mod codec;
+
+use alloc::{collections::BTreeMap, string::String};
+use core::{fmt, time::Duration};
+
+/// A message from a client to a server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClientMessage<T> {
+    /// A request for the server to handle.
+    Request(Request<T>),
+    /// A command to cancel an in-flight request.
+    Cancel {
+        /// The trace context of the cancellation.
+        trace_context: TraceContext,
+        /// The ID of the request to cancel.
+        request_id: u64,
+    },
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+/// A request from a client to a server. Serialized as an enum with a variant per version of its
+/// layout.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request<T> {
+    /// Trace context, deadline, and other cross-cutting concerns.
+    pub context: Context,
+    /// Uniquely identifies the request among the requests in flight on a connection.
+    pub id: u64,
+    /// The request body.
+    pub message: T,
+    /// Whether the request is one-way, i.e. the server sends no response to it.
+    pub one_way: bool,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+#[cfg(feature = "serde1")]
+impl<T: serde::Serialize> serde::Serialize for Request<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(serde::Serialize)]
+        #[serde(rename = "Request")]
+        enum Versions<'a, T> {
+            V1 {
+                context: &'a Context,
+                id: u64,
+                message: &'a T,
+                one_way: bool,
+            },
+        }
+
+        serde::Serialize::serialize(
+            &Versions::V1 {
+                context: &self.context,
+                id: self.id,
+                message: &self.message,
+                one_way: self.one_way,
+            },
+            serializer,
+        )
+    }
+}
+
+#[cfg(feature = "serde1")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Request<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "Request")]
+        enum Versions<T> {
+            V1 {
+                context: Context,
+                id: u64,
+                message: T,
+                one_way: bool,
+            },
+        }
+
+        let Versions::V1 {
+            context,
+            id,
+            message,
+            one_way,
+        } = Versions::deserialize(deserializer)?;
+        Ok(Request {
+            context,
+            id,
+            message,
+            one_way,
+            _non_exhaustive: (),
+        })
+    }
+}
+
+impl<T> Request<T> {
+    /// Returns a two-way request.
+    pub fn new(context: Context, id: u64, message: T) -> Self {
+        Request {
+            context,
+            id,
+            message,
+            one_way: false,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+/// The deadline, trace context, metadata, and baggage of a request.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Context {
+    /// The seconds since the Unix epoch by which the client expects the response. Peers without
+    /// a wall clock can leave it 0 and set [`time_remaining`](Context::time_remaining) instead.
+    pub deadline: u64,
+    /// Identifies the trace and span of the request.
+    pub trace_context: TraceContext,
+    /// Request-scoped key-value pairs read by the server.
+    pub metadata: BTreeMap<String, String>,
+    /// Request-scoped key-value pairs read by the server and forwarded on the requests it makes
+    /// while handling the request.
+    pub baggage: BTreeMap<String, String>,
+    /// The time left until the deadline when the request was sent. Servers prefer it to
+    /// [`deadline`](Context::deadline), since it doesn't depend on the peers' clocks agreeing.
+    pub time_remaining: Option<Duration>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Context {
+    /// Returns the context of a request that must complete within `time_remaining`, in the
+    /// given trace.
+    pub fn new(trace_context: TraceContext, time_remaining: Duration) -> Self {
+        Context {
+            trace_context,
+            time_remaining: Some(time_remaining),
+            ..Context::default()
+        }
+    }
+}
+
+/// Identifies a span of a trace. The default is a root span with zero IDs whose trace is
+/// recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct TraceContext {
+    /// Identifies the trace.
+    pub trace_id: u128,
+    /// Identifies the span within the trace.
+    pub span_id: u64,
+    /// The span of which this span is a child, if any.
+    pub parent_id: Option<u64>,
+    /// Whether the trace is recorded.
+    pub sampled: bool,
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        TraceContext {
+            trace_id: 0,
+            span_id: 0,
+            parent_id: None,
+            sampled: true,
+        }
+    }
+}
+
+/// A response from a server to a client. Serialized as an enum with a variant per version of its
+/// layout.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Response<T> {
+    /// The ID of the request being responded to.
+    pub request_id: u64,
+    /// The response body, or an error if the request failed.
+    pub message: Result<T, ServerError>,
+    /// Whether more responses to the same request will follow.
+    pub partial: bool,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+#[cfg(feature = "serde1")]
+impl<T: serde::Serialize> serde::Serialize for Response<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(serde::Serialize)]
+        #[serde(rename = "Response")]
+        enum Versions<'a, T> {
+            V1 {
+                request_id: u64,
+                message: &'a Result<T, ServerError>,
+                partial: bool,
+            },
+        }
+
+        serde::Serialize::serialize(
+            &Versions::V1 {
+                request_id: self.request_id,
+                message: &self.message,
+                partial: self.partial,
+            },
+            serializer,
+        )
+    }
+}
+
+#[cfg(feature = "serde1")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Response<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "Response")]
+        enum Versions<T> {
+            V1 {
+                request_id: u64,
+                message: Result<T, ServerError>,
+                partial: bool,
+            },
+        }
+
+        let Versions::V1 {
+            request_id,
+            message,
+            partial,
+        } = Versions::deserialize(deserializer)?;
+        Ok(Response {
+            request_id,
+            message,
+            partial,
+            _non_exhaustive: (),
+        })
+    }
+}
+
+impl<T> Response<T> {
+    /// Returns the final response to request `request_id`.
+    pub fn new(request_id: u64, message: Result<T, ServerError>) -> Self {
+        Response {
+            request_id,
+            message,
+            partial: false,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+/// An error response from a server to a client.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct ServerError {
+    /// The type of error that occurred to fail the request.
+    pub kind: ErrorKind,
+    /// A message describing more detail about the error that occurred.
+    pub detail: Option<String>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl ServerError {
+    /// Returns an error of the given kind.
+    pub fn new(kind: ErrorKind, detail: Option<String>) -> Self {
+        ServerError {
+            kind,
+            detail,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.detail {
+            Some(ref detail) => write!(f, "{:?}: {}", self.kind, detail),
+            None => write!(f, "{:?}", self.kind),
+        }
+    }
+}
+
+/// The kind of a [`ServerError`], mirroring std's `io::ErrorKind`, plus
+/// [`Unauthenticated`](ErrorKind::Unauthenticated). Serialized as its `u32` code; unknown codes
+/// are read as [`Other`](ErrorKind::Other).
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde1",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "u32", into = "u32")
+)]
+pub enum ErrorKind {
+    NotFound,
+    PermissionDenied,
+    ConnectionRefused,
+    ConnectionReset,
+    ConnectionAborted,
+    NotConnected,
+    AddrInUse,
+    AddrNotAvailable,
+    BrokenPipe,
+    AlreadyExists,
+    WouldBlock,
+    InvalidInput,
+    InvalidData,
+    TimedOut,
+    WriteZero,
+    Interrupted,
+    Other,
+    UnexpectedEof,
+    /// A `PermissionDenied` error rejecting a request that lacks valid credentials, as opposed
+    /// to one the caller isn't authorized to make.
+    Unauthenticated,
+}
+
+impl From<u32> for ErrorKind {
+    fn from(code: u32) -> Self {
+        use ErrorKind::*;
+        match code {
+            0 => NotFound,
+            1 => PermissionDenied,
+            2 => ConnectionRefused,
+            3 => ConnectionReset,
+            4 => ConnectionAborted,
+            5 => NotConnected,
+            6 => AddrInUse,
+            7 => AddrNotAvailable,
+            8 => BrokenPipe,
+            9 => AlreadyExists,
+            10 => WouldBlock,
+            11 => InvalidInput,
+            12 => InvalidData,
+            13 => TimedOut,
+            14 => WriteZero,
+            15 => Interrupted,
+            17 => UnexpectedEof,
+            18 => Unauthenticated,
+            _ => Other,
+        }
+    }
+}
+
+impl From<ErrorKind> for u32 {
+    fn from(kind: ErrorKind) -> Self {
+        kind as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn error_kind_codes_roundtrip() {
+        for code in 0..19 {
+            assert_eq!(u32::from(ErrorKind::from(code)), code);
+        }
+        assert_eq!(ErrorKind::from(19), ErrorKind::Other);
+        assert_eq!(u32::from(ErrorKind::TimedOut), 13);
+    }
+
+    #[test]
+    fn server_error_display() {
+        let e = ServerError::new(ErrorKind::TimedOut, Some("deadline exceeded".into()));
+        assert_eq!(e.to_string(), "TimedOut: deadline exceeded");
+        assert_eq!(
+            ServerError::new(ErrorKind::Other, None).to_string(),
+            "Other"
+        );
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn json_encoding() {
+        let response = Response::<u32>::new(
+            6,
+            Err(ServerError::new(
+                ErrorKind::TimedOut,
+                Some("deadline exceeded".into()),
+            )),
+        );
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            json,
+            r#"{"V1":{"request_id":6,"message":{"Err":{"kind":13,"detail":"deadline exceeded","_non_exhaustive":null}},"partial":false}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Response<u32>>(&json).unwrap(),
+            response
+        );
+    }
+}
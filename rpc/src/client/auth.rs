@@ -0,0 +1,332 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Sends requests with a bearer token, refreshing it when the server rejects it.
+//!
+//! A [`BearerAuth`] client sets the [bearer token](crate::context::Context::bearer_token) of each
+//! request it sends. When the server responds that a request is
+//! [unauthenticated](crate::error::ErrorKind::Unauthenticated), e.g. because the token expired,
+//! the client fetches a new token with the refresh function it was given and sends the request
+//! once more. Concurrent requests rejected with the same token share a single refresh:
+//!
+//! ```ignore
+//! let oauth = Arc::new(oauth_client);
+//! let channel = client::new(client::Config::default(), transport).spawn()?;
+//! let client = BearerAuth::new(channel, move || {
+//!     let oauth = oauth.clone();
+//!     async move { oauth.fetch_access_token().await }
+//! });
+//! let mut client = WorldClient::from(client);
+//! ```
+//!
+//! Sending a request again takes a copy of it, so the request type must be `Clone`; services
+//! derive it for theirs with `#[tarpc::service(derive(Debug, Clone))]`.
+//!
+//! `BearerAuth` wraps any [`Client`], so it stacks with other client layers, such as a
+//! [balanced](crate::client::balance) client.
+//!
+//! The first request fetches the first token, unless the client is given one
+//! [up front](BearerAuth::with_initial_token). Notifications and streaming requests carry the
+//! token but aren't retried, since their rejections arrive after the request future completes,
+//! if at all.
+//!
+//! Servers validate the token with an [`Authenticator`](crate::server::Authenticator).
+
+use super::Client;
+use crate::{
+    context,
+    error::{Classify, ErrorKind},
+};
+use futures::{future::BoxFuture, lock::Mutex, prelude::*};
+use std::{fmt, io, sync::Arc};
+
+/// A client that sends each request with a bearer token through an inner client, refreshing the
+/// token when the server rejects it.
+///
+/// Clones share the same token.
+pub struct BearerAuth<C> {
+    inner: C,
+    token: Arc<Token>,
+}
+
+/// Fetches a new bearer token.
+type Refresh = dyn Fn() -> BoxFuture<'static, io::Result<String>> + Send + Sync;
+
+struct Token {
+    /// The latest token, or `None` before the first is fetched. Held while refreshing, so that
+    /// requests wait for the refresh rather than starting their own.
+    current: Mutex<Option<String>>,
+    refresh: Box<Refresh>,
+}
+
+impl Token {
+    /// Returns the latest token, fetching the first if there is none yet.
+    async fn get(&self) -> io::Result<String> {
+        let mut current = self.current.lock().await;
+        match &*current {
+            Some(token) => Ok(token.clone()),
+            None => self.fetch(&mut current).await,
+        }
+    }
+
+    /// Returns a token to replace `rejected`: the latest token, if another request has already
+    /// replaced it, or else a new one.
+    async fn replace(&self, rejected: &str) -> io::Result<String> {
+        let mut current = self.current.lock().await;
+        match &*current {
+            Some(token) if token != rejected => Ok(token.clone()),
+            _ => self.fetch(&mut current).await,
+        }
+    }
+
+    async fn fetch(&self, current: &mut Option<String>) -> io::Result<String> {
+        let token = (self.refresh)().await?;
+        *current = Some(token.clone());
+        Ok(token)
+    }
+}
+
+impl<C> BearerAuth<C> {
+    /// Returns a client that sends requests through `inner` with tokens fetched by `refresh`.
+    /// The first request fetches the first token.
+    pub fn new<F, Fut>(inner: C, refresh: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<String>> + Send + 'static,
+    {
+        BearerAuth::with_current_token(inner, None, refresh)
+    }
+
+    /// Returns a client that sends requests through `inner` with `token` until the server
+    /// rejects it, and then with tokens fetched by `refresh`.
+    pub fn with_initial_token<F, Fut>(inner: C, token: impl Into<String>, refresh: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<String>> + Send + 'static,
+    {
+        BearerAuth::with_current_token(inner, Some(token.into()), refresh)
+    }
+
+    fn with_current_token<F, Fut>(inner: C, current: Option<String>, refresh: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = io::Result<String>> + Send + 'static,
+    {
+        BearerAuth {
+            inner,
+            token: Arc::new(Token {
+                current: Mutex::new(current),
+                refresh: Box::new(move || refresh().boxed()),
+            }),
+        }
+    }
+}
+
+impl<C: Clone> Clone for BearerAuth<C> {
+    fn clone(&self) -> Self {
+        BearerAuth {
+            inner: self.inner.clone(),
+            token: self.token.clone(),
+        }
+    }
+}
+
+impl<C: fmt::Debug> fmt::Debug for BearerAuth<C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BearerAuth")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+// A rejected request is sent again through the same inner client, which takes a second borrow of
+// it, so the inner client must be a client for borrows of any lifetime.
+impl<'a, C, Req, Resp> Client<'a, Req> for BearerAuth<C>
+where
+    C: for<'b> Client<'b, Req, Response = Resp> + Send,
+    for<'b> <C as Client<'b, Req>>::Future: Send,
+    for<'b> <C as Client<'b, Req>>::NotifyFuture: Send,
+    for<'b> <C as Client<'b, Req>>::StreamFuture: Send,
+    for<'b> <C as Client<'b, Req>>::ResponseStream: Send,
+    Req: Clone + Send + 'a,
+    Resp: Send + 'a,
+{
+    type Response = Resp;
+    type Future = BoxFuture<'a, io::Result<Resp>>;
+    type NotifyFuture = BoxFuture<'a, io::Result<()>>;
+    type ResponseStream = <C as Client<'a, Req>>::ResponseStream;
+    type StreamFuture = BoxFuture<'a, io::Result<Self::ResponseStream>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        let inner = &mut self.inner;
+        let token = &self.token;
+        async move {
+            let current = token.get().await?;
+            let response = inner
+                .call(ctx.clone().with_bearer_token(&current), request.clone())
+                .await;
+            match response {
+                Err(ref e) if e.classify() == ErrorKind::Unauthenticated => {
+                    let refreshed = token.replace(&current).await?;
+                    inner.call(ctx.with_bearer_token(&refreshed), request).await
+                }
+                response => response,
+            }
+        }
+        .boxed()
+    }
+
+    fn notify(&'a mut self, ctx: context::Context, request: Req) -> Self::NotifyFuture {
+        let inner = &mut self.inner;
+        let token = &self.token;
+        async move {
+            let current = token.get().await?;
+            inner.notify(ctx.with_bearer_token(&current), request).await
+        }
+        .boxed()
+    }
+
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req) -> Self::StreamFuture {
+        let inner = &mut self.inner;
+        let token = &self.token;
+        async move {
+            let current = token.get().await?;
+            inner
+                .call_stream(ctx.with_bearer_token(&current), request)
+                .await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BearerAuth;
+    use crate::{
+        client::{self, Client},
+        context,
+        server::{Handler, Server},
+        transport, ClientMessage, Response, ServerError,
+    };
+    use futures::{executor::block_on, future, prelude::*};
+    use std::{
+        io,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    #[tokio::test]
+    async fn refreshes_rejected_tokens() -> io::Result<()> {
+        let _ = env_logger::try_init();
+
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        tokio::spawn(
+            Server::default()
+                .incoming(stream::once(future::ready(server_channel)))
+                .authenticate(|token: &str| token == "fresh")
+                .respond_with(|_ctx, request: String| future::ready(request.to_uppercase())),
+        );
+        let channel = client::new(client::Config::default(), client_channel).spawn()?;
+
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let mut client = BearerAuth::with_initial_token(channel, "expired", {
+            let refreshes = refreshes.clone();
+            move || {
+                refreshes.fetch_add(1, Ordering::SeqCst);
+                future::ok("fresh".to_string())
+            }
+        });
+
+        assert_eq!(client.call(context::current(), "hi".into()).await?, "HI");
+        assert_eq!(client.call(context::current(), "bye".into()).await?, "BYE");
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn wraps_other_clients() -> io::Result<()> {
+        let (client_channel, server_channel) = transport::channel::unbounded();
+        tokio::spawn(
+            Server::default()
+                .incoming(stream::once(future::ready(server_channel)))
+                .authenticate(|token: &str| token == "fresh")
+                .respond_with(|_ctx, request: String| future::ready(request.to_uppercase())),
+        );
+        let channel = client::new(client::Config::default(), client_channel).spawn()?;
+
+        let mut client = BearerAuth::with_initial_token(
+            channel.map_response(|response: String| response.len()),
+            "expired",
+            || future::ok("fresh".to_string()),
+        );
+        assert_eq!(client.call(context::current(), "hi".into()).await?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn other_permission_errors_are_not_retried() {
+        let (client_channel, mut server_channel) = transport::channel::unbounded();
+        let client::NewClient { client, dispatch } =
+            client::new::<String, String, _>(client::Config::default(), client_channel);
+        let server = async move {
+            let mut requests = 0;
+            while let Some(Ok(ClientMessage::Request(request))) = server_channel.next().await {
+                requests += 1;
+                let response = Response {
+                    request_id: request.id,
+                    message: Err(ServerError::new(
+                        io::ErrorKind::PermissionDenied,
+                        Some("Not an admin.".into()),
+                    )),
+                    partial: false,
+                    _non_exhaustive: (),
+                };
+                server_channel.send(response).await.unwrap();
+            }
+            requests
+        };
+
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let mut client = BearerAuth::with_initial_token(client, "valid", {
+            let refreshes = refreshes.clone();
+            move || {
+                refreshes.fetch_add(1, Ordering::SeqCst);
+                future::ok("fresh".to_string())
+            }
+        });
+        let call = async move {
+            let err = client
+                .call(context::current(), "hi".into())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        };
+
+        // The call drops the client when it completes, which ends the dispatch, and with it the
+        // server.
+        let (requests, ..) = block_on(future::join3(server, dispatch, call));
+        assert_eq!(requests, 1);
+        assert_eq!(refreshes.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn refresh_failures_fail_the_request() {
+        let (client_channel, _server_channel) = transport::channel::unbounded();
+        let client::NewClient { client, .. } =
+            client::new::<String, String, _>(client::Config::default(), client_channel);
+        let mut client = BearerAuth::new(client, || {
+            future::err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Invalid refresh token.",
+            ))
+        });
+
+        let err = block_on(client.call(context::current(), "hi".into())).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid refresh token.");
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Spreads requests over the servers of a service, as found by a [resolver](Resolve).
+//!
+//! [`new`] returns a [`Balance`] client and its discovery, which must be polled continuously or
+//! spawned, like a channel's dispatch. Discovery watches the resolver and keeps a channel
+//! connected to each endpoint it reports: channels to new endpoints are connected as they
+//! appear, and channels to endpoints that disappear are dropped, ending their dispatches once
+//! their in-flight requests complete and every clone of the client has sent another request.
+//! The client sends each request over the next connected channel, in turn:
+//!
+//! ```ignore
+//! let resolver = DnsSrv::new("_hello._tcp.example.com")?;
+//! let client = balance::new(resolver, |addr| async move {
+//!     let transport = bincode_transport::connect(&addr).await?;
+//!     client::new(client::Config::default(), transport).spawn()
+//! })
+//! .spawn()?;
+//! let mut client = WorldClient::from(client);
+//! ```
+//!
+//! The endpoints of a [`DnsSrv`] resolver are the targets of a service's DNS SRV records.
+//! Other service catalogs, such as Consul or etcd, plug in by implementing [`Resolve`].
+
+#[cfg(feature = "tokio1")]
+mod dns;
+#[cfg(feature = "tokio1")]
+pub use dns::DnsSrv;
+
+use super::{channel, Channel, Client, NewClient, Prepared};
+use crate::{
+    context,
+    event::{Event, EventSink, LogSink},
+};
+use futures::{
+    future::{self, Either, Ready},
+    prelude::*,
+};
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Finds the endpoints of a service.
+pub trait Resolve {
+    /// The stream of endpoint sets. Each item is the complete set of endpoints at the time,
+    /// replacing the set before it; resolvers that watch a catalog yield a new set whenever the
+    /// catalog changes. An error leaves the current endpoints in place.
+    type Endpoints: Stream<Item = io::Result<Vec<SocketAddr>>> + Send + 'static;
+
+    /// Starts watching the endpoints of the service.
+    fn resolve(self) -> Self::Endpoints;
+}
+
+/// A fixed set of endpoints.
+impl Resolve for Vec<SocketAddr> {
+    type Endpoints = stream::Once<Ready<io::Result<Vec<SocketAddr>>>>;
+
+    fn resolve(self) -> Self::Endpoints {
+        stream::once(future::ready(Ok(self)))
+    }
+}
+
+/// The discovery returned by [`new`], which keeps the client's endpoints up to date.
+pub type Discovery = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+/// Returns a client that spreads requests over the endpoints found by `resolver`, and the
+/// discovery that connects `connect`ed channels to them.
+pub fn new<Req, Resp, R, C, Fut>(
+    resolver: R,
+    connect: C,
+) -> NewClient<Balance<Req, Resp>, Discovery>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    R: Resolve,
+    C: FnMut(SocketAddr) -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<Channel<Req, Resp>>> + Send + 'static,
+{
+    with_events(resolver, connect, Arc::new(LogSink))
+}
+
+/// Like [`new`], but reports discovery's changes and failures to `events` rather than logging
+/// them.
+pub fn with_events<Req, Resp, R, C, Fut>(
+    resolver: R,
+    mut connect: C,
+    events: Arc<dyn EventSink>,
+) -> NewClient<Balance<Req, Resp>, Discovery>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+    R: Resolve,
+    C: FnMut(SocketAddr) -> Fut + Send + 'static,
+    Fut: Future<Output = io::Result<Channel<Req, Resp>>> + Send + 'static,
+{
+    let shared = Arc::new(Shared {
+        channels: Mutex::new(vec![]),
+        version: AtomicUsize::new(0),
+        next: AtomicUsize::new(0),
+    });
+    let client = Balance {
+        shared: shared.clone(),
+        channels: vec![],
+        version: 0,
+    };
+    let discovery = async move {
+        let mut updates = resolver.resolve();
+        while let Some(update) = updates.next().await {
+            let addrs = match update {
+                Ok(addrs) => addrs,
+                Err(error) => {
+                    events.event(&Event::EndpointsUnresolved { error: &error });
+                    continue;
+                }
+            };
+            let new_addrs: Vec<_> = {
+                let mut channels = shared.channels.lock().unwrap();
+                // Drops channels whose dispatch has ended, so that their endpoints are
+                // reconnected to if they're still reported.
+                channels.retain(|(addr, channel)| {
+                    let keep = addrs.contains(addr);
+                    if !keep {
+                        events.event(&Event::EndpointRemoved { addr: *addr });
+                    }
+                    keep && !channel.is_closed()
+                });
+                shared.version.fetch_add(1, Ordering::Release);
+                addrs
+                    .into_iter()
+                    .filter(|addr| !channels.iter().any(|(known, _)| known == addr))
+                    .collect()
+            };
+            for addr in new_addrs {
+                match connect(addr).await {
+                    Ok(channel) => {
+                        events.event(&Event::EndpointAdded { addr });
+                        shared.channels.lock().unwrap().push((addr, channel));
+                        shared.version.fetch_add(1, Ordering::Release);
+                    }
+                    Err(error) => events.event(&Event::EndpointUnreachable {
+                        addr,
+                        error: &error,
+                    }),
+                }
+            }
+        }
+        Ok(())
+    };
+    NewClient {
+        client,
+        dispatch: Box::pin(discovery),
+    }
+}
+
+/// A client that sends each request over the next of a set of channels, in turn.
+///
+/// Clones share the same channels, and take turns with each other.
+pub struct Balance<Req, Resp> {
+    shared: Arc<Shared<Req, Resp>>,
+    /// The client's copy of the channels, which it updates only when discovery changes them.
+    channels: Vec<(SocketAddr, Channel<Req, Resp>)>,
+    /// The version of the channels copied.
+    version: usize,
+}
+
+/// The channels kept by discovery, shared by the clients it serves.
+struct Shared<Req, Resp> {
+    channels: Mutex<Vec<(SocketAddr, Channel<Req, Resp>)>>,
+    /// Incremented whenever the channels change.
+    version: AtomicUsize,
+    /// The turn of the next request.
+    next: AtomicUsize,
+}
+
+impl<Req, Resp> Balance<Req, Resp> {
+    /// Returns the endpoints the client currently has channels to.
+    pub fn endpoints(&self) -> Vec<SocketAddr> {
+        self.shared
+            .channels
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Picks the next open channel, or returns an error if there is none.
+    fn pick(&mut self) -> io::Result<&mut Channel<Req, Resp>> {
+        let version = self.shared.version.load(Ordering::Acquire);
+        if version != self.version {
+            self.channels = self.shared.channels.lock().unwrap().clone();
+            self.version = version;
+        }
+        let len = self.channels.len();
+        let next = self.shared.next.fetch_add(1, Ordering::Relaxed);
+        // Skips channels whose dispatch has ended, until discovery drops them.
+        let channels = &self.channels;
+        let i = (0..len)
+            .map(|i| (next + i) % len)
+            .find(|&i| !channels[i].1.is_closed())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "no endpoints are connected")
+            })?;
+        Ok(&mut self.channels[i].1)
+    }
+}
+
+impl<Req, Resp> Clone for Balance<Req, Resp> {
+    fn clone(&self) -> Self {
+        Balance {
+            shared: self.shared.clone(),
+            channels: self.channels.clone(),
+            version: self.version,
+        }
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Balance<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Balance")
+            .field("endpoints", &self.endpoints())
+            .finish()
+    }
+}
+
+impl<'a, Req, Resp> Client<'a, Req> for Balance<Req, Resp>
+where
+    Req: 'a,
+    Resp: 'a,
+{
+    type Response = Resp;
+    type Future = Either<channel::Call<'a, Req, Resp>, Ready<io::Result<Resp>>>;
+    type NotifyFuture = Either<channel::Notify<'a, Req, Resp>, Ready<io::Result<()>>>;
+    type ResponseStream = channel::ResponseStream<Resp>;
+    type StreamFuture =
+        Either<channel::CallStream<'a, Req, Resp>, Ready<io::Result<Self::ResponseStream>>>;
+
+    fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
+        match self.pick() {
+            Ok(channel) => Either::Left(channel.call(ctx, request)),
+            Err(e) => Either::Right(future::err(e)),
+        }
+    }
+
+    fn notify(&'a mut self, ctx: context::Context, request: Req) -> Self::NotifyFuture {
+        match self.pick() {
+            Ok(channel) => Either::Left(channel.notify(ctx, request)),
+            Err(e) => Either::Right(future::err(e)),
+        }
+    }
+
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req) -> Self::StreamFuture {
+        match self.pick() {
+            Ok(channel) => Either::Left(channel.call_stream(ctx, request)),
+            Err(e) => Either::Right(future::err(e)),
+        }
+    }
+
+    fn call_prepared(&'a mut self, ctx: context::Context, prepared: &Prepared<Req>) -> Self::Future
+    where
+        Req: Clone,
+    {
+        match self.pick() {
+            Ok(channel) => Either::Left(channel.call_prepared(ctx, prepared)),
+            Err(e) => Either::Right(future::err(e)),
+        }
+    }
+
+    fn notify_prepared(
+        &'a mut self,
+        ctx: context::Context,
+        prepared: &Prepared<Req>,
+    ) -> Self::NotifyFuture
+    where
+        Req: Clone,
+    {
+        match self.pick() {
+            Ok(channel) => Either::Left(channel.notify_prepared(ctx, prepared)),
+            Err(e) => Either::Right(future::err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resolve;
+    use crate::{
+        client::{self, Client},
+        context,
+        server::{Handler, Server},
+        transport,
+    };
+    use futures::{executor::block_on, future, prelude::*};
+    use std::{io, net::SocketAddr};
+
+    #[test]
+    fn static_endpoints_resolve_once() {
+        let addrs: Vec<SocketAddr> = vec![([10, 0, 0, 1], 80).into(), ([10, 0, 0, 2], 80).into()];
+        let resolved: Vec<_> = block_on(addrs.clone().resolve().collect());
+        assert_eq!(resolved.len(), 1);
+        assert_eq!(resolved[0].as_ref().unwrap(), &addrs);
+    }
+
+    #[test]
+    fn no_endpoints() {
+        let client::NewClient { mut client, .. } =
+            super::new::<String, String, _, _, _>(vec![], |_| {
+                future::err(io::Error::from(io::ErrorKind::ConnectionRefused))
+            });
+        let err = block_on(client.call(context::current(), "hi".into())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+
+    #[tokio::test]
+    async fn round_robin() -> io::Result<()> {
+        let _ = env_logger::try_init();
+
+        let addrs: Vec<SocketAddr> = vec![([10, 0, 0, 1], 80).into(), ([10, 0, 0, 2], 80).into()];
+        let client::NewClient {
+            mut client,
+            dispatch: discovery,
+        } = super::new(addrs.clone(), |addr| {
+            let (client_channel, server_channel) = transport::channel::unbounded();
+            tokio::spawn(
+                Server::default()
+                    .incoming(stream::once(future::ready(server_channel)))
+                    .respond_with(move |_ctx, request: String| {
+                        future::ready(Ok(format!("{} from {}", request, addr)))
+                    }),
+            );
+            future::ready(client::new(client::Config::default(), client_channel).spawn())
+        });
+        discovery.await?;
+        assert_eq!(client.endpoints(), addrs);
+
+        let first = client.call(context::current(), "hi".into()).await?;
+        let second = client.call(context::current(), "hi".into()).await?;
+        let third = client.call(context::current(), "hi".into()).await?;
+        assert_eq!(first, "hi from 10.0.0.1:80");
+        assert_eq!(second, "hi from 10.0.0.2:80");
+        assert_eq!(third, first);
+        Ok(())
+    }
+}
@@ -0,0 +1,587 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Resolves the endpoints of a service from its DNS SRV records.
+//!
+//! Queries are sent over UDP to a single nameserver, by default the first in `/etc/resolv.conf`,
+//! and retried over TCP if the response doesn't fit in a datagram. The addresses of the records'
+//! targets are taken from the additional records of the response, if the nameserver includes
+//! them, and looked up with A and AAAA queries otherwise.
+
+use super::Resolve;
+use futures::{future, prelude::*};
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+};
+use tokio_timer::{timeout, Timeout};
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+/// The largest response read over UDP, which queries advertise in an EDNS0 OPT record. The
+/// nameserver truncates responses that don't fit, and those are retried over TCP.
+const MAX_RESPONSE: usize = 4096;
+
+/// Resolves the endpoints of a service from its DNS SRV records, looking them up again
+/// periodically.
+///
+/// Only the records of the lowest priority are used; the others are fallbacks, which the
+/// balanced client doesn't need while any endpoint of the lowest priority is reported. Records'
+/// weights are ignored, as requests are spread evenly over the endpoints.
+#[derive(Clone)]
+pub struct DnsSrv {
+    name: String,
+    nameserver: SocketAddr,
+    refresh: Duration,
+    timeout: Duration,
+}
+
+impl DnsSrv {
+    /// Returns a resolver of the SRV records of `name`, e.g. `_hello._tcp.example.com`, that
+    /// queries the first nameserver in `/etc/resolv.conf`.
+    pub fn new(name: impl Into<String>) -> io::Result<Self> {
+        let resolv_conf = fs::read_to_string("/etc/resolv.conf")?;
+        let nameserver = nameserver(&resolv_conf).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no nameserver in /etc/resolv.conf")
+        })?;
+        Ok(DnsSrv::with_nameserver(name, nameserver))
+    }
+
+    /// Returns a resolver of the SRV records of `name` that queries `nameserver`.
+    pub fn with_nameserver(name: impl Into<String>, nameserver: SocketAddr) -> Self {
+        DnsSrv {
+            name: name.into(),
+            nameserver,
+            refresh: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Sets how long to wait between lookups. Defaults to 30 seconds.
+    pub fn refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Sets how long to wait for the nameserver to respond to a query. Defaults to 5 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Looks up the endpoints of the service once.
+    ///
+    /// Targets whose addresses can't be looked up are skipped, unless none of the targets can be,
+    /// in which case the lookup fails.
+    pub async fn lookup(&self) -> io::Result<Vec<SocketAddr>> {
+        let response = self.query(&self.name, TYPE_SRV).await?;
+        let lowest_priority = response.srvs.iter().map(|srv| srv.priority).min();
+        let mut endpoints = vec![];
+        let mut error = None;
+        for srv in &response.srvs {
+            // A target of "." means the service isn't available at this name.
+            if Some(srv.priority) != lowest_priority || srv.target.is_empty() {
+                continue;
+            }
+            let mut addrs: Vec<_> = response.addresses_of(&srv.target).collect();
+            if addrs.is_empty() {
+                addrs = match self.addresses(&srv.target).await {
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        error = Some(e);
+                        continue;
+                    }
+                };
+            }
+            endpoints.extend(addrs.into_iter().map(|ip| SocketAddr::new(ip, srv.port)));
+        }
+        if let (true, Some(error)) = (endpoints.is_empty(), error) {
+            return Err(error);
+        }
+        endpoints.sort();
+        endpoints.dedup();
+        Ok(endpoints)
+    }
+
+    /// Looks up the IPv4 and IPv6 addresses of `target`, failing only if neither lookup succeeds.
+    async fn addresses(&self, target: &str) -> io::Result<Vec<IpAddr>> {
+        let (v4, v6) =
+            future::join(self.query(target, TYPE_A), self.query(target, TYPE_AAAA)).await;
+        match (v4, v6) {
+            (Err(e), Err(_)) => Err(e),
+            (v4, v6) => Ok(v4
+                .iter()
+                .chain(v6.iter())
+                .flat_map(|response| response.addresses_of(target))
+                .collect()),
+        }
+    }
+
+    async fn query(&self, name: &str, qtype: u16) -> io::Result<Message> {
+        let id = rand::random();
+        let query = encode_query(id, name, qtype)?;
+        let response = match Timeout::new(self.exchange(id, &query), self.timeout).await {
+            Ok(response) => response?,
+            Err(timeout::Elapsed { .. }) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("nameserver {} didn't respond", self.nameserver),
+                ))
+            }
+        };
+        decode_response(&response)
+    }
+
+    /// Sends `query` over UDP, and again over TCP if the response is truncated, returning the
+    /// response.
+    async fn exchange(&self, id: u16, query: &[u8]) -> io::Result<Vec<u8>> {
+        let local: SocketAddr = match self.nameserver {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let mut socket = UdpSocket::bind(local).await?;
+        socket.connect(self.nameserver).await?;
+        socket.send(query).await?;
+        let mut buf = vec![0; MAX_RESPONSE];
+        let len = loop {
+            let len = socket.recv(&mut buf).await?;
+            // Ignores stray datagrams, e.g. late responses to earlier queries.
+            if buf[..len].starts_with(&id.to_be_bytes()) {
+                break len;
+            }
+        };
+        buf.truncate(len);
+        if !is_truncated(&buf) {
+            return Ok(buf);
+        }
+
+        // Over TCP, messages are prefixed with their length.
+        let mut stream = TcpStream::connect(self.nameserver).await?;
+        stream
+            .write_all(&(query.len() as u16).to_be_bytes())
+            .await?;
+        stream.write_all(query).await?;
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).await?;
+        let mut response = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut response).await?;
+        Ok(response)
+    }
+}
+
+impl Resolve for DnsSrv {
+    type Endpoints = Pin<Box<dyn Stream<Item = io::Result<Vec<SocketAddr>>> + Send>>;
+
+    fn resolve(self) -> Self::Endpoints {
+        stream::unfold((self, true), |(resolver, first)| async move {
+            if !first {
+                tokio_timer::delay_for(resolver.refresh).await;
+            }
+            let endpoints = resolver.lookup().await;
+            Some((endpoints, (resolver, false)))
+        })
+        .boxed()
+    }
+}
+
+impl fmt::Debug for DnsSrv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DnsSrv")
+            .field("name", &self.name)
+            .field("nameserver", &self.nameserver)
+            .finish()
+    }
+}
+
+/// Returns the first nameserver in the contents of a `resolv.conf` file.
+fn nameserver(resolv_conf: &str) -> Option<SocketAddr> {
+    resolv_conf
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => words.next()?.parse::<IpAddr>().ok(),
+                _ => None,
+            }
+        })
+        .map(|ip| SocketAddr::new(ip, 53))
+        .next()
+}
+
+#[derive(Debug, PartialEq)]
+struct Srv {
+    priority: u16,
+    port: u16,
+    /// The target's name, without the trailing dot; empty for the root.
+    target: String,
+}
+
+/// The records of a response that the resolver uses.
+#[derive(Debug, Default)]
+struct Message {
+    id: u16,
+    srvs: Vec<Srv>,
+    addresses: Vec<(String, IpAddr)>,
+}
+
+impl Message {
+    fn addresses_of<'a>(&'a self, name: &'a str) -> impl Iterator<Item = IpAddr> + 'a {
+        self.addresses
+            .iter()
+            .filter(move |(owner, _)| owner.eq_ignore_ascii_case(name))
+            .map(|(_, ip)| *ip)
+    }
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(29 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired.
+    query.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question; no answer or authority records; one additional record, the OPT record.
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid domain name {:?}", name),
+            ));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // The OPT record, owned by the root, whose class is the largest UDP response accepted. Its
+    // TTL and data, the extended rcode, version, and options, are all zero.
+    query.push(0);
+    query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    query.extend_from_slice(&(MAX_RESPONSE as u16).to_be_bytes());
+    query.extend_from_slice(&[0; 6]);
+    Ok(query)
+}
+
+/// Returns whether the nameserver truncated `response` to fit in a datagram.
+fn is_truncated(response: &[u8]) -> bool {
+    response.get(2).map_or(false, |flags| flags & 0x02 != 0)
+}
+
+fn decode_response(buf: &[u8]) -> io::Result<Message> {
+    let mut reader = Reader { buf, pos: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    if is_truncated(buf) {
+        return Err(invalid_data("response was truncated"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        // The name doesn't exist, so neither do any endpoints.
+        3 => {
+            return Ok(Message {
+                id,
+                ..Message::default()
+            })
+        }
+        rcode => {
+            return Err(invalid_data(format!(
+                "nameserver failed with rcode {}",
+                rcode
+            )))
+        }
+    }
+    let questions = reader.u16()?;
+    let records = reader.u16()? as usize + reader.u16()? as usize + reader.u16()? as usize;
+    for _ in 0..questions {
+        reader.name()?;
+        reader.take(4)?;
+    }
+    let mut message = Message {
+        id,
+        ..Message::default()
+    };
+    for _ in 0..records {
+        let owner = reader.name()?;
+        let rtype = reader.u16()?;
+        let class = reader.u16()?;
+        let _ttl = reader.take(4)?;
+        let len = reader.u16()? as usize;
+        let end = reader.pos + len;
+        if class == CLASS_IN {
+            match rtype {
+                TYPE_SRV => {
+                    let priority = reader.u16()?;
+                    let _weight = reader.u16()?;
+                    let port = reader.u16()?;
+                    let target = reader.name()?;
+                    message.srvs.push(Srv {
+                        priority,
+                        port,
+                        target,
+                    });
+                }
+                TYPE_A if len == 4 => {
+                    let octets = reader.take(4)?;
+                    let ip = Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]);
+                    message.addresses.push((owner, ip.into()));
+                }
+                TYPE_AAAA if len == 16 => {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(reader.take(16)?);
+                    message
+                        .addresses
+                        .push((owner, Ipv6Addr::from(octets).into()));
+                }
+                _ => {}
+            }
+        }
+        reader.pos = end;
+    }
+    Ok(message)
+}
+
+fn invalid_data(detail: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, detail.into())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| invalid_data("response ended early"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Reads a possibly compressed name, returning it without the trailing dot.
+    fn name(&mut self) -> io::Result<String> {
+        let mut labels: Vec<String> = vec![];
+        // Where to continue reading once the name is read, if it ended in a pointer.
+        let mut resume = None;
+        // Bounds the pointers followed, so that pointer loops fail rather than hang.
+        let mut jumps = 0;
+        loop {
+            let len = self.take(1)?[0];
+            match len & 0xc0 {
+                0x00 if len == 0 => break,
+                0x00 => {
+                    let label = self.take(len as usize)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                }
+                0xc0 => {
+                    let offset = ((len as usize & 0x3f) << 8) | self.take(1)?[0] as usize;
+                    jumps += 1;
+                    if jumps > 64 {
+                        return Err(invalid_data("too many compression pointers"));
+                    }
+                    resume.get_or_insert(self.pos);
+                    self.pos = offset;
+                }
+                _ => return Err(invalid_data("unsupported label type")),
+            }
+        }
+        if let Some(resume) = resume {
+            self.pos = resume;
+        }
+        Ok(labels.join("."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    const NAME: &str = "_hello._tcp.example.com";
+
+    /// Returns the header and question of a query, without its OPT record, to build a response
+    /// on.
+    fn question(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+        let mut question = encode_query(id, name, qtype).unwrap();
+        question.truncate(question.len() - 11);
+        question[10..12].copy_from_slice(&0u16.to_be_bytes());
+        question
+    }
+
+    /// A response to an SRV query for `_hello._tcp.example.com`, with two targets of priority 10,
+    /// one of priority 20, and the address of one target in the additional records.
+    fn srv_response() -> Vec<u8> {
+        let mut response = question(7, NAME, TYPE_SRV);
+        // Flags: a response, recursion desired and available.
+        response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        // Three answers, one additional record.
+        response[6..8].copy_from_slice(&3u16.to_be_bytes());
+        response[10..12].copy_from_slice(&1u16.to_be_bytes());
+        let srv = |response: &mut Vec<u8>, priority: u16, port: u16, target: &[u8]| {
+            // The owner points to the question's name, at offset 12.
+            response.extend_from_slice(&[0xc0, 12]);
+            response.extend_from_slice(&TYPE_SRV.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&[0, 0, 0, 60]);
+            response.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            response.extend_from_slice(&priority.to_be_bytes());
+            response.extend_from_slice(&5u16.to_be_bytes());
+            response.extend_from_slice(&port.to_be_bytes());
+            response.extend_from_slice(target);
+        };
+        // The targets are relative to the question's `example.com`, at offset 24.
+        srv(&mut response, 10, 5000, b"\x02a1\xc0\x18");
+        srv(&mut response, 10, 5001, b"\x02a2\xc0\x18");
+        srv(&mut response, 20, 5002, b"\x02b1\xc0\x18");
+        // The owner points to the first answer's target, which follows the 41 bytes of the
+        // header and question, and the first 18 bytes of the answer.
+        response.extend_from_slice(&[0xc0, 59]);
+        response.extend_from_slice(&TYPE_A.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&[0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+        response
+    }
+
+    #[test]
+    fn decodes_srv_records() -> io::Result<()> {
+        let message = decode_response(&srv_response())?;
+        assert_eq!(message.id, 7);
+        assert_eq!(
+            message.srvs,
+            vec![
+                Srv {
+                    priority: 10,
+                    port: 5000,
+                    target: "a1.example.com".into()
+                },
+                Srv {
+                    priority: 10,
+                    port: 5001,
+                    target: "a2.example.com".into()
+                },
+                Srv {
+                    priority: 20,
+                    port: 5002,
+                    target: "b1.example.com".into()
+                },
+            ]
+        );
+        assert_eq!(
+            message.addresses_of("A1.example.com").collect::<Vec<_>>(),
+            vec![IpAddr::from([10, 0, 0, 1])]
+        );
+        assert_eq!(message.addresses_of("a2.example.com").count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_truncated_responses() {
+        let mut response = srv_response();
+        response[2..4].copy_from_slice(&0x8380u16.to_be_bytes());
+        assert_eq!(
+            decode_response(&response).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        response.truncate(response.len() - 1);
+        assert_eq!(
+            decode_response(&response).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn nonexistent_name_has_no_endpoints() -> io::Result<()> {
+        let mut response = question(7, NAME, TYPE_SRV);
+        response[2..4].copy_from_slice(&0x8183u16.to_be_bytes());
+        assert!(decode_response(&response)?.srvs.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn queries_advertise_large_responses() -> io::Result<()> {
+        let query = encode_query(7, NAME, TYPE_SRV)?;
+        assert_eq!(query[10..12], 1u16.to_be_bytes());
+        assert_eq!(
+            query[query.len() - 11..],
+            [0, 0, 41, 16, 0, 0, 0, 0, 0, 0, 0]
+        );
+        // Responses may echo the OPT record, which is ignored.
+        let mut response = srv_response();
+        response[10..12].copy_from_slice(&2u16.to_be_bytes());
+        response.extend_from_slice(&query[query.len() - 11..]);
+        assert_eq!(decode_response(&response)?.srvs.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retries_truncated_responses_over_tcp_and_skips_failed_targets() -> io::Result<()> {
+        let mut udp = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let nameserver = udp.local_addr()?;
+        let mut tcp = TcpListener::bind(nameserver).await?;
+        // Truncates the response to the SRV query over UDP, and fails the lookups of the target
+        // missing from the additional records.
+        tokio::spawn(async move {
+            let srv_query = encode_query(0, NAME, TYPE_SRV).unwrap();
+            let mut buf = [0; 512];
+            loop {
+                let (len, client) = udp.recv_from(&mut buf).await.unwrap();
+                let mut response = if buf[2..len] == srv_query[2..] {
+                    let mut response = srv_response();
+                    response[2..4].copy_from_slice(&0x8380u16.to_be_bytes());
+                    response
+                } else {
+                    let mut response = buf[..12].to_vec();
+                    // A server failure.
+                    response[2..4].copy_from_slice(&0x8182u16.to_be_bytes());
+                    response
+                };
+                response[..2].copy_from_slice(&buf[..2]);
+                udp.send_to(&response, &client).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await.unwrap();
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).await.unwrap();
+            let mut query = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut query).await.unwrap();
+            let mut response = srv_response();
+            response[..2].copy_from_slice(&query[..2]);
+            stream
+                .write_all(&(response.len() as u16).to_be_bytes())
+                .await
+                .unwrap();
+            stream.write_all(&response).await.unwrap();
+        });
+
+        let endpoints = DnsSrv::with_nameserver(NAME, nameserver).lookup().await?;
+        assert_eq!(endpoints, vec![([10, 0, 0, 1], 5000).into()]);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_nameserver_from_resolv_conf() {
+        let resolv_conf = "# generated\nsearch example.com\nnameserver 10.0.0.53\nnameserver ::1\n";
+        assert_eq!(nameserver(resolv_conf), Some(([10, 0, 0, 53], 53).into()));
+        assert_eq!(nameserver("search example.com\n"), None);
+    }
+}
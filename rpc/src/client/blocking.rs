@@ -0,0 +1,130 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A client for callers without an async runtime, such as mobile apps calling tarpc backends
+//! through FFI bindings.
+//!
+//! [`Blocking`] drives a client's dispatch on a background thread and blocks the calling thread
+//! for the duration of each request, so the functions wrapping it are plain synchronous
+//! functions that FFI generators such as UniFFI can export. It's `Send` and `Sync`, so the app
+//! can share one behind an `Arc` between its threads:
+//!
+//! ```ignore
+//! let mut config = client::Config::default();
+//! config.timer = Arc::new(ThreadTimer);
+//! let client = Blocking::new(WorldClient::new(config, transport))?;
+//!
+//! pub fn hello(client: &Blocking<WorldClient>, name: String) -> Result<String, HelloError> {
+//!     let greeting = client.call(|mut client| async move {
+//!         client.hello(context::current(), name).await
+//!     })?;
+//!     Ok(greeting)
+//! }
+//! ```
+//!
+//! Builds with a runtime feature enforce deadlines with the runtime's timer, which only works
+//! while the runtime runs; the example gives the client a
+//! [`ThreadTimer`](crate::runtime::ThreadTimer), which times them on a background thread, as
+//! client-only builds do by default.
+
+use super::NewClient;
+use futures::{executor, Future};
+use std::{io, thread};
+
+/// Wraps a client, blocking the calling thread on each of its requests.
+#[derive(Debug)]
+pub struct Blocking<C> {
+    client: C,
+}
+
+impl<C: Clone + Sync> Blocking<C> {
+    /// Runs the dispatch of `new_client` on a background thread until the client and all its
+    /// clones are dropped.
+    ///
+    /// The dispatch thread doesn't run a reactor, so transports whose I/O needs one, such as
+    /// tokio's, should [spawn](NewClient::spawn_with) the dispatch onto their runtime and wrap
+    /// the client with [`from_client`](Blocking::from_client) instead.
+    pub fn new<D>(new_client: NewClient<C, D>) -> io::Result<Self>
+    where
+        D: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let NewClient { client, dispatch } = new_client;
+        thread::Builder::new()
+            .name("tarpc-dispatch".into())
+            .spawn(move || {
+                let _ = executor::block_on(dispatch);
+            })?;
+        Ok(Blocking::from_client(client))
+    }
+
+    /// Wraps `client`, whose dispatch is already running.
+    pub fn from_client(client: C) -> Self {
+        Blocking { client }
+    }
+
+    /// Calls `f` with a clone of the client and blocks until the future it returns completes.
+    pub fn call<F, Fut>(&self, f: F) -> Fut::Output
+    where
+        F: FnOnce(C) -> Fut,
+        Fut: Future,
+    {
+        executor::block_on(f(self.client.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Blocking;
+    use crate::{
+        client::{self, Config},
+        context,
+        runtime::ThreadTimer,
+        transport, ClientMessage, Response,
+    };
+    use futures::{executor, prelude::*};
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn calls_block_until_the_response_arrives() {
+        let (client_transport, mut server_transport) = transport::channel::unbounded();
+        thread::spawn(move || {
+            executor::block_on(async move {
+                while let Some(Ok(ClientMessage::Request(request))) = server_transport.next().await
+                {
+                    let response = Response {
+                        request_id: request.id,
+                        message: Ok(request.message.to_uppercase()),
+                        partial: false,
+                        _non_exhaustive: (),
+                    };
+                    server_transport.send(response).await.unwrap();
+                }
+            })
+        });
+
+        let mut config = Config::default();
+        config.timer = Arc::new(ThreadTimer);
+        let client = Arc::new(
+            Blocking::new(client::new::<String, String, _>(config, client_transport)).unwrap(),
+        );
+        let callers: Vec<_> = ["hi", "bye"]
+            .iter()
+            .map(|&request| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    client.call(|mut client| async move {
+                        client.call(context::current(), request.into()).await
+                    })
+                })
+            })
+            .collect();
+        let responses: Vec<_> = callers
+            .into_iter()
+            .map(|caller| caller.join().unwrap().unwrap())
+            .collect();
+        assert_eq!(responses, ["HI", "BYE"]);
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides the expectations used by the mock clients that the `service` macro generates with
+//! `mock = true`.
+//!
+//! Each RPC of a mock client has a list of expectations. A call is answered by the first
+//! expectation, in the order they were added, that matches the call's arguments and hasn't been
+//! called the number of times it expects. Calls that match no expectation panic, as do
+//! expectations that are dropped before being called the number of times they expect.
+
+use crate::context;
+use std::{fmt, io};
+
+/// Describes the calls a mock client expects to an RPC, and how to respond to them.
+pub struct Expectation<Args, Output> {
+    rpc: &'static str,
+    matcher: Option<Box<dyn Fn(&Args) -> bool + Send>>,
+    response: Option<Box<dyn FnMut(context::Context, Args) -> io::Result<Output> + Send>>,
+    times: Option<usize>,
+    calls: usize,
+}
+
+impl<Args, Output> Expectation<Args, Output> {
+    fn new(rpc: &'static str) -> Self {
+        Expectation {
+            rpc,
+            matcher: None,
+            response: None,
+            times: None,
+            calls: 0,
+        }
+    }
+
+    /// Only match calls whose arguments satisfy `matcher`. The arguments are passed as a tuple.
+    pub fn with<F>(&mut self, matcher: F) -> &mut Self
+    where
+        F: Fn(&Args) -> bool + Send + 'static,
+    {
+        self.matcher = Some(Box::new(matcher));
+        self
+    }
+
+    /// Expect exactly `times` matching calls. By default, any number of calls is allowed.
+    pub fn times(&mut self, times: usize) -> &mut Self {
+        self.times = Some(times);
+        self
+    }
+
+    /// Respond to matching calls with the output of `response`. For streaming RPCs, the output is
+    /// the list of items in the response stream.
+    pub fn returning<F>(&mut self, mut response: F) -> &mut Self
+    where
+        F: FnMut(context::Context, Args) -> Output + Send + 'static,
+    {
+        self.response = Some(Box::new(move |ctx, args| Ok(response(ctx, args))));
+        self
+    }
+
+    /// Respond to matching calls with the result of `response`, which allows simulating errors.
+    pub fn returning_result<F>(&mut self, response: F) -> &mut Self
+    where
+        F: FnMut(context::Context, Args) -> io::Result<Output> + Send + 'static,
+    {
+        self.response = Some(Box::new(response));
+        self
+    }
+
+    fn matches(&self, args: &Args) -> bool {
+        self.times.map_or(true, |times| self.calls < times)
+            && self.matcher.as_ref().map_or(true, |matcher| matcher(args))
+    }
+}
+
+impl<Args, Output> fmt::Debug for Expectation<Args, Output> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Expectation")
+            .field("rpc", &self.rpc)
+            .field("times", &self.times)
+            .field("calls", &self.calls)
+            .finish()
+    }
+}
+
+impl<Args, Output> Drop for Expectation<Args, Output> {
+    fn drop(&mut self) {
+        if let Some(times) = self.times {
+            if self.calls != times && !std::thread::panicking() {
+                panic!(
+                    "expected {} call(s) to `{}`, but it was called {} time(s)",
+                    times, self.rpc, self.calls
+                );
+            }
+        }
+    }
+}
+
+/// The expectations for calls to one RPC of a mock client.
+pub struct Expectations<Args, Output> {
+    rpc: &'static str,
+    expectations: Vec<Expectation<Args, Output>>,
+}
+
+impl<Args, Output> Expectations<Args, Output> {
+    #[doc(hidden)]
+    pub fn new(rpc: &'static str) -> Self {
+        Expectations {
+            rpc,
+            expectations: vec![],
+        }
+    }
+
+    /// Adds a new expectation, which is matched after all previously added expectations.
+    pub fn expect(&mut self) -> &mut Expectation<Args, Output> {
+        self.expectations.push(Expectation::new(self.rpc));
+        self.expectations.last_mut().unwrap()
+    }
+
+    /// Responds to a call using the first matching expectation.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no expectation matches, or if the matching expectation has no response.
+    #[doc(hidden)]
+    pub fn call(&mut self, ctx: context::Context, args: Args) -> io::Result<Output> {
+        let rpc = self.rpc;
+        let expectation = self
+            .expectations
+            .iter_mut()
+            .find(|expectation| expectation.matches(&args))
+            .unwrap_or_else(|| panic!("no expectation matches call to `{}`", rpc));
+        expectation.calls += 1;
+        let response = expectation
+            .response
+            .as_mut()
+            .unwrap_or_else(|| panic!("expectation for `{}` has no response", rpc));
+        response(ctx, args)
+    }
+}
+
+impl<Args, Output> fmt::Debug for Expectations<Args, Output> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.expectations.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expectations;
+    use crate::context;
+    use std::io;
+
+    #[test]
+    fn first_matching_expectation_responds() -> io::Result<()> {
+        let mut expectations = Expectations::<(u32,), u32>::new("double");
+        expectations
+            .expect()
+            .with(|&(x,)| x == 0)
+            .returning(|_, _| 100);
+        expectations.expect().returning(|_, (x,)| x * 2);
+
+        assert_eq!(expectations.call(context::current(), (0,))?, 100);
+        assert_eq!(expectations.call(context::current(), (3,))?, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn saturated_expectation_falls_through() -> io::Result<()> {
+        let mut expectations = Expectations::<(), &'static str>::new("ping");
+        expectations.expect().times(1).returning(|_, ()| "first");
+        expectations.expect().returning(|_, ()| "rest");
+
+        assert_eq!(expectations.call(context::current(), ())?, "first");
+        assert_eq!(expectations.call(context::current(), ())?, "rest");
+        assert_eq!(expectations.call(context::current(), ())?, "rest");
+        Ok(())
+    }
+
+    #[test]
+    fn returning_result() {
+        let mut expectations = Expectations::<(), ()>::new("ping");
+        expectations
+            .expect()
+            .returning_result(|_, ()| Err(io::Error::from(io::ErrorKind::ConnectionReset)));
+
+        let err = expectations.call(context::current(), ()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    #[should_panic(expected = "no expectation matches call to `ping`")]
+    fn unexpected_call_panics() {
+        let mut expectations = Expectations::<(), ()>::new("ping");
+        let _ = expectations.call(context::current(), ());
+    }
+
+    #[test]
+    #[should_panic(expected = "expected 2 call(s) to `ping`, but it was called 1 time(s)")]
+    fn unmet_times_panics_on_drop() {
+        let mut expectations = Expectations::<(), ()>::new("ping");
+        expectations.expect().times(2).returning(|_, ()| ());
+        let _ = expectations.call(context::current(), ());
+    }
+}
@@ -6,14 +6,32 @@
 
 //! Provides a client that connects to a server and sends multiplexed requests.
 
-use crate::context;
-use futures::prelude::*;
-use std::io;
+use crate::{
+    context,
+    error::{Classify, ErrorKind},
+    event::{EventSink, LogSink},
+    export::SpanExporter,
+    runtime::{self, Spawn, Timer},
+    transport::FlushPolicy,
+    MapHasher,
+};
+use futures::{prelude::*, ready, stream::MapOk, task::Context, Poll};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{error::Error, fmt, io, pin::Pin, sync::Arc};
 
+pub mod auth;
+pub mod balance;
+#[cfg(feature = "blocking")]
+pub mod blocking;
 /// Provides a [`Client`] backed by a transport.
 pub mod channel;
 pub use channel::{new, Channel};
 
+pub mod mock;
+
+pub(crate) mod prepared;
+pub use prepared::Prepared;
+
 /// Sends multiplexed requests to, and receives responses from, a server.
 pub trait Client<'a, Req> {
     /// The response type.
@@ -22,6 +40,15 @@ pub trait Client<'a, Req> {
     /// The future response.
     type Future: Future<Output = io::Result<Self::Response>> + 'a;
 
+    /// The future returned by [`notify`](Client::notify).
+    type NotifyFuture: Future<Output = io::Result<()>> + 'a;
+
+    /// The stream of responses to a streaming request.
+    type ResponseStream: Stream<Item = io::Result<Self::Response>> + 'a;
+
+    /// The future returned by [`call_stream`](Client::call_stream).
+    type StreamFuture: Future<Output = io::Result<Self::ResponseStream>> + 'a;
+
     /// Initiates a request, sending it to the dispatch task.
     ///
     /// Returns a [`Future`] that resolves to this client and the future response
@@ -30,6 +57,46 @@ pub trait Client<'a, Req> {
     /// [`Future`]: futures::Future
     fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future;
 
+    /// Initiates a one-way request, sending it to the dispatch task. The server does not send a
+    /// response to one-way requests.
+    ///
+    /// Returns a [`Future`] that resolves once the request is successfully enqueued.
+    ///
+    /// [`Future`]: futures::Future
+    fn notify(&'a mut self, ctx: context::Context, request: Req) -> Self::NotifyFuture;
+
+    /// Initiates a streaming request, sending it to the dispatch task.
+    ///
+    /// Returns a [`Future`] that resolves to a stream of the server's responses once the request
+    /// is successfully enqueued. Dropping the stream before it ends cancels the request.
+    ///
+    /// [`Future`]: futures::Future
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req) -> Self::StreamFuture;
+
+    /// Initiates a [prepared](Prepared) request, like [`call`](Client::call).
+    ///
+    /// By default, the prepared message is sent like any other request, and serialized anew.
+    fn call_prepared(&'a mut self, ctx: context::Context, prepared: &Prepared<Req>) -> Self::Future
+    where
+        Req: Clone,
+    {
+        self.call(ctx, prepared.message().clone())
+    }
+
+    /// Initiates a [prepared](Prepared) one-way request, like [`notify`](Client::notify).
+    ///
+    /// By default, the prepared message is sent like any other request, and serialized anew.
+    fn notify_prepared(
+        &'a mut self,
+        ctx: context::Context,
+        prepared: &Prepared<Req>,
+    ) -> Self::NotifyFuture
+    where
+        Req: Clone,
+    {
+        self.notify(ctx, prepared.message().clone())
+    }
+
     /// Returns a Client that applies a post-processing function to the returned response.
     fn map_response<F, R>(self, f: F) -> MapResponse<Self, F>
     where
@@ -63,10 +130,75 @@ where
 {
     type Response = Resp2;
     type Future = futures::future::MapOk<<C as Client<'a, Req>>::Future, &'a mut F>;
+    type NotifyFuture = <C as Client<'a, Req>>::NotifyFuture;
+    type ResponseStream = MapOk<<C as Client<'a, Req>>::ResponseStream, &'a mut F>;
+    type StreamFuture = MapResponseStream<'a, <C as Client<'a, Req>>::StreamFuture, F>;
 
     fn call(&'a mut self, ctx: context::Context, request: Req) -> Self::Future {
         self.inner.call(ctx, request).map_ok(&mut self.f)
     }
+
+    fn notify(&'a mut self, ctx: context::Context, request: Req) -> Self::NotifyFuture {
+        self.inner.notify(ctx, request)
+    }
+
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req) -> Self::StreamFuture {
+        MapResponseStream {
+            future: self.inner.call_stream(ctx, request),
+            f: Some(&mut self.f),
+        }
+    }
+
+    fn call_prepared(&'a mut self, ctx: context::Context, prepared: &Prepared<Req>) -> Self::Future
+    where
+        Req: Clone,
+    {
+        self.inner.call_prepared(ctx, prepared).map_ok(&mut self.f)
+    }
+
+    fn notify_prepared(
+        &'a mut self,
+        ctx: context::Context,
+        prepared: &Prepared<Req>,
+    ) -> Self::NotifyFuture
+    where
+        Req: Clone,
+    {
+        self.inner.notify_prepared(ctx, prepared)
+    }
+}
+
+/// A future that resolves to a stream of responses with a function applied to each response.
+/// Returned by [`MapResponse::call_stream`](Client::call_stream).
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct MapResponseStream<'a, Fut, F> {
+    future: Fut,
+    f: Option<&'a mut F>,
+}
+
+impl<'a, Fut, F> MapResponseStream<'a, Fut, F> {
+    unsafe_pinned!(future: Fut);
+    unsafe_unpinned!(f: Option<&'a mut F>);
+}
+
+impl<'a, Fut, F, St, R> Future for MapResponseStream<'a, Fut, F>
+where
+    Fut: Future<Output = io::Result<St>>,
+    St: TryStream<Error = io::Error>,
+    F: FnMut(St::Ok) -> R,
+{
+    type Output = io::Result<MapOk<St, &'a mut F>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let stream = ready!(self.as_mut().future().poll(cx))?;
+        let f = self
+            .as_mut()
+            .f()
+            .take()
+            .expect("MapResponseStream must not be polled after it returned `Poll::Ready`");
+        Poll::Ready(Ok(stream.map_ok(f)))
+    }
 }
 
 /// A Client that applies a pre-processing function to the request.
@@ -83,10 +215,21 @@ where
 {
     type Response = Resp;
     type Future = <C as Client<'a, Req>>::Future;
+    type NotifyFuture = <C as Client<'a, Req>>::NotifyFuture;
+    type ResponseStream = <C as Client<'a, Req>>::ResponseStream;
+    type StreamFuture = <C as Client<'a, Req>>::StreamFuture;
 
     fn call(&'a mut self, ctx: context::Context, request: Req2) -> Self::Future {
         self.inner.call(ctx, (self.f)(request))
     }
+
+    fn notify(&'a mut self, ctx: context::Context, request: Req2) -> Self::NotifyFuture {
+        self.inner.notify(ctx, (self.f)(request))
+    }
+
+    fn call_stream(&'a mut self, ctx: context::Context, request: Req2) -> Self::StreamFuture {
+        self.inner.call_stream(ctx, (self.f)(request))
+    }
 }
 
 impl<'a, Req, Resp> Client<'a, Req> for Channel<Req, Resp>
@@ -96,10 +239,93 @@ where
 {
     type Response = Resp;
     type Future = channel::Call<'a, Req, Resp>;
+    type NotifyFuture = channel::Notify<'a, Req, Resp>;
+    type ResponseStream = channel::ResponseStream<Resp>;
+    type StreamFuture = channel::CallStream<'a, Req, Resp>;
 
     fn call(&'a mut self, ctx: context::Context, request: Req) -> channel::Call<'a, Req, Resp> {
         self.call(ctx, request)
     }
+
+    fn notify(&'a mut self, ctx: context::Context, request: Req) -> channel::Notify<'a, Req, Resp> {
+        self.notify(ctx, request)
+    }
+
+    fn call_stream(
+        &'a mut self,
+        ctx: context::Context,
+        request: Req,
+    ) -> channel::CallStream<'a, Req, Resp> {
+        self.call_stream(ctx, request)
+    }
+
+    fn call_prepared(
+        &'a mut self,
+        ctx: context::Context,
+        prepared: &Prepared<Req>,
+    ) -> channel::Call<'a, Req, Resp>
+    where
+        Req: Clone,
+    {
+        self.call_prepared(ctx, prepared)
+    }
+
+    fn notify_prepared(
+        &'a mut self,
+        ctx: context::Context,
+        prepared: &Prepared<Req>,
+    ) -> channel::Notify<'a, Req, Resp>
+    where
+        Req: Clone,
+    {
+        self.notify_prepared(ctx, prepared)
+    }
+}
+
+/// The error of an RPC declared with `#[throws(E)]`: either the RPC failed, or the service
+/// returned an error.
+#[derive(Debug)]
+pub enum CallError<E> {
+    /// The RPC failed before the service could respond, e.g. because the request couldn't be sent,
+    /// the deadline passed, or the server replied with a [`ServerError`](crate::ServerError).
+    Rpc(io::Error),
+    /// The service returned an error.
+    Service(E),
+}
+
+impl<E> From<io::Error> for CallError<E> {
+    fn from(e: io::Error) -> Self {
+        CallError::Rpc(e)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for CallError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::Rpc(e) => write!(f, "RPC failed: {}", e),
+            CallError::Service(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Classifies RPC failures like the underlying [`io::Error`], and errors returned by the service
+/// as [application](ErrorKind::Application) errors.
+impl<E> Classify for CallError<E> {
+    fn classify(&self) -> ErrorKind {
+        match self {
+            CallError::Rpc(e) => e.classify(),
+            CallError::Service(_) => ErrorKind::Application,
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for CallError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CallError::Rpc(e) => Some(e),
+            CallError::Service(e) => Some(e),
+        }
+    }
 }
 
 /// Settings that control the behavior of the client.
@@ -113,6 +339,27 @@ pub struct Config {
     /// `pending_requests_buffer` controls the size of the channel clients use
     /// to communicate with the request dispatch task.
     pub pending_request_buffer: usize,
+    /// The most requests and cancellations written to the transport and not yet flushed. Once
+    /// this many are unflushed, the dispatch waits for the transport to flush them before writing
+    /// more, whatever the [flush policy](Config::flush). Larger buffers batch small requests into
+    /// fewer writes; smaller ones bound the memory held by large requests waiting to be written.
+    pub write_buffer: usize,
+    /// Decides whether the traces of requests are recorded. The decision is sent to the server
+    /// in the request's trace context. Defaults to recording every new trace and respecting the
+    /// decision of the trace a request is part of.
+    pub sampler: Arc<dyn trace::Sample>,
+    /// Receives the events of the client's requests. Defaults to [logging](LogSink) them.
+    pub events: Arc<dyn EventSink>,
+    /// Exports a span for each sampled request that expects a response.
+    pub spans: Option<SpanExporter>,
+    /// When to flush requests and cancellations written to the transport. Defaults to flushing
+    /// whenever no more are ready to write.
+    pub flush: FlushPolicy,
+    /// The hash function of the map of in-flight requests, which is keyed by request ID.
+    pub hasher: MapHasher,
+    /// Times the deadlines of requests and corked flushes. Defaults to the timer of the
+    /// [default runtime](runtime::DefaultRuntime).
+    pub timer: Arc<dyn Timer>,
     #[doc(hidden)]
     _non_exhaustive: (),
 }
@@ -122,6 +369,13 @@ impl Default for Config {
         Config {
             max_in_flight_requests: 1_000,
             pending_request_buffer: 100,
+            write_buffer: 64,
+            sampler: Arc::new(trace::ParentBasedSample::new(trace::AlwaysSample)),
+            events: Arc::new(LogSink),
+            spans: None,
+            flush: FlushPolicy::default(),
+            hasher: MapHasher::default(),
+            timer: runtime::default(),
             _non_exhaustive: (),
         }
     }
@@ -141,15 +395,16 @@ impl<C, D> NewClient<C, D>
 where
     D: Future<Output = io::Result<()>> + Send + 'static,
 {
-    /// Helper method to spawn the dispatch on the default executor.
-    #[cfg(feature = "tokio1")]
+    /// Helper method to spawn the dispatch on the default executor. The dispatch reports a broken
+    /// connection to its [event sink](Config::events).
+    #[cfg(any(feature = "tokio1", feature = "async-std1"))]
     pub fn spawn(self) -> io::Result<C> {
-        use log::error;
+        self.spawn_with(&runtime::DefaultRuntime::default())
+    }
 
-        let dispatch = self
-            .dispatch
-            .unwrap_or_else(move |e| error!("Connection broken: {}", e));
-        tokio::spawn(dispatch);
+    /// Spawns the dispatch with `spawner`, like [`spawn`](NewClient::spawn).
+    pub fn spawn_with(self, spawner: &dyn Spawn) -> io::Result<C> {
+        spawner.spawn(Box::pin(self.dispatch.map(|_| ())));
         Ok(self.client)
     }
 }
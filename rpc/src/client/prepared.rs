@@ -0,0 +1,75 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use std::sync::Arc;
+
+/// A request message that can be sent any number of times, to any number of clients, while
+/// being serialized only once.
+///
+/// The message is serialized to bincode when it's prepared. Transports whose format isn't
+/// human-readable, such as the bincode transport, write those bytes in place of the message
+/// every time it's sent, so the message itself is never serialized again. Human-readable formats,
+/// such as JSON, and transports that don't serialize, serialize the message every time, as they
+/// would any other request.
+///
+/// Cloning a prepared request shares its serialized bytes.
+#[derive(Clone, Debug)]
+pub struct Prepared<Req> {
+    message: Req,
+    /// The bincode encoding of the message, unless it couldn't be encoded.
+    encoded: Option<Arc<[u8]>>,
+}
+
+impl<Req> Prepared<Req> {
+    /// Prepares `message` to be sent, serializing it once. A message that can't be serialized
+    /// is sent like any other request, and fails to send the same way.
+    #[cfg(feature = "serde1")]
+    pub fn new(message: Req) -> Self
+    where
+        Req: serde::Serialize,
+    {
+        let encoded = bincode::serialize(&message).ok().map(Arc::from);
+        Prepared { message, encoded }
+    }
+
+    /// Returns the request message.
+    pub fn message(&self) -> &Req {
+        &self.message
+    }
+
+    /// Returns the message, along with the encoding shared by every send of it.
+    pub(crate) fn to_parts(&self) -> (Req, Option<Arc<[u8]>>)
+    where
+        Req: Clone,
+    {
+        (self.message.clone(), self.encoded.clone())
+    }
+}
+
+#[cfg(all(test, feature = "serde1"))]
+mod tests {
+    use super::*;
+    use crate::{context, Request};
+
+    #[test]
+    fn prepared_requests_are_encoded_like_other_requests() {
+        let message = (7u32, "prepared".to_string(), vec![1u16, 2, 3]);
+        let (message, encoded) = Prepared::new(message).to_parts();
+        let request = Request::new(context::current(), 1, message);
+        let prepared = Request {
+            encoded,
+            ..request.clone()
+        };
+        assert_eq!(
+            bincode::serialize(&prepared).unwrap(),
+            bincode::serialize(&request).unwrap()
+        );
+        assert_eq!(
+            bincode::serialized_size(&prepared).unwrap(),
+            bincode::serialized_size(&request).unwrap()
+        );
+    }
+}
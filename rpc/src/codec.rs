@@ -0,0 +1,132 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Checks that payloads survive a transport's serialization format.
+//!
+//! A [`Codec`] names the format a transport serializes messages as, e.g. [`Bincode`] for the
+//! bincode transport, or [`Json`] for the JSON transport. [`roundtrip`] checks that a value decodes as what was encoded, which catches
+//! payload types whose serde impls don't suit the format, e.g. `#[serde(skip_serializing_if)]`
+//! fields in a positional format like bincode, or `u128`s in JSON.
+//!
+//! With the `proptest1` feature, [`roundtrips`] checks many values, generated by the type's
+//! [`Arbitrary`](proptest::arbitrary::Arbitrary) impl. To derive it for a service's request and
+//! response enums, pass it to the `service` macro, along with `Debug` and `PartialEq`, e.g.
+//! `derive(Debug, PartialEq, proptest_derive::Arbitrary)`; then
+//! `roundtrips::<Bincode, ServiceRequest>()` checks that every RPC's arguments roundtrip.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fmt, io};
+
+/// A serialization format.
+pub trait Codec {
+    /// Serializes `value`.
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>>;
+
+    /// Deserializes a value from `bytes`.
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T>;
+}
+
+/// The bincode format, which the bincode transport serializes payloads as.
+#[derive(Clone, Copy, Debug)]
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        bincode::serialize(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+        bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The JSON format, which the JSON transport serializes payloads as.
+#[derive(Clone, Copy, Debug)]
+pub struct Json;
+
+impl Codec for Json {
+    fn encode<T: Serialize>(value: &T) -> io::Result<Vec<u8>> {
+        serde_json::to_vec(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+        serde_json::from_slice(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Encodes `value` with `C` and decodes it again, returning an
+/// [`InvalidData`](io::ErrorKind::InvalidData) error if it decodes as a different value, or any
+/// error encoding or decoding it.
+pub fn roundtrip<C, T>(value: &T) -> io::Result<()>
+where
+    C: Codec,
+    T: Serialize + DeserializeOwned + PartialEq + fmt::Debug,
+{
+    let decoded: T = C::decode(&C::encode(value)?)?;
+    if decoded != *value {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} decoded as {:?}", value, decoded),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that arbitrary values of `T` [roundtrip] through `C`.
+///
+/// # Panics
+///
+/// If a value doesn't roundtrip, with the simplest such value proptest finds.
+#[cfg(feature = "proptest1")]
+pub fn roundtrips<C, T>()
+where
+    C: Codec,
+    T: proptest::arbitrary::Arbitrary + Serialize + DeserializeOwned + PartialEq,
+{
+    use proptest::{
+        arbitrary::any,
+        test_runner::{TestCaseError, TestRunner},
+    };
+
+    let result = TestRunner::default().run(&any::<T>(), |value| {
+        roundtrip::<C, T>(&value).map_err(|e| TestCaseError::fail(e.to_string()))
+    });
+    if let Err(e) = result {
+        panic!("{}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Forgets what it encodes, decoding every value as though it were `()`.
+    struct Lossy;
+
+    impl Codec for Lossy {
+        fn encode<T: Serialize>(_: &T) -> io::Result<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn decode<T: DeserializeOwned>(_: &[u8]) -> io::Result<T> {
+            T::deserialize(serde::de::value::UnitDeserializer::<serde::de::value::Error>::new())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    #[test]
+    fn lossy_codecs_fail() {
+        assert!(roundtrip::<Lossy, _>(&()).is_ok());
+        assert_eq!(
+            roundtrip::<Lossy, _>(&Some(1)).unwrap_err().to_string(),
+            "Some(1) decoded as None"
+        );
+        assert_eq!(
+            roundtrip::<Lossy, _>(&1).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
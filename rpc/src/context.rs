@@ -4,18 +4,34 @@
 // license that can be found in the LICENSE file or at
 // https://opensource.org/licenses/MIT.
 
-//! Provides a request context that carries a deadline and trace context. This context is sent from
-//! client to server and is used by the server to enforce response deadlines.
+//! Provides a request context that carries a deadline, trace context, metadata, and baggage. This
+//! context is sent from client to server and is used by the server to enforce response deadlines.
 
-use std::time::{Duration, SystemTime};
+use std::{
+    cell::Cell,
+    collections::BTreeMap,
+    ptr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use trace::{self, TraceId};
 
+/// The [metadata](Context::metadata) key holding the credentials of the request, as in HTTP's
+/// `Authorization` header.
+pub const AUTHORIZATION_METADATA_KEY: &str = "authorization";
+
+/// The scheme prefixing [bearer tokens](Context::bearer_token).
+const BEARER: &str = "Bearer ";
+
 /// A request context that carries request-scoped information like deadlines and trace information.
 /// It is sent from client to server and is used by the server to enforce response deadlines.
 ///
 /// The context should not be stored directly in a server implementation, because the context will
 /// be different for each request in scope.
-#[derive(Clone, Copy, Debug)]
+///
+/// A context owns its metadata and baggage, so it isn't `Copy`; clone it to send more than one
+/// request with it.
+#[derive(Clone, Debug)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 pub struct Context {
     /// When the client expects the request to be complete by. The server should cancel the request
@@ -28,35 +44,307 @@ pub struct Context {
         feature = "serde1",
         serde(deserialize_with = "crate::util::serde::deserialize_epoch_secs")
     )]
-    #[cfg_attr(feature = "serde1", serde(default = "ten_seconds_from_now"))]
     pub deadline: SystemTime,
     /// Uniquely identifies requests originating from the same source.
     /// When a service handles a request by making requests itself, those requests should
     /// include the same `trace_id` as that included on the original request. This way,
     /// users can trace related actions across a distributed system.
     pub trace_context: trace::Context,
+    /// Request-scoped key-value pairs, like auth tokens, tenant ids, and feature flags, that are
+    /// sent along with the request and readable by the server, like gRPC metadata.
+    pub metadata: BTreeMap<String, String>,
+    /// Request-scoped key-value pairs, like experiment ids and routing hints, that are sent along
+    /// with the request and, unlike [`metadata`](Context::metadata), forwarded on any requests
+    /// the server makes using [`current()`] while handling it.
+    pub baggage: BTreeMap<String, String>,
+    /// The time remaining until the deadline when the request was sent. Because it doesn't depend
+    /// on the client's clock agreeing with the server's, the server prefers it to `deadline` for
+    /// reconstructing the deadline on arrival.
+    pub(crate) time_remaining: Option<Duration>,
+    /// The ID of the request, set by the server when the request arrives. It is unique only among
+    /// the requests of a single channel, so it isn't sent.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub(crate) request_id: Option<u64>,
+    /// The authenticated identity of the client, set by the server from the channel the request
+    /// arrived on. Never sent, so that clients can't claim an identity.
+    #[cfg_attr(feature = "serde1", serde(skip))]
+    pub(crate) peer_identity: Option<Arc<PeerIdentity>>,
     #[doc(hidden)]
     pub(crate) _non_exhaustive: (),
 }
 
-#[cfg(feature = "serde1")]
-fn ten_seconds_from_now() -> SystemTime {
-    return SystemTime::now() + Duration::from_secs(10);
+/// The identity of a peer, as established by the transport it's connected over, e.g. from a TLS
+/// client certificate, Unix socket peer credentials, or an authentication handshake.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PeerIdentity {
+    /// The name the peer authenticated as, e.g. a certificate's subject or a user name.
+    pub principal: String,
+    /// How the peer was authenticated.
+    pub method: AuthMethod,
+    /// Further facts about the peer, like certificate fields or a process id.
+    pub attributes: BTreeMap<String, String>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl PeerIdentity {
+    /// Returns the identity of `principal`, authenticated with `method`, with no attributes.
+    pub fn new(principal: impl Into<String>, method: AuthMethod) -> Self {
+        PeerIdentity {
+            principal: principal.into(),
+            method,
+            attributes: BTreeMap::new(),
+            _non_exhaustive: (),
+        }
+    }
+
+    /// Sets the attribute `key`, returning the identity for chaining.
+    pub fn with_attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(key.into(), value.into());
+        self
+    }
+
+    /// Returns the attribute `key`, if any.
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        self.attributes.get(key).map(String::as_str)
+    }
+}
+
+/// The means by which a peer's identity was established.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AuthMethod {
+    /// The peer presented a TLS client certificate.
+    TlsCertificate,
+    /// The peer's credentials were read from a Unix domain socket.
+    UnixCredentials,
+    /// The peer authenticated in a handshake when connecting.
+    Handshake,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+thread_local! {
+    /// The context of the request being polled on this thread, if any.
+    static CURRENT: Cell<*const Context> = Cell::new(ptr::null());
 }
 
 /// Returns the context for the current request, or a default Context if no request is active.
-// TODO: populate Context with request-scoped data, with default fallbacks.
+///
+/// While a server is handling a request, the returned context inherits the request's deadline,
+/// trace context, and baggage, so that requests made with it continue the request's call graph.
+/// Metadata is not inherited.
 pub fn current() -> Context {
-    Context {
-        deadline: SystemTime::now() + Duration::from_secs(10),
-        trace_context: trace::Context::new_root(),
-        _non_exhaustive: (),
+    // The pointer is valid while it's current; see `set_current`.
+    CURRENT.with(|current| match unsafe { current.get().as_ref() } {
+        Some(ctx) => Context {
+            deadline: ctx.deadline,
+            trace_context: ctx.trace_context,
+            metadata: BTreeMap::new(),
+            baggage: ctx.baggage.clone(),
+            time_remaining: None,
+            request_id: None,
+            peer_identity: None,
+            _non_exhaustive: (),
+        },
+        None => Context {
+            deadline: SystemTime::now() + Duration::from_secs(10),
+            trace_context: trace::Context::new_root(),
+            metadata: BTreeMap::new(),
+            baggage: BTreeMap::new(),
+            time_remaining: None,
+            request_id: None,
+            peer_identity: None,
+            _non_exhaustive: (),
+        },
+    })
+}
+
+/// Makes `ctx` the current context until the returned guard is dropped.
+///
+/// # Safety
+///
+/// `ctx` must not move or be dropped before the guard is, and the guard must not be forgotten.
+pub(crate) unsafe fn set_current(ctx: &Context) -> CurrentGuard {
+    CurrentGuard(CURRENT.with(|current| current.replace(ctx)))
+}
+
+/// Restores the previously current context when dropped.
+#[derive(Debug)]
+pub(crate) struct CurrentGuard(*const Context);
+
+impl Drop for CurrentGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
     }
 }
 
 impl Context {
+    /// Replaces the deadline with one reconstructed from the time remaining when the request was
+    /// sent, if known, returning how far the sender's clock is ahead of the local clock, which
+    /// reads `now`, in seconds. The estimate includes the time the request spent in transit, and,
+    /// for serialized requests, error from the deadline being sent with second precision.
+    pub(crate) fn reconstruct_deadline(&mut self, now: SystemTime) -> Option<f64> {
+        let remaining = self.time_remaining.take()?;
+        let local = now + remaining;
+        let skew = match self.deadline.duration_since(local) {
+            Ok(ahead) => ahead.as_secs_f64(),
+            Err(behind) => -behind.duration().as_secs_f64(),
+        };
+        self.deadline = local;
+        Some(skew)
+    }
+
     /// Returns the ID of the request-scoped trace.
     pub fn trace_id(&self) -> &TraceId {
         &self.trace_context.trace_id
     }
+
+    /// Returns the ID of the request being handled, if this is the context of a request received
+    /// by a server. Request IDs are unique only among the requests of a single channel, so pair it
+    /// with the [trace ID](Context::trace_id) when correlating logs.
+    pub fn request_id(&self) -> Option<u64> {
+        self.request_id
+    }
+
+    /// Returns the authenticated identity of the client that sent the request, if this is the
+    /// context of a request received by a server over a channel whose peer was identified.
+    pub fn peer_identity(&self) -> Option<&PeerIdentity> {
+        self.peer_identity.as_ref().map(|identity| &**identity)
+    }
+
+    /// Records the request ID and trace ID in the `request_id` and `trace_id` fields of the
+    /// current [`tracing`] span, so that events logged inside it can be correlated with the
+    /// request. The span must declare the fields, e.g. as
+    /// [`tracing::field::Empty`](tracing::field::Empty), or they're ignored.
+    #[cfg(feature = "tracing")]
+    pub fn record_in_current_span(&self) {
+        let span = tracing::Span::current();
+        if let Some(request_id) = self.request_id {
+            span.record("request_id", &request_id);
+        }
+        span.record("trace_id", &tracing::field::display(self.trace_id()));
+    }
+
+    /// Returns the metadata value for `key`, if any.
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    /// Sets the metadata value for `key`, returning the context for chaining.
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Returns the bearer token of the [`AUTHORIZATION_METADATA_KEY`] metadata, if any.
+    pub fn bearer_token(&self) -> Option<&str> {
+        let value = self.metadata(AUTHORIZATION_METADATA_KEY)?;
+        let scheme = value.get(..BEARER.len())?;
+        if scheme.eq_ignore_ascii_case(BEARER) && value.len() > BEARER.len() {
+            Some(&value[BEARER.len()..])
+        } else {
+            None
+        }
+    }
+
+    /// Sets the [`AUTHORIZATION_METADATA_KEY`] metadata to `token`, as a bearer token, returning
+    /// the context for chaining.
+    pub fn with_bearer_token(self, token: &str) -> Self {
+        self.with_metadata(AUTHORIZATION_METADATA_KEY, format!("{}{}", BEARER, token))
+    }
+
+    /// Returns the baggage value for `key`, if any.
+    pub fn baggage(&self, key: &str) -> Option<&str> {
+        self.baggage.get(key).map(String::as_str)
+    }
+
+    /// Sets the baggage value for `key`, returning the context for chaining.
+    pub fn with_baggage(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.baggage.insert(key.into(), value.into());
+        self
+    }
+}
+
+/// Drops what isn't sent: the request ID and peer identity.
+impl From<Context> for proto::Context {
+    fn from(ctx: Context) -> Self {
+        let mut converted = proto::Context::default();
+        converted.deadline = ctx
+            .deadline
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        converted.trace_context = trace_context_to_proto(ctx.trace_context);
+        converted.metadata = ctx.metadata;
+        converted.baggage = ctx.baggage;
+        converted.time_remaining = ctx.time_remaining;
+        converted
+    }
+}
+
+impl From<proto::Context> for Context {
+    fn from(ctx: proto::Context) -> Self {
+        Context {
+            deadline: SystemTime::UNIX_EPOCH + Duration::from_secs(ctx.deadline),
+            trace_context: trace_context_from_proto(ctx.trace_context),
+            metadata: ctx.metadata,
+            baggage: ctx.baggage,
+            time_remaining: ctx.time_remaining,
+            request_id: None,
+            peer_identity: None,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+pub(crate) fn trace_context_to_proto(trace_context: trace::Context) -> proto::TraceContext {
+    proto::TraceContext {
+        trace_id: trace_context.trace_id.into(),
+        span_id: trace_context.span_id.into(),
+        parent_id: trace_context.parent_id.map(Into::into),
+        sampled: trace_context.sampled,
+    }
+}
+
+pub(crate) fn trace_context_from_proto(trace_context: proto::TraceContext) -> trace::Context {
+    trace::Context {
+        trace_id: trace_context.trace_id.into(),
+        span_id: trace_context.span_id.into(),
+        parent_id: trace_context.parent_id.map(Into::into),
+        sampled: trace_context.sampled,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconstruct_deadline_from_time_remaining() {
+        let mut ctx = current();
+        // The sender's clock is an hour ahead.
+        ctx.deadline = SystemTime::now() + Duration::from_secs(3600 + 10);
+        ctx.time_remaining = Some(Duration::from_secs(10));
+
+        let skew = ctx.reconstruct_deadline(SystemTime::now()).unwrap();
+        assert!((skew - 3600.).abs() < 1., "skew = {}", skew);
+        let timeout = ctx.deadline.duration_since(SystemTime::now()).unwrap();
+        assert!(timeout <= Duration::from_secs(10) && timeout > Duration::from_secs(9));
+        assert_eq!(ctx.reconstruct_deadline(SystemTime::now()), None);
+    }
+
+    #[test]
+    fn bearer_token_round_trips() {
+        let ctx = current().with_bearer_token("s3cr3t");
+        assert_eq!(
+            ctx.metadata(AUTHORIZATION_METADATA_KEY),
+            Some("Bearer s3cr3t")
+        );
+        assert_eq!(ctx.bearer_token(), Some("s3cr3t"));
+
+        let basic = current().with_metadata(AUTHORIZATION_METADATA_KEY, "Basic dXNlcg==");
+        assert_eq!(basic.bearer_token(), None);
+        let lowercase = current().with_metadata(AUTHORIZATION_METADATA_KEY, "bearer s3cr3t");
+        assert_eq!(lowercase.bearer_token(), Some("s3cr3t"));
+    }
 }
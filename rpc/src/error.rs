@@ -0,0 +1,199 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a classification of the errors clients see, so that retry policies and metrics can
+//! tell failures apart without inspecting error messages.
+
+use crate::ServerError;
+use std::{error::Error, fmt, io};
+
+/// The detail of the error with which servers reject requests that lack valid credentials.
+pub const UNAUTHENTICATED: &str = "Unauthenticated: the request lacks valid credentials.";
+
+/// Returns a `PermissionDenied` error with `detail`, marked as
+/// [unauthenticated](ErrorKind::Unauthenticated).
+pub fn unauthenticated(detail: impl Into<String>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        Unauthenticated(detail.into()),
+    )
+}
+
+/// The inner error of [unauthenticated](unauthenticated) `io::Error`s, which marks them as such.
+#[derive(Debug)]
+struct Unauthenticated(String);
+
+impl fmt::Display for Unauthenticated {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for Unauthenticated {}
+
+/// The broad category of a failed request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The connection to the server failed or closed.
+    Transport,
+    /// A message couldn't be decoded.
+    Decode,
+    /// The request didn't complete before its deadline.
+    Deadline,
+    /// The request was canceled by the client before it completed, e.g. because the client's
+    /// dispatch task was dropped when its runtime shut down.
+    Cancelled,
+    /// The server rejected the request because it was at capacity.
+    Overloaded,
+    /// The server rejected the request's credentials, e.g. an expired
+    /// [bearer token](crate::context::Context::bearer_token). Only errors marked
+    /// [unauthenticated](ServerError::unauthenticated) are; other `PermissionDenied` errors, such
+    /// as authorization failures, are application errors, whatever their detail says.
+    Unauthenticated,
+    /// Any other error, including those raised by the service itself.
+    Application,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl ErrorKind {
+    /// Returns whether a request that failed this way may succeed if sent again.
+    ///
+    /// Only [transport](ErrorKind::Transport) failures and [overloaded](ErrorKind::Overloaded)
+    /// rejections are retryable, since a new connection or a less loaded server may succeed. A
+    /// transport failure may happen after the server acted on the request, so only idempotent
+    /// requests should be retried. No other kind is retryable: a retry would share an exceeded
+    /// deadline, repeat a decode or application error, be cancelled again, or be unauthenticated
+    /// again until it's sent with fresh credentials.
+    pub fn is_retryable(self) -> bool {
+        match self {
+            ErrorKind::Transport | ErrorKind::Overloaded => true,
+            _ => false,
+        }
+    }
+
+    /// Returns a short, stable name for the kind, suitable as a metrics label.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorKind::Transport => "transport",
+            ErrorKind::Decode => "decode",
+            ErrorKind::Deadline => "deadline",
+            ErrorKind::Cancelled => "cancelled",
+            ErrorKind::Overloaded => "overloaded",
+            ErrorKind::Unauthenticated => "unauthenticated",
+            ErrorKind::Application => "application",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<io::ErrorKind> for ErrorKind {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::AddrInUse
+            | io::ErrorKind::AddrNotAvailable
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => ErrorKind::Transport,
+            io::ErrorKind::InvalidData => ErrorKind::Decode,
+            io::ErrorKind::TimedOut => ErrorKind::Deadline,
+            io::ErrorKind::Interrupted => ErrorKind::Cancelled,
+            io::ErrorKind::WouldBlock => ErrorKind::Overloaded,
+            _ => ErrorKind::Application,
+        }
+    }
+}
+
+/// An error that can be [classified](ErrorKind).
+pub trait Classify {
+    /// Returns the category of the error.
+    fn classify(&self) -> ErrorKind;
+
+    /// Returns whether a request that failed with this error may succeed if sent again.
+    fn is_retryable(&self) -> bool {
+        self.classify().is_retryable()
+    }
+}
+
+impl Classify for io::Error {
+    fn classify(&self) -> ErrorKind {
+        if self
+            .get_ref()
+            .map_or(false, |inner| inner.is::<Unauthenticated>())
+        {
+            ErrorKind::Unauthenticated
+        } else {
+            self.kind().into()
+        }
+    }
+}
+
+impl Classify for ServerError {
+    fn classify(&self) -> ErrorKind {
+        if self.unauthenticated {
+            ErrorKind::Unauthenticated
+        } else {
+            self.kind.into()
+        }
+    }
+}
+
+#[test]
+fn classify_client_errors() {
+    assert_eq!(
+        io::Error::from(io::ErrorKind::ConnectionReset).classify(),
+        ErrorKind::Transport
+    );
+    assert_eq!(
+        io::Error::new(io::ErrorKind::TimedOut, "Client dropped expired request.").classify(),
+        ErrorKind::Deadline
+    );
+    assert_eq!(
+        io::Error::from(io::ErrorKind::InvalidData).classify(),
+        ErrorKind::Decode
+    );
+    assert_eq!(
+        io::Error::new(io::ErrorKind::Other, "boom").classify(),
+        ErrorKind::Application
+    );
+
+    let throttled = ServerError::new(
+        io::ErrorKind::WouldBlock,
+        Some("Server throttled the request.".into()),
+    );
+    assert_eq!(throttled.classify(), ErrorKind::Overloaded);
+    assert!(throttled.is_retryable());
+    assert!(!io::Error::from(io::ErrorKind::TimedOut).is_retryable());
+
+    let mut unauthenticated = ServerError::new(
+        io::ErrorKind::PermissionDenied,
+        Some("Token expired.".into()),
+    );
+    unauthenticated.unauthenticated = true;
+    assert_eq!(unauthenticated.classify(), ErrorKind::Unauthenticated);
+    let unauthenticated = io::Error::from(unauthenticated);
+    assert_eq!(unauthenticated.classify(), ErrorKind::Unauthenticated);
+    assert_eq!(unauthenticated.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(unauthenticated.to_string(), "Token expired.");
+    let forbidden = io::Error::new(io::ErrorKind::PermissionDenied, "Not an admin.");
+    assert_eq!(forbidden.classify(), ErrorKind::Application);
+    // Only the marker counts, not the detail.
+    let unmarked = io::Error::new(io::ErrorKind::PermissionDenied, UNAUTHENTICATED);
+    assert_eq!(unmarked.classify(), ErrorKind::Application);
+    assert_eq!(
+        io::Error::from(io::ErrorKind::PermissionDenied).classify(),
+        ErrorKind::Application
+    );
+}
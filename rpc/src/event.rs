@@ -0,0 +1,888 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides structured events describing the lifecycle of channels and requests.
+//!
+//! Clients and servers report what they're doing to an [`EventSink`] rather than writing log
+//! lines directly, as do the audit log, span exporter, StatsD recorder, and balancing client. The
+//! default sink, [`LogSink`], forwards each event to the `log` crate, or to `tracing` when the
+//! `tracing` feature is enabled, but high-volume deployments can install a sink that samples
+//! events or writes them to a structured backend instead.
+//!
+//! For automating on connection churn, [`connection_events`] returns a sink paired with a stream
+//! of the channel lifecycle events it sees.
+
+use crate::ServerError;
+use futures::channel::mpsc;
+use humantime::format_rfc3339;
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use trace::TraceId;
+
+/// Something that happened to a channel or request.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// A channel filter accepted a new channel.
+    ChannelOpened {
+        /// The key the channel was filed under.
+        key: fmt::Arguments<'a>,
+        /// The number of channels open for the key, including the new one.
+        channels: usize,
+        /// The maximum number of channels allowed per key.
+        limit: u32,
+    },
+    /// A channel filter dropped a new channel because its key was at the limit.
+    ChannelRejected {
+        /// The key the channel would have been filed under.
+        key: fmt::Arguments<'a>,
+        /// The number of channels open for the key.
+        channels: usize,
+        /// The maximum number of channels allowed per key.
+        limit: u32,
+    },
+    /// A channel accepted by a channel filter closed.
+    ChannelClosed {
+        /// The key the channel was filed under.
+        key: fmt::Arguments<'a>,
+        /// The number of channels still open for the key.
+        channels: usize,
+    },
+    /// Every channel for a key closed.
+    KeyClosed {
+        /// The key that no longer has any channels.
+        key: fmt::Arguments<'a>,
+    },
+    /// A channel filter's listener stopped producing channels.
+    ListenerClosed,
+    /// A server received a request.
+    RequestReceived {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+        /// Whether the client is waiting for a response.
+        one_way: bool,
+        /// When the client expects the response by.
+        deadline: SystemTime,
+    },
+    /// A server canceled an in-flight request at the client's behest.
+    RequestCanceled {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+        /// The number of requests still in flight on the channel.
+        in_flight_requests: usize,
+    },
+    /// A server received a cancellation for a request that was already complete.
+    CancellationIgnored {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+    },
+    /// A server rejected a request because its channel was at the in-flight request limit.
+    RequestThrottled {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+        /// Whether the request was one-way, in which case the client isn't told.
+        one_way: bool,
+        /// The number of requests in flight on the channel.
+        in_flight_requests: usize,
+        /// The maximum number of requests allowed in flight on the channel.
+        limit: usize,
+    },
+    /// A server rejected a request because it lacked a valid bearer token.
+    RequestUnauthenticated {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+        /// Whether the request was one-way, in which case the client isn't told.
+        one_way: bool,
+    },
+    /// A server rejected a request that its service doesn't serve, such as a call to an RPC
+    /// removed from every version the server supports.
+    RequestRejected {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+        /// Whether the request was one-way, in which case the client isn't told.
+        one_way: bool,
+        /// The error the server responds with.
+        error: &'a ServerError,
+    },
+    /// A server rejected a request because a request with the same ID was already in flight on
+    /// its channel.
+    DuplicateRequest {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The ID the request shares with a request in flight.
+        request_id: u64,
+    },
+    /// A server stopped reading a channel's requests because the channel was at its limit of
+    /// buffered bytes.
+    BufferLimitReached {
+        /// The bytes buffered by the channel's requests and responses.
+        buffered_bytes: usize,
+        /// The most bytes the channel may buffer.
+        limit: usize,
+    },
+    /// A server finished a one-way request.
+    OneWayComplete {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+    },
+    /// A server didn't finish a request before its deadline.
+    DeadlineExceeded {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+        /// When the client expected the response by.
+        deadline: SystemTime,
+    },
+    /// A server is writing a response to its channel.
+    ResponseStaged {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+        /// The number of requests in flight on the channel.
+        in_flight_requests: usize,
+    },
+    /// A server request has been in flight longer than the watchdog's threshold.
+    LongCall {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its channel.
+        request_id: u64,
+        /// The name of the method invoked, or `"unknown"` if the server can't name it.
+        method: &'static str,
+        /// The authenticated principal of the client, or the channel's filter key, if either is
+        /// known.
+        peer: Option<&'a str>,
+        /// How long the request has been in flight.
+        elapsed: Duration,
+        /// How long ago the handler was last polled.
+        since_last_poll: Duration,
+        /// Whether the handler has been woken but not yet polled.
+        awaiting_poll: bool,
+    },
+    /// A server stopped handling a channel because of an error.
+    ChannelErrored {
+        /// The error that stopped the channel.
+        error: &'a io::Error,
+    },
+    /// A server stopped accepting channels.
+    ServerShutdown,
+    /// A client queued a request to be sent by its dispatch.
+    RequestQueued {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its client.
+        request_id: u64,
+        /// How long the client will wait for a response, or `None` for one-way requests.
+        timeout: Option<Duration>,
+        /// Whether the server may respond with a stream of responses.
+        streaming: bool,
+    },
+    /// A client dispatch dropped a request because the caller stopped waiting before it was sent.
+    RequestCanceledBeforeSend {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its client.
+        request_id: u64,
+    },
+    /// A client dispatch stopped tracking a request because the caller stopped waiting.
+    RequestRemoved {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its client.
+        request_id: u64,
+    },
+    /// A client dispatch sent a request to the server.
+    RequestSent {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its client.
+        request_id: u64,
+    },
+    /// A client dispatch sent a cancellation to the server.
+    CancellationSent {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its client.
+        request_id: u64,
+    },
+    /// A client's streaming request outlived its deadline.
+    StreamExpired {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its client.
+        request_id: u64,
+    },
+    /// A client dispatch stopped reading requests because it was at the in-flight request limit.
+    AtCapacity {
+        /// The number of requests in flight.
+        in_flight_requests: usize,
+        /// The maximum number of requests allowed in flight.
+        limit: usize,
+    },
+    /// A client dispatch received a response.
+    ResponseReceived {
+        /// The trace the request is part of.
+        trace_id: TraceId,
+        /// The request's id, unique to its client.
+        request_id: u64,
+        /// Whether more responses to the request will follow.
+        partial: bool,
+    },
+    /// A client dispatch received a response to a request it isn't tracking, most likely because
+    /// the request was canceled.
+    UnknownResponse {
+        /// The id of the request the response was for.
+        request_id: u64,
+    },
+    /// A client dispatch dropped a partial response to a request that expects only one response.
+    PartialResponseDropped {
+        /// The id of the request the response was for.
+        request_id: u64,
+    },
+    /// A client dispatch's channels were all dropped.
+    DispatchShutdown {
+        /// The number of requests still awaiting responses.
+        in_flight_requests: usize,
+    },
+    /// A client dispatch stopped because of an error.
+    ConnectionBroken {
+        /// The error that broke the connection.
+        error: &'a io::Error,
+    },
+    /// A balancing client's resolver failed to resolve its endpoints, leaving the current ones in
+    /// place.
+    EndpointsUnresolved {
+        /// The resolver's error.
+        error: &'a io::Error,
+    },
+    /// A balancing client connected to a new endpoint.
+    EndpointAdded {
+        /// The endpoint's address.
+        addr: SocketAddr,
+    },
+    /// A balancing client dropped an endpoint its resolver no longer reports.
+    EndpointRemoved {
+        /// The endpoint's address.
+        addr: SocketAddr,
+    },
+    /// A balancing client failed to connect to a new endpoint.
+    EndpointUnreachable {
+        /// The endpoint's address.
+        addr: SocketAddr,
+        /// The connection error.
+        error: &'a io::Error,
+    },
+    /// A queue of records to write in the background, such as the audit log or span exporter,
+    /// dropped a record because it was full.
+    QueueFull {
+        /// What the queue holds, e.g. `"audit records"`.
+        queue: &'static str,
+        /// The number of records the queue has dropped so far.
+        dropped: u64,
+    },
+    /// A batch of queued records failed to be written.
+    BatchWriteFailed {
+        /// What the queue holds, e.g. `"audit records"`.
+        queue: &'static str,
+        /// The number of records in the batch, which are lost.
+        records: usize,
+        /// The sink's error.
+        error: &'a io::Error,
+    },
+    /// A metrics recorder failed to send a metric, which is lost.
+    MetricDropped {
+        /// The metric, as the recorder encodes it.
+        metric: &'a str,
+        /// The error sending it.
+        error: &'a io::Error,
+    },
+    /// A server shard could not be pinned to a CPU, so it runs unpinned.
+    ShardNotPinned {
+        /// The shard's index.
+        shard: usize,
+        /// The CPU the shard was to be pinned to, or `None` if the CPUs the process is allowed to
+        /// run on could not be determined, so that no shard is pinned.
+        cpu: Option<usize>,
+        /// The error pinning the shard.
+        error: &'a io::Error,
+    },
+    /// A TLS certificate resolver failed to reload the files it watches, so it keeps serving
+    /// the certificate it has.
+    CertReloadFailed {
+        /// The error reloading the files.
+        error: &'a io::Error,
+    },
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl Event<'_> {
+    /// Returns how noteworthy the event is. [`LogSink`] logs each event at this level.
+    pub fn level(&self) -> log::Level {
+        use log::Level;
+
+        match self {
+            Event::ChannelRejected { .. }
+            | Event::ChannelErrored { .. }
+            | Event::ServerShutdown
+            | Event::AtCapacity { .. }
+            | Event::BufferLimitReached { .. }
+            | Event::DispatchShutdown { .. } => Level::Info,
+            Event::LongCall { .. } => Level::Warn,
+            Event::EndpointsUnresolved { .. }
+            | Event::EndpointUnreachable { .. }
+            | Event::QueueFull { .. }
+            | Event::ShardNotPinned { .. }
+            | Event::CertReloadFailed { .. } => Level::Warn,
+            Event::ConnectionBroken { .. } | Event::BatchWriteFailed { .. } => Level::Error,
+            Event::KeyClosed { .. }
+            | Event::EndpointAdded { .. }
+            | Event::EndpointRemoved { .. }
+            | Event::MetricDropped { .. }
+            | Event::RequestThrottled { .. }
+            | Event::RequestUnauthenticated { .. }
+            | Event::RequestRejected { .. }
+            | Event::DuplicateRequest { .. }
+            | Event::DeadlineExceeded { .. }
+            | Event::RequestRemoved { .. }
+            | Event::StreamExpired { .. }
+            | Event::UnknownResponse { .. }
+            | Event::PartialResponseDropped { .. } => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::ChannelOpened {
+                key,
+                channels,
+                limit,
+            } => write!(
+                f,
+                "[{}] Opening channel ({}/{}) channels for key.",
+                key, channels, limit
+            ),
+            Event::ChannelRejected {
+                key,
+                channels,
+                limit,
+            } => write!(
+                f,
+                "[{}] Opened max channels from key ({}/{}).",
+                key, channels, limit
+            ),
+            Event::ChannelClosed { key, channels } => write!(
+                f,
+                "[{}] Channel closed; {} channels left for key.",
+                key, channels
+            ),
+            Event::KeyClosed { key } => write!(f, "All channels dropped for key [{}]", key),
+            Event::ListenerClosed => write!(f, "Shutting down listener."),
+            Event::RequestReceived {
+                trace_id,
+                one_way,
+                deadline,
+                ..
+            } => write!(
+                f,
+                "[{}] Received {}request with deadline {}.",
+                trace_id,
+                if *one_way { "one-way " } else { "" },
+                format_rfc3339(*deadline),
+            ),
+            Event::RequestCanceled {
+                trace_id,
+                in_flight_requests,
+                ..
+            } => write!(
+                f,
+                "[{}] Request canceled. In-flight requests = {}",
+                trace_id, in_flight_requests
+            ),
+            Event::CancellationIgnored { trace_id, .. } => write!(
+                f,
+                "[{}] Received cancellation, but response handler is already complete.",
+                trace_id
+            ),
+            Event::RequestThrottled {
+                trace_id,
+                one_way,
+                in_flight_requests,
+                limit,
+                ..
+            } => write!(
+                f,
+                "[{}] Client has reached in-flight request limit ({}/{}){}.",
+                trace_id,
+                in_flight_requests,
+                limit,
+                if *one_way {
+                    "; dropping one-way request"
+                } else {
+                    ""
+                }
+            ),
+            Event::RequestUnauthenticated {
+                trace_id, one_way, ..
+            } => write!(
+                f,
+                "[{}] Request lacks a valid bearer token{}.",
+                trace_id,
+                if *one_way {
+                    "; dropping one-way request"
+                } else {
+                    ""
+                }
+            ),
+            Event::RequestRejected {
+                trace_id,
+                one_way,
+                error,
+                ..
+            } => write!(
+                f,
+                "[{}] Rejecting request: {}{}.",
+                trace_id,
+                error
+                    .detail
+                    .as_ref()
+                    .map(String::as_str)
+                    .unwrap_or("not served"),
+                if *one_way {
+                    "; dropping one-way request"
+                } else {
+                    ""
+                }
+            ),
+            Event::DuplicateRequest {
+                trace_id,
+                request_id,
+            } => write!(
+                f,
+                "[{}] Request ID {} is already in flight; rejecting the duplicate.",
+                trace_id, request_id
+            ),
+            Event::BufferLimitReached {
+                buffered_bytes,
+                limit,
+            } => write!(
+                f,
+                "Channel has reached buffered bytes limit ({}/{}); pausing reads.",
+                buffered_bytes, limit
+            ),
+            Event::OneWayComplete { trace_id, .. } => write!(
+                f,
+                "[{}] One-way request complete; not sending a response.",
+                trace_id
+            ),
+            Event::DeadlineExceeded {
+                trace_id, deadline, ..
+            } => write!(
+                f,
+                "[{}] Response did not complete before deadline of {}s.",
+                trace_id,
+                format_rfc3339(*deadline)
+            ),
+            Event::ResponseStaged {
+                trace_id,
+                in_flight_requests,
+                ..
+            } => write!(
+                f,
+                "[{}] Staging response. In-flight requests = {}.",
+                trace_id, in_flight_requests
+            ),
+            Event::LongCall {
+                trace_id,
+                request_id,
+                method,
+                peer,
+                elapsed,
+                since_last_poll,
+                awaiting_poll,
+            } => write!(
+                f,
+                "[{}] Request {} to {} from {} has been in flight for {:?}; \
+                 last polled {:?} ago{}.",
+                trace_id,
+                request_id,
+                method,
+                peer.unwrap_or("unknown peer"),
+                elapsed,
+                since_last_poll,
+                if *awaiting_poll {
+                    ", and woken since"
+                } else {
+                    ""
+                }
+            ),
+            Event::ChannelErrored { error } => write!(f, "ClientHandler errored out: {}", error),
+            Event::ServerShutdown => write!(f, "Server shutting down."),
+            Event::RequestQueued {
+                trace_id,
+                timeout: Some(timeout),
+                streaming,
+                ..
+            } => write!(
+                f,
+                "[{}] Queuing {}request with timeout {:?}.",
+                trace_id,
+                if *streaming { "streaming " } else { "" },
+                timeout
+            ),
+            Event::RequestQueued {
+                trace_id,
+                timeout: None,
+                ..
+            } => write!(f, "[{}] Queuing one-way request.", trace_id),
+            Event::RequestCanceledBeforeSend { trace_id, .. } => {
+                write!(f, "[{}] Request canceled before being sent.", trace_id)
+            }
+            Event::RequestRemoved { trace_id, .. } => write!(f, "[{}] Removed request.", trace_id),
+            Event::RequestSent { trace_id, .. } => write!(f, "[{}] Request sent.", trace_id),
+            Event::CancellationSent { trace_id, .. } => {
+                write!(f, "[{}] Cancel message sent.", trace_id)
+            }
+            Event::StreamExpired { trace_id, .. } => {
+                write!(f, "[{}] Streaming request expired.", trace_id)
+            }
+            Event::AtCapacity {
+                in_flight_requests,
+                limit,
+            } => write!(
+                f,
+                "At in-flight request capacity ({}/{}).",
+                in_flight_requests, limit
+            ),
+            Event::ResponseReceived {
+                trace_id,
+                partial: true,
+                ..
+            } => write!(f, "[{}] Received partial response.", trace_id),
+            Event::ResponseReceived { trace_id, .. } => {
+                write!(f, "[{}] Received response.", trace_id)
+            }
+            Event::UnknownResponse { request_id } => write!(
+                f,
+                "No in-flight request found for request_id = {}.",
+                request_id
+            ),
+            Event::PartialResponseDropped { request_id } => write!(
+                f,
+                "Dropping partial response to unary request {}.",
+                request_id
+            ),
+            Event::DispatchShutdown {
+                in_flight_requests: 0,
+            } => write!(f, "Shutdown: write half closed, and no requests in flight."),
+            Event::DispatchShutdown { in_flight_requests } => write!(
+                f,
+                "Shutdown: write half closed, and {} requests in flight.",
+                in_flight_requests
+            ),
+            Event::ConnectionBroken { error } => write!(f, "Connection broken: {}", error),
+            Event::EndpointsUnresolved { error } => {
+                write!(f, "Failed to resolve endpoints: {}", error)
+            }
+            Event::EndpointAdded { addr } => write!(f, "Endpoint {} added.", addr),
+            Event::EndpointRemoved { addr } => write!(f, "Endpoint {} removed.", addr),
+            Event::EndpointUnreachable { addr, error } => {
+                write!(f, "Failed to connect to endpoint {}: {}", addr, error)
+            }
+            Event::QueueFull { queue, dropped } => {
+                write!(f, "Queue of {} is full; dropped {} so far.", queue, dropped)
+            }
+            Event::BatchWriteFailed {
+                queue,
+                records,
+                error,
+            } => write!(f, "Failed to write {} {}: {}", records, queue, error),
+            Event::MetricDropped { metric, error } => {
+                write!(f, "Failed to send metric {:?}: {}", metric, error)
+            }
+            Event::ShardNotPinned {
+                shard,
+                cpu: Some(cpu),
+                error,
+            } => write!(f, "Failed to pin shard {} to CPU {}: {}", shard, cpu, error),
+            Event::ShardNotPinned { error, .. } => {
+                write!(f, "Failed to find the CPUs to pin shards to: {}", error)
+            }
+            Event::CertReloadFailed { error } => {
+                write!(f, "Failed to reload the TLS certificate: {}", error)
+            }
+            _ => write!(f, "Unknown event."),
+        }
+    }
+}
+
+/// Receives the events of clients and servers.
+///
+/// Sinks are called inline on the task that produced the event, so they should be quick; a sink
+/// that does I/O should buffer and do it elsewhere.
+pub trait EventSink: fmt::Debug + Send + Sync {
+    /// Handles an event.
+    fn event(&self, event: &Event);
+}
+
+/// Writes events to the `log` crate at [their level](Event::level).
+///
+/// With the `tracing` feature, events are written to `tracing` instead, in the span of the request
+/// they concern, if any. `tracing` still forwards them to `log` while no subscriber is installed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogSink;
+
+impl EventSink for LogSink {
+    #[cfg(not(feature = "tracing"))]
+    fn event(&self, event: &Event) {
+        log::log!(event.level(), "{}", event);
+    }
+
+    #[cfg(feature = "tracing")]
+    fn event(&self, event: &Event) {
+        use tracing::Level;
+
+        match event.level() {
+            log::Level::Error => tracing::event!(Level::ERROR, "{}", event),
+            log::Level::Warn => tracing::event!(Level::WARN, "{}", event),
+            log::Level::Info => tracing::event!(Level::INFO, "{}", event),
+            log::Level::Debug => tracing::event!(Level::DEBUG, "{}", event),
+            log::Level::Trace => tracing::event!(Level::TRACE, "{}", event),
+        }
+    }
+}
+
+/// Discards every event.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullSink;
+
+impl EventSink for NullSink {
+    fn event(&self, _: &Event) {}
+}
+
+/// A change in the channels of a server, owned so that it can outlive the [`Event`] it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// A channel filter accepted a new channel.
+    Opened {
+        /// The key the channel was filed under.
+        key: String,
+        /// The number of channels open for the key, including the new one.
+        channels: usize,
+        /// The maximum number of channels allowed per key.
+        limit: u32,
+    },
+    /// A channel filter dropped a new channel because its key was at the limit.
+    Rejected {
+        /// The key the channel would have been filed under.
+        key: String,
+        /// The number of channels open for the key.
+        channels: usize,
+        /// The maximum number of channels allowed per key.
+        limit: u32,
+    },
+    /// A channel accepted by a channel filter closed.
+    Closed {
+        /// The key the channel was filed under.
+        key: String,
+        /// The number of channels still open for the key.
+        channels: usize,
+    },
+    /// Every channel for a key closed.
+    KeyClosed {
+        /// The key that no longer has any channels.
+        key: String,
+    },
+    /// A server stopped handling a channel because of an error, such as a transport failing to
+    /// decode a message.
+    Errored {
+        /// The kind of the error that stopped the channel.
+        kind: io::ErrorKind,
+        /// The error, as rendered by `Display`.
+        message: String,
+    },
+    /// A channel filter's listener stopped producing channels.
+    ListenerClosed,
+    /// A server stopped accepting channels.
+    ServerShutdown,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl ConnectionEvent {
+    /// Returns the connection event an event describes, if any.
+    pub fn from_event(event: &Event) -> Option<Self> {
+        Some(match event {
+            Event::ChannelOpened {
+                key,
+                channels,
+                limit,
+            } => ConnectionEvent::Opened {
+                key: key.to_string(),
+                channels: *channels,
+                limit: *limit,
+            },
+            Event::ChannelRejected {
+                key,
+                channels,
+                limit,
+            } => ConnectionEvent::Rejected {
+                key: key.to_string(),
+                channels: *channels,
+                limit: *limit,
+            },
+            Event::ChannelClosed { key, channels } => ConnectionEvent::Closed {
+                key: key.to_string(),
+                channels: *channels,
+            },
+            Event::KeyClosed { key } => ConnectionEvent::KeyClosed {
+                key: key.to_string(),
+            },
+            Event::ChannelErrored { error } => ConnectionEvent::Errored {
+                kind: error.kind(),
+                message: error.to_string(),
+            },
+            Event::ListenerClosed => ConnectionEvent::ListenerClosed,
+            Event::ServerShutdown => ConnectionEvent::ServerShutdown,
+            _ => return None,
+        })
+    }
+}
+
+/// Forwards every event to another sink, and sends [connection events](ConnectionEvent) to a
+/// stream.
+///
+/// Created by [`connection_events`].
+#[derive(Debug)]
+pub struct ConnectionEventSink {
+    forward: Arc<dyn EventSink>,
+    tx: mpsc::UnboundedSender<ConnectionEvent>,
+}
+
+impl EventSink for ConnectionEventSink {
+    fn event(&self, event: &Event) {
+        self.forward.event(event);
+        if let Some(event) = ConnectionEvent::from_event(event) {
+            // Don't care if the stream is dropped.
+            let _ = self.tx.unbounded_send(event);
+        }
+    }
+}
+
+/// Returns a sink that forwards every event to `forward`, and the stream of connection events the
+/// sink sees.
+///
+/// The stream is unbounded: connection events are rare compared to request events, but a stream
+/// that's never read still grows with every channel opened.
+pub fn connection_events(
+    forward: Arc<dyn EventSink>,
+) -> (
+    ConnectionEventSink,
+    mpsc::UnboundedReceiver<ConnectionEvent>,
+) {
+    let (tx, rx) = mpsc::unbounded();
+    (ConnectionEventSink { forward, tx }, rx)
+}
+
+#[test]
+fn log_sink_levels_match_messages() {
+    let trace_id = TraceId::default();
+    let received = Event::RequestReceived {
+        trace_id,
+        request_id: 0,
+        one_way: true,
+        deadline: SystemTime::UNIX_EPOCH,
+    };
+    assert_eq!(received.level(), log::Level::Trace);
+    assert_eq!(
+        received.to_string(),
+        format!(
+            "[{}] Received one-way request with deadline 1970-01-01T00:00:00Z.",
+            trace_id
+        )
+    );
+
+    let throttled = Event::RequestThrottled {
+        trace_id,
+        request_id: 0,
+        one_way: false,
+        in_flight_requests: 3,
+        limit: 3,
+    };
+    assert_eq!(throttled.level(), log::Level::Debug);
+    assert_eq!(
+        throttled.to_string(),
+        format!(
+            "[{}] Client has reached in-flight request limit (3/3).",
+            trace_id
+        )
+    );
+
+    let unauthenticated = Event::RequestUnauthenticated {
+        trace_id,
+        request_id: 0,
+        one_way: true,
+    };
+    assert_eq!(unauthenticated.level(), log::Level::Debug);
+    assert_eq!(
+        unauthenticated.to_string(),
+        format!(
+            "[{}] Request lacks a valid bearer token; dropping one-way request.",
+            trace_id
+        )
+    );
+
+    let duplicate = Event::DuplicateRequest {
+        trace_id,
+        request_id: 7,
+    };
+    assert_eq!(duplicate.level(), log::Level::Debug);
+    assert_eq!(
+        duplicate.to_string(),
+        format!(
+            "[{}] Request ID 7 is already in flight; rejecting the duplicate.",
+            trace_id
+        )
+    );
+
+    let error = io::Error::from(io::ErrorKind::ConnectionReset);
+    assert_eq!(
+        Event::ConnectionBroken { error: &error }.level(),
+        log::Level::Error
+    );
+
+    let full = Event::QueueFull {
+        queue: "audit records",
+        dropped: 4,
+    };
+    assert_eq!(full.level(), log::Level::Warn);
+    assert_eq!(
+        full.to_string(),
+        "Queue of audit records is full; dropped 4 so far."
+    );
+}
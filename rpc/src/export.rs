@@ -0,0 +1,419 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Exports the spans of finished requests to a distributed tracing backend.
+//!
+//! Clients and servers export a span for each sampled request when the `spans` field of their
+//! config is set to a [`SpanExporter`]. Spans are queued in memory and exported in batches on a
+//! background thread, so that a slow collector never blocks requests. When the queue is full,
+//! spans are dropped and counted.
+//!
+//! [`ZipkinSink`] posts spans to a Zipkin collector, or to Jaeger's Zipkin-compatible endpoint.
+//! A client and server share the span of a request, as Zipkin expects: the client's span has kind
+//! [`Client`](SpanKind::Client), and the server's has the same ID and kind
+//! [`Server`](SpanKind::Server).
+
+use crate::{
+    event::{EventSink, LogSink},
+    util::batch,
+};
+use futures::executor;
+use std::{
+    fmt::{self, Write as _},
+    io::{self, BufRead, Write as _},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Which side of a request a span was recorded on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SpanKind {
+    /// The span covers a request from when the client sent it until the response arrived.
+    Client,
+    /// The span covers a request from when the server received it until the response was ready.
+    Server,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+/// The span of a finished request.
+#[derive(Clone, Debug)]
+pub struct FinishedSpan {
+    /// The trace context the request was sent with.
+    pub trace_context: trace::Context,
+    /// Which side of the request recorded the span.
+    pub kind: SpanKind,
+    /// The name of the method invoked, if known. Servers name the methods they serve; clients name
+    /// the methods they call if their channel
+    /// [names them](crate::client::Channel::with_method_names).
+    pub name: Option<&'static str>,
+    /// When the span started.
+    pub start: SystemTime,
+    /// How long the span lasted.
+    pub duration: Duration,
+    /// Why the request failed, if it did.
+    pub error: Option<String>,
+    #[doc(hidden)]
+    pub(crate) _non_exhaustive: (),
+}
+
+impl FinishedSpan {
+    /// Returns the span of a request that started `start` and finished now.
+    pub(crate) fn finish(
+        trace_context: trace::Context,
+        kind: SpanKind,
+        name: Option<&'static str>,
+        start: Instant,
+        error: Option<String>,
+    ) -> Self {
+        let duration = start.elapsed();
+        FinishedSpan {
+            trace_context,
+            kind,
+            name,
+            start: SystemTime::now() - duration,
+            duration,
+            error,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+/// A destination for finished spans.
+pub trait ExportSink {
+    /// Exports a batch of spans.
+    fn export(&mut self, spans: &[FinishedSpan]) -> io::Result<()>;
+}
+
+/// Queues finished spans to be exported on a background thread.
+#[derive(Clone, Debug)]
+pub struct SpanExporter {
+    spans: batch::Queue<FinishedSpan>,
+}
+
+impl SpanExporter {
+    /// Returns an exporter queuing up to `capacity` spans, which a thread named
+    /// `tarpc-span-export` exports to `sink` in batches. Exports may block, e.g. on a slow
+    /// collector, so they never run on the executor serving requests. The thread exits once every
+    /// clone of the exporter is dropped and the queue is drained.
+    pub fn new<S>(sink: S, capacity: usize) -> io::Result<SpanExporter>
+    where
+        S: ExportSink + Send + 'static,
+    {
+        SpanExporter::with_events(sink, capacity, Arc::new(LogSink))
+    }
+
+    /// Like [`new`](SpanExporter::new), but reports dropped spans and failed exports to `events`
+    /// rather than logging them.
+    pub fn with_events<S>(
+        sink: S,
+        capacity: usize,
+        events: Arc<dyn EventSink>,
+    ) -> io::Result<SpanExporter>
+    where
+        S: ExportSink + Send + 'static,
+    {
+        let (exporter, writer) = SpanExporter::queue(sink, capacity, events);
+        thread::Builder::new()
+            .name("tarpc-span-export".into())
+            .spawn(move || executor::block_on(writer))?;
+        Ok(exporter)
+    }
+
+    /// Returns an exporter and the writer that exports its spans to `sink`, to run wherever the
+    /// caller likes.
+    fn queue<S: ExportSink>(
+        sink: S,
+        capacity: usize,
+        events: Arc<dyn EventSink>,
+    ) -> (SpanExporter, batch::Writer<FinishedSpan, S>) {
+        let (spans, writer) = batch::queue("spans", sink, capacity, events);
+        (SpanExporter { spans }, writer)
+    }
+
+    /// Queues `span` to be exported, dropping it if the queue is full.
+    pub fn export(&self, span: FinishedSpan) {
+        self.spans.push(span);
+    }
+
+    /// Returns the number of spans dropped because the queue was full or the export thread was
+    /// gone.
+    pub fn dropped(&self) -> u64 {
+        self.spans.dropped()
+    }
+}
+
+impl<S: ExportSink> batch::BatchSink<FinishedSpan> for S {
+    fn write(&mut self, batch: &[FinishedSpan]) -> io::Result<()> {
+        self.export(batch)
+    }
+}
+
+/// Posts spans to a Zipkin collector in the [Zipkin v2 JSON
+/// format](https://zipkin.io/zipkin-api/#/default/post_spans).
+///
+/// Jaeger accepts the same format when its collector's Zipkin endpoint is enabled. Each batch is
+/// posted over a new HTTP/1.1 connection, blocking the export thread until the collector responds
+/// or the timeout elapses.
+#[derive(Clone, Debug)]
+pub struct ZipkinSink {
+    collector: String,
+    path: String,
+    service_name: String,
+    timeout: Duration,
+}
+
+impl ZipkinSink {
+    /// Returns a sink posting to the collector at `collector`, e.g. `"localhost:9411"`, on behalf
+    /// of the service named `service_name`.
+    pub fn new(collector: impl Into<String>, service_name: impl Into<String>) -> Self {
+        ZipkinSink {
+            collector: collector.into(),
+            path: "/api/v2/spans".into(),
+            service_name: service_name.into(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Posts to `path` rather than the default, `/api/v2/spans`.
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Gives up on connecting, writing, or reading a response after `timeout` rather than the
+    /// default, 5 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the JSON body posted for `spans`.
+    pub fn encode(&self, spans: &[FinishedSpan]) -> String {
+        let mut body = String::from("[");
+        for (i, span) in spans.iter().enumerate() {
+            if i > 0 {
+                body.push(',');
+            }
+            self.encode_span(span, &mut body).unwrap();
+        }
+        body.push(']');
+        body
+    }
+
+    fn encode_span(&self, span: &FinishedSpan, out: &mut String) -> fmt::Result {
+        let context = &span.trace_context;
+        write!(
+            out,
+            r#"{{"traceId":"{:032x}","id":"{:016x}""#,
+            context.trace_id, context.span_id
+        )?;
+        if let Some(parent_id) = context.parent_id {
+            write!(out, r#","parentId":"{:016x}""#, parent_id)?;
+        }
+        match span.kind {
+            SpanKind::Client => out.push_str(r#","kind":"CLIENT""#),
+            SpanKind::Server => out.push_str(r#","kind":"SERVER","shared":true"#),
+            // Zipkin accepts spans of no kind.
+            _ => {}
+        }
+        if let Some(name) = span.name {
+            out.push_str(r#","name":"#);
+            write_json_string(out, name)?;
+        }
+        let start = span
+            .start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        // Zipkin treats a duration of 0 as unset.
+        let duration = span.duration.as_micros().max(1);
+        write!(
+            out,
+            r#","timestamp":{},"duration":{},"localEndpoint":{{"serviceName":"#,
+            start, duration
+        )?;
+        write_json_string(out, &self.service_name)?;
+        out.push('}');
+        if let Some(ref error) = span.error {
+            out.push_str(r#","tags":{"error":"#);
+            write_json_string(out, error)?;
+            out.push('}');
+        }
+        out.push('}');
+        Ok(())
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.collector,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+
+        let mut status_line = String::new();
+        io::BufReader::new(stream).read_line(&mut status_line)?;
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Collector responded {:?}", status_line.trim_end()),
+            )),
+        }
+    }
+
+    /// Connects to the first of the collector's addresses that accepts within the timeout.
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in self.collector.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Collector {:?} resolved to no addresses", self.collector),
+            )
+        }))
+    }
+}
+
+impl ExportSink for ZipkinSink {
+    fn export(&mut self, spans: &[FinishedSpan]) -> io::Result<()> {
+        if spans.is_empty() {
+            return Ok(());
+        }
+        self.post(&self.encode(spans))
+    }
+}
+
+fn write_json_string(out: &mut String, s: &str) -> fmt::Result {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::{io::Read, net::TcpListener, thread};
+
+    fn span(kind: SpanKind, error: Option<&str>) -> FinishedSpan {
+        let root = trace::Context::new_root();
+        FinishedSpan {
+            trace_context: root.new_child(&trace::AlwaysSample),
+            kind,
+            name: Some("add"),
+            start: UNIX_EPOCH + Duration::from_millis(1500),
+            duration: Duration::from_micros(250),
+            error: error.map(String::from),
+            _non_exhaustive: (),
+        }
+    }
+
+    #[test]
+    fn encodes_zipkin_json() {
+        let sink = ZipkinSink::new("localhost:9411", "calc");
+        let server = span(SpanKind::Server, Some("deadline \"exceeded\""));
+        let context = server.trace_context;
+        assert_eq!(
+            sink.encode(&[server]),
+            format!(
+                concat!(
+                    r#"[{{"traceId":"{:032x}","id":"{:016x}","parentId":"{:016x}","#,
+                    r#""kind":"SERVER","shared":true,"name":"add","timestamp":1500000,"#,
+                    r#""duration":250,"localEndpoint":{{"serviceName":"calc"}},"#,
+                    r#""tags":{{"error":"deadline \"exceeded\""}}}}]"#,
+                ),
+                context.trace_id,
+                context.span_id,
+                context.parent_id.unwrap()
+            )
+        );
+        assert_eq!(sink.encode(&[]), "[]");
+    }
+
+    /// Accepts one post on a local port, returning the collector's address and the request.
+    fn collector() -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let collector = listener.local_addr().unwrap().to_string();
+        let received = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut buf = [0; 4096];
+            while !request.ends_with("]") {
+                let n = stream.read(&mut buf).unwrap();
+                request.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            request
+        });
+        (collector, received)
+    }
+
+    #[test]
+    fn posts_batches_to_collector() {
+        let (collector, received) = collector();
+        let (exporter, mut writer) =
+            SpanExporter::queue(ZipkinSink::new(collector, "calc"), 1, Arc::new(LogSink));
+        exporter.export(span(SpanKind::Client, None));
+        exporter.export(span(SpanKind::Client, None));
+        assert_eq!(exporter.dropped(), 1);
+        drop(exporter);
+        block_on(&mut writer);
+
+        let request = received.join().unwrap();
+        assert!(request.starts_with("POST /api/v2/spans HTTP/1.1\r\n"));
+        assert!(request.contains(r#""kind":"CLIENT""#));
+        assert!(!request.contains("tags"));
+    }
+
+    #[test]
+    fn exports_on_background_thread() {
+        let (collector, received) = collector();
+        let exporter = SpanExporter::new(ZipkinSink::new(collector, "calc"), 1).unwrap();
+        exporter.export(span(SpanKind::Server, None));
+        drop(exporter);
+
+        let request = received.join().unwrap();
+        assert!(request.contains(r#""kind":"SERVER""#));
+    }
+
+    #[test]
+    fn connect_fails_without_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let collector = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let mut sink = ZipkinSink::new(collector, "calc").with_timeout(Duration::from_secs(1));
+        assert!(sink.export(&[span(SpanKind::Client, None)]).is_err());
+    }
+}
@@ -23,20 +23,50 @@
 //!        * When an incoming connection is accepted, if already at maximum, the connection is
 //!          dropped.
 //! * Transport agnostic.
+//! * Wire compatible with no_std peers: the messages convert to and from those of `tarpc-proto`,
+//!   which serialize the same way.
 
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "serde1")]
+pub mod codec;
 pub mod context;
+pub mod error;
+pub mod event;
+pub mod export;
+pub mod runtime;
+pub mod schema;
+#[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "tokio1")]
+pub mod testing;
 pub mod transport;
 pub(crate) mod util;
+#[cfg(feature = "serde1")]
+pub mod wire;
 
-pub use crate::{client::Client, server::Server, transport::sealed::Transport};
+#[cfg(feature = "client")]
+pub use crate::client::Client;
+#[cfg(feature = "server")]
+pub use crate::server::Server;
+pub use crate::{transport::sealed::Transport, util::hash::MapHasher};
+
+// Used by the code generated by the `service` macro.
+#[doc(hidden)]
+pub use futures;
 
 use futures::task::Poll;
-use std::{io, time::SystemTime};
+use std::{io, sync::Arc, time::SystemTime};
+
+/// An uninhabited type. Used by the `service` macro to fill the positions of request and response
+/// enum variants that aren't assigned to an RPC.
+#[doc(hidden)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum Reserved {}
 
 /// A message from a client to a server.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 pub enum ClientMessage<T> {
     /// A request initiated by a user. The server responds to a request by invoking a
@@ -63,8 +93,11 @@ pub enum ClientMessage<T> {
 }
 
 /// A request from a client to a server.
-#[derive(Clone, Copy, Debug)]
-#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+///
+/// Requests are serialized as an enum with a variant per version of their layout, so that a
+/// layout can change without positional formats like bincode misreading the old one. Every
+/// version so far is listed in the [wire specification](wire).
+#[derive(Clone, Debug)]
 pub struct Request<T> {
     /// Trace context, deadline, and other cross-cutting concerns.
     pub context: context::Context,
@@ -72,53 +105,345 @@ pub struct Request<T> {
     pub id: u64,
     /// The request body.
     pub message: T,
+    /// Whether the request is one-way. The server handles one-way requests like any other
+    /// request, but does not send a response when the handler completes.
+    pub one_way: bool,
     #[doc(hidden)]
     _non_exhaustive: (),
+    /// The bincode encoding of the message, if it was [prepared](client::Prepared).
+    #[cfg_attr(not(feature = "serde1"), allow(dead_code))]
+    encoded: Option<Arc<[u8]>>,
+}
+
+#[cfg(feature = "serde1")]
+impl<T: serde::Serialize> serde::Serialize for Request<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        /// The message, written from its prepared encoding if it has one and the format is bincode.
+        struct Message<'a, T> {
+            message: &'a T,
+            encoded: Option<&'a [u8]>,
+        }
+
+        impl<T: serde::Serialize> serde::Serialize for Message<'_, T> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                // The formats tarpc's transports use that aren't human-readable are all bincode.
+                match self.encoded {
+                    Some(encoded) if !serializer.is_human_readable() => {
+                        Verbatim(encoded).serialize(serializer)
+                    }
+                    _ => self.message.serialize(serializer),
+                }
+            }
+        }
+
+        /// Bincode encoding, written verbatim. Bincode writes tuples without a length, so it's
+        /// written as a tuple of words, and of bytes for the remainder, which bincode writes
+        /// little-endian, as they were read.
+        struct Verbatim<'a>(&'a [u8]);
+
+        impl serde::Serialize for Verbatim<'_> {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                use serde::ser::SerializeTuple;
+                use std::convert::TryInto;
+
+                let words = self.0.chunks_exact(8);
+                let rest = words.remainder();
+                let mut tuple = serializer.serialize_tuple(words.len() + rest.len())?;
+                for word in words {
+                    tuple.serialize_element(&u64::from_le_bytes(word.try_into().unwrap()))?;
+                }
+                for byte in rest {
+                    tuple.serialize_element(byte)?;
+                }
+                tuple.end()
+            }
+        }
+
+        #[derive(serde::Serialize)]
+        #[serde(rename = "Request")]
+        enum Versions<'a, T> {
+            V1 {
+                context: &'a context::Context,
+                id: u64,
+                message: Message<'a, T>,
+                one_way: bool,
+            },
+        }
+
+        serde::Serialize::serialize(
+            &Versions::V1 {
+                context: &self.context,
+                id: self.id,
+                message: Message {
+                    message: &self.message,
+                    encoded: self.encoded.as_ref().map(|encoded| &**encoded),
+                },
+                one_way: self.one_way,
+            },
+            serializer,
+        )
+    }
+}
+
+#[cfg(feature = "serde1")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Request<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "Request")]
+        enum Versions<T> {
+            V1 {
+                context: context::Context,
+                id: u64,
+                message: T,
+                one_way: bool,
+            },
+        }
+
+        let Versions::V1 {
+            context,
+            id,
+            message,
+            one_way,
+        } = Versions::deserialize(deserializer)?;
+        let mut request = Request::new(context, id, message);
+        request.one_way = one_way;
+        Ok(request)
+    }
 }
 
 /// A response from a server to a client.
+///
+/// Like [requests](Request), responses are serialized as an enum with a variant per version of
+/// their layout.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
 pub struct Response<T> {
     /// The ID of the request being responded to.
     pub request_id: u64,
     /// The response body, or an error if the request failed.
     pub message: Result<T, ServerError>,
+    /// Whether more responses to the same request will follow. Streaming requests receive any
+    /// number of partial responses followed by exactly one final response.
+    pub partial: bool,
     #[doc(hidden)]
     _non_exhaustive: (),
 }
 
+#[cfg(feature = "serde1")]
+impl<T: serde::Serialize> serde::Serialize for Response<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(serde::Serialize)]
+        #[serde(rename = "Response")]
+        enum Versions<'a, T> {
+            V1 {
+                request_id: u64,
+                message: &'a Result<T, ServerError>,
+                partial: bool,
+            },
+        }
+
+        serde::Serialize::serialize(
+            &Versions::V1 {
+                request_id: self.request_id,
+                message: &self.message,
+                partial: self.partial,
+            },
+            serializer,
+        )
+    }
+}
+
+#[cfg(feature = "serde1")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for Response<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename = "Response")]
+        enum Versions<T> {
+            V1 {
+                request_id: u64,
+                message: Result<T, ServerError>,
+                partial: bool,
+            },
+        }
+
+        let Versions::V1 {
+            request_id,
+            message,
+            partial,
+        } = Versions::deserialize(deserializer)?;
+        Ok(Response {
+            request_id,
+            message,
+            partial,
+            _non_exhaustive: (),
+        })
+    }
+}
+
 /// An error response from a server to a client.
+///
+/// Serialized as a [`proto::ServerError`], whose kind is
+/// [`Unauthenticated`](proto::ErrorKind::Unauthenticated) for unauthenticated errors.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde1",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "proto::ServerError", into = "proto::ServerError")
+)]
 pub struct ServerError {
-    #[cfg_attr(
-        feature = "serde1",
-        serde(serialize_with = "util::serde::serialize_io_error_kind_as_u32")
-    )]
-    #[cfg_attr(
-        feature = "serde1",
-        serde(deserialize_with = "util::serde::deserialize_io_error_kind_from_u32")
-    )]
     /// The type of error that occurred to fail the request.
     pub kind: io::ErrorKind,
     /// A message describing more detail about the error that occurred.
     pub detail: Option<String>,
+    /// Whether the request was rejected for lacking valid credentials, e.g. an expired
+    /// [bearer token](context::Context::bearer_token). Such errors are `PermissionDenied`, and
+    /// are [classified](error::ErrorKind::Unauthenticated) apart from other permission errors,
+    /// such as authorization failures.
+    pub unauthenticated: bool,
     #[doc(hidden)]
     _non_exhaustive: (),
 }
 
+impl ServerError {
+    /// Returns an error of the given kind, for transports that fail requests before the server
+    /// sees them.
+    pub fn new(kind: io::ErrorKind, detail: Option<String>) -> Self {
+        ServerError {
+            kind,
+            detail,
+            unauthenticated: false,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+impl<T> Response<T> {
+    /// Returns a final response, for transports that answer requests the server never sees.
+    pub fn new(request_id: u64, message: Result<T, ServerError>) -> Self {
+        Response {
+            request_id,
+            message,
+            partial: false,
+            _non_exhaustive: (),
+        }
+    }
+}
+
 impl From<ServerError> for io::Error {
     fn from(e: ServerError) -> io::Error {
-        io::Error::new(e.kind, e.detail.unwrap_or_default())
+        if e.unauthenticated {
+            error::unauthenticated(e.detail.unwrap_or_default())
+        } else {
+            io::Error::new(e.kind, e.detail.unwrap_or_default())
+        }
     }
 }
 
 impl<T> Request<T> {
+    /// Returns a two-way request, for transports that translate requests from other protocols.
+    pub fn new(context: context::Context, id: u64, message: T) -> Self {
+        Request {
+            context,
+            id,
+            message,
+            one_way: false,
+            _non_exhaustive: (),
+            encoded: None,
+        }
+    }
+
     /// Returns the deadline for this request.
     pub fn deadline(&self) -> &SystemTime {
         &self.context.deadline
     }
 }
 
+impl<T> From<ClientMessage<T>> for proto::ClientMessage<T> {
+    fn from(message: ClientMessage<T>) -> Self {
+        match message {
+            ClientMessage::Request(request) => proto::ClientMessage::Request(request.into()),
+            ClientMessage::Cancel {
+                trace_context,
+                request_id,
+            } => proto::ClientMessage::Cancel {
+                trace_context: context::trace_context_to_proto(trace_context),
+                request_id,
+            },
+            ClientMessage::_NonExhaustive => proto::ClientMessage::_NonExhaustive,
+        }
+    }
+}
+
+impl<T> From<proto::ClientMessage<T>> for ClientMessage<T> {
+    fn from(message: proto::ClientMessage<T>) -> Self {
+        match message {
+            proto::ClientMessage::Request(request) => ClientMessage::Request(request.into()),
+            proto::ClientMessage::Cancel {
+                trace_context,
+                request_id,
+            } => ClientMessage::Cancel {
+                trace_context: context::trace_context_from_proto(trace_context),
+                request_id,
+            },
+            proto::ClientMessage::_NonExhaustive => ClientMessage::_NonExhaustive,
+        }
+    }
+}
+
+/// Drops the request's prepared encoding, if any.
+impl<T> From<Request<T>> for proto::Request<T> {
+    fn from(request: Request<T>) -> Self {
+        let mut converted =
+            proto::Request::new(request.context.into(), request.id, request.message);
+        converted.one_way = request.one_way;
+        converted
+    }
+}
+
+impl<T> From<proto::Request<T>> for Request<T> {
+    fn from(request: proto::Request<T>) -> Self {
+        let mut converted = Request::new(request.context.into(), request.id, request.message);
+        converted.one_way = request.one_way;
+        converted
+    }
+}
+
+impl<T> From<Response<T>> for proto::Response<T> {
+    fn from(response: Response<T>) -> Self {
+        let mut converted =
+            proto::Response::new(response.request_id, response.message.map_err(Into::into));
+        converted.partial = response.partial;
+        converted
+    }
+}
+
+impl<T> From<proto::Response<T>> for Response<T> {
+    fn from(response: proto::Response<T>) -> Self {
+        Response {
+            request_id: response.request_id,
+            message: response.message.map_err(Into::into),
+            partial: response.partial,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+impl From<ServerError> for proto::ServerError {
+    fn from(e: ServerError) -> Self {
+        let kind = if e.unauthenticated {
+            proto::ErrorKind::Unauthenticated
+        } else {
+            util::error_kind_to_proto(e.kind)
+        };
+        proto::ServerError::new(kind, e.detail)
+    }
+}
+
+impl From<proto::ServerError> for ServerError {
+    fn from(e: proto::ServerError) -> Self {
+        let mut error = ServerError::new(util::error_kind_from_proto(e.kind), e.detail);
+        error.unauthenticated = e.kind == proto::ErrorKind::Unauthenticated;
+        error
+    }
+}
+
 pub(crate) type PollIo<T> = Poll<Option<io::Result<T>>>;
@@ -0,0 +1,418 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Abstracts the executor that runs background tasks and the timer that measures deadlines.
+//!
+//! Servers spawn their tasks, such as channels and request handlers, with the [`Spawn`] of their
+//! [config](crate::server::Config::spawn), and clients and servers time requests, corked flushes,
+//! and inline handlers with the [`Timer`] of theirs. [`Tokio`] implements both with the tokio
+//! executor and timer, and [`AsyncStd`], with the `async-std1` feature, implements them with
+//! async-std's. Configs default to the [`DefaultRuntime`], which is chosen by feature: tokio if
+//! `tokio1` is enabled, else async-std if `async-std1` is, else the [`NoExecutor`] of minimal
+//! client builds, else the [`NoRuntime`] of minimal server builds. Other executors, such as smol
+//! or glommio, plug in by implementing the traits, as do tests that control time themselves.
+//!
+//! Servers whose handlers aren't `Send` run them on the current thread with a [`SpawnLocal`],
+//! such as the spawner of a [`LocalPool`](futures::executor::LocalPool).
+
+use futures::{
+    executor::LocalSpawner,
+    prelude::*,
+    ready,
+    task::{Context, LocalSpawnExt},
+    Poll,
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    fmt,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Runs futures in the background.
+pub trait Spawn: fmt::Debug + Send + Sync {
+    /// Spawns `future` to run to completion in the background.
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>);
+
+    /// Spawns `future` as a task named `name`, for executors whose diagnostics show task names.
+    /// Executors without named tasks spawn it unnamed, which is the default.
+    fn spawn_named(
+        &self,
+        name: &dyn fmt::Display,
+        future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    ) {
+        let _ = name;
+        self.spawn(future)
+    }
+}
+
+/// Runs futures in the background on the current thread, so they needn't be `Send`.
+pub trait SpawnLocal {
+    /// Spawns `future` to run to completion in the background, on the current thread.
+    fn spawn_local(&self, future: Pin<Box<dyn Future<Output = ()>>>);
+}
+
+/// Spawns onto the [`LocalPool`](futures::executor::LocalPool) of the spawner.
+impl SpawnLocal for LocalSpawner {
+    fn spawn_local(&self, future: Pin<Box<dyn Future<Output = ()>>>) {
+        // Futures spawned after the pool is dropped would never run anyway.
+        let mut spawner = self.clone();
+        let _ = <LocalSpawner as LocalSpawnExt>::spawn_local(&mut spawner, future);
+    }
+}
+
+/// Measures time and waits for it to pass.
+pub trait Timer: fmt::Debug + Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes at `deadline`.
+    fn delay(&self, deadline: Instant) -> Delay;
+
+    /// Returns a future that completes once `duration` has passed.
+    fn delay_for(&self, duration: Duration) -> Delay {
+        self.delay(self.now() + duration)
+    }
+
+    /// Returns the current system time, which request deadlines are measured against.
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Returns the time left until `deadline`, or zero if it's passed.
+    fn time_until(&self, deadline: SystemTime) -> Duration {
+        deadline
+            .duration_since(self.system_now())
+            .unwrap_or_default()
+    }
+}
+
+/// A future that completes at a deadline, returned by [`Timer::delay`].
+///
+/// The delays of the timers tarpc provides are held as they are, since every request is timed
+/// with one; only those of other timers are boxed.
+#[must_use = "futures do nothing unless polled"]
+pub struct Delay {
+    inner: Inner,
+}
+
+enum Inner {
+    #[cfg(feature = "tokio1")]
+    Tokio(tokio_timer::Delay),
+    #[cfg(feature = "futures-timer")]
+    Thread(futures_timer::Delay),
+    /// Never completes.
+    Pending,
+    Boxed(Pin<Box<dyn Future<Output = ()> + Send>>),
+}
+
+impl Delay {
+    /// Wraps a future that completes at a deadline, for implementations of [`Timer`].
+    pub fn new(delay: impl Future<Output = ()> + Send + 'static) -> Self {
+        Delay {
+            inner: Inner::Boxed(Box::pin(delay)),
+        }
+    }
+
+    /// Returns a delay until `deadline` on tokio's timer.
+    #[cfg(feature = "tokio1")]
+    pub(crate) fn tokio(deadline: Instant) -> Self {
+        Delay {
+            inner: Inner::Tokio(tokio_timer::delay(deadline)),
+        }
+    }
+
+    /// Returns a delay until `deadline` on futures-timer's background thread.
+    #[cfg(feature = "futures-timer")]
+    fn thread(deadline: Instant) -> Self {
+        Delay {
+            inner: Inner::Thread(futures_timer::Delay::new_at(deadline)),
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        match self.get_mut().inner {
+            #[cfg(feature = "tokio1")]
+            Inner::Tokio(ref mut delay) => delay.poll_unpin(cx),
+            // The timer fails only if its thread is gone, in which case no delay would complete.
+            #[cfg(feature = "futures-timer")]
+            Inner::Thread(ref mut delay) => delay.poll_unpin(cx).map(|_| ()),
+            Inner::Pending => Poll::Pending,
+            Inner::Boxed(ref mut delay) => delay.as_mut().poll(cx),
+        }
+    }
+}
+
+impl fmt::Debug for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Delay").finish()
+    }
+}
+
+/// A future that fails with [`Elapsed`] if it doesn't complete before its [`Delay`] does.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Timeout<F> {
+    future: F,
+    delay: Delay,
+}
+
+impl<F> Timeout<F> {
+    unsafe_pinned!(future: F);
+    unsafe_unpinned!(delay: Delay);
+
+    /// Returns a future that fails if `future` doesn't complete before `delay`.
+    pub fn new(future: F, delay: Delay) -> Self {
+        Timeout { future, delay }
+    }
+
+    /// Returns the future being timed.
+    pub fn get_mut(&mut self) -> &mut F {
+        &mut self.future
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.as_mut().future().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        ready!(self.as_mut().delay().poll_unpin(cx));
+        Poll::Ready(Err(Elapsed { _private: () }))
+    }
+}
+
+/// The error of a [`Timeout`] whose delay completed first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed {
+    _private: (),
+}
+
+/// The tokio executor and timer.
+///
+/// Spawns onto the default executor and times with the default timer of the thread that spawns
+/// or times, which are set while running on a tokio runtime.
+#[cfg(feature = "tokio1")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Tokio;
+
+#[cfg(feature = "tokio1")]
+impl Spawn for Tokio {
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        tokio_executor::spawn(future);
+    }
+}
+
+#[cfg(feature = "tokio1")]
+impl Timer for Tokio {
+    fn now(&self) -> Instant {
+        tokio_timer::clock::now()
+    }
+
+    fn delay(&self, deadline: Instant) -> Delay {
+        Delay::tokio(deadline)
+    }
+}
+
+/// The async-std executor and timer.
+#[cfg(feature = "async-std1")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std1")]
+impl Spawn for AsyncStd {
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        async_std::task::spawn(future);
+    }
+
+    fn spawn_named(
+        &self,
+        name: &dyn fmt::Display,
+        future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    ) {
+        async_std::task::Builder::new()
+            .name(name.to_string())
+            .spawn(future)
+            .expect("cannot spawn task");
+    }
+}
+
+#[cfg(feature = "async-std1")]
+impl Timer for AsyncStd {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, deadline: Instant) -> Delay {
+        // async-std sleeps with futures-timer, whose delays, unlike async-std's, can be named.
+        Delay::thread(deadline)
+    }
+}
+
+/// A timer that times delays on a background thread, for executors without a timer of their
+/// own, such as the [blocking client](crate::client::blocking)'s. It's enabled by the `client`
+/// feature.
+#[cfg(feature = "futures-timer")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadTimer;
+
+#[cfg(feature = "futures-timer")]
+impl Timer for ThreadTimer {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, deadline: Instant) -> Delay {
+        Delay::thread(deadline)
+    }
+}
+
+/// The runtime of client builds without a runtime feature, e.g. command-line tools that poll
+/// their client's dispatch themselves, with `futures::executor::block_on` or the like.
+///
+/// It has no executor: spawning panics, so servers need a [`Spawn`] configured. It times with a
+/// [`ThreadTimer`], so clients still time out requests whose server stopped responding.
+#[cfg(feature = "futures-timer")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoExecutor;
+
+#[cfg(feature = "futures-timer")]
+impl Spawn for NoExecutor {
+    fn spawn(&self, _: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        panic!("No runtime to spawn on: enable `tokio1` or `async-std1`, or configure a `Spawn`.")
+    }
+}
+
+#[cfg(feature = "futures-timer")]
+impl Timer for NoExecutor {
+    fn now(&self) -> Instant {
+        ThreadTimer.now()
+    }
+
+    fn delay(&self, deadline: Instant) -> Delay {
+        ThreadTimer.delay(deadline)
+    }
+}
+
+/// The runtime of server builds without a runtime feature, which configure a [`Spawn`] and
+/// [`Timer`] of their own, and a timer for tests that control time themselves.
+///
+/// It has no executor: spawning panics. It has no timer either: its delays never complete.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoRuntime;
+
+impl Spawn for NoRuntime {
+    fn spawn(&self, _: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        panic!("No runtime to spawn on: enable `tokio1` or `async-std1`, or configure a `Spawn`.")
+    }
+}
+
+impl Timer for NoRuntime {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, _: Instant) -> Delay {
+        Delay {
+            inner: Inner::Pending,
+        }
+    }
+}
+
+/// The runtime chosen by feature.
+#[cfg(feature = "tokio1")]
+pub type DefaultRuntime = Tokio;
+/// The runtime chosen by feature.
+#[cfg(all(feature = "async-std1", not(feature = "tokio1")))]
+pub type DefaultRuntime = AsyncStd;
+/// The runtime chosen by feature.
+#[cfg(all(
+    feature = "futures-timer",
+    not(any(feature = "tokio1", feature = "async-std1"))
+))]
+pub type DefaultRuntime = NoExecutor;
+/// The runtime chosen by feature.
+#[cfg(not(any(feature = "tokio1", feature = "async-std1", feature = "futures-timer")))]
+pub type DefaultRuntime = NoRuntime;
+
+/// Returns a handle to the [default runtime](DefaultRuntime), for configs to spawn and time with.
+pub(crate) fn default() -> Arc<DefaultRuntime> {
+    Arc::new(DefaultRuntime::default())
+}
+
+/// Spawns `future` with the [default runtime](DefaultRuntime).
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+    DefaultRuntime::default().spawn(Box::pin(future))
+}
+
+/// Returns a delay of `duration` on the [default runtime](DefaultRuntime).
+pub(crate) fn delay_for(duration: Duration) -> Delay {
+    DefaultRuntime::default().delay_for(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use futures_test::task::noop_waker_ref;
+
+    #[test]
+    fn timeout_prefers_the_future() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut timeout = Timeout::new(future::ready(7), Delay::new(future::ready(())));
+        assert_eq!(timeout.poll_unpin(&mut cx), Poll::Ready(Ok(7)));
+    }
+
+    #[test]
+    fn timeout_elapses_with_the_delay() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut timeout =
+            Timeout::new(future::pending::<()>(), Delay::new(future::pending::<()>()));
+        assert_eq!(timeout.poll_unpin(&mut cx), Poll::Pending);
+        let mut timeout = Timeout::new(future::pending::<()>(), Delay::new(future::ready(())));
+        assert_eq!(
+            timeout.poll_unpin(&mut cx),
+            Poll::Ready(Err(Elapsed { _private: () }))
+        );
+    }
+
+    #[cfg(feature = "futures-timer")]
+    #[test]
+    fn no_executor_delays_complete() {
+        let delay = NoExecutor.delay_for(Duration::from_millis(1));
+        futures::executor::block_on(delay);
+    }
+
+    #[cfg(feature = "async-std1")]
+    #[test]
+    fn async_std_spawns_and_delays() {
+        let (tx, rx) = futures::channel::oneshot::channel();
+        let start = AsyncStd.now();
+        AsyncStd.spawn_named(
+            &"delayed",
+            Box::pin(async move {
+                AsyncStd.delay_for(Duration::from_millis(1)).await;
+                let _ = tx.send(AsyncStd.now());
+            }),
+        );
+        let delayed = async_std::task::block_on(rx).unwrap();
+        assert!(delayed >= start + Duration::from_millis(1));
+    }
+
+    #[test]
+    fn no_runtime_delays_never_complete() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut delay = NoRuntime.delay(Instant::now());
+        assert_eq!(delay.poll_unpin(&mut cx), Poll::Pending);
+    }
+}
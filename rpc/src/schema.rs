@@ -0,0 +1,862 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a machine-readable description of a service, for use by external code generators,
+//! documentation tools, and compatibility checks.
+//!
+//! The `service` macro emits a schema for a service when invoked with `schema = true`. With the
+//! `serde1` feature enabled, schemas can be serialized to JSON or any other serde format. The
+//! [`Display`](fmt::Display) impl of [`Service`] prints the schema as a service definition that
+//! the `service` macro accepts.
+//!
+//! The request enums generated by the `service` macro implement [`Introspect`], which lets code
+//! that's generic over requests, like middleware and metrics, label requests by RPC.
+//!
+//! [`breaking_changes`] compares two versions of a service's schema, e.g. the schema of the
+//! last release and of the release being prepared, and reports the changes that would break
+//! peers built against the older version.
+//!
+//! [`Type`] parses the types named in a schema, for generators that map them to other languages.
+
+use std::{borrow::Cow, fmt};
+
+/// Describes a service.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Service {
+    /// The name of the service trait.
+    pub name: Cow<'static, str>,
+    /// The RPCs of the service, in the order they're defined.
+    pub methods: Cow<'static, [Method]>,
+}
+
+/// Describes an RPC.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Method {
+    /// The name of the RPC.
+    pub name: Cow<'static, str>,
+    /// The name of the RPC's variants in the request and response enums when serialized.
+    pub wire_name: Cow<'static, str>,
+    /// The position of the RPC's variants in the request and response enums.
+    pub id: u32,
+    /// How the server responds to the RPC.
+    pub kind: MethodKind,
+    /// The arguments of the RPC.
+    pub args: Cow<'static, [Arg]>,
+    /// The return type of the RPC, or for streaming RPCs, the type of each item.
+    pub output: Cow<'static, str>,
+    /// The type of error returned by the RPC, if declared with `#[throws]`.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub error: Option<Cow<'static, str>>,
+    /// The version of the service that added the RPC, if declared with `#[since]`.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub since: Option<u32>,
+    /// The version of the service that removed the RPC, if declared with `#[removed]`.
+    #[cfg_attr(feature = "serde1", serde(default))]
+    pub removed: Option<u32>,
+}
+
+/// How the server responds to an RPC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub enum MethodKind {
+    /// The server sends a single response.
+    Unary,
+    /// The server doesn't respond.
+    OneWay,
+    /// The server sends a stream of responses.
+    Streaming,
+}
+
+/// Describes an argument of an RPC.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+pub struct Arg {
+    /// The name of the argument.
+    pub name: Cow<'static, str>,
+    /// The type of the argument, as written in the service definition.
+    pub ty: Cow<'static, str>,
+}
+
+/// Describes the RPCs of a service's requests. Implemented by the request enums generated by the
+/// `service` macro.
+pub trait Introspect {
+    /// The RPCs of the service, in the order they're defined.
+    const METHODS: &'static [Method];
+
+    /// Returns the name of the RPC invoked by this request.
+    fn method_name(&self) -> &'static str;
+
+    /// Returns the RPC invoked by this request, or `None` for requests that don't invoke an RPC,
+    /// like version negotiation.
+    fn method(&self) -> Option<&'static Method> {
+        let name = self.method_name();
+        Self::METHODS.iter().find(|method| method.name == name)
+    }
+}
+
+/// Names the RPC of a service's responses. Implemented by the response enums generated by the
+/// `service` macro.
+pub trait IntrospectResponse {
+    /// Returns the name of the RPC this is a response to.
+    fn method_name(&self) -> &'static str;
+}
+
+impl Service {
+    /// Returns the RPC named `name`, if any.
+    pub fn method(&self, name: &str) -> Option<&Method> {
+        self.methods.iter().find(|method| method.name == name)
+    }
+}
+
+/// A change between two versions of a service that breaks peers built against the older
+/// version. Returned by [`breaking_changes`].
+///
+/// Types are compared as written in the service definitions, so any change to a type's name is
+/// reported, even one that serializes the same way, like `String` to `Box<str>`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BreakingChange {
+    /// The RPC was removed, or marked `#[removed]`.
+    MethodRemoved {
+        /// The name of the RPC.
+        method: String,
+    },
+    /// The RPC's variants moved to a different position in the request and response enums,
+    /// which breaks positional formats like bincode.
+    IdChanged {
+        /// The name of the RPC.
+        method: String,
+        /// The RPC's id in the older version.
+        old: u32,
+        /// The RPC's id in the newer version.
+        new: u32,
+    },
+    /// The RPC's variants are serialized under a different name, which breaks formats that name
+    /// RPCs, like JSON.
+    WireNameChanged {
+        /// The name of the RPC.
+        method: String,
+        /// The RPC's wire name in the older version.
+        old: String,
+        /// The RPC's wire name in the newer version.
+        new: String,
+    },
+    /// The server responds to the RPC differently.
+    KindChanged {
+        /// The name of the RPC.
+        method: String,
+        /// The RPC's kind in the older version.
+        old: MethodKind,
+        /// The RPC's kind in the newer version.
+        new: MethodKind,
+    },
+    /// The RPC takes an argument it didn't.
+    ArgAdded {
+        /// The name of the RPC.
+        method: String,
+        /// The new argument.
+        arg: Arg,
+    },
+    /// The RPC no longer takes an argument.
+    ArgRemoved {
+        /// The name of the RPC.
+        method: String,
+        /// The removed argument.
+        arg: Arg,
+    },
+    /// An argument of the RPC changed type.
+    ArgTypeChanged {
+        /// The name of the RPC.
+        method: String,
+        /// The name of the argument.
+        arg: String,
+        /// The argument's type in the older version.
+        old: String,
+        /// The argument's type in the newer version.
+        new: String,
+    },
+    /// An argument of the RPC moved to a different position, which breaks positional formats
+    /// like bincode.
+    ArgMoved {
+        /// The name of the RPC.
+        method: String,
+        /// The name of the argument.
+        arg: String,
+        /// The argument's position in the older version.
+        old: usize,
+        /// The argument's position in the newer version.
+        new: usize,
+    },
+    /// The RPC's return type, or for streaming RPCs, the type of each item, changed.
+    OutputChanged {
+        /// The name of the RPC.
+        method: String,
+        /// The RPC's output in the older version.
+        old: String,
+        /// The RPC's output in the newer version.
+        new: String,
+    },
+    /// The type of error the RPC returns changed, or the RPC started or stopped declaring one
+    /// with `#[throws]`.
+    ErrorChanged {
+        /// The name of the RPC.
+        method: String,
+        /// The RPC's error type in the older version, if any.
+        old: Option<String>,
+        /// The RPC's error type in the newer version, if any.
+        new: Option<String>,
+    },
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+/// Returns the changes from the `old` version of a service to the `new` version that break
+/// peers built against the old version, in the order of the old version's RPCs.
+///
+/// RPCs are matched by name, so a renamed RPC is reported as removed. Adding RPCs doesn't break
+/// older peers, and isn't reported.
+pub fn breaking_changes(old: &Service, new: &Service) -> Vec<BreakingChange> {
+    let mut changes = vec![];
+    for old in old.methods.iter().filter(|method| method.removed.is_none()) {
+        let method = || old.name.to_string();
+        let new = match new.method(&old.name) {
+            Some(new) if new.removed.is_none() => new,
+            _ => {
+                changes.push(BreakingChange::MethodRemoved { method: method() });
+                continue;
+            }
+        };
+        if old.id != new.id {
+            changes.push(BreakingChange::IdChanged {
+                method: method(),
+                old: old.id,
+                new: new.id,
+            });
+        }
+        if old.wire_name != new.wire_name {
+            changes.push(BreakingChange::WireNameChanged {
+                method: method(),
+                old: old.wire_name.to_string(),
+                new: new.wire_name.to_string(),
+            });
+        }
+        if old.kind != new.kind {
+            changes.push(BreakingChange::KindChanged {
+                method: method(),
+                old: old.kind,
+                new: new.kind,
+            });
+        }
+        for (old_position, old_arg) in old.args.iter().enumerate() {
+            let (new_position, new_arg) = match new
+                .args
+                .iter()
+                .enumerate()
+                .find(|(_, arg)| arg.name == old_arg.name)
+            {
+                Some(new_arg) => new_arg,
+                None => {
+                    changes.push(BreakingChange::ArgRemoved {
+                        method: method(),
+                        arg: old_arg.clone(),
+                    });
+                    continue;
+                }
+            };
+            if old_arg.ty != new_arg.ty {
+                changes.push(BreakingChange::ArgTypeChanged {
+                    method: method(),
+                    arg: old_arg.name.to_string(),
+                    old: old_arg.ty.to_string(),
+                    new: new_arg.ty.to_string(),
+                });
+            }
+            if old_position != new_position {
+                changes.push(BreakingChange::ArgMoved {
+                    method: method(),
+                    arg: old_arg.name.to_string(),
+                    old: old_position,
+                    new: new_position,
+                });
+            }
+        }
+        for new_arg in new.args.iter() {
+            if !old.args.iter().any(|arg| arg.name == new_arg.name) {
+                changes.push(BreakingChange::ArgAdded {
+                    method: method(),
+                    arg: new_arg.clone(),
+                });
+            }
+        }
+        if old.output != new.output {
+            changes.push(BreakingChange::OutputChanged {
+                method: method(),
+                old: old.output.to_string(),
+                new: new.output.to_string(),
+            });
+        }
+        if old.error != new.error {
+            changes.push(BreakingChange::ErrorChanged {
+                method: method(),
+                old: old.error.as_ref().map(|error| error.to_string()),
+                new: new.error.as_ref().map(|error| error.to_string()),
+            });
+        }
+    }
+    changes
+}
+
+impl fmt::Display for BreakingChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakingChange::MethodRemoved { method } => write!(f, "`{}` was removed", method),
+            BreakingChange::IdChanged { method, old, new } => write!(
+                f,
+                "`{}` moved from id {} to id {}, which breaks positional formats like bincode",
+                method, old, new
+            ),
+            BreakingChange::WireNameChanged { method, old, new } => write!(
+                f,
+                "`{}` is serialized as {:?} rather than {:?}, which breaks formats that name \
+                 RPCs, like JSON",
+                method, new, old
+            ),
+            BreakingChange::KindChanged { method, old, new } => write!(
+                f,
+                "`{}` changed from {} to {}",
+                method,
+                kind_name(*old),
+                kind_name(*new)
+            ),
+            BreakingChange::ArgAdded { method, arg } => write!(
+                f,
+                "`{}` takes a new argument, `{}: {}`",
+                method, arg.name, arg.ty
+            ),
+            BreakingChange::ArgRemoved { method, arg } => {
+                write!(f, "`{}` no longer takes `{}: {}`", method, arg.name, arg.ty)
+            }
+            BreakingChange::ArgTypeChanged {
+                method,
+                arg,
+                old,
+                new,
+            } => write!(
+                f,
+                "argument `{}` of `{}` changed type from `{}` to `{}`",
+                arg, method, old, new
+            ),
+            BreakingChange::ArgMoved {
+                method,
+                arg,
+                old,
+                new,
+            } => write!(
+                f,
+                "argument `{}` of `{}` moved from position {} to {}, which breaks positional \
+                 formats like bincode",
+                arg, method, old, new
+            ),
+            BreakingChange::OutputChanged { method, old, new } => write!(
+                f,
+                "the output of `{}` changed from `{}` to `{}`",
+                method, old, new
+            ),
+            BreakingChange::ErrorChanged { method, old, new } => match (old, new) {
+                (Some(old), Some(new)) => write!(
+                    f,
+                    "the error of `{}` changed from `{}` to `{}`",
+                    method, old, new
+                ),
+                (None, Some(new)) => write!(f, "`{}` throws `{}`", method, new),
+                (Some(old), None) => write!(f, "`{}` no longer throws `{}`", method, old),
+                (None, None) => unreachable!(),
+            },
+            _ => write!(f, "an incompatible change"),
+        }
+    }
+}
+
+fn kind_name(kind: MethodKind) -> &'static str {
+    match kind {
+        MethodKind::Unary => "unary",
+        MethodKind::OneWay => "one-way",
+        MethodKind::Streaming => "streaming",
+    }
+}
+
+/// The shape of a type named in a schema, as seen by serde. Parsed from the type as written in
+/// the service definition by [`Type::parse`], for code generators that map RPC types to other
+/// languages.
+///
+/// Only types from the standard library are understood; other types are [`Named`](Type::Named),
+/// since the schema doesn't describe their fields.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Type {
+    /// `()`, serialized like `None`.
+    Unit,
+    /// `bool`.
+    Bool,
+    /// An integer type, like `u32` or `isize`.
+    Integer {
+        /// Whether the type is signed.
+        signed: bool,
+        /// The size of the type in bits. `isize` and `usize` are 64 bits.
+        bits: u8,
+    },
+    /// `f32` or `f64`.
+    Float {
+        /// The size of the type in bits.
+        bits: u8,
+    },
+    /// `char`.
+    Char,
+    /// `String`, `str`, or another string type.
+    String,
+    /// `Option<T>`.
+    Option(Box<Type>),
+    /// A sequence of any length, like `Vec<T>`, `HashSet<T>`, or `[T]`.
+    List(Box<Type>),
+    /// An array of fixed length, `[T; N]`.
+    Array(Box<Type>, usize),
+    /// A tuple of one or more elements, e.g. `(T,)`.
+    Tuple(Vec<Type>),
+    /// A map, like `HashMap<K, V>` or `BTreeMap<K, V>`.
+    Map(Box<Type>, Box<Type>),
+    /// Any other type, by its path as written, e.g. `model::User`. Types written with different
+    /// paths are told apart, even if they end in the same name.
+    Named {
+        /// The path of the type, without generic arguments.
+        name: String,
+        /// The type's generic arguments, if any.
+        args: Vec<Type>,
+    },
+}
+
+impl Type {
+    /// Parses a type as written in a service definition, like [`Arg::ty`] or
+    /// [`Method::output`].
+    ///
+    /// References and smart pointers, like `&'static str` or `Box<T>`, serialize as the types
+    /// they point to, and so parse as them. Text that isn't a type parses as a
+    /// [`Named`](Type::Named) type of that name.
+    pub fn parse(ty: &str) -> Type {
+        let ty = ty.trim();
+        if ty.starts_with('&') {
+            let ty = ty[1..].trim_start();
+            let ty = if ty.starts_with('\'') {
+                ty.find(char::is_whitespace).map_or("", |end| &ty[end..])
+            } else {
+                ty
+            };
+            let ty = ty.trim_start();
+            let ty = if ty.starts_with("mut ") { &ty[4..] } else { ty };
+            return Type::parse(ty);
+        }
+        if ty.starts_with('(') && ty.ends_with(')') {
+            let inner = &ty[1..ty.len() - 1];
+            let elems = split_args(inner);
+            return match elems.len() {
+                0 => Type::Unit,
+                // `(T,)` is a tuple of one element, serialized as a sequence; `(T)` is `T`.
+                1 if !inner.trim_end().ends_with(',') => Type::parse(elems[0]),
+                _ => Type::Tuple(elems.into_iter().map(Type::parse).collect()),
+            };
+        }
+        if ty.starts_with('[') && ty.ends_with(']') {
+            let inner = &ty[1..ty.len() - 1];
+            let mut depth = 0;
+            for (i, c) in inner.char_indices() {
+                match c {
+                    '<' | '(' | '[' => depth += 1,
+                    '>' | ')' | ']' => depth -= 1,
+                    ';' if depth == 0 => {
+                        let elem = Box::new(Type::parse(&inner[..i]));
+                        return match inner[i + 1..].trim().parse() {
+                            Ok(len) => Type::Array(elem, len),
+                            // Lengths given by constants aren't known.
+                            Err(_) => Type::List(elem),
+                        };
+                    }
+                    _ => {}
+                }
+            }
+            return Type::List(Box::new(Type::parse(inner)));
+        }
+
+        let (path, args) = match ty.find('<') {
+            Some(start) if ty.ends_with('>') => {
+                (&ty[..start], split_args(&ty[start + 1..ty.len() - 1]))
+            }
+            _ => (ty, vec![]),
+        };
+        let path: String = path
+            .trim_start_matches("::")
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        let name = path.rsplit("::").next().unwrap();
+        // Lifetimes don't change how a type serializes.
+        let mut args: Vec<Type> = args
+            .into_iter()
+            .filter(|arg| !arg.starts_with('\''))
+            .map(Type::parse)
+            .collect();
+        let integer = |signed, bits| Type::Integer { signed, bits };
+        match (name, args.len()) {
+            ("bool", 0) => Type::Bool,
+            ("i8", 0) => integer(true, 8),
+            ("i16", 0) => integer(true, 16),
+            ("i32", 0) => integer(true, 32),
+            ("i64", 0) | ("isize", 0) => integer(true, 64),
+            ("i128", 0) => integer(true, 128),
+            ("u8", 0) => integer(false, 8),
+            ("u16", 0) => integer(false, 16),
+            ("u32", 0) => integer(false, 32),
+            ("u64", 0) | ("usize", 0) => integer(false, 64),
+            ("u128", 0) => integer(false, 128),
+            ("f32", 0) => Type::Float { bits: 32 },
+            ("f64", 0) => Type::Float { bits: 64 },
+            ("char", 0) => Type::Char,
+            ("str", 0) | ("String", 0) | ("OsString", 0) | ("PathBuf", 0) | ("Path", 0) => {
+                Type::String
+            }
+            ("Option", 1) => Type::Option(Box::new(args.remove(0))),
+            ("Vec", 1)
+            | ("VecDeque", 1)
+            | ("LinkedList", 1)
+            | ("BinaryHeap", 1)
+            | ("HashSet", 1)
+            | ("BTreeSet", 1) => Type::List(Box::new(args.remove(0))),
+            ("HashMap", 2) | ("BTreeMap", 2) => {
+                let key = args.remove(0);
+                Type::Map(Box::new(key), Box::new(args.remove(0)))
+            }
+            ("Box", 1) | ("Rc", 1) | ("Arc", 1) | ("Cow", 1) => args.remove(0),
+            _ => Type::Named { name: path, args },
+        }
+    }
+}
+
+/// Splits the comma-separated types in `args`, ignoring commas nested in other types.
+fn split_args(args: &str) -> Vec<&str> {
+    let mut split = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in args.char_indices() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' | ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(args[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    let last = args[start..].trim();
+    if !last.is_empty() {
+        split.push(last);
+    }
+    split
+}
+
+impl fmt::Display for Service {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "trait {} {{", self.name)?;
+        for method in self.methods.iter() {
+            writeln!(f, "    {}", method)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+impl fmt::Display for Method {
+    /// Writes the RPC as it would appear in a service definition.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#[id = {}] ", self.id)?;
+        if self.wire_name != snake_to_camel(&self.name) {
+            write!(f, "#[name = {:?}] ", self.wire_name)?;
+        }
+        if let Some(since) = self.since {
+            write!(f, "#[since({})] ", since)?;
+        }
+        if let Some(removed) = self.removed {
+            write!(f, "#[removed({})] ", removed)?;
+        }
+        if let Some(ref error) = self.error {
+            write!(f, "#[throws({})] ", error)?;
+        }
+        match self.kind {
+            MethodKind::Unary => {}
+            MethodKind::OneWay => write!(f, "#[oneway] ")?,
+            MethodKind::Streaming => write!(f, "#[stream] ")?,
+        }
+        write!(f, "async fn {}(", self.name)?;
+        for (i, arg) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", arg.name, arg.ty)?;
+        }
+        write!(f, ")")?;
+        if self.output != "()" {
+            write!(f, " -> {}", self.output)?;
+        }
+        write!(f, ";")
+    }
+}
+
+/// Converts the snake_case name of an RPC to the CamelCase name of its generated request and
+/// response variants, which is also its default wire name. Underscores are dropped, and each
+/// word is capitalized and the rest of it lowercased.
+pub fn snake_to_camel(ident_str: &str) -> String {
+    let mut camel_ty = String::new();
+    let mut last_char_was_underscore = true;
+    for c in ident_str.chars() {
+        match c {
+            '_' => last_char_was_underscore = true,
+            c if last_char_was_underscore => {
+                camel_ty.extend(c.to_uppercase());
+                last_char_was_underscore = false;
+            }
+            c => camel_ty.extend(c.to_lowercase()),
+        }
+    }
+    camel_ty
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{snake_to_camel, Arg, Method, MethodKind, Service, Type};
+    use std::borrow::Cow;
+
+    const SERVICE: Service = Service {
+        name: Cow::Borrowed("World"),
+        methods: Cow::Borrowed(&[
+            Method {
+                name: Cow::Borrowed("hello"),
+                wire_name: Cow::Borrowed("Hello"),
+                id: 0,
+                kind: MethodKind::Unary,
+                args: Cow::Borrowed(&[Arg {
+                    name: Cow::Borrowed("name"),
+                    ty: Cow::Borrowed("String"),
+                }]),
+                output: Cow::Borrowed("String"),
+                error: None,
+                since: None,
+                removed: None,
+            },
+            Method {
+                name: Cow::Borrowed("get"),
+                wire_name: Cow::Borrowed("Get"),
+                id: 1,
+                kind: MethodKind::Unary,
+                args: Cow::Borrowed(&[Arg {
+                    name: Cow::Borrowed("key"),
+                    ty: Cow::Borrowed("u64"),
+                }]),
+                output: Cow::Borrowed("Vec<u8>"),
+                error: Some(Cow::Borrowed("GetError")),
+                since: Some(2),
+                removed: None,
+            },
+            Method {
+                name: Cow::Borrowed("log_lines"),
+                wire_name: Cow::Borrowed("lines_v2"),
+                id: 2,
+                kind: MethodKind::Streaming,
+                args: Cow::Borrowed(&[
+                    Arg {
+                        name: Cow::Borrowed("path"),
+                        ty: Cow::Borrowed("String"),
+                    },
+                    Arg {
+                        name: Cow::Borrowed("limit"),
+                        ty: Cow::Borrowed("Option<u64>"),
+                    },
+                ]),
+                output: Cow::Borrowed("Vec<u8>"),
+                error: None,
+                since: None,
+                removed: None,
+            },
+            Method {
+                name: Cow::Borrowed("ping"),
+                wire_name: Cow::Borrowed("Ping"),
+                id: 3,
+                kind: MethodKind::OneWay,
+                args: Cow::Borrowed(&[]),
+                output: Cow::Borrowed("()"),
+                error: None,
+                since: None,
+                removed: None,
+            },
+        ]),
+    };
+
+    #[test]
+    fn display() {
+        assert_eq!(
+            SERVICE.to_string(),
+            "trait World {\n\
+             \x20   #[id = 0] async fn hello(name: String) -> String;\n\
+             \x20   #[id = 1] #[since(2)] #[throws(GetError)] async fn get(key: u64) -> Vec<u8>;\n\
+             \x20   #[id = 2] #[name = \"lines_v2\"] #[stream] \
+             async fn log_lines(path: String, limit: Option<u64>) -> Vec<u8>;\n\
+             \x20   #[id = 3] #[oneway] async fn ping();\n\
+             }"
+        );
+    }
+
+    #[test]
+    fn breaking_changes() {
+        let mut methods = SERVICE.methods.to_vec();
+        // hello swaps places with get, and takes its name as a &str, and a new greeting.
+        methods.swap(0, 1);
+        methods[0].id = 0;
+        methods[1].id = 1;
+        methods[1].args = Cow::Owned(vec![
+            Arg {
+                name: Cow::Borrowed("greeting"),
+                ty: Cow::Borrowed("String"),
+            },
+            Arg {
+                name: Cow::Borrowed("name"),
+                ty: Cow::Borrowed("&str"),
+            },
+        ]);
+        // get stops throwing, log_lines is unary, and ping is gone.
+        methods[0].error = None;
+        methods[2].kind = MethodKind::Unary;
+        methods[2].wire_name = Cow::Borrowed("LogLines");
+        methods[3].removed = Some(3);
+        // A new RPC doesn't break old peers.
+        methods.push(Method {
+            name: Cow::Borrowed("pong"),
+            wire_name: Cow::Borrowed("Pong"),
+            id: 4,
+            ..methods[3].clone()
+        });
+        let new = Service {
+            name: SERVICE.name.clone(),
+            methods: Cow::Owned(methods),
+        };
+
+        let changes: Vec<_> = super::breaking_changes(&SERVICE, &new)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                "`hello` moved from id 0 to id 1, which breaks positional formats like bincode",
+                "argument `name` of `hello` changed type from `String` to `&str`",
+                "argument `name` of `hello` moved from position 0 to 1, which breaks positional \
+                 formats like bincode",
+                "`hello` takes a new argument, `greeting: String`",
+                "`get` moved from id 1 to id 0, which breaks positional formats like bincode",
+                "`get` no longer throws `GetError`",
+                "`log_lines` is serialized as \"LogLines\" rather than \"lines_v2\", which \
+                 breaks formats that name RPCs, like JSON",
+                "`log_lines` changed from streaming to unary",
+                "`ping` was removed",
+            ]
+        );
+        assert!(super::breaking_changes(&SERVICE, &SERVICE).is_empty());
+    }
+
+    #[test]
+    fn method() {
+        assert_eq!(SERVICE.method("ping").map(|m| m.id), Some(3));
+        assert!(SERVICE.method("pong").is_none());
+    }
+
+    #[test]
+    fn parse_type() {
+        let list = |ty| Type::List(Box::new(ty));
+        let string = || Type::String;
+        assert_eq!(Type::parse("()"), Type::Unit);
+        assert_eq!(
+            Type::parse("usize"),
+            Type::Integer {
+                signed: false,
+                bits: 64
+            }
+        );
+        assert_eq!(Type::parse("&'static str"), string());
+        assert_eq!(Type::parse("std::borrow::Cow<'static, str>"), string());
+        assert_eq!(Type::parse("Vec<Box<String>>"), list(string()));
+        assert_eq!(
+            Type::parse("&'static [u8]"),
+            list(Type::Integer {
+                signed: false,
+                bits: 8
+            })
+        );
+        assert_eq!(
+            Type::parse("[f32; 3]"),
+            Type::Array(Box::new(Type::Float { bits: 32 }), 3)
+        );
+        assert_eq!(
+            Type::parse("Option<(String, Vec<char>)>"),
+            Type::Option(Box::new(Type::Tuple(vec![string(), list(Type::Char)])))
+        );
+        assert_eq!(Type::parse("(String)"), string());
+        assert_eq!(Type::parse("(String,)"), Type::Tuple(vec![string()]));
+        assert_eq!(
+            Type::parse("HashMap<String, model::User>"),
+            Type::Map(
+                Box::new(string()),
+                Box::new(Type::Named {
+                    name: "model::User".into(),
+                    args: vec![]
+                })
+            )
+        );
+        assert_ne!(Type::parse("admin::User"), Type::parse("model::User"));
+        assert_eq!(
+            Type::parse("Result<bool, E>"),
+            Type::Named {
+                name: "Result".into(),
+                args: vec![
+                    Type::Bool,
+                    Type::Named {
+                        name: "E".into(),
+                        args: vec![]
+                    }
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn snake_to_camel_basic() {
+        assert_eq!(snake_to_camel("abc_def"), "AbcDef");
+    }
+
+    #[test]
+    fn snake_to_camel_underscore_suffix() {
+        assert_eq!(snake_to_camel("abc_def_"), "AbcDef");
+    }
+
+    #[test]
+    fn snake_to_camel_underscore_prefix() {
+        assert_eq!(snake_to_camel("_abc_def"), "AbcDef");
+    }
+
+    #[test]
+    fn snake_to_camel_underscore_consecutive() {
+        assert_eq!(snake_to_camel("abc__def"), "AbcDef");
+    }
+
+    #[test]
+    fn snake_to_camel_capital_in_middle() {
+        assert_eq!(snake_to_camel("aBc_dEf"), "AbcDef");
+    }
+}
@@ -0,0 +1,271 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Writes a record of every request handled by a server to an audit log.
+//!
+//! A server audits requests when its [`Config::audit`](super::Config::audit) is set to an
+//! [`AuditLog`]. Records are queued in memory and written in batches by an [`AuditWriter`]
+//! running on its own task, so that a slow sink never blocks request handling. When the queue is
+//! full, records are dropped and counted.
+
+use super::metrics::ErrorClass;
+use crate::{
+    event::{EventSink, LogSink},
+    util::batch,
+};
+use fnv::FnvHasher;
+use futures::{
+    prelude::*,
+    task::{Context, Poll},
+};
+use humantime::format_rfc3339;
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use trace::TraceId;
+
+/// A record of a single request.
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    /// When the request finished.
+    pub timestamp: SystemTime,
+    /// The trace of the request.
+    pub trace_id: TraceId,
+    /// The principal on whose behalf the request was made: the
+    /// [peer identity](crate::context::Context::peer_identity)'s principal, if the peer was
+    /// authenticated. Metadata sent by the client is never trusted as a principal.
+    pub principal: Option<String>,
+    /// The name of the method invoked, or `"unknown"` if the server can't
+    /// [name it](super::Serve::method_name).
+    pub method: &'static str,
+    /// A hash of the request's arguments, with redacted arguments left out, if the server
+    /// [provides one](super::Serve::args_hash).
+    pub args_hash: Option<u64>,
+    /// Whether the request succeeded, or the class of error with which it failed.
+    pub result: Result<(), ErrorClass>,
+    /// The time taken to handle the request.
+    pub duration: Duration,
+    #[doc(hidden)]
+    pub(crate) _non_exhaustive: (),
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} trace_id={} principal={} method={}",
+            format_rfc3339(self.timestamp),
+            self.trace_id,
+            self.principal.as_ref().map(String::as_str).unwrap_or("-"),
+            self.method,
+        )?;
+        match self.args_hash {
+            Some(hash) => write!(f, " args_hash={:016x}", hash)?,
+            None => write!(f, " args_hash=-")?,
+        }
+        match self.result {
+            Ok(()) => write!(f, " result=ok")?,
+            Err(class) => write!(f, " result={}", class)?,
+        }
+        write!(f, " duration_us={}", self.duration.as_micros())
+    }
+}
+
+/// Hashes the name and arguments of a request through the arguments' `Hash` impls. The hash is
+/// FNV, which, unlike std's default hasher, isn't seeded per process, so the same request hashes
+/// the same on every server built with the same argument types.
+pub struct ArgsHasher(FnvHasher);
+
+impl ArgsHasher {
+    /// Starts the hash of a request invoking `method`.
+    pub fn new(method: &str) -> Self {
+        let mut hasher = FnvHasher::default();
+        method.hash(&mut hasher);
+        ArgsHasher(hasher)
+    }
+
+    /// Adds the next argument to the hash.
+    pub fn arg<T: Hash + ?Sized>(&mut self, arg: &T) {
+        arg.hash(&mut self.0);
+    }
+
+    /// Returns the hash of the arguments added so far.
+    pub fn finish(&self) -> u64 {
+        self.0.finish()
+    }
+}
+
+impl fmt::Debug for ArgsHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ArgsHasher").field(&self.finish()).finish()
+    }
+}
+
+/// A destination for audit records.
+pub trait AuditSink {
+    /// Writes a batch of records.
+    fn write(&mut self, records: &[AuditRecord]) -> io::Result<()>;
+}
+
+/// A sink writing each record as a line of text to an [`io::Write`].
+#[derive(Debug)]
+pub struct WriteSink<W>(pub W);
+
+impl<W: io::Write> AuditSink for WriteSink<W> {
+    fn write(&mut self, records: &[AuditRecord]) -> io::Result<()> {
+        for record in records {
+            writeln!(self.0, "{}", record)?;
+        }
+        self.0.flush()
+    }
+}
+
+/// Queues audit records to be written by an [`AuditWriter`].
+#[derive(Clone, Debug)]
+pub struct AuditLog {
+    records: batch::Queue<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Returns a log queuing up to `capacity` records, and the writer that writes them to `sink`.
+    /// The writer must be spawned or polled for records to be written; it completes once every
+    /// clone of the log is dropped and the queue is drained.
+    pub fn new<S: AuditSink>(sink: S, capacity: usize) -> (AuditLog, AuditWriter<S>) {
+        AuditLog::with_events(sink, capacity, Arc::new(LogSink))
+    }
+
+    /// Like [`new`](AuditLog::new), but reports dropped records and failed writes to `events`
+    /// rather than logging them.
+    pub fn with_events<S: AuditSink>(
+        sink: S,
+        capacity: usize,
+        events: Arc<dyn EventSink>,
+    ) -> (AuditLog, AuditWriter<S>) {
+        let (records, writer) = batch::queue("audit records", sink, capacity, events);
+        (AuditLog { records }, AuditWriter { writer })
+    }
+
+    /// Queues `record` to be written, dropping it if the queue is full.
+    pub fn log(&self, record: AuditRecord) {
+        self.records.push(record);
+    }
+
+    /// Returns the number of records dropped because the queue was full or the writer was gone.
+    pub fn dropped(&self) -> u64 {
+        self.records.dropped()
+    }
+}
+
+/// Writes queued audit records to a sink in batches.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct AuditWriter<S> {
+    writer: batch::Writer<AuditRecord, S>,
+}
+
+impl<S: AuditSink> batch::BatchSink<AuditRecord> for S {
+    fn write(&mut self, batch: &[AuditRecord]) -> io::Result<()> {
+        AuditSink::write(self, batch)
+    }
+}
+
+impl<S: AuditSink> Future for AuditWriter<S> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.writer.poll_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use futures::executor::block_on;
+
+    fn record(method: &'static str) -> AuditRecord {
+        AuditRecord {
+            timestamp: SystemTime::UNIX_EPOCH,
+            trace_id: TraceId::default(),
+            principal: Some("alice".into()),
+            method,
+            args_hash: Some(0xabc),
+            result: Err(ErrorClass::DeadlineExceeded),
+            duration: Duration::from_micros(1500),
+            _non_exhaustive: (),
+        }
+    }
+
+    #[test]
+    fn writes_queued_records() {
+        let (log, writer) = AuditLog::new(WriteSink(vec![]), 2);
+        log.log(record("get"));
+        log.log(record("put"));
+        log.log(record("dropped"));
+        assert_eq!(log.dropped(), 1);
+        drop(log);
+
+        let mut writer = writer;
+        block_on(&mut writer);
+        let lines = String::from_utf8(writer.writer.sink().0.clone()).unwrap();
+        let lines: Vec<_> = lines.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "1970-01-01T00:00:00Z trace_id=00 principal=alice method=get \
+                 args_hash=0000000000000abc result=deadline_exceeded duration_us=1500",
+                "1970-01-01T00:00:00Z trace_id=00 principal=alice method=put \
+                 args_hash=0000000000000abc result=deadline_exceeded duration_us=1500",
+            ]
+        );
+    }
+
+    #[derive(Debug, Default)]
+    struct Events(std::sync::Mutex<Vec<String>>);
+
+    impl EventSink for Events {
+        fn event(&self, event: &Event) {
+            self.0.lock().unwrap().push(event.to_string());
+        }
+    }
+
+    #[test]
+    fn reports_full_queue_rarely() {
+        let events = Arc::new(Events::default());
+        let (log, _writer) = AuditLog::with_events(WriteSink(vec![]), 0, events.clone());
+        for _ in 0..5 {
+            log.log(record("dropped"));
+        }
+        assert_eq!(log.dropped(), 5);
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            [
+                "Queue of audit records is full; dropped 1 so far.",
+                "Queue of audit records is full; dropped 2 so far.",
+                "Queue of audit records is full; dropped 4 so far.",
+            ]
+        );
+    }
+
+    fn hash(method: &str, key: &str, value: u32) -> u64 {
+        let mut hasher = ArgsHasher::new(method);
+        hasher.arg(key);
+        hasher.arg(&value);
+        hasher.finish()
+    }
+
+    #[test]
+    fn args_hash_is_stable() {
+        assert_eq!(hash("put", "k", 1), hash("put", "k", 1));
+        assert_ne!(hash("put", "k", 1), hash("put", "k", 2));
+        assert_ne!(hash("put", "k", 1), hash("get", "k", 1));
+    }
+}
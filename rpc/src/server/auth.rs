@@ -0,0 +1,293 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Rejects requests that lack a valid bearer token.
+//!
+//! An [`Authenticator`] checks the [bearer token](crate::context::Context::bearer_token) of each
+//! request with a validation function before the request reaches the server, responding to
+//! requests whose token is missing or invalid with a [`PermissionDenied`](io::ErrorKind) error
+//! marked [unauthenticated](ServerError::unauthenticated), which clients
+//! [classify](crate::error::Classify) as such. A
+//! [`BearerAuth`](crate::client::auth::BearerAuth) client refreshes its token when it sees one;
+//! other `PermissionDenied` errors, such as a service's own authorization failures, don't.
+//!
+//! ```ignore
+//! let keys = Arc::new(verifying_keys);
+//! server
+//!     .incoming(transport)
+//!     .authenticate(move |token: &str| keys.verify(token).is_ok())
+//!     .respond_with(serve(HelloServer));
+//! ```
+//!
+//! Validation runs as the channel reads each request, so it should be quick, such as checking a
+//! signature or looking the token up in memory; tokens that must be introspected remotely are
+//! better cached by the validation function.
+
+use super::{Channel, Config, InFlightSlot};
+use crate::{context::PeerIdentity, error::UNAUTHENTICATED, event::Event, Response, ServerError};
+use futures::{
+    future::AbortRegistration,
+    prelude::*,
+    ready,
+    task::{Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{io, pin::Pin, sync::Arc};
+
+/// A [`Channel`] that rejects requests whose bearer token `validate` doesn't accept.
+#[derive(Debug)]
+pub struct Authenticator<C, F> {
+    validate: F,
+    /// The ID of the request whose rejection is waiting for the sink to be ready.
+    rejected: Option<u64>,
+    inner: C,
+}
+
+impl<C, F> Authenticator<C, F> {
+    unsafe_unpinned!(rejected: Option<u64>);
+    unsafe_pinned!(inner: C);
+
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, F> Authenticator<C, F>
+where
+    C: Channel,
+    F: Fn(&str) -> bool,
+{
+    /// Returns a new `Authenticator` that wraps the given channel and admits only requests whose
+    /// bearer token `validate` returns true for.
+    pub fn new(inner: C, validate: F) -> Self {
+        Authenticator {
+            inner,
+            validate,
+            rejected: None,
+        }
+    }
+}
+
+impl<C, F> Stream for Authenticator<C, F>
+where
+    C: Channel,
+    F: Fn(&str) -> bool,
+{
+    type Item = <C as Stream>::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(request_id) = *self.as_mut().rejected() {
+                ready!(self.as_mut().inner().poll_ready(cx)?);
+                *self.as_mut().rejected() = None;
+                self.as_mut().start_send(Response {
+                    request_id,
+                    message: Err(ServerError {
+                        kind: io::ErrorKind::PermissionDenied,
+                        detail: Some(UNAUTHENTICATED.into()),
+                        unauthenticated: true,
+                        _non_exhaustive: (),
+                    }),
+                    partial: false,
+                    _non_exhaustive: (),
+                })?;
+            }
+
+            let request = match ready!(self.as_mut().inner().poll_next(cx)?) {
+                Some(request) => request,
+                None => return Poll::Ready(None),
+            };
+            let authenticated = request
+                .context
+                .bearer_token()
+                .map_or(false, |token| (self.validate)(token));
+            if authenticated {
+                return Poll::Ready(Some(Ok(request)));
+            }
+
+            self.config().events.event(&Event::RequestUnauthenticated {
+                trace_id: *request.context.trace_id(),
+                request_id: request.id,
+                one_way: request.one_way,
+            });
+            if !request.one_way {
+                *self.as_mut().rejected() = Some(request.id);
+            }
+        }
+    }
+}
+
+impl<C, F> Sink<Response<<C as Channel>::Resp>> for Authenticator<C, F>
+where
+    C: Channel,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Response<<C as Channel>::Resp>) -> io::Result<()> {
+        self.inner().start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<C, F> AsRef<C> for Authenticator<C, F> {
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C, F> Channel for Authenticator<C, F>
+where
+    C: Channel,
+    F: Fn(&str) -> bool,
+{
+    type Req = <C as Channel>::Req;
+    type Resp = <C as Channel>::Resp;
+
+    fn in_flight_requests(self: Pin<&mut Self>) -> usize {
+        self.inner().in_flight_requests()
+    }
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
+        self.inner().start_request(request_id)
+    }
+
+    fn track_request(self: Pin<&mut Self>, request_id: u64) -> InFlightSlot {
+        self.inner().track_request(request_id)
+    }
+
+    fn in_flight_capacity(&self) -> usize {
+        self.inner.in_flight_capacity()
+    }
+
+    fn filter_key(&self) -> Option<String> {
+        self.inner.filter_key()
+    }
+
+    fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        self.inner.peer_identity()
+    }
+}
+
+/// A stream of authenticating channels.
+#[derive(Debug)]
+pub struct AuthenticatorStream<S, F> {
+    inner: S,
+    validate: F,
+}
+
+impl<S, F> AuthenticatorStream<S, F>
+where
+    S: Stream,
+    <S as Stream>::Item: Channel,
+    F: Fn(&str) -> bool + Clone,
+{
+    unsafe_pinned!(inner: S);
+    unsafe_unpinned!(validate: F);
+
+    pub(crate) fn new(inner: S, validate: F) -> Self {
+        Self { inner, validate }
+    }
+}
+
+impl<S, F> Stream for AuthenticatorStream<S, F>
+where
+    S: Stream,
+    <S as Stream>::Item: Channel,
+    F: Fn(&str) -> bool + Clone,
+{
+    type Item = Authenticator<<S as Stream>::Item, F>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match ready!(self.as_mut().inner().poll_next(cx)) {
+            Some(channel) => {
+                Poll::Ready(Some(Authenticator::new(channel, self.validate().clone())))
+            }
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::testing::{self, FakeChannel, PollExt};
+#[cfg(test)]
+use crate::error::{Classify, ErrorKind};
+#[cfg(test)]
+use pin_utils::pin_mut;
+
+#[test]
+fn authenticator_admits_valid_tokens() -> io::Result<()> {
+    let authenticator =
+        Authenticator::new(FakeChannel::default::<isize, isize>(), |token: &str| {
+            token == "s3cr3t"
+        });
+
+    pin_mut!(authenticator);
+    authenticator.inner.push_req(0, 1);
+    let request = authenticator.inner.stream[0].as_mut().unwrap();
+    request.context = request.context.clone().with_bearer_token("s3cr3t");
+    assert_eq!(
+        authenticator
+            .as_mut()
+            .poll_next(&mut testing::cx())?
+            .map(|r| r.map(|r| (r.id, r.message))),
+        Poll::Ready(Some((0, 1)))
+    );
+    assert!(authenticator.inner.sink.is_empty());
+    Ok(())
+}
+
+#[test]
+fn authenticator_rejects_missing_and_invalid_tokens() {
+    let authenticator =
+        Authenticator::new(FakeChannel::default::<isize, isize>(), |token: &str| {
+            token == "s3cr3t"
+        });
+
+    pin_mut!(authenticator);
+    authenticator.inner.push_req(0, 1);
+    authenticator.inner.push_req(1, 1);
+    let request = authenticator.inner.stream[1].as_mut().unwrap();
+    request.context = request.context.clone().with_bearer_token("expired");
+    assert!(authenticator
+        .as_mut()
+        .poll_next(&mut testing::cx())
+        .is_done());
+
+    let rejections: Vec<_> = authenticator
+        .inner
+        .sink
+        .iter()
+        .map(|resp| {
+            (
+                resp.request_id,
+                resp.message.as_ref().unwrap_err().classify(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        rejections,
+        [
+            (0, ErrorKind::Unauthenticated),
+            (1, ErrorKind::Unauthenticated)
+        ]
+    );
+}
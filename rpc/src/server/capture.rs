@@ -0,0 +1,173 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Captures a sample of full request and response payloads, for diagnosing what exactly a client
+//! sent.
+//!
+//! A server captures payloads when its [`Config::capture`](super::Config::capture) is set. Payloads
+//! are rendered with `Debug`, so arguments marked `#[redact]` are left out, and only servers that
+//! [can render them](super::Serve::debug_request) are captured.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+use trace::TraceId;
+
+/// A captured request and its response.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    /// Numbers captures in the order they were taken.
+    pub id: u64,
+    /// When the request arrived.
+    pub timestamp: SystemTime,
+    /// The trace of the request.
+    pub trace_id: TraceId,
+    /// The ID of the request, unique to its channel.
+    pub request_id: u64,
+    /// The name of the method invoked, or `"unknown"` if the server can't
+    /// [name it](super::Serve::method_name).
+    pub method: &'static str,
+    /// The request, as rendered by `Debug`.
+    pub request: String,
+    /// The final response, as rendered by `Debug`, or `None` if the request is one-way or hasn't
+    /// been responded to.
+    pub response: Option<String>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+/// Samples requests for capture and keeps the most recent captures in a ring buffer.
+///
+/// Clones share the same buffer, so a handle kept outside the server can read what the server
+/// captured.
+#[derive(Clone)]
+pub struct PayloadCapture {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    one_in: u64,
+    capacity: usize,
+    seen: AtomicU64,
+    next_id: AtomicU64,
+    captures: Mutex<VecDeque<Capture>>,
+}
+
+impl PayloadCapture {
+    /// Returns a capture that samples one in every `one_in` requests and keeps the last
+    /// `capacity` captures.
+    pub fn new(one_in: u64, capacity: usize) -> Self {
+        assert!(one_in > 0, "one_in must be positive");
+        PayloadCapture {
+            inner: Arc::new(Inner {
+                one_in,
+                capacity,
+                seen: AtomicU64::new(0),
+                next_id: AtomicU64::new(0),
+                captures: Mutex::new(VecDeque::with_capacity(capacity)),
+            }),
+        }
+    }
+
+    /// Returns the captures in the buffer, oldest first.
+    pub fn captures(&self) -> Vec<Capture> {
+        self.inner
+            .captures
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Empties the buffer.
+    pub fn clear(&self) {
+        self.inner.captures.lock().unwrap().clear();
+    }
+
+    /// Returns whether the next request should be captured.
+    pub(crate) fn sample(&self) -> bool {
+        self.inner.seen.fetch_add(1, Ordering::Relaxed) % self.inner.one_in == 0
+    }
+
+    /// Adds a request to the buffer, evicting the oldest capture if it's full. Returns the ID of
+    /// the capture, for [completing](PayloadCapture::complete) it once the response is ready.
+    pub(crate) fn start(
+        &self,
+        trace_id: TraceId,
+        request_id: u64,
+        method: &'static str,
+        request: String,
+    ) -> u64 {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        if self.inner.capacity == 0 {
+            return id;
+        }
+        let mut captures = self.inner.captures.lock().unwrap();
+        if captures.len() == self.inner.capacity {
+            captures.pop_front();
+        }
+        captures.push_back(Capture {
+            id,
+            timestamp: SystemTime::now(),
+            trace_id,
+            request_id,
+            method,
+            request,
+            response: None,
+            _non_exhaustive: (),
+        });
+        id
+    }
+
+    /// Adds the response to capture `id`, if it's still in the buffer.
+    pub(crate) fn complete(&self, id: u64, response: String) {
+        let mut captures = self.inner.captures.lock().unwrap();
+        if let Some(capture) = captures.iter_mut().rev().find(|capture| capture.id == id) {
+            capture.response = Some(response);
+        }
+    }
+}
+
+impl fmt::Debug for PayloadCapture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PayloadCapture")
+            .field("one_in", &self.inner.one_in)
+            .field("capacity", &self.inner.capacity)
+            .finish()
+    }
+}
+
+#[test]
+fn samples_and_evicts() {
+    let capture = PayloadCapture::new(2, 2);
+    let sampled: Vec<_> = (0..4).map(|_| capture.sample()).collect();
+    assert_eq!(sampled, [true, false, true, false]);
+
+    let first = capture.start(TraceId::default(), 0, "get", "Get".into());
+    capture.start(TraceId::default(), 1, "get", "Get".into());
+    capture.start(TraceId::default(), 2, "put", "Put".into());
+    // The first capture was evicted, so completing it does nothing.
+    capture.complete(first, "Gotten".into());
+    capture.complete(first + 2, "Put".into());
+
+    let captures = capture.captures();
+    assert_eq!(
+        captures.iter().map(|c| c.request_id).collect::<Vec<_>>(),
+        [1, 2]
+    );
+    assert_eq!(captures[0].response, None);
+    assert_eq!(
+        captures[1].response.as_ref().map(String::as_str),
+        Some("Put")
+    );
+}
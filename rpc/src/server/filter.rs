@@ -5,10 +5,17 @@
 // https://opensource.org/licenses/MIT.
 
 use crate::{
-    server::{self, Channel},
-    util::Compact,
+    context::PeerIdentity,
+    event::{Event, EventSink, LogSink},
+    runtime::{self, Timer},
+    server::{self, Channel, InFlightSlot},
+    util::{
+        hash::HashMap,
+        sync::{Counter, WeakCounter},
+        Compact,
+    },
+    MapHasher,
 };
-use fnv::FnvHashMap;
 use futures::{
     channel::mpsc,
     future::AbortRegistration,
@@ -17,14 +24,22 @@ use futures::{
     stream::Fuse,
     task::{Context, Poll},
 };
-use log::{debug, info, trace};
 use pin_utils::{unsafe_pinned, unsafe_unpinned};
-use raii_counter::{Counter, WeakCounter};
-use std::sync::{Arc, Weak};
 use std::{
-    collections::hash_map::Entry, convert::TryInto, fmt, hash::Hash, marker::Unpin, pin::Pin,
+    collections::BTreeMap,
+    convert::TryInto,
+    fmt,
+    hash::Hash,
+    marker::Unpin,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::SystemTime,
 };
 
+/// The most channel closures the filter handles per poll, so that a flood of disconnects can't
+/// keep it from its listener.
+const CLOSURES_PER_POLL: usize = 1024;
+
 /// A single-threaded filter that drops channels based on per-key limits.
 #[derive(Debug)]
 pub struct ChannelFilter<S, K, F>
@@ -33,10 +48,136 @@ where
 {
     listener: Fuse<S>,
     channels_per_key: u32,
-    dropped_keys: mpsc::UnboundedReceiver<K>,
-    dropped_keys_tx: mpsc::UnboundedSender<K>,
-    key_counts: FnvHashMap<K, TrackerPrototype<K>>,
+    /// The key of each channel that closed, and the number of channels left open for the key.
+    dropped_keys: mpsc::UnboundedReceiver<(Arc<K>, usize)>,
+    dropped_keys_tx: mpsc::UnboundedSender<(Arc<K>, usize)>,
+    /// The channels open per key. Each key is shared with the trackers of its channels.
+    key_counts: HashMap<Arc<K>, WeakCounter>,
     keymaker: F,
+    events: Arc<dyn EventSink>,
+    key_stats: Option<KeyStats>,
+}
+
+/// The history of a single key in a [`ChannelFilter`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyActivity {
+    /// The number of channels accepted for the key.
+    pub accepted: u64,
+    /// The number of channels rejected because the key was at its limit.
+    pub rejected: u64,
+    /// When a channel for the key was last accepted, rejected, or closed.
+    pub last_activity: SystemTime,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+/// Counts the channels a [`ChannelFilter`] accepts and rejects per key.
+///
+/// Unlike [open channel counts](super::Stats::channels_per_key), keys are remembered after their
+/// channels close, so that a client that repeatedly connects and disconnects stands out. Up to
+/// `max_keys` keys are remembered; beyond that, the key least recently active is forgotten.
+///
+/// Clones share the same counts, so a handle kept outside the server can read them while the
+/// filter runs.
+#[derive(Clone, Debug)]
+pub struct KeyStats {
+    max_keys: usize,
+    keys: Arc<Mutex<Keys>>,
+    timer: Arc<dyn Timer>,
+}
+
+/// The keys remembered by [`KeyStats`], ordered by when they were last active, so that the least
+/// recently active is found without scanning them all.
+#[derive(Debug, Default)]
+struct Keys {
+    /// The activity of each key, and its position in `by_recency`.
+    activity: HashMap<String, (KeyActivity, u64)>,
+    /// The keys by when they were last active, least recently active first.
+    by_recency: BTreeMap<u64, String>,
+    /// The position of the next key to be active.
+    next: u64,
+}
+
+impl KeyStats {
+    /// Returns empty stats remembering up to `max_keys` keys.
+    pub fn new(max_keys: usize) -> Self {
+        KeyStats {
+            max_keys,
+            keys: Arc::default(),
+            timer: runtime::default(),
+        }
+    }
+
+    /// Returns the stats with activity timed by `timer`. Defaults to the timer of the
+    /// [default runtime](runtime::DefaultRuntime).
+    pub fn with_timer(mut self, timer: Arc<dyn Timer>) -> Self {
+        self.timer = timer;
+        self
+    }
+
+    /// Returns the stats with their keys hashed by `hasher`. Defaults to [FNV](MapHasher::Fnv).
+    pub fn with_hasher(self, hasher: MapHasher) -> Self {
+        {
+            let mut keys = self.keys.lock().unwrap();
+            let mut activity = HashMap::with_hasher(hasher.build());
+            activity.extend(keys.activity.drain());
+            keys.activity = activity;
+        }
+        self
+    }
+
+    /// Returns the activity of each remembered key.
+    pub fn snapshot(&self) -> BTreeMap<String, KeyActivity> {
+        self.keys
+            .lock()
+            .unwrap()
+            .activity
+            .iter()
+            .map(|(key, (activity, _))| (key.clone(), activity.clone()))
+            .collect()
+    }
+
+    fn record(&self, key: &dyn fmt::Display, update: impl FnOnce(&mut KeyActivity)) {
+        if self.max_keys == 0 {
+            return;
+        }
+        let key = key.to_string();
+        let now = self.timer.system_now();
+        let mut keys = self.keys.lock().unwrap();
+        let Keys {
+            activity,
+            by_recency,
+            next,
+        } = &mut *keys;
+        match activity.get(&key) {
+            Some(&(_, recency)) => {
+                by_recency.remove(&recency);
+            }
+            None if activity.len() >= self.max_keys => {
+                let least_recent = by_recency.keys().next().cloned();
+                if let Some(forgotten) =
+                    least_recent.and_then(|recency| by_recency.remove(&recency))
+                {
+                    activity.remove(&forgotten);
+                }
+            }
+            None => {}
+        }
+        let (entry, recency) = activity.entry(key.clone()).or_insert_with(|| {
+            let new = KeyActivity {
+                accepted: 0,
+                rejected: 0,
+                last_activity: now,
+                _non_exhaustive: (),
+            };
+            (new, 0)
+        });
+        update(entry);
+        entry.last_activity = now;
+        *recency = *next;
+        by_recency.insert(*next, key);
+        *next += 1;
+    }
 }
 
 /// A channel that is tracked by a ChannelFilter.
@@ -44,6 +185,8 @@ where
 pub struct TrackedChannel<C, K> {
     inner: C,
     tracker: Tracker<K>,
+    /// The key, rendered when the channel is admitted.
+    filter_key: String,
 }
 
 impl<C, K> TrackedChannel<C, K> {
@@ -52,32 +195,24 @@ impl<C, K> TrackedChannel<C, K> {
 
 #[derive(Clone, Debug)]
 struct Tracker<K> {
-    key: Option<Arc<K>>,
+    key: Arc<K>,
     counter: Counter,
-    dropped_keys: mpsc::UnboundedSender<K>,
+    dropped_keys: mpsc::UnboundedSender<(Arc<K>, usize)>,
 }
 
 impl<K> Drop for Tracker<K> {
     fn drop(&mut self) {
-        if self.counter.count() <= 1 {
+        // Of the trackers of a key dropped at once, only the last to release its count sends
+        // none left.
+        if let Some(remaining) = self.counter.release() {
             // Don't care if the listener is dropped.
-            match Arc::try_unwrap(self.key.take().unwrap()) {
-                Ok(key) => {
-                    let _ = self.dropped_keys.unbounded_send(key);
-                }
-                _ => unreachable!(),
-            }
+            let _ = self
+                .dropped_keys
+                .unbounded_send((self.key.clone(), remaining));
         }
     }
 }
 
-#[derive(Clone, Debug)]
-struct TrackerPrototype<K> {
-    key: Weak<K>,
-    counter: WeakCounter,
-    dropped_keys: mpsc::UnboundedSender<K>,
-}
-
 impl<C, K> Stream for TrackedChannel<C, K>
 where
     C: Stream,
@@ -136,6 +271,22 @@ where
     fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
         self.inner().start_request(request_id)
     }
+
+    fn track_request(self: Pin<&mut Self>, request_id: u64) -> InFlightSlot {
+        self.inner().track_request(request_id)
+    }
+
+    fn in_flight_capacity(&self) -> usize {
+        self.inner.in_flight_capacity()
+    }
+
+    fn filter_key(&self) -> Option<String> {
+        Some(self.filter_key.clone())
+    }
+
+    fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        self.inner.peer_identity()
+    }
 }
 
 impl<C, K> TrackedChannel<C, K> {
@@ -155,9 +306,9 @@ where
     K: fmt::Display + Eq + Hash + Clone,
 {
     unsafe_pinned!(listener: Fuse<S>);
-    unsafe_pinned!(dropped_keys: mpsc::UnboundedReceiver<K>);
-    unsafe_pinned!(dropped_keys_tx: mpsc::UnboundedSender<K>);
-    unsafe_unpinned!(key_counts: FnvHashMap<K, TrackerPrototype<K>>);
+    unsafe_pinned!(dropped_keys: mpsc::UnboundedReceiver<(Arc<K>, usize)>);
+    unsafe_pinned!(dropped_keys_tx: mpsc::UnboundedSender<(Arc<K>, usize)>);
+    unsafe_unpinned!(key_counts: HashMap<Arc<K>, WeakCounter>);
     unsafe_unpinned!(channels_per_key: u32);
     unsafe_unpinned!(keymaker: F);
 }
@@ -176,10 +327,34 @@ where
             channels_per_key,
             dropped_keys,
             dropped_keys_tx,
-            key_counts: FnvHashMap::default(),
+            key_counts: HashMap::default(),
             keymaker,
+            events: Arc::new(LogSink),
+            key_stats: None,
         }
     }
+
+    /// Counts the channels accepted and rejected per key in `key_stats`.
+    pub fn with_key_stats(mut self, key_stats: &KeyStats) -> Self {
+        self.key_stats = Some(key_stats.clone());
+        self
+    }
+
+    /// Hashes keys with `hasher`, which should resist collisions when keys come from untrusted
+    /// peers. Defaults to [FNV](MapHasher::Fnv).
+    pub fn with_hasher(mut self, hasher: MapHasher) -> Self {
+        let mut key_counts = HashMap::with_hasher(hasher.build());
+        key_counts.extend(self.key_counts.drain());
+        self.key_counts = key_counts;
+        self
+    }
+
+    /// Reports the opening and closing of channels to `events` instead of [logging](LogSink)
+    /// them.
+    pub fn with_events(mut self, events: Arc<dyn EventSink>) -> Self {
+        self.events = events;
+        self
+    }
 }
 
 impl<S, K, F> ChannelFilter<S, K, F>
@@ -193,63 +368,58 @@ where
         stream: S::Item,
     ) -> Result<TrackedChannel<S::Item, K>, K> {
         let key = self.as_mut().keymaker()(&stream);
-        let tracker = self.as_mut().increment_channels_for_key(key.clone())?;
-
-        trace!(
-            "[{}] Opening channel ({}/{}) channels for key.",
-            key,
-            tracker.counter.count(),
-            self.as_mut().channels_per_key()
-        );
+        let tracker = self.as_mut().increment_channels_for_key(key)?;
+        let filter_key = tracker.key.to_string();
+
+        self.events.event(&Event::ChannelOpened {
+            key: format_args!("{}", filter_key),
+            channels: tracker.counter.count(),
+            limit: self.channels_per_key,
+        });
+        if let Some(ref key_stats) = self.key_stats {
+            key_stats.record(&*tracker.key, |activity| activity.accepted += 1);
+        }
 
         Ok(TrackedChannel {
             tracker,
             inner: stream,
+            filter_key,
         })
     }
 
     fn increment_channels_for_key(mut self: Pin<&mut Self>, key: K) -> Result<Tracker<K>, K> {
         let channels_per_key = self.channels_per_key;
         let dropped_keys = self.dropped_keys_tx.clone();
-        let key_counts = &mut self.as_mut().key_counts();
-        match key_counts.entry(key.clone()) {
-            Entry::Vacant(vacant) => {
-                let key = Arc::new(key);
-                let counter = WeakCounter::new();
-
-                vacant.insert(TrackerPrototype {
-                    key: Arc::downgrade(&key),
-                    counter: counter.clone(),
-                    dropped_keys: dropped_keys.clone(),
+        // Look the key up by reference, so that only the first channel for a key moves it into
+        // the map, and later channels share it rather than cloning it.
+        if let Some((shared_key, counter)) = self.key_counts.get_key_value(&key) {
+            let count = counter.count();
+            if count >= channels_per_key.try_into().unwrap() {
+                self.events.event(&Event::ChannelRejected {
+                    key: format_args!("{}", key),
+                    channels: count,
+                    limit: channels_per_key,
                 });
-                Ok(Tracker {
-                    key: Some(key),
-                    counter: counter.upgrade(),
-                    dropped_keys,
-                })
-            }
-            Entry::Occupied(o) => {
-                let count = o.get().counter.count();
-                if count >= channels_per_key.try_into().unwrap() {
-                    info!(
-                        "[{}] Opened max channels from key ({}/{}).",
-                        key, count, channels_per_key
-                    );
-                    Err(key)
-                } else {
-                    let TrackerPrototype {
-                        key,
-                        counter,
-                        dropped_keys,
-                    } = o.get().clone();
-                    Ok(Tracker {
-                        counter: counter.upgrade(),
-                        key: Some(key.upgrade().unwrap()),
-                        dropped_keys,
-                    })
+                if let Some(ref key_stats) = self.key_stats {
+                    key_stats.record(&key, |activity| activity.rejected += 1);
                 }
+                return Err(key);
             }
+            return Ok(Tracker {
+                key: shared_key.clone(),
+                counter: counter.upgrade(),
+                dropped_keys,
+            });
         }
+        let key = Arc::new(key);
+        let counter = WeakCounter::new();
+        let tracker = Tracker {
+            key: key.clone(),
+            counter: counter.upgrade(),
+            dropped_keys,
+        };
+        self.as_mut().key_counts().insert(key, counter);
+        Ok(tracker)
     }
 
     fn poll_listener(
@@ -262,15 +432,54 @@ where
         }
     }
 
+    /// Reports the channels that have closed since the last call, up to [`CLOSURES_PER_POLL`],
+    /// and forgets the keys left without channels. Returns ready if any had closed.
     fn poll_closed_channels(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
-        match ready!(self.as_mut().dropped_keys().poll_next_unpin(cx)) {
-            Some(key) => {
-                debug!("All channels dropped for key [{}]", key);
-                self.as_mut().key_counts().remove(&key);
-                self.as_mut().key_counts().compact(0.1);
-                Poll::Ready(())
+        let mut closed = 0;
+        let mut removed = false;
+        while closed < CLOSURES_PER_POLL {
+            let dropped = match self.as_mut().dropped_keys().poll_next_unpin(cx) {
+                Poll::Ready(dropped) => dropped,
+                Poll::Pending => break,
+            };
+            let (key, remaining) =
+                dropped.expect("Holding a copy of closed_channels and didn't close it.");
+            closed += 1;
+            self.events.event(&Event::ChannelClosed {
+                key: format_args!("{}", key),
+                channels: remaining,
+            });
+            // A channel for the key may have been accepted since its last one closed, in which
+            // case the key stays open.
+            let reopened = self
+                .key_counts
+                .get(&key)
+                .map_or(false, |counter| counter.count() > 0);
+            if remaining > 0 || reopened {
+                continue;
+            }
+            self.events.event(&Event::KeyClosed {
+                key: format_args!("{}", key),
+            });
+            if let Some(ref key_stats) = self.key_stats {
+                key_stats.record(&*key, |_| {});
             }
-            None => unreachable!("Holding a copy of closed_channels and didn't close it."),
+            self.as_mut().key_counts().remove(&key);
+            removed = true;
+        }
+        // Compact once per batch of closures rather than once per key, so that a burst of
+        // disconnects doesn't shrink the map repeatedly, only for the next connections to grow it.
+        if removed {
+            self.as_mut().key_counts().compact(0.1);
+        }
+        if closed == CLOSURES_PER_POLL {
+            // More may be waiting; handle them on the next poll.
+            cx.waker().wake_by_ref();
+        }
+        if closed > 0 {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
         }
     }
 }
@@ -287,21 +496,17 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Option<TrackedChannel<S::Item, K>>> {
+        // Closed channels are drained all at once, ahead of the listener, so that the keys they
+        // free are available to the channels accepted next, and so that a burst of disconnects
+        // costs one pass rather than a poll of the listener per closed channel.
+        let _ = self.as_mut().poll_closed_channels(cx);
         loop {
-            match (
-                self.as_mut().poll_listener(cx),
-                self.as_mut().poll_closed_channels(cx),
-            ) {
-                (Poll::Ready(Some(Ok(channel))), _) => {
-                    return Poll::Ready(Some(channel));
-                }
-                (Poll::Ready(Some(Err(_))), _) => {
-                    continue;
-                }
-                (_, Poll::Ready(())) => continue,
-                (Poll::Pending, Poll::Pending) => return Poll::Pending,
-                (Poll::Ready(None), Poll::Pending) => {
-                    trace!("Shutting down listener.");
+            match ready!(self.as_mut().poll_listener(cx)) {
+                Some(Ok(channel)) => return Poll::Ready(Some(channel)),
+                // Rejected channels are dropped untracked, so they close no keys.
+                Some(Err(_)) => continue,
+                None => {
+                    self.events.event(&Event::ListenerClosed);
                     return Poll::Ready(None);
                 }
             }
@@ -319,32 +524,31 @@ fn ctx() -> Context<'static> {
 #[test]
 fn tracker_drop() {
     use assert_matches::assert_matches;
-    use raii_counter::Counter;
 
     let (tx, mut rx) = mpsc::unbounded();
     Tracker {
-        key: Some(Arc::new(1)),
+        key: Arc::new(1),
         counter: Counter::new(),
         dropped_keys: tx,
     };
-    assert_matches!(rx.try_next(), Ok(Some(1)));
+    assert_matches!(rx.try_next(), Ok(Some((ref key, 0))) if **key == 1);
 }
 
 #[test]
 fn tracked_channel_stream() {
     use assert_matches::assert_matches;
     use pin_utils::pin_mut;
-    use raii_counter::Counter;
 
     let (chan_tx, chan) = mpsc::unbounded();
     let (dropped_keys, _) = mpsc::unbounded();
     let channel = TrackedChannel {
         inner: chan,
         tracker: Tracker {
-            key: Some(Arc::new(1)),
+            key: Arc::new(1),
             counter: Counter::new(),
             dropped_keys,
         },
+        filter_key: "1".into(),
     };
 
     chan_tx.unbounded_send("test").unwrap();
@@ -356,17 +560,17 @@ fn tracked_channel_stream() {
 fn tracked_channel_sink() {
     use assert_matches::assert_matches;
     use pin_utils::pin_mut;
-    use raii_counter::Counter;
 
     let (chan, mut chan_rx) = mpsc::unbounded();
     let (dropped_keys, _) = mpsc::unbounded();
     let channel = TrackedChannel {
         inner: chan,
         tracker: Tracker {
-            key: Some(Arc::new(1)),
+            key: Arc::new(1),
             counter: Counter::new(),
             dropped_keys,
         },
+        filter_key: "1".into(),
     };
 
     pin_mut!(channel);
@@ -492,6 +696,106 @@ fn channel_filter_poll_closed_channels() {
     assert!(filter.key_counts.is_empty());
 }
 
+#[test]
+fn channel_filter_keeps_key_reopened_before_close() {
+    use assert_matches::assert_matches;
+    use pin_utils::pin_mut;
+
+    #[derive(Debug)]
+    struct TestChannel {
+        key: &'static str,
+    }
+    let (new_channels, listener) = mpsc::unbounded();
+    let filter = ChannelFilter::new(listener, 1, |chan: &TestChannel| chan.key);
+    pin_mut!(filter);
+
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    let channel1 =
+        assert_matches!(filter.as_mut().poll_listener(&mut ctx()), Poll::Ready(Some(Ok(c))) => c);
+    drop(channel1);
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    let channel2 =
+        assert_matches!(filter.as_mut().poll_listener(&mut ctx()), Poll::Ready(Some(Ok(c))) => c);
+
+    // The close of the first channel is processed after the second was accepted, and must not
+    // forget the second.
+    assert_matches!(
+        filter.as_mut().poll_closed_channels(&mut ctx()),
+        Poll::Ready(())
+    );
+    assert_eq!(filter.key_counts.len(), 1);
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    assert_matches!(
+        filter.as_mut().poll_listener(&mut ctx()),
+        Poll::Ready(Some(Err("key")))
+    );
+    drop(channel2);
+}
+
+#[test]
+fn channel_filter_forgets_closed_keys_in_one_pass() {
+    use assert_matches::assert_matches;
+    use pin_utils::pin_mut;
+
+    #[derive(Debug)]
+    struct TestChannel {
+        key: &'static str,
+    }
+    let (new_channels, listener) = mpsc::unbounded();
+    let filter = ChannelFilter::new(listener, 1, |chan: &TestChannel| chan.key);
+    pin_mut!(filter);
+
+    for key in &["a", "b", "c"] {
+        new_channels.unbounded_send(TestChannel { key }).unwrap();
+    }
+    let channels: Vec<_> = (0..3)
+        .map(|_| assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Ready(Some(c)) => c))
+        .collect();
+    assert_eq!(filter.key_counts.len(), 3);
+
+    drop(channels);
+    assert_matches!(
+        filter.as_mut().poll_closed_channels(&mut ctx()),
+        Poll::Ready(())
+    );
+    assert!(filter.key_counts.is_empty());
+    assert_matches!(
+        filter.as_mut().poll_closed_channels(&mut ctx()),
+        Poll::Pending
+    );
+}
+
+#[test]
+fn channel_filter_frees_closed_keys_before_accepting() {
+    use assert_matches::assert_matches;
+    use pin_utils::pin_mut;
+
+    #[derive(Debug)]
+    struct TestChannel {
+        key: &'static str,
+    }
+    let (new_channels, listener) = mpsc::unbounded();
+    let filter = ChannelFilter::new(listener, 1, |chan: &TestChannel| chan.key);
+    pin_mut!(filter);
+
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    let channel = assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Ready(Some(c)) => c);
+    // The replacement connects before the filter learns that the first channel closed.
+    drop(channel);
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Ready(Some(_)));
+}
+
 #[test]
 fn channel_filter_stream() {
     use assert_matches::assert_matches;
@@ -515,3 +819,192 @@ fn channel_filter_stream() {
     assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Pending);
     assert!(filter.key_counts.is_empty());
 }
+
+#[test]
+fn channel_filter_connection_events() {
+    use crate::event::{connection_events, ConnectionEvent, NullSink};
+    use assert_matches::assert_matches;
+    use pin_utils::pin_mut;
+
+    #[derive(Debug)]
+    struct TestChannel {
+        key: &'static str,
+    }
+    let (sink, mut events) = connection_events(Arc::new(NullSink));
+    let (new_channels, listener) = mpsc::unbounded();
+    let filter =
+        ChannelFilter::new(listener, 1, |chan: &TestChannel| chan.key).with_events(Arc::new(sink));
+    pin_mut!(filter);
+
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    let channel = assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Ready(Some(c)) => c);
+    assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Pending);
+    drop(channel);
+    drop(new_channels);
+    assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Ready(None));
+
+    assert_eq!(
+        events.try_next().unwrap(),
+        Some(ConnectionEvent::Opened {
+            key: "key".into(),
+            channels: 1,
+            limit: 1
+        })
+    );
+    assert_eq!(
+        events.try_next().unwrap(),
+        Some(ConnectionEvent::Rejected {
+            key: "key".into(),
+            channels: 1,
+            limit: 1
+        })
+    );
+    assert_eq!(
+        events.try_next().unwrap(),
+        Some(ConnectionEvent::Closed {
+            key: "key".into(),
+            channels: 0
+        })
+    );
+    assert_eq!(
+        events.try_next().unwrap(),
+        Some(ConnectionEvent::KeyClosed { key: "key".into() })
+    );
+    assert_eq!(
+        events.try_next().unwrap(),
+        Some(ConnectionEvent::ListenerClosed)
+    );
+}
+
+#[test]
+fn channel_filter_keeps_reopened_keys_open() {
+    use crate::event::{connection_events, ConnectionEvent, NullSink};
+    use assert_matches::assert_matches;
+    use pin_utils::pin_mut;
+
+    #[derive(Debug)]
+    struct TestChannel {
+        key: &'static str,
+    }
+    let (sink, mut events) = connection_events(Arc::new(NullSink));
+    let (new_channels, listener) = mpsc::unbounded();
+    let filter =
+        ChannelFilter::new(listener, 1, |chan: &TestChannel| chan.key).with_events(Arc::new(sink));
+    pin_mut!(filter);
+
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    let channel1 =
+        assert_matches!(filter.as_mut().poll_listener(&mut ctx()), Poll::Ready(Some(Ok(c))) => c);
+    drop(channel1);
+    new_channels
+        .unbounded_send(TestChannel { key: "key" })
+        .unwrap();
+    let _channel2 =
+        assert_matches!(filter.as_mut().poll_listener(&mut ctx()), Poll::Ready(Some(Ok(c))) => c);
+
+    // The first channel's close is processed after the second was accepted, so the key never
+    // closed.
+    assert_matches!(
+        filter.as_mut().poll_closed_channels(&mut ctx()),
+        Poll::Ready(())
+    );
+    for _ in 0..2 {
+        assert_eq!(
+            events.try_next().unwrap(),
+            Some(ConnectionEvent::Opened {
+                key: "key".into(),
+                channels: 1,
+                limit: 1
+            })
+        );
+    }
+    assert_eq!(
+        events.try_next().unwrap(),
+        Some(ConnectionEvent::Closed {
+            key: "key".into(),
+            channels: 0
+        })
+    );
+    assert!(events.try_next().is_err());
+}
+
+#[test]
+fn channel_filter_key_stats() {
+    use assert_matches::assert_matches;
+    use pin_utils::pin_mut;
+
+    #[derive(Debug)]
+    struct TestChannel {
+        key: &'static str,
+    }
+    let key_stats = KeyStats::new(2).with_hasher(MapHasher::SipHash);
+    let (new_channels, listener) = mpsc::unbounded();
+    let filter = ChannelFilter::new(listener, 1, |chan: &TestChannel| chan.key)
+        .with_hasher(MapHasher::SipHash)
+        .with_key_stats(&key_stats);
+    pin_mut!(filter);
+
+    for key in &["a", "a", "a", "b"] {
+        new_channels.unbounded_send(TestChannel { key }).unwrap();
+    }
+    let a = assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Ready(Some(c)) => c);
+    let b = assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Ready(Some(c)) => c);
+    drop((a, b));
+    assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Pending);
+
+    let snapshot = key_stats.snapshot();
+    assert_eq!((snapshot["a"].accepted, snapshot["a"].rejected), (1, 2));
+    assert_eq!((snapshot["b"].accepted, snapshot["b"].rejected), (1, 0));
+
+    // Remembering a third key forgets the least recently active one.
+    new_channels
+        .unbounded_send(TestChannel { key: "c" })
+        .unwrap();
+    let _c = assert_matches!(filter.as_mut().poll_next(&mut ctx()), Poll::Ready(Some(c)) => c);
+    let snapshot = key_stats.snapshot();
+    assert_eq!(snapshot.len(), 2);
+    assert!(snapshot.contains_key("c"));
+}
+
+#[test]
+fn key_stats_forget_the_least_recently_active_key() {
+    let key_stats = KeyStats::new(2);
+    key_stats.record(&"a", |activity| activity.accepted += 1);
+    key_stats.record(&"b", |activity| activity.accepted += 1);
+    key_stats.record(&"a", |activity| activity.rejected += 1);
+    key_stats.record(&"c", |activity| activity.accepted += 1);
+
+    let snapshot = key_stats.snapshot();
+    assert_eq!(snapshot.keys().collect::<Vec<_>>(), ["a", "c"]);
+    assert_eq!((snapshot["a"].accepted, snapshot["a"].rejected), (1, 1));
+}
+
+#[cfg(loom)]
+#[test]
+fn loom_concurrently_dropped_trackers_close_their_key_once() {
+    loom::model(|| {
+        let (dropped_keys, mut closed) = mpsc::unbounded();
+        let tracker = Tracker {
+            key: Arc::new(1),
+            counter: Counter::new(),
+            dropped_keys,
+        };
+        let clone = tracker.clone();
+        let thread = loom::thread::spawn(move || drop(clone));
+        drop(tracker);
+        thread.join().unwrap();
+
+        // Both report their channel closing, but only one sees none left.
+        let (_, first) = closed.try_next().unwrap().unwrap();
+        let (_, second) = closed.try_next().unwrap().unwrap();
+        assert_eq!(first.min(second), 0);
+        assert_eq!(first.max(second), 1);
+    });
+}
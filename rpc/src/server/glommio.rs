@@ -0,0 +1,182 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serves on glommio's thread-per-core executors.
+//!
+//! [`run_sharded`] runs each [shard](super::shard) on its own glommio executor, pinned to its
+//! own core if configured to be. A shard [`listen`]s on its own socket: the sockets share the
+//! address with `SO_REUSEPORT`, so the kernel spreads connections over the shards, and a
+//! request is handled on the core that accepted its connection. Handlers run on the shard with
+//! [`respond_with_local`](super::Handler::respond_with_local), so services can keep their state
+//! in `Rc`s and `RefCell`s instead of locks:
+//!
+//! ```ignore
+//! let mut config = ShardConfig::default();
+//! config.pin_to_cores = true;
+//! let shards = config.shards;
+//! glommio::run_sharded(config, move |_shard| async move {
+//!     let mut server_config = server::Config::default();
+//!     server_config.timer = Arc::new(Glommio);
+//!     let connections = glommio::listen("0.0.0.0:5000").unwrap();
+//!     server::new(server_config)
+//!         .incoming(connections.filter_map(|conn| async { conn.ok() }).map(transport))
+//!         .max_channels_per_key(glommio::per_shard_limit(8, shards), |t| t.peer_ip())
+//!         .respond_with_local(Storage::default().serve(), Glommio)
+//!         .await
+//! })?
+//! .join()
+//! ```
+//!
+//! Each shard filters its own channels: a [`ChannelFilter`](super::ChannelFilter) counts only
+//! the channels of its shard, so the hot path shares nothing with other cores.
+//! [`per_shard_limit`] splits a server-wide limit per key over the shards.
+
+use super::shard::{self, ShardConfig, Shards};
+use crate::event::Event;
+use crate::runtime::{Delay, SpawnLocal, ThreadTimer, Timer};
+use ::glommio::{net::TcpListener, LocalExecutorBuilder, Task};
+use futures::prelude::*;
+use std::{io, net::ToSocketAddrs, pin::Pin, sync::Arc, thread, time::Instant};
+
+/// The executor of the current glommio shard, and a timer that works on it.
+///
+/// Spawns onto the executor of the thread that spawns. Delays are timed by a
+/// [`ThreadTimer`] rather than by glommio's own timers, which can't leave their shard; they're
+/// precise enough for deadlines and flushes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Glommio;
+
+impl SpawnLocal for Glommio {
+    fn spawn_local(&self, future: Pin<Box<dyn Future<Output = ()>>>) {
+        Task::local(future).detach();
+    }
+}
+
+impl Timer for Glommio {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn delay(&self, deadline: Instant) -> Delay {
+        ThreadTimer.delay(deadline)
+    }
+}
+
+/// Runs `config.shards` shards, each on its own thread and glommio executor. Shard `i` runs
+/// the future returned by `make_shard(i)`, which is created on the shard's thread, so it
+/// needn't be `Send`.
+pub fn run_sharded<F, Fut>(config: ShardConfig, make_shard: F) -> io::Result<Shards>
+where
+    F: Fn(usize) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    let make_shard = Arc::new(make_shard);
+    let cpus = if config.pin_to_cores {
+        shard::allowed_cpus(&*config.events)
+    } else {
+        vec![]
+    };
+    let threads = (0..config.shards)
+        .map(|shard| {
+            let make_shard = make_shard.clone();
+            let cpu = shard::shard_cpu(&cpus, shard);
+            let events = config.events.clone();
+            let name = format!("tarpc-shard-{}", shard);
+            thread::Builder::new().name(name.clone()).spawn(move || {
+                let executor = match cpu {
+                    Some(cpu) => match LocalExecutorBuilder::new()
+                        .name(&name)
+                        .pin_to_cpu(cpu)
+                        .make()
+                    {
+                        Ok(executor) => executor,
+                        Err(error) => {
+                            events.event(&Event::ShardNotPinned {
+                                shard,
+                                cpu: Some(cpu),
+                                error: &error,
+                            });
+                            LocalExecutorBuilder::new().name(&name).make()?
+                        }
+                    },
+                    None => LocalExecutorBuilder::new().name(&name).make()?,
+                };
+                executor.run(make_shard(shard));
+                Ok(())
+            })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(Shards { threads })
+}
+
+/// Listens on `addr` for the current shard, returning the connections it accepts.
+///
+/// Every shard that listens on the same address gets its own socket, bound with
+/// `SO_REUSEPORT`, and the kernel spreads incoming connections over them.
+pub fn listen(
+    addr: impl ToSocketAddrs,
+) -> io::Result<impl Stream<Item = io::Result<::glommio::net::TcpStream>>> {
+    let listener = TcpListener::bind(addr)?;
+    Ok(stream::unfold(listener, |listener| async move {
+        let connection = listener.accept().await;
+        Some((connection, listener))
+    }))
+}
+
+/// Splits `limit`, a server-wide limit of channels per key, over `shards` shard-local
+/// [filters](super::ChannelFilter), rounding up so that every shard admits at least one
+/// channel per key.
+///
+/// The kernel spreads a client's connections over the shards by address and port, so the
+/// limits hold on average rather than exactly.
+pub fn per_shard_limit(limit: u32, shards: usize) -> u32 {
+    let shards = shards.max(1) as u32;
+    (limit.saturating_add(shards - 1) / shards).max(1)
+}
+
+#[test]
+fn per_shard_limits_round_up() {
+    assert_eq!(per_shard_limit(8, 4), 2);
+    assert_eq!(per_shard_limit(9, 4), 3);
+    assert_eq!(per_shard_limit(1, 4), 1);
+    assert_eq!(per_shard_limit(0, 4), 1);
+    assert_eq!(per_shard_limit(8, 0), 8);
+}
+
+#[test]
+fn runs_each_shard_on_its_own_executor() {
+    use futures::channel::mpsc;
+
+    let (tx, rx) = mpsc::unbounded();
+    let mut config = ShardConfig::default();
+    config.shards = 2;
+    let shards = run_sharded(config, move |shard| {
+        let tx = tx.clone();
+        async move {
+            let (done_tx, done_rx) = futures::channel::oneshot::channel();
+            // Spawned onto the shard's executor, so the task can hold an Rc.
+            let shard = std::rc::Rc::new(shard);
+            Glommio.spawn_local(Box::pin(async move {
+                let name = thread::current().name().map(String::from);
+                tx.unbounded_send((*shard, name)).unwrap();
+                let _ = done_tx.send(());
+            }));
+            let _ = done_rx.await;
+        }
+    })
+    .unwrap();
+    shards.join().unwrap();
+
+    let mut ran: Vec<_> = futures::executor::block_on(rx.collect());
+    ran.sort();
+    assert_eq!(
+        ran,
+        [
+            (0, Some("tarpc-shard-0".into())),
+            (1, Some("tarpc-shard-1".into()))
+        ]
+    );
+}
@@ -0,0 +1,426 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Tracks the requests in flight over a channel.
+//!
+//! Each request is canceled through a slot holding an atomic flag and the waker of the request's
+//! handler, so neither the channel nor the handler ever waits on the other. Once a request is no
+//! longer in flight and its handler is dropped, the channel reuses its slot, so once the channel
+//! has allocated slots for its peak concurrency, starting a request allocates nothing.
+
+use crate::{
+    util::{
+        hash::HashMap,
+        sync::{Arc, AtomicUsize, Ordering},
+        Compact,
+    },
+    MapHasher,
+};
+use futures::{
+    future::{self, AbortHandle, AbortRegistration, Abortable},
+    prelude::*,
+    task::{AtomicWaker, Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{fmt, pin::Pin};
+
+/// Set once the channel cancels the request.
+const CANCELED: usize = 0b01;
+/// Set once the request's handler is dropped.
+const RELEASED: usize = 0b10;
+
+/// The requests in flight over a channel.
+#[derive(Debug)]
+pub(crate) struct InFlightRequests {
+    /// How each in-flight request is canceled, by request ID.
+    requests: HashMap<u64, Handle>,
+    /// The slots of requests no longer in flight, reused once their handlers are dropped.
+    spare: Vec<Arc<Slot>>,
+    /// The number of slots allocated.
+    slots: usize,
+}
+
+/// How the channel cancels a request.
+#[derive(Debug)]
+enum Handle {
+    Slot(Arc<Slot>),
+    /// The request was started with [`InFlightRequests::start_abortable`].
+    Abort(AbortHandle),
+}
+
+#[derive(Debug)]
+struct Slot {
+    state: AtomicUsize,
+    waker: AtomicWaker,
+}
+
+impl InFlightRequests {
+    pub(crate) fn new(hasher: MapHasher) -> Self {
+        InFlightRequests {
+            requests: HashMap::with_hasher(hasher.build()),
+            spare: vec![],
+            slots: 0,
+        }
+    }
+
+    /// Returns the number of requests in flight.
+    pub(crate) fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Returns the number of slots allocated, which grows with the most requests handled at
+    /// once.
+    pub(crate) fn capacity(&self) -> usize {
+        self.slots
+    }
+
+    /// Returns whether a request is in flight.
+    pub(crate) fn contains(&self, request_id: u64) -> bool {
+        self.requests.contains_key(&request_id)
+    }
+
+    /// Starts tracking a request, returning the slot through which it can be canceled, or `None`
+    /// if a request with the same ID is already in flight.
+    pub(crate) fn start(&mut self, request_id: u64) -> Option<InFlightSlot> {
+        if self.contains(request_id) {
+            return None;
+        }
+        let released = self
+            .spare
+            .iter()
+            .position(|slot| slot.state.load(Ordering::Acquire) & RELEASED != 0);
+        let slot = match released {
+            Some(i) => {
+                let slot = self.spare.swap_remove(i);
+                slot.state.store(0, Ordering::Relaxed);
+                slot.waker.take();
+                slot
+            }
+            None => {
+                self.slots += 1;
+                Arc::new(Slot {
+                    state: AtomicUsize::new(0),
+                    waker: AtomicWaker::new(),
+                })
+            }
+        };
+        self.requests.insert(request_id, Handle::Slot(slot.clone()));
+        Some(InFlightSlot {
+            kind: SlotKind::Tracked(slot),
+        })
+    }
+
+    /// Like [`start`](InFlightRequests::start), but returns a registration aborted when the
+    /// request is canceled, for channels implementing [`Channel::start_request`].
+    ///
+    /// [`Channel::start_request`]: super::Channel::start_request
+    pub(crate) fn start_abortable(&mut self, request_id: u64) -> Option<AbortRegistration> {
+        if self.contains(request_id) {
+            return None;
+        }
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        self.requests
+            .insert(request_id, Handle::Abort(abort_handle));
+        Some(abort_registration)
+    }
+
+    /// Stops tracking a request that was responded to. Returns whether it was in flight.
+    pub(crate) fn complete(&mut self, request_id: u64) -> bool {
+        match self.remove(request_id) {
+            Some(handle) => {
+                self.retire(handle);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops tracking a request and aborts its handler. Returns whether it was in flight.
+    pub(crate) fn cancel(&mut self, request_id: u64) -> bool {
+        match self.remove(request_id) {
+            Some(handle) => {
+                handle.cancel();
+                self.retire(handle);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, request_id: u64) -> Option<Handle> {
+        let handle = self.requests.remove(&request_id)?;
+        self.requests.compact(0.1);
+        Some(handle)
+    }
+
+    /// Keeps the slot of a request no longer in flight for reuse.
+    fn retire(&mut self, handle: Handle) {
+        if let Handle::Slot(slot) = handle {
+            self.spare.push(slot);
+        }
+    }
+}
+
+impl Handle {
+    fn cancel(&self) {
+        match *self {
+            Handle::Slot(ref slot) => {
+                slot.state.fetch_or(CANCELED, Ordering::AcqRel);
+                slot.waker.wake();
+            }
+            Handle::Abort(ref abort_handle) => abort_handle.abort(),
+        }
+    }
+}
+
+impl Default for InFlightRequests {
+    fn default() -> Self {
+        InFlightRequests::new(MapHasher::default())
+    }
+}
+
+impl Drop for InFlightRequests {
+    fn drop(&mut self) {
+        // Once the channel is gone, the responses of the requests in flight have nowhere to go,
+        // so their handlers are aborted, even if they run on another executor than the channel.
+        for (_, handle) in self.requests.drain() {
+            handle.cancel();
+        }
+    }
+}
+
+/// A request's slot among the requests in flight over its channel. The channel cancels the
+/// request through its slot, and reuses the slot once it's dropped.
+pub struct InFlightSlot {
+    kind: SlotKind,
+}
+
+enum SlotKind {
+    /// Never canceled.
+    Detached,
+    Tracked(Arc<Slot>),
+    /// Canceled when the registration is aborted.
+    Registered(Abortable<future::Pending<()>>),
+}
+
+impl InFlightSlot {
+    /// Returns a slot that's never canceled, for requests that aren't tracked by a channel.
+    pub fn detached() -> Self {
+        InFlightSlot {
+            kind: SlotKind::Detached,
+        }
+    }
+
+    /// Returns whether the request was canceled, or else arranges for `cx` to be woken when it is.
+    fn poll_canceled(&mut self, cx: &mut Context<'_>) -> bool {
+        match self.kind {
+            SlotKind::Detached => false,
+            SlotKind::Tracked(ref slot) => {
+                if slot.state.load(Ordering::Acquire) & CANCELED != 0 {
+                    return true;
+                }
+                slot.waker.register(cx.waker());
+                // Checked again in case the request was canceled before the waker was registered.
+                slot.state.load(Ordering::Acquire) & CANCELED != 0
+            }
+            SlotKind::Registered(ref mut aborted) => aborted.poll_unpin(cx).is_ready(),
+        }
+    }
+}
+
+impl From<AbortRegistration> for InFlightSlot {
+    /// Returns a slot canceled when `abort_registration`'s handle is aborted.
+    fn from(abort_registration: AbortRegistration) -> Self {
+        InFlightSlot {
+            kind: SlotKind::Registered(Abortable::new(future::pending(), abort_registration)),
+        }
+    }
+}
+
+impl Drop for InFlightSlot {
+    fn drop(&mut self) {
+        if let SlotKind::Tracked(ref slot) = self.kind {
+            slot.state.fetch_or(RELEASED, Ordering::Release);
+        }
+    }
+}
+
+impl fmt::Debug for InFlightSlot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            SlotKind::Detached => f.write_str("InFlightSlot::detached"),
+            SlotKind::Tracked(_) => f.write_str("InFlightSlot"),
+            SlotKind::Registered(_) => f.write_str("InFlightSlot::registered"),
+        }
+    }
+}
+
+/// A future that resolves to `None` if its request is canceled before it completes.
+#[derive(Debug)]
+pub(crate) struct Cancelable<F> {
+    f: F,
+    slot: InFlightSlot,
+}
+
+impl<F> Cancelable<F> {
+    unsafe_pinned!(f: F);
+    unsafe_unpinned!(slot: InFlightSlot);
+
+    pub(crate) fn new(f: F, slot: InFlightSlot) -> Self {
+        Cancelable { f, slot }
+    }
+
+    /// Returns the pinned future.
+    pub(crate) fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut F> {
+        self.f()
+    }
+}
+
+impl<F: Future> Future for Cancelable<F> {
+    type Output = Option<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<F::Output>> {
+        if self.as_mut().slot().poll_canceled(cx) {
+            return Poll::Ready(None);
+        }
+        self.f().poll(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use futures_test::task::noop_waker_ref;
+    use pin_utils::pin_mut;
+
+    #[test]
+    fn reuses_slots() {
+        let mut in_flight = InFlightRequests::default();
+        // Request 0 stays in flight, so its slot isn't reused even though its handler is gone.
+        drop(in_flight.start(0).unwrap());
+        for request_id in 1..100 {
+            let slot = in_flight.start(request_id).unwrap();
+            assert!(in_flight.complete(request_id));
+            drop(slot);
+        }
+        assert!(in_flight.complete(0));
+        assert_eq!(in_flight.len(), 0);
+        assert_eq!(in_flight.capacity(), 2);
+    }
+
+    #[test]
+    fn keeps_slots_of_live_handlers() {
+        let mut in_flight = InFlightRequests::default();
+        let handler = Cancelable::new(future::pending::<()>(), in_flight.start(1).unwrap());
+        pin_mut!(handler);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(in_flight.complete(1));
+
+        // Request 1's handler is still running, so request 2 can't take its slot.
+        let _slot = in_flight.start(2).unwrap();
+        assert_eq!(in_flight.capacity(), 2);
+        assert!(in_flight.cancel(2));
+        assert_eq!(handler.poll(&mut cx), Poll::Pending);
+    }
+
+    #[test]
+    fn cancel_aborts_registration() {
+        let mut in_flight = InFlightRequests::default();
+        let registration = in_flight.start_abortable(1).unwrap();
+        assert!(in_flight.start_abortable(1).is_none());
+        let handler = Cancelable::new(future::pending::<()>(), InFlightSlot::from(registration));
+        pin_mut!(handler);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert_eq!(handler.as_mut().poll(&mut cx), Poll::Pending);
+
+        assert!(in_flight.cancel(1));
+        assert_eq!(handler.poll(&mut cx), Poll::Ready(None));
+        assert_eq!(in_flight.capacity(), 0);
+    }
+
+    #[test]
+    fn start_rejects_duplicates() {
+        let mut in_flight = InFlightRequests::default();
+        let _slot = in_flight.start(1).unwrap();
+        let capacity = in_flight.capacity();
+        assert!(in_flight.start(1).is_none());
+        assert_eq!(in_flight.len(), 1);
+        assert_eq!(in_flight.capacity(), capacity);
+    }
+
+    #[test]
+    fn cancel_aborts_handler() {
+        let mut in_flight = InFlightRequests::default();
+        let handler = Cancelable::new(future::pending::<()>(), in_flight.start(1).unwrap());
+        pin_mut!(handler);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert_eq!(handler.as_mut().poll(&mut cx), Poll::Pending);
+
+        assert!(in_flight.cancel(1));
+        assert!(!in_flight.cancel(1));
+        assert_eq!(handler.poll(&mut cx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn drop_aborts_handlers() {
+        let mut in_flight = InFlightRequests::default();
+        let handler = Cancelable::new(future::pending::<()>(), in_flight.start(1).unwrap());
+        pin_mut!(handler);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert_eq!(handler.as_mut().poll(&mut cx), Poll::Pending);
+
+        drop(in_flight);
+        assert_eq!(handler.poll(&mut cx), Poll::Ready(None));
+    }
+
+    #[test]
+    fn cancel_ignores_reused_slot() {
+        let mut in_flight = InFlightRequests::default();
+        drop(in_flight.start(1).unwrap());
+        let handler = Cancelable::new(future::pending::<()>(), in_flight.start(2).unwrap());
+        pin_mut!(handler);
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        // Request 1's handler is gone, and canceling it must leave request 2's handler alone.
+        assert!(in_flight.cancel(1));
+        assert_eq!(handler.poll(&mut cx), Poll::Pending);
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn loom_cancel_aborts_or_wakes_handler() {
+        use crate::util::sync::{AtomicUsize, Ordering};
+        use futures::task::{waker, ArcWake};
+
+        struct Woken(AtomicUsize);
+
+        impl ArcWake for Woken {
+            fn wake_by_ref(woken: &std::sync::Arc<Self>) {
+                woken.0.store(1, Ordering::SeqCst);
+            }
+        }
+
+        loom::model(|| {
+            let mut in_flight = InFlightRequests::default();
+            let slot = in_flight.start(1).unwrap();
+            let woken = std::sync::Arc::new(Woken(AtomicUsize::new(0)));
+            let thread = {
+                let waker = waker(woken.clone());
+                loom::thread::spawn(move || {
+                    let mut handler = Box::pin(Cancelable::new(future::pending::<()>(), slot));
+                    let aborted = handler.as_mut().poll(&mut Context::from_waker(&waker))
+                        == Poll::Ready(None);
+                    // Kept alive until the cancellation is done, as a running handler would be.
+                    (aborted, handler)
+                })
+            };
+            assert!(in_flight.cancel(1));
+            let (aborted, _handler) = thread.join().unwrap();
+            assert!(aborted || woken.0.load(Ordering::SeqCst) == 1);
+        });
+    }
+}
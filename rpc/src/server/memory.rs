@@ -0,0 +1,147 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Counts the bytes buffered by each channel's requests and responses, so that a channel can stop
+//! reading requests once it's holding too much.
+
+use crate::Response;
+use futures::task::{AtomicWaker, Context, Poll};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// The bytes buffered by a channel's requests and responses.
+#[derive(Debug, Default)]
+pub(crate) struct BufferedBytes {
+    bytes: AtomicUsize,
+    /// Woken when bytes are released.
+    waker: AtomicWaker,
+}
+
+impl BufferedBytes {
+    /// Returns the number of bytes buffered.
+    pub(crate) fn get(&self) -> usize {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Returns ready if fewer than `limit` bytes are buffered, or else arranges for `cx` to be
+    /// woken when bytes are released.
+    pub(crate) fn poll_below(&self, limit: usize, cx: &mut Context<'_>) -> Poll<()> {
+        if self.get() < limit {
+            return Poll::Ready(());
+        }
+        self.waker.register(cx.waker());
+        // Bytes may have been released before the waker was registered.
+        if self.get() < limit {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Counts `bytes` as buffered until the returned charge is dropped.
+    pub(crate) fn charge(self: &Arc<Self>, bytes: usize) -> Charge {
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+        Charge {
+            bytes,
+            buffered: Some(self.clone()),
+        }
+    }
+}
+
+/// Bytes counted as buffered by a channel until dropped.
+#[derive(Debug)]
+pub(crate) struct Charge {
+    bytes: usize,
+    buffered: Option<Arc<BufferedBytes>>,
+}
+
+impl Charge {
+    /// Returns a charge that counts nothing, for channels that don't limit buffered bytes.
+    pub(crate) fn none() -> Self {
+        Charge {
+            bytes: 0,
+            buffered: None,
+        }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        if let Some(ref buffered) = self.buffered {
+            buffered.bytes.fetch_sub(self.bytes, Ordering::SeqCst);
+            buffered.waker.wake();
+        }
+    }
+}
+
+/// Sizes the responses to a channel's requests, charging them to the channel until they're
+/// written.
+pub(crate) struct ResponseMeter<R> {
+    size: fn(&R) -> usize,
+    buffered: Arc<BufferedBytes>,
+}
+
+impl<R> ResponseMeter<R> {
+    pub(crate) fn new(size: fn(&R) -> usize, buffered: Arc<BufferedBytes>) -> Self {
+        ResponseMeter { size, buffered }
+    }
+
+    /// Charges `response` to its channel. Errors are small, so they're left uncounted.
+    pub(crate) fn charge(&self, response: &Response<R>) -> Charge {
+        match response.message {
+            Ok(ref message) => self.buffered.charge((self.size)(message)),
+            Err(_) => Charge::none(),
+        }
+    }
+}
+
+impl<R> Clone for ResponseMeter<R> {
+    fn clone(&self) -> Self {
+        ResponseMeter {
+            size: self.size,
+            buffered: self.buffered.clone(),
+        }
+    }
+}
+
+impl<R> fmt::Debug for ResponseMeter<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseMeter")
+            .field("buffered", &self.buffered.get())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_test::task::noop_waker_ref;
+
+    #[test]
+    fn charges_are_released_on_drop() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let buffered = Arc::new(BufferedBytes::default());
+        let request = buffered.charge(10);
+        let response = ResponseMeter::new(String::len, buffered.clone()).charge(&Response {
+            request_id: 0,
+            message: Ok("hello".to_string()),
+            partial: false,
+            _non_exhaustive: (),
+        });
+        assert_eq!(buffered.get(), 15);
+        assert_eq!(buffered.poll_below(15, &mut cx), Poll::Pending);
+
+        drop(request);
+        assert_eq!(buffered.poll_below(15, &mut cx), Poll::Ready(()));
+        drop(response);
+        assert_eq!(buffered.get(), 0);
+    }
+}
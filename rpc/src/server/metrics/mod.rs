@@ -0,0 +1,281 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Records per-method request latencies and error counts in the server dispatch path.
+//!
+//! A server records metrics when its [`Config::metrics`](super::Config::metrics) is set to a
+//! [`MetricsRecorder`]. [`InMemoryRecorder`] aggregates metrics in memory for export; with the
+//! `prometheus` feature, they can be rendered in the Prometheus text format, and with the `statsd`
+//! feature, [`statsd::StatsdRecorder`] pushes them to a StatsD daemon as they are recorded.
+
+use std::{collections::BTreeMap, fmt, sync::Mutex, time::Duration};
+
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+
+/// Receives the metrics of each request handled by a server.
+pub trait MetricsRecorder: fmt::Debug + Send + Sync {
+    /// Records the time taken to handle a request to `method`, from the request being read off
+    /// the wire to the response being ready to send.
+    fn record_latency(&self, method: &str, latency: Duration);
+
+    /// Records that a request to `method` failed with an error of class `class`.
+    fn record_error(&self, method: &str, class: ErrorClass);
+
+    /// Records that a request to `method` expired or was canceled, and at what point. Does
+    /// nothing by default.
+    fn record_interruption(&self, _method: &str, _interruption: Interruption) {}
+
+    /// Records the current value of a server-wide gauge, tracked by [`Stats`](super::Stats).
+    /// `key` is the [filter key](super::Channel::filter_key) for per-key gauges, and `None` for
+    /// totals. Does nothing by default.
+    fn record_gauge(&self, _gauge: Gauge, _key: Option<&str>, _value: u64) {}
+
+    /// Records how far a client's clock is ahead of the server's, in seconds, as observed from a
+    /// request's deadline. Negative if the client's clock is behind. Does nothing by default.
+    fn record_clock_skew(&self, _skew: f64) {}
+}
+
+/// The class of error with which a request failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorClass {
+    /// The request's deadline elapsed before the response was ready.
+    DeadlineExceeded,
+    /// The request was canceled by the client or dropped by the server before it completed.
+    Canceled,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl ErrorClass {
+    /// Returns the snake-case name of the error class, as used in exported metrics.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorClass::DeadlineExceeded => "deadline_exceeded",
+            ErrorClass::Canceled => "canceled",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How and when a request's deadline or cancellation cut it short.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Interruption {
+    /// The request's deadline had already elapsed when it arrived.
+    ExpiredBeforeStart,
+    /// The request's deadline elapsed while it was being handled.
+    ExpiredDuringExecution,
+    /// The client canceled the request while it was being handled.
+    CanceledDuringExecution,
+    /// The request was handled, but the client canceled it before the response was sent, so the
+    /// response was discarded.
+    CompletedAfterCancellation,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl Interruption {
+    /// Returns the snake-case name of the interruption, as used in exported metrics.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Interruption::ExpiredBeforeStart => "expired_before_start",
+            Interruption::ExpiredDuringExecution => "expired_during_execution",
+            Interruption::CanceledDuringExecution => "canceled_during_execution",
+            Interruption::CompletedAfterCancellation => "completed_after_cancellation",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Interruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A live measurement of a server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Gauge {
+    /// The number of open channels, in total and per filter key.
+    OpenChannels,
+    /// The number of requests being handled.
+    InFlightRequests,
+    /// The number of responses written to channels but not yet flushed.
+    PendingResponses,
+    /// The number of slots allocated for tracking in-flight requests, across channels.
+    InFlightSlots,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl Gauge {
+    /// Returns the snake-case name of the gauge, as used in exported metrics.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Gauge::OpenChannels => "open_channels",
+            Gauge::InFlightRequests => "in_flight_requests",
+            Gauge::PendingResponses => "pending_responses",
+            Gauge::InFlightSlots => "in_flight_slots",
+            _ => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Gauge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The upper bounds, in seconds, of the buckets of a latency [`Histogram`].
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.,
+];
+
+/// A distribution of request latencies.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    /// The number of latencies no greater than the corresponding bound in [`LATENCY_BUCKETS`].
+    /// Counts are not cumulative.
+    pub buckets: Vec<u64>,
+    /// The total number of latencies recorded, including those exceeding every bucket.
+    pub count: u64,
+    /// The sum of the latencies recorded.
+    pub sum: Duration,
+}
+
+impl Histogram {
+    /// Adds `latency` to the distribution.
+    pub fn record(&mut self, latency: Duration) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; LATENCY_BUCKETS.len()];
+        }
+        let secs = latency.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += latency;
+    }
+}
+
+/// The metrics of a single method.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MethodMetrics {
+    /// The latencies of requests to the method.
+    pub latency: Histogram,
+    /// The number of failed requests to the method, by class of error.
+    pub errors: BTreeMap<ErrorClass, u64>,
+    /// The number of expired and canceled requests to the method, by when they were cut short.
+    pub interruptions: BTreeMap<Interruption, u64>,
+}
+
+/// Aggregates metrics in memory, keyed by method name.
+#[derive(Debug, Default)]
+pub struct InMemoryRecorder {
+    methods: Mutex<BTreeMap<String, MethodMetrics>>,
+    gauges: Mutex<BTreeMap<(Gauge, Option<String>), u64>>,
+    clock_skew: Mutex<Option<f64>>,
+}
+
+impl InMemoryRecorder {
+    /// Returns a new recorder with no metrics.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the metrics recorded so far, keyed by method name.
+    pub fn snapshot(&self) -> BTreeMap<String, MethodMetrics> {
+        self.methods.lock().unwrap().clone()
+    }
+
+    /// Returns the most recently observed clock skew, in seconds.
+    pub fn clock_skew(&self) -> Option<f64> {
+        *self.clock_skew.lock().unwrap()
+    }
+
+    /// Returns the last recorded value of each gauge, keyed by gauge and filter key.
+    pub fn gauges(&self) -> BTreeMap<(Gauge, Option<String>), u64> {
+        self.gauges.lock().unwrap().clone()
+    }
+
+    fn update(&self, method: &str, f: impl FnOnce(&mut MethodMetrics)) {
+        let mut methods = self.methods.lock().unwrap();
+        match methods.get_mut(method) {
+            Some(metrics) => f(metrics),
+            None => f(methods.entry(method.to_string()).or_default()),
+        }
+    }
+}
+
+impl MetricsRecorder for InMemoryRecorder {
+    fn record_latency(&self, method: &str, latency: Duration) {
+        self.update(method, |metrics| metrics.latency.record(latency));
+    }
+
+    fn record_error(&self, method: &str, class: ErrorClass) {
+        self.update(method, |metrics| {
+            *metrics.errors.entry(class).or_insert(0) += 1
+        });
+    }
+
+    fn record_interruption(&self, method: &str, interruption: Interruption) {
+        self.update(method, |metrics| {
+            *metrics.interruptions.entry(interruption).or_insert(0) += 1
+        });
+    }
+
+    fn record_gauge(&self, gauge: Gauge, key: Option<&str>, value: u64) {
+        self.gauges
+            .lock()
+            .unwrap()
+            .insert((gauge, key.map(String::from)), value);
+    }
+
+    fn record_clock_skew(&self, skew: f64) {
+        *self.clock_skew.lock().unwrap() = Some(skew);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn in_memory() {
+        let recorder = InMemoryRecorder::new();
+        recorder.record_latency("get", Duration::from_millis(3));
+        recorder.record_latency("get", Duration::from_secs(60));
+        recorder.record_error("get", ErrorClass::DeadlineExceeded);
+        recorder.record_error("put", ErrorClass::Canceled);
+        recorder.record_error("put", ErrorClass::Canceled);
+
+        let snapshot = recorder.snapshot();
+        let get = &snapshot["get"];
+        assert_eq!(get.latency.count, 2);
+        assert_eq!(get.latency.buckets[1], 1);
+        assert_eq!(get.latency.buckets.iter().sum::<u64>(), 1);
+        assert_eq!(get.latency.sum, Duration::from_millis(60_003));
+        assert_eq!(get.errors[&ErrorClass::DeadlineExceeded], 1);
+        assert_eq!(snapshot["put"].errors[&ErrorClass::Canceled], 2);
+        assert_eq!(snapshot["put"].latency.count, 0);
+
+        recorder.record_gauge(Gauge::OpenChannels, None, 2);
+        recorder.record_gauge(Gauge::OpenChannels, Some("a"), 1);
+        recorder.record_gauge(Gauge::OpenChannels, None, 1);
+        let gauges = recorder.gauges();
+        assert_eq!(gauges[&(Gauge::OpenChannels, None)], 1);
+        assert_eq!(gauges[&(Gauge::OpenChannels, Some("a".into()))], 1);
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Renders metrics in the [Prometheus text
+//! format](https://prometheus.io/docs/instrumenting/exposition_formats/).
+
+use super::{Gauge, InMemoryRecorder, MethodMetrics, LATENCY_BUCKETS};
+use std::{collections::BTreeMap, fmt::Write};
+
+impl InMemoryRecorder {
+    /// Renders the metrics recorded so far in the Prometheus text format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = encode(&self.snapshot());
+        out.push_str(&encode_gauges(&self.gauges()));
+        if let Some(skew) = self.clock_skew() {
+            writeln!(
+                out,
+                "# HELP tarpc_clock_skew_seconds How far the last client's clock was ahead.\n\
+                 # TYPE tarpc_clock_skew_seconds gauge\n\
+                 tarpc_clock_skew_seconds {}",
+                skew
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// Renders per-method metrics in the Prometheus text format, as a histogram named
+/// `tarpc_request_duration_seconds` and counters named `tarpc_request_errors_total` and
+/// `tarpc_request_interruptions_total`, all labeled by method.
+pub fn encode(methods: &BTreeMap<String, MethodMetrics>) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "# HELP tarpc_request_duration_seconds Time taken to handle requests.\n\
+         # TYPE tarpc_request_duration_seconds histogram\n",
+    );
+    for (method, metrics) in methods {
+        let method = escape(method);
+        let histogram = &metrics.latency;
+        let mut cumulative = 0;
+        for (i, bound) in LATENCY_BUCKETS.iter().enumerate() {
+            cumulative += histogram.buckets.get(i).cloned().unwrap_or(0);
+            writeln!(
+                out,
+                "tarpc_request_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                method, bound, cumulative
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "tarpc_request_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}\n\
+             tarpc_request_duration_seconds_sum{{method=\"{0}\"}} {}\n\
+             tarpc_request_duration_seconds_count{{method=\"{0}\"}} {1}",
+            method,
+            histogram.count,
+            histogram.sum.as_secs_f64()
+        )
+        .unwrap();
+    }
+    out.push_str(
+        "# HELP tarpc_request_errors_total Requests that failed, by class of error.\n\
+         # TYPE tarpc_request_errors_total counter\n",
+    );
+    for (method, metrics) in methods {
+        let method = escape(method);
+        for (class, count) in &metrics.errors {
+            writeln!(
+                out,
+                "tarpc_request_errors_total{{method=\"{}\",class=\"{}\"}} {}",
+                method, class, count
+            )
+            .unwrap();
+        }
+    }
+    out.push_str(
+        "# HELP tarpc_request_interruptions_total Requests that expired or were canceled, by \
+         when.\n\
+         # TYPE tarpc_request_interruptions_total counter\n",
+    );
+    for (method, metrics) in methods {
+        let method = escape(method);
+        for (interruption, count) in &metrics.interruptions {
+            writeln!(
+                out,
+                "tarpc_request_interruptions_total{{method=\"{}\",kind=\"{}\"}} {}",
+                method, interruption, count
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Renders gauges in the Prometheus text format, as gauges named `tarpc_<gauge>`. Per-key values
+/// are labeled by key.
+pub fn encode_gauges(gauges: &BTreeMap<(Gauge, Option<String>), u64>) -> String {
+    let mut out = String::new();
+    let mut last = None;
+    for ((gauge, key), value) in gauges {
+        if last != Some(gauge) {
+            writeln!(out, "# TYPE tarpc_{} gauge", gauge).unwrap();
+            last = Some(gauge);
+        }
+        match key {
+            Some(key) => writeln!(out, "tarpc_{}{{key=\"{}\"}} {}", gauge, escape(key), value),
+            None => writeln!(out, "tarpc_{} {}", gauge, value),
+        }
+        .unwrap();
+    }
+    out
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ErrorClass, Interruption, MetricsRecorder};
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn encode_histogram_and_errors() {
+        let recorder = InMemoryRecorder::new();
+        recorder.record_latency("get", Duration::from_millis(20));
+        recorder.record_latency("get", Duration::from_millis(200));
+        recorder.record_error("get", ErrorClass::DeadlineExceeded);
+
+        let text = recorder.to_prometheus();
+        let lines: Vec<_> = text.lines().collect();
+        assert!(
+            lines.contains(&"tarpc_request_duration_seconds_bucket{method=\"get\",le=\"0.01\"} 0")
+        );
+        assert!(
+            lines.contains(&"tarpc_request_duration_seconds_bucket{method=\"get\",le=\"0.025\"} 1")
+        );
+        assert!(
+            lines.contains(&"tarpc_request_duration_seconds_bucket{method=\"get\",le=\"0.25\"} 2")
+        );
+        assert!(
+            lines.contains(&"tarpc_request_duration_seconds_bucket{method=\"get\",le=\"+Inf\"} 2")
+        );
+        assert!(lines.contains(&"tarpc_request_duration_seconds_count{method=\"get\"} 2"));
+        assert!(lines.contains(&"tarpc_request_duration_seconds_sum{method=\"get\"} 0.22"));
+        assert!(lines
+            .contains(&"tarpc_request_errors_total{method=\"get\",class=\"deadline_exceeded\"} 1"));
+
+        recorder.record_interruption("get", Interruption::ExpiredBeforeStart);
+        let text = recorder.to_prometheus();
+        assert!(text.lines().any(|line| {
+            line
+            == "tarpc_request_interruptions_total{method=\"get\",kind=\"expired_before_start\"} 1"
+        }));
+
+        recorder.record_gauge(Gauge::OpenChannels, None, 2);
+        recorder.record_gauge(Gauge::OpenChannels, Some("10.0.0.1"), 2);
+        let text = recorder.to_prometheus();
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE tarpc_open_channels gauge"));
+        assert!(lines.contains(&"tarpc_open_channels 2"));
+        assert!(lines.contains(&"tarpc_open_channels{key=\"10.0.0.1\"} 2"));
+    }
+}
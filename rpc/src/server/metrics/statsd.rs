@@ -0,0 +1,135 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Pushes metrics to a [StatsD](https://github.com/statsd/statsd) daemon over UDP.
+
+use super::{ErrorClass, Gauge, Interruption, MetricsRecorder};
+use crate::event::{Event, EventSink, LogSink};
+use std::{
+    io,
+    net::{ToSocketAddrs, UdpSocket},
+    sync::Arc,
+    time::Duration,
+};
+
+/// A recorder sending each metric to a StatsD daemon as it is recorded.
+///
+/// Latencies are sent as timers named `<prefix>.<method>.latency`, errors as counters named
+/// `<prefix>.<method>.errors.<class>`, and gauges as gauges named `<prefix>.<gauge>` or, per key,
+/// `<prefix>.<gauge>.<key>`. The magnitude of clock skew is sent as a gauge named
+/// `<prefix>.clock_skew`, in milliseconds. Metrics that fail to send are dropped and reported
+/// as [`MetricDropped`](Event::MetricDropped) events.
+#[derive(Debug)]
+pub struct StatsdRecorder {
+    socket: UdpSocket,
+    prefix: String,
+    events: Arc<dyn EventSink>,
+}
+
+impl StatsdRecorder {
+    /// Returns a recorder sending metrics named with `prefix` to the daemon at `addr`.
+    pub fn new(prefix: impl Into<String>, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdRecorder {
+            socket,
+            prefix: prefix.into(),
+            events: Arc::new(LogSink),
+        })
+    }
+
+    /// Reports metrics that fail to send to `events` rather than logging them.
+    pub fn with_events(mut self, events: Arc<dyn EventSink>) -> Self {
+        self.events = events;
+        self
+    }
+
+    fn send(&self, metric: String) {
+        if let Err(error) = self.socket.send(metric.as_bytes()) {
+            self.events.event(&Event::MetricDropped {
+                metric: &metric,
+                error: &error,
+            });
+        }
+    }
+}
+
+impl MetricsRecorder for StatsdRecorder {
+    fn record_latency(&self, method: &str, latency: Duration) {
+        self.send(format!(
+            "{}.{}.latency:{}|ms",
+            self.prefix,
+            sanitize(method),
+            latency.as_secs_f64() * 1000.
+        ));
+    }
+
+    fn record_error(&self, method: &str, class: ErrorClass) {
+        self.send(format!(
+            "{}.{}.errors.{}:1|c",
+            self.prefix,
+            sanitize(method),
+            class
+        ));
+    }
+
+    fn record_interruption(&self, method: &str, interruption: Interruption) {
+        self.send(format!(
+            "{}.{}.interruptions.{}:1|c",
+            self.prefix,
+            sanitize(method),
+            interruption
+        ));
+    }
+
+    fn record_gauge(&self, gauge: Gauge, key: Option<&str>, value: u64) {
+        self.send(match key {
+            Some(key) => format!("{}.{}.{}:{}|g", self.prefix, gauge, sanitize(key), value),
+            None => format!("{}.{}:{}|g", self.prefix, gauge, value),
+        });
+    }
+
+    fn record_clock_skew(&self, skew: f64) {
+        // StatsD reads signed gauge values as deltas, so only the magnitude is sent.
+        self.send(format!(
+            "{}.clock_skew:{}|g",
+            self.prefix,
+            (skew.abs() * 1000.).round()
+        ));
+    }
+}
+
+/// Replaces characters that delimit the StatsD line protocol.
+fn sanitize(name: &str) -> String {
+    name.replace(|c| c == ':' || c == '|' || c == '@' || c == '\n', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_timers_and_counters() -> io::Result<()> {
+        let daemon = UdpSocket::bind("127.0.0.1:0")?;
+        daemon.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let recorder = StatsdRecorder::new("tarpc", daemon.local_addr()?)?;
+
+        let mut buf = [0; 128];
+        recorder.record_latency("get", Duration::from_millis(12));
+        let n = daemon.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"tarpc.get.latency:12|ms");
+
+        recorder.record_error("a:b", ErrorClass::Canceled);
+        let n = daemon.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"tarpc.a_b.errors.canceled:1|c");
+
+        recorder.record_gauge(Gauge::InFlightRequests, None, 3);
+        let n = daemon.recv(&mut buf)?;
+        assert_eq!(&buf[..n], b"tarpc.in_flight_requests:3|g");
+        Ok(())
+    }
+}
@@ -0,0 +1,625 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Provides a mock server that answers requests by rules, so that client code can be tested
+//! without implementing the service it calls.
+//!
+//! A [`MockServer`] answers each request by the first rule, in the order they were added, that's
+//! for the request's RPC, matches the request, and hasn't answered the number of times it allows.
+//! A rule can respond with canned responses, fail the request with an error, never respond, or
+//! close the connection, each after an optional delay. Requests that no rule answers fail with
+//! [`io::ErrorKind::Other`], and requests that a rule answers with a response to a different RPC
+//! fail with [`io::ErrorKind::InvalidData`]. Requests that the client cancels before they're
+//! answered are never answered.
+//!
+//! ```ignore
+//! let server = MockServer::new();
+//! server
+//!     .on("hello")
+//!     .times(1)
+//!     .delay(Duration::from_millis(100))
+//!     .fail(io::ErrorKind::ConnectionRefused, "overloaded");
+//! server.on("hello").respond(WorldResponse::Hello("Hello, Tim!".into()));
+//! let mut client = WorldClient::new(client::Config::default(), server.spawn()).spawn()?;
+//! ```
+
+use crate::{
+    context,
+    runtime::Timer,
+    schema::{Introspect, IntrospectResponse},
+    server::Config,
+    ClientMessage, Request, Response, ServerError, Transport,
+};
+use futures::{
+    future::{self, AbortHandle, Abortable, Aborted},
+    prelude::*,
+    stream::FuturesUnordered,
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// Answers requests by rules, added with [`on`](MockServer::on).
+///
+/// Clones of a mock server share its rules, so rules can be added, and calls counted, while it
+/// serves.
+pub struct MockServer<Req, Resp> {
+    state: Arc<Mutex<State<Req, Resp>>>,
+    config: Config,
+}
+
+struct State<Req, Resp> {
+    rules: Vec<Rule<Req, Resp>>,
+    /// The number of requests received for each RPC.
+    calls: HashMap<&'static str, usize>,
+    /// The number of requests canceled before they were answered, for each RPC.
+    cancellations: HashMap<&'static str, usize>,
+}
+
+struct Rule<Req, Resp> {
+    rpc: &'static str,
+    matcher: Option<Box<dyn Fn(&Req) -> bool + Send>>,
+    times: Option<usize>,
+    calls: usize,
+    delay: Duration,
+    action: Action<Req, Resp>,
+}
+
+enum Action<Req, Resp> {
+    /// Responds with the returned responses, all but the last of which are partial.
+    Respond(Box<dyn FnMut(context::Context, Req) -> Vec<Resp> + Send>),
+    Fail(io::ErrorKind, String),
+    Ignore,
+    Disconnect,
+}
+
+impl<Req, Resp> MockServer<Req, Resp> {
+    /// Returns a mock server without rules.
+    pub fn new() -> Self {
+        MockServer {
+            state: Arc::new(Mutex::new(State {
+                rules: vec![],
+                calls: HashMap::new(),
+                cancellations: HashMap::new(),
+            })),
+            config: Config::default(),
+        }
+    }
+
+    /// Configures the server, whose [`spawn`](Config::spawn) spawns it and whose
+    /// [`timer`](Config::timer) times the delays of its answers. Defaults to
+    /// [`Config::default`].
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Starts a rule for requests to `rpc`, named as in the service definition. The rule is added
+    /// once it's given an outcome, e.g. by [`respond`](MockRpc::respond).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the service has no RPC named `rpc`.
+    pub fn on(&self, rpc: &'static str) -> MockRpc<'_, Req, Resp>
+    where
+        Req: Introspect,
+    {
+        if !Req::METHODS.iter().any(|method| method.name == rpc) {
+            panic!("the service has no RPC named `{}`", rpc);
+        }
+        MockRpc {
+            server: self,
+            rpc,
+            matcher: None,
+            times: None,
+            delay: Duration::from_secs(0),
+        }
+    }
+
+    /// Returns the number of requests to `rpc` received, whether or not a rule answered them.
+    pub fn calls(&self, rpc: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.calls.get(rpc).cloned().unwrap_or(0)
+    }
+
+    /// Returns the number of requests to `rpc` that clients canceled before they were answered.
+    pub fn cancellations(&self, rpc: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.cancellations.get(rpc).cloned().unwrap_or(0)
+    }
+
+    /// Returns a future that answers the requests received over `transport`, until the transport
+    /// closes or a rule closes it.
+    pub fn serve<T>(&self, transport: T) -> MockServe<T, Req, Resp>
+    where
+        T: Transport<Response<Resp>, ClientMessage<Req>>,
+        Req: Introspect,
+        Resp: IntrospectResponse + Send + 'static,
+    {
+        MockServe {
+            serve: Serve::new(transport, self.clone(), self.config.timer.clone()),
+        }
+    }
+
+    /// Spawns the server with the config's [`spawn`](Config::spawn), returning the client end of
+    /// an in-process [channel](crate::transport::channel) to it.
+    pub fn spawn(
+        &self,
+    ) -> crate::transport::channel::UnboundedChannel<Response<Resp>, ClientMessage<Req>>
+    where
+        Req: Introspect + Send + 'static,
+        Resp: IntrospectResponse + Send + 'static,
+    {
+        let (client, server) = crate::transport::channel::unbounded();
+        self.config.spawn.spawn(Box::pin(self.serve(server)));
+        client
+    }
+}
+
+impl<Req, Resp> Clone for MockServer<Req, Resp> {
+    fn clone(&self) -> Self {
+        MockServer {
+            state: self.state.clone(),
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> Default for MockServer<Req, Resp> {
+    fn default() -> Self {
+        MockServer::new()
+    }
+}
+
+impl<Req, Resp> fmt::Debug for MockServer<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("MockServer")
+            .field("rules", &state.rules.len())
+            .field("calls", &state.calls)
+            .finish()
+    }
+}
+
+impl<Req: Introspect, Resp: IntrospectResponse> State<Req, Resp> {
+    /// Answers a request by the first rule that matches it.
+    fn answer(&mut self, request: Request<Req>) -> Option<(Duration, Answer<Resp>)> {
+        let rpc = request.message.method_name();
+        *self.calls.entry(rpc).or_insert(0) += 1;
+        let request_id = request.id;
+        let rule = self.rules.iter_mut().find(|rule| {
+            rule.rpc == rpc
+                && rule.times.map_or(true, |times| rule.calls < times)
+                && rule
+                    .matcher
+                    .as_ref()
+                    .map_or(true, |matcher| matcher(&request.message))
+        });
+        let rule = match rule {
+            Some(rule) => rule,
+            None => {
+                let detail = format!("no mock response for `{}`", rpc);
+                let answer = Answer::fail(request_id, io::ErrorKind::Other, detail);
+                return Some((Duration::from_secs(0), answer));
+            }
+        };
+        rule.calls += 1;
+        let answer = match rule.action {
+            Action::Respond(ref mut respond) => {
+                Answer::respond(rpc, request_id, respond(request.context, request.message))
+            }
+            Action::Fail(kind, ref detail) => Answer::fail(request_id, kind, detail.clone()),
+            Action::Ignore => return None,
+            Action::Disconnect => Answer::Disconnect,
+        };
+        Some((rule.delay, answer))
+    }
+}
+
+/// A rule of a [`MockServer`], being built. Finish it by giving it an outcome, like
+/// [`respond`](MockRpc::respond) or [`fail`](MockRpc::fail).
+#[must_use = "rules are only added once they're given an outcome"]
+pub struct MockRpc<'a, Req, Resp> {
+    server: &'a MockServer<Req, Resp>,
+    rpc: &'static str,
+    matcher: Option<Box<dyn Fn(&Req) -> bool + Send>>,
+    times: Option<usize>,
+    delay: Duration,
+}
+
+impl<Req, Resp> MockRpc<'_, Req, Resp> {
+    /// Only answers requests that satisfy `matcher`.
+    pub fn with<F>(mut self, matcher: F) -> Self
+    where
+        F: Fn(&Req) -> bool + Send + 'static,
+    {
+        self.matcher = Some(Box::new(matcher));
+        self
+    }
+
+    /// Answers at most `times` requests, after which later rules answer them. By default, the
+    /// rule answers any number of requests.
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = Some(times);
+        self
+    }
+
+    /// Waits `delay` before answering each request. Defaults to no delay.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Responds with `response`, which must be a response to the rule's RPC.
+    pub fn respond(self, response: Resp)
+    where
+        Resp: Clone + Send + 'static,
+    {
+        self.returning(move |_, _| response.clone())
+    }
+
+    /// Responds with the response `f` returns for each request.
+    pub fn returning<F>(self, mut f: F)
+    where
+        F: FnMut(context::Context, Req) -> Resp + Send + 'static,
+    {
+        self.add(Action::Respond(Box::new(move |ctx, req| vec![f(ctx, req)])))
+    }
+
+    /// Responds to a streaming RPC with `responses`, sending all but the last as partial
+    /// responses. For a service's streaming RPC, the last is the response that ends the stream.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `responses` is empty.
+    pub fn respond_stream(self, responses: Vec<Resp>)
+    where
+        Resp: Clone + Send + 'static,
+    {
+        assert!(!responses.is_empty(), "a stream ends with a final response");
+        self.add(Action::Respond(Box::new(move |_, _| responses.clone())))
+    }
+
+    /// Fails requests with an error of kind `kind`, which the client returns as an
+    /// [`io::Error`].
+    pub fn fail(self, kind: io::ErrorKind, detail: impl Into<String>) {
+        self.add(Action::Fail(kind, detail.into()))
+    }
+
+    /// Never answers requests, so clients wait out their deadlines.
+    pub fn ignore(self) {
+        self.add(Action::Ignore)
+    }
+
+    /// Closes the connection, failing every request in flight over it.
+    pub fn disconnect(self) {
+        self.add(Action::Disconnect)
+    }
+
+    fn add(self, action: Action<Req, Resp>) {
+        self.server.state.lock().unwrap().rules.push(Rule {
+            rpc: self.rpc,
+            matcher: self.matcher,
+            times: self.times,
+            calls: 0,
+            delay: self.delay,
+            action,
+        });
+    }
+}
+
+impl<Req, Resp> fmt::Debug for MockRpc<'_, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockRpc")
+            .field("rpc", &self.rpc)
+            .field("times", &self.times)
+            .field("delay", &self.delay)
+            .finish()
+    }
+}
+
+/// Answers the requests received over a transport. Returned by [`MockServer::serve`].
+#[must_use = "futures do nothing unless polled"]
+pub struct MockServe<T, Req, Resp> {
+    serve: Serve<T, Req, Resp, MockServer<Req, Resp>>,
+}
+
+impl<T, Req, Resp> MockServe<T, Req, Resp> {
+    unsafe_pinned!(serve: Serve<T, Req, Resp, MockServer<Req, Resp>>);
+}
+
+impl<T, Req, Resp> Future for MockServe<T, Req, Resp>
+where
+    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    Req: Introspect,
+    Resp: IntrospectResponse + Send + 'static,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.serve().poll(cx)
+    }
+}
+
+impl<T, Req, Resp> fmt::Debug for MockServe<T, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockServe")
+            .field("serve", &self.serve)
+            .finish()
+    }
+}
+
+impl<Req, Resp> Handler<Req, Resp> for MockServer<Req, Resp>
+where
+    Req: Introspect,
+    Resp: IntrospectResponse,
+{
+    type Output = ();
+
+    fn handle(&mut self, request: Request<Req>, _: usize) -> Option<(Duration, Answer<Resp>)> {
+        self.state.lock().unwrap().answer(request)
+    }
+
+    fn cancel(&mut self, _: u64, rpc: &'static str) {
+        let mut state = self.state.lock().unwrap();
+        *state.cancellations.entry(rpc).or_insert(0) += 1;
+    }
+
+    fn finish(&mut self) {}
+}
+
+/// What a server does with a request.
+pub(crate) enum Answer<Resp> {
+    Respond(Vec<Response<Resp>>),
+    Disconnect,
+}
+
+impl<Resp> Answer<Resp> {
+    /// Fails request `request_id` with an error of kind `kind`.
+    pub(crate) fn fail(request_id: u64, kind: io::ErrorKind, detail: String) -> Self {
+        Answer::Respond(vec![Response {
+            request_id,
+            message: Err(ServerError {
+                kind,
+                detail: Some(detail),
+                unauthenticated: false,
+                _non_exhaustive: (),
+            }),
+            partial: false,
+            _non_exhaustive: (),
+        }])
+    }
+
+    /// Responds to request `request_id` to `rpc` with `responses`, all but the last of which are
+    /// partial. Fails the request instead if any of them is a response to a different RPC.
+    pub(crate) fn respond(rpc: &str, request_id: u64, responses: Vec<Resp>) -> Self
+    where
+        Resp: IntrospectResponse,
+    {
+        if let Some(response) = responses.iter().find(|resp| resp.method_name() != rpc) {
+            let detail = format!(
+                "the mock response for `{}` is a response to `{}`",
+                rpc,
+                response.method_name()
+            );
+            return Answer::fail(request_id, io::ErrorKind::InvalidData, detail);
+        }
+        let last = responses.len() - 1;
+        Answer::Respond(
+            responses
+                .into_iter()
+                .enumerate()
+                .map(|(i, message)| Response {
+                    request_id,
+                    message: Ok(message),
+                    partial: i < last,
+                    _non_exhaustive: (),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Decides how a [`Serve`] answers the requests it receives.
+pub(crate) trait Handler<Req, Resp> {
+    /// What serving resolves to.
+    type Output;
+
+    /// Returns the answer to `request`, and how long to wait before giving it, given the number
+    /// of requests in flight, including `request` unless it's one-way. Returns `None` to leave
+    /// the request in flight until it's canceled or [released](Handler::release).
+    fn handle(
+        &mut self,
+        request: Request<Req>,
+        in_flight: usize,
+    ) -> Option<(Duration, Answer<Resp>)>;
+
+    /// Returns the answers to requests left in flight that are now ready to be given, by request
+    /// ID, given the number of requests in flight. By default, no answer is ever released.
+    fn release(&mut self, in_flight: usize) -> Vec<(u64, Duration, Answer<Resp>)> {
+        let _ = in_flight;
+        vec![]
+    }
+
+    /// Notes that the client canceled request `request_id` to `rpc` before it was answered.
+    fn cancel(&mut self, request_id: u64, rpc: &'static str);
+
+    /// Returns what serving resolves to, once the connection closes.
+    fn finish(&mut self) -> Self::Output;
+}
+
+/// An answer waiting out its delay, which is dropped if its request is canceled.
+type PendingAnswer<Resp> = Abortable<Pin<Box<dyn Future<Output = (u64, Answer<Resp>)> + Send>>>;
+
+/// Answers the requests received over a transport as its [`Handler`] decides, waiting out the
+/// delays of answers with its timer and dropping the answers to requests that are canceled.
+pub(crate) struct Serve<T, Req, Resp, H> {
+    transport: T,
+    handler: H,
+    timer: Arc<dyn Timer>,
+    /// The RPC of each request that has yet to be answered, by request ID, and the handle that
+    /// drops its answer, unless it's left in flight.
+    in_flight: HashMap<u64, (&'static str, Option<AbortHandle>)>,
+    /// Answers waiting out their delays.
+    pending: FuturesUnordered<PendingAnswer<Resp>>,
+    /// Responses waiting to be written.
+    queued: VecDeque<Response<Resp>>,
+    requests: PhantomData<fn(Req)>,
+}
+
+impl<T, Req, Resp, H> Serve<T, Req, Resp, H> {
+    unsafe_pinned!(transport: T);
+    unsafe_unpinned!(handler: H);
+    unsafe_unpinned!(in_flight: HashMap<u64, (&'static str, Option<AbortHandle>)>);
+    unsafe_unpinned!(pending: FuturesUnordered<PendingAnswer<Resp>>);
+    unsafe_unpinned!(queued: VecDeque<Response<Resp>>);
+
+    /// Returns a future that answers the requests received over `transport` as `handler`
+    /// decides, timing delays with `timer`.
+    pub(crate) fn new(transport: T, handler: H, timer: Arc<dyn Timer>) -> Self {
+        Serve {
+            transport,
+            handler,
+            timer,
+            in_flight: HashMap::new(),
+            pending: FuturesUnordered::new(),
+            queued: VecDeque::new(),
+            requests: PhantomData,
+        }
+    }
+}
+
+impl<T, Req, Resp, H> Serve<T, Req, Resp, H>
+where
+    Req: Introspect,
+    Resp: Send + 'static,
+    H: Handler<Req, Resp>,
+{
+    fn handle(mut self: Pin<&mut Self>, request: Request<Req>) {
+        let one_way = request.one_way;
+        let request_id = request.id;
+        if !one_way {
+            let rpc = request.message.method_name();
+            self.as_mut().in_flight().insert(request_id, (rpc, None));
+        }
+        let in_flight = self.in_flight.len();
+        match self.as_mut().handler().handle(request, in_flight) {
+            // One-way requests are never responded to.
+            Some((_, Answer::Respond(_))) if one_way => {}
+            Some((delay, answer)) => self.give(request_id, delay, answer),
+            None => {}
+        }
+    }
+
+    /// Gives `answer` to request `request_id` once `delay` has passed, unless the request is
+    /// canceled first.
+    fn give(mut self: Pin<&mut Self>, request_id: u64, delay: Duration, answer: Answer<Resp>) {
+        let answer = if delay == Duration::from_secs(0) {
+            future::ready((request_id, answer)).boxed()
+        } else {
+            self.timer
+                .delay_for(delay)
+                .map(move |()| (request_id, answer))
+                .boxed()
+        };
+        let (abort, registration) = AbortHandle::new_pair();
+        if let Some((_, handle)) = self.as_mut().in_flight().get_mut(&request_id) {
+            *handle = Some(abort);
+        }
+        self.as_mut()
+            .pending()
+            .push(Abortable::new(answer, registration));
+    }
+
+    /// Drops the answer to request `request_id`, if it has yet to be answered.
+    fn cancel(mut self: Pin<&mut Self>, request_id: u64) {
+        if let Some((rpc, abort)) = self.as_mut().in_flight().remove(&request_id) {
+            if let Some(abort) = abort {
+                abort.abort();
+            }
+            self.as_mut().handler().cancel(request_id, rpc);
+        }
+    }
+}
+
+impl<T, Req, Resp, H> Future for Serve<T, Req, Resp, H>
+where
+    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    Req: Introspect,
+    Resp: Send + 'static,
+    H: Handler<Req, Resp>,
+{
+    type Output = H::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<H::Output> {
+        loop {
+            match self.as_mut().transport().poll_next(cx) {
+                Poll::Ready(Some(Ok(ClientMessage::Request(request)))) => {
+                    self.as_mut().handle(request)
+                }
+                Poll::Ready(Some(Ok(ClientMessage::Cancel { request_id, .. }))) => {
+                    self.as_mut().cancel(request_id)
+                }
+                Poll::Ready(Some(Ok(ClientMessage::_NonExhaustive))) => {}
+                Poll::Ready(Some(Err(_))) | Poll::Ready(None) => {
+                    return Poll::Ready(self.as_mut().handler().finish())
+                }
+                Poll::Pending => break,
+            }
+        }
+        let in_flight = self.in_flight.len();
+        for (request_id, delay, answer) in self.as_mut().handler().release(in_flight) {
+            self.as_mut().give(request_id, delay, answer);
+        }
+        while let Poll::Ready(Some(answer)) = self.as_mut().pending().poll_next_unpin(cx) {
+            match answer {
+                Ok((request_id, Answer::Respond(responses))) => {
+                    self.as_mut().in_flight().remove(&request_id);
+                    self.as_mut().queued().extend(responses);
+                }
+                Ok((_, Answer::Disconnect)) => {
+                    return Poll::Ready(self.as_mut().handler().finish())
+                }
+                // The request was canceled.
+                Err(Aborted) => {}
+            }
+        }
+        while !self.queued.is_empty() {
+            match self.as_mut().transport().poll_ready(cx) {
+                Poll::Ready(Ok(())) => {
+                    let response = self.as_mut().queued().pop_front().unwrap();
+                    if self.as_mut().transport().start_send(response).is_err() {
+                        return Poll::Ready(self.as_mut().handler().finish());
+                    }
+                }
+                Poll::Ready(Err(_)) => return Poll::Ready(self.as_mut().handler().finish()),
+                Poll::Pending => break,
+            }
+        }
+        match self.as_mut().transport().poll_flush(cx) {
+            Poll::Ready(Err(_)) => Poll::Ready(self.as_mut().handler().finish()),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl<T, Req, Resp, H: fmt::Debug> fmt::Debug for Serve<T, Req, Resp, H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Serve")
+            .field("handler", &self.handler)
+            .field("in_flight", &self.in_flight.len())
+            .field("pending", &self.pending.len())
+            .field("queued", &self.queued.len())
+            .finish()
+    }
+}
@@ -7,32 +7,74 @@
 //! Provides a server that concurrently handles many connections sending multiplexed requests.
 
 use crate::{
-    context, util::Compact, util::TimeUntil, ClientMessage, PollIo, Request, Response, ServerError,
-    Transport,
+    context::{self, PeerIdentity},
+    event::{Event, EventSink, LogSink},
+    export::{FinishedSpan, SpanExporter, SpanKind},
+    runtime::{self, Spawn, SpawnLocal, Timeout, Timer},
+    transport::{FlushPolicy, Flusher},
+    ClientMessage, MapHasher, PollIo, Request, Response, ServerError, Transport,
 };
-use fnv::FnvHashMap;
 use futures::{
     channel::mpsc,
-    future::{AbortHandle, AbortRegistration, Abortable},
+    future::{AbortHandle, AbortRegistration},
     prelude::*,
     ready,
     stream::Fuse,
     task::{Context, Poll},
 };
 use humantime::format_rfc3339;
-use log::{debug, trace};
 use pin_utils::{unsafe_pinned, unsafe_unpinned};
-use std::{fmt, hash::Hash, io, marker::PhantomData, pin::Pin, time::SystemTime};
-use tokio_timer::{timeout, Timeout};
+use std::{
+    collections::VecDeque,
+    fmt,
+    hash::Hash,
+    io,
+    marker::PhantomData,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+use trace::TraceId;
 
+pub mod audit;
+pub mod auth;
+pub mod capture;
 mod filter;
+#[cfg(all(feature = "glommio1", target_os = "linux"))]
+pub mod glommio;
+mod in_flight;
+mod memory;
+pub mod metrics;
+pub mod mock;
+pub mod placement;
+#[cfg(any(feature = "tokio1", all(feature = "glommio1", target_os = "linux")))]
+pub mod shard;
+mod stats;
+pub mod task;
 #[cfg(test)]
 mod testing;
 mod throttle;
+pub mod watchdog;
 
 pub use self::{
-    filter::ChannelFilter,
+    audit::AuditLog,
+    auth::{Authenticator, AuthenticatorStream},
+    capture::PayloadCapture,
+    filter::{ChannelFilter, KeyActivity, KeyStats},
+    in_flight::InFlightSlot,
+    metrics::MetricsRecorder,
+    placement::{SpawnFn, SpawnRequest},
+    stats::{Stats, StatsChannel, StatsStream},
+    task::TaskCounts,
     throttle::{Throttler, ThrottlerStream},
+    watchdog::Watchdog,
+};
+use self::{
+    in_flight::{Cancelable, InFlightRequests},
+    memory::{BufferedBytes, Charge, ResponseMeter},
 };
 
 /// Manages clients, serving multiplexed requests over each connection.
@@ -55,12 +97,78 @@ pub struct Config {
     /// `pending_response_buffer` controls the buffer size of the channel that a server's
     /// response tasks use to send responses to the client handler task.
     pub pending_response_buffer: usize,
+    /// Records the latency and errors of each request, keyed by method name. Requests to servers
+    /// that can't [name their methods](Serve::method_name) are recorded as method `"unknown"`.
+    pub metrics: Option<Arc<dyn MetricsRecorder>>,
+    /// Records every request handled, with its principal, method, arguments hash, result, and
+    /// duration.
+    pub audit: Option<AuditLog>,
+    /// Counts the live request and connection handlers, by method and peer.
+    pub task_counts: Option<TaskCounts>,
+    /// Captures a sample of request and response payloads, for debugging.
+    pub capture: Option<PayloadCapture>,
+    /// Tracks in-flight requests so that those running too long can be reported.
+    pub watchdog: Option<Watchdog>,
+    /// Exports a span for each sampled request to a tracing backend.
+    pub spans: Option<SpanExporter>,
+    /// When to flush responses written to the transport. Defaults to flushing whenever no more
+    /// responses are ready to write.
+    pub flush: FlushPolicy,
+    /// How long a handler [marked inline](Serve::is_inline) may run on the channel's task before
+    /// it's spawned onto its own task. The timer is only armed for handlers that don't complete
+    /// when first polled, which run alongside the channel's other requests until then. A handler
+    /// that blocks without yielding holds up the channel regardless, so only mark handlers inline
+    /// that never block.
+    pub inline_timeout: Duration,
+    /// Responses smaller than this many bytes, as [estimated by the server](Serve::response_size),
+    /// from handlers [marked inline](Serve::is_inline) that complete when first polled, are handed
+    /// straight to the channel's task to write, rather than being sent through the channel of
+    /// pending responses. Responses the server can't size, and errors, always go through the
+    /// channel, as does every response if this is 0. Defaults to 1 KiB.
+    pub direct_response_threshold: usize,
+    /// The hash function of the maps that track each channel's in-flight requests, which are
+    /// keyed by request IDs the client chooses.
+    pub hasher: MapHasher,
+    /// The most bytes a channel may buffer in requests being handled and responses waiting to be
+    /// written, as [estimated by the server](Serve::request_size). A channel at the limit stops
+    /// reading requests until handlers finish and responses are written, so that a client that
+    /// doesn't read its responses can't make the server buffer them without bound. Defaults to no
+    /// limit.
+    pub max_buffered_bytes: Option<usize>,
+    /// Receives the events of the server's channels and requests. Defaults to [logging](LogSink)
+    /// them.
+    pub events: Arc<dyn EventSink>,
+    /// Spawns channels and request handlers. Defaults to the executor of the
+    /// [default runtime](runtime::DefaultRuntime).
+    pub spawn: Arc<dyn Spawn>,
+    /// Spawns request handlers by method, in place of [`spawn`](Config::spawn), which still
+    /// spawns the channels. Handlers [marked inline](Serve::is_inline) are spawned with it only
+    /// if they outlive [`inline_timeout`](Config::inline_timeout). Defaults to none.
+    pub placement: Option<Arc<dyn SpawnRequest>>,
+    /// Times request deadlines, corked flushes, and inline handlers. Defaults to the timer of the
+    /// [default runtime](runtime::DefaultRuntime).
+    pub timer: Arc<dyn Timer>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             pending_response_buffer: 100,
+            metrics: None,
+            audit: None,
+            task_counts: None,
+            capture: None,
+            watchdog: None,
+            spans: None,
+            flush: FlushPolicy::default(),
+            inline_timeout: Duration::from_millis(1),
+            direct_response_threshold: 1024,
+            hasher: MapHasher::default(),
+            max_buffered_bytes: None,
+            events: Arc::new(LogSink),
+            spawn: runtime::default(),
+            placement: None,
+            timer: runtime::default(),
         }
     }
 }
@@ -83,6 +191,13 @@ pub fn new<Req, Resp>(config: Config) -> Server<Req, Resp> {
     }
 }
 
+/// Returns the length of `value`'s bincode encoding, as an estimate of the bytes it holds. Services
+/// generated with serde support [size](Serve::request_size) their requests and responses with it.
+#[cfg(feature = "serde1")]
+pub fn encoded_size<T: serde::Serialize + ?Sized>(value: &T) -> usize {
+    bincode::serialized_size(value).map_or(0, |size| size as usize)
+}
+
 impl<Req, Resp> Server<Req, Resp> {
     /// Returns the config for this server.
     pub fn config(&self) -> &Config {
@@ -109,6 +224,222 @@ pub trait Serve<Req>: Sized + Clone {
 
     /// Responds to a single request.
     fn serve(self, ctx: context::Context, req: Req) -> Self::Fut;
+
+    /// Returns the name of the RPC invoked by `req`, if known. Used to label the request in
+    /// traces.
+    fn method_name(&self, _req: &Req) -> Option<&'static str> {
+        None
+    }
+
+    /// Returns the name of the service, if known. Used to name request handlers.
+    fn service_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Returns a stable hash of the arguments of `req`, leaving out redacted arguments, if
+    /// known. Used to identify requests in the [audit log](audit) without revealing them.
+    fn args_hash(&self, _req: &Req) -> Option<u64> {
+        None
+    }
+
+    /// Returns whether the handler for `req` is cheap enough to run directly on the channel's task
+    /// rather than being spawned onto its own. Inline handlers that run longer than
+    /// [`Config::inline_timeout`] are spawned anyway.
+    fn is_inline(&self, _req: &Req) -> bool {
+        false
+    }
+
+    /// Estimates the bytes `req` holds while it's handled, which count against
+    /// [`Config::max_buffered_bytes`]. Defaults to 0, leaving requests uncounted; services
+    /// generated with serde support count the length of the request's [encoding](encoded_size).
+    fn request_size(_req: &Req) -> usize {
+        0
+    }
+
+    /// Estimates the bytes `resp` holds while it waits to be written, which count against
+    /// [`Config::max_buffered_bytes`]. Defaults to 0, leaving responses uncounted; services
+    /// generated with serde support count the length of the response's [encoding](encoded_size).
+    fn response_size(_resp: &Self::Resp) -> usize {
+        0
+    }
+
+    /// Returns the error to answer `req` with instead of serving it, if the service won't serve
+    /// it. Rejected one-way requests are dropped.
+    fn reject(&self, _req: &Req) -> Option<ServerError> {
+        None
+    }
+
+    /// Renders `req` for [payload capture](capture), leaving out redacted arguments. Requests
+    /// that can't be rendered aren't captured.
+    fn debug_request(&self, _req: &Req) -> Option<String> {
+        None
+    }
+
+    /// Renders `resp` for [payload capture](capture).
+    fn debug_response(&self, _resp: &Self::Resp) -> Option<String> {
+        None
+    }
+
+    /// Responds to a single request, using `sink` to send [partial](Response::partial) responses
+    /// ahead of the final response. The default implementation ignores `sink` and calls
+    /// [`serve`](Serve::serve), which suffices for services that don't stream responses.
+    fn serve_with_sink(
+        self,
+        ctx: context::Context,
+        req: Req,
+        sink: ResponseSink<Self::Resp>,
+    ) -> Self::Fut {
+        drop(sink);
+        self.serve(ctx, req)
+    }
+}
+
+/// A response waiting to be written, with the trace of its request, its charge against the
+/// channel's buffered bytes, and, if it's the final response of a captured request, the ID of the
+/// request's payload capture.
+type QueuedResponse<R> = (TraceId, Response<R>, Charge, Option<u64>);
+
+/// Sends partial responses for a single request over the channel the request arrived on.
+///
+/// Items sent to a `ResponseSink` are delivered to the client in order, ahead of the request's
+/// final response.
+#[derive(Debug)]
+pub struct ResponseSink<R> {
+    request_id: u64,
+    /// The channel's pending responses, and its count of the partial responses among them.
+    /// `None` if the sink is disconnected or closed.
+    tx: Option<(TraceId, mpsc::Sender<QueuedResponse<R>>, Arc<AtomicUsize>)>,
+    /// Charges partial responses to the channel, if it limits buffered bytes.
+    meter: Option<ResponseMeter<R>>,
+}
+
+impl<R> ResponseSink<R> {
+    fn new(
+        request_id: u64,
+        trace_id: TraceId,
+        tx: mpsc::Sender<QueuedResponse<R>>,
+        queued_partials: Arc<AtomicUsize>,
+        meter: Option<ResponseMeter<R>>,
+    ) -> Self {
+        ResponseSink {
+            request_id,
+            tx: Some((trace_id, tx, queued_partials)),
+            meter,
+        }
+    }
+
+    /// Returns a sink that discards everything sent to it. Useful for serving requests that
+    /// don't arrive over a channel.
+    pub fn disconnected() -> Self {
+        ResponseSink {
+            request_id: 0,
+            tx: None,
+            meter: None,
+        }
+    }
+}
+
+impl<R> Sink<R> for ResponseSink<R> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.tx {
+            Some((_, ref mut tx, _)) => tx
+                .poll_ready(cx)
+                .map_err(|_| io::Error::from(io::ErrorKind::ConnectionReset)),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: R) -> io::Result<()> {
+        let response = Response {
+            request_id: self.request_id,
+            message: Ok(item),
+            partial: true,
+            _non_exhaustive: (),
+        };
+        let charge = match self.meter {
+            Some(ref meter) => meter.charge(&response),
+            None => Charge::none(),
+        };
+        match self.tx {
+            Some((trace_id, ref mut tx, ref queued_partials)) => {
+                // Counted before it's sent, so that it's never written before it's counted.
+                queued_partials.fetch_add(1, Ordering::Relaxed);
+                tx.start_send((trace_id, response, charge, None))
+                    .map_err(|_| {
+                        queued_partials.fetch_sub(1, Ordering::Relaxed);
+                        io::Error::from(io::ErrorKind::ConnectionReset)
+                    })
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        self.tx = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A future that sends each item of a stream as a partial response, then resolves to the final
+/// response. Used to serve streaming requests.
+#[derive(Debug)]
+pub struct ForwardStream<St: Stream, R> {
+    stream: Fuse<St>,
+    sink: ResponseSink<R>,
+    /// Wraps stream items in a response; `None` signifies the end of the stream.
+    f: fn(Option<St::Item>) -> R,
+    /// The next partial response, waiting for room in the sink.
+    buffered: Option<R>,
+}
+
+impl<St: Stream, R> ForwardStream<St, R> {
+    unsafe_pinned!(stream: Fuse<St>);
+    unsafe_unpinned!(sink: ResponseSink<R>);
+    unsafe_unpinned!(buffered: Option<R>);
+
+    /// Returns a future that sends each item of `stream`, wrapped by `f`, to `sink`, and that
+    /// resolves to `f(None)` once `stream` is exhausted.
+    pub fn new(stream: St, sink: ResponseSink<R>, f: fn(Option<St::Item>) -> R) -> Self {
+        ForwardStream {
+            stream: stream.fuse(),
+            sink,
+            f,
+            buffered: None,
+        }
+    }
+}
+
+impl<St: Stream, R> Future for ForwardStream<St, R> {
+    type Output = R;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<R> {
+        loop {
+            if self.buffered.is_some() {
+                let sent = match ready!(Pin::new(self.as_mut().sink()).poll_ready(cx)) {
+                    Ok(()) => {
+                        let item = self.as_mut().buffered().take().unwrap();
+                        Pin::new(self.as_mut().sink()).start_send(item)
+                    }
+                    Err(e) => Err(e),
+                };
+                if sent.is_err() {
+                    // The connection is gone, so the remaining items have nowhere to go.
+                    *self.as_mut().buffered() = None;
+                    return Poll::Ready((self.f)(None));
+                }
+            }
+            match ready!(self.as_mut().stream().poll_next(cx)) {
+                Some(item) => *self.as_mut().buffered() = Some((self.f)(Some(item))),
+                None => return Poll::Ready((self.f)(None)),
+            }
+        }
+    }
 }
 
 impl<Req, Resp, Fut, F> Serve<Req> for F
@@ -144,8 +475,21 @@ where
         ThrottlerStream::new(self, n)
     }
 
+    /// Rejects requests on every channel whose [bearer token](context::Context::bearer_token)
+    /// `validate` doesn't accept.
+    fn authenticate<F>(self, validate: F) -> AuthenticatorStream<Self, F>
+    where
+        F: Fn(&str) -> bool + Clone,
+    {
+        AuthenticatorStream::new(self, validate)
+    }
+
+    /// Tracks open channels, in-flight requests, and pending responses in `stats`.
+    fn stats(self, stats: &Stats) -> StatsStream<Self> {
+        StatsStream::new(self, stats.clone())
+    }
+
     /// Responds to all requests with `server`.
-    #[cfg(feature = "tokio1")]
     fn respond_with<S>(self, server: S) -> Running<Self, S>
     where
         S: Serve<C::Req, Resp = C::Resp>,
@@ -153,6 +497,22 @@ where
         Running {
             incoming: self,
             server,
+            events: Arc::new(LogSink),
+        }
+    }
+
+    /// Responds to all requests with `server`, running channels and request handlers with
+    /// `spawner` on the current thread, so that neither `server` nor its futures need be `Send`.
+    fn respond_with_local<S, Sp>(self, server: S, spawner: Sp) -> RunningLocal<Self, S, Sp>
+    where
+        S: Serve<C::Req, Resp = C::Resp>,
+        Sp: SpawnLocal + Clone,
+    {
+        RunningLocal {
+            incoming: self,
+            server,
+            spawner,
+            events: Arc::new(LogSink),
         }
     }
 }
@@ -170,14 +530,19 @@ pub struct BaseChannel<Req, Resp, T> {
     config: Config,
     /// Writes responses to the wire and reads requests off the wire.
     transport: Fuse<T>,
-    /// Number of requests currently being responded to.
-    in_flight_requests: FnvHashMap<u64, AbortHandle>,
+    /// Requests currently being responded to.
+    in_flight_requests: InFlightRequests,
+    /// The authenticated identity of the peer.
+    peer_identity: Option<Arc<PeerIdentity>>,
+    /// The ID of the duplicate request whose rejection is waiting for the transport to be ready.
+    rejected: Option<u64>,
     /// Types the request and response.
     ghost: PhantomData<(Req, Resp)>,
 }
 
 impl<Req, Resp, T> BaseChannel<Req, Resp, T> {
-    unsafe_unpinned!(in_flight_requests: FnvHashMap<u64, AbortHandle>);
+    unsafe_unpinned!(in_flight_requests: InFlightRequests);
+    unsafe_unpinned!(rejected: Option<u64>);
 }
 
 impl<Req, Resp, T> BaseChannel<Req, Resp, T>
@@ -187,9 +552,11 @@ where
     /// Creates a new channel backed by `transport` and configured with `config`.
     pub fn new(config: Config, transport: T) -> Self {
         BaseChannel {
+            in_flight_requests: InFlightRequests::new(config.hasher),
             config,
             transport: transport.fuse(),
-            in_flight_requests: FnvHashMap::default(),
+            peer_identity: None,
+            rejected: None,
             ghost: PhantomData,
         }
     }
@@ -199,6 +566,13 @@ where
         Self::new(Config::default(), transport)
     }
 
+    /// Sets the identity the transport authenticated the peer as, returning the channel for
+    /// chaining.
+    pub fn with_peer_identity(mut self, identity: PeerIdentity) -> Self {
+        self.peer_identity = Some(Arc::new(identity));
+        self
+    }
+
     /// Returns the inner transport.
     pub fn get_ref(&self) -> &T {
         self.transport.get_ref()
@@ -212,22 +586,18 @@ where
     fn cancel_request(mut self: Pin<&mut Self>, trace_context: &trace::Context, request_id: u64) {
         // It's possible the request was already completed, so it's fine
         // if this is None.
-        if let Some(cancel_handle) = self.as_mut().in_flight_requests().remove(&request_id) {
-            self.as_mut().in_flight_requests().compact(0.1);
-
-            cancel_handle.abort();
+        if self.as_mut().in_flight_requests().cancel(request_id) {
             let remaining = self.as_mut().in_flight_requests().len();
-            trace!(
-                "[{}] Request canceled. In-flight requests = {}",
-                trace_context.trace_id,
-                remaining,
-            );
+            self.config.events.event(&Event::RequestCanceled {
+                trace_id: trace_context.trace_id,
+                request_id,
+                in_flight_requests: remaining,
+            });
         } else {
-            trace!(
-                "[{}] Received cancellation, but response handler \
-                 is already complete.",
-                trace_context.trace_id,
-            );
+            self.config.events.event(&Event::CancellationIgnored {
+                trace_id: trace_context.trace_id,
+                request_id,
+            });
         }
     }
 }
@@ -263,11 +633,47 @@ where
         Throttler::new(self, n)
     }
 
+    /// Rejects requests whose [bearer token](context::Context::bearer_token) `validate` doesn't
+    /// accept.
+    fn authenticate<F>(self, validate: F) -> Authenticator<Self, F>
+    where
+        F: Fn(&str) -> bool,
+        Self: Sized,
+    {
+        Authenticator::new(self, validate)
+    }
+
     /// Tells the Channel that request with ID `request_id` is being handled.
     /// The request will be tracked until a response with the same ID is sent
     /// to the Channel.
     fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration;
 
+    /// Like [`start_request`](Channel::start_request), but returns the slot through which the
+    /// channel cancels the request's handler. Channels that keep slots of their own reuse them
+    /// across requests, rather than allocating an abort handle for each. By default, the slot
+    /// wraps the registration returned by `start_request`.
+    fn track_request(self: Pin<&mut Self>, request_id: u64) -> InFlightSlot {
+        self.start_request(request_id).into()
+    }
+
+    /// Returns the number of slots allocated for tracking in-flight requests, which grows with
+    /// the most requests the channel has handled at once. Channels that don't keep slots for
+    /// in-flight requests return 0.
+    fn in_flight_capacity(&self) -> usize {
+        0
+    }
+
+    /// Returns the key under which a [`ChannelFilter`] admitted the channel, if any.
+    fn filter_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the authenticated identity of the channel's peer, if known. The server copies it
+    /// into the [context](context::Context::peer_identity) of each request it handles.
+    fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        None
+    }
+
     /// Respond to requests coming over the channel with `f`. Returns a future that drives the
     /// responses and resolves when the connection is closed.
     fn respond_with<S>(self, server: S) -> ClientHandler<Self, S>
@@ -277,12 +683,24 @@ where
     {
         let (responses_tx, responses) = mpsc::channel(self.config().pending_response_buffer);
         let responses = responses.fuse();
+        let task = self
+            .config()
+            .task_counts
+            .as_ref()
+            .map(|counts| counts.start(connection_name(&self)));
 
         ClientHandler {
+            flusher: Flusher::new(self.config().flush, self.config().timer.clone()),
             channel: self,
             server,
             pending_responses: responses,
             responses_tx,
+            direct_responses: VecDeque::new(),
+            queued_partials: Arc::default(),
+            direct_turn: false,
+            buffered: Arc::default(),
+            paused: false,
+            _task: task,
         }
     }
 }
@@ -295,9 +713,43 @@ where
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         loop {
+            if let Some(request_id) = *self.as_mut().rejected() {
+                ready!(self.as_mut().transport().poll_ready(cx)?);
+                *self.as_mut().rejected() = None;
+                // Written straight to the transport, so that the request in flight with the same
+                // ID stays in flight.
+                self.as_mut().transport().start_send(Response {
+                    request_id,
+                    message: Err(ServerError {
+                        kind: io::ErrorKind::AlreadyExists,
+                        detail: Some("A request with this ID is already in flight.".into()),
+                        unauthenticated: false,
+                        _non_exhaustive: (),
+                    }),
+                    partial: false,
+                    _non_exhaustive: (),
+                })?;
+            }
+
             match ready!(self.as_mut().transport().poll_next(cx)?) {
                 Some(message) => match message {
-                    ClientMessage::Request(request) => {
+                    ClientMessage::Request(mut request) => {
+                        // One-way requests aren't tracked, so only requests awaiting responses
+                        // can collide.
+                        if !request.one_way && self.in_flight_requests.contains(request.id) {
+                            self.config.events.event(&Event::DuplicateRequest {
+                                trace_id: *request.context.trace_id(),
+                                request_id: request.id,
+                            });
+                            *self.as_mut().rejected() = Some(request.id);
+                            continue;
+                        }
+                        let now = self.config.timer.system_now();
+                        if let Some(skew) = request.context.reconstruct_deadline(now) {
+                            if let Some(ref recorder) = self.config.metrics {
+                                recorder.record_clock_skew(skew);
+                            }
+                        }
                         return Poll::Ready(Some(Ok(request)));
                     }
                     ClientMessage::Cancel {
@@ -325,13 +777,11 @@ where
     }
 
     fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> Result<(), Self::Error> {
-        if self
-            .as_mut()
-            .in_flight_requests()
-            .remove(&response.request_id)
-            .is_some()
-        {
-            self.as_mut().in_flight_requests().compact(0.1);
+        // Partial responses are followed by more responses, so the request is still in flight.
+        if !response.partial {
+            self.as_mut()
+                .in_flight_requests()
+                .complete(response.request_id);
         }
 
         self.transport().start_send(response)
@@ -367,13 +817,26 @@ where
         self.as_mut().in_flight_requests().len()
     }
 
+    fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        self.peer_identity.clone()
+    }
+
     fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
-        let (abort_handle, abort_registration) = AbortHandle::new_pair();
-        assert!(self
-            .in_flight_requests()
-            .insert(request_id, abort_handle)
-            .is_none());
-        abort_registration
+        // Requests reusing the ID of a request in flight are rejected as they're read, so this
+        // one can't be a duplicate.
+        self.in_flight_requests()
+            .start_abortable(request_id)
+            .unwrap_or_else(|| AbortHandle::new_pair().1)
+    }
+
+    fn track_request(self: Pin<&mut Self>, request_id: u64) -> InFlightSlot {
+        self.in_flight_requests()
+            .start(request_id)
+            .unwrap_or_else(InFlightSlot::detached)
+    }
+
+    fn in_flight_capacity(&self) -> usize {
+        self.in_flight_requests.capacity()
     }
 }
 
@@ -385,11 +848,46 @@ where
 {
     channel: C,
     /// Responses waiting to be written to the wire.
-    pending_responses: Fuse<mpsc::Receiver<(context::Context, Response<C::Resp>)>>,
+    pending_responses: Fuse<mpsc::Receiver<QueuedResponse<C::Resp>>>,
     /// Handed out to request handlers to fan in responses.
-    responses_tx: mpsc::Sender<(context::Context, Response<C::Resp>)>,
+    responses_tx: mpsc::Sender<QueuedResponse<C::Resp>>,
+    /// Responses from inline handlers that completed on the channel's task, and responses to
+    /// rejected requests, waiting to be written. Holds at most as many responses as the channel
+    /// of pending responses buffers.
+    direct_responses: VecDeque<QueuedResponse<C::Resp>>,
+    /// The partial responses sent through `responses_tx` that haven't been written yet. Direct
+    /// responses wait for them, since they may be the final responses of the same requests.
+    queued_partials: Arc<AtomicUsize>,
+    /// Whether a direct response is written next, if any is waiting, so that neither kind of
+    /// response starves the other.
+    direct_turn: bool,
+    /// The bytes buffered by requests being handled and responses waiting to be written.
+    buffered: Arc<BufferedBytes>,
+    /// Whether reading requests is paused because too many bytes are buffered.
+    paused: bool,
     /// Server
     server: S,
+    /// Applies the flush policy to written responses.
+    flusher: Flusher,
+    /// Counts the handler as a live task.
+    _task: Option<task::TaskGuard>,
+}
+
+/// Identifies the peer of `channel`: the principal it authenticated as, if any, or else the
+/// channel's [filter key](Channel::filter_key).
+fn peer<C: Channel>(channel: &C) -> Option<String> {
+    channel
+        .peer_identity()
+        .map(|identity| identity.principal.clone())
+        .or_else(|| channel.filter_key())
+}
+
+/// Names the handler of `channel`, which also counts it among the live tasks of its peer.
+fn connection_name<C: Channel>(channel: &C) -> String {
+    match peer(channel) {
+        Some(peer) => format!("connection {}", peer),
+        None => "connection".into(),
+    }
 }
 
 impl<C, S> ClientHandler<C, S>
@@ -397,11 +895,53 @@ where
     C: Channel,
 {
     unsafe_pinned!(channel: C);
-    unsafe_pinned!(pending_responses: Fuse<mpsc::Receiver<(context::Context, Response<C::Resp>)>>);
-    unsafe_pinned!(responses_tx: mpsc::Sender<(context::Context, Response<C::Resp>)>);
+    unsafe_pinned!(pending_responses: Fuse<mpsc::Receiver<QueuedResponse<C::Resp>>>);
+    unsafe_pinned!(responses_tx: mpsc::Sender<QueuedResponse<C::Resp>>);
     // For this to be safe, field f must be private, and code in this module must never
     // construct PinMut<S>.
     unsafe_unpinned!(server: S);
+    unsafe_unpinned!(direct_responses: VecDeque<QueuedResponse<C::Resp>>);
+    unsafe_unpinned!(direct_turn: bool);
+    unsafe_unpinned!(flusher: Flusher);
+    unsafe_unpinned!(paused: bool);
+
+    /// Returns the name of the handler: `connection`, followed by the principal the peer
+    /// authenticated as or else the channel's [filter key](Channel::filter_key), if any.
+    pub fn name(&self) -> String {
+        connection_name(&self.channel)
+    }
+
+    /// Writes the responses that are ready, as far as the transport takes them without waiting,
+    /// and starts flushing them. Responses that don't fit are dropped.
+    fn write_ready_responses(mut self: Pin<&mut Self>) {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        while let Poll::Ready(Ok(())) = self.as_mut().channel().poll_ready(&mut cx) {
+            let (_, response, _) = match self.as_mut().pending_responses().poll_next(&mut cx) {
+                Poll::Ready(Some(response)) => response,
+                _ => match self.as_mut().direct_responses().pop_front() {
+                    Some(response) => response,
+                    None => break,
+                },
+            };
+            if self.as_mut().channel().start_send(response).is_err() {
+                return;
+            }
+        }
+        let _ = self.as_mut().channel().poll_flush(&mut cx);
+    }
+}
+
+/// A client handler is dropped before it finishes when the runtime running it shuts down, or
+/// when its owner drops it. The responses that are ready are still written, best-effort, and
+/// dropping the channel aborts the handlers of the requests in flight.
+impl<C, S> Drop for ClientHandler<C, S>
+where
+    C: Channel,
+{
+    fn drop(&mut self) {
+        // Safe because the handler is never moved again once it's being dropped.
+        unsafe { Pin::new_unchecked(self) }.write_ready_responses();
+    }
 }
 
 impl<C, S> ClientHandler<C, S>
@@ -413,10 +953,60 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> PollIo<RequestHandler<S::Fut, C::Resp>> {
-        match ready!(self.as_mut().channel().poll_next(cx)?) {
-            Some(request) => Poll::Ready(Some(Ok(self.handle_request(request)))),
-            None => Poll::Ready(None),
+        if let Some(limit) = self.channel.config().max_buffered_bytes {
+            if self.buffered.poll_below(limit, cx).is_pending() {
+                if !self.paused {
+                    *self.as_mut().paused() = true;
+                    self.channel
+                        .config()
+                        .events
+                        .event(&Event::BufferLimitReached {
+                            buffered_bytes: self.buffered.get(),
+                            limit,
+                        });
+                }
+                return Poll::Pending;
+            }
+            *self.as_mut().paused() = false;
+        }
+        loop {
+            // Direct responses are written before further requests are read once as many are
+            // waiting as the channel of pending responses buffers.
+            if self.direct_responses.len() >= self.channel.config().pending_response_buffer.max(1) {
+                return Poll::Pending;
+            }
+            let request = match ready!(self.as_mut().channel().poll_next(cx)?) {
+                Some(request) => request,
+                None => return Poll::Ready(None),
+            };
+            match self.server.reject(&request.message) {
+                Some(error) => self.as_mut().reject_request(request, error),
+                None => return Poll::Ready(Some(Ok(self.handle_request(request)))),
+            }
+        }
+    }
+
+    /// Answers a request the service won't serve with `error`, without starting a handler.
+    fn reject_request(mut self: Pin<&mut Self>, request: Request<C::Req>, error: ServerError) {
+        let trace_id = *request.context.trace_id();
+        self.channel.config().events.event(&Event::RequestRejected {
+            trace_id,
+            request_id: request.id,
+            one_way: request.one_way,
+            error: &error,
+        });
+        if request.one_way {
+            return;
         }
+        let response = Response {
+            request_id: request.id,
+            message: Err(error),
+            partial: false,
+            _non_exhaustive: (),
+        };
+        self.as_mut()
+            .direct_responses()
+            .push_back((trace_id, response, Charge::none(), None));
     }
 
     fn pump_write(
@@ -425,13 +1015,23 @@ where
         read_half_closed: bool,
     ) -> PollIo<()> {
         match self.as_mut().poll_next_response(cx)? {
-            Poll::Ready(Some((ctx, response))) => {
-                trace!(
-                    "[{}] Staging response. In-flight requests = {}.",
-                    ctx.trace_id(),
-                    self.as_mut().channel().in_flight_requests(),
-                );
+            Poll::Ready(Some((trace_id, response, _charge, capture))) => {
+                if let Some(id) = capture {
+                    self.as_mut().complete_capture(id, &response);
+                }
+                let in_flight_requests = self.as_mut().channel().in_flight_requests();
+                self.channel.config().events.event(&Event::ResponseStaged {
+                    trace_id,
+                    request_id: response.request_id,
+                    in_flight_requests,
+                });
                 self.as_mut().channel().start_send(response)?;
+                if self.as_mut().flusher().wrote() {
+                    // A flush that doesn't finish now is finished before the next idle flush.
+                    if self.as_mut().channel().poll_flush(cx)?.is_ready() {
+                        self.as_mut().flusher().flushed();
+                    }
+                }
                 Poll::Ready(Some(Ok(())))
             }
             Poll::Ready(None) => {
@@ -440,8 +1040,11 @@ where
                 Poll::Ready(None)
             }
             Poll::Pending => {
-                // No more requests to process, so flush any requests buffered in the transport.
+                // No more requests to process, so flush any requests buffered in the transport,
+                // once the flush policy allows.
+                ready!(self.as_mut().flusher().poll_idle(cx));
                 ready!(self.as_mut().channel().poll_flush(cx)?);
+                self.as_mut().flusher().flushed();
 
                 // Being here means there are no staged requests and all written responses are
                 // fully flushed. So, if the read half is closed and there are no in-flight
@@ -458,18 +1061,48 @@ where
     fn poll_next_response(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> PollIo<(context::Context, Response<C::Resp>)> {
+    ) -> PollIo<QueuedResponse<C::Resp>> {
         // Ensure there's room to write a response.
         while let Poll::Pending = self.as_mut().channel().poll_ready(cx)? {
             ready!(self.as_mut().channel().poll_flush(cx)?);
         }
 
-        match ready!(self.as_mut().pending_responses().poll_next(cx)) {
-            Some((ctx, response)) => Poll::Ready(Some(Ok((ctx, response)))),
-            None => {
+        // Direct responses take turns with the responses sent through the channel, but wait for
+        // the partial responses in the channel: a request whose final response is waiting among
+        // the direct responses may have sent some.
+        if self.direct_turn && self.queued_partials.load(Ordering::Relaxed) == 0 {
+            if let Some(response) = self.as_mut().direct_responses().pop_front() {
+                *self.as_mut().direct_turn() = false;
+                return Poll::Ready(Some(Ok(response)));
+            }
+        }
+        match self.as_mut().pending_responses().poll_next(cx) {
+            Poll::Ready(Some(response)) => {
+                if response.1.partial {
+                    self.queued_partials.fetch_sub(1, Ordering::Relaxed);
+                }
+                *self.as_mut().direct_turn() = true;
+                Poll::Ready(Some(Ok(response)))
+            }
+            Poll::Ready(None) => {
                 // This branch likely won't happen, since the ClientHandler is holding a Sender.
                 Poll::Ready(None)
             }
+            Poll::Pending => match self.as_mut().direct_responses().pop_front() {
+                Some(response) => Poll::Ready(Some(Ok(response))),
+                None => Poll::Pending,
+            },
+        }
+    }
+
+    /// Adds `response` to payload capture `id`.
+    fn complete_capture(mut self: Pin<&mut Self>, id: u64, response: &Response<C::Resp>) {
+        let rendered = match response.message {
+            Ok(ref message) => self.as_mut().server().debug_response(message),
+            Err(ref e) => Some(format!("{:?}", e)),
+        };
+        if let (Some(capture), Some(rendered)) = (&self.channel.config().capture, rendered) {
+            capture.complete(id, rendered);
         }
     }
 
@@ -478,30 +1111,159 @@ where
         request: Request<C::Req>,
     ) -> RequestHandler<S::Fut, C::Resp> {
         let request_id = request.id;
+        let one_way = request.one_way;
         let deadline = request.context.deadline;
-        let timeout = deadline.time_until();
-        trace!(
-            "[{}] Received request with deadline {} (timeout {:?}).",
-            request.context.trace_id(),
-            format_rfc3339(deadline),
-            timeout,
-        );
-        let ctx = request.context;
+        let timeout = self.channel.config().timer.time_until(deadline);
+        let mut ctx = request.context;
+        ctx.request_id = Some(request_id);
+        ctx.peer_identity = self.channel.peer_identity();
         let request = request.message;
+        let method = self.as_mut().server().method_name(&request);
+        let service = self.as_mut().server().service_name();
+        let inline = self.as_mut().server().is_inline(&request);
+        // Borrowed until the handler is set up; it keeps copies of only the parts it needs.
+        let config = self.channel.config();
+        let (request_charge, meter) = if config.max_buffered_bytes.is_some() {
+            (
+                self.buffered.charge(S::request_size(&request)),
+                Some(ResponseMeter::new(S::response_size, self.buffered.clone())),
+            )
+        } else {
+            (Charge::none(), None)
+        };
+        config.events.event(&Event::RequestReceived {
+            trace_id: *ctx.trace_id(),
+            request_id,
+            one_way,
+            deadline,
+        });
+        let task = config
+            .task_counts
+            .as_ref()
+            .map(|counts| counts.start(task::request_kind(service, method)));
+        let capture = config
+            .capture
+            .as_ref()
+            .filter(|capture| capture.sample())
+            .and_then(|capture| {
+                let rendered = self.server.debug_request(&request)?;
+                Some(capture.start(
+                    *ctx.trace_id(),
+                    request_id,
+                    method.unwrap_or("unknown"),
+                    rendered,
+                ))
+            });
+        let watch = config.watchdog.as_ref().map(|watchdog| {
+            watchdog.watch(
+                *ctx.trace_id(),
+                request_id,
+                method.unwrap_or("unknown"),
+                peer(&self.channel),
+            )
+        });
+        let span_exporter = config
+            .spans
+            .as_ref()
+            .filter(|_| ctx.trace_context.sampled)
+            .map(|exporter| (exporter.clone(), ctx.trace_context));
+        let record =
+            if config.metrics.is_some() || config.audit.is_some() || span_exporter.is_some() {
+                Some(RequestRecord {
+                    method: method.unwrap_or("unknown"),
+                    start: Instant::now(),
+                    expired_on_arrival: timeout == Duration::from_secs(0),
+                    finished: false,
+                    metrics: config.metrics.clone(),
+                    audit: config.audit.clone().map(|log| {
+                        let principal = ctx
+                            .peer_identity()
+                            .map(|identity| identity.principal.clone());
+                        let args_hash = self.server.args_hash(&request);
+                        (log, principal, args_hash, *ctx.trace_id())
+                    }),
+                    span: span_exporter,
+                })
+            } else {
+                None
+            };
+        let delay = config.timer.delay_for(timeout);
+        let events = config.events.clone();
+        #[cfg(feature = "tracing")]
+        let span = if !ctx.trace_context.sampled {
+            tracing::Span::none()
+        } else {
+            let span = tracing::info_span!(
+                "rpc",
+                method = tracing::field::Empty,
+                peer = tracing::field::Empty,
+                request_id,
+                trace_id = %ctx.trace_id(),
+                deadline = %format_rfc3339(deadline),
+                one_way,
+            );
+            if let Some(method) = method {
+                span.record("method", &method);
+            }
+            if let Some(peer) = peer(&self.channel) {
+                span.record("peer", &peer.as_str());
+            }
+            span
+        };
 
-        let response = self.as_mut().server().clone().serve(ctx, request);
+        // One-way requests are never responded to, so they can't stream responses.
+        let sink = if one_way {
+            ResponseSink::disconnected()
+        } else {
+            ResponseSink::new(
+                request_id,
+                *ctx.trace_id(),
+                self.as_mut().responses_tx().clone(),
+                self.queued_partials.clone(),
+                meter.clone(),
+            )
+        };
+        let response = {
+            // Safe because the guard is dropped before `ctx` is moved.
+            let _current = unsafe { context::set_current(&ctx) };
+            self.as_mut()
+                .server()
+                .clone()
+                .serve_with_sink(ctx.clone(), request, sink)
+        };
         let response = Resp {
             state: RespState::PollResp,
             request_id,
+            one_way,
             ctx,
             deadline,
-            f: Timeout::new(response, timeout),
+            f: Timeout::new(response, delay),
             response: None,
             response_tx: self.as_mut().responses_tx().clone(),
+            meter,
+            response_size: S::response_size,
+            direct_response_threshold: 0,
+            _request_charge: request_charge,
+            record,
+            capture: capture.filter(|_| !one_way),
+            events,
+            watch,
+            #[cfg(feature = "tracing")]
+            span,
+        };
+        // One-way requests are never responded to, so the channel doesn't track them.
+        let slot = if one_way {
+            InFlightSlot::detached()
+        } else {
+            self.as_mut().channel().track_request(request_id)
         };
-        let abort_registration = self.as_mut().channel().start_request(request_id);
         RequestHandler {
-            resp: Abortable::new(response, abort_registration),
+            resp: Cancelable::new(response, slot),
+            service,
+            method,
+            request_id,
+            inline,
+            _task: task,
         }
     }
 }
@@ -509,11 +1271,67 @@ where
 /// A future fulfilling a single client request.
 #[derive(Debug)]
 pub struct RequestHandler<F, R> {
-    resp: Abortable<Resp<F, R>>,
+    resp: Cancelable<Resp<F, R>>,
+    service: Option<&'static str>,
+    method: Option<&'static str>,
+    request_id: u64,
+    inline: bool,
+    /// Counts the handler as a live task.
+    _task: Option<task::TaskGuard>,
 }
 
 impl<F, R> RequestHandler<F, R> {
-    unsafe_pinned!(resp: Abortable<Resp<F, R>>);
+    unsafe_pinned!(resp: Cancelable<Resp<F, R>>);
+
+    /// Returns the name of the handler, `<service>.<method>#<request id>`, with `unknown` standing
+    /// in for a service or method the server can't name.
+    pub fn name(&self) -> String {
+        self.task_name().to_string()
+    }
+
+    fn task_name(&self) -> task::RequestName {
+        task::RequestName {
+            service: self.service,
+            method: self.method,
+            request_id: self.request_id,
+        }
+    }
+
+    /// Returns the method of the request, or `"unknown"` if the server can't
+    /// [name it](Serve::method_name).
+    pub fn method(&self) -> &'static str {
+        self.method.unwrap_or("unknown")
+    }
+
+    /// Returns whether the server [marked the request inline](Serve::is_inline).
+    pub fn is_inline(&self) -> bool {
+        self.inline
+    }
+}
+
+impl<F, R> RequestHandler<F, R>
+where
+    F: Future<Output = R>,
+{
+    /// Polls the handler on the channel's task. If it completes with a response smaller than
+    /// `threshold`, the response is returned for the channel to write, rather than being sent
+    /// through the channel of pending responses.
+    fn poll_direct(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        threshold: usize,
+    ) -> Poll<Option<QueuedResponse<R>>> {
+        *self
+            .as_mut()
+            .resp()
+            .get_pin_mut()
+            .direct_response_threshold() = threshold;
+        let poll = self.as_mut().resp().poll(cx);
+        let mut resp = self.resp().get_pin_mut();
+        *resp.as_mut().direct_response_threshold() = 0;
+        ready!(poll);
+        Poll::Ready(resp.take_direct_response())
+    }
 }
 
 impl<F, R> Future for RequestHandler<F, R>
@@ -532,11 +1350,105 @@ where
 struct Resp<F, R> {
     state: RespState,
     request_id: u64,
+    one_way: bool,
     ctx: context::Context,
     deadline: SystemTime,
+    /// Reports the request to the watchdog while it's in flight. Declared before the handler so
+    /// that the request is no longer watched by the time the handler is dropped.
+    watch: Option<watchdog::Watch>,
     f: Timeout<F>,
     response: Option<Response<R>>,
-    response_tx: mpsc::Sender<(context::Context, Response<R>)>,
+    response_tx: mpsc::Sender<QueuedResponse<R>>,
+    /// Charges the response to the channel, if it limits buffered bytes.
+    meter: Option<ResponseMeter<R>>,
+    /// Estimates the size of the response, to decide whether it's small enough to be written
+    /// directly.
+    response_size: fn(&R) -> usize,
+    /// While the channel's task polls the handler, the size below which the response is left
+    /// for the channel to write. 0 otherwise.
+    direct_response_threshold: usize,
+    /// Counts the request against the channel's buffered bytes until it's handled.
+    _request_charge: Charge,
+    /// Taken when the response is sent, so that a request dropped before then is recorded as
+    /// canceled.
+    record: Option<RequestRecord>,
+    /// The ID of the request's payload capture, if it was captured. It's sent along with the
+    /// response, so a request that's canceled or dropped leaves nothing behind in the channel.
+    capture: Option<u64>,
+    events: Arc<dyn EventSink>,
+    /// The span of the request, entered while polling the response.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// Records the outcome of a request to the configured metrics, audit log, and span exporter.
+#[derive(Debug)]
+struct RequestRecord {
+    method: &'static str,
+    start: Instant,
+    /// Whether the request's deadline had elapsed before it arrived.
+    expired_on_arrival: bool,
+    /// Whether the request's outcome was recorded.
+    finished: bool,
+    metrics: Option<Arc<dyn MetricsRecorder>>,
+    /// The log, principal, arguments hash, and trace ID to audit.
+    audit: Option<(AuditLog, Option<String>, Option<u64>, trace::TraceId)>,
+    /// The exporter and trace context of the request's span.
+    span: Option<(SpanExporter, trace::Context)>,
+}
+
+impl RequestRecord {
+    fn finish(&mut self, result: Result<(), metrics::ErrorClass>) {
+        self.finished = true;
+        let duration = self.start.elapsed();
+        if let Some(ref recorder) = self.metrics {
+            if result != Err(metrics::ErrorClass::Canceled) {
+                recorder.record_latency(self.method, duration);
+            }
+            if let Err(class) = result {
+                recorder.record_error(self.method, class);
+            }
+        }
+        match result {
+            Err(metrics::ErrorClass::DeadlineExceeded) if self.expired_on_arrival => {
+                self.interrupt(metrics::Interruption::ExpiredBeforeStart)
+            }
+            Err(metrics::ErrorClass::DeadlineExceeded) => {
+                self.interrupt(metrics::Interruption::ExpiredDuringExecution)
+            }
+            Err(metrics::ErrorClass::Canceled) => {
+                self.interrupt(metrics::Interruption::CanceledDuringExecution)
+            }
+            _ => {}
+        }
+        if let Some((log, principal, args_hash, trace_id)) = self.audit.take() {
+            log.log(audit::AuditRecord {
+                timestamp: SystemTime::now(),
+                trace_id,
+                principal,
+                method: self.method,
+                args_hash,
+                result,
+                duration,
+                _non_exhaustive: (),
+            });
+        }
+        if let Some((exporter, trace_context)) = self.span.take() {
+            exporter.export(FinishedSpan::finish(
+                trace_context,
+                SpanKind::Server,
+                Some(self.method),
+                self.start,
+                result.err().map(|class| class.to_string()),
+            ));
+        }
+    }
+
+    fn interrupt(&self, interruption: metrics::Interruption) {
+        if let Some(ref recorder) = self.metrics {
+            recorder.record_interruption(self.method, interruption);
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -544,13 +1456,45 @@ enum RespState {
     PollResp,
     PollReady,
     PollFlush,
+    /// The response was left for the channel's task to write.
+    Direct,
 }
 
 impl<F, R> Resp<F, R> {
     unsafe_pinned!(f: Timeout<F>);
-    unsafe_pinned!(response_tx: mpsc::Sender<(context::Context, Response<R>)>);
+    unsafe_pinned!(response_tx: mpsc::Sender<QueuedResponse<R>>);
     unsafe_unpinned!(response: Option<Response<R>>);
     unsafe_unpinned!(state: RespState);
+    unsafe_unpinned!(record: Option<RequestRecord>);
+    unsafe_unpinned!(direct_response_threshold: usize);
+    unsafe_unpinned!(watch: Option<watchdog::Watch>);
+
+    /// Takes the response left for the channel's task to write, if any.
+    fn take_direct_response(mut self: Pin<&mut Self>) -> Option<QueuedResponse<R>> {
+        if let RespState::Direct = self.state {
+            let response = self.as_mut().response().take()?;
+            let charge = match self.meter {
+                Some(ref meter) => meter.charge(&response),
+                None => Charge::none(),
+            };
+            Some((*self.ctx.trace_id(), response, charge, self.capture))
+        } else {
+            None
+        }
+    }
+}
+
+impl<F, R> Drop for Resp<F, R> {
+    fn drop(&mut self) {
+        if let Some(mut record) = self.record.take() {
+            if record.finished {
+                // The response was ready, but the request was canceled before it was sent.
+                record.interrupt(metrics::Interruption::CompletedAfterCancellation);
+            } else {
+                record.finish(Err(metrics::ErrorClass::Canceled));
+            }
+        }
+    }
 }
 
 impl<F, R> Future for Resp<F, R>
@@ -560,20 +1504,52 @@ where
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Requests made while handling this one inherit its deadline, trace, and baggage.
+        // Safe because the context is pinned along with the response, and the guard is dropped
+        // by the end of the poll.
+        let _current = unsafe { context::set_current(&self.ctx) };
+        #[cfg(feature = "tracing")]
+        let span = self.span.clone();
+        #[cfg(feature = "tracing")]
+        let _enter = span.enter();
         loop {
             match self.as_mut().state() {
                 RespState::PollResp => {
-                    let result = ready!(self.as_mut().f().poll(cx));
+                    let waker = self
+                        .as_mut()
+                        .watch()
+                        .as_mut()
+                        .map(|watch| watch.polled(cx.waker()));
+                    let result = match waker {
+                        Some(ref waker) => {
+                            ready!(self.as_mut().f().poll(&mut Context::from_waker(waker)))
+                        }
+                        None => ready!(self.as_mut().f().poll(cx)),
+                    };
+                    if let Some(record) = self.as_mut().record() {
+                        record.finish(match result {
+                            Ok(_) => Ok(()),
+                            Err(_) => Err(metrics::ErrorClass::DeadlineExceeded),
+                        });
+                    }
+                    if self.one_way {
+                        self.as_mut().record().take();
+                        self.events.event(&Event::OneWayComplete {
+                            trace_id: *self.ctx.trace_id(),
+                            request_id: self.request_id,
+                        });
+                        return Poll::Ready(());
+                    }
                     *self.as_mut().response() = Some(Response {
                         request_id: self.request_id,
                         message: match result {
                             Ok(message) => Ok(message),
-                            Err(timeout::Elapsed { .. }) => {
-                                debug!(
-                                    "[{}] Response did not complete before deadline of {}s.",
-                                    self.ctx.trace_id(),
-                                    format_rfc3339(self.deadline)
-                                );
+                            Err(runtime::Elapsed { .. }) => {
+                                self.events.event(&Event::DeadlineExceeded {
+                                    trace_id: *self.ctx.trace_id(),
+                                    request_id: self.request_id,
+                                    deadline: self.deadline,
+                                });
                                 // No point in responding, since the client will have dropped the
                                 // request.
                                 Err(ServerError {
@@ -582,32 +1558,57 @@ where
                                         "Response did not complete before deadline of {}s.",
                                         format_rfc3339(self.deadline)
                                     )),
+                                    unauthenticated: false,
                                     _non_exhaustive: (),
                                 })
                             }
                         },
+                        partial: false,
                         _non_exhaustive: (),
                     });
+                    // Only responses the server can size are small enough to write directly.
+                    let direct = self.direct_response_threshold > 0
+                        && match self.response {
+                            Some(Response {
+                                message: Ok(ref message),
+                                ..
+                            }) => {
+                                let size = (self.response_size)(message);
+                                size > 0 && size < self.direct_response_threshold
+                            }
+                            _ => false,
+                        };
+                    if direct {
+                        self.as_mut().record().take();
+                        *self.as_mut().state() = RespState::Direct;
+                        return Poll::Ready(());
+                    }
                     *self.as_mut().state() = RespState::PollReady;
                 }
                 RespState::PollReady => {
                     let ready = ready!(self.as_mut().response_tx().poll_ready(cx));
                     if ready.is_err() {
+                        self.as_mut().record().take();
                         return Poll::Ready(());
                     }
-                    let resp = (self.ctx, self.as_mut().response().take().unwrap());
-                    if self.as_mut().response_tx().start_send(resp).is_err() {
+                    let response = self.as_mut().response().take().unwrap();
+                    let charge = match self.meter {
+                        Some(ref meter) => meter.charge(&response),
+                        None => Charge::none(),
+                    };
+                    let resp = (*self.ctx.trace_id(), response, charge, self.capture);
+                    let sent = self.as_mut().response_tx().start_send(resp);
+                    self.as_mut().record().take();
+                    if sent.is_err() {
                         return Poll::Ready(());
                     }
                     *self.as_mut().state() = RespState::PollFlush;
                 }
                 RespState::PollFlush => {
-                    let ready = ready!(self.as_mut().response_tx().poll_flush(cx));
-                    if ready.is_err() {
-                        return Poll::Ready(());
-                    }
+                    let _ = ready!(self.as_mut().response_tx().poll_flush(cx));
                     return Poll::Ready(());
                 }
+                RespState::Direct => return Poll::Ready(()),
             }
         }
     }
@@ -644,6 +1645,92 @@ where
     }
 }
 
+impl<C, S> ClientHandler<C, S>
+where
+    C: Channel,
+    S: Serve<C::Req, Resp = C::Resp>,
+{
+    /// Runs the client handler until completion, handing each request handler that should run on
+    /// its own task to `spawn`.
+    fn run<F>(self, spawn: F) -> impl Future<Output = ()>
+    where
+        F: Fn(Pin<Box<RequestHandler<S::Fut, C::Resp>>>),
+    {
+        let events = self.channel.config().events.clone();
+        let timer = self.channel.config().timer.clone();
+        let inline_timeout = self.channel.config().inline_timeout;
+        let threshold = self.channel.config().direct_response_threshold;
+        let client_handler = self;
+        async move {
+            pin_utils::pin_mut!(client_handler);
+            // Inline handlers that didn't complete when first polled. They run alongside the
+            // reading of further requests, so that a slow one holds up no others, until they
+            // complete or outlive the inline timeout and are spawned.
+            let mut pending_inline = stream::FuturesUnordered::new();
+            // The allocation of the last inline handler that completed when first polled, reused
+            // for the next, so that inline handlers that complete right away allocate nothing.
+            let mut spare: Option<Pin<Box<RequestHandler<S::Fut, C::Resp>>>> = None;
+            loop {
+                let next = future::poll_fn(|cx| {
+                    while let Poll::Ready(Some(timed_out)) = pending_inline.poll_next_unpin(cx) {
+                        if let Some(handler) = timed_out {
+                            spawn(handler);
+                        }
+                    }
+                    client_handler.as_mut().poll_next(cx)
+                })
+                .await;
+                let request_handler = match next {
+                    Some(Ok(request_handler)) => request_handler,
+                    Some(Err(error)) => {
+                        return events.event(&Event::ChannelErrored { error: &error })
+                    }
+                    None => break,
+                };
+                if !request_handler.is_inline() {
+                    spawn(Box::pin(request_handler));
+                    continue;
+                }
+                let mut handler = match spare.take() {
+                    Some(mut handler) => {
+                        handler.as_mut().set(request_handler);
+                        handler
+                    }
+                    None => Box::pin(request_handler),
+                };
+                let direct =
+                    future::poll_fn(|cx| Poll::Ready(handler.as_mut().poll_direct(cx, threshold)))
+                        .await;
+                match direct {
+                    Poll::Ready(response) => {
+                        if let Some(response) = response {
+                            client_handler
+                                .as_mut()
+                                .direct_responses()
+                                .push_back(response);
+                        }
+                        spare = Some(handler);
+                    }
+                    Poll::Pending => {
+                        let timeout = timer.delay_for(inline_timeout);
+                        pending_inline.push(future::select(handler, timeout).map(
+                            |raced| match raced {
+                                future::Either::Left(_) => None,
+                                future::Either::Right((_, handler)) => Some(handler),
+                            },
+                        ));
+                    }
+                }
+            }
+            while let Some(timed_out) = pending_inline.next().await {
+                if let Some(handler) = timed_out {
+                    spawn(handler);
+                }
+            }
+        }
+    }
+}
+
 // Send + 'static execution helper methods.
 
 impl<C, S> ClientHandler<C, S>
@@ -654,38 +1741,58 @@ where
     S: Serve<C::Req, Resp = C::Resp> + Send + 'static,
     S::Fut: Send + 'static,
 {
-    /// Runs the client handler until completion by spawning each
-    /// request handler onto the default executor.
-    #[cfg(feature = "tokio1")]
+    /// Runs the client handler until completion by spawning each request handler with the
+    /// channel's [`Config::placement`], if set, or else its [`Config::spawn`].
+    ///
+    /// Handlers [marked inline](Serve::is_inline) are instead run on the client handler's task,
+    /// and only spawned if they don't complete within [`Config::inline_timeout`]. Small responses
+    /// from inline handlers that complete right away are written without a trip through the
+    /// channel of pending responses; see [`Config::direct_response_threshold`].
     pub fn execute(self) -> impl Future<Output = ()> {
-        use log::info;
-
-        self.try_for_each(|request_handler| {
-            async {
-                tokio::spawn(request_handler);
-                Ok(())
-            }
+        let spawner = self.channel.config().spawn.clone();
+        let placement = self.channel.config().placement.clone();
+        self.run(move |handler| match placement {
+            Some(ref placement) => placement.spawn_request(handler.method(), handler),
+            None => spawner.spawn_named(&handler.task_name(), handler),
         })
-        .unwrap_or_else(|e| info!("ClientHandler errored out: {}", e))
     }
 }
 
-/// A future that drives the server by spawning channels and request handlers on the default
-/// executor.
+// 'static execution helper methods, for handlers that aren't Send.
+
+impl<C, S> ClientHandler<C, S>
+where
+    C: Channel + 'static,
+    C::Req: 'static,
+    C::Resp: 'static,
+    S: Serve<C::Req, Resp = C::Resp> + 'static,
+    S::Fut: 'static,
+{
+    /// Runs the client handler until completion by spawning each request handler with
+    /// `spawner`, which runs them on the current thread, so neither the service nor its
+    /// futures need be `Send`. Otherwise like [`execute`](ClientHandler::execute), except that
+    /// [`Config::placement`] is ignored.
+    pub fn execute_local(self, spawner: impl SpawnLocal) -> impl Future<Output = ()> {
+        self.run(move |handler| spawner.spawn_local(handler))
+    }
+}
+
+/// A future that drives the server by spawning channels and request handlers with the channels'
+/// [`Config::spawn`], or request handlers with their [`Config::placement`] if set.
 #[derive(Debug)]
-#[cfg(feature = "tokio1")]
 pub struct Running<St, Se> {
     incoming: St,
     server: Se,
+    /// Receives the server's shutdown. Taken from the config of the latest channel.
+    events: Arc<dyn EventSink>,
 }
 
-#[cfg(feature = "tokio1")]
 impl<St, Se> Running<St, Se> {
     unsafe_pinned!(incoming: St);
     unsafe_unpinned!(server: Se);
+    unsafe_unpinned!(events: Arc<dyn EventSink>);
 }
 
-#[cfg(feature = "tokio1")]
 impl<St, C, Se> Future for Running<St, Se>
 where
     St: Sized + Stream<Item = C>,
@@ -698,16 +1805,391 @@ where
     type Output = ();
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
-        use log::info;
+        while let Some(channel) = ready!(self.as_mut().incoming().poll_next(cx)) {
+            *self.as_mut().events() = channel.config().events.clone();
+            let spawner = channel.config().spawn.clone();
+            let handler = channel.respond_with(self.as_mut().server().clone());
+            spawner.spawn_named(&handler.name(), Box::pin(handler.execute()));
+        }
+        self.events.event(&Event::ServerShutdown);
+        Poll::Ready(())
+    }
+}
+
+/// A future that drives the server by spawning channels and request handlers on the current
+/// thread. Returned by [`Handler::respond_with_local`].
+#[derive(Debug)]
+pub struct RunningLocal<St, Se, Sp> {
+    incoming: St,
+    server: Se,
+    spawner: Sp,
+    /// Receives the server's shutdown. Taken from the config of the latest channel.
+    events: Arc<dyn EventSink>,
+}
+
+impl<St, Se, Sp> RunningLocal<St, Se, Sp> {
+    unsafe_pinned!(incoming: St);
+    unsafe_unpinned!(server: Se);
+    unsafe_unpinned!(events: Arc<dyn EventSink>);
+}
 
+impl<St, C, Se, Sp> Future for RunningLocal<St, Se, Sp>
+where
+    St: Sized + Stream<Item = C>,
+    C: Channel + 'static,
+    C::Req: 'static,
+    C::Resp: 'static,
+    Se: Serve<C::Req, Resp = C::Resp> + 'static + Clone,
+    Se::Fut: 'static,
+    Sp: SpawnLocal + Clone + 'static,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
         while let Some(channel) = ready!(self.as_mut().incoming().poll_next(cx)) {
-            tokio::spawn(
-                channel
-                    .respond_with(self.as_mut().server().clone())
-                    .execute(),
-            );
+            *self.as_mut().events() = channel.config().events.clone();
+            let handler = channel
+                .respond_with(self.as_mut().server().clone())
+                .execute_local(self.spawner.clone());
+            self.spawner.spawn_local(Box::pin(handler));
         }
-        info!("Server shutting down.");
+        self.events.event(&Event::ServerShutdown);
         Poll::Ready(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        testing::{self, FakeChannel, PollExt},
+        *,
+    };
+    use pin_utils::pin_mut;
+
+    /// Echoes requests inline, estimating each response's size as its value.
+    #[derive(Clone)]
+    struct InlineEcho;
+
+    impl Serve<u32> for InlineEcho {
+        type Resp = u32;
+        type Fut = future::Ready<u32>;
+
+        fn serve(self, _: context::Context, req: u32) -> Self::Fut {
+            future::ready(req)
+        }
+
+        fn is_inline(&self, _: &u32) -> bool {
+            true
+        }
+
+        fn response_size(resp: &u32) -> usize {
+            *resp as usize
+        }
+    }
+
+    #[test]
+    fn small_inline_responses_skip_the_response_channel() {
+        let mut channel = FakeChannel::default::<u32, u32>();
+        channel.push_req(1, 10);
+        channel.push_req(2, 2000);
+        let handler = channel.respond_with(InlineEcho);
+        pin_mut!(handler);
+        let mut cx = testing::cx();
+
+        let small = match handler.as_mut().poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(small))) => small,
+            _ => panic!("expected a request handler"),
+        };
+        let large = match handler.as_mut().poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(large))) => large,
+            _ => panic!("expected a request handler"),
+        };
+        pin_mut!(small, large);
+
+        match large.poll_direct(&mut cx, 1024) {
+            Poll::Ready(None) => {}
+            _ => panic!("expected the response to be sent through the channel"),
+        }
+        let response = match small.poll_direct(&mut cx, 1024) {
+            Poll::Ready(Some(response)) => response,
+            _ => panic!("expected a direct response"),
+        };
+        assert_eq!(response.1.message, Ok(10));
+        handler.as_mut().direct_responses().push_back(response);
+
+        assert!(handler.as_mut().poll_next(&mut cx).is_done());
+        // The response sent through the channel is written ahead of the direct response.
+        let written: Vec<_> = handler
+            .channel
+            .sink
+            .iter()
+            .map(|response| (response.request_id, response.message.clone()))
+            .collect();
+        assert_eq!(written, [(2, Ok(2000)), (1, Ok(10))]);
+    }
+
+    #[test]
+    fn unsized_inline_responses_use_the_response_channel() {
+        let mut channel = FakeChannel::default::<u32, u32>();
+        channel.push_req(1, 0);
+        let handler = channel.respond_with(InlineEcho);
+        pin_mut!(handler);
+        let mut cx = testing::cx();
+
+        let request_handler = match handler.as_mut().poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(request_handler))) => request_handler,
+            _ => panic!("expected a request handler"),
+        };
+        pin_mut!(request_handler);
+        match request_handler.poll_direct(&mut cx, 1024) {
+            Poll::Ready(None) => {}
+            _ => panic!("expected the response to be sent through the channel"),
+        }
+    }
+
+    /// Returns a final response to request `request_id`, ready to be written.
+    fn queued(request_id: u64, message: u32) -> QueuedResponse<u32> {
+        let response = Response {
+            request_id,
+            message: Ok(message),
+            partial: false,
+            _non_exhaustive: (),
+        };
+        (TraceId::default(), response, Charge::none(), None)
+    }
+
+    /// Returns the request IDs and messages written to `channel`.
+    fn written(channel: &FakeChannel<io::Result<Request<u32>>, Response<u32>>) -> Vec<(u64, u32)> {
+        channel
+            .sink
+            .iter()
+            .map(|response| (response.request_id, response.message.clone().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn direct_responses_take_turns_with_the_response_channel() {
+        let channel = FakeChannel::default::<u32, u32>();
+        let handler = channel.respond_with(InlineEcho);
+        pin_mut!(handler);
+        let mut cx = testing::cx();
+
+        let mut tx = handler.responses_tx.clone();
+        tx.try_send(queued(1, 1)).unwrap();
+        tx.try_send(queued(2, 2)).unwrap();
+        handler.as_mut().direct_responses().push_back(queued(3, 3));
+        handler.as_mut().direct_responses().push_back(queued(4, 4));
+
+        assert!(handler.as_mut().poll_next(&mut cx).is_done());
+        assert_eq!(written(&handler.channel), [(1, 1), (3, 3), (2, 2), (4, 4)]);
+    }
+
+    #[test]
+    fn direct_responses_wait_for_queued_partial_responses() {
+        let channel = FakeChannel::default::<u32, u32>();
+        let handler = channel.respond_with(InlineEcho);
+        pin_mut!(handler);
+        let mut cx = testing::cx();
+
+        let mut tx = handler.responses_tx.clone();
+        tx.try_send(queued(1, 1)).unwrap();
+        let mut sink = ResponseSink::new(
+            2,
+            TraceId::default(),
+            tx,
+            handler.queued_partials.clone(),
+            None,
+        );
+        futures::executor::block_on(sink.send(20)).unwrap();
+        handler.as_mut().direct_responses().push_back(queued(2, 2));
+
+        assert!(handler.as_mut().poll_next(&mut cx).is_done());
+        // The final response of request 2 follows its partial response.
+        assert_eq!(written(&handler.channel), [(1, 1), (2, 20), (2, 2)]);
+    }
+
+    #[test]
+    fn reading_pauses_while_direct_responses_are_full() {
+        let mut channel = FakeChannel::default::<u32, u32>();
+        channel.config.pending_response_buffer = 1;
+        channel.push_req(1, 10);
+        let handler = channel.respond_with(InlineEcho);
+        pin_mut!(handler);
+        let mut cx = testing::cx();
+
+        handler.as_mut().direct_responses().push_back(queued(2, 2));
+        assert!(handler.as_mut().pump_read(&mut cx).is_pending());
+
+        handler.as_mut().direct_responses().clear();
+        match handler.as_mut().pump_read(&mut cx) {
+            Poll::Ready(Some(Ok(_))) => {}
+            _ => panic!("expected a request handler"),
+        }
+    }
+
+    /// Echoes requests, naming even requests `even` and odd ones `odd`.
+    #[derive(Clone)]
+    struct ParityEcho;
+
+    impl Serve<u32> for ParityEcho {
+        type Resp = u32;
+        type Fut = future::Ready<u32>;
+
+        fn serve(self, _: context::Context, req: u32) -> Self::Fut {
+            future::ready(req)
+        }
+
+        fn method_name(&self, req: &u32) -> Option<&'static str> {
+            Some(if req % 2 == 0 { "even" } else { "odd" })
+        }
+    }
+
+    #[test]
+    fn placement_spawns_handlers_by_method() {
+        let (placed_tx, placed_rx) = mpsc::unbounded();
+        let mut channel = FakeChannel::default::<u32, u32>();
+        channel.config.placement = Some(Arc::new(SpawnFn::new(move |method, handler| {
+            placed_tx.unbounded_send((method, handler)).unwrap()
+        })));
+        channel.push_req(1, 4);
+        channel.push_req(2, 7);
+        channel.push_req(3, 8);
+
+        // Runs the placed handlers until the channel, and with it the placement, is dropped.
+        let mut methods = vec![];
+        let run_placed = placed_rx.for_each(|(method, handler)| {
+            methods.push(method);
+            handler
+        });
+        futures::executor::block_on(future::join(
+            channel.respond_with(ParityEcho).execute(),
+            run_placed,
+        ));
+        assert_eq!(methods, ["even", "odd", "even"]);
+    }
+
+    /// Echoes requests, except that requests of 0 are never responded to.
+    #[derive(Clone)]
+    struct EchoOrHang;
+
+    impl Serve<u32> for EchoOrHang {
+        type Resp = u32;
+        type Fut = future::Either<future::Ready<u32>, future::Pending<u32>>;
+
+        fn serve(self, _: context::Context, req: u32) -> Self::Fut {
+            if req == 0 {
+                future::Either::Right(future::pending())
+            } else {
+                future::Either::Left(future::ready(req))
+            }
+        }
+    }
+
+    #[test]
+    fn dropped_handlers_write_ready_responses_and_abort_requests() {
+        let (mut client, server) = crate::transport::channel::unbounded();
+        for &(id, req) in &[(1, 0), (2, 5)] {
+            futures::executor::block_on(client.send(ClientMessage::Request(Request::new(
+                context::current(),
+                id,
+                req,
+            ))))
+            .unwrap();
+        }
+        let mut config = Config::default();
+        config.timer = Arc::new(runtime::NoRuntime);
+        let mut handler = Box::pin(config.channel(server).respond_with(EchoOrHang));
+        let mut cx = testing::cx();
+        let mut next_request = || match handler.as_mut().poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(request_handler))) => Box::pin(request_handler),
+            _ => panic!("expected a request handler"),
+        };
+        let mut hanging = next_request();
+        let mut echo = next_request();
+        assert_eq!(hanging.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(echo.as_mut().poll(&mut cx), Poll::Ready(()));
+
+        // As when the runtime running the client handler shuts down.
+        drop(handler);
+        assert_eq!(hanging.as_mut().poll(&mut cx), Poll::Ready(()));
+        let written = match client.poll_next_unpin(&mut cx) {
+            Poll::Ready(Some(Ok(response))) => response,
+            _ => panic!("expected the ready response to be written"),
+        };
+        assert_eq!((written.request_id, written.message), (2, Ok(5)));
+    }
+
+    #[test]
+    fn duplicate_request_ids_are_rejected() {
+        let (mut client, server) = crate::transport::channel::unbounded();
+        for &(id, req) in &[(1, 0), (1, 5), (2, 7)] {
+            futures::executor::block_on(client.send(ClientMessage::Request(Request::new(
+                context::current(),
+                id,
+                req,
+            ))))
+            .unwrap();
+        }
+        let channel: BaseChannel<u32, u32, _> = BaseChannel::with_defaults(server);
+        pin_mut!(channel);
+        let mut cx = testing::cx();
+
+        let first = match channel.as_mut().poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(request))) => request,
+            _ => panic!("expected a request"),
+        };
+        assert_eq!((first.id, first.message), (1, 0));
+        let _slot = Channel::start_request(channel.as_mut(), 1);
+
+        let next = match channel.as_mut().poll_next(&mut cx) {
+            Poll::Ready(Some(Ok(request))) => request,
+            _ => panic!("expected a request"),
+        };
+        assert_eq!((next.id, next.message), (2, 7));
+        let rejection = match client.poll_next_unpin(&mut cx) {
+            Poll::Ready(Some(Ok(response))) => response,
+            _ => panic!("expected the duplicate to be rejected"),
+        };
+        assert_eq!(rejection.request_id, 1);
+        assert_eq!(
+            rejection.message.unwrap_err().kind,
+            io::ErrorKind::AlreadyExists
+        );
+        // The first request is still in flight.
+        assert_eq!(channel.as_mut().in_flight_requests().len(), 1);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn request_spans_name_the_method_and_peer() {
+        use crate::util::spans::SpanRecorder;
+
+        let (mut client, server) = crate::transport::channel::unbounded();
+        futures::executor::block_on(client.send(ClientMessage::Request(Request::new(
+            context::current(),
+            1,
+            2,
+        ))))
+        .unwrap();
+        let identity = PeerIdentity::new("alice", context::AuthMethod::UnixCredentials);
+        let channel: BaseChannel<u32, u32, _> =
+            BaseChannel::with_defaults(server).with_peer_identity(identity);
+        let handler = channel.respond_with(ParityEcho);
+        pin_mut!(handler);
+        let mut cx = testing::cx();
+
+        let recorder = SpanRecorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            match handler.as_mut().poll_next(&mut cx) {
+                Poll::Ready(Some(Ok(_))) => {}
+                _ => panic!("expected a request handler"),
+            }
+        });
+
+        let spans = recorder.spans("rpc");
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0]["method"], "even");
+        assert_eq!(spans[0]["peer"], "alice");
+        assert_eq!(spans[0]["request_id"], "1");
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Places request handlers on executors by method.
+//!
+//! By default, a channel spawns its request handlers with [`Config::spawn`](super::Config::spawn),
+//! as it does itself. A server whose methods differ in cost can instead route each handler by
+//! method with [`Config::placement`](super::Config::placement), e.g. to keep slow, CPU-heavy
+//! methods on a dedicated runtime, away from latency-sensitive ones:
+//!
+//! ```ignore
+//! let bulk = Arc::new(ThreadPool::new()?);
+//! config.placement = Some(Arc::new(SpawnFn::new(move |method, handler| match method {
+//!     "reindex" | "export" => bulk.spawn_ok(handler),
+//!     _ => tokio_executor::spawn(handler),
+//! })));
+//! ```
+
+use futures::Future;
+use std::{fmt, pin::Pin};
+
+/// Spawns request handlers, choosing where to run each by the method it handles.
+pub trait SpawnRequest: fmt::Debug + Send + Sync {
+    /// Spawns `handler`, which handles a request to `method`, to run to completion in the
+    /// background. Requests to servers that can't [name their methods](super::Serve::method_name)
+    /// are spawned as method `"unknown"`.
+    fn spawn_request(
+        &self,
+        method: &'static str,
+        handler: Pin<Box<dyn Future<Output = ()> + Send>>,
+    );
+}
+
+/// Spawns request handlers with a function of the method and handler.
+pub struct SpawnFn<F> {
+    f: F,
+}
+
+impl<F> SpawnFn<F>
+where
+    F: Fn(&'static str, Pin<Box<dyn Future<Output = ()> + Send>>) + Send + Sync,
+{
+    /// Returns a [`SpawnRequest`] that spawns each handler by calling `f`.
+    pub fn new(f: F) -> Self {
+        SpawnFn { f }
+    }
+}
+
+impl<F> SpawnRequest for SpawnFn<F>
+where
+    F: Fn(&'static str, Pin<Box<dyn Future<Output = ()> + Send>>) + Send + Sync,
+{
+    fn spawn_request(
+        &self,
+        method: &'static str,
+        handler: Pin<Box<dyn Future<Output = ()> + Send>>,
+    ) {
+        (self.f)(method, handler)
+    }
+}
+
+impl<F> fmt::Debug for SpawnFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SpawnFn").finish()
+    }
+}
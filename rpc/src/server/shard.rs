@@ -0,0 +1,270 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Runs a server as one single-threaded shard per core.
+//!
+//! Each shard runs on its own thread with its own single-threaded runtime, and is expected to
+//! accept connections from its own listener, e.g. one of the sockets bound by `bind_reuseport`
+//! for a transport's `SO_REUSEPORT` listener. Requests never leave the thread that accepted their
+//! connection, so the hot path needs no cross-thread synchronization. Unless shards are
+//! [pinned](ShardConfig::pin_to_cores), the OS may still move a shard's thread between cores.
+//!
+//! [`run_sharded`] runs each shard on a tokio runtime; with the `glommio1` feature,
+//! `glommio::run_sharded` runs each on a glommio executor.
+
+use crate::event::{Event, EventSink, LogSink};
+#[cfg(feature = "tokio1")]
+use futures::prelude::*;
+#[cfg(all(feature = "tokio1", unix))]
+use std::net::{SocketAddr, TcpListener};
+use std::{io, sync::Arc, thread};
+#[cfg(feature = "tokio1")]
+use tokio::runtime::current_thread::Runtime;
+
+/// Settings that control how a server is sharded.
+#[derive(Clone, Debug)]
+pub struct ShardConfig {
+    /// The number of shards. Defaults to the number of logical cores.
+    pub shards: usize,
+    /// Whether to pin each shard to one of the CPUs the process is allowed to run on: shard `i`
+    /// to the `i`th allowed CPU, modulo their number. A shard that fails to be pinned runs
+    /// unpinned, and the failure is reported to [`events`](ShardConfig::events). Pinning is only
+    /// supported on Linux, and is ignored elsewhere. Defaults to false.
+    pub pin_to_cores: bool,
+    /// Receives the events of the shards. Defaults to [logging](LogSink) them.
+    pub events: Arc<dyn EventSink>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Default for ShardConfig {
+    fn default() -> Self {
+        ShardConfig {
+            shards: num_cpus::get(),
+            pin_to_cores: false,
+            events: Arc::new(LogSink),
+            _non_exhaustive: (),
+        }
+    }
+}
+
+/// The threads running server shards, returned by [`run_sharded`].
+#[derive(Debug)]
+pub struct Shards {
+    pub(crate) threads: Vec<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Shards {
+    /// Returns the number of shards.
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Returns true if there are no shards.
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    /// Blocks until every shard completes, returning the first error a shard failed with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a shard panicked.
+    pub fn join(self) -> io::Result<()> {
+        let mut result = Ok(());
+        for thread in self.threads {
+            let shard_result = thread.join().expect("Server shard panicked.");
+            if result.is_ok() {
+                result = shard_result;
+            }
+        }
+        result
+    }
+}
+
+/// Runs `config.shards` shards, each on its own thread and single-threaded runtime. Shard `i`
+/// runs the future returned by `make_shard(i)`, which is created on the shard's thread, so it
+/// needn't be `Send`.
+#[cfg(feature = "tokio1")]
+pub fn run_sharded<F, Fut>(config: ShardConfig, make_shard: F) -> io::Result<Shards>
+where
+    F: Fn(usize) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    let make_shard = Arc::new(make_shard);
+    let cpus = if config.pin_to_cores {
+        allowed_cpus(&*config.events)
+    } else {
+        vec![]
+    };
+    let threads = (0..config.shards)
+        .map(|shard| {
+            let make_shard = make_shard.clone();
+            let cpu = shard_cpu(&cpus, shard);
+            let events = config.events.clone();
+            thread::Builder::new()
+                .name(format!("tarpc-shard-{}", shard))
+                .spawn(move || {
+                    if let Some(cpu) = cpu {
+                        if let Err(error) = pin_to_cpu(cpu) {
+                            events.event(&Event::ShardNotPinned {
+                                shard,
+                                cpu: Some(cpu),
+                                error: &error,
+                            });
+                        }
+                    }
+                    Runtime::new()?.block_on(make_shard(shard));
+                    Ok(())
+                })
+        })
+        .collect::<io::Result<_>>()?;
+    Ok(Shards { threads })
+}
+
+/// Returns the CPU to pin `shard` to, given the CPUs shards may be pinned to.
+pub(crate) fn shard_cpu(cpus: &[usize], shard: usize) -> Option<usize> {
+    if cpus.is_empty() {
+        None
+    } else {
+        Some(cpus[shard % cpus.len()])
+    }
+}
+
+/// Returns the CPUs the process is allowed to run on, in ascending order. If they can't be
+/// determined, the failure is reported to `events` and no CPUs are returned, so that no shard is
+/// pinned.
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+pub(crate) fn allowed_cpus(events: &dyn EventSink) -> Vec<usize> {
+    // Safe because the set is plain data, zeroed before use, and only tested below CPU_SETSIZE.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            events.event(&Event::ShardNotPinned {
+                shard: 0,
+                cpu: None,
+                error: &io::Error::last_os_error(),
+            });
+            return vec![];
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&cpu| libc::CPU_ISSET(cpu, &set))
+            .collect()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn allowed_cpus(_: &dyn EventSink) -> Vec<usize> {
+    vec![]
+}
+
+/// Binds `shards` listeners to `addr` with `SO_REUSEPORT`, so that the kernel spreads incoming
+/// connections across them and each shard can accept its own. If `addr` has port 0, every
+/// listener is bound to the port chosen for the first.
+#[cfg(all(feature = "tokio1", unix))]
+pub fn bind_reuseport(addr: &SocketAddr, shards: usize) -> io::Result<Vec<TcpListener>> {
+    use net2::{unix::UnixTcpBuilderExt, TcpBuilder};
+
+    let mut addr = *addr;
+    let mut listeners = Vec::with_capacity(shards);
+    for _ in 0..shards {
+        let builder = match addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+        };
+        builder.reuse_address(true)?.reuse_port(true)?.bind(addr)?;
+        let listener = builder.listen(1024)?;
+        addr = listener.local_addr()?;
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+#[cfg(all(feature = "tokio1", target_os = "linux"))]
+#[allow(unsafe_code)]
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("CPU {} is beyond CPU_SETSIZE", cpu),
+        ));
+    }
+    // Safe because the set is plain data, zeroed before use, and only read by the call.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "tokio1", not(target_os = "linux")))]
+fn pin_to_cpu(_: usize) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(feature = "tokio1")]
+#[test]
+fn runs_each_shard_on_its_own_thread() {
+    use futures::channel::mpsc;
+
+    let (tx, rx) = mpsc::unbounded();
+    let mut config = ShardConfig::default();
+    config.shards = 2;
+    let shards = run_sharded(config, move |shard| {
+        let tx = tx.clone();
+        async move {
+            let name = thread::current().name().map(String::from);
+            tx.unbounded_send((shard, name)).unwrap();
+        }
+    })
+    .unwrap();
+    assert_eq!(shards.len(), 2);
+    shards.join().unwrap();
+
+    let mut ran: Vec<_> = futures::executor::block_on(rx.collect());
+    ran.sort();
+    assert_eq!(
+        ran,
+        [
+            (0, Some("tarpc-shard-0".into())),
+            (1, Some("tarpc-shard-1".into()))
+        ]
+    );
+}
+
+#[cfg(all(feature = "tokio1", unix))]
+#[test]
+fn reuseport_listeners_share_the_port() {
+    let listeners = bind_reuseport(&"127.0.0.1:0".parse().unwrap(), 3).unwrap();
+    assert_eq!(listeners.len(), 3);
+    let addr = listeners[0].local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+    for listener in &listeners {
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+}
+
+#[test]
+fn shards_cycle_through_allowed_cpus() {
+    assert_eq!(shard_cpu(&[], 3), None);
+    assert_eq!(shard_cpu(&[2, 5], 0), Some(2));
+    assert_eq!(shard_cpu(&[2, 5], 1), Some(5));
+    assert_eq!(shard_cpu(&[2, 5], 2), Some(2));
+}
+
+#[cfg(all(feature = "tokio1", target_os = "linux"))]
+#[test]
+fn allowed_cpus_include_the_current_one() {
+    #[allow(unsafe_code)]
+    let current = unsafe { libc::sched_getcpu() };
+    assert!(current >= 0);
+    assert!(allowed_cpus(&LogSink).contains(&(current as usize)));
+    assert!(pin_to_cpu(libc::CPU_SETSIZE as usize).is_err());
+}
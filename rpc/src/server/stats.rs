@@ -0,0 +1,432 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use super::{metrics::Gauge, Channel, Config, InFlightSlot};
+use crate::{context::PeerIdentity, Response};
+use fnv::FnvHashMap;
+use futures::{
+    future::AbortRegistration,
+    prelude::*,
+    ready,
+    task::{Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    collections::BTreeMap,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Live gauges of a server: open channels, in-flight requests, and pending responses, along
+/// with [info](Stats::info) describing what the server is serving with.
+///
+/// Stats are shared by every channel of a [`StatsStream`] and can be read while the server runs.
+/// When a channel's [`Config::metrics`] is set, each change is also recorded as a [`Gauge`].
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    inner: Arc<Gauges>,
+}
+
+#[derive(Debug, Default)]
+struct Gauges {
+    channels: AtomicUsize,
+    channels_per_key: Mutex<FnvHashMap<String, usize>>,
+    in_flight_requests: AtomicUsize,
+    pending_responses: AtomicUsize,
+    in_flight_slots: AtomicUsize,
+    info: Mutex<BTreeMap<String, String>>,
+}
+
+impl Stats {
+    /// Returns new stats with every gauge at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of open channels.
+    pub fn channels(&self) -> usize {
+        self.inner.channels.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of open channels per [filter key](Channel::filter_key). Keys with no
+    /// open channels are omitted.
+    pub fn channels_per_key(&self) -> BTreeMap<String, usize> {
+        self.inner
+            .channels_per_key
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, &count)| (key.clone(), count))
+            .collect()
+    }
+
+    /// Returns the number of requests being handled across all channels.
+    pub fn in_flight_requests(&self) -> usize {
+        self.inner.in_flight_requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of responses written to channels but not yet flushed.
+    pub fn pending_responses(&self) -> usize {
+        self.inner.pending_responses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of slots allocated for tracking in-flight requests, across all
+    /// channels. Each channel's slots grow with the most requests it has handled at once and are
+    /// reused after that, so this bounds the memory spent on request bookkeeping.
+    pub fn in_flight_slots(&self) -> usize {
+        self.inner.in_flight_slots.load(Ordering::Relaxed)
+    }
+
+    /// Sets `key` of the server's info to `value`. Transports and other components set info
+    /// that changes while the server runs, such as the serial of the TLS certificate it's
+    /// serving with.
+    pub fn set_info(&self, key: impl Into<String>, value: impl Into<String>) {
+        self.inner
+            .info
+            .lock()
+            .unwrap()
+            .insert(key.into(), value.into());
+    }
+
+    /// Returns the server's info.
+    pub fn info(&self) -> BTreeMap<String, String> {
+        self.inner.info.lock().unwrap().clone()
+    }
+
+    fn channel_opened(&self, key: Option<&str>, config: &Config) {
+        self.add_channel(key, 1, config);
+    }
+
+    fn channel_closed(&self, key: Option<&str>, config: &Config) {
+        self.add_channel(key, -1, config);
+    }
+
+    fn add_channel(&self, key: Option<&str>, delta: isize, config: &Config) {
+        let total = add(&self.inner.channels, delta);
+        let per_key = key.map(|key| {
+            let mut channels_per_key = self.inner.channels_per_key.lock().unwrap();
+            let count = channels_per_key.entry(key.to_string()).or_insert(0);
+            *count = (*count as isize + delta) as usize;
+            let count = *count;
+            if count == 0 {
+                channels_per_key.remove(key);
+            }
+            (key, count)
+        });
+        if let Some(ref recorder) = config.metrics {
+            recorder.record_gauge(Gauge::OpenChannels, None, total as u64);
+            if let Some((key, count)) = per_key {
+                recorder.record_gauge(Gauge::OpenChannels, Some(key), count as u64);
+            }
+        }
+    }
+
+    fn add_gauge(&self, gauge: Gauge, delta: isize, config: &Config) {
+        if delta == 0 {
+            return;
+        }
+        let counter = match gauge {
+            Gauge::InFlightRequests => &self.inner.in_flight_requests,
+            Gauge::PendingResponses => &self.inner.pending_responses,
+            Gauge::InFlightSlots => &self.inner.in_flight_slots,
+            _ => unreachable!(),
+        };
+        let value = add(counter, delta);
+        if let Some(ref recorder) = config.metrics {
+            recorder.record_gauge(gauge, None, value as u64);
+        }
+    }
+}
+
+/// Adds `delta` to `counter`, returning the new value.
+fn add(counter: &AtomicUsize, delta: isize) -> usize {
+    if delta >= 0 {
+        counter.fetch_add(delta as usize, Ordering::Relaxed) + delta as usize
+    } else {
+        counter.fetch_sub(-delta as usize, Ordering::Relaxed) - (-delta as usize)
+    }
+}
+
+/// A [`Channel`] that contributes to server [`Stats`].
+#[derive(Debug)]
+pub struct StatsChannel<C>
+where
+    C: Channel,
+{
+    inner: C,
+    stats: Stats,
+    key: Option<String>,
+    /// The number of in-flight requests last contributed to the stats.
+    in_flight_requests: usize,
+    /// The number of responses written since the channel was last flushed.
+    pending_responses: usize,
+    /// The number of in-flight slots last contributed to the stats.
+    in_flight_slots: usize,
+}
+
+impl<C> StatsChannel<C>
+where
+    C: Channel,
+{
+    unsafe_pinned!(inner: C);
+    unsafe_unpinned!(in_flight_requests: usize);
+    unsafe_unpinned!(pending_responses: usize);
+    unsafe_unpinned!(in_flight_slots: usize);
+
+    /// Returns a channel contributing to `stats`.
+    pub fn new(inner: C, stats: Stats) -> Self {
+        let key = inner.filter_key();
+        stats.channel_opened(key.as_ref().map(String::as_str), inner.config());
+        StatsChannel {
+            inner,
+            stats,
+            key,
+            in_flight_requests: 0,
+            pending_responses: 0,
+            in_flight_slots: 0,
+        }
+    }
+
+    /// Returns the inner channel.
+    pub fn get_ref(&self) -> &C {
+        &self.inner
+    }
+
+    /// Brings the stats in line with the inner channel's in-flight requests, which can change
+    /// whenever the channel is read from or written to.
+    fn sync_in_flight_requests(mut self: Pin<&mut Self>) {
+        let current = self.as_mut().inner().in_flight_requests();
+        let previous = std::mem::replace(self.as_mut().in_flight_requests(), current);
+        self.stats.add_gauge(
+            Gauge::InFlightRequests,
+            current as isize - previous as isize,
+            self.inner.config(),
+        );
+        let current = self.inner.in_flight_capacity();
+        let previous = std::mem::replace(self.as_mut().in_flight_slots(), current);
+        self.stats.add_gauge(
+            Gauge::InFlightSlots,
+            current as isize - previous as isize,
+            self.inner.config(),
+        );
+    }
+}
+
+impl<C> Drop for StatsChannel<C>
+where
+    C: Channel,
+{
+    fn drop(&mut self) {
+        let config = self.inner.config();
+        self.stats.add_gauge(
+            Gauge::InFlightRequests,
+            -(self.in_flight_requests as isize),
+            config,
+        );
+        self.stats.add_gauge(
+            Gauge::PendingResponses,
+            -(self.pending_responses as isize),
+            config,
+        );
+        self.stats.add_gauge(
+            Gauge::InFlightSlots,
+            -(self.in_flight_slots as isize),
+            config,
+        );
+        self.stats
+            .channel_closed(self.key.as_ref().map(String::as_str), config);
+    }
+}
+
+impl<C> Stream for StatsChannel<C>
+where
+    C: Channel,
+{
+    type Item = <C as Stream>::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let next = self.as_mut().inner().poll_next(cx);
+        self.sync_in_flight_requests();
+        next
+    }
+}
+
+impl<C> Sink<Response<<C as Channel>::Resp>> for StatsChannel<C>
+where
+    C: Channel,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(
+        mut self: Pin<&mut Self>,
+        response: Response<<C as Channel>::Resp>,
+    ) -> io::Result<()> {
+        self.as_mut().inner().start_send(response)?;
+        *self.as_mut().pending_responses() += 1;
+        self.stats
+            .add_gauge(Gauge::PendingResponses, 1, self.inner.config());
+        self.sync_in_flight_requests();
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().inner().poll_flush(cx)?);
+        let flushed = std::mem::replace(self.as_mut().pending_responses(), 0);
+        self.stats.add_gauge(
+            Gauge::PendingResponses,
+            -(flushed as isize),
+            self.inner.config(),
+        );
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<C> AsRef<C> for StatsChannel<C>
+where
+    C: Channel,
+{
+    fn as_ref(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C> Channel for StatsChannel<C>
+where
+    C: Channel,
+{
+    type Req = <C as Channel>::Req;
+    type Resp = <C as Channel>::Resp;
+
+    fn config(&self) -> &Config {
+        self.inner.config()
+    }
+
+    fn in_flight_requests(self: Pin<&mut Self>) -> usize {
+        self.inner().in_flight_requests()
+    }
+
+    fn start_request(mut self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
+        let abort_registration = self.as_mut().inner().start_request(request_id);
+        self.sync_in_flight_requests();
+        abort_registration
+    }
+
+    fn track_request(mut self: Pin<&mut Self>, request_id: u64) -> InFlightSlot {
+        let slot = self.as_mut().inner().track_request(request_id);
+        self.sync_in_flight_requests();
+        slot
+    }
+
+    fn in_flight_capacity(&self) -> usize {
+        self.inner.in_flight_capacity()
+    }
+
+    fn filter_key(&self) -> Option<String> {
+        self.key.clone()
+    }
+
+    fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        self.inner.peer_identity()
+    }
+}
+
+/// A stream of channels contributing to server [`Stats`].
+#[derive(Debug)]
+pub struct StatsStream<S> {
+    inner: S,
+    stats: Stats,
+}
+
+impl<S> StatsStream<S>
+where
+    S: Stream,
+    <S as Stream>::Item: Channel,
+{
+    unsafe_pinned!(inner: S);
+
+    pub(crate) fn new(inner: S, stats: Stats) -> Self {
+        StatsStream { inner, stats }
+    }
+}
+
+impl<S> Stream for StatsStream<S>
+where
+    S: Stream,
+    <S as Stream>::Item: Channel,
+{
+    type Item = StatsChannel<<S as Stream>::Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match ready!(self.as_mut().inner().poll_next(cx)) {
+            Some(channel) => Poll::Ready(Some(StatsChannel::new(channel, self.stats.clone()))),
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{
+    metrics::InMemoryRecorder,
+    testing::{self, FakeChannel},
+};
+#[cfg(test)]
+use pin_utils::pin_mut;
+
+#[test]
+fn stats_track_channels_requests_and_responses() {
+    let stats = Stats::new();
+    let recorder = Arc::new(InMemoryRecorder::new());
+    let mut inner = FakeChannel::default::<isize, isize>();
+    inner.config.metrics = Some(recorder.clone());
+    inner.push_req(0, 1);
+    inner.push_req(1, 2);
+
+    {
+        let channel = StatsChannel::new(inner, stats.clone());
+        pin_mut!(channel);
+        assert_eq!(stats.channels(), 1);
+
+        for _ in 0..2 {
+            match channel.as_mut().poll_next(&mut testing::cx()) {
+                Poll::Ready(Some(Ok(req))) => channel.as_mut().start_request(req.id),
+                poll => panic!("Expected a request, got {:?}", poll.map(|_| ())),
+            };
+        }
+        assert_eq!(stats.in_flight_requests(), 2);
+
+        channel
+            .as_mut()
+            .start_send(Response {
+                request_id: 0,
+                message: Ok(1),
+                partial: false,
+                _non_exhaustive: (),
+            })
+            .unwrap();
+        assert_eq!(stats.in_flight_requests(), 1);
+        assert_eq!(stats.pending_responses(), 1);
+
+        assert!(channel.as_mut().poll_flush(&mut testing::cx()).is_ready());
+        assert_eq!(stats.pending_responses(), 0);
+        assert_eq!(recorder.gauges()[&(Gauge::InFlightRequests, None)], 1);
+    }
+
+    assert_eq!(stats.channels(), 0);
+    assert_eq!(stats.in_flight_requests(), 0);
+    assert_eq!(recorder.gauges()[&(Gauge::OpenChannels, None)], 0);
+}
@@ -0,0 +1,127 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Counts the live tasks of a server by what they are doing, so that load can be attributed to
+//! specific RPC methods and connections.
+//!
+//! Each [`RequestHandler`](super::RequestHandler) and [`ClientHandler`](super::ClientHandler) has
+//! a [name](super::RequestHandler::name) describing it, which it's
+//! [spawned](crate::runtime::Spawn::spawn_named) with. When a server's
+//! [`Config::task_counts`](super::Config::task_counts) is set, the handlers alive at any moment are
+//! counted by kind: `<service>.<method>` for requests, and `connection <peer>` for connections,
+//! or just `connection` for connections from unidentified peers.
+
+use fnv::FnvHashMap;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// The number of live tasks, by kind.
+#[derive(Clone, Debug, Default)]
+pub struct TaskCounts {
+    counts: Arc<Mutex<FnvHashMap<String, usize>>>,
+}
+
+impl TaskCounts {
+    /// Returns new counts with no tasks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of live tasks of each kind. Kinds with no live tasks are omitted.
+    pub fn get(&self) -> BTreeMap<String, usize> {
+        self.counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(kind, &count)| (kind.clone(), count))
+            .collect()
+    }
+
+    /// Counts a task of kind `kind` until the returned guard is dropped.
+    pub(crate) fn start(&self, kind: String) -> TaskGuard {
+        *self.counts.lock().unwrap().entry(kind.clone()).or_insert(0) += 1;
+        TaskGuard {
+            counts: self.clone(),
+            kind,
+        }
+    }
+}
+
+/// Counts a live task.
+#[derive(Debug)]
+pub(crate) struct TaskGuard {
+    counts: TaskCounts,
+    kind: String,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.counts.lock().unwrap();
+        let remove = match counts.get_mut(&self.kind) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if remove {
+            counts.remove(&self.kind);
+        }
+    }
+}
+
+/// Returns the kind of a task handling a request to `method` of `service`.
+pub(crate) fn request_kind(service: Option<&str>, method: Option<&str>) -> String {
+    format!(
+        "{}.{}",
+        service.unwrap_or("unknown"),
+        method.unwrap_or("unknown")
+    )
+}
+
+/// The name of a task handling a request, `<service>.<method>#<request id>`, formatted only if
+/// it's used.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RequestName {
+    pub service: Option<&'static str>,
+    pub method: Option<&'static str>,
+    pub request_id: u64,
+}
+
+impl fmt::Display for RequestName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}#{}",
+            self.service.unwrap_or("unknown"),
+            self.method.unwrap_or("unknown"),
+            self.request_id
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_live_tasks() {
+        let counts = TaskCounts::new();
+        let a = counts.start(request_kind(Some("Kv"), Some("get")));
+        let b = counts.start(request_kind(Some("Kv"), Some("get")));
+        let c = counts.start("connection".into());
+        assert_eq!(counts.get()["Kv.get"], 2);
+        assert_eq!(counts.get()["connection"], 1);
+        drop((a, c));
+        assert_eq!(counts.get()["Kv.get"], 1);
+        assert!(!counts.get().contains_key("connection"));
+        drop(b);
+        assert!(counts.get().is_empty());
+    }
+}
@@ -1,8 +1,10 @@
 use crate::server::{Channel, Config};
 use crate::{context, Request, Response};
 use fnv::FnvHashSet;
-use futures::future::{AbortHandle, AbortRegistration};
-use futures::{Sink, Stream};
+use futures::{
+    future::{AbortHandle, AbortRegistration},
+    Sink, Stream,
+};
 use futures_test::task::noop_waker_ref;
 use pin_utils::{unsafe_pinned, unsafe_unpinned};
 use std::collections::VecDeque;
@@ -43,9 +45,11 @@ impl<In, Resp> Sink<Response<Resp>> for FakeChannel<In, Response<Resp>> {
     }
 
     fn start_send(mut self: Pin<&mut Self>, response: Response<Resp>) -> Result<(), Self::Error> {
-        self.as_mut()
-            .in_flight_requests()
-            .remove(&response.request_id);
+        if !response.partial {
+            self.as_mut()
+                .in_flight_requests()
+                .remove(&response.request_id);
+        }
         self.sink().start_send(response).map_err(|e| match e {})
     }
 
@@ -85,11 +89,18 @@ impl<Req, Resp> FakeChannel<io::Result<Request<Req>>, Response<Resp>> {
             context: context::Context {
                 deadline: SystemTime::UNIX_EPOCH,
                 trace_context: Default::default(),
+                metadata: Default::default(),
+                baggage: Default::default(),
+                time_remaining: None,
+                request_id: None,
+                peer_identity: None,
                 _non_exhaustive: (),
             },
             id,
             message,
+            one_way: false,
             _non_exhaustive: (),
+            encoded: None,
         }));
     }
 }
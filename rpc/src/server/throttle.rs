@@ -1,14 +1,13 @@
-use super::{Channel, Config};
-use crate::{Response, ServerError};
+use super::{Channel, Config, InFlightSlot};
+use crate::{context::PeerIdentity, event::Event, Response, ServerError};
 use futures::{
     future::AbortRegistration,
     prelude::*,
     ready,
     task::{Context, Poll},
 };
-use log::debug;
 use pin_utils::{unsafe_pinned, unsafe_unpinned};
-use std::{io, pin::Pin};
+use std::{io, pin::Pin, sync::Arc};
 
 /// A [`Channel`] that limits the number of concurrent
 /// requests by throttling.
@@ -54,20 +53,27 @@ where
 
             match ready!(self.as_mut().inner().poll_next(cx)?) {
                 Some(request) => {
-                    debug!(
-                        "[{}] Client has reached in-flight request limit ({}/{}).",
-                        request.context.trace_id(),
-                        self.as_mut().in_flight_requests(),
-                        self.as_mut().max_in_flight_requests(),
-                    );
+                    let in_flight_requests = self.as_mut().in_flight_requests();
+                    self.config().events.event(&Event::RequestThrottled {
+                        trace_id: *request.context.trace_id(),
+                        request_id: request.id,
+                        one_way: request.one_way,
+                        in_flight_requests,
+                        limit: self.max_in_flight_requests,
+                    });
+                    if request.one_way {
+                        continue;
+                    }
 
                     self.as_mut().start_send(Response {
                         request_id: request.id,
                         message: Err(ServerError {
                             kind: io::ErrorKind::WouldBlock,
                             detail: Some("Server throttled the request.".into()),
+                            unauthenticated: false,
                             _non_exhaustive: (),
                         }),
+                        partial: false,
                         _non_exhaustive: (),
                     })?;
                 }
@@ -125,6 +131,22 @@ where
     fn start_request(self: Pin<&mut Self>, request_id: u64) -> AbortRegistration {
         self.inner().start_request(request_id)
     }
+
+    fn track_request(self: Pin<&mut Self>, request_id: u64) -> InFlightSlot {
+        self.inner().track_request(request_id)
+    }
+
+    fn in_flight_capacity(&self) -> usize {
+        self.inner.in_flight_capacity()
+    }
+
+    fn filter_key(&self) -> Option<String> {
+        self.inner.filter_key()
+    }
+
+    fn peer_identity(&self) -> Option<Arc<PeerIdentity>> {
+        self.inner.peer_identity()
+    }
 }
 
 /// A stream of throttling channels.
@@ -317,6 +339,7 @@ fn throttler_start_send() {
         .start_send(Response {
             request_id: 0,
             message: Ok(1),
+            partial: false,
             _non_exhaustive: (),
         })
         .unwrap();
@@ -326,6 +349,7 @@ fn throttler_start_send() {
         Some(&Response {
             request_id: 0,
             message: Ok(1),
+            partial: false,
             _non_exhaustive: ()
         })
     );
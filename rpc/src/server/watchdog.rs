@@ -0,0 +1,259 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Reports requests that have been in flight for too long.
+//!
+//! A server watches its requests when its [`Config::watchdog`](super::Config::watchdog) is set.
+//! Besides how long each long call has run, the watchdog reports when its handler was last polled
+//! and whether it has been woken since. A handler that was woken but not polled points to a
+//! starved executor; one that's still waiting to be woken points to a stuck handler.
+
+use crate::{
+    event::{Event, EventSink},
+    runtime::{self, Timer},
+};
+use fnv::FnvHashMap;
+use futures::task::{self, ArcWake, Waker};
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use trace::TraceId;
+
+/// A request that has been in flight longer than the watchdog's threshold.
+#[derive(Clone, Debug)]
+pub struct LongCall {
+    /// The trace of the request.
+    pub trace_id: TraceId,
+    /// The ID of the request, unique to its channel.
+    pub request_id: u64,
+    /// The name of the method invoked, or `"unknown"` if the server can't
+    /// [name it](super::Serve::method_name).
+    pub method: &'static str,
+    /// The authenticated principal of the client, or the channel's
+    /// [filter key](super::Channel::filter_key), if either is known.
+    pub peer: Option<String>,
+    /// How long the request has been in flight.
+    pub elapsed: Duration,
+    /// How long ago the handler was last polled.
+    pub since_last_poll: Duration,
+    /// Whether the handler has been woken but not yet polled.
+    pub awaiting_poll: bool,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+/// Tracks in-flight requests and reports those running longer than a threshold.
+///
+/// Clones share the same requests, so a handle kept outside the server can report on them.
+#[derive(Clone)]
+pub struct Watchdog {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    threshold: Duration,
+    timer: Arc<dyn Timer>,
+    next_id: AtomicU64,
+    calls: Mutex<FnvHashMap<u64, Arc<Call>>>,
+}
+
+struct Call {
+    trace_id: TraceId,
+    request_id: u64,
+    method: &'static str,
+    peer: Option<String>,
+    started: Instant,
+    last_polled: Mutex<Instant>,
+    woken: AtomicBool,
+}
+
+impl Watchdog {
+    /// Returns a watchdog that reports requests in flight for longer than `threshold`.
+    pub fn new(threshold: Duration) -> Self {
+        Watchdog::with_timer(threshold, runtime::default())
+    }
+
+    /// Returns a watchdog that measures how long requests have been in flight with `timer`.
+    pub fn with_timer(threshold: Duration, timer: Arc<dyn Timer>) -> Self {
+        Watchdog {
+            inner: Arc::new(Inner {
+                threshold,
+                timer,
+                next_id: AtomicU64::new(0),
+                calls: Mutex::new(FnvHashMap::default()),
+            }),
+        }
+    }
+
+    /// Returns the requests currently in flight for longer than the threshold, longest first.
+    pub fn long_calls(&self) -> Vec<LongCall> {
+        let now = self.inner.timer.now();
+        let mut long_calls: Vec<_> = self
+            .inner
+            .calls
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|call| now.duration_since(call.started) > self.inner.threshold)
+            .map(|call| LongCall {
+                trace_id: call.trace_id,
+                request_id: call.request_id,
+                method: call.method,
+                peer: call.peer.clone(),
+                elapsed: now.duration_since(call.started),
+                since_last_poll: now.duration_since(*call.last_polled.lock().unwrap()),
+                awaiting_poll: call.woken.load(Ordering::Relaxed),
+                _non_exhaustive: (),
+            })
+            .collect();
+        long_calls.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+        long_calls
+    }
+
+    /// Reports each long call to `events`.
+    pub fn report(&self, events: &dyn EventSink) {
+        for call in self.long_calls() {
+            events.event(&Event::LongCall {
+                trace_id: call.trace_id,
+                request_id: call.request_id,
+                method: call.method,
+                peer: call.peer.as_ref().map(String::as_str),
+                elapsed: call.elapsed,
+                since_last_poll: call.since_last_poll,
+                awaiting_poll: call.awaiting_poll,
+            });
+        }
+    }
+
+    /// Reports long calls to `events` every `period`, forever.
+    pub async fn run(self, period: Duration, events: Arc<dyn EventSink>) {
+        loop {
+            self.inner.timer.delay_for(period).await;
+            self.report(&*events);
+        }
+    }
+
+    /// Starts watching a request, until the returned handle is dropped.
+    pub(crate) fn watch(
+        &self,
+        trace_id: TraceId,
+        request_id: u64,
+        method: &'static str,
+        peer: Option<String>,
+    ) -> Watch {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let now = self.inner.timer.now();
+        let call = Arc::new(Call {
+            trace_id,
+            request_id,
+            method,
+            peer,
+            started: now,
+            last_polled: Mutex::new(now),
+            woken: AtomicBool::new(false),
+        });
+        self.inner.calls.lock().unwrap().insert(id, call.clone());
+        Watch {
+            inner: self.inner.clone(),
+            id,
+            call,
+            waker: None,
+        }
+    }
+}
+
+impl fmt::Debug for Watchdog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("threshold", &self.inner.threshold)
+            .finish()
+    }
+}
+
+/// A request being watched. Stops watching the request when dropped.
+pub(crate) struct Watch {
+    inner: Arc<Inner>,
+    id: u64,
+    call: Arc<Call>,
+    /// The task waker the handler was last polled with, and the waker wrapping it.
+    waker: Option<(Waker, Waker)>,
+}
+
+impl Watch {
+    /// Records that the handler is being polled, returning a waker that records when the handler
+    /// is woken before waking `waker`. The wrapping waker is reused for as long as the handler is
+    /// polled by the same task.
+    pub(crate) fn polled(&mut self, waker: &Waker) -> Waker {
+        *self.call.last_polled.lock().unwrap() = self.inner.timer.now();
+        self.call.woken.store(false, Ordering::Relaxed);
+        match self.waker {
+            Some((ref task, ref wrapped)) if task.will_wake(waker) => wrapped.clone(),
+            _ => {
+                let wrapped = task::waker(Arc::new(RecordWake {
+                    call: self.call.clone(),
+                    waker: waker.clone(),
+                }));
+                self.waker = Some((waker.clone(), wrapped.clone()));
+                wrapped
+            }
+        }
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        self.inner.calls.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl fmt::Debug for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Watch").field("id", &self.id).finish()
+    }
+}
+
+struct RecordWake {
+    call: Arc<Call>,
+    waker: Waker,
+}
+
+impl ArcWake for RecordWake {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.call.woken.store(true, Ordering::Relaxed);
+        arc_self.waker.wake_by_ref();
+    }
+}
+
+#[test]
+fn reports_long_calls() {
+    use futures_test::task::noop_waker_ref;
+
+    let watchdog = Watchdog::new(Duration::from_millis(0));
+    let mut watch = watchdog.watch(TraceId::default(), 7, "get", Some("alice".into()));
+    std::thread::sleep(Duration::from_millis(1));
+
+    let calls = watchdog.long_calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].request_id, 7);
+    assert_eq!(calls[0].peer.as_ref().map(String::as_str), Some("alice"));
+    assert!(!calls[0].awaiting_poll);
+
+    watch.polled(noop_waker_ref()).wake();
+    assert!(watchdog.long_calls()[0].awaiting_poll);
+
+    // Polling by the same task clears the wake and reuses the waker.
+    let waker = watch.polled(noop_waker_ref());
+    assert!(!watchdog.long_calls()[0].awaiting_poll);
+    assert!(waker.will_wake(&watch.polled(noop_waker_ref())));
+
+    drop(watch);
+    assert!(watchdog.long_calls().is_empty());
+}
@@ -0,0 +1,507 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Deterministic tests of behavior that depends on time.
+//!
+//! A [`MockTime`] runs futures on the current thread against a virtual clock that stands still
+//! until the test advances it. Timers, such as request deadlines and the
+//! [`tokio_timer`] delays and timeouts that clients, servers, and services set, fire as the virtual
+//! clock reaches them, in order, without the test sleeping. While futures run, the
+//! [`tokio_timer::clock`] is virtual, and tasks they spawn with `tokio::spawn`, such as a client's
+//! dispatch or a server's request handlers, run against the virtual clock too. Clients and servers
+//! measure deadlines with the timer of their config, so for their deadlines to be virtual, give
+//! them the clock's [`timer`](MockTime::timer).
+//!
+//! ```
+//! use futures::channel::oneshot;
+//! use std::time::Duration;
+//! use tarpc_lib::testing::MockTime;
+//!
+//! let mut time = MockTime::new();
+//! let (tx, rx) = oneshot::channel();
+//! time.spawn(async move {
+//!     tokio_timer::delay_for(Duration::from_secs(60)).await;
+//!     tx.send(()).unwrap();
+//! });
+//! time.advance(Duration::from_secs(59));
+//! // An hour passes in an instant.
+//! time.block_on(async move {
+//!     tokio_timer::delay_for(Duration::from_secs(3600)).await;
+//!     rx.await.unwrap();
+//! });
+//! assert_eq!(time.elapsed(), Duration::from_secs(59 + 3600));
+//! ```
+//!
+//! To check that an RPC fits its latency budget, e.g. over a transport that
+//! [simulates](crate::transport::testing::SimulatedLink) a slow network,
+//! [`assert_within_deadline`](MockTime::assert_within_deadline) runs it and checks that it
+//! completed within its context's deadline, and
+//! [`assert_deadline_exceeded`](MockTime::assert_deadline_exceeded) checks that it failed with
+//! a deadline error, in time, when it couldn't.
+
+#[cfg(all(feature = "client", feature = "server"))]
+pub mod scenario;
+
+use crate::{
+    context,
+    error::{Classify, ErrorKind},
+    runtime::{self, Delay},
+};
+use futures::{
+    executor::{LocalPool, LocalSpawner},
+    prelude::*,
+    task::{self, ArcWake, LocalSpawnExt},
+};
+use std::{
+    cell::Cell,
+    fmt,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio_executor::{
+    park::{Park, Unpark},
+    Executor, SpawnError,
+};
+use tokio_timer::{
+    clock::{self, Clock, Now},
+    timer::Handle,
+    Timer,
+};
+
+/// How late the tokio timer may fire a timer, which is due between two of its ticks.
+const TIMER_RESOLUTION: Duration = Duration::from_millis(1);
+
+/// Runs futures on the current thread against a virtual clock.
+///
+/// Time only passes when the test calls [`advance`](MockTime::advance), or when
+/// [`block_on`](MockTime::block_on) is waiting on nothing but timers.
+pub struct MockTime {
+    pool: LocalPool,
+    /// The number of tasks spawned by the futures running against the clock.
+    spawned: Cell<usize>,
+    timer: Timer<RecordPark, Clock>,
+    clock: Clock,
+    now: VirtualNow,
+    start: Instant,
+    epoch: SystemTime,
+}
+
+impl MockTime {
+    /// Returns a virtual clock that starts at the current time.
+    pub fn new() -> Self {
+        let start = Instant::now();
+        let now = VirtualNow(Arc::new(Mutex::new(start)));
+        let clock = Clock::new_with_now(now.clone());
+        MockTime {
+            pool: LocalPool::new(),
+            spawned: Cell::new(0),
+            timer: Timer::new_with_now(RecordPark::default(), clock.clone()),
+            clock,
+            now,
+            start,
+            epoch: SystemTime::now(),
+        }
+    }
+
+    /// Returns the current virtual instant.
+    pub fn now(&self) -> Instant {
+        *self.now.0.lock().unwrap()
+    }
+
+    /// Returns the virtual time passed since the clock started.
+    pub fn elapsed(&self) -> Duration {
+        self.now() - self.start
+    }
+
+    /// Returns the current virtual system time, which request deadlines are measured against.
+    pub fn system_now(&self) -> SystemTime {
+        self.epoch + self.elapsed()
+    }
+
+    /// Returns a timer that reads the virtual clock, for the configs of clients and servers
+    /// running against it. Its delays only fire while futures run against the clock.
+    pub fn timer(&self) -> Arc<dyn runtime::Timer> {
+        Arc::new(VirtualTimer {
+            now: self.now.clone(),
+            start: self.start,
+            epoch: self.epoch,
+        })
+    }
+
+    /// Spawns a task that runs against the virtual clock. It makes progress whenever the clock
+    /// is advanced or another future is run to completion.
+    pub fn spawn(&self, task: impl Future<Output = ()> + 'static) {
+        self.pool
+            .spawner()
+            .spawn_local(task)
+            .expect("the pool is owned by this MockTime, so it can't have shut down")
+    }
+
+    /// Calls `f` with the virtual clock in effect on this thread, e.g. to set a timer that fires
+    /// in virtual time.
+    pub fn enter<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let timer = self.timer.handle();
+        let spawner = Spawner {
+            pool: self.pool.spawner(),
+            spawned: &self.spawned,
+        };
+        with_virtual_time(spawner, &self.clock, &timer, f)
+    }
+
+    /// Advances the virtual clock by `duration`, firing each timer that comes due in order, and
+    /// running the tasks it wakes until they're blocked, before moving on to the next.
+    pub fn advance(&mut self, duration: Duration) {
+        let target = self.now() + duration;
+        while let Some(next) = self.settle() {
+            let at = self.now() + next;
+            if at > target {
+                break;
+            }
+            self.now.set(at);
+        }
+        self.now.set(target);
+        self.settle();
+    }
+
+    /// Runs spawned tasks until they're blocked, without advancing the clock.
+    pub fn run_until_stalled(&mut self) {
+        self.settle();
+    }
+
+    /// Runs `future` to completion, along with any spawned tasks. Whenever everything is waiting
+    /// on timers, the clock skips ahead to the next one.
+    ///
+    /// # Panics
+    ///
+    /// If `future` is blocked on something other than a timer, such as a task that isn't running
+    /// against this clock, it can never complete, so `block_on` panics.
+    pub fn block_on<F: Future>(&mut self, future: F) -> F::Output {
+        let woken = Arc::new(WakeFlag(AtomicBool::new(true)));
+        let waker = task::waker(woken.clone());
+        futures::pin_mut!(future);
+        loop {
+            if woken.0.swap(false, Ordering::SeqCst) {
+                let poll = self.enter(|| future.as_mut().poll(&mut Context::from_waker(&waker)));
+                if let Poll::Ready(output) = poll {
+                    return output;
+                }
+            }
+            let next = self.settle();
+            if woken.0.load(Ordering::SeqCst) {
+                continue;
+            }
+            match next {
+                Some(next) => {
+                    let at = self.now() + next;
+                    self.now.set(at);
+                }
+                None => {
+                    panic!("the future can never complete: it's blocked, and no timers are pending")
+                }
+            }
+        }
+    }
+
+    /// Runs `call`, an RPC made with `ctx`, to completion like [`block_on`](MockTime::block_on),
+    /// and returns its output.
+    ///
+    /// # Panics
+    ///
+    /// If the call fails, or completes later than the deadline of `ctx`, measured in virtual
+    /// time.
+    pub fn assert_within_deadline<F, T, E>(&mut self, ctx: &context::Context, call: F) -> T
+    where
+        F: Future<Output = Result<T, E>>,
+        E: fmt::Debug,
+    {
+        let budget = self.budget(ctx);
+        let start = self.now();
+        let result = self.block_on(call);
+        let took = self.now() - start;
+        match result {
+            Ok(output) if took <= budget => output,
+            Ok(_) => panic!(
+                "the call completed in {:?}, over its {:?} budget",
+                took, budget
+            ),
+            Err(e) => panic!(
+                "the call failed after {:?} of its {:?} budget: {:?}",
+                took, budget, e
+            ),
+        }
+    }
+
+    /// Runs `call`, an RPC made with `ctx`, to completion like [`block_on`](MockTime::block_on),
+    /// and returns the error it fails with.
+    ///
+    /// # Panics
+    ///
+    /// Unless the call fails with an error [classified](crate::error::Classify) as
+    /// [exceeding its deadline](ErrorKind::Deadline) by the deadline of `ctx`, measured in virtual
+    /// time. Since timers fire on the millisecond tick after they're due, the error may come up to
+    /// a millisecond late.
+    pub fn assert_deadline_exceeded<F, T, E>(&mut self, ctx: &context::Context, call: F) -> E
+    where
+        F: Future<Output = Result<T, E>>,
+        E: Classify + fmt::Debug,
+    {
+        let budget = self.budget(ctx);
+        let start = self.now();
+        let result = self.block_on(call);
+        let took = self.now() - start;
+        match result {
+            Err(e) if e.classify() == ErrorKind::Deadline && took <= budget + TIMER_RESOLUTION => e,
+            Err(e) if e.classify() == ErrorKind::Deadline => panic!(
+                "the call's deadline was exceeded {:?} after its {:?} budget ran out: {:?}",
+                took - budget,
+                budget,
+                e
+            ),
+            Err(e) => panic!(
+                "expected the call to exceed its {:?} budget, but it failed after {:?}: {:?}",
+                budget, took, e
+            ),
+            Ok(_) => panic!(
+                "expected the call to exceed its {:?} budget, but it completed in {:?}",
+                budget, took
+            ),
+        }
+    }
+
+    /// Returns the virtual time left until the deadline of `ctx`.
+    fn budget(&self, ctx: &context::Context) -> Duration {
+        ctx.deadline
+            .duration_since(self.system_now())
+            .unwrap_or_else(|_| Duration::from_secs(0))
+    }
+
+    /// Runs tasks until they're blocked, and fires the timers that are due, until no more are
+    /// due. Returns the time until the next timer is due, if any are pending.
+    fn settle(&mut self) -> Option<Duration> {
+        loop {
+            let timer = self.timer.handle();
+            let spawner = Spawner {
+                pool: self.pool.spawner(),
+                spawned: &self.spawned,
+            };
+            let pool = &mut self.pool;
+            let spawned = &self.spawned;
+            with_virtual_time(spawner, &self.clock, &timer, || {
+                // The pool stops once its tasks are blocked, without polling any they spawned,
+                // so run it again until they stop spawning tasks.
+                loop {
+                    spawned.set(0);
+                    pool.run_until_stalled();
+                    if spawned.get() == 0 {
+                        break;
+                    }
+                }
+            });
+            // Registers new timers and fires those that are due, recording when the next is.
+            self.timer.turn(None).unwrap();
+            match self.timer.get_park_mut().next.take() {
+                Some(next) if next == Duration::from_secs(0) => continue,
+                next => return next,
+            }
+        }
+    }
+}
+
+impl Default for MockTime {
+    fn default() -> Self {
+        MockTime::new()
+    }
+}
+
+impl fmt::Debug for MockTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockTime")
+            .field("elapsed", &self.elapsed())
+            .finish()
+    }
+}
+
+/// Calls `f` with the virtual clock and timer, and `spawner`, as the defaults on this thread.
+fn with_virtual_time<R>(
+    mut spawner: Spawner,
+    clock: &Clock,
+    timer: &Handle,
+    f: impl FnOnce() -> R,
+) -> R {
+    tokio_executor::with_default(&mut spawner, || {
+        clock::with_default(clock, || {
+            let _timer = tokio_timer::set_default(timer);
+            f()
+        })
+    })
+}
+
+/// Spawns the tasks spawned by futures running against the virtual clock onto the same pool.
+struct Spawner<'a> {
+    pool: LocalSpawner,
+    spawned: &'a Cell<usize>,
+}
+
+impl Executor for Spawner<'_> {
+    fn spawn(
+        &mut self,
+        future: Pin<Box<dyn Future<Output = ()> + Send>>,
+    ) -> Result<(), SpawnError> {
+        self.spawned.set(self.spawned.get() + 1);
+        self.pool
+            .spawn_local(future)
+            .map_err(|_| SpawnError::shutdown())
+    }
+}
+
+/// The virtual clock's current instant, shared by the clock and the timer.
+#[derive(Clone, Debug)]
+struct VirtualNow(Arc<Mutex<Instant>>);
+
+impl VirtualNow {
+    fn set(&self, now: Instant) {
+        *self.0.lock().unwrap() = now;
+    }
+}
+
+impl Now for VirtualNow {
+    fn now(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Reads the virtual clock of a [`MockTime`], returned by [`MockTime::timer`].
+#[derive(Debug)]
+struct VirtualTimer {
+    now: VirtualNow,
+    start: Instant,
+    epoch: SystemTime,
+}
+
+impl runtime::Timer for VirtualTimer {
+    fn now(&self) -> Instant {
+        self.now.now()
+    }
+
+    fn delay(&self, deadline: Instant) -> Delay {
+        Delay::tokio(deadline)
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.epoch + (self.now() - self.start)
+    }
+}
+
+/// Never blocks, recording instead how long the timer asked to wait for its next timer.
+#[derive(Debug, Default)]
+struct RecordPark {
+    next: Option<Duration>,
+}
+
+impl Park for RecordPark {
+    type Unpark = NoUnpark;
+    type Error = ();
+
+    fn unpark(&self) -> NoUnpark {
+        NoUnpark
+    }
+
+    fn park(&mut self) -> Result<(), ()> {
+        self.next = None;
+        Ok(())
+    }
+
+    fn park_timeout(&mut self, duration: Duration) -> Result<(), ()> {
+        self.next = Some(duration);
+        Ok(())
+    }
+}
+
+/// The timer is only turned by its `MockTime`, so there's never a thread to wake.
+#[derive(Debug)]
+struct NoUnpark;
+
+impl Unpark for NoUnpark {
+    fn unpark(&self) {}
+}
+
+/// Records that a future was woken.
+struct WakeFlag(AtomicBool);
+
+impl ArcWake for WakeFlag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Timer as _;
+    use futures::channel::oneshot;
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn advance_fires_timers_in_order() {
+        let mut time = MockTime::new();
+        let fired = Rc::new(RefCell::new(vec![]));
+        for &secs in &[30, 10, 20] {
+            let fired = fired.clone();
+            time.spawn(async move {
+                tokio_timer::delay_for(Duration::from_secs(secs)).await;
+                fired.borrow_mut().push(secs);
+            });
+        }
+        time.run_until_stalled();
+        assert!(fired.borrow().is_empty());
+
+        time.advance(Duration::from_secs(15));
+        assert_eq!(*fired.borrow(), vec![10]);
+        time.advance(Duration::from_secs(15));
+        assert_eq!(*fired.borrow(), vec![10, 20, 30]);
+        assert_eq!(time.elapsed(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn deadlines_are_measured_in_virtual_time() {
+        let mut time = MockTime::new();
+        let timer = time.timer();
+        let deadline = timer.system_now() + Duration::from_secs(10);
+        let remaining = time.block_on(async {
+            timer.delay_for(Duration::from_secs(4)).await;
+            timer.time_until(deadline)
+        });
+        assert_eq!(remaining, Duration::from_secs(6));
+        assert_eq!(time.elapsed(), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn tokio_spawn_runs_against_the_virtual_clock() {
+        let mut time = MockTime::new();
+        let (tx, rx) = oneshot::channel();
+        time.enter(|| {
+            tokio_executor::spawn(async move {
+                tokio_timer::delay_for(Duration::from_secs(60)).await;
+                tx.send(tokio_timer::clock::now()).unwrap();
+            })
+        });
+        let start = time.now();
+        assert_eq!(time.block_on(rx).unwrap(), start + Duration::from_secs(60));
+    }
+
+    #[test]
+    #[should_panic(expected = "the future can never complete")]
+    fn block_on_panics_when_blocked_forever() {
+        let mut time = MockTime::new();
+        let (_tx, rx) = oneshot::channel::<()>();
+        let _ = time.block_on(rx);
+    }
+}
@@ -0,0 +1,490 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Scripted conversations between a client and an in-process server.
+//!
+//! A [`Scenario`] lists the calls a client is expected to make, in order, and how the server
+//! answers each. [Running](Scenario::run) it connects a client to a server that follows the
+//! script, hands the client to the code under test, and then checks that the client made the
+//! expected calls in the expected order, canceled the calls it was expected to cancel, and kept
+//! the expected number of calls in flight at once:
+//!
+//! ```ignore
+//! Scenario::new()
+//!     .expect_call("hello")
+//!     .respond(WorldResponse::Hello("Hello, Tim!".into()))
+//!     // The client gives up on a slow call, and doesn't wait for the next one to send it.
+//!     .expect_call("hello")
+//!     .expect_cancel()
+//!     .expect_call("goodbye")
+//!     .once_in_flight(2)
+//!     .respond(WorldResponse::Goodbye("Bye!".into()))
+//!     .run(|channel| code_under_test(WorldClient::from(channel)))
+//!     .await?;
+//! ```
+
+use crate::{
+    client, context,
+    schema::{Introspect, IntrospectResponse},
+    server::mock::{Answer, Handler, Serve},
+    ClientMessage, Request, Response, Transport,
+};
+use futures::prelude::*;
+use pin_utils::unsafe_pinned;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+/// The calls a client is expected to make, in order, and how the server answers each.
+pub struct Scenario<Req, Resp> {
+    config: client::Config,
+    expected: VecDeque<Expectation<Req, Resp>>,
+    max_in_flight: Option<usize>,
+}
+
+struct Expectation<Req, Resp> {
+    rpc: &'static str,
+    matcher: Option<Box<dyn Fn(&Req) -> bool + Send>>,
+    delay: Duration,
+    in_flight: usize,
+    outcome: Outcome<Req, Resp>,
+}
+
+enum Outcome<Req, Resp> {
+    Reply(Reply<Req, Resp>),
+    /// Never responds, expecting the client to cancel the request.
+    Cancel,
+}
+
+enum Reply<Req, Resp> {
+    /// Responds with the returned responses, all but the last of which are partial.
+    Respond(Box<dyn FnOnce(context::Context, Req) -> Vec<Resp> + Send>),
+    Fail(io::ErrorKind, String),
+}
+
+impl<Req, Resp> Scenario<Req, Resp> {
+    /// Returns a scenario in which the client is expected to make no calls.
+    pub fn new() -> Self {
+        Scenario {
+            config: client::Config::default(),
+            expected: VecDeque::new(),
+            max_in_flight: None,
+        }
+    }
+
+    /// Configures the client handed to the code under test, whose timer also times the delays of
+    /// the server's answers. Defaults to [`client::Config::default`].
+    pub fn client(mut self, config: client::Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Expects the client to never have more than `max` calls in flight at once.
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        self.max_in_flight = Some(max);
+        self
+    }
+
+    /// Expects the client's next call to be to `rpc`, named as in the service definition. The
+    /// expectation is added once it's given an outcome, e.g. by
+    /// [`respond`](ExpectCall::respond).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the service has no RPC named `rpc`.
+    pub fn expect_call(self, rpc: &'static str) -> ExpectCall<Req, Resp>
+    where
+        Req: Introspect,
+    {
+        if !Req::METHODS.iter().any(|method| method.name == rpc) {
+            panic!("the service has no RPC named `{}`", rpc);
+        }
+        ExpectCall {
+            scenario: self,
+            rpc,
+            matcher: None,
+            delay: Duration::from_secs(0),
+            in_flight: 1,
+        }
+    }
+
+    /// Returns a future that follows the scenario's script for the requests received over
+    /// `transport`, until the transport closes. It resolves to an error describing every way
+    /// the client strayed from the script, if it did.
+    pub fn serve<T>(self, transport: T) -> ScenarioServe<T, Req, Resp>
+    where
+        T: Transport<Response<Resp>, ClientMessage<Req>>,
+        Req: Introspect,
+        Resp: IntrospectResponse + Send + 'static,
+    {
+        let script = Script {
+            expected: self.expected,
+            max_in_flight: self.max_in_flight,
+            waiting: HashMap::new(),
+            canceling: HashMap::new(),
+            peak_in_flight: 0,
+            violations: vec![],
+        };
+        ScenarioServe {
+            serve: Serve::new(transport, script, self.config.timer),
+        }
+    }
+
+    /// Connects a client to a server following the scenario's script over an in-process
+    /// [channel](crate::transport::channel), and runs the future `f` returns for the client.
+    ///
+    /// Once the future completes and the client's connection closes, returns its output, or an
+    /// error describing every way the client strayed from the script. The server answers calls
+    /// that stray with [`ServerError`]s of kind [`Other`](io::ErrorKind::Other).
+    #[cfg(feature = "tokio1")]
+    pub async fn run<F, Fut>(self, f: F) -> io::Result<Fut::Output>
+    where
+        F: FnOnce(client::Channel<Req, Resp>) -> Fut,
+        Fut: Future,
+        Req: Introspect + Send + 'static,
+        Resp: IntrospectResponse + Send + 'static,
+    {
+        let (client, server) = crate::transport::channel::unbounded();
+        let config = self.config.clone();
+        let (serve, served) = self.serve(server).remote_handle();
+        tokio::spawn(serve);
+        let output = f(client::new(config, client).spawn()?).await;
+        served.await?;
+        Ok(output)
+    }
+}
+
+impl<Req, Resp> Default for Scenario<Req, Resp> {
+    fn default() -> Self {
+        Scenario::new()
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Scenario<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scenario")
+            .field("config", &self.config)
+            .field(
+                "expected",
+                &self.expected.iter().map(|e| e.rpc).collect::<Vec<_>>(),
+            )
+            .field("max_in_flight", &self.max_in_flight)
+            .finish()
+    }
+}
+
+/// An expected call of a [`Scenario`], being built. Finish it by giving it an outcome, like
+/// [`respond`](ExpectCall::respond) or [`expect_cancel`](ExpectCall::expect_cancel).
+#[must_use = "expectations are only added once they're given an outcome"]
+pub struct ExpectCall<Req, Resp> {
+    scenario: Scenario<Req, Resp>,
+    rpc: &'static str,
+    matcher: Option<Box<dyn Fn(&Req) -> bool + Send>>,
+    delay: Duration,
+    in_flight: usize,
+}
+
+impl<Req, Resp> ExpectCall<Req, Resp> {
+    /// Expects the request to satisfy `matcher`.
+    pub fn with<F>(mut self, matcher: F) -> Self
+    where
+        F: Fn(&Req) -> bool + Send + 'static,
+    {
+        self.matcher = Some(Box::new(matcher));
+        self
+    }
+
+    /// Waits `delay` before answering. Defaults to no delay.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Withholds the answer until `calls` calls, including this one, are in flight at once, so
+    /// that the client is expected to make them concurrently. Defaults to 1.
+    pub fn once_in_flight(mut self, calls: usize) -> Self {
+        self.in_flight = calls;
+        self
+    }
+
+    /// Responds with `response`.
+    pub fn respond(self, response: Resp) -> Scenario<Req, Resp>
+    where
+        Resp: Send + 'static,
+    {
+        self.returning(move |_, _| response)
+    }
+
+    /// Responds with the response `f` returns for the request.
+    pub fn returning<F>(self, f: F) -> Scenario<Req, Resp>
+    where
+        F: FnOnce(context::Context, Req) -> Resp + Send + 'static,
+    {
+        self.add(Outcome::Reply(Reply::Respond(Box::new(move |ctx, req| {
+            vec![f(ctx, req)]
+        }))))
+    }
+
+    /// Responds to a streaming RPC with `responses`, sending all but the last as partial
+    /// responses. For a service's streaming RPC, the last is the response that ends the stream.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `responses` is empty.
+    pub fn respond_stream(self, responses: Vec<Resp>) -> Scenario<Req, Resp>
+    where
+        Resp: Send + 'static,
+    {
+        assert!(!responses.is_empty(), "a stream ends with a final response");
+        self.add(Outcome::Reply(Reply::Respond(Box::new(move |_, _| {
+            responses
+        }))))
+    }
+
+    /// Fails the request with an error of kind `kind`, which the client returns as an
+    /// [`io::Error`].
+    pub fn fail(self, kind: io::ErrorKind, detail: impl Into<String>) -> Scenario<Req, Resp> {
+        self.add(Outcome::Reply(Reply::Fail(kind, detail.into())))
+    }
+
+    /// Never answers, expecting the client to cancel the request, e.g. by dropping the call or
+    /// letting its deadline pass.
+    pub fn expect_cancel(self) -> Scenario<Req, Resp> {
+        self.add(Outcome::Cancel)
+    }
+
+    fn add(mut self, outcome: Outcome<Req, Resp>) -> Scenario<Req, Resp> {
+        self.scenario.expected.push_back(Expectation {
+            rpc: self.rpc,
+            matcher: self.matcher,
+            delay: self.delay,
+            in_flight: self.in_flight,
+            outcome,
+        });
+        self.scenario
+    }
+}
+
+impl<Req, Resp> fmt::Debug for ExpectCall<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ExpectCall")
+            .field("rpc", &self.rpc)
+            .field("delay", &self.delay)
+            .field("in_flight", &self.in_flight)
+            .finish()
+    }
+}
+
+/// A scenario's script, as it's followed.
+struct Script<Req, Resp> {
+    expected: VecDeque<Expectation<Req, Resp>>,
+    max_in_flight: Option<usize>,
+    /// The requests received as expected whose answers wait for enough calls in flight, by
+    /// request ID.
+    waiting: HashMap<u64, Waiting<Req, Resp>>,
+    /// The RPCs of the requests received as expected that are to be canceled, by request ID.
+    canceling: HashMap<u64, &'static str>,
+    peak_in_flight: usize,
+    violations: Vec<String>,
+}
+
+/// A request received as expected, whose answer waits for enough calls in flight.
+struct Waiting<Req, Resp> {
+    request: Request<Req>,
+    delay: Duration,
+    in_flight: usize,
+    reply: Reply<Req, Resp>,
+}
+
+impl<Req, Resp> Handler<Req, Resp> for Script<Req, Resp>
+where
+    Req: Introspect,
+    Resp: IntrospectResponse,
+{
+    type Output = io::Result<()>;
+
+    fn handle(
+        &mut self,
+        request: Request<Req>,
+        in_flight: usize,
+    ) -> Option<(Duration, Answer<Resp>)> {
+        let rpc = request.message.method_name();
+        let strayed = match self.expected.front() {
+            None => Some(format!("unexpected call to `{}`", rpc)),
+            Some(expected) if expected.rpc != rpc => Some(format!(
+                "expected a call to `{}`, got a call to `{}`",
+                expected.rpc, rpc
+            )),
+            Some(expected)
+                if !expected
+                    .matcher
+                    .as_ref()
+                    .map_or(true, |matcher| matcher(&request.message)) =>
+            {
+                Some(format!(
+                    "a call to `{}` didn't match the expected request",
+                    rpc
+                ))
+            }
+            Some(_) => None,
+        };
+        if let Some(violation) = strayed {
+            self.violations.push(violation.clone());
+            let answer = Answer::fail(request.id, io::ErrorKind::Other, violation);
+            return Some((Duration::from_secs(0), answer));
+        }
+        let expected = self.expected.pop_front().unwrap();
+        if request.one_way {
+            if let Outcome::Cancel = expected.outcome {
+                self.violations
+                    .push(format!("the one-way call to `{}` can't be canceled", rpc));
+            }
+            return None;
+        }
+        self.peak_in_flight = self.peak_in_flight.max(in_flight);
+        if let Some(max) = self.max_in_flight {
+            if in_flight > max {
+                self.violations.push(format!(
+                    "{} calls were in flight at once, more than the {} expected",
+                    in_flight, max
+                ));
+            }
+        }
+        match expected.outcome {
+            Outcome::Reply(reply) => {
+                let waiting = Waiting {
+                    delay: expected.delay,
+                    in_flight: expected.in_flight,
+                    reply,
+                    request,
+                };
+                self.waiting.insert(waiting.request.id, waiting);
+            }
+            Outcome::Cancel => {
+                self.canceling.insert(request.id, rpc);
+            }
+        }
+        None
+    }
+
+    /// Answers the requests whose answers were waiting for enough calls in flight.
+    fn release(&mut self, in_flight: usize) -> Vec<(u64, Duration, Answer<Resp>)> {
+        let ready: Vec<u64> = self
+            .waiting
+            .iter()
+            .filter(|(_, waiting)| waiting.in_flight <= in_flight)
+            .map(|(&request_id, _)| request_id)
+            .collect();
+        ready
+            .into_iter()
+            .map(|request_id| {
+                let Waiting {
+                    request,
+                    delay,
+                    reply,
+                    ..
+                } = self.waiting.remove(&request_id).unwrap();
+                let answer = match reply {
+                    Reply::Respond(respond) => {
+                        let rpc = request.message.method_name();
+                        let responses = respond(request.context, request.message);
+                        Answer::respond(rpc, request_id, responses)
+                    }
+                    Reply::Fail(kind, detail) => Answer::fail(request_id, kind, detail),
+                };
+                (request_id, delay, answer)
+            })
+            .collect()
+    }
+
+    fn cancel(&mut self, request_id: u64, rpc: &'static str) {
+        if self.canceling.remove(&request_id).is_none() {
+            self.waiting.remove(&request_id);
+            self.violations.push(format!(
+                "the call to `{}` was canceled before it was answered",
+                rpc
+            ));
+        }
+    }
+
+    /// Returns an error describing every way the client strayed from the script.
+    fn finish(&mut self) -> io::Result<()> {
+        for expected in self.expected.drain(..) {
+            self.violations.push(format!(
+                "expected a call to `{}`, which never came",
+                expected.rpc
+            ));
+        }
+        for rpc in self.canceling.values() {
+            self.violations
+                .push(format!("expected the call to `{}` to be canceled", rpc));
+        }
+        for waiting in self.waiting.values() {
+            self.violations.push(format!(
+                "the call to `{}` waited for {} calls in flight, but at most {} were",
+                waiting.request.message.method_name(),
+                waiting.in_flight,
+                self.peak_in_flight
+            ));
+        }
+        if self.violations.is_empty() {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            self.violations.join("; "),
+        ))
+    }
+}
+
+impl<Req, Resp> fmt::Debug for Script<Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Script")
+            .field(
+                "expected",
+                &self.expected.iter().map(|e| e.rpc).collect::<Vec<_>>(),
+            )
+            .field("waiting", &self.waiting.len())
+            .field("canceling", &self.canceling.values().collect::<Vec<_>>())
+            .field("violations", &self.violations)
+            .finish()
+    }
+}
+
+/// Follows a scenario's script. Returned by [`Scenario::serve`].
+#[must_use = "futures do nothing unless polled"]
+pub struct ScenarioServe<T, Req, Resp> {
+    serve: Serve<T, Req, Resp, Script<Req, Resp>>,
+}
+
+impl<T, Req, Resp> ScenarioServe<T, Req, Resp> {
+    unsafe_pinned!(serve: Serve<T, Req, Resp, Script<Req, Resp>>);
+}
+
+impl<T, Req, Resp> Future for ScenarioServe<T, Req, Resp>
+where
+    T: Transport<Response<Resp>, ClientMessage<Req>>,
+    Req: Introspect,
+    Resp: IntrospectResponse + Send + 'static,
+{
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.serve().poll(cx)
+    }
+}
+
+impl<T, Req, Resp> fmt::Debug for ScenarioServe<T, Req, Resp> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ScenarioServe")
+            .field("serve", &self.serve)
+            .finish()
+    }
+}
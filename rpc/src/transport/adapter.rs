@@ -0,0 +1,316 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Serves clients that speak protocols other than tarpc's, such as JSON-RPC or D-Bus.
+//!
+//! An [`Adapter`] is a server transport that translates between a foreign protocol's messages
+//! and tarpc's with a [`Protocol`]. It reads the protocol's messages from its inner transport,
+//! hands the requests they hold to the server, and writes the replies the protocol makes of the
+//! server's responses. It gives each request its ID and keeps the request's
+//! [call](Protocol::Call) until the request is answered, so that a protocol only translates.
+//!
+//! Protocols that read messages from one stream and write them to another, such as Kafka's
+//! consumers and producers, join the two with a [`Duplex`].
+
+#[cfg(feature = "serde1")]
+pub mod json;
+
+use crate::{context, ClientMessage, Request, Response};
+use futures::{prelude::*, ready};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Translates between a foreign protocol's messages and tarpc's.
+pub trait Protocol: Sized {
+    /// The service's requests.
+    type Req;
+    /// The service's responses.
+    type Resp;
+    /// The messages read from the client.
+    type Incoming;
+    /// The messages written to the client.
+    type Outgoing;
+    /// What the protocol keeps of a request to answer it, e.g. the ID the client gave it.
+    type Call;
+
+    /// Handles a message read from the client, queueing the requests it holds and the replies
+    /// it needs without the server's help, e.g. errors for malformed requests. An error is
+    /// yielded by the adapter's stream of requests.
+    fn receive(&mut self, message: Self::Incoming, queue: &mut Queue<Self>) -> io::Result<()>;
+
+    /// Handles the server's response to the request of `call`, queueing the replies it makes.
+    /// A partial response is followed by more responses to the same call.
+    fn respond(
+        &mut self,
+        call: &mut Self::Call,
+        response: Response<Self::Resp>,
+        queue: &mut Queue<Self>,
+    ) -> io::Result<()>;
+}
+
+/// The requests an [`Adapter`] has yet to hand to the server, and the replies it has yet to
+/// write.
+pub struct Queue<P: Protocol> {
+    /// The requests not yet answered, keyed by the IDs they're given on the tarpc side.
+    calls: HashMap<u64, P::Call>,
+    next_request_id: u64,
+    /// Requests received, and not yet handed to the server.
+    requests: VecDeque<ClientMessage<P::Req>>,
+    /// Replies waiting to be written.
+    replies: VecDeque<P::Outgoing>,
+}
+
+impl<P: Protocol> Queue<P> {
+    fn new() -> Self {
+        Queue {
+            calls: HashMap::new(),
+            next_request_id: 0,
+            requests: VecDeque::new(),
+            replies: VecDeque::new(),
+        }
+    }
+
+    /// Queues a request for the server, with context `ctx`. The server's responses to it are
+    /// [translated](Protocol::respond) along with `call`, unless `call` is `None`, in which case
+    /// the request is one-way.
+    pub fn request(&mut self, ctx: context::Context, message: P::Req, call: Option<P::Call>) {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        let mut request = Request::new(ctx, request_id, message);
+        match call {
+            Some(call) => {
+                self.calls.insert(request_id, call);
+            }
+            None => request.one_way = true,
+        }
+        self.requests.push_back(ClientMessage::Request(request));
+    }
+
+    /// Queues `reply` to be written to the client.
+    pub fn reply(&mut self, reply: P::Outgoing) {
+        self.replies.push_back(reply);
+    }
+}
+
+impl<P: Protocol> fmt::Debug for Queue<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Queue")
+            .field("calls", &self.calls.len())
+            .field("requests", &self.requests.len())
+            .field("replies", &self.replies.len())
+            .finish()
+    }
+}
+
+/// A transport that speaks a foreign protocol to the client over the transport `T`, and tarpc's
+/// messages to the server.
+pub struct Adapter<T, P: Protocol> {
+    inner: T,
+    protocol: P,
+    queue: Queue<P>,
+}
+
+impl<T, P: Protocol> Adapter<T, P> {
+    unsafe_pinned!(inner: T);
+    unsafe_unpinned!(queue: Queue<P>);
+
+    /// Returns a transport that speaks `protocol` over `inner`.
+    pub fn new(inner: T, protocol: P) -> Self {
+        Adapter {
+            inner,
+            protocol,
+            queue: Queue::new(),
+        }
+    }
+
+    /// Returns the underlying transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the protocol spoken.
+    pub fn protocol(&self) -> &P {
+        &self.protocol
+    }
+
+    fn state(self: Pin<&mut Self>) -> (Pin<&mut T>, &mut P, &mut Queue<P>) {
+        // Safe because only the inner transport is pinned, and it isn't moved.
+        let this = unsafe { self.get_unchecked_mut() };
+        let inner = unsafe { Pin::new_unchecked(&mut this.inner) };
+        (inner, &mut this.protocol, &mut this.queue)
+    }
+}
+
+impl<T, P> Adapter<T, P>
+where
+    T: Sink<P::Outgoing, Error = io::Error>,
+    P: Protocol,
+{
+    /// Writes the queued replies, resolving once they've all been sent to the inner transport.
+    fn poll_replies(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let (mut inner, _, queue) = self.state();
+        while !queue.replies.is_empty() {
+            ready!(inner.as_mut().poll_ready(cx)?);
+            let reply = queue.replies.pop_front().unwrap();
+            inner.as_mut().start_send(reply)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, P> Stream for Adapter<T, P>
+where
+    T: Stream<Item = io::Result<P::Incoming>> + Sink<P::Outgoing, Error = io::Error>,
+    P: Protocol,
+{
+    type Item = io::Result<ClientMessage<P::Req>>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<io::Result<ClientMessage<P::Req>>>> {
+        loop {
+            if let Some(request) = self.as_mut().queue().requests.pop_front() {
+                return Poll::Ready(Some(Ok(request)));
+            }
+            // Replies to messages the server never sees are written here, since the server has
+            // nothing to send for them.
+            if !self.queue.replies.is_empty() {
+                if let Poll::Ready(Err(e)) = self.as_mut().poll_replies(cx) {
+                    return Poll::Ready(Some(Err(e)));
+                }
+                if let Poll::Ready(Err(e)) = self.as_mut().inner().poll_flush(cx) {
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+            let (inner, protocol, queue) = self.as_mut().state();
+            match ready!(inner.poll_next(cx)) {
+                Some(Ok(message)) => {
+                    if let Err(e) = protocol.receive(message, queue) {
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl<T, P> Sink<Response<P::Resp>> for Adapter<T, P>
+where
+    T: Sink<P::Outgoing, Error = io::Error>,
+    P: Protocol,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_replies(cx)?);
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, response: Response<P::Resp>) -> io::Result<()> {
+        let (inner, protocol, queue) = self.state();
+        let request_id = response.request_id;
+        let mut call = match queue.calls.remove(&request_id) {
+            Some(call) => call,
+            // The request was one-way.
+            None => return Ok(()),
+        };
+        let partial = response.partial;
+        let responded = protocol.respond(&mut call, response, queue);
+        if partial {
+            queue.calls.insert(request_id, call);
+        }
+        responded?;
+        // poll_ready made room for one reply; any others are written on the next poll_ready or
+        // flush.
+        match queue.replies.pop_front() {
+            Some(reply) => inner.start_send(reply),
+            None => Ok(()),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_replies(cx)?);
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_replies(cx)?);
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<T: fmt::Debug, P: Protocol + fmt::Debug> fmt::Debug for Adapter<T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Adapter")
+            .field("inner", &self.inner)
+            .field("protocol", &self.protocol)
+            .field("queue", &self.queue)
+            .finish()
+    }
+}
+
+/// A transport that reads messages from one stream and writes them to a separate sink.
+#[derive(Debug)]
+pub struct Duplex<St, Si> {
+    stream: St,
+    sink: Si,
+}
+
+impl<St, Si> Duplex<St, Si> {
+    unsafe_pinned!(stream: St);
+    unsafe_pinned!(sink: Si);
+
+    /// Returns a transport that reads from `stream` and writes to `sink`.
+    pub fn new(stream: St, sink: Si) -> Self {
+        Duplex { stream, sink }
+    }
+
+    /// Returns the stream messages are read from.
+    pub fn get_stream(&self) -> &St {
+        &self.stream
+    }
+
+    /// Returns the sink messages are written to.
+    pub fn get_sink(&self) -> &Si {
+        &self.sink
+    }
+}
+
+impl<St: Stream, Si> Stream for Duplex<St, Si> {
+    type Item = St::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<St::Item>> {
+        self.stream().poll_next(cx)
+    }
+}
+
+impl<St, Si: Sink<Item>, Item> Sink<Item> for Duplex<St, Si> {
+    type Error = Si::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        self.sink().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Item) -> Result<(), Si::Error> {
+        self.sink().start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        self.sink().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        self.sink().poll_close(cx)
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Converts the outputs of RPCs to JSON, for protocols that send an RPC's output rather than the
+//! service's response, such as JSON-RPC.
+
+use crate::schema::Method;
+use serde::{ser::Error as _, Serialize};
+use serde_json::Value;
+use std::mem;
+
+/// The most items of a streaming RPC's output that [`Items`] collects.
+pub const MAX_ITEMS: usize = 10_000;
+
+/// Returns the output of `method` in `response`, unwrapped from the RPC's variant of the
+/// service's response.
+pub fn output<Resp: Serialize>(method: &Method, response: Resp) -> serde_json::Result<Value> {
+    Ok(match serde_json::to_value(response)? {
+        // The output is keyed by the RPC's wire name.
+        Value::Object(mut response) => match response.remove(&*method.wire_name) {
+            Some(output) => output,
+            None => Value::Object(response),
+        },
+        response => response,
+    })
+}
+
+/// Splits the output of `method` into what it returned and what it threw, if it's declared with
+/// `#[throws]`. The outputs of other RPCs are returned as they are.
+pub fn result(method: &Method, output: Value) -> serde_json::Result<Result<Value, Value>> {
+    if method.error.is_none() {
+        return Ok(Ok(output));
+    }
+    if let Value::Object(mut result) = output {
+        if result.len() == 1 {
+            if let Some(output) = result.remove("Ok") {
+                return Ok(Ok(output));
+            }
+            if let Some(thrown) = result.remove("Err") {
+                return Ok(Err(thrown));
+            }
+        }
+    }
+    Err(serde_json::Error::custom(format!(
+        "the output of `{}` is neither Ok nor Err",
+        method.name
+    )))
+}
+
+/// Collects the items a streaming RPC streams, for protocols that send them all at once, as an
+/// array. Once an item fails to serialize, or the RPC streams more than [`MAX_ITEMS`], the rest
+/// are dropped, and [`finish`](Items::finish) fails.
+#[derive(Debug, Default)]
+pub struct Items {
+    items: Vec<Value>,
+    error: Option<serde_json::Error>,
+}
+
+impl Items {
+    /// Adds the item streamed by `method` in `response`.
+    pub fn push<Resp: Serialize>(&mut self, method: &Method, response: Resp) {
+        if self.error.is_some() {
+            return;
+        }
+        let error = if self.items.len() == MAX_ITEMS {
+            serde_json::Error::custom(format!("more than {} items were streamed", MAX_ITEMS))
+        } else {
+            match output(method, response) {
+                Ok(item) => return self.items.push(item),
+                Err(e) => e,
+            }
+        };
+        self.error = Some(error);
+        self.items = vec![];
+    }
+
+    /// Returns the items streamed, as an array.
+    pub fn finish(&mut self) -> serde_json::Result<Value> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(Value::Array(mem::replace(&mut self.items, vec![]))),
+        }
+    }
+}
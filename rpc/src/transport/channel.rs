@@ -6,11 +6,13 @@
 
 //! Transports backed by in-memory channels.
 
+use super::kill::{KillSwitch, Watch};
 use crate::PollIo;
 use futures::{channel::mpsc, task::Context, Poll, Sink, Stream};
 use pin_utils::unsafe_pinned;
 use std::io;
 use std::pin::Pin;
+use std::sync::Arc;
 
 /// Returns two unbounded channel peers. Each [`Stream`] yields items sent through the other's
 /// [`Sink`].
@@ -21,8 +23,31 @@ pub fn unbounded<SinkItem, Item>() -> (
     let (tx1, rx2) = mpsc::unbounded();
     let (tx2, rx1) = mpsc::unbounded();
     (
-        UnboundedChannel { tx: tx1, rx: rx1 },
-        UnboundedChannel { tx: tx2, rx: rx2 },
+        UnboundedChannel {
+            tx: tx1,
+            rx: rx1,
+            kill: None,
+        },
+        UnboundedChannel {
+            tx: tx2,
+            rx: rx2,
+            kill: None,
+        },
+    )
+}
+
+/// Returns a listener that yields the server ends of in-memory connections, and a connector
+/// that makes them.
+///
+/// Meant for tests, the connector lets a test decide when clients connect, and a
+/// [`Connection`] handle lets it kill any connection, even while requests are in flight.
+pub fn listen<Item, SinkItem>() -> (Connector<SinkItem, Item>, Listener<Item, SinkItem>) {
+    let (connections_tx, connections) = mpsc::unbounded();
+    (
+        Connector {
+            connections: connections_tx,
+        },
+        Listener { connections },
     )
 }
 
@@ -32,17 +57,38 @@ pub fn unbounded<SinkItem, Item>() -> (
 pub struct UnboundedChannel<Item, SinkItem> {
     rx: mpsc::UnboundedReceiver<Item>,
     tx: mpsc::UnboundedSender<SinkItem>,
+    /// For a channel made by a [`Connector`], its watch on the connection's kill switch.
+    kill: Option<Watch>,
 }
 
 impl<Item, SinkItem> UnboundedChannel<Item, SinkItem> {
     unsafe_pinned!(rx: mpsc::UnboundedReceiver<Item>);
     unsafe_pinned!(tx: mpsc::UnboundedSender<SinkItem>);
+
+    /// Returns true if the channel's connection was killed, registering the reader to be woken
+    /// when it is otherwise.
+    fn poll_read_killed(&self, cx: &mut Context<'_>) -> bool {
+        self.kill
+            .as_ref()
+            .map_or(false, |kill| kill.poll_read_killed(cx))
+    }
+
+    /// Returns true if the channel's connection was killed, registering the writer to be woken
+    /// when it is otherwise.
+    fn poll_write_killed(&self, cx: &mut Context<'_>) -> bool {
+        self.kill
+            .as_ref()
+            .map_or(false, |kill| kill.poll_write_killed(cx))
+    }
 }
 
 impl<Item, SinkItem> Stream for UnboundedChannel<Item, SinkItem> {
     type Item = Result<Item, io::Error>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<Item> {
+        if self.poll_read_killed(cx) {
+            return Poll::Ready(None);
+        }
         self.rx().poll_next(cx).map(|option| option.map(Ok))
     }
 }
@@ -51,6 +97,9 @@ impl<Item, SinkItem> Sink<SinkItem> for UnboundedChannel<Item, SinkItem> {
     type Error = io::Error;
 
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.poll_write_killed(cx) {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
         self.tx()
             .poll_ready(cx)
             .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
@@ -63,6 +112,9 @@ impl<Item, SinkItem> Sink<SinkItem> for UnboundedChannel<Item, SinkItem> {
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.poll_write_killed(cx) {
+            return Poll::Ready(Err(io::ErrorKind::NotConnected.into()));
+        }
         self.tx()
             .poll_flush(cx)
             .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))
@@ -75,6 +127,83 @@ impl<Item, SinkItem> Sink<SinkItem> for UnboundedChannel<Item, SinkItem> {
     }
 }
 
+/// Yields the server end of each connection made by its [`Connector`]. Ends once every
+/// connector is dropped or [closed](Connector::close).
+#[derive(Debug)]
+pub struct Listener<Item, SinkItem> {
+    connections: mpsc::UnboundedReceiver<UnboundedChannel<Item, SinkItem>>,
+}
+
+impl<Item, SinkItem> Listener<Item, SinkItem> {
+    unsafe_pinned!(connections: mpsc::UnboundedReceiver<UnboundedChannel<Item, SinkItem>>);
+}
+
+impl<Item, SinkItem> Stream for Listener<Item, SinkItem> {
+    type Item = UnboundedChannel<Item, SinkItem>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<UnboundedChannel<Item, SinkItem>>> {
+        self.connections().poll_next(cx)
+    }
+}
+
+/// Connects clients to a [`Listener`].
+#[derive(Debug)]
+pub struct Connector<Item, SinkItem> {
+    connections: mpsc::UnboundedSender<UnboundedChannel<SinkItem, Item>>,
+}
+
+impl<Item, SinkItem> Connector<Item, SinkItem> {
+    /// Connects a client to the listener, returning the client's end of the connection, and a
+    /// handle to the connection.
+    ///
+    /// Returns a [`ConnectionRefused`](io::ErrorKind::ConnectionRefused) error if the listener
+    /// was dropped or closed.
+    pub fn connect(&self) -> io::Result<(Connection, UnboundedChannel<Item, SinkItem>)> {
+        let kill = Arc::new(KillSwitch::default());
+        let (mut client, mut server) = unbounded();
+        client.kill = Some(kill.watch());
+        server.kill = Some(kill.watch());
+        self.connections
+            .unbounded_send(server)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok((Connection(kill), client))
+    }
+
+    /// Closes the listener, so that it yields the connections already made, and then ends.
+    pub fn close(&self) {
+        self.connections.close_channel();
+    }
+}
+
+impl<Item, SinkItem> Clone for Connector<Item, SinkItem> {
+    fn clone(&self) -> Self {
+        Connector {
+            connections: self.connections.clone(),
+        }
+    }
+}
+
+/// A handle to a connection made by a [`Connector`].
+#[derive(Clone, Debug)]
+pub struct Connection(Arc<KillSwitch>);
+
+impl Connection {
+    /// Kills the connection, as though the network between its ends failed. Both ends' streams
+    /// end, dropping any frames still in flight, and their sinks return
+    /// [`NotConnected`](io::ErrorKind::NotConnected) errors.
+    pub fn kill(&self) {
+        self.0.kill();
+    }
+
+    /// Returns true if the connection was killed.
+    pub fn is_killed(&self) -> bool {
+        self.0.is_killed()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
@@ -83,7 +212,7 @@ mod tests {
         transport,
     };
     use assert_matches::assert_matches;
-    use futures::{prelude::*, stream};
+    use futures::{executor::block_on, prelude::*, stream};
     use log::trace;
     use std::io;
 
@@ -117,4 +246,39 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn killed_connection_closes_both_ends() -> io::Result<()> {
+        let (connector, mut listener) = transport::channel::listen::<String, u64>();
+        let (connection1, mut client1) = connector.connect()?;
+        let (_connection2, mut client2) = connector.connect()?;
+        block_on(async {
+            let mut server1 = listener.next().await.unwrap();
+            let mut server2 = listener.next().await.unwrap();
+            client1.send("one".into()).await?;
+            client2.send("two".into()).await?;
+
+            connection1.kill();
+            assert!(connection1.is_killed());
+            // The request in flight is lost with the connection.
+            assert!(server1.next().await.is_none());
+            assert!(client1.next().await.is_none());
+            assert_matches!(
+                server1.send(1).await,
+                Err(ref e) if e.kind() == io::ErrorKind::NotConnected
+            );
+
+            assert_eq!(server2.next().await.unwrap()?, "two");
+            server2.send(2).await?;
+            assert_eq!(client2.next().await.unwrap()?, 2);
+
+            connector.close();
+            assert!(listener.next().await.is_none());
+            assert_matches!(
+                connector.connect(),
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused
+            );
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,446 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! An in-process network of named nodes, whose connectivity tests can partition and heal.
+//!
+//! Meant for testing replicated systems, a [`Fabric`] connects nodes over in-memory
+//! [channels](crate::transport::channel). Nodes [listen](Fabric::listen) for connections and
+//! [connect](Fabric::connect) to each other by name. At any time, a test can cut the links
+//! between nodes, in one direction or both, and heal them again.
+//!
+//! Like TCP, a partition doesn't lose frames: frames sent across it wait, in order, until it
+//! heals, and the connections over it stay open. Requests across a partition therefore time out,
+//! unless it heals before their deadlines. Connecting across a partition, in either direction,
+//! fails with [`TimedOut`](io::ErrorKind::TimedOut).
+//!
+//! ```ignore
+//! let fabric = Fabric::new();
+//! let incoming = fabric.listen("a")?;
+//! let transport = fabric.connect("b", "a")?;
+//! // Requests from b reach a, but a's responses don't reach b.
+//! fabric.partition_one_way("a", "b");
+//! ```
+
+use crate::{
+    transport::channel::{self, UnboundedChannel},
+    PollIo,
+};
+use futures::{
+    channel::mpsc,
+    prelude::*,
+    task::{AtomicWaker, Context, Poll},
+};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+/// An in-process network of named nodes. Clones of a fabric share its nodes and links.
+///
+/// `Item` is the type of frames servers receive, and `SinkItem` the type they send, e.g.
+/// `Fabric<ClientMessage<Req>, Response<Resp>>` for a service with requests `Req` and responses
+/// `Resp`.
+pub struct Fabric<Item, SinkItem> {
+    state: Arc<Mutex<State<Item, SinkItem>>>,
+    links: Arc<Links>,
+}
+
+struct State<Item, SinkItem> {
+    /// The nodes listening for connections.
+    listeners: HashMap<String, mpsc::UnboundedSender<FabricTransport<Item, SinkItem>>>,
+}
+
+/// The connectivity between nodes, shared by a fabric and its transports.
+#[derive(Debug, Default)]
+struct Links {
+    cuts: Mutex<Cuts>,
+    /// Counts the changes to the links, so that transports only lock them to check their links
+    /// after they've changed. Only changes while the cuts are locked.
+    version: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Cuts {
+    /// Directed links that frames can't cross, as (from, to).
+    cut: HashSet<(String, String)>,
+    /// Nodes cut off from every other node.
+    isolated: HashSet<String>,
+    /// Wakes the transports waiting for frames to cross a cut link, once links heal.
+    wakers: Vec<Weak<AtomicWaker>>,
+}
+
+impl Cuts {
+    fn is_cut(&self, from: &str, to: &str) -> bool {
+        from != to
+            && (self.isolated.contains(from)
+                || self.isolated.contains(to)
+                || self.cut.contains(&(from.to_string(), to.to_string())))
+    }
+}
+
+impl Links {
+    /// Changes the links, waking transports so that they see what healed.
+    fn update(&self, f: impl FnOnce(&mut Cuts)) {
+        let mut cuts = self.cuts.lock().unwrap();
+        f(&mut cuts);
+        self.version.fetch_add(1, Ordering::Release);
+        cuts.wakers.retain(|waker| match waker.upgrade() {
+            Some(waker) => {
+                waker.wake();
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+impl<Item, SinkItem> Fabric<Item, SinkItem> {
+    /// Returns a fabric without nodes, in which every link is up.
+    pub fn new() -> Self {
+        Fabric {
+            state: Arc::new(Mutex::new(State {
+                listeners: HashMap::new(),
+            })),
+            links: Arc::new(Links::default()),
+        }
+    }
+
+    /// Returns the server ends of connections to `node`, made by [`connect`](Fabric::connect).
+    ///
+    /// Returns an [`AddrInUse`](io::ErrorKind::AddrInUse) error if `node` is already listening.
+    /// Once the returned stream is dropped, another can listen in its place.
+    pub fn listen(&self, node: &str) -> io::Result<Incoming<Item, SinkItem>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(listener) = state.listeners.get(node) {
+            if !listener.is_closed() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("node {} is already listening", node),
+                ));
+            }
+        }
+        let (tx, connections) = mpsc::unbounded();
+        state.listeners.insert(node.to_string(), tx);
+        Ok(Incoming { connections })
+    }
+
+    /// Connects node `from` to node `to`, returning the client end of the connection.
+    ///
+    /// Returns a [`ConnectionRefused`](io::ErrorKind::ConnectionRefused) error if `to` isn't
+    /// listening, or a [`TimedOut`](io::ErrorKind::TimedOut) error if a partition separates the
+    /// nodes in either direction.
+    pub fn connect(&self, from: &str, to: &str) -> io::Result<FabricTransport<SinkItem, Item>> {
+        let cut = {
+            let cuts = self.links.cuts.lock().unwrap();
+            cuts.is_cut(from, to) || cuts.is_cut(to, from)
+        };
+        if cut {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("a partition separates {} from {}", from, to),
+            ));
+        }
+        let (client, server) = channel::unbounded();
+        let client = self.transport(client, from, to);
+        let server = self.transport(server, to, from);
+        let state = self.state.lock().unwrap();
+        match state.listeners.get(to) {
+            Some(listener) if listener.unbounded_send(server).is_ok() => Ok(client),
+            _ => Err(io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("node {} isn't listening", to),
+            )),
+        }
+    }
+
+    fn transport<In, Out>(
+        &self,
+        inner: UnboundedChannel<In, Out>,
+        local: &str,
+        peer: &str,
+    ) -> FabricTransport<In, Out> {
+        let waker = Arc::new(AtomicWaker::new());
+        let mut cuts = self.links.cuts.lock().unwrap();
+        // Forget the transports that were dropped since, so that churning connections across
+        // links that never change don't pile up wakers.
+        cuts.wakers.retain(|waker| waker.upgrade().is_some());
+        cuts.wakers.push(Arc::downgrade(&waker));
+        let link = Link {
+            version: self.links.version.load(Ordering::Acquire),
+            cut: cuts.is_cut(peer, local),
+        };
+        FabricTransport {
+            inner,
+            local: local.into(),
+            peer: peer.into(),
+            links: self.links.clone(),
+            waker,
+            link,
+        }
+    }
+
+    /// Cuts the links between nodes `a` and `b`, in both directions.
+    pub fn partition(&self, a: &str, b: &str) {
+        self.partition_one_way(a, b);
+        self.partition_one_way(b, a);
+    }
+
+    /// Cuts the link from node `from` to node `to`, so that frames `from` sends don't reach
+    /// `to`, while frames `to` sends still reach `from`.
+    pub fn partition_one_way(&self, from: &str, to: &str) {
+        self.links.update(|cuts| {
+            cuts.cut.insert((from.to_string(), to.to_string()));
+        });
+    }
+
+    /// Cuts every link to and from `node`, including links to nodes that join later, until it
+    /// [rejoins](Fabric::rejoin).
+    pub fn isolate(&self, node: &str) {
+        self.links.update(|cuts| {
+            cuts.isolated.insert(node.to_string());
+        });
+    }
+
+    /// Ends the isolation of `node`. Links cut by [`partition`](Fabric::partition) stay cut.
+    pub fn rejoin(&self, node: &str) {
+        self.links.update(|cuts| {
+            cuts.isolated.remove(node);
+        });
+    }
+
+    /// Heals the links between nodes `a` and `b`, in both directions, delivering the frames
+    /// that waited to cross them. Isolated nodes stay isolated.
+    pub fn heal(&self, a: &str, b: &str) {
+        self.links.update(|cuts| {
+            cuts.cut.remove(&(a.to_string(), b.to_string()));
+            cuts.cut.remove(&(b.to_string(), a.to_string()));
+        });
+    }
+
+    /// Heals every link, and ends every isolation.
+    pub fn heal_all(&self) {
+        self.links.update(|cuts| {
+            cuts.cut.clear();
+            cuts.isolated.clear();
+        });
+    }
+
+    /// Returns true if frames sent by node `from` can reach node `to`.
+    pub fn is_connected(&self, from: &str, to: &str) -> bool {
+        !self.links.cuts.lock().unwrap().is_cut(from, to)
+    }
+}
+
+impl<Item, SinkItem> Clone for Fabric<Item, SinkItem> {
+    fn clone(&self) -> Self {
+        Fabric {
+            state: self.state.clone(),
+            links: self.links.clone(),
+        }
+    }
+}
+
+impl<Item, SinkItem> Default for Fabric<Item, SinkItem> {
+    fn default() -> Self {
+        Fabric::new()
+    }
+}
+
+impl<Item, SinkItem> fmt::Debug for Fabric<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        let cuts = self.links.cuts.lock().unwrap();
+        f.debug_struct("Fabric")
+            .field("listeners", &state.listeners.keys().collect::<Vec<_>>())
+            .field("cut", &cuts.cut)
+            .field("isolated", &cuts.isolated)
+            .finish()
+    }
+}
+
+/// Yields the server end of each connection to a node of a [`Fabric`]. Returned by
+/// [`Fabric::listen`].
+#[derive(Debug)]
+pub struct Incoming<Item, SinkItem> {
+    connections: mpsc::UnboundedReceiver<FabricTransport<Item, SinkItem>>,
+}
+
+impl<Item, SinkItem> Incoming<Item, SinkItem> {
+    unsafe_pinned!(connections: mpsc::UnboundedReceiver<FabricTransport<Item, SinkItem>>);
+}
+
+impl<Item, SinkItem> Stream for Incoming<Item, SinkItem> {
+    type Item = FabricTransport<Item, SinkItem>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<FabricTransport<Item, SinkItem>>> {
+        self.connections().poll_next(cx)
+    }
+}
+
+/// One end of a connection between two nodes of a [`Fabric`].
+pub struct FabricTransport<Item, SinkItem> {
+    inner: UnboundedChannel<Item, SinkItem>,
+    local: Arc<str>,
+    peer: Arc<str>,
+    links: Arc<Links>,
+    /// Woken when links heal.
+    waker: Arc<AtomicWaker>,
+    /// The link from the peer, as of the last check.
+    link: Link,
+}
+
+/// Whether the link from a transport's peer was cut, as of a version of the [`Links`].
+#[derive(Clone, Copy, Debug)]
+struct Link {
+    version: u64,
+    cut: bool,
+}
+
+impl<Item, SinkItem> FabricTransport<Item, SinkItem> {
+    unsafe_pinned!(inner: UnboundedChannel<Item, SinkItem>);
+    unsafe_unpinned!(link: Link);
+
+    /// Returns true if frames from the peer can't reach this end, checking the links again only
+    /// if they've changed since they were last checked.
+    fn is_cut(mut self: Pin<&mut Self>) -> bool {
+        let version = self.links.version.load(Ordering::Acquire);
+        if version != self.link.version {
+            let cut = self
+                .links
+                .cuts
+                .lock()
+                .unwrap()
+                .is_cut(&self.peer, &self.local);
+            *self.as_mut().link() = Link { version, cut };
+        }
+        self.link.cut
+    }
+
+    /// Returns the name of the node at this end of the connection.
+    pub fn local(&self) -> &str {
+        &self.local
+    }
+
+    /// Returns the name of the node at the other end of the connection.
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+}
+
+impl<Item, SinkItem> Stream for FabricTransport<Item, SinkItem> {
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<Item> {
+        self.waker.register(cx.waker());
+        // Frames from the peer wait in the channel until the link heals.
+        if self.as_mut().is_cut() {
+            return Poll::Pending;
+        }
+        self.inner().poll_next(cx)
+    }
+}
+
+impl<Item, SinkItem> Sink<SinkItem> for FabricTransport<Item, SinkItem> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        self.inner().start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<Item, SinkItem> fmt::Debug for FabricTransport<Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FabricTransport")
+            .field("local", &self.local)
+            .field("peer", &self.peer)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+    use futures::executor::block_on;
+    use futures_test::task::noop_waker_ref;
+
+    #[test]
+    fn partitions_hold_frames_until_healed() -> io::Result<()> {
+        let fabric = Fabric::<String, String>::new();
+        let mut incoming = fabric.listen("a")?;
+        assert_matches!(
+            fabric.listen("a"),
+            Err(ref e) if e.kind() == io::ErrorKind::AddrInUse
+        );
+        assert_matches!(
+            fabric.connect("b", "c"),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused
+        );
+        let mut b = fabric.connect("b", "a")?;
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        block_on(async {
+            let mut a = incoming.next().await.unwrap();
+            assert_eq!((a.local(), a.peer()), ("a", "b"));
+
+            fabric.partition_one_way("b", "a");
+            assert!(!fabric.is_connected("b", "a"));
+            assert!(fabric.is_connected("a", "b"));
+            b.send("ping".into()).await?;
+            assert!(a.poll_next_unpin(cx).is_pending());
+            a.send("pong".into()).await?;
+            assert_eq!(b.next().await.unwrap()?, "pong");
+
+            fabric.heal("a", "b");
+            assert_eq!(a.next().await.unwrap()?, "ping");
+
+            fabric.isolate("a");
+            assert_matches!(
+                fabric.connect("c", "a"),
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut
+            );
+            a.send("lost?".into()).await?;
+            assert!(b.poll_next_unpin(cx).is_pending());
+            fabric.rejoin("a");
+            assert_eq!(b.next().await.unwrap()?, "lost?");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn dropped_transports_are_forgotten() -> io::Result<()> {
+        let fabric = Fabric::<String, String>::new();
+        let mut incoming = fabric.listen("a")?;
+        for _ in 0..10 {
+            let b = fabric.connect("b", "a")?;
+            let a = block_on(incoming.next()).unwrap();
+            drop((a, b));
+        }
+        // Only the last connection's ends, dropped since it was made, are left to forget.
+        assert_eq!(fabric.links.cuts.lock().unwrap().wakers.len(), 2);
+        Ok(())
+    }
+}
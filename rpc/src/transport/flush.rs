@@ -0,0 +1,140 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Decides when clients and servers flush the messages they've written to their transports.
+
+use crate::runtime::{Delay, Timer};
+use futures::{
+    prelude::*,
+    task::{Context, Poll},
+};
+use std::{sync::Arc, time::Duration};
+
+/// When to flush messages written to a transport.
+///
+/// Flushing less often lets a transport batch messages into fewer syscalls, at the cost of the
+/// latency of the messages that wait.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Flushes after every message, for the lowest latency.
+    Immediate,
+    /// Flushes once `max_messages` messages are unflushed, or once the oldest unflushed message
+    /// has waited `max_delay`, whichever comes first. Messages are held even when no more are
+    /// ready to write, so this trades latency for throughput under steady load.
+    Corked {
+        /// The most messages left unflushed.
+        max_messages: usize,
+        /// The longest a message is left unflushed.
+        max_delay: Duration,
+    },
+    /// Flushes whenever no more messages are ready to write, so that a burst of messages is
+    /// flushed together but a lone message isn't delayed. Also flushes once `max_messages` are
+    /// unflushed, so that a steady stream of messages doesn't wait indefinitely.
+    Adaptive {
+        /// The most messages left unflushed while more are ready to write.
+        max_messages: usize,
+    },
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl Default for FlushPolicy {
+    /// Flushes whenever no more messages are ready to write.
+    fn default() -> Self {
+        FlushPolicy::Adaptive {
+            max_messages: usize::max_value(),
+        }
+    }
+}
+
+/// Tracks unflushed messages to apply a [`FlushPolicy`].
+#[derive(Debug)]
+pub(crate) struct Flusher {
+    policy: FlushPolicy,
+    unflushed: usize,
+    /// When a corked flush is due.
+    deadline: Option<Delay>,
+    /// Times corked flushes.
+    timer: Arc<dyn Timer>,
+}
+
+impl Flusher {
+    pub(crate) fn new(policy: FlushPolicy, timer: Arc<dyn Timer>) -> Self {
+        Flusher {
+            policy,
+            unflushed: 0,
+            deadline: None,
+            timer,
+        }
+    }
+
+    /// Records that a message was written, returning whether to flush before writing another.
+    pub(crate) fn wrote(&mut self) -> bool {
+        self.unflushed += 1;
+        match self.policy {
+            FlushPolicy::Immediate => true,
+            FlushPolicy::Corked {
+                max_messages,
+                max_delay,
+            } => {
+                if self.deadline.is_none() {
+                    self.deadline = Some(self.timer.delay_for(max_delay));
+                }
+                self.unflushed >= max_messages
+            }
+            FlushPolicy::Adaptive { max_messages } => self.unflushed >= max_messages,
+            // Flushing every message is correct under any policy, if not the most efficient.
+            _ => true,
+        }
+    }
+
+    /// Returns the number of messages written since the last flush.
+    pub(crate) fn unflushed(&self) -> usize {
+        self.unflushed
+    }
+
+    /// Returns ready once it's time to flush, given that no more messages are ready to write.
+    pub(crate) fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match self.deadline {
+            Some(ref mut deadline) if self.unflushed > 0 => deadline.poll_unpin(cx),
+            _ => Poll::Ready(()),
+        }
+    }
+
+    /// Records that every written message was flushed.
+    pub(crate) fn flushed(&mut self) {
+        self.unflushed = 0;
+        self.deadline = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_test::task::noop_waker_ref;
+
+    #[test]
+    fn adaptive_flushes_when_idle_or_full() {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut flusher = Flusher::new(
+            FlushPolicy::Adaptive { max_messages: 2 },
+            Arc::new(crate::runtime::Tokio),
+        );
+        assert!(!flusher.wrote());
+        assert_eq!(flusher.poll_idle(&mut cx), Poll::Ready(()));
+        assert!(flusher.wrote());
+        flusher.flushed();
+        assert!(!flusher.wrote());
+    }
+
+    #[test]
+    fn immediate_flushes_every_message() {
+        let mut flusher = Flusher::new(FlushPolicy::Immediate, Arc::new(crate::runtime::Tokio));
+        assert!(flusher.wrote());
+        flusher.flushed();
+        assert!(flusher.wrote());
+    }
+}
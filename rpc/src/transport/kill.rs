@@ -0,0 +1,95 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Kill switches, which drop connections mid-test as though the network between their ends
+//! failed.
+
+use futures::task::{AtomicWaker, Context};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, Weak,
+};
+
+/// Kills a connection, waking every transport [watching](KillSwitch::watch) it.
+#[derive(Debug, Default)]
+pub(crate) struct KillSwitch {
+    killed: AtomicBool,
+    /// The wakers of the transports watching the switch.
+    watchers: Mutex<Vec<Weak<Wakers>>>,
+}
+
+/// Wakes a transport's reader and writer, which may be polled by different tasks.
+#[derive(Debug, Default)]
+struct Wakers {
+    reader: AtomicWaker,
+    writer: AtomicWaker,
+}
+
+impl KillSwitch {
+    /// Kills the connection.
+    pub(crate) fn kill(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// Returns true if the connection was killed.
+    pub(crate) fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    /// Wakes every transport watching the switch, e.g. to notice that it was killed.
+    pub(crate) fn wake(&self) {
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|watcher| match watcher.upgrade() {
+                Some(wakers) => {
+                    wakers.reader.wake();
+                    wakers.writer.wake();
+                    true
+                }
+                None => false,
+            });
+    }
+
+    /// Returns a watch on the switch, for a transport to learn when it's killed.
+    pub(crate) fn watch(self: &Arc<Self>) -> Watch {
+        let wakers = Arc::new(Wakers::default());
+        self.watchers.lock().unwrap().push(Arc::downgrade(&wakers));
+        Watch {
+            switch: self.clone(),
+            wakers,
+        }
+    }
+}
+
+/// A transport's watch on a [`KillSwitch`].
+#[derive(Debug)]
+pub(crate) struct Watch {
+    switch: Arc<KillSwitch>,
+    wakers: Arc<Wakers>,
+}
+
+impl Watch {
+    /// Returns true if the connection was killed, registering the transport's reader to be woken
+    /// when it is otherwise.
+    pub(crate) fn poll_read_killed(&self, cx: &mut Context<'_>) -> bool {
+        self.wakers.reader.register(cx.waker());
+        self.switch.is_killed()
+    }
+
+    /// Returns true if the connection was killed, registering the transport's writer to be woken
+    /// when it is otherwise.
+    pub(crate) fn poll_write_killed(&self, cx: &mut Context<'_>) -> bool {
+        self.wakers.writer.register(cx.waker());
+        self.switch.is_killed()
+    }
+
+    /// Returns the switch watched.
+    pub(crate) fn switch(&self) -> &KillSwitch {
+        &self.switch
+    }
+}
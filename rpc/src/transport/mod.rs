@@ -12,7 +12,16 @@
 use futures::prelude::*;
 use std::io;
 
+pub mod adapter;
 pub mod channel;
+pub mod fabric;
+mod flush;
+mod kill;
+#[cfg(feature = "tokio1")]
+pub mod testing;
+
+pub use flush::FlushPolicy;
+pub(crate) use flush::Flusher;
 
 pub(crate) mod sealed {
     use super::*;
@@ -0,0 +1,1039 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Transports for testing how clients and servers cope with unreliable, slow, or stuck
+//! connections.
+
+use super::kill::{KillSwitch, Watch};
+use crate::PollIo;
+use futures::{prelude::*, ready, task::Context, Poll};
+use pin_utils::{unsafe_pinned, unsafe_unpinned};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::VecDeque,
+    fmt, io, mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio_timer::{clock, Delay};
+
+/// Holds a transport that's dropped once its [`KillSwitch`] is killed, so that its peer sees the
+/// connection close.
+struct Killable<T> {
+    inner: Option<T>,
+    watch: Watch,
+}
+
+impl<T> Killable<T> {
+    unsafe_pinned!(inner: Option<T>);
+
+    /// Wraps `transport`, which is dropped once `switch` is killed.
+    fn new(transport: T, switch: &Arc<KillSwitch>) -> Self {
+        Killable {
+            inner: Some(transport),
+            watch: switch.watch(),
+        }
+    }
+
+    /// Returns the wrapped transport, or `None` if it was killed.
+    fn get_ref(&self) -> Option<&T> {
+        self.inner.as_ref()
+    }
+
+    /// Kills the connection, dropping the wrapped transport now.
+    fn kill(self: Pin<&mut Self>) {
+        self.watch.switch().kill();
+        self.inner().set(None);
+    }
+
+    /// Returns the wrapped transport to read from, dropping it first if the connection was
+    /// killed. Registers the reader to be woken when the connection is killed.
+    fn reader(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Option<Pin<&mut T>> {
+        if self.watch.poll_read_killed(cx) {
+            self.as_mut().inner().set(None);
+        }
+        self.inner().as_pin_mut()
+    }
+
+    /// Returns the wrapped transport to write to, dropping it first if the connection was
+    /// killed. Registers the writer to be woken when the connection is killed.
+    fn writer(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Option<Pin<&mut T>> {
+        if self.watch.poll_write_killed(cx) {
+            self.as_mut().inner().set(None);
+        }
+        self.inner().as_pin_mut()
+    }
+
+    /// Returns the wrapped transport to write to without polling, dropping it first if the
+    /// connection was killed.
+    fn sender(mut self: Pin<&mut Self>) -> Option<Pin<&mut T>> {
+        if self.watch.switch().is_killed() {
+            self.as_mut().inner().set(None);
+        }
+        self.inner().as_pin_mut()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Killable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Killable")
+            .field("inner", &self.inner)
+            .field("killed", &self.watch.switch().is_killed())
+            .finish()
+    }
+}
+
+/// The faults a [`FaultyTransport`] injects, each as the probability, between 0 and 1, that it
+/// befalls a frame sent or received. Defaults to no faults.
+#[derive(Clone, Debug)]
+pub struct Faults {
+    /// The frame is lost.
+    pub drop: f64,
+    /// The frame arrives twice.
+    pub duplicate: f64,
+    /// The frame arrives after the next frame, rather than before it.
+    pub reorder: f64,
+    /// A bit of the frame's encoding is flipped in transit. The frame arrives as whatever the
+    /// corrupted bytes [decode to](Corrupt), or is lost if they no longer decode: a received
+    /// frame as an [`InvalidData`](io::ErrorKind::InvalidData) error, and a sent frame silently.
+    pub corrupt: f64,
+    /// The connection is lost, along with the frame and any frames held back by reordering.
+    pub disconnect: f64,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Faults {
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            corrupt: 0.0,
+            disconnect: 0.0,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+/// A frame that can be [corrupted](Faults::corrupt) in transit.
+///
+/// With the `serde1` feature, every frame that can be serialized can be corrupted: a bit of its
+/// bincode encoding is flipped, and the corrupted bytes decoded.
+pub trait Corrupt: Sized {
+    /// Returns the frame that arrives when the given bit of its encoding, modulo the encoding's
+    /// length in bits, is flipped, or an [`InvalidData`](io::ErrorKind::InvalidData) error if
+    /// the corrupted encoding no longer decodes.
+    fn corrupt(self, bit: u64) -> io::Result<Self>;
+}
+
+#[cfg(feature = "serde1")]
+impl<T> Corrupt for T
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    fn corrupt(self, bit: u64) -> io::Result<Self> {
+        let invalid = |e: bincode::Error| io::Error::new(io::ErrorKind::InvalidData, e);
+        let mut bytes = bincode::serialize(&self).map_err(invalid)?;
+        if bytes.is_empty() {
+            return Ok(self);
+        }
+        let bit = bit % (bytes.len() as u64 * 8);
+        bytes[(bit / 8) as usize] ^= 1 << (bit % 8);
+        bincode::deserialize(&bytes).map_err(invalid)
+    }
+}
+
+/// Wraps a transport, injecting [faults](Faults) into the frames it sends and receives.
+///
+/// Whether each frame meets with each fault is decided by random number generators seeded by the
+/// user, one for each direction, so a test sending and receiving the same frames meets with the
+/// same faults every time it runs.
+///
+/// Frames are duplicated by cloning them, and corrupted by [corrupting](Corrupt) their encoding,
+/// so the frames must be `Clone` and `Corrupt`. To wrap the transport of a service's client or
+/// server, enable the `serde1` feature, and derive `Clone` for the service's request and response
+/// enums with `#[tarpc::service(derive(Debug, Clone))]`.
+///
+/// A reordered frame is held back until the next frame in the same direction, or until the
+/// transport ends or is closed. Once disconnected, the wrapped transport is dropped, so the peer
+/// sees the connection close; the stream then ends, and sending fails with
+/// [`NotConnected`](io::ErrorKind::NotConnected).
+pub struct FaultyTransport<T, Item, SinkItem> {
+    inner: Killable<T>,
+    /// Decides the faults that befall received frames.
+    receiving: Injector,
+    /// Decides the faults that befall sent frames.
+    sending: Injector,
+    received: Frames<Item>,
+    sent: Frames<SinkItem>,
+    /// Whether the wrapped transport's stream ended.
+    ended: bool,
+}
+
+/// Decides the faults that befall the frames travelling one way through a [`FaultyTransport`].
+struct Injector {
+    faults: Faults,
+    rng: StdRng,
+}
+
+/// Frames travelling one way through a [`FaultyTransport`].
+struct Frames<I> {
+    /// Frames ready to be passed on.
+    ready: VecDeque<io::Result<I>>,
+    /// Frames held back by reordering.
+    held: Vec<io::Result<I>>,
+}
+
+impl Injector {
+    /// Injects faults into a frame, readying what's left of it. Returns false if the connection
+    /// was lost instead.
+    fn inject<I: Clone + Corrupt>(&mut self, frames: &mut Frames<I>, item: I) -> bool {
+        let (faults, rng) = (&self.faults, &mut self.rng);
+        if rng.gen_bool(faults.disconnect) {
+            return false;
+        }
+        if rng.gen_bool(faults.drop) {
+            return true;
+        }
+        let corrupt = if rng.gen_bool(faults.corrupt) {
+            Some(rng.gen::<u64>())
+        } else {
+            None
+        };
+        let copies = if rng.gen_bool(faults.duplicate) { 2 } else { 1 };
+        let reorder = rng.gen_bool(faults.reorder);
+        let copies = (0..copies).map(|_| match corrupt {
+            Some(bit) => item.clone().corrupt(bit),
+            None => Ok(item.clone()),
+        });
+        if reorder && frames.held.is_empty() {
+            frames.held.extend(copies);
+        } else {
+            frames.ready.extend(copies);
+            frames.release();
+        }
+        true
+    }
+}
+
+impl<I> Frames<I> {
+    fn new() -> Self {
+        Frames {
+            ready: VecDeque::new(),
+            held: vec![],
+        }
+    }
+
+    /// Readies the frames held back by reordering.
+    fn release(&mut self) {
+        self.ready.extend(self.held.drain(..));
+    }
+
+    fn clear(&mut self) {
+        self.ready.clear();
+        self.held.clear();
+    }
+}
+
+impl<T, Item, SinkItem> FaultyTransport<T, Item, SinkItem> {
+    unsafe_pinned!(inner: Killable<T>);
+    unsafe_unpinned!(received: Frames<Item>);
+    unsafe_unpinned!(sent: Frames<SinkItem>);
+
+    /// Returns a transport injecting `faults` into the frames sent and received by `transport`,
+    /// as decided by random number generators seeded with `seed`.
+    ///
+    /// # Panics
+    ///
+    /// When a frame is sent or received, if any fault's probability isn't between 0 and 1.
+    pub fn new(transport: T, faults: Faults, seed: u64) -> Self {
+        FaultyTransport {
+            inner: Killable::new(transport, &Arc::new(KillSwitch::default())),
+            receiving: Injector {
+                faults: faults.clone(),
+                rng: StdRng::seed_from_u64(seed),
+            },
+            sending: Injector {
+                faults,
+                rng: StdRng::seed_from_u64(!seed),
+            },
+            received: Frames::new(),
+            sent: Frames::new(),
+            ended: false,
+        }
+    }
+
+    /// Returns the wrapped transport, or `None` if it was disconnected.
+    pub fn get_ref(&self) -> Option<&T> {
+        self.inner.get_ref()
+    }
+
+    /// Disconnects the transport now, dropping the wrapped transport and any frames not yet
+    /// passed on.
+    pub fn disconnect(mut self: Pin<&mut Self>) {
+        self.as_mut().inner().kill();
+        self.as_mut().received().clear();
+        self.sent().clear();
+    }
+
+    /// Sends the frames ready to be sent through the wrapped transport.
+    fn poll_send_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>
+    where
+        T: Sink<SinkItem, Error = io::Error>,
+    {
+        // Safe because only the wrapped transport is pinned, and it isn't moved.
+        let this = unsafe { self.as_mut().get_unchecked_mut() };
+        loop {
+            match this.sent.ready.front() {
+                None => return Poll::Ready(Ok(())),
+                // The corrupted frame no longer decodes, so the peer can't receive it.
+                Some(Err(_)) => {
+                    this.sent.ready.pop_front();
+                    continue;
+                }
+                Some(Ok(_)) => {}
+            }
+            let mut inner = match unsafe { Pin::new_unchecked(&mut this.inner) }.writer(cx) {
+                Some(inner) => inner,
+                None => return Poll::Ready(Err(io::Error::from(io::ErrorKind::NotConnected))),
+            };
+            ready!(inner.as_mut().poll_ready(cx)?);
+            if let Some(Ok(item)) = this.sent.ready.pop_front() {
+                inner.start_send(item)?;
+            }
+        }
+    }
+}
+
+impl<T, Item, SinkItem> Stream for FaultyTransport<T, Item, SinkItem>
+where
+    T: Stream<Item = io::Result<Item>>,
+    Item: Clone + Corrupt,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<Item> {
+        loop {
+            if let Some(frame) = self.as_mut().received().ready.pop_front() {
+                return Poll::Ready(Some(frame));
+            }
+            if self.ended {
+                return Poll::Ready(None);
+            }
+            let inner = match self.as_mut().inner().reader(cx) {
+                Some(inner) => inner,
+                None => return Poll::Ready(None),
+            };
+            match ready!(inner.poll_next(cx)) {
+                Some(Ok(item)) => {
+                    // Safe because neither the injector nor the received frames are pinned.
+                    let this = unsafe { self.as_mut().get_unchecked_mut() };
+                    if !this.receiving.inject(&mut this.received, item) {
+                        self.as_mut().disconnect();
+                    }
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => {
+                    // Safe because neither the flag nor the received frames are pinned.
+                    let this = unsafe { self.as_mut().get_unchecked_mut() };
+                    this.ended = true;
+                    this.received.release();
+                }
+            }
+        }
+    }
+}
+
+impl<T, Item, SinkItem> Sink<SinkItem> for FaultyTransport<T, Item, SinkItem>
+where
+    T: Sink<SinkItem, Error = io::Error>,
+    SinkItem: Clone + Corrupt,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_send_ready(cx)?);
+        match self.inner().writer(cx) {
+            Some(inner) => inner.poll_ready(cx),
+            None => Poll::Ready(Err(io::Error::from(io::ErrorKind::NotConnected))),
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        if self.as_mut().inner().sender().is_none() {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        }
+        // Safe because neither the injector nor the sent frames are pinned.
+        let this = unsafe { self.as_mut().get_unchecked_mut() };
+        if !this.sending.inject(&mut this.sent, item) {
+            self.disconnect();
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_send_ready(cx)?);
+        match self.inner().writer(cx) {
+            Some(inner) => inner.poll_flush(cx),
+            None => Poll::Ready(Err(io::Error::from(io::ErrorKind::NotConnected))),
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.as_mut().sent().release();
+        if self.inner.get_ref().is_some() {
+            ready!(self.as_mut().poll_send_ready(cx)?);
+        }
+        match self.inner().writer(cx) {
+            Some(inner) => inner.poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl<T: fmt::Debug, Item, SinkItem> fmt::Debug for FaultyTransport<T, Item, SinkItem> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FaultyTransport")
+            .field("inner", &self.inner)
+            .field("faults", &self.receiving.faults)
+            .field("received", &self.received.ready.len())
+            .field("sent", &self.sent.ready.len())
+            .field("ended", &self.ended)
+            .finish()
+    }
+}
+
+/// How long frames take to cross a [`SimulatedLink`], once sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Latency {
+    /// Every frame is delayed by the same amount.
+    Fixed(Duration),
+    /// Frames are delayed by amounts uniformly distributed between the two bounds, inclusive.
+    Uniform(Duration, Duration),
+    /// Frames are delayed by at least the first amount, plus an exponentially distributed amount
+    /// averaging the second: most frames are delayed a little, and a few a lot, as on a link
+    /// that's occasionally congested.
+    Exponential(Duration, Duration),
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl Latency {
+    fn sample(self, rng: &mut StdRng) -> Duration {
+        fn nanos(duration: Duration) -> u64 {
+            duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())
+        }
+
+        match self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform(min, max) if min < max => {
+                Duration::from_nanos(rng.gen_range(nanos(min), nanos(max) + 1))
+            }
+            Latency::Uniform(min, _) => min,
+            Latency::Exponential(min, mean) => {
+                // The inverse of the distribution's CDF, applied to a uniform sample in (0, 1].
+                let extra = -(1.0 - rng.gen::<f64>()).ln() * nanos(mean) as f64;
+                min + Duration::from_nanos(extra as u64)
+            }
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+/// The characteristics of the link simulated by a [`SimulatedLink`]. Defaults to a link with no
+/// latency and unlimited bandwidth.
+#[derive(Clone, Debug)]
+pub struct Link {
+    /// How long each frame takes to cross the link once it's sent.
+    pub latency: Latency,
+    /// The most bytes per second the link carries, or `None` for no limit. Each frame is sent
+    /// once the frames before it are, and takes its size divided by the bandwidth to send.
+    pub bytes_per_sec: Option<u64>,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Link {
+            latency: Latency::Fixed(Duration::from_secs(0)),
+            bytes_per_sec: None,
+            _non_exhaustive: (),
+        }
+    }
+}
+
+/// Wraps a transport, delaying the frames it receives as though they crossed a slow [link](Link).
+///
+/// Frames are received in the order they were sent, each no sooner than its latency after it was
+/// sent. While the link is busy sending a frame, the wrapped transport isn't read, so a sender
+/// outpacing the link's bandwidth is pushed back on by the wrapped transport, as it would be by
+/// a real network. Latencies are sampled from a random number generator seeded by the user.
+///
+/// Frames are sized by [`mem::size_of`] their type unless [sized otherwise](SimulatedLink::with_frame_size),
+/// e.g. by their serialized length. Timing relies on the tokio timer.
+pub struct SimulatedLink<T, Item> {
+    inner: T,
+    link: Link,
+    frame_size: fn(&Item) -> usize,
+    rng: StdRng,
+    /// Frames crossing the link, each with the time it's received.
+    in_flight: VecDeque<(Instant, io::Result<Item>)>,
+    /// When the link finishes sending the last frame read.
+    sent: Instant,
+    /// Wakes the transport when the next frame is received, or the link is free to send another.
+    timer: Option<Delay>,
+    /// Whether the wrapped transport's stream ended.
+    ended: bool,
+}
+
+impl<T, Item> SimulatedLink<T, Item> {
+    unsafe_pinned!(inner: T);
+
+    /// Returns a transport delaying the frames received by `transport` as though they crossed
+    /// `link`, sampling latencies from a random number generator seeded with `seed`.
+    pub fn new(transport: T, link: Link, seed: u64) -> Self {
+        SimulatedLink {
+            inner: transport,
+            link,
+            frame_size: |_| mem::size_of::<Item>(),
+            rng: StdRng::seed_from_u64(seed),
+            in_flight: VecDeque::new(),
+            sent: clock::now(),
+            timer: None,
+            ended: false,
+        }
+    }
+
+    /// Sizes each frame by `frame_size`, in bytes, to decide how long it takes to send.
+    pub fn with_frame_size(mut self, frame_size: fn(&Item) -> usize) -> Self {
+        self.frame_size = frame_size;
+        self
+    }
+
+    /// Returns the wrapped transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Sends a frame read from the wrapped transport across the link.
+    fn send(&mut self, now: Instant, frame: io::Result<Item>) {
+        if let (Some(bytes_per_sec), Ok(ref item)) = (self.link.bytes_per_sec, &frame) {
+            let nanos =
+                (self.frame_size)(item) as u128 * 1_000_000_000 / u128::from(bytes_per_sec.max(1));
+            self.sent = now + Duration::from_nanos(nanos as u64);
+        } else {
+            self.sent = now;
+        }
+        let received = self.sent + self.link.latency.sample(&mut self.rng);
+        // Frames aren't received before the frames sent ahead of them.
+        let received = match self.in_flight.back() {
+            Some(&(last, _)) => received.max(last),
+            None => received,
+        };
+        self.in_flight.push_back((received, frame));
+    }
+}
+
+impl<T, Item> Stream for SimulatedLink<T, Item>
+where
+    T: Stream<Item = io::Result<Item>>,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<Item> {
+        loop {
+            let now = clock::now();
+            // Read frames while the link is free to send them.
+            while !self.ended && self.sent <= now {
+                match self.as_mut().inner().poll_next(cx) {
+                    Poll::Ready(Some(frame)) => {
+                        // Safe because the wrapped transport isn't moved.
+                        unsafe { self.as_mut().get_unchecked_mut() }.send(now, frame)
+                    }
+                    Poll::Ready(None) => {
+                        // Safe because the flag isn't pinned.
+                        unsafe { self.as_mut().get_unchecked_mut() }.ended = true
+                    }
+                    Poll::Pending => break,
+                }
+            }
+            // Safe because none of the fields used below are pinned.
+            let this = unsafe { self.as_mut().get_unchecked_mut() };
+            match this.in_flight.front() {
+                Some(&(received, _)) if received <= now => {
+                    return Poll::Ready(this.in_flight.pop_front().map(|(_, frame)| frame));
+                }
+                None if this.ended => return Poll::Ready(None),
+                _ => {}
+            }
+            let received = this.in_flight.front().map(|&(received, _)| received);
+            let free = if this.ended || this.sent <= now {
+                None
+            } else {
+                Some(this.sent)
+            };
+            let wake = match (received, free) {
+                (Some(received), Some(free)) => received.min(free),
+                (Some(wake), None) | (None, Some(wake)) => wake,
+                // The wrapped transport wakes this one when it's ready.
+                (None, None) => return Poll::Pending,
+            };
+            match this.timer {
+                Some(ref mut timer) => timer.reset(wake),
+                None => this.timer = Some(tokio_timer::delay(wake)),
+            }
+            ready!(Pin::new(this.timer.as_mut().unwrap()).poll(cx));
+        }
+    }
+}
+
+impl<T, Item, SinkItem> Sink<SinkItem> for SimulatedLink<T, Item>
+where
+    T: Sink<SinkItem, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        self.inner().start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner().poll_close(cx)
+    }
+}
+
+impl<T: fmt::Debug, Item> fmt::Debug for SimulatedLink<T, Item> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimulatedLink")
+            .field("inner", &self.inner)
+            .field("link", &self.link)
+            .field("in_flight", &self.in_flight.len())
+            .field("ended", &self.ended)
+            .finish()
+    }
+}
+
+/// Wraps a transport, letting a test kill, pause, or stall the connection mid-test through a
+/// [`ChaosHandle`].
+///
+/// Wrap a server's transport before making a [channel](crate::server::BaseChannel) of it, or a
+/// client's before [making the client](crate::client::new), to test how the peer copes with a
+/// half-open connection, or with backpressure:
+///
+/// * [Paused](ChaosHandle::pause), the transport stops reading, so frames sent by the peer wait
+///   in the wrapped transport, as though the process stopped reading its socket.
+/// * [Stalled](ChaosHandle::stall), the transport stops writing: it isn't ready to send, nor to
+///   flush frames already sent, as though the peer stopped reading.
+/// * [Killed](ChaosHandle::kill), the wrapped transport is dropped, so the peer sees the
+///   connection close; the stream then ends, and sending fails with
+///   [`NotConnected`](io::ErrorKind::NotConnected).
+pub struct ChaosTransport<T> {
+    inner: Killable<T>,
+    chaos: Arc<Chaos>,
+}
+
+/// A handle to a [`ChaosTransport`], which it wraps the connection of.
+#[derive(Clone, Debug)]
+pub struct ChaosHandle(Arc<Chaos>);
+
+/// Shared by a [`ChaosTransport`] and its handles.
+#[derive(Debug, Default)]
+struct Chaos {
+    /// Kills the connection, and wakes the transport when it's resumed.
+    switch: Arc<KillSwitch>,
+    paused: AtomicBool,
+    stalled: AtomicBool,
+}
+
+impl<T> ChaosTransport<T> {
+    unsafe_pinned!(inner: Killable<T>);
+
+    /// Returns a transport wrapping `transport`, which reads and writes as usual until told
+    /// otherwise by a [handle](ChaosTransport::handle).
+    pub fn new(transport: T) -> Self {
+        let chaos = Arc::new(Chaos::default());
+        ChaosTransport {
+            inner: Killable::new(transport, &chaos.switch),
+            chaos,
+        }
+    }
+
+    /// Returns a handle to kill, pause, or stall the connection.
+    pub fn handle(&self) -> ChaosHandle {
+        ChaosHandle(self.chaos.clone())
+    }
+
+    /// Returns the wrapped transport, or `None` if it was killed.
+    pub fn get_ref(&self) -> Option<&T> {
+        self.inner.get_ref()
+    }
+}
+
+impl ChaosHandle {
+    /// Kills the connection, as though the network failed. The transport drops the connection the
+    /// next time it's polled, which the handle wakes it to do, along with any frames not yet read.
+    pub fn kill(&self) {
+        self.0.switch.kill();
+    }
+
+    /// Stops reading frames until [resumed](ChaosHandle::resume).
+    pub fn pause(&self) {
+        self.0.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Stops writing frames until [resumed](ChaosHandle::resume).
+    pub fn stall(&self) {
+        self.0.stalled.store(true, Ordering::SeqCst);
+    }
+
+    /// Resumes reading and writing frames, if paused or stalled.
+    pub fn resume(&self) {
+        self.0.paused.store(false, Ordering::SeqCst);
+        self.0.stalled.store(false, Ordering::SeqCst);
+        self.0.switch.wake();
+    }
+
+    /// Returns true if the connection was killed.
+    pub fn is_killed(&self) -> bool {
+        self.0.switch.is_killed()
+    }
+}
+
+impl<T, Item> Stream for ChaosTransport<T>
+where
+    T: Stream<Item = io::Result<Item>>,
+{
+    type Item = io::Result<Item>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> PollIo<Item> {
+        // Safe because only the wrapped transport is pinned, and it isn't moved.
+        let this = unsafe { self.get_unchecked_mut() };
+        match unsafe { Pin::new_unchecked(&mut this.inner) }.reader(cx) {
+            None => Poll::Ready(None),
+            Some(_) if this.chaos.paused.load(Ordering::SeqCst) => Poll::Pending,
+            Some(inner) => inner.poll_next(cx),
+        }
+    }
+}
+
+impl<T, SinkItem> Sink<SinkItem> for ChaosTransport<T>
+where
+    T: Sink<SinkItem, Error = io::Error>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Safe because only the wrapped transport is pinned, and it isn't moved.
+        let this = unsafe { self.get_unchecked_mut() };
+        match unsafe { Pin::new_unchecked(&mut this.inner) }.writer(cx) {
+            None => Poll::Ready(Err(io::Error::from(io::ErrorKind::NotConnected))),
+            Some(_) if this.chaos.stalled.load(Ordering::SeqCst) => Poll::Pending,
+            Some(inner) => inner.poll_ready(cx),
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: SinkItem) -> io::Result<()> {
+        match self.inner().sender() {
+            Some(inner) => inner.start_send(item),
+            None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Safe because only the wrapped transport is pinned, and it isn't moved.
+        let this = unsafe { self.get_unchecked_mut() };
+        match unsafe { Pin::new_unchecked(&mut this.inner) }.writer(cx) {
+            None => Poll::Ready(Err(io::Error::from(io::ErrorKind::NotConnected))),
+            Some(_) if this.chaos.stalled.load(Ordering::SeqCst) => Poll::Pending,
+            Some(inner) => inner.poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.inner().writer(cx) {
+            Some(inner) => inner.poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ChaosTransport<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChaosTransport")
+            .field("inner", &self.inner)
+            .field("paused", &self.chaos.paused)
+            .field("stalled", &self.chaos.stalled)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::channel;
+    use assert_matches::assert_matches;
+    use futures::executor::block_on;
+    use futures_test::task::noop_waker_ref;
+
+    /// Sends `0..frames` to a faulty transport, and returns what it receives.
+    #[cfg(feature = "serde1")]
+    fn receive(faults: Faults, seed: u64, frames: u32) -> Vec<io::Result<u32>> {
+        let (mut tx, rx) = channel::unbounded::<u32, u32>();
+        let rx = FaultyTransport::new(rx, faults, seed);
+        block_on(async {
+            for i in 0..frames {
+                tx.send(i).await.unwrap();
+            }
+            drop(tx);
+            rx.collect().await
+        })
+    }
+
+    /// Sends `0..frames` through a faulty transport, and returns what its peer receives.
+    #[cfg(feature = "serde1")]
+    fn send(faults: Faults, seed: u64, frames: u32) -> Vec<u32> {
+        let (tx, rx) = channel::unbounded::<u32, u32>();
+        let mut tx = FaultyTransport::new(tx, faults, seed);
+        block_on(async {
+            for i in 0..frames {
+                tx.send(i).await.unwrap();
+            }
+            tx.close().await.unwrap();
+            rx.map(Result::unwrap).collect().await
+        })
+    }
+
+    #[cfg(feature = "serde1")]
+    fn received(frames: Vec<io::Result<u32>>) -> Vec<Option<u32>> {
+        frames.into_iter().map(Result::ok).collect()
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn no_faults_by_default() {
+        assert_eq!(
+            received(receive(Faults::default(), 0, 4)),
+            vec![Some(0), Some(1), Some(2), Some(3)]
+        );
+        assert_eq!(send(Faults::default(), 0, 4), vec![0, 1, 2, 3]);
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn certain_faults() {
+        let mut faults = Faults::default();
+        faults.drop = 1.0;
+        assert!(receive(faults.clone(), 0, 4).is_empty());
+        assert!(send(faults, 0, 4).is_empty());
+
+        let mut faults = Faults::default();
+        faults.duplicate = 1.0;
+        assert_eq!(
+            received(receive(faults.clone(), 0, 2)),
+            vec![Some(0), Some(0), Some(1), Some(1)]
+        );
+        assert_eq!(send(faults, 0, 2), vec![0, 0, 1, 1]);
+
+        // Every other frame is held back, to be released by the next, or when the transport
+        // ends or is closed.
+        let mut faults = Faults::default();
+        faults.reorder = 1.0;
+        assert_eq!(
+            received(receive(faults.clone(), 0, 5)),
+            vec![Some(1), Some(0), Some(3), Some(2), Some(4)]
+        );
+        assert_eq!(send(faults, 0, 5), vec![1, 0, 3, 2, 4]);
+
+        // Every encoding of a u32 decodes, so corrupted frames arrive with a bit flipped.
+        let mut faults = Faults::default();
+        faults.corrupt = 1.0;
+        let frames = received(receive(faults.clone(), 0, 100));
+        assert_eq!(frames.len(), 100);
+        for (i, frame) in (0..).zip(frames) {
+            assert_eq!((frame.unwrap() ^ i).count_ones(), 1);
+        }
+        let frames = send(faults, 0, 100);
+        assert_eq!(frames.len(), 100);
+        for (i, frame) in (0..).zip(frames) {
+            assert_eq!((frame ^ i).count_ones(), 1);
+        }
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn corrupted_frames_that_dont_decode() {
+        let mut faults = Faults::default();
+        faults.corrupt = 1.0;
+        let (mut tx, rx) = channel::unbounded::<bool, bool>();
+        let mut rx = FaultyTransport::new(rx, faults.clone(), 0);
+        let (sent_tx, sent_rx) = channel::unbounded::<bool, bool>();
+        let mut sent_tx = FaultyTransport::new(sent_tx, faults, 0);
+        block_on(async {
+            // Flipping any bit but the lowest of a bool's encoding makes it invalid.
+            let mut invalid = 0;
+            for _ in 0..100 {
+                tx.send(false).await.unwrap();
+                match rx.next().await.unwrap() {
+                    Ok(frame) => assert!(frame),
+                    Err(e) => {
+                        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+                        invalid += 1;
+                    }
+                }
+                sent_tx.send(false).await.unwrap();
+            }
+            assert!(invalid > 0 && invalid < 100);
+
+            // Sent frames that no longer decode are lost.
+            sent_tx.close().await.unwrap();
+            let sent: Vec<_> = sent_rx.map(Result::unwrap).collect().await;
+            assert!(sent.len() < 100);
+            assert!(sent.iter().all(|&frame| frame));
+        });
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn seeded_faults_are_deterministic() {
+        let mut faults = Faults::default();
+        faults.drop = 0.2;
+        faults.duplicate = 0.2;
+        faults.reorder = 0.2;
+        faults.corrupt = 0.2;
+        let run = || received(receive(faults.clone(), 7, 100));
+        let frames = run();
+        assert_eq!(frames, run());
+        assert_ne!(frames, received(receive(faults.clone(), 8, 100)));
+        assert_ne!(frames, (0..100).map(Some).collect::<Vec<_>>());
+        assert_eq!(send(faults.clone(), 7, 100), send(faults, 7, 100));
+    }
+
+    #[cfg(feature = "serde1")]
+    #[test]
+    fn disconnect_closes_both_directions() {
+        let (mut client, server) = channel::unbounded::<u32, u32>();
+        let mut faults = Faults::default();
+        faults.disconnect = 1.0;
+        let mut server = FaultyTransport::new(server, faults, 0);
+        block_on(async {
+            client.send(1).await.unwrap();
+            assert_matches!(server.next().await, None);
+            assert_matches!(
+                server.send(2).await,
+                Err(ref e) if e.kind() == io::ErrorKind::NotConnected
+            );
+            // The peer sees the connection close.
+            assert_matches!(client.next().await, None);
+            assert_matches!(client.send(3).await, Err(_));
+        });
+
+        // A disconnect befalling a sent frame closes the connection too.
+        let (mut client, server) = channel::unbounded::<u32, u32>();
+        let mut faults = Faults::default();
+        faults.disconnect = 1.0;
+        let mut server = FaultyTransport::new(server, faults, 0);
+        block_on(async {
+            assert_matches!(
+                server.send(1).await,
+                Err(ref e) if e.kind() == io::ErrorKind::NotConnected
+            );
+            assert_matches!(server.next().await, None);
+            assert_matches!(client.next().await, None);
+        });
+    }
+
+    #[test]
+    fn chaos_pauses_stalls_and_kills() {
+        let (mut client, server) = channel::unbounded::<u32, u32>();
+        let mut server = ChaosTransport::new(server);
+        let chaos = server.handle();
+        let cx = &mut Context::from_waker(noop_waker_ref());
+        block_on(async {
+            chaos.pause();
+            client.send(1).await.unwrap();
+            assert!(server.poll_next_unpin(cx).is_pending());
+            chaos.stall();
+            assert!(Sink::<u32>::poll_ready(Pin::new(&mut server), cx).is_pending());
+            assert!(Sink::<u32>::poll_flush(Pin::new(&mut server), cx).is_pending());
+            chaos.resume();
+            assert_eq!(server.next().await.unwrap().unwrap(), 1);
+            server.send(2).await.unwrap();
+            assert_eq!(client.next().await.unwrap().unwrap(), 2);
+
+            // Killing a paused connection drops the frames waiting to be read.
+            chaos.pause();
+            client.send(3).await.unwrap();
+            chaos.kill();
+            assert!(chaos.is_killed());
+            assert_matches!(server.next().await, None);
+            assert_matches!(
+                server.send(4).await,
+                Err(ref e) if e.kind() == io::ErrorKind::NotConnected
+            );
+            // The peer sees the connection close.
+            assert_matches!(client.next().await, None);
+            assert_matches!(client.send(5).await, Err(_));
+        });
+    }
+
+    #[test]
+    fn latency_distributions() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let ms = Duration::from_millis;
+        assert_eq!(Latency::Fixed(ms(5)).sample(&mut rng), ms(5));
+        for _ in 0..100 {
+            let latency = Latency::Uniform(ms(5), ms(10)).sample(&mut rng);
+            assert!(latency >= ms(5) && latency <= ms(10), "{:?}", latency);
+            assert!(Latency::Exponential(ms(5), ms(1)).sample(&mut rng) >= ms(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn link_delays_frames_in_order() {
+        let (mut tx, rx) = channel::unbounded::<u32, u32>();
+        let mut link = Link::default();
+        link.latency = Latency::Uniform(Duration::from_millis(10), Duration::from_millis(30));
+        let rx = SimulatedLink::new(rx, link, 0);
+        let start = Instant::now();
+        for i in 0..10 {
+            tx.send(i).await.unwrap();
+        }
+        drop(tx);
+        let frames: Vec<_> = rx.map(Result::unwrap).collect().await;
+        assert_eq!(frames, (0..10).collect::<Vec<_>>());
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn link_caps_bandwidth() {
+        let (mut tx, rx) = channel::unbounded::<u32, u32>();
+        let mut link = Link::default();
+        // Each frame takes 10ms to send.
+        link.bytes_per_sec = Some(100);
+        let rx = SimulatedLink::new(rx, link, 0).with_frame_size(|_| 1);
+        let start = Instant::now();
+        for i in 0..5 {
+            tx.send(i).await.unwrap();
+        }
+        drop(tx);
+        assert_eq!(rx.collect::<Vec<_>>().await.len(), 5);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! A bounded in-memory queue of records, written in batches by a [`Writer`] running elsewhere, so
+//! that a slow sink never blocks whoever produces the records. Backs the audit log and the span
+//! exporter.
+
+use crate::event::{Event, EventSink};
+use futures::{
+    channel::mpsc,
+    prelude::*,
+    task::{Context, Poll},
+};
+use std::{
+    fmt, io, mem,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+/// A destination for batches of records of type `T`.
+pub(crate) trait BatchSink<T> {
+    /// Writes a batch of records.
+    fn write(&mut self, batch: &[T]) -> io::Result<()>;
+}
+
+/// Queues records to be written by a [`Writer`].
+pub(crate) struct Queue<T> {
+    records: mpsc::UnboundedSender<T>,
+    queued: Arc<AtomicUsize>,
+    capacity: usize,
+    dropped: Arc<AtomicU64>,
+    /// What the queue holds, e.g. `"audit records"`.
+    name: &'static str,
+    events: Arc<dyn EventSink>,
+}
+
+/// Returns a queue of up to `capacity` records named `name`, and the writer that writes them to
+/// `sink`. Dropped records and failed writes are reported to `events`.
+pub(crate) fn queue<T, S>(
+    name: &'static str,
+    sink: S,
+    capacity: usize,
+    events: Arc<dyn EventSink>,
+) -> (Queue<T>, Writer<T, S>) {
+    let (records, rx) = mpsc::unbounded();
+    let queued = Arc::new(AtomicUsize::new(0));
+    let queue = Queue {
+        records,
+        queued: queued.clone(),
+        capacity,
+        dropped: Arc::new(AtomicU64::new(0)),
+        name,
+        events: events.clone(),
+    };
+    let writer = Writer {
+        records: rx,
+        queued,
+        sink,
+        batch: vec![],
+        name,
+        events,
+    };
+    (queue, writer)
+}
+
+impl<T> Queue<T> {
+    /// Queues `record` to be written, dropping it if the queue is full.
+    pub(crate) fn push(&self, record: T) {
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.capacity
+            || self.records.unbounded_send(record).is_err()
+        {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            // Report the first drop and then ever more rarely, so a full queue doesn't flood.
+            if dropped.is_power_of_two() {
+                self.events.event(&Event::QueueFull {
+                    queue: self.name,
+                    dropped,
+                });
+            }
+        }
+    }
+
+    /// Returns the number of records dropped because the queue was full or the writer was gone.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Queue {
+            records: self.records.clone(),
+            queued: self.queued.clone(),
+            capacity: self.capacity,
+            dropped: self.dropped.clone(),
+            name: self.name,
+            events: self.events.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Queue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Queue")
+            .field("name", &self.name)
+            .field("capacity", &self.capacity)
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+/// Writes queued records to a sink in batches. Completes once every clone of the queue is dropped
+/// and the queue is drained.
+pub(crate) struct Writer<T, S> {
+    records: mpsc::UnboundedReceiver<T>,
+    queued: Arc<AtomicUsize>,
+    sink: S,
+    batch: Vec<T>,
+    name: &'static str,
+    events: Arc<dyn EventSink>,
+}
+
+impl<T, S> Writer<T, S> {
+    /// Returns the sink the writer writes to.
+    #[cfg(test)]
+    pub(crate) fn sink(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<T, S: BatchSink<T>> Writer<T, S> {
+    fn write_batch(&mut self) {
+        if self.batch.is_empty() {
+            return;
+        }
+        let mut batch = mem::replace(&mut self.batch, vec![]);
+        if let Err(error) = self.sink.write(&batch) {
+            self.events.event(&Event::BatchWriteFailed {
+                queue: self.name,
+                records: batch.len(),
+                error: &error,
+            });
+        }
+        self.queued.fetch_sub(batch.len(), Ordering::Relaxed);
+        batch.clear();
+        self.batch = batch;
+    }
+}
+
+// The sink and batch are never pinned.
+impl<T, S> Unpin for Writer<T, S> {}
+
+impl<T, S: BatchSink<T>> Future for Writer<T, S> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            match self.records.poll_next_unpin(cx) {
+                Poll::Ready(Some(record)) => self.batch.push(record),
+                Poll::Ready(None) => {
+                    self.write_batch();
+                    return Poll::Ready(());
+                }
+                Poll::Pending if self.batch.is_empty() => return Poll::Pending,
+                // Write what's queued, then check for records that arrived in the meantime.
+                Poll::Pending => self.write_batch(),
+            }
+        }
+    }
+}
+
+impl<T, S: fmt::Debug> fmt::Debug for Writer<T, S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Writer")
+            .field("name", &self.name)
+            .field("sink", &self.sink)
+            .field("batch", &self.batch.len())
+            .finish()
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+use fnv::FnvHasher;
+use std::{
+    collections::hash_map::{DefaultHasher, RandomState},
+    hash::{BuildHasher, Hasher},
+};
+
+/// The hash function of the maps that track in-flight requests and channel filter keys.
+///
+/// Those maps are keyed by values a peer chooses, like request IDs and addresses, so a server
+/// facing untrusted clients may want to trade some speed for resistance to hash flooding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapHasher {
+    /// FNV-1a: fast for small keys like request IDs, but it degrades as key sets grow, and a peer
+    /// who chooses the keys can make them collide.
+    Fnv,
+    /// SipHash with random keys, as used by `std::collections::HashMap`: slower, but resistant to
+    /// collisions crafted by a peer.
+    SipHash,
+    #[doc(hidden)]
+    _NonExhaustive,
+}
+
+impl Default for MapHasher {
+    fn default() -> Self {
+        MapHasher::Fnv
+    }
+}
+
+impl MapHasher {
+    /// Returns the hasher builder of a new map.
+    pub(crate) fn build(self) -> BuildMapHasher {
+        match self {
+            MapHasher::Fnv => BuildMapHasher::Fnv,
+            // SipHash resists collision attacks, so it is safe for any key.
+            _ => BuildMapHasher::SipHash(RandomState::new()),
+        }
+    }
+}
+
+/// A map hashed by a configurable [`MapHasher`].
+pub(crate) type HashMap<K, V> = std::collections::HashMap<K, V, BuildMapHasher>;
+
+/// Builds the hashers of a single map.
+#[derive(Clone, Debug)]
+pub(crate) enum BuildMapHasher {
+    Fnv,
+    SipHash(RandomState),
+}
+
+impl Default for BuildMapHasher {
+    fn default() -> Self {
+        BuildMapHasher::Fnv
+    }
+}
+
+impl BuildHasher for BuildMapHasher {
+    type Hasher = MapHasherState;
+
+    fn build_hasher(&self) -> MapHasherState {
+        match self {
+            BuildMapHasher::Fnv => MapHasherState::Fnv(FnvHasher::default()),
+            BuildMapHasher::SipHash(state) => MapHasherState::SipHash(state.build_hasher()),
+        }
+    }
+}
+
+pub(crate) enum MapHasherState {
+    Fnv(FnvHasher),
+    SipHash(DefaultHasher),
+}
+
+impl Hasher for MapHasherState {
+    fn finish(&self) -> u64 {
+        match self {
+            MapHasherState::Fnv(hasher) => hasher.finish(),
+            MapHasherState::SipHash(hasher) => hasher.finish(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self {
+            MapHasherState::Fnv(hasher) => hasher.write(bytes),
+            MapHasherState::SipHash(hasher) => hasher.write(bytes),
+        }
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        match self {
+            MapHasherState::Fnv(hasher) => hasher.write_u64(i),
+            MapHasherState::SipHash(hasher) => hasher.write_u64(i),
+        }
+    }
+}
+
+#[test]
+fn hashers_agree_on_map_contents() {
+    for &hasher in &[MapHasher::Fnv, MapHasher::SipHash] {
+        let mut map = HashMap::with_hasher(hasher.build());
+        for i in 0..100u64 {
+            map.insert(i, i * 2);
+        }
+        assert_eq!(map.len(), 100);
+        assert_eq!(map[&42], 84);
+    }
+}
@@ -7,23 +7,17 @@
 use std::{
     collections::HashMap,
     hash::{BuildHasher, Hash},
-    time::{Duration, SystemTime},
+    io,
 };
 
+pub(crate) mod batch;
+pub mod hash;
 #[cfg(feature = "serde")]
 pub mod serde;
-
-/// Extension trait for [SystemTimes](SystemTime) in the future, i.e. deadlines.
-pub trait TimeUntil {
-    /// How much time from now until this time is reached.
-    fn time_until(&self) -> Duration;
-}
-
-impl TimeUntil for SystemTime {
-    fn time_until(&self) -> Duration {
-        self.duration_since(SystemTime::now()).unwrap_or_default()
-    }
-}
+#[cfg(all(test, feature = "tracing"))]
+pub(crate) mod spans;
+#[cfg(feature = "server")]
+pub(crate) mod sync;
 
 /// Collection compaction; configurable `shrink_to_fit`.
 pub trait Compact {
@@ -45,3 +39,54 @@ where
         }
     }
 }
+
+/// Converts [`io::ErrorKind`] to its counterpart on the wire, or `Other` if it has none.
+pub(crate) fn error_kind_to_proto(kind: io::ErrorKind) -> proto::ErrorKind {
+    use std::io::ErrorKind::*;
+    match kind {
+        NotFound => proto::ErrorKind::NotFound,
+        PermissionDenied => proto::ErrorKind::PermissionDenied,
+        ConnectionRefused => proto::ErrorKind::ConnectionRefused,
+        ConnectionReset => proto::ErrorKind::ConnectionReset,
+        ConnectionAborted => proto::ErrorKind::ConnectionAborted,
+        NotConnected => proto::ErrorKind::NotConnected,
+        AddrInUse => proto::ErrorKind::AddrInUse,
+        AddrNotAvailable => proto::ErrorKind::AddrNotAvailable,
+        BrokenPipe => proto::ErrorKind::BrokenPipe,
+        AlreadyExists => proto::ErrorKind::AlreadyExists,
+        WouldBlock => proto::ErrorKind::WouldBlock,
+        InvalidInput => proto::ErrorKind::InvalidInput,
+        InvalidData => proto::ErrorKind::InvalidData,
+        TimedOut => proto::ErrorKind::TimedOut,
+        WriteZero => proto::ErrorKind::WriteZero,
+        Interrupted => proto::ErrorKind::Interrupted,
+        UnexpectedEof => proto::ErrorKind::UnexpectedEof,
+        _ => proto::ErrorKind::Other,
+    }
+}
+
+/// Converts an error kind from the wire to [`io::ErrorKind`].
+pub(crate) fn error_kind_from_proto(kind: proto::ErrorKind) -> io::ErrorKind {
+    use std::io::ErrorKind::*;
+    match kind {
+        proto::ErrorKind::NotFound => NotFound,
+        proto::ErrorKind::PermissionDenied => PermissionDenied,
+        proto::ErrorKind::ConnectionRefused => ConnectionRefused,
+        proto::ErrorKind::ConnectionReset => ConnectionReset,
+        proto::ErrorKind::ConnectionAborted => ConnectionAborted,
+        proto::ErrorKind::NotConnected => NotConnected,
+        proto::ErrorKind::AddrInUse => AddrInUse,
+        proto::ErrorKind::AddrNotAvailable => AddrNotAvailable,
+        proto::ErrorKind::BrokenPipe => BrokenPipe,
+        proto::ErrorKind::AlreadyExists => AlreadyExists,
+        proto::ErrorKind::WouldBlock => WouldBlock,
+        proto::ErrorKind::InvalidInput => InvalidInput,
+        proto::ErrorKind::InvalidData => InvalidData,
+        proto::ErrorKind::TimedOut => TimedOut,
+        proto::ErrorKind::WriteZero => WriteZero,
+        proto::ErrorKind::Interrupted => Interrupted,
+        proto::ErrorKind::Other => Other,
+        proto::ErrorKind::UnexpectedEof => UnexpectedEof,
+        proto::ErrorKind::Unauthenticated => PermissionDenied,
+    }
+}
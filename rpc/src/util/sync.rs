@@ -0,0 +1,126 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! The primitives guarding the state that the server's tasks share: the slots of in-flight
+//! requests and the channel counts of the [`ChannelFilter`](crate::server::ChannelFilter).
+//!
+//! Built with `RUSTFLAGS="--cfg loom"`, they're loom's instead of std's, so that the loom tests
+//! of the modules using them explore every interleaving of their threads:
+//!
+//! ```text
+//! RUSTFLAGS="--cfg loom" cargo test -p tarpc-lib --release --lib loom
+//! ```
+//!
+//! The futures channels the tasks send to each other aren't swapped; loom sees them as
+//! ordinary code.
+
+#[cfg(loom)]
+pub(crate) use loom::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+#[cfg(not(loom))]
+pub(crate) use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+/// Counts itself and its clones while they're live.
+#[derive(Debug)]
+pub(crate) struct Counter {
+    count: Option<Arc<AtomicUsize>>,
+}
+
+impl Counter {
+    /// Returns the first count of a new counter.
+    pub(crate) fn new() -> Self {
+        WeakCounter::new().upgrade()
+    }
+
+    /// Returns the number of live counts.
+    pub(crate) fn count(&self) -> usize {
+        self.count
+            .as_ref()
+            .map_or(0, |count| count.load(Ordering::Acquire))
+    }
+
+    /// Gives up this count before it's dropped, returning the number of counts left, or `None`
+    /// if it was already given up. Of counts released concurrently, exactly one sees none left.
+    pub(crate) fn release(&mut self) -> Option<usize> {
+        self.count
+            .take()
+            .map(|count| count.fetch_sub(1, Ordering::AcqRel) - 1)
+    }
+}
+
+impl Clone for Counter {
+    fn clone(&self) -> Self {
+        let count = self.count.clone();
+        if let Some(ref count) = count {
+            count.fetch_add(1, Ordering::AcqRel);
+        }
+        Counter { count }
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// Reads the count of [`Counter`]s without counting itself.
+#[derive(Clone, Debug)]
+pub(crate) struct WeakCounter {
+    count: Arc<AtomicUsize>,
+}
+
+impl WeakCounter {
+    /// Returns a counter with no counts.
+    pub(crate) fn new() -> Self {
+        WeakCounter {
+            count: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Returns the number of live counts.
+    pub(crate) fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Returns a new count.
+    pub(crate) fn upgrade(&self) -> Counter {
+        self.count.fetch_add(1, Ordering::AcqRel);
+        Counter {
+            count: Some(self.count.clone()),
+        }
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+
+    #[test]
+    fn loom_exactly_one_release_is_last() {
+        loom::model(|| {
+            let lasts = Arc::new(AtomicUsize::new(0));
+            let release = |mut counter: Counter, lasts: Arc<AtomicUsize>| {
+                if counter.release() == Some(0) {
+                    lasts.fetch_add(1, Ordering::SeqCst);
+                }
+            };
+            let counter = Counter::new();
+            let thread = {
+                let (counter, lasts) = (counter.clone(), lasts.clone());
+                loom::thread::spawn(move || release(counter, lasts))
+            };
+            release(counter, lasts.clone());
+            thread.join().unwrap();
+            assert_eq!(lasts.load(Ordering::SeqCst), 1);
+        });
+    }
+}
@@ -0,0 +1,491 @@
+// Copyright 2019 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Specifies the messages tarpc clients and servers exchange, so that they can be implemented
+//! in other languages, and exports conformance [`vectors`] to check such implementations
+//! against.
+//!
+//! This is version [`VERSION`] of the specification. Changes that old peers can't read bump
+//! the version.
+//!
+//! # Versioning
+//!
+//! Requests and responses are written as an enum with a variant per version of their layout,
+//! e.g. `V1`, so that positional formats, which can't tell a field that's missing from one
+//! that's out of place, never misread one layout as another. A version's layout never changes:
+//! adding a field adds a variant, which new peers write and read alongside the old ones, and
+//! which old peers reject as an unknown variant rather than misreading. Every field is
+//! required, in every format.
+//!
+//! # Messages
+//!
+//! Messages are serialized with serde, in the format of the transport, e.g. bincode or JSON.
+//! The transport frames them; the bincode and JSON transports prefix each message with its
+//! length, as a 4-byte big-endian integer. Below, structs are listed with their fields in
+//! order, which matters to positional formats like bincode, and enums with their variants in
+//! order, which bincode identifies by index. Text formats like JSON write structs as objects
+//! and enums as objects with a single field, named for the variant, except unit variants, which
+//! are written as the variant's name.
+//!
+//! A client sends a `ClientMessage`, an enum with the variants:
+//!
+//! 0. `Request`, an enum of the versions of the request layout:
+//!    0. `V1`, a struct of:
+//!       - `context`: a `Context`, below.
+//!       - `id`: a `u64`, unique among the requests in flight on the connection.
+//!       - `message`: the request, as defined by the service.
+//!       - `one_way`: a `bool`. The server sends no response to one-way requests.
+//! 1. `Cancel`, a struct of:
+//!    - `trace_context`: a trace `Context`, below.
+//!    - `request_id`: the `u64` id of the request to cancel. Servers ignore unknown ids.
+//!
+//! A request's `Context` is a struct of:
+//!
+//! - `deadline`: a `u64`, the seconds since the Unix epoch by which the client expects the
+//!   response. The server fails requests that pass their deadline with `TimedOut`.
+//! - `trace_context`: a trace `Context`, a struct of:
+//!   - `trace_id`: a `u128` identifying the trace. Text formats write it as a number, which
+//!     can't be represented exactly as a double.
+//!   - `span_id`: a `u64` identifying the client's span.
+//!   - `parent_id`: an optional `u64`, the span of which the client's span is a child.
+//!   - `sampled`: a `bool`, whether the trace is recorded.
+//! - `metadata`: a map of strings to strings, read by the server.
+//! - `baggage`: a map of strings to strings, read by the server and forwarded on the requests
+//!   it makes while handling the request.
+//! - `time_remaining`: an optional duration, the time left until the deadline when the client
+//!   sent the request, a struct of `secs`, a `u64`, and `nanos`, a `u32`. Servers prefer it to
+//!   `deadline`, since it doesn't depend on the peers' clocks agreeing.
+//! - `_non_exhaustive`: a unit, which bincode writes as nothing, and JSON as `null`.
+//!
+//! A server sends a `Response`, an enum of the versions of the response layout:
+//!
+//! 0. `V1`, a struct of:
+//!    - `request_id`: the `u64` id of the request responded to.
+//!    - `message`: a `Result` enum, with the variants:
+//!      0. `Ok`: the response, as defined by the service.
+//!      1. `Err`: a `ServerError`, a struct of:
+//!         - `kind`: a `u32`, the kind of error: 0 `NotFound`, 1 `PermissionDenied`,
+//!           2 `ConnectionRefused`, 3 `ConnectionReset`, 4 `ConnectionAborted`,
+//!           5 `NotConnected`, 6 `AddrInUse`, 7 `AddrNotAvailable`, 8 `BrokenPipe`,
+//!           9 `AlreadyExists`, 10 `WouldBlock`, 11 `InvalidInput`, 12 `InvalidData`,
+//!           13 `TimedOut`, 14 `WriteZero`, 15 `Interrupted`, 16 `Other`, 17 `UnexpectedEof`,
+//!           18 `Unauthenticated`. Unknown kinds are read as `Other`.
+//!         - `detail`: an optional string describing the error.
+//!         - `_non_exhaustive`: a unit.
+//!
+//!         A server rejects a request that lacks valid credentials with an `Unauthenticated`
+//!         error, which clients read as a `PermissionDenied` error marked
+//!         [unauthenticated](crate::ServerError::unauthenticated), so that they can tell it
+//!         from other `PermissionDenied` errors, such as authorization failures, whatever the
+//!         `detail`. Peers that predate the kind read it as `Other`.
+//!    - `partial`: a `bool`, whether more responses to the request follow.
+//!
+//! Responses can arrive in any order. A server that fails to parse a message may close the
+//! connection, or skip the message, answering it with an `InvalidData` error if it's a two-way
+//! request whose `id` it could read.
+//!
+//! # Services
+//!
+//! A service's requests are an enum with a variant per RPC, named for the RPC's wire name and
+//! holding a struct of its arguments, in order. Its responses are an enum with a variant per
+//! RPC holding its output, in the same order, so that bincode gives an RPC's request and
+//! response the same index. The output of an RPC declared with `#[throws(E)]` is a `Result` of
+//! its output and `E`.
+//!
+//! An RPC's index is its position in the service definition, or, if the service gives its RPCs
+//! ids with `#[id = N]`, its id. Indices without an RPC hold a variant with no values, named
+//! `__Reserved{N}` for index `N`, which is never sent. An RPC declared with `#[removed(V)]` is
+//! still declared, so it keeps its index; servers answer it with an `InvalidInput` error once
+//! they no longer support a version below `V`.
+//!
+//! A versioned service, one with an RPC declared with `#[since]` or `#[removed]`, has one more
+//! variant in each enum, after every RPC's, so that versioning a service doesn't change the
+//! indices of its RPCs:
+//!
+//! - The request variant `__Negotiate` is a struct of `min_version` and `max_version`, `u32`s,
+//!   the range of versions of the service the client supports.
+//! - The response variant `__Negotiate` holds an optional `u32`: the newest version both peers
+//!   support, or none if there's no such version.
+//!
+//! A streaming RPC responds with any number of partial responses, each holding an item,
+//! followed by one final response. Its output is an `Option` of the item type: partial
+//! responses hold `Some(item)`, and the final response holds `None`, or an error.
+//!
+//! The [`SampleRequest`] and [`SampleResponse`] enums are what the `service` macro generates
+//! for
+//!
+//! ```ignore
+//! #[tarpc::service]
+//! trait Sample {
+//!     async fn hello(name: String) -> String;
+//!     #[stream]
+//!     async fn count(to: u32) -> u32;
+//!     #[throws(String)]
+//!     async fn divide(dividend: u32, divisor: u32) -> u32;
+//! }
+//! ```
+//!
+//! # Conformance
+//!
+//! Each of the [`vectors`] is a message and its meaning. An implementation conforms if, for each
+//! vector, it writes the message the vector describes as the vector's
+//! [encoding](Vector::encode), and reads the encoding as that message. Encodings an
+//! implementation writes can be [checked](Vector::check) by decoding them with tarpc.
+//! `tarpc-cli conformance` prints the vectors as JSON, with their bincode and JSON encodings.
+
+use crate::{codec::Codec, context, ClientMessage, Request, Response, ServerError};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io,
+    time::{Duration, SystemTime},
+};
+
+/// The version of the wire protocol this module specifies.
+pub const VERSION: u32 = 1;
+
+/// The requests of the sample service used by the conformance vectors.
+#[allow(missing_docs)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SampleRequest {
+    Hello { name: String },
+    Count { to: u32 },
+    Divide { dividend: u32, divisor: u32 },
+}
+
+/// The responses of the sample service used by the conformance vectors.
+#[allow(missing_docs)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SampleResponse {
+    Hello(String),
+    Count(Option<u32>),
+    Divide(Result<u32, String>),
+}
+
+/// A message of the [sample service](SampleRequest).
+#[derive(Clone, Debug)]
+pub enum Message {
+    /// A message from a client.
+    Client(ClientMessage<SampleRequest>),
+    /// A message from a server.
+    Server(Response<SampleResponse>),
+}
+
+/// A conformance vector: a message, and its meaning.
+#[derive(Clone, Debug)]
+pub struct Vector {
+    /// Identifies the vector.
+    pub name: &'static str,
+    /// What the message means.
+    pub description: &'static str,
+    /// The message.
+    pub message: Message,
+    #[doc(hidden)]
+    _non_exhaustive: (),
+}
+
+impl Vector {
+    /// Returns the message, serialized with `C`.
+    pub fn encode<C: Codec>(&self) -> io::Result<Vec<u8>> {
+        match self.message {
+            Message::Client(ref message) => C::encode(message),
+            Message::Server(ref message) => C::encode(message),
+        }
+    }
+
+    /// Checks that `bytes`, written by another implementation, decode with `C` as the
+    /// message, returning an [`InvalidData`](io::ErrorKind::InvalidData) error if they don't.
+    ///
+    /// Equivalent encodings, like JSON objects whose fields are in another order, pass.
+    pub fn check<C: Codec>(&self, bytes: &[u8]) -> io::Result<()> {
+        let reencoded = match self.message {
+            Message::Client(_) => C::encode(&C::decode::<ClientMessage<SampleRequest>>(bytes)?)?,
+            Message::Server(_) => C::encode(&C::decode::<Response<SampleResponse>>(bytes)?)?,
+        };
+        if reencoded != self.encode::<C>()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the encoding isn't the message of `{}`", self.name),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the conformance vectors of this version of the protocol.
+pub fn vectors() -> Vec<Vector> {
+    let vector = |name, description, message| Vector {
+        name,
+        description,
+        message,
+        _non_exhaustive: (),
+    };
+    let request = |id, message| {
+        let mut request = Request::new(context(), id, message);
+        request.context.time_remaining = Some(Duration::from_millis(9_500));
+        ClientMessage::Request(request)
+    };
+    let response = |request_id, message, partial| {
+        Message::Server(Response {
+            request_id,
+            message,
+            partial,
+            _non_exhaustive: (),
+        })
+    };
+
+    let mut with_metadata = Request::new(
+        context(),
+        2,
+        SampleRequest::Hello {
+            name: "Grace".into(),
+        },
+    );
+    with_metadata.context.trace_context.parent_id = Some(7.into());
+    with_metadata.context.trace_context.sampled = false;
+    with_metadata
+        .context
+        .metadata
+        .insert("authorization".into(), "Bearer abc".into());
+    with_metadata
+        .context
+        .baggage
+        .insert("tenant".into(), "acme".into());
+
+    let mut one_way = Request::new(context(), 3, SampleRequest::Hello { name: "Ada".into() });
+    one_way.one_way = true;
+
+    vec![
+        vector(
+            "request",
+            "Request 1 calls `hello` with name \"Ada\", a deadline of 1600000000 seconds since \
+             the Unix epoch, with 9.5 seconds remaining, in trace \
+             0x0102030405060708090a0b0c0d0e0f10, span 0x1112131415161718, which is a root span.",
+            Message::Client(request(1, SampleRequest::Hello { name: "Ada".into() })),
+        ),
+        vector(
+            "request_with_metadata",
+            "Request 2 calls `hello` with name \"Grace\", in an unsampled span whose parent is \
+             span 7, with metadata `authorization: Bearer abc` and baggage `tenant: acme`. It \
+             doesn't say how much time remains until its deadline.",
+            Message::Client(ClientMessage::Request(with_metadata)),
+        ),
+        vector(
+            "one_way_request",
+            "Request 3 calls `hello` with name \"Ada\", without expecting a response.",
+            Message::Client(ClientMessage::Request(one_way)),
+        ),
+        vector(
+            "streaming_request",
+            "Request 4 calls the streaming RPC `count` with 2.",
+            Message::Client(request(4, SampleRequest::Count { to: 2 })),
+        ),
+        vector(
+            "cancel",
+            "Cancels request 4, in its trace.",
+            Message::Client(ClientMessage::Cancel {
+                trace_context: context().trace_context,
+                request_id: 4,
+            }),
+        ),
+        vector(
+            "response",
+            "Request 1 returned \"Hello, Ada!\".",
+            response(1, Ok(SampleResponse::Hello("Hello, Ada!".into())), false),
+        ),
+        vector(
+            "partial_response",
+            "Request 4 streamed 1, and will stream more.",
+            response(4, Ok(SampleResponse::Count(Some(1))), true),
+        ),
+        vector(
+            "final_response",
+            "Request 4 finished streaming.",
+            response(4, Ok(SampleResponse::Count(None)), false),
+        ),
+        vector(
+            "thrown_error",
+            "Request 5, a call to `divide`, threw \"division by zero\".",
+            response(
+                5,
+                Ok(SampleResponse::Divide(Err("division by zero".into()))),
+                false,
+            ),
+        ),
+        vector(
+            "server_error",
+            "Request 6 failed with a `TimedOut` error, described as \"deadline exceeded\".",
+            response(
+                6,
+                Err(ServerError::new(
+                    io::ErrorKind::TimedOut,
+                    Some("deadline exceeded".into()),
+                )),
+                false,
+            ),
+        ),
+        vector(
+            "server_error_without_detail",
+            "Request 7 failed with a `WouldBlock` error, without a description.",
+            response(
+                7,
+                Err(ServerError::new(io::ErrorKind::WouldBlock, None)),
+                false,
+            ),
+        ),
+        vector(
+            "unauthenticated",
+            "Request 8 was rejected for lacking valid credentials, described as \"token expired\".",
+            response(
+                8,
+                Err({
+                    let mut error = ServerError::new(
+                        io::ErrorKind::PermissionDenied,
+                        Some("token expired".into()),
+                    );
+                    error.unauthenticated = true;
+                    error
+                }),
+                false,
+            ),
+        ),
+    ]
+}
+
+/// The context of the vectors' requests.
+fn context() -> context::Context {
+    context::Context {
+        deadline: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        trace_context: trace::Context {
+            trace_id: 0x0102_0304_0506_0708_090a_0b0c_0d0e_0f10.into(),
+            span_id: 0x1112_1314_1516_1718.into(),
+            parent_id: None,
+            sampled: true,
+        },
+        metadata: BTreeMap::new(),
+        baggage: BTreeMap::new(),
+        time_remaining: None,
+        request_id: None,
+        peer_identity: None,
+        _non_exhaustive: (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{vectors, Message, SampleRequest, SampleResponse};
+    use crate::codec::{Bincode, Codec, Json};
+    use std::io;
+
+    fn vector(name: &str) -> super::Vector {
+        vectors().into_iter().find(|v| v.name == name).unwrap()
+    }
+
+    /// Checks that `tarpc-proto` reads the vector's encoding with `C` as the vector's message,
+    /// converted, and writes it back unchanged.
+    fn check_proto<C: Codec>(vector: &super::Vector) -> io::Result<()> {
+        let encoding = vector.encode::<C>()?;
+        let reencoded = match vector.message {
+            Message::Client(ref message) => {
+                let decoded: proto::ClientMessage<SampleRequest> = C::decode(&encoding)?;
+                assert_eq!(decoded, message.clone().into(), "{}", vector.name);
+                C::encode(&decoded)?
+            }
+            Message::Server(ref message) => {
+                let decoded: proto::Response<SampleResponse> = C::decode(&encoding)?;
+                assert_eq!(decoded, message.clone().into(), "{}", vector.name);
+                C::encode(&decoded)?
+            }
+        };
+        assert_eq!(reencoded, encoding, "{}", vector.name);
+        Ok(())
+    }
+
+    #[test]
+    fn vectors_check_against_their_encodings() -> io::Result<()> {
+        for vector in vectors() {
+            vector.check::<Json>(&vector.encode::<Json>()?)?;
+            vector.check::<Bincode>(&vector.encode::<Bincode>()?)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn proto_messages_are_wire_compatible() -> io::Result<()> {
+        for vector in vectors() {
+            check_proto::<Json>(&vector)?;
+            check_proto::<Bincode>(&vector)?;
+        }
+        Ok(())
+    }
+
+    // The encodings below are the protocol; a change that breaks these tests breaks peers.
+    #[test]
+    fn json_encodings() -> io::Result<()> {
+        assert_eq!(
+            String::from_utf8(vector("request").encode::<Json>()?).unwrap(),
+            r#"{"Request":{"V1":{"context":{"deadline":1600000000,"trace_context":{"trace_id":1339673755198158349044581307228491536,"span_id":1230066625199609624,"parent_id":null,"sampled":true},"metadata":{},"baggage":{},"time_remaining":{"secs":9,"nanos":500000000},"_non_exhaustive":null},"id":1,"message":{"Hello":{"name":"Ada"}},"one_way":false}}}"#
+        );
+        assert_eq!(
+            String::from_utf8(vector("cancel").encode::<Json>()?).unwrap(),
+            r#"{"Cancel":{"trace_context":{"trace_id":1339673755198158349044581307228491536,"span_id":1230066625199609624,"parent_id":null,"sampled":true},"request_id":4}}"#
+        );
+        assert_eq!(
+            String::from_utf8(vector("partial_response").encode::<Json>()?).unwrap(),
+            r#"{"V1":{"request_id":4,"message":{"Ok":{"Count":1}},"partial":true}}"#
+        );
+        assert_eq!(
+            String::from_utf8(vector("server_error").encode::<Json>()?).unwrap(),
+            r#"{"V1":{"request_id":6,"message":{"Err":{"kind":13,"detail":"deadline exceeded","_non_exhaustive":null}},"partial":false}}"#
+        );
+        assert_eq!(
+            String::from_utf8(vector("unauthenticated").encode::<Json>()?).unwrap(),
+            r#"{"V1":{"request_id":8,"message":{"Err":{"kind":18,"detail":"token expired","_non_exhaustive":null}},"partial":false}}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bincode_encodings() -> io::Result<()> {
+        assert_eq!(
+            vector("final_response").encode::<Bincode>()?,
+            [
+                0, 0, 0, 0, // V1
+                4, 0, 0, 0, 0, 0, 0, 0, // request_id
+                0, 0, 0, 0, // Ok
+                1, 0, 0, 0, // Count
+                0, // None
+                0, // partial
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn checks_equivalent_encodings() -> io::Result<()> {
+        // Fields reordered.
+        let request = br#"{"Request":{"V1":{"one_way":false,"id":1,"message":{"Hello":{"name":"Ada"}},"context":{"trace_context":{"sampled":true,"span_id":1230066625199609624,"trace_id":1339673755198158349044581307228491536,"parent_id":null},"deadline":1600000000,"baggage":{},"metadata":{},"time_remaining":{"secs":9,"nanos":500000000},"_non_exhaustive":null}}}}"#;
+        vector("request").check::<Json>(request)?;
+
+        let wrong_id = br#"{"V1":{"request_id":5,"message":{"Ok":{"Count":1}},"partial":true}}"#;
+        let e = vector("partial_response")
+            .check::<Json>(wrong_id)
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn fields_are_required() {
+        // Like the partial response, without `partial`.
+        let response = br#"{"V1":{"request_id":4,"message":{"Ok":{"Count":1}}}}"#;
+        assert!(vector("partial_response").check::<Json>(response).is_err());
+
+        // A layout this version doesn't know.
+        let response = br#"{"V2":{"request_id":4,"message":{"Ok":{"Count":1}},"partial":true}}"#;
+        assert!(vector("partial_response").check::<Json>(response).is_err());
+    }
+}
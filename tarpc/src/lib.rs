@@ -45,6 +45,32 @@
 //! - Serde serialization: enabling the `serde1` Cargo feature will make service requests and
 //!   responses `Serialize + Deserialize`. It's entirely optional, though: in-memory transports can
 //!   be used, as well, so the price of eerialization doesn't have to be paid when it's not needed.
+//! - Tracing: enabling the `tracing` Cargo feature instruments client dispatch and server
+//!   request handling with [`tracing`](https://docs.rs/tracing) spans carrying the method name,
+//!   request id, trace id, and deadline (plus the peer on the server). The default event sink
+//!   writes events to `tracing`, within the span of the request they concern, rather than `log`.
+//! - Metrics: servers record per-method latency histograms and error counts to a
+//!   `server::metrics::MetricsRecorder`. The `prometheus` and `statsd` Cargo features add
+//!   exporters for the Prometheus text format and StatsD.
+//! - Runtime agnostic: clients and servers spawn tasks and set timers through
+//!   `tarpc::runtime`, which uses tokio by default. Disabling the default features and enabling
+//!   `async-std1` runs them on [async-std](https://docs.rs/async-std) instead. With `glommio1`,
+//!   `tarpc::server::glommio` serves thread-per-core, on [glommio](https://docs.rs/glommio).
+//! - Slim builds: the `client` and `server` Cargo features, both on by default, each bring in
+//!   their half of tarpc and of the code the `service` attribute generates. A command-line tool
+//!   that only calls a service can depend on
+//!   `tarpc = { version = "0.18", default-features = false, features = ["client"] }`, which
+//!   leaves out the server and tokio; it polls the client's dispatch itself, and times out
+//!   requests with a timer thread. Transports are separate crates, so only the ones
+//!   depended on are built.
+//! - Mobile clients: client-only builds run on Android and iOS. The `blocking` feature adds
+//!   `tarpc::client::blocking`, which drives a client's dispatch on a background thread and
+//!   blocks the caller on each request, so apps can call tarpc backends through synchronous FFI
+//!   bindings, such as UniFFI's.
+//! - Bearer-token auth: `tarpc::client::auth::BearerAuth` sends each request with a token and
+//!   refreshes it with a user-supplied async function when the server rejects it as
+//!   unauthenticated; servers check tokens with `Channel::authenticate` or
+//!   `Handler::authenticate`.
 //!
 //! ## Usage
 //! Add to your `Cargo.toml` dependencies:
@@ -222,10 +248,152 @@ pub use rpc::*;
 /// will then be attached to the generated service traits'
 /// corresponding `fn`s, as well as to the client stubs' RPCs.
 ///
-/// The following items are expanded in the enclosing module:
+/// The following attributes are instead interpreted by the macro:
+///
+/// * `#[oneway]` -- the client stub resolves as soon as the request is enqueued, and the server
+///   does not send a response. One-way RPCs cannot return a value.
+/// * `#[stream]` -- the server responds with a stream of values of the return type, rather than a
+///   single value. The service trait returns a `Stream` for the RPC, and the client stub resolves
+///   to a stream of `io::Result`s.
+/// * `#[throws(E)]` -- the RPC can fail with an error of type `E`. The service trait returns a
+///   `Result<T, E>` for the RPC, and the client stub resolves to a
+///   `Result<T, client::CallError<E>>`, which separates errors returned by the service from errors
+///   of the RPC itself. Cannot be combined with `#[oneway]` or `#[stream]`.
+/// * `#[id = N]` -- the position of the RPC's variants in the generated request and response
+///   enums, which is how positional formats like bincode identify the RPC on the wire. Without
+///   ids, RPCs are numbered in source order, so reordering or removing RPCs changes the wire
+///   format. If any RPC has an id, all must, and ids must be unique and less than 1024.
+/// * `#[name = "..."]` -- the name of the RPC's variants when serialized, for formats that
+///   identify the RPC by name, like JSON. Names must be unique. Requires `derive_serde`.
+/// * `#[since(N)]`, `#[removed(N)]` -- the version of the service that added or removed the RPC.
+///   Services with versioned RPCs, or with the `version` option, are versioned: their servers
+///   answer version negotiation, and their client stubs refuse to send RPCs that the negotiated
+///   version doesn't have. Version negotiation is encoded after every RPC, so versioning a
+///   service doesn't change its RPCs' ids. Servers reject calls to an RPC removed from every
+///   version they support.
+/// * `#[run_inline]` -- the server runs the RPC's handler directly on the connection's task
+///   instead of spawning it, saving a task per request for trivially cheap handlers. Handlers that
+///   don't finish within the server's `inline_timeout` are spawned anyway.
+///
+/// RPC args can be marked `#[redact]`, e.g. `async fn login(user: String, #[redact] password:
+/// String)`, so that the request's `Debug` output shows `<redacted>` in place of their values.
+///
+/// The macro accepts the following options, e.g. `#[tarpc::service(schema = true)]`:
+///
+/// * `derive_serde = {bool}` -- whether to derive serde traits for the request and response
+///   enums. Defaults to whether the `serde1` feature is enabled.
+/// * `schema = {bool}` -- whether to emit a [schema](schema::Service) describing the service.
+///   Defaults to false.
+/// * `mock = {bool}` -- whether to emit a mock client for unit tests. Defaults to false. To only
+///   emit it in tests, use `#[cfg_attr(test, tarpc::service(mock = true))]` together with
+///   `#[cfg_attr(not(test), tarpc::service)]`.
+/// * `from_fn = {bool}` -- whether to emit a `ServiceFn` that implements the service by calling a
+///   closure. Only services with a single RPC can set it. Defaults to false.
+/// * `args_hash = {bool}` -- whether servers hash each request's args, leaving out redacted
+///   ones, to identify requests in the [audit log](server::audit) without revealing them.
+///   Requires the args to implement `Hash`. Defaults to false.
+/// * `arc_self = {bool}` -- whether RPC methods take `self: Arc<Self>` rather than `self`. The
+///   service trait then doesn't require `Clone`, and `serve` takes an `Arc<Self>`, which is cloned
+///   for each request, so services with heavyweight state can share it without cloning it.
+///   Defaults to false.
+/// * `prepared = {bool}` -- whether to give the client a `prepare_rpc` fn and an `rpc_prepared`
+///   method for each RPC that isn't streaming, to [prepare](client::Prepared) a request once and
+///   send it many times. Requires `derive_serde`, and a request enum that implements `Clone`, e.g.
+///   with `derive(Clone, Debug)`. Defaults to false.
+/// * `derive(...)` -- the traits to derive for the request and response enums, besides the serde
+///   traits, e.g. `derive(Clone, PartialEq)`. Replaces the default, `derive(Debug)`. Deriving
+///   `PartialEq` and an `Arbitrary` impl, e.g. `proptest_derive::Arbitrary`, lets tests check
+///   that requests and responses roundtrip through a transport's codec; see `codec::roundtrips`.
+/// * `serde(...)` -- a serde container attribute for the request and response enums, e.g.
+///   `serde(rename_all = "snake_case")`. May be given more than once. Requires `derive_serde`.
+/// * `wire_case = "..."` -- the case of RPC and argument names when serialized, for formats that
+///   identify them by name, like JSON: `"camelCase"`, which is idiomatic for JavaScript peers, or
+///   `"snake_case"`. Names set by `#[name]` are kept as is. Defaults to the names of the generated
+///   variants and fields. Requires `derive_serde`.
+/// * `vis = "..."` -- the visibility of the generated items, e.g. `vis = "pub(crate)"`. Defaults
+///   to the visibility of the service trait.
+/// * `client = "..."`, `request = "..."`, `response = "..."`, `serve = "..."` -- the names of the
+///   generated client stub, request and response enums, and serving struct. Default to
+///   `ServiceClient`, `ServiceRequest`, `ServiceResponse`, and `ServeService`.
+/// * `types_only = {bool}` -- whether to only emit the request and response enums. They then
+///   depend on neither tarpc nor std, so a `no_std` peer can share the exact wire types of a
+///   service by depending on `tarpc-plugins` and `serde` alone. Can't be combined with options
+///   that configure the other items. Defaults to false.
+/// * `version = N` -- the current version of the service. Defaults to the latest version named by
+///   a `#[since]` or `#[removed]` attribute.
+///
+/// The macro can also be attached to a module, to define a family of related services along with
+/// the types they share, like a common error enum. The options apply to every service trait in the
+/// module, except for the names of generated items, which can't be set:
+///
+/// ```rust
+/// #[tarpc::service(derive_serde = false)]
+/// mod api {
+///     #[derive(Debug)]
+///     pub enum ApiError {
+///         Unauthorized,
+///     }
+///
+///     pub trait Admin {
+///         #[throws(ApiError)]
+///         async fn reset();
+///     }
+///
+///     pub trait Health {
+///         async fn healthy() -> bool;
+///     }
+/// }
+/// ```
+///
+/// The following items are expanded in the enclosing module, or in the module of services for
+/// each of its service traits:
 ///
 /// * `trait Service` -- defines the RPC service.
 ///   * `fn serve` -- turns a service impl into a request handler.
 /// * `Client` -- a client stub with a fn for each RPC.
 ///   * `fn new_stub` -- creates a new Client stub.
+///   * `fn negotiate` -- picks the newest version supported by both the client and the server.
+///     Only emitted for versioned services, along with `const VERSION` and `fn version`.
+/// * `ServeService` -- the request handler returned by `serve`. For versioned services,
+///   `fn min_version` sets the oldest version that clients can negotiate, so that one server can
+///   serve every version from `min_version` to the current one.
+/// * `ServiceRequest` and `ServiceResponse` -- the enums sent over the wire. The request enum
+///   implements [`schema::Introspect`], which lists the RPCs and names the RPC of each request,
+///   and the response enum implements [`schema::IntrospectResponse`], which names the RPC of each
+///   response.
+/// * `const SERVICE_SCHEMA` -- describes the service. Only emitted with `schema = true`.
+/// * `ServiceFn` -- implements a service with a single RPC by calling a closure, e.g.
+///   `WorldFn::from_fn(|_ctx, name| future::ready(format!("Hello, {}!", name)))`, which is handy for
+///   prototypes and test fixtures. Only emitted with `from_fn = true`.
+/// * `MockClient` -- a [`Client`](client::Client) that answers requests with
+///   [expectations](client::mock::Expectation) set by an `expect_*` fn for each RPC. Wrap it with
+///   `Client::from` to get a client stub. Only emitted with `mock = true`. To test code that
+///   connects to a server instead, serve the request enum with
+///   [`MockServer`](server::mock::MockServer).
 pub use tarpc_plugins::service;
+
+/// Lets service impls define RPCs with `async fn`s, instead of defining a future type for each
+/// RPC. Attach it to the impl of a service trait:
+///
+/// ```rust
+/// # use tarpc::context;
+/// #[tarpc::service]
+/// trait World {
+///     async fn hello(name: String) -> String;
+/// }
+///
+/// #[derive(Clone)]
+/// struct HelloServer;
+///
+/// #[tarpc::server]
+/// impl World for HelloServer {
+///     async fn hello(self, _: context::Context, name: String) -> String {
+///         format!("Hello, {}!", name)
+///     }
+/// }
+/// ```
+///
+/// Each `async fn` is rewritten to return a boxed future, and the RPC's future type, e.g.
+/// `HelloFut`, is defined as `Pin<Box<dyn Future<Output = String> + Send>>` unless the impl
+/// defines it. Streaming RPCs still need to define their stream types.
+pub use tarpc_plugins::server;
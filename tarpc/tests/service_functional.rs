@@ -1,112 +1,2243 @@
 use assert_matches::assert_matches;
 use futures::{
+    channel::mpsc,
     future::{ready, Ready},
     prelude::*,
 };
-use std::{io, rc::Rc};
+use std::{
+    io,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tarpc::{
     client::{self, NewClient},
     context,
+    error::{Classify, ErrorKind},
     server::{self, BaseChannel, Channel, Handler},
     transport::channel,
 };
 
-#[tarpc_plugins::service]
-trait Service {
-    async fn add(x: i32, y: i32) -> i32;
-    async fn hey(name: String) -> String;
+#[tarpc_plugins::service]
+trait Service {
+    async fn add(x: i32, y: i32) -> i32;
+    async fn hey(name: String) -> String;
+}
+
+#[derive(Clone)]
+struct Server;
+
+impl Service for Server {
+    type AddFut = Ready<i32>;
+
+    fn add(self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
+        ready(x + y)
+    }
+
+    type HeyFut = Ready<String>;
+
+    fn hey(self, _: context::Context, name: String) -> Self::HeyFut {
+        ready(format!("Hey, {}.", name))
+    }
+}
+
+#[tokio::test]
+async fn server_metrics() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let recorder = Arc::new(server::metrics::InMemoryRecorder::new());
+    let mut config = server::Config::default();
+    config.metrics = Some(recorder.clone());
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(Server.serve())
+            .execute(),
+    );
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    client.add(context::current(), 1, 2).await?;
+    client.add(context::current(), 3, 4).await?;
+    client.hey(context::current(), "Tim".into()).await?;
+
+    let metrics = recorder.snapshot();
+    assert_eq!(metrics["add"].latency.count, 2);
+    assert_eq!(metrics["hey"].latency.count, 1);
+    assert!(metrics["add"].errors.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn corked_flush_policy() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let corked = tarpc::transport::FlushPolicy::Corked {
+        max_messages: 10,
+        max_delay: Duration::from_millis(1),
+    };
+    let (tx, rx) = channel::unbounded();
+    let mut server_config = server::Config::default();
+    server_config.flush = corked;
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(Server.serve())
+            .execute(),
+    );
+
+    let mut client_config = client::Config::default();
+    client_config.flush = corked;
+    let mut client = ServiceClient::new(client_config, tx).spawn()?;
+    // A lone request is held until the delay elapses, rather than forever.
+    assert_eq!(client.add(context::current(), 1, 2).await?, 3);
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Stall {
+    async fn stall();
+}
+
+/// Never responds, signaling when a request starts and when its handler is dropped.
+#[derive(Clone)]
+struct StallServer {
+    started: mpsc::UnboundedSender<()>,
+    dropped: mpsc::UnboundedSender<()>,
+}
+
+struct SignalOnDrop(mpsc::UnboundedSender<()>);
+
+impl Drop for SignalOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.unbounded_send(());
+    }
+}
+
+#[tarpc::server]
+impl Stall for StallServer {
+    async fn stall(self, _: context::Context) {
+        let _dropped = SignalOnDrop(self.dropped);
+        let _ = self.started.unbounded_send(());
+        future::pending::<()>().await
+    }
+}
+
+#[tokio::test]
+async fn server_interruption_metrics() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let (started_tx, mut started) = mpsc::unbounded();
+    let (dropped_tx, mut dropped) = mpsc::unbounded();
+    let recorder = Arc::new(server::metrics::InMemoryRecorder::new());
+    let mut config = server::Config::default();
+    config.metrics = Some(recorder.clone());
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(
+                StallServer {
+                    started: started_tx,
+                    dropped: dropped_tx,
+                }
+                .serve(),
+            )
+            .execute(),
+    );
+
+    let mut client = StallClient::new(client::Config::default(), tx).spawn()?;
+    // Dropping the call once the server has started handling it cancels the request.
+    {
+        let call = client.stall(context::current());
+        futures::pin_mut!(call);
+        future::select(call, started.next()).await;
+    }
+    dropped.next().await;
+
+    let metrics = recorder.snapshot();
+    assert_eq!(
+        metrics["stall"].interruptions[&server::metrics::Interruption::CanceledDuringExecution],
+        1
+    );
+    assert_eq!(metrics["stall"].latency.count, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_watchdog() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let (started_tx, mut started) = mpsc::unbounded();
+    let (dropped_tx, mut dropped) = mpsc::unbounded();
+    let watchdog = server::Watchdog::new(Duration::from_millis(0));
+    let mut config = server::Config::default();
+    config.watchdog = Some(watchdog.clone());
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(
+                StallServer {
+                    started: started_tx,
+                    dropped: dropped_tx,
+                }
+                .serve(),
+            )
+            .execute(),
+    );
+
+    let mut client = StallClient::new(client::Config::default(), tx).spawn()?;
+    {
+        let call = client.stall(context::current());
+        futures::pin_mut!(call);
+        future::select(call, started.next()).await;
+
+        let long_calls = watchdog.long_calls();
+        assert_eq!(long_calls.len(), 1);
+        assert_eq!(long_calls[0].method, "stall");
+        assert!(!long_calls[0].awaiting_poll);
+    }
+    dropped.next().await;
+    assert!(watchdog.long_calls().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn deadlines_in_virtual_time() -> io::Result<()> {
+    use futures::channel::oneshot;
+    use tarpc::testing::MockTime;
+
+    let _ = env_logger::try_init();
+
+    let mut time = MockTime::new();
+    let (tx, rx) = channel::unbounded();
+    let (started_tx, mut started) = mpsc::unbounded();
+    let (dropped_tx, mut dropped) = mpsc::unbounded();
+    let watchdog = server::Watchdog::new(Duration::from_secs(5));
+    let mut config = server::Config::default();
+    config.watchdog = Some(watchdog.clone());
+    config.timer = time.timer();
+    time.spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(
+                StallServer {
+                    started: started_tx,
+                    dropped: dropped_tx,
+                }
+                .serve(),
+            )
+            .execute(),
+    );
+
+    let (result_tx, result) = oneshot::channel();
+    let mut config = client::Config::default();
+    config.timer = time.timer();
+    let mut ctx = context::current();
+    ctx.deadline = time.system_now() + Duration::from_secs(10);
+    time.spawn(async move {
+        let mut client = StallClient::new(config, tx).spawn().unwrap();
+        let _ = result_tx.send(client.stall(ctx).await);
+    });
+    time.run_until_stalled();
+    assert_matches!(started.try_next(), Ok(Some(())));
+
+    time.advance(Duration::from_secs(4));
+    assert!(time.enter(|| watchdog.long_calls()).is_empty());
+    time.advance(Duration::from_secs(2));
+    assert_eq!(time.enter(|| watchdog.long_calls()).len(), 1);
+
+    // The request's ten second deadline passes without the test waiting for it.
+    assert_matches!(
+        time.block_on(result).unwrap(),
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut
+    );
+    assert_eq!(time.elapsed(), Duration::from_secs(10));
+    time.run_until_stalled();
+    assert_matches!(dropped.try_next(), Ok(Some(())));
+
+    Ok(())
+}
+
+#[test]
+fn latency_budgets() -> io::Result<()> {
+    use tarpc::{
+        testing::MockTime,
+        transport::testing::{Latency, Link, SimulatedLink},
+    };
+
+    let _ = env_logger::try_init();
+
+    let mut time = MockTime::new();
+    let (tx, rx) = channel::unbounded();
+    let mut link = Link::default();
+    link.latency = Latency::Fixed(Duration::from_millis(60));
+    let mut config = server::Config::default();
+    config.timer = time.timer();
+    time.spawn(
+        BaseChannel::new(config, SimulatedLink::new(rx, link, 0))
+            .respond_with(Server.serve())
+            .execute(),
+    );
+    let mut config = client::Config::default();
+    config.timer = time.timer();
+    let mut client = time.enter(|| ServiceClient::new(config, tx).spawn())?;
+
+    let mut ctx = context::current();
+    ctx.deadline = time.system_now() + Duration::from_millis(100);
+    let sum = time.assert_within_deadline(&ctx, client.add(ctx.clone(), 1, 2));
+    assert_eq!(sum, 3);
+
+    // The request alone takes longer than the budget.
+    ctx.deadline = time.system_now() + Duration::from_millis(50);
+    time.assert_deadline_exceeded(&ctx, client.add(ctx.clone(), 1, 2));
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Inline {
+    #[run_inline]
+    async fn echo(x: u32) -> u32;
+    #[run_inline]
+    async fn stall();
+    async fn spawned();
+}
+
+#[derive(Clone)]
+struct InlineServer;
+
+#[tarpc::server]
+impl Inline for InlineServer {
+    async fn echo(self, _: context::Context, x: u32) -> u32 {
+        x
+    }
+
+    async fn stall(self, _: context::Context) {
+        future::pending::<()>().await
+    }
+
+    async fn spawned(self, _: context::Context) {}
+}
+
+#[tokio::test]
+async fn inline_handlers() -> io::Result<()> {
+    use tarpc::server::Serve;
+
+    let _ = env_logger::try_init();
+
+    let server = InlineServer.serve();
+    assert!(server.is_inline(&InlineRequest::Echo { x: 1 }));
+    assert!(!server.is_inline(&InlineRequest::Spawned {}));
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(server)
+            .execute(),
+    );
+
+    let mut client = InlineClient::new(client::Config::default(), tx).spawn()?;
+    let mut stall_client = client.clone();
+    {
+        let stall = stall_client.stall(context::current());
+        let echo = client.echo(context::current(), 7);
+        futures::pin_mut!(stall, echo);
+        // The stalled handler outlives the inline timeout, so it's spawned rather than holding up
+        // the requests behind it.
+        match future::select(stall, echo).await {
+            future::Either::Right((echoed, _)) => assert_eq!(echoed?, 7),
+            future::Either::Left(_) => panic!("stall responded"),
+        }
+    }
+    client.spawned(context::current()).await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn slow_inline_handlers_dont_hold_up_others() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let mut config = server::Config::default();
+    // Long enough that the stalled handler is never spawned during the test.
+    config.inline_timeout = Duration::from_secs(3600);
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(InlineServer.serve())
+            .execute(),
+    );
+
+    let mut client = InlineClient::new(client::Config::default(), tx).spawn()?;
+    let mut stall_client = client.clone();
+    let stall = stall_client.stall(context::current());
+    let echo = client.echo(context::current(), 7);
+    futures::pin_mut!(stall, echo);
+    match future::select(stall, echo).await {
+        future::Either::Right((echoed, _)) => assert_eq!(echoed?, 7),
+        future::Either::Left(_) => panic!("stall responded"),
+    }
+
+    Ok(())
+}
+
+/// Echoes each request once the gate opens, counting requests by their length.
+#[derive(Clone)]
+struct GatedEcho(future::Shared<futures::channel::oneshot::Receiver<()>>);
+
+impl server::Serve<String> for GatedEcho {
+    type Resp = String;
+    type Fut = std::pin::Pin<Box<dyn Future<Output = String> + Send>>;
+
+    fn serve(self, _: context::Context, req: String) -> Self::Fut {
+        Box::pin(self.0.map(move |_| req))
+    }
+
+    fn request_size(req: &String) -> usize {
+        req.len()
+    }
+
+    fn response_size(resp: &String) -> usize {
+        resp.len()
+    }
+}
+
+/// Forwards the buffered bytes of each channel that hits its buffer limit to the test.
+#[derive(Debug)]
+struct BufferLimitSink(std::sync::Mutex<mpsc::UnboundedSender<usize>>);
+
+impl tarpc::event::EventSink for BufferLimitSink {
+    fn event(&self, event: &tarpc::event::Event) {
+        if let tarpc::event::Event::BufferLimitReached { buffered_bytes, .. } = *event {
+            let _ = self.0.lock().unwrap().unbounded_send(buffered_bytes);
+        }
+    }
+}
+
+#[tokio::test]
+async fn server_buffer_limit() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (open, gate) = futures::channel::oneshot::channel();
+    let (limits_tx, mut limits) = mpsc::unbounded();
+    let mut config = server::Config::default();
+    config.max_buffered_bytes = Some(4);
+    config.events = Arc::new(BufferLimitSink(std::sync::Mutex::new(limits_tx)));
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(GatedEcho(gate.shared()))
+            .execute(),
+    );
+
+    let NewClient { client, dispatch } = client::new(client::Config::default(), tx);
+    tokio::spawn(dispatch.map(|_| ()));
+    let mut client1 = client.clone();
+    let mut client2 = client;
+    let (first, first_response) =
+        async move { client1.call(context::current(), "four".into()).await }.remote_handle();
+    tokio::spawn(first);
+    // The first request fills the channel's buffer, so it stops reading requests until the
+    // first is handled.
+    assert_eq!(limits.next().await, Some(4));
+    let (second, second_response) =
+        async move { client2.call(context::current(), "more".into()).await }.remote_handle();
+    tokio::spawn(second);
+
+    open.send(()).unwrap();
+    assert_eq!(first_response.await?, "four");
+    assert_eq!(second_response.await?, "more");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_stats() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx1, rx1) = channel::unbounded();
+    let (tx2, rx2) = channel::unbounded();
+    let stats = server::Stats::new();
+    tokio::spawn(
+        stream::iter(vec![rx1, rx2])
+            .map(BaseChannel::with_defaults)
+            .max_channels_per_key(2, |_| "local")
+            .stats(&stats)
+            .respond_with(Server.serve()),
+    );
+
+    let mut client1 = ServiceClient::new(client::Config::default(), tx1).spawn()?;
+    let mut client2 = ServiceClient::new(client::Config::default(), tx2).spawn()?;
+    client1.add(context::current(), 1, 2).await?;
+    client2.add(context::current(), 3, 4).await?;
+
+    assert_eq!(stats.channels(), 2);
+    assert_eq!(stats.channels_per_key()["local"], 2);
+    assert!(stats.in_flight_slots() > 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn scripted_connections() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (connector, listener) = channel::listen();
+    let (started_tx, mut started) = mpsc::unbounded();
+    let (dropped_tx, mut dropped) = mpsc::unbounded();
+    let stats = server::Stats::new();
+    tokio::spawn(
+        listener
+            .map(BaseChannel::with_defaults)
+            .max_channels_per_key(1, |_| "local")
+            .stats(&stats)
+            .respond_with(
+                StallServer {
+                    started: started_tx,
+                    dropped: dropped_tx,
+                }
+                .serve(),
+            ),
+    );
+
+    let (connection, transport) = connector.connect()?;
+    let mut client = StallClient::new(client::Config::default(), transport).spawn()?;
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now() + Duration::from_millis(500);
+    let (call, response) = async move { client.stall(ctx).await }.remote_handle();
+    tokio::spawn(call);
+    started.next().await;
+    assert_eq!(stats.channels(), 1);
+
+    // A second connection is over the limit, so the filter closes it.
+    let (_, transport) = connector.connect()?;
+    let mut client = StallClient::new(client::Config::default(), transport).spawn()?;
+    assert!(client.stall(context::current()).await.is_err());
+
+    // Killing the first connection fails its request in flight. The server handles the request
+    // until its deadline, and then the connection's slot is freed.
+    connection.kill();
+    assert_matches!(
+        response.await,
+        Err(ref e) if e.kind() != io::ErrorKind::TimedOut
+    );
+    dropped.next().await;
+    let (_connection, transport) = connector.connect()?;
+    let mut client = StallClient::new(client::Config::default(), transport).spawn()?;
+    let (call, _response) = async move { client.stall(context::current()).await }.remote_handle();
+    tokio::spawn(call);
+    started.next().await;
+    assert_eq!(stats.channels(), 1);
+
+    Ok(())
+}
+
+/// Forwards audit records to the test.
+struct ForwardingSink(mpsc::UnboundedSender<server::audit::AuditRecord>);
+
+impl server::audit::AuditSink for ForwardingSink {
+    fn write(&mut self, records: &[server::audit::AuditRecord]) -> io::Result<()> {
+        for record in records {
+            let _ = self.0.unbounded_send(record.clone());
+        }
+        Ok(())
+    }
+}
+
+#[tarpc::service(args_hash = true)]
+trait Vault {
+    async fn store(key: String, #[redact] secret: String);
+}
+
+#[derive(Clone)]
+struct VaultServer;
+
+#[tarpc::server]
+impl Vault for VaultServer {
+    async fn store(self, _: context::Context, _key: String, _secret: String) {}
+}
+
+#[tokio::test]
+async fn server_audit() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (records_tx, mut records) = mpsc::unbounded();
+    let (log, writer) = server::AuditLog::new(ForwardingSink(records_tx), 100);
+    tokio::spawn(writer);
+    let mut config = server::Config::default();
+    config.audit = Some(log);
+
+    let (tx, rx) = channel::unbounded();
+    let identity = context::PeerIdentity::new("alice", context::AuthMethod::UnixCredentials);
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .with_peer_identity(identity)
+            .respond_with(VaultServer.serve())
+            .execute(),
+    );
+
+    let mut client = VaultClient::new(client::Config::default(), tx).spawn()?;
+    // Clients can't claim to be someone else through metadata.
+    let ctx = context::current().with_metadata("principal", "mallory");
+    client.store(ctx, "k".into(), "a".into()).await?;
+    client
+        .store(context::current(), "k".into(), "b".into())
+        .await?;
+    client
+        .store(context::current(), "j".into(), "a".into())
+        .await?;
+
+    let first = records.next().await.unwrap();
+    assert_eq!(first.method, "store");
+    assert_eq!(first.principal, Some("alice".into()));
+    assert_eq!(first.result, Ok(()));
+    let second = records.next().await.unwrap();
+    let third = records.next().await.unwrap();
+    assert!(first.args_hash.is_some());
+    // The redacted secret is left out of the hash.
+    assert_eq!(first.args_hash, second.args_hash);
+    assert_ne!(first.args_hash, third.args_hash);
+
+    Ok(())
+}
+
+/// Forwards exported spans to the test.
+struct ForwardingExportSink(mpsc::UnboundedSender<tarpc::export::FinishedSpan>);
+
+impl tarpc::export::ExportSink for ForwardingExportSink {
+    fn export(&mut self, spans: &[tarpc::export::FinishedSpan]) -> io::Result<()> {
+        for span in spans {
+            let _ = self.0.unbounded_send(span.clone());
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn span_export() -> io::Result<()> {
+    use tarpc::export::{SpanExporter, SpanKind};
+
+    let _ = env_logger::try_init();
+
+    let (spans_tx, mut spans) = mpsc::unbounded();
+    let exporter = SpanExporter::new(ForwardingExportSink(spans_tx), 100)?;
+
+    let (tx, rx) = channel::unbounded();
+    let mut server_config = server::Config::default();
+    server_config.spans = Some(exporter.clone());
+    tokio::spawn(
+        BaseChannel::new(server_config, rx)
+            .respond_with(Server.serve())
+            .execute(),
+    );
+
+    let mut client_config = client::Config::default();
+    client_config.spans = Some(exporter);
+    let mut client = ServiceClient::new(client_config, tx).spawn()?;
+    client.add(context::current(), 1, 2).await?;
+
+    let server_span = spans.next().await.unwrap();
+    let client_span = spans.next().await.unwrap();
+    assert_eq!(server_span.kind, SpanKind::Server);
+    assert_eq!(server_span.name, Some("add"));
+    assert_eq!(client_span.kind, SpanKind::Client);
+    assert_eq!(client_span.name, Some("add"));
+    assert_eq!(server_span.trace_context, client_span.trace_context);
+    assert!(client_span.duration >= server_span.duration);
+    assert_eq!(client_span.error, None);
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Login {
+    async fn login(user: String, #[redact] password: String) -> bool;
+}
+
+#[derive(Clone)]
+struct LoginServer;
+
+#[tarpc::server]
+impl Login for LoginServer {
+    async fn login(self, _: context::Context, user: String, password: String) -> bool {
+        user == "alice" && password == "hunter2"
+    }
+}
+
+#[tokio::test]
+async fn server_payload_capture() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let capture = server::PayloadCapture::new(2, 10);
+    let mut config = server::Config::default();
+    config.capture = Some(capture.clone());
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(LoginServer.serve())
+            .execute(),
+    );
+
+    let mut client = LoginClient::new(client::Config::default(), tx).spawn()?;
+    for user in &["alice", "bob", "carol"] {
+        client
+            .login(context::current(), user.to_string(), "hunter2".into())
+            .await?;
+    }
+
+    let captures = capture.captures();
+    assert_eq!(captures.len(), 2);
+    assert_eq!(captures[0].method, "login");
+    assert_eq!(captures[0].request_id, 0);
+    assert!(captures[0].request.contains("alice"));
+    assert!(!captures[0].request.contains("hunter2"));
+    assert_matches!(captures[0].response, Some(ref r) if r.contains("true"));
+    assert!(captures[1].request.contains("carol"));
+    assert_matches!(captures[1].response, Some(ref r) if r.contains("false"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn canceled_captures_have_no_response() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let (started_tx, mut started) = mpsc::unbounded();
+    let (dropped_tx, mut dropped) = mpsc::unbounded();
+    let capture = server::PayloadCapture::new(1, 10);
+    let mut config = server::Config::default();
+    config.capture = Some(capture.clone());
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(
+                StallServer {
+                    started: started_tx,
+                    dropped: dropped_tx,
+                }
+                .serve(),
+            )
+            .execute(),
+    );
+
+    let mut client = StallClient::new(client::Config::default(), tx).spawn()?;
+    {
+        let call = client.stall(context::current());
+        futures::pin_mut!(call);
+        future::select(call, started.next()).await;
+    }
+    dropped.next().await;
+
+    let captures = capture.captures();
+    assert_eq!(captures.len(), 1);
+    assert_eq!(captures[0].method, "stall");
+    assert_eq!(captures[0].response, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn task_names_and_counts() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let counts = server::TaskCounts::new();
+    let mut config = server::Config::default();
+    config.task_counts = Some(counts.clone());
+    let mut handler = BaseChannel::new(config, rx).respond_with(Server.serve());
+    assert_eq!(handler.name(), "connection");
+    assert_eq!(counts.get()["connection"], 1);
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    let (response_tx, response) = futures::channel::oneshot::channel();
+    tokio::spawn(async move {
+        let _ = response_tx.send(client.add(context::current(), 1, 2).await);
+    });
+    let request_handler = handler.next().await.unwrap()?;
+    assert_eq!(request_handler.name(), "Service.add#0");
+    assert_eq!(counts.get()["Service.add"], 1);
+
+    request_handler.await;
+    assert!(!counts.get().contains_key("Service.add"));
+    tokio::spawn(handler.execute());
+    assert_matches!(response.await, Ok(Ok(3)));
+
+    // Connections are counted per peer.
+    let mut config = server::Config::default();
+    config.task_counts = Some(counts.clone());
+    let identity = context::PeerIdentity::new("alice", context::AuthMethod::UnixCredentials);
+    let (_tx, rx) = channel::unbounded::<
+        tarpc::Response<ServiceResponse>,
+        tarpc::ClientMessage<ServiceRequest>,
+    >();
+    let handler = BaseChannel::new(config, rx)
+        .with_peer_identity(identity)
+        .respond_with(Server.serve());
+    assert_eq!(handler.name(), "connection alice");
+    assert_eq!(counts.get()["connection alice"], 1);
+    drop(handler);
+    assert!(!counts.get().contains_key("connection alice"));
+
+    Ok(())
+}
+
+/// Spawns on tokio, recording the names of the tasks spawned.
+#[derive(Debug, Default)]
+struct NamingSpawner(std::sync::Mutex<Vec<String>>);
+
+impl tarpc::runtime::Spawn for NamingSpawner {
+    fn spawn(&self, future: std::pin::Pin<Box<dyn Future<Output = ()> + Send>>) {
+        tokio::spawn(future);
+    }
+
+    fn spawn_named(
+        &self,
+        name: &dyn std::fmt::Display,
+        future: std::pin::Pin<Box<dyn Future<Output = ()> + Send>>,
+    ) {
+        self.0.lock().unwrap().push(name.to_string());
+        tokio::spawn(future);
+    }
+}
+
+#[tokio::test]
+async fn spawned_tasks_are_named() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let spawner = Arc::new(NamingSpawner::default());
+    let mut config = server::Config::default();
+    config.spawn = spawner.clone();
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        futures::stream::once(ready(BaseChannel::new(config, rx))).respond_with(Server.serve()),
+    );
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    assert_eq!(*spawner.0.lock().unwrap(), ["connection", "Service.add#0"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sequential() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+
+    tokio::spawn(
+        BaseChannel::new(server::Config::default(), rx)
+            .respond_with(Server.serve())
+            .execute(),
+    );
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    assert_matches!(
+        client.hey(context::current(), "Tim".into()).await,
+        Ok(ref s) if s == "Hey, Tim.");
+
+    Ok(())
+}
+
+// Faulty transports duplicate frames by cloning them, and corrupt them by serializing them.
+#[cfg(feature = "serde1")]
+#[tarpc::service(derive(Debug, Clone))]
+trait Adder {
+    async fn add(x: i32, y: i32) -> i32;
+}
+
+#[cfg(feature = "serde1")]
+#[derive(Clone)]
+struct AdderServer;
+
+#[cfg(feature = "serde1")]
+impl Adder for AdderServer {
+    type AddFut = Ready<i32>;
+
+    fn add(self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
+        ready(x + y)
+    }
+}
+
+#[cfg(feature = "serde1")]
+#[test]
+fn retry_over_faulty_transport() -> io::Result<()> {
+    use tarpc::{
+        testing::MockTime,
+        transport::testing::{Faults, FaultyTransport},
+    };
+
+    let _ = env_logger::try_init();
+
+    let mut time = MockTime::new();
+    let (tx, rx) = channel::unbounded();
+    let mut faults = Faults::default();
+    faults.drop = 0.3;
+    let mut config = server::Config::default();
+    config.timer = time.timer();
+    time.spawn(
+        BaseChannel::new(config, FaultyTransport::new(rx, faults, 1))
+            .respond_with(AdderServer.serve())
+            .execute(),
+    );
+    let mut config = client::Config::default();
+    config.timer = time.timer();
+    let mut client = time.enter(|| AdderClient::new(config, tx).spawn())?;
+
+    // Requests the server loses, and responses it loses, time out, and are retried.
+    let mut attempts = 0;
+    let sum = loop {
+        attempts += 1;
+        let mut ctx = context::current();
+        ctx.deadline = time.system_now() + Duration::from_secs(1);
+        match time.block_on(client.add(ctx, 1, 2)) {
+            Ok(sum) => break sum,
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut && attempts < 20 => continue,
+            Err(e) => return Err(e),
+        }
+    };
+    assert_eq!(sum, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn deadline_over_slow_link() -> io::Result<()> {
+    use tarpc::transport::testing::{Latency, Link, SimulatedLink};
+
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let mut link = Link::default();
+    link.latency = Latency::Fixed(Duration::from_millis(50));
+    tokio::spawn(
+        BaseChannel::with_defaults(SimulatedLink::new(rx, link, 0))
+            .respond_with(Server.serve())
+            .execute(),
+    );
+
+    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+
+    let mut ctx = context::current();
+    ctx.deadline = std::time::SystemTime::now() + Duration::from_millis(10);
+    assert_matches!(
+        client.add(ctx, 1, 2).await,
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut
+    );
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+
+    Ok(())
+}
+
+#[test]
+fn chaos_handles() -> io::Result<()> {
+    use tarpc::{testing::MockTime, transport::testing::ChaosTransport};
+
+    let _ = env_logger::try_init();
+
+    let mut time = MockTime::new();
+    let (tx, rx) = channel::unbounded();
+    let rx = ChaosTransport::new(rx);
+    let server = rx.handle();
+    let mut config = server::Config::default();
+    config.timer = time.timer();
+    time.spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(Server.serve())
+            .execute(),
+    );
+    let tx = ChaosTransport::new(tx);
+    let connection = tx.handle();
+    let mut config = client::Config::default();
+    config.timer = time.timer();
+    let mut client = time.enter(|| ServiceClient::new(config, tx).spawn())?;
+
+    let soon = |time: &MockTime| {
+        let mut ctx = context::current();
+        ctx.deadline = time.system_now() + Duration::from_secs(1);
+        ctx
+    };
+    // A server that stops reading, or writing, leaves requests to time out.
+    server.pause();
+    let ctx = soon(&time);
+    time.assert_deadline_exceeded(&ctx, client.add(ctx.clone(), 1, 2));
+    server.resume();
+    server.stall();
+    let ctx = soon(&time);
+    time.assert_deadline_exceeded(&ctx, client.add(ctx.clone(), 1, 2));
+    server.resume();
+    let ctx = soon(&time);
+    assert_eq!(
+        time.assert_within_deadline(&ctx, client.add(ctx.clone(), 1, 2)),
+        3
+    );
+
+    // Once the connection is killed, requests fail fast.
+    connection.kill();
+    let ctx = soon(&time);
+    let start = time.now();
+    assert!(time.block_on(client.add(ctx, 1, 2)).is_err());
+    assert_eq!(time.now(), start);
+
+    Ok(())
+}
+
+#[test]
+fn network_partitions() -> io::Result<()> {
+    use tarpc::{testing::MockTime, transport::fabric::Fabric};
+
+    let _ = env_logger::try_init();
+
+    let mut time = MockTime::new();
+    let fabric = Fabric::new();
+    for node in &["a", "b"] {
+        let mut config = server::Config::default();
+        config.timer = time.timer();
+        time.spawn(
+            server::new(config)
+                .incoming(fabric.listen(node)?)
+                .respond_with(Server.serve()),
+        );
+    }
+    let mut config = client::Config::default();
+    config.timer = time.timer();
+    let mut a =
+        time.enter(|| ServiceClient::new(config.clone(), fabric.connect("c", "a")?).spawn())?;
+    let mut b = time.enter(|| ServiceClient::new(config, fabric.connect("c", "b")?).spawn())?;
+
+    let soon = |time: &MockTime| {
+        let mut ctx = context::current();
+        ctx.deadline = time.system_now() + Duration::from_secs(1);
+        ctx
+    };
+    fabric.partition("c", "a");
+    let ctx = soon(&time);
+    time.assert_deadline_exceeded(&ctx, a.add(ctx.clone(), 1, 2));
+    let ctx = soon(&time);
+    assert_eq!(
+        time.assert_within_deadline(&ctx, b.add(ctx.clone(), 1, 2)),
+        3
+    );
+    assert_matches!(
+        fabric.connect("c", "a"),
+        Err(ref e) if e.kind() == io::ErrorKind::TimedOut
+    );
+
+    // Requests reach a, but its responses don't reach c.
+    fabric.heal("c", "a");
+    fabric.partition_one_way("a", "c");
+    let ctx = soon(&time);
+    time.assert_deadline_exceeded(&ctx, a.add(ctx.clone(), 1, 2));
+
+    fabric.heal_all();
+    let ctx = soon(&time);
+    assert_eq!(
+        time.assert_within_deadline(&ctx, a.add(ctx.clone(), 1, 2)),
+        3
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test]
+async fn serde() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let transport = tarpc_bincode_transport::listen(&([0, 0, 0, 0], 56789).into())?;
+    let addr = transport.local_addr();
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(transport.take(1).filter_map(|r| async { r.ok() }))
+            .respond_with(Server.serve()),
+    );
+
+    let transport = tarpc_bincode_transport::connect(&addr).await?;
+    let mut client = ServiceClient::new(client::Config::default(), transport).spawn()?;
+
+    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    assert_matches!(
+        client.hey(context::current(), "Tim".to_string()).await,
+        Ok(ref s) if s == "Hey, Tim."
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Profile {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nickname: Option<String>,
+}
+
+#[cfg(feature = "serde1")]
+#[tarpc::service(derive(Debug, PartialEq))]
+trait Directory {
+    async fn update(profile: Profile) -> bool;
+}
+
+#[cfg(feature = "serde1")]
+#[test]
+fn codec_roundtrips() {
+    use tarpc::codec::roundtrip;
+    use tarpc_bincode_transport::Bincode;
+
+    let profile = |nickname: Option<&str>| DirectoryRequest::Update {
+        profile: Profile {
+            name: "Tim".into(),
+            nickname: nickname.map(String::from),
+        },
+    };
+    assert_matches!(roundtrip::<Bincode, _>(&profile(Some("T"))), Ok(()));
+    assert_matches!(
+        roundtrip::<Bincode, _>(&DirectoryResponse::Update(true)),
+        Ok(())
+    );
+    // Bincode is positional, so a skipped field shifts the fields after it.
+    assert_matches!(
+        roundtrip::<Bincode, _>(&profile(None)),
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData
+    );
+}
+
+#[cfg(feature = "serde1")]
+#[tarpc::service]
+trait Gated {
+    async fn hold(payload: String) -> String;
+}
+
+/// Responds once the gate opens.
+#[cfg(feature = "serde1")]
+#[derive(Clone)]
+struct GatedServer(future::Shared<futures::channel::oneshot::Receiver<()>>);
+
+#[cfg(feature = "serde1")]
+impl Gated for GatedServer {
+    type HoldFut = std::pin::Pin<Box<dyn Future<Output = String> + Send>>;
+
+    fn hold(self, _: context::Context, payload: String) -> Self::HoldFut {
+        Box::pin(self.0.map(move |_| payload))
+    }
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test]
+async fn generated_services_count_buffered_bytes() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (open, gate) = futures::channel::oneshot::channel();
+    let (limits_tx, mut limits) = mpsc::unbounded();
+    let mut config = server::Config::default();
+    config.max_buffered_bytes = Some(1);
+    config.events = Arc::new(BufferLimitSink(std::sync::Mutex::new(limits_tx)));
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::new(config, rx)
+            .respond_with(GatedServer(gate.shared()).serve())
+            .execute(),
+    );
+
+    let mut client = GatedClient::new(client::Config::default(), tx).spawn()?;
+    let payload = "x".repeat(100);
+    let (call, response) = {
+        let payload = payload.clone();
+        async move { client.hold(context::current(), payload).await }.remote_handle()
+    };
+    tokio::spawn(call);
+    // The request is counted at the length of its encoding.
+    let request = GatedRequest::Hold {
+        payload: payload.clone(),
+    };
+    let request_size = bincode::serialized_size(&request).unwrap() as usize;
+    assert_eq!(limits.next().await, Some(request_size));
+
+    open.send(()).unwrap();
+    assert_eq!(response.await?, payload);
+
+    Ok(())
+}
+
+/// Counts the times it's serialized.
+#[cfg(feature = "serde1")]
+#[derive(Clone, Debug, serde::Deserialize)]
+struct Tally(u32);
+
+#[cfg(feature = "serde1")]
+static TALLY_SERIALIZED: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "serde1")]
+impl serde::Serialize for Tally {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        TALLY_SERIALIZED.fetch_add(1, Ordering::SeqCst);
+        serializer.serialize_u32(self.0)
+    }
+}
+
+#[cfg(feature = "serde1")]
+#[tarpc::service(derive(Debug, Clone), prepared = true)]
+trait Broadcast {
+    async fn tally(tally: Tally) -> u32;
+    async fn ping() -> bool;
+}
+
+#[cfg(feature = "serde1")]
+#[derive(Clone)]
+struct BroadcastServer;
+
+#[cfg(feature = "serde1")]
+impl Broadcast for BroadcastServer {
+    type TallyFut = Ready<u32>;
+
+    fn tally(self, _: context::Context, tally: Tally) -> Self::TallyFut {
+        ready(tally.0)
+    }
+
+    type PingFut = Ready<bool>;
+
+    fn ping(self, _: context::Context) -> Self::PingFut {
+        ready(true)
+    }
+}
+
+#[cfg(feature = "serde1")]
+#[tokio::test]
+async fn prepared_requests() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let transport = tarpc_bincode_transport::listen(&"127.0.0.1:0".parse().unwrap())?;
+    let addr = transport.local_addr();
+    tokio::spawn(
+        tarpc::Server::default()
+            .incoming(transport.filter_map(|r| async { r.ok() }))
+            .respond_with(BroadcastServer.serve()),
+    );
+
+    let mut pool = vec![];
+    for _ in 0..2 {
+        let transport = tarpc_bincode_transport::connect(&addr).await?;
+        pool.push(BroadcastClient::new(client::Config::default(), transport).spawn()?);
+    }
+    // The args are serialized once, when the request is prepared, and every send, by any client,
+    // reuses the bytes.
+    let prepared = BroadcastClient::prepare_tally(Tally(7));
+    assert_eq!(TALLY_SERIALIZED.load(Ordering::SeqCst), 1);
+    for client in &mut pool {
+        for _ in 0..2 {
+            assert_matches!(
+                client.tally_prepared(context::current(), &prepared).await,
+                Ok(7)
+            );
+        }
+    }
+    assert_eq!(TALLY_SERIALIZED.load(Ordering::SeqCst), 1);
+
+    // Transports that don't reuse the bytes send prepared requests like any other.
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(BroadcastServer.serve())
+            .execute(),
+    );
+    let mut client = BroadcastClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(
+        client.tally_prepared(context::current(), &prepared).await,
+        Ok(7)
+    );
+
+    // A request prepared for one rpc can't be sent as another.
+    assert_matches!(
+        client.ping_prepared(context::current(), &prepared).await,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput
+    );
+    let ping = BroadcastClient::prepare_ping();
+    assert_matches!(
+        client.ping_prepared(context::current(), &ping).await,
+        Ok(true)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn concurrent() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        rpc::Server::default()
+            .incoming(stream::once(ready(rx)))
+            .respond_with(Server.serve()),
+    );
+
+    let client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+
+    let mut c = client.clone();
+    let req1 = c.add(context::current(), 1, 2);
+
+    let mut c = client.clone();
+    let req2 = c.add(context::current(), 3, 4);
+
+    let mut c = client.clone();
+    let req3 = c.hey(context::current(), "Tim".to_string());
+
+    assert_matches!(req1.await, Ok(3));
+    assert_matches!(req2.await, Ok(7));
+    assert_matches!(req3.await, Ok(ref s) if s == "Hey, Tim.");
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Log {
+    #[oneway]
+    async fn log(message: String);
+}
+
+#[derive(Clone)]
+struct LogServer(mpsc::UnboundedSender<String>);
+
+impl Log for LogServer {
+    type LogFut = Ready<()>;
+
+    fn log(self, _: context::Context, message: String) -> Self::LogFut {
+        let _ = self.0.unbounded_send(message);
+        ready(())
+    }
+}
+
+#[tokio::test]
+async fn one_way() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let (logs_tx, mut logs) = mpsc::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(LogServer(logs_tx).serve())
+            .execute(),
+    );
+
+    let mut client = LogClient::new(client::Config::default(), tx).spawn()?;
+
+    client.log(context::current(), "first".into()).await?;
+    client.log(context::current(), "second".into()).await?;
+    assert_matches!(logs.next().await, Some(ref s) if s == "first");
+    assert_matches!(logs.next().await, Some(ref s) if s == "second");
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Tail {
+    #[stream]
+    async fn tail(lines: u32) -> String;
+}
+
+#[derive(Clone)]
+struct TailServer;
+
+impl Tail for TailServer {
+    type TailStream = stream::Iter<std::vec::IntoIter<String>>;
+
+    fn tail(self, _: context::Context, lines: u32) -> Self::TailStream {
+        let lines: Vec<_> = (0..lines).map(|i| format!("line {}", i)).collect();
+        stream::iter(lines)
+    }
+}
+
+#[tokio::test]
+async fn streaming() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(TailServer.serve())
+            .execute(),
+    );
+
+    let mut client = TailClient::new(client::Config::default(), tx).spawn()?;
+
+    let lines: Vec<String> = client
+        .tail(context::current(), 3)
+        .await?
+        .try_collect()
+        .await?;
+    assert_eq!(lines, vec!["line 0", "line 1", "line 2"]);
+
+    let lines: Vec<String> = client
+        .tail(context::current(), 0)
+        .await?
+        .try_collect()
+        .await?;
+    assert!(lines.is_empty());
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Versioned {
+    #[id = 2]
+    async fn hello_v2(name: String) -> String;
+    #[id = 0]
+    async fn hello(name: String) -> String;
+}
+
+#[derive(Clone)]
+struct VersionedServer;
+
+impl Versioned for VersionedServer {
+    type HelloV2Fut = Ready<String>;
+
+    fn hello_v2(self, _: context::Context, name: String) -> Self::HelloV2Fut {
+        ready(format!("Hello, {}!", name))
+    }
+
+    type HelloFut = Ready<String>;
+
+    fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+        ready(format!("Hello, {}.", name))
+    }
+}
+
+#[tokio::test]
+async fn explicit_ids() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(VersionedServer.serve())
+            .execute(),
+    );
+
+    let mut client = VersionedClient::new(client::Config::default(), tx).spawn()?;
+
+    assert_matches!(
+        client.hello(context::current(), "Tim".into()).await,
+        Ok(ref s) if s == "Hello, Tim.");
+    assert_matches!(
+        client.hello_v2(context::current(), "Tim".into()).await,
+        Ok(ref s) if s == "Hello, Tim!");
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Greeting {
+    #[removed(3)]
+    async fn hello(name: String) -> String;
+    #[since(2)]
+    async fn greet(greeting: String, name: String) -> String;
+}
+
+#[derive(Clone)]
+struct GreetingServer;
+
+impl Greeting for GreetingServer {
+    type HelloFut = Ready<String>;
+
+    fn hello(self, _: context::Context, name: String) -> Self::HelloFut {
+        ready(format!("Hello, {}!", name))
+    }
+
+    type GreetFut = Ready<String>;
+
+    fn greet(self, _: context::Context, greeting: String, name: String) -> Self::GreetFut {
+        ready(format!("{}, {}!", greeting, name))
+    }
+}
+
+#[tokio::test]
+async fn version_negotiation() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(GreetingServer.serve().min_version(2))
+            .execute(),
+    );
+
+    let mut client = GreetingClient::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(GreetingClient::VERSION, 3);
+    assert_eq!(client.version(), None);
+    assert_eq!(client.negotiate(context::current()).await?, 3);
+    assert_eq!(client.version(), Some(3));
+
+    assert_matches!(
+        client.greet(context::current(), "Hi".into(), "Tim".into()).await,
+        Ok(ref s) if s == "Hi, Tim!");
+    assert_matches!(
+        client.hello(context::current(), "Tim".into()).await,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn version_negotiation_fails() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(GreetingServer.serve().min_version(4))
+            .execute(),
+    );
+
+    let mut client = GreetingClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(
+        client.negotiate(context::current()).await,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData);
+    assert_eq!(client.version(), None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn removed_rpcs_are_rejected() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(GreetingServer.serve().min_version(3))
+            .execute(),
+    );
+
+    // A client that doesn't negotiate still sends `hello`, which no version the server supports
+    // has.
+    let mut client = client::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(
+        client
+            .call(
+                context::current(),
+                GreetingRequest::Hello { name: "Tim".into() }
+            )
+            .await,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidInput);
+    assert_matches!(
+        client
+            .call(
+                context::current(),
+                GreetingRequest::Greet {
+                    greeting: "Hi".into(),
+                    name: "Tim".into()
+                }
+            )
+            .await,
+        Ok(GreetingResponse::Greet(ref s)) if s == "Hi, Tim!");
+
+    Ok(())
+}
+
+#[tarpc::service]
+mod api {
+    #[derive(Debug, PartialEq)]
+    #[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+    pub enum ApiError {
+        Unauthorized,
+    }
+
+    pub trait Admin {
+        #[throws(ApiError)]
+        async fn reset(token: String);
+    }
+
+    pub trait Health {
+        async fn healthy() -> bool;
+    }
+}
+
+#[derive(Clone)]
+struct ApiServer;
+
+impl api::Admin for ApiServer {
+    type ResetFut = Ready<Result<(), api::ApiError>>;
+
+    fn reset(self, _: context::Context, token: String) -> Self::ResetFut {
+        ready(if token == "admin" {
+            Ok(())
+        } else {
+            Err(api::ApiError::Unauthorized)
+        })
+    }
+}
+
+impl api::Health for ApiServer {
+    type HealthyFut = Ready<bool>;
+
+    fn healthy(self, _: context::Context) -> Self::HealthyFut {
+        ready(true)
+    }
+}
+
+#[tokio::test]
+async fn module_of_services() -> io::Result<()> {
+    use api::{Admin, Health};
+
+    let _ = env_logger::try_init();
+
+    let (admin_tx, admin_rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(admin_rx)
+            .respond_with(Admin::serve(ApiServer))
+            .execute(),
+    );
+    let (health_tx, health_rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(health_rx)
+            .respond_with(Health::serve(ApiServer))
+            .execute(),
+    );
+
+    let mut admin = api::AdminClient::new(client::Config::default(), admin_tx).spawn()?;
+    let mut health = api::HealthClient::new(client::Config::default(), health_tx).spawn()?;
+    assert_matches!(
+        admin.reset(context::current(), "admin".into()).await,
+        Ok(())
+    );
+    assert_matches!(
+        admin.reset(context::current(), "guest".into()).await,
+        Err(client::CallError::Service(api::ApiError::Unauthorized))
+    );
+    assert_matches!(health.healthy(context::current()).await, Ok(true));
+
+    Ok(())
+}
+
+#[tarpc::service(from_fn = true)]
+trait Doubler {
+    async fn double(x: i32) -> i32;
+}
+
+#[tokio::test]
+async fn from_fn() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let doubler = DoublerFn::from_fn(|_, x| ready(x * 2));
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(doubler.serve())
+            .execute(),
+    );
+
+    let mut client = DoublerClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(client.double(context::current(), 21).await, Ok(42));
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Tenant {
+    async fn tenant() -> Option<String>;
 }
 
 #[derive(Clone)]
-struct Server;
+struct TenantServer;
 
-impl Service for Server {
-    type AddFut = Ready<i32>;
+impl Tenant for TenantServer {
+    type TenantFut = Ready<Option<String>>;
 
-    fn add(self, _: context::Context, x: i32, y: i32) -> Self::AddFut {
-        ready(x + y)
+    fn tenant(self, ctx: context::Context) -> Self::TenantFut {
+        ready(ctx.metadata("tenant").map(String::from))
     }
+}
 
-    type HeyFut = Ready<String>;
+#[tokio::test]
+async fn context_metadata() -> io::Result<()> {
+    let _ = env_logger::try_init();
 
-    fn hey(self, _: context::Context, name: String) -> Self::HeyFut {
-        ready(format!("Hey, {}.", name))
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(TenantServer.serve())
+            .execute(),
+    );
+
+    let mut client = TenantClient::new(client::Config::default(), tx).spawn()?;
+    let ctx = context::current().with_metadata("tenant", "acme");
+    assert_matches!(client.tenant(ctx).await, Ok(Some(ref t)) if t == "acme");
+    assert_matches!(client.tenant(context::current()).await, Ok(None));
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Experiment {
+    async fn experiment() -> Option<String>;
+}
+
+#[derive(Clone)]
+struct ExperimentServer;
+
+impl Experiment for ExperimentServer {
+    type ExperimentFut = Ready<Option<String>>;
+
+    fn experiment(self, _: context::Context) -> Self::ExperimentFut {
+        ready(context::current().baggage("experiment").map(String::from))
+    }
+}
+
+/// Forwards requests to an ExperimentServer using the current context.
+#[derive(Clone)]
+struct ExperimentRelay(ExperimentClient);
+
+#[tarpc::server]
+impl Experiment for ExperimentRelay {
+    async fn experiment(mut self, _: context::Context) -> Option<String> {
+        self.0.experiment(context::current()).await.unwrap_or(None)
     }
 }
 
 #[tokio::test]
-async fn sequential() -> io::Result<()> {
+async fn context_baggage() -> io::Result<()> {
     let _ = env_logger::try_init();
 
     let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(ExperimentServer.serve())
+            .execute(),
+    );
+    let backend = ExperimentClient::new(client::Config::default(), tx).spawn()?;
 
+    let (tx, rx) = channel::unbounded();
     tokio::spawn(
-        BaseChannel::new(server::Config::default(), rx)
-            .respond_with(Server.serve())
+        BaseChannel::with_defaults(rx)
+            .respond_with(ExperimentRelay(backend).serve())
             .execute(),
     );
+    let mut client = ExperimentClient::new(client::Config::default(), tx).spawn()?;
 
-    let mut client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    let ctx = context::current().with_baggage("experiment", "blue");
+    assert_matches!(client.experiment(ctx).await, Ok(Some(ref e)) if e == "blue");
+    assert_matches!(client.experiment(context::current()).await, Ok(None));
+    assert_eq!(context::current().baggage("experiment"), None);
 
-    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    Ok(())
+}
+
+#[tarpc::service]
+trait Ids {
+    async fn ids() -> (Option<u64>, String);
+}
+
+#[derive(Clone)]
+struct IdsServer;
+
+#[tarpc::server]
+impl Ids for IdsServer {
+    async fn ids(self, ctx: context::Context) -> (Option<u64>, String) {
+        (ctx.request_id(), ctx.trace_id().to_string())
+    }
+}
+
+#[tokio::test]
+async fn context_request_id() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(IdsServer.serve())
+            .execute(),
+    );
+    let mut client = IdsClient::new(client::Config::default(), tx).spawn()?;
+
+    let ctx = context::current();
+    assert_eq!(ctx.request_id(), None);
+    let (request_id, trace_id) = client.ids(ctx.clone()).await?;
+    assert_eq!(request_id, Some(0));
+    assert_eq!(trace_id, ctx.trace_id().to_string());
+    assert_matches!(client.ids(context::current()).await, Ok((Some(1), _)));
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Whoami {
+    async fn whoami() -> Option<String>;
+}
+
+#[derive(Clone)]
+struct WhoamiServer;
+
+#[tarpc::server]
+impl Whoami for WhoamiServer {
+    async fn whoami(self, ctx: context::Context) -> Option<String> {
+        let identity = ctx.peer_identity()?;
+        Some(format!(
+            "{}:{}",
+            identity.principal,
+            identity.attribute("uid")?
+        ))
+    }
+}
+
+#[tokio::test]
+async fn context_peer_identity() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    let identity = context::PeerIdentity::new("alice", context::AuthMethod::UnixCredentials)
+        .with_attribute("uid", "1000");
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .with_peer_identity(identity)
+            .respond_with(WhoamiServer.serve())
+            .execute(),
+    );
+    let mut client = WhoamiClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(client.whoami(context::current()).await, Ok(Some(ref id)) if id == "alice:1000");
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(WhoamiServer.serve())
+            .execute(),
+    );
+    let mut client = WhoamiClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(client.whoami(context::current()).await, Ok(None));
+
+    Ok(())
+}
+
+#[tarpc::service]
+trait Parser {
+    async fn parse(s: String) -> Result<i32, String>;
+    async fn sum(numbers: Vec<String>) -> Result<i32, String>;
+}
+
+#[derive(Clone)]
+struct ParserServer;
+
+#[tarpc::server]
+impl Parser for ParserServer {
+    async fn parse(self, _: context::Context, s: String) -> Result<i32, String> {
+        s.parse().map_err(|e| format!("{}: {}", s, e))
+    }
+
+    async fn sum(self, ctx: context::Context, numbers: Vec<String>) -> Result<i32, String> {
+        let mut sum = 0;
+        for s in numbers {
+            if s.is_empty() {
+                return Ok(sum);
+            }
+            sum += self.clone().parse(ctx.clone(), s).await?;
+        }
+        Ok(sum)
+    }
+}
+
+#[tokio::test]
+async fn async_fn_impl() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let (tx, rx) = channel::unbounded();
+    tokio::spawn(
+        BaseChannel::with_defaults(rx)
+            .respond_with(ParserServer.serve())
+            .execute(),
+    );
+
+    let mut client = ParserClient::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(client.parse(context::current(), "12".into()).await?, Ok(12));
+    assert_eq!(
+        client
+            .sum(
+                context::current(),
+                vec!["1".into(), "2".into(), "".into(), "x".into()]
+            )
+            .await?,
+        Ok(3)
+    );
     assert_matches!(
-        client.hey(context::current(), "Tim".into()).await,
-        Ok(ref s) if s == "Hey, Tim.");
+        client.sum(context::current(), vec!["1".into(), "x".into()]).await?,
+        Err(ref e) if e.starts_with("x: "));
 
     Ok(())
 }
 
-#[cfg(feature = "serde1")]
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde1", derive(serde::Serialize, serde::Deserialize))]
+enum GetError {
+    NotFound,
+}
+
+#[tarpc::service]
+trait Store {
+    #[throws(GetError)]
+    async fn get(key: u32) -> String;
+}
+
+#[derive(Clone)]
+struct StoreServer;
+
+impl Store for StoreServer {
+    type GetFut = Ready<Result<String, GetError>>;
+
+    fn get(self, _: context::Context, key: u32) -> Self::GetFut {
+        ready(match key {
+            0 => Ok("zero".into()),
+            _ => Err(GetError::NotFound),
+        })
+    }
+}
+
 #[tokio::test]
-async fn serde() -> io::Result<()> {
+async fn throws() -> io::Result<()> {
     let _ = env_logger::try_init();
 
-    let transport = tarpc_bincode_transport::listen(&([0, 0, 0, 0], 56789).into())?;
-    let addr = transport.local_addr();
+    let (tx, rx) = channel::unbounded();
     tokio::spawn(
-        tarpc::Server::default()
-            .incoming(transport.take(1).filter_map(|r| async { r.ok() }))
-            .respond_with(Server.serve()),
+        BaseChannel::with_defaults(rx)
+            .respond_with(StoreServer.serve())
+            .execute(),
     );
 
-    let transport = tarpc_bincode_transport::connect(&addr).await?;
-    let mut client = ServiceClient::new(client::Config::default(), transport).spawn()?;
+    let mut client = StoreClient::new(client::Config::default(), tx).spawn()?;
+    assert_matches!(client.get(context::current(), 0).await, Ok(ref s) if s == "zero");
+    assert_matches!(
+        client.get(context::current(), 1).await,
+        Err(client::CallError::Service(GetError::NotFound))
+    );
+    let not_found = client.get(context::current(), 1).await.unwrap_err();
+    assert_eq!(not_found.classify(), ErrorKind::Application);
 
-    assert_matches!(client.add(context::current(), 1, 2).await, Ok(3));
+    // Without a server, the RPC itself fails.
+    let (tx, rx) = channel::unbounded();
+    drop(rx);
+    let mut client = StoreClient::new(client::Config::default(), tx).spawn()?;
     assert_matches!(
-        client.hey(context::current(), "Tim".to_string()).await,
-        Ok(ref s) if s == "Hey, Tim."
+        client.get(context::current(), 0).await,
+        Err(client::CallError::Rpc(_))
     );
+    let unsent = client.get(context::current(), 0).await.unwrap_err();
+    assert_eq!(unsent.classify(), ErrorKind::Transport);
 
     Ok(())
 }
 
+#[tarpc::service(arc_self = true)]
+trait Counter {
+    async fn increment() -> u64;
+}
+
+/// Not Clone: the counter is shared by all requests through an Arc.
+struct CounterServer {
+    count: AtomicU64,
+}
+
+impl Counter for CounterServer {
+    type IncrementFut = Ready<u64>;
+
+    fn increment(self: Arc<Self>, _: context::Context) -> Self::IncrementFut {
+        ready(self.count.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+}
+
 #[tokio::test]
-async fn concurrent() -> io::Result<()> {
+async fn arc_self() -> io::Result<()> {
     let _ = env_logger::try_init();
 
+    let server = Arc::new(CounterServer {
+        count: AtomicU64::new(0),
+    });
     let (tx, rx) = channel::unbounded();
     tokio::spawn(
-        rpc::Server::default()
-            .incoming(stream::once(ready(rx)))
-            .respond_with(Server.serve()),
+        BaseChannel::with_defaults(rx)
+            .respond_with(server.clone().serve())
+            .execute(),
     );
 
-    let client = ServiceClient::new(client::Config::default(), tx).spawn()?;
+    let mut client = CounterClient::new(client::Config::default(), tx).spawn()?;
+    assert_eq!(client.increment(context::current()).await?, 1);
+    assert_eq!(client.increment(context::current()).await?, 2);
+    assert_eq!(server.count.load(Ordering::SeqCst), 2);
 
-    let mut c = client.clone();
-    let req1 = c.add(context::current(), 1, 2);
+    Ok(())
+}
 
-    let mut c = client.clone();
-    let req2 = c.add(context::current(), 3, 4);
+#[tarpc::service(mock = true)]
+trait Inventory {
+    async fn count(item: String) -> u32;
+    #[oneway]
+    async fn restock(item: String, amount: u32);
+    #[stream]
+    async fn items() -> String;
+}
 
-    let mut c = client.clone();
-    let req3 = c.hey(context::current(), "Tim".to_string());
+/// Code under test that holds a generated client.
+async fn count_all<C>(client: &mut InventoryClient<C>) -> io::Result<u32>
+where
+    for<'a> C: tarpc::Client<'a, InventoryRequest, Response = InventoryResponse>,
+{
+    let items: Vec<String> = client
+        .items(context::current())
+        .await?
+        .try_collect()
+        .await?;
+    let mut total = 0;
+    for item in items {
+        total += client.count(context::current(), item).await?;
+    }
+    Ok(total)
+}
 
-    assert_matches!(req1.await, Ok(3));
-    assert_matches!(req2.await, Ok(7));
-    assert_matches!(req3.await, Ok(ref s) if s == "Hey, Tim.");
+#[tokio::test]
+async fn mock_client() -> io::Result<()> {
+    let mut mock = MockInventoryClient::new();
+    mock.expect_items()
+        .times(1)
+        .returning(|_, ()| vec!["apple".into(), "pear".into()]);
+    mock.expect_count()
+        .with(|(item,)| item == "apple")
+        .returning(|_, _| 3);
+    mock.expect_count().returning(|_, _| 2);
+    mock.expect_restock()
+        .with(|(item, amount)| item == "pear" && *amount == 10)
+        .times(1)
+        .returning(|_, _| ());
+
+    let mut client = InventoryClient::from(mock);
+    assert_eq!(count_all(&mut client).await?, 5);
+    client
+        .restock(context::current(), "pear".into(), 10)
+        .await?;
+
+    Ok(())
+}
+
+/// A service for a mock server to answer, which needs to clone its responses.
+#[tarpc::service(derive(Debug, Clone))]
+trait Pantry {
+    async fn count(item: String) -> u32;
+    #[stream]
+    async fn items() -> String;
+}
+
+/// Code under test that holds a generated client.
+async fn count_pantry<C>(client: &mut PantryClient<C>) -> io::Result<u32>
+where
+    for<'a> C: tarpc::Client<'a, PantryRequest, Response = PantryResponse>,
+{
+    let items: Vec<String> = client
+        .items(context::current())
+        .await?
+        .try_collect()
+        .await?;
+    let mut total = 0;
+    for item in items {
+        total += client.count(context::current(), item).await?;
+    }
+    Ok(total)
+}
+
+#[tokio::test]
+async fn mock_server() -> io::Result<()> {
+    let _ = env_logger::try_init();
+
+    let mock = server::mock::MockServer::<PantryRequest, PantryResponse>::new();
+    mock.on("items").respond_stream(vec![
+        PantryResponse::Items(Some("apple".into())),
+        PantryResponse::Items(Some("pear".into())),
+        PantryResponse::Items(None),
+    ]);
+    mock.on("count")
+        .with(|request| match request {
+            PantryRequest::Count { item } => item == "apple",
+            _ => false,
+        })
+        .delay(Duration::from_millis(10))
+        .respond(PantryResponse::Count(3));
+    mock.on("count")
+        .times(1)
+        .fail(io::ErrorKind::ConnectionRefused, "overloaded");
+    mock.on("count").respond(PantryResponse::Count(2));
+
+    // The code under test talks to the mock over a real client.
+    let mut client = PantryClient::new(client::Config::default(), mock.spawn()).spawn()?;
+    assert_matches!(
+        count_pantry(&mut client).await,
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused
+    );
+    assert_eq!(count_pantry(&mut client).await?, 5);
+    assert_eq!(mock.calls("items"), 2);
+    assert_eq!(mock.calls("count"), 4);
+
+    // Requests that no rule answers fail, as do requests answered with another RPC's response.
+    let mock = server::mock::MockServer::<PantryRequest, PantryResponse>::new();
+    mock.on("items").respond(PantryResponse::Count(1));
+    mock.on("count").disconnect();
+    let mut client = PantryClient::new(client::Config::default(), mock.spawn()).spawn()?;
+    assert_matches!(
+        client.items(context::current()).await?.try_collect::<Vec<_>>().await,
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData
+    );
+    assert!(client
+        .count(context::current(), "apple".into())
+        .await
+        .is_err());
+    let mock = server::mock::MockServer::<PantryRequest, PantryResponse>::new();
+    let mut client = PantryClient::new(client::Config::default(), mock.spawn()).spawn()?;
+    assert_matches!(
+        client.count(context::current(), "apple".into()).await,
+        Err(ref e) if e.kind() == io::ErrorKind::Other
+    );
+
+    Ok(())
+}
+
+#[test]
+fn mock_server_cancellations() -> io::Result<()> {
+    use tarpc::testing::MockTime;
+
+    let _ = env_logger::try_init();
+
+    let mut time = MockTime::new();
+    let mut server_config = server::Config::default();
+    server_config.timer = time.timer();
+    let mock =
+        server::mock::MockServer::<PantryRequest, PantryResponse>::new().config(server_config);
+    mock.on("count")
+        .delay(Duration::from_secs(2))
+        .respond(PantryResponse::Count(3));
+    mock.on("items").ignore();
+    let mut config = client::Config::default();
+    config.timer = time.timer();
+    let mut client = time.enter(|| PantryClient::new(config, mock.spawn()).spawn())?;
+
+    // Requests whose deadlines pass are canceled, whether or not a rule would answer them.
+    let mut ctx = context::current();
+    ctx.deadline = time.system_now() + Duration::from_secs(1);
+    time.assert_deadline_exceeded(&ctx, client.count(ctx.clone(), "apple".into()));
+    ctx.deadline = time.system_now() + Duration::from_secs(1);
+    time.assert_deadline_exceeded(&ctx, client.items(ctx.clone()));
+    time.advance(Duration::from_secs(2));
+    assert_eq!(mock.cancellations("count"), 1);
+    assert_eq!(mock.cancellations("items"), 1);
+
+    Ok(())
+}
+
+#[test]
+fn scenario() -> io::Result<()> {
+    use tarpc::testing::{scenario::Scenario, MockTime};
+
+    let _ = env_logger::try_init();
+
+    let mut time = MockTime::new();
+    let mut config = client::Config::default();
+    config.timer = time.timer();
+    let mut ctx = context::current();
+    ctx.deadline = time.system_now() + Duration::from_secs(5);
+
+    // The first add is answered only once both are in flight, the second after a second, and the
+    // hey that's never answered is canceled when its deadline passes.
+    let scenario = Scenario::new()
+        .client(config.clone())
+        .expect_call("add")
+        .once_in_flight(2)
+        .respond(ServiceResponse::Add(3))
+        .expect_call("add")
+        .delay(Duration::from_secs(1))
+        .respond(ServiceResponse::Add(7))
+        .expect_call("hey")
+        .with(|request| match request {
+            ServiceRequest::Hey { name } => name == "Tim",
+            _ => false,
+        })
+        .expect_cancel()
+        .max_in_flight(2)
+        .run(|channel| async move {
+            let mut client = ServiceClient::from(channel);
+            let mut client2 = client.clone();
+            let sums = future::try_join(
+                client.add(ctx.clone(), 1, 2),
+                client2.add(ctx.clone(), 3, 4),
+            )
+            .await?;
+            assert_matches!(
+                client.hey(ctx, "Tim".into()).await,
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut
+            );
+            Ok::<_, io::Error>(sums)
+        });
+    let sums = time.block_on(scenario)??;
+    assert_eq!(sums, (3, 7));
+    assert_eq!(time.elapsed(), Duration::from_secs(5));
+
+    // Calls out of order are answered with errors, and reported.
+    let scenario = Scenario::new()
+        .client(config)
+        .expect_call("hey")
+        .respond(ServiceResponse::Hey("Hey, Tim.".into()))
+        .expect_call("add")
+        .respond(ServiceResponse::Add(3))
+        .run(|channel| async move {
+            let mut client = ServiceClient::from(channel);
+            assert!(client.add(context::current(), 1, 2).await.is_err());
+        });
+    let error = time.block_on(scenario).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "expected a call to `hey`, got a call to `add`; expected a call to `hey`, which never \
+         came; expected a call to `add`, which never came"
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "serde1")]
+#[test]
+fn explicit_ids_are_variant_indices() -> bincode::Result<()> {
+    #[tarpc::service]
+    trait Renamed {
+        #[id = 1]
+        #[name = "hello_v2"]
+        async fn hello(name: String) -> String;
+    }
+
+    let request = bincode::serialize(&RenamedRequest::Hello { name: "".into() })?;
+    assert_eq!(request[..4], 1u32.to_le_bytes());
+    let request = bincode::serialize(&VersionedRequest::HelloV2 { name: "".into() })?;
+    assert_eq!(request[..4], 2u32.to_le_bytes());
+    let request = bincode::serialize(&VersionedRequest::Hello { name: "".into() })?;
+    assert_eq!(request[..4], 0u32.to_le_bytes());
+    let response = bincode::serialize(&VersionedResponse::HelloV2("".into()))?;
+    assert_eq!(response[..4], 2u32.to_le_bytes());
+    Ok(())
+}
 
+#[cfg(feature = "serde1")]
+#[test]
+fn versioning_keeps_old_clients_compatible() -> bincode::Result<()> {
+    // `Greeting` before it was versioned.
+    #[tarpc::service]
+    trait GreetingV1 {
+        async fn hello(name: String) -> String;
+    }
+
+    let request = bincode::serialize(&GreetingV1Request::Hello { name: "Tim".into() })?;
+    assert_matches!(
+        bincode::deserialize(&request)?,
+        GreetingRequest::Hello { ref name } if name == "Tim");
+    let response = bincode::serialize(&GreetingResponse::Hello("Hi".into()))?;
+    assert_matches!(
+        bincode::deserialize(&response)?,
+        GreetingV1Response::Hello(ref s) if s == "Hi");
     Ok(())
 }
 
@@ -170,3 +2301,69 @@ fn in_memory_single_threaded() -> io::Result<()> {
 
     Ok(())
 }
+
+#[tarpc::service]
+trait Counter {
+    async fn increment() -> u64;
+}
+
+/// Counts in an `Rc`, so neither it nor its futures are `Send`.
+#[derive(Clone, Default)]
+struct LocalCounter(Rc<std::cell::Cell<u64>>);
+
+#[tarpc::server(local)]
+impl Counter for LocalCounter {
+    async fn increment(self, _: context::Context) -> u64 {
+        self.0.set(self.0.get() + 1);
+        self.0.get()
+    }
+}
+
+/// A timer whose delays never complete, for running without a runtime's timer.
+#[derive(Debug)]
+struct Never;
+
+impl tarpc::runtime::Timer for Never {
+    fn now(&self) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+
+    fn delay(&self, _: std::time::Instant) -> tarpc::runtime::Delay {
+        tarpc::runtime::Delay::new(future::pending())
+    }
+}
+
+#[test]
+fn local_service() -> io::Result<()> {
+    use futures::{executor::LocalPool, task::LocalSpawnExt};
+
+    let _ = env_logger::try_init();
+    let mut pool = LocalPool::new();
+    let mut spawner = pool.spawner();
+
+    let (tx, rx) = channel::unbounded();
+    let counter = LocalCounter::default();
+    let mut config = server::Config::default();
+    config.timer = Arc::new(Never);
+    spawner
+        .spawn_local(
+            BaseChannel::new(config, rx)
+                .respond_with(counter.clone().serve())
+                .execute_local(pool.spawner()),
+        )
+        .unwrap();
+
+    let mut config = client::Config::default();
+    config.timer = Arc::new(Never);
+    let NewClient {
+        mut client,
+        dispatch,
+    } = CounterClient::new(config, tx);
+    spawner.spawn_local(dispatch.map(|_| ())).unwrap();
+
+    assert_matches!(pool.run_until(client.increment(context::current())), Ok(1));
+    assert_matches!(pool.run_until(client.increment(context::current())), Ok(2));
+    assert_eq!(counter.0.get(), 2);
+
+    Ok(())
+}
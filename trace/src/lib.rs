@@ -13,9 +13,18 @@
 //! distributed systems, a context can be sent from client to server to connect events occurring on
 //! either side.
 //!
+//! Whether a trace is recorded is decided by a [sampler](Sample) when a span is started, and the
+//! decision is carried in the context so that every service in the trace agrees.
+//!
 //! This crate's design is based on [opencensus
 //! tracing](https://opencensus.io/core-concepts/tracing/).
 
+mod sample;
+
+pub use sample::{
+    AlwaysSample, NeverSample, ParentBasedSample, ProbabilitySample, RateLimitedSample, Sample,
+};
+
 use rand::Rng;
 use std::{
     fmt::{self, Formatter},
@@ -26,7 +35,10 @@ use std::{
 ///
 /// Consists of a span identifying an event, an optional parent span identifying a causal event
 /// that triggered the current span, and a trace with which all related spans are associated.
-#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+///
+/// The default context is a root with zero IDs whose spans are recorded, like those of
+/// [`new_root`](Context::new_root).
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Context {
     /// An identifier of the trace associated with the current context. A trace ID is typically
@@ -42,6 +54,8 @@ pub struct Context {
     ///
     /// If `parent_id` is `None`, then this is a root context.
     pub parent_id: Option<SpanId>,
+    /// Whether spans in this trace should be recorded.
+    pub sampled: bool,
 }
 
 /// A 128-bit UUID identifying a trace. All spans caused by the same originating span share the
@@ -55,6 +69,17 @@ pub struct TraceId(u128);
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SpanId(u64);
 
+impl Default for Context {
+    fn default() -> Self {
+        Context {
+            trace_id: TraceId::default(),
+            span_id: SpanId::default(),
+            parent_id: None,
+            sampled: true,
+        }
+    }
+}
+
 impl Context {
     /// Constructs a new root context. A root context is one with no parent span.
     pub fn new_root() -> Self {
@@ -63,6 +88,24 @@ impl Context {
             trace_id: TraceId::random(rng),
             span_id: SpanId::random(rng),
             parent_id: None,
+            sampled: true,
+        }
+    }
+
+    /// Returns a context for a new span caused by the current span, whose sampling decision is
+    /// made by `sampler`. If the current span is a root, the new span starts the trace in earnest,
+    /// so `sampler` decides without a parent; otherwise, it is given the current span as parent.
+    pub fn new_child(&self, sampler: &dyn Sample) -> Self {
+        let parent = if self.parent_id.is_some() {
+            Some(self)
+        } else {
+            None
+        };
+        Context {
+            trace_id: self.trace_id,
+            span_id: SpanId::random(&mut rand::thread_rng()),
+            parent_id: Some(self.span_id),
+            sampled: sampler.sample(parent, self.trace_id),
         }
     }
 }
@@ -82,6 +125,30 @@ impl SpanId {
     }
 }
 
+impl From<u128> for TraceId {
+    fn from(id: u128) -> Self {
+        TraceId(id)
+    }
+}
+
+impl From<TraceId> for u128 {
+    fn from(id: TraceId) -> Self {
+        id.0
+    }
+}
+
+impl From<u64> for SpanId {
+    fn from(id: u64) -> Self {
+        SpanId(id)
+    }
+}
+
+impl From<SpanId> for u64 {
+    fn from(id: SpanId) -> Self {
+        id.0
+    }
+}
+
 impl fmt::Display for TraceId {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         write!(f, "{:02x}", self.0)?;
@@ -95,3 +162,15 @@ impl fmt::Display for SpanId {
         Ok(())
     }
 }
+
+impl fmt::LowerHex for TraceId {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::LowerHex for SpanId {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2018 Google LLC
+//
+// Use of this source code is governed by an MIT-style
+// license that can be found in the LICENSE file or at
+// https://opensource.org/licenses/MIT.
+
+//! Samplers decide which traces are recorded.
+
+use crate::{Context, TraceId};
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Decides whether a trace should be recorded.
+///
+/// The decision is made once per request that starts a new span and is carried in
+/// [`Context::sampled`], so that downstream services can respect it.
+pub trait Sample: fmt::Debug + Send + Sync {
+    /// Returns true if the span about to be started in `trace_id` should be recorded. `parent` is
+    /// the context of the causal span, if any.
+    fn sample(&self, parent: Option<&Context>, trace_id: TraceId) -> bool;
+}
+
+/// Records every trace.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AlwaysSample;
+
+impl Sample for AlwaysSample {
+    fn sample(&self, _: Option<&Context>, _: TraceId) -> bool {
+        true
+    }
+}
+
+/// Records no traces.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NeverSample;
+
+impl Sample for NeverSample {
+    fn sample(&self, _: Option<&Context>, _: TraceId) -> bool {
+        false
+    }
+}
+
+/// Records a fixed fraction of traces. The decision is a function of the trace ID, so every
+/// service using the same probability makes the same decision for a given trace.
+#[derive(Clone, Copy, Debug)]
+pub struct ProbabilitySample {
+    threshold: u64,
+}
+
+impl ProbabilitySample {
+    /// Returns a sampler recording `probability` of traces. `probability` is clamped to `[0, 1]`.
+    pub fn new(probability: f64) -> Self {
+        let probability = probability.max(0.).min(1.);
+        ProbabilitySample {
+            threshold: (probability * u64::max_value() as f64) as u64,
+        }
+    }
+}
+
+impl Sample for ProbabilitySample {
+    fn sample(&self, _: Option<&Context>, trace_id: TraceId) -> bool {
+        self.threshold == u64::max_value() || (trace_id.0 as u64) < self.threshold
+    }
+}
+
+/// Records at most a fixed number of traces per second.
+#[derive(Debug)]
+pub struct RateLimitedSample {
+    per_second: u32,
+    /// The start of the current one-second window and the number of traces recorded in it.
+    window: Mutex<(Instant, u32)>,
+}
+
+impl RateLimitedSample {
+    /// Returns a sampler recording at most `per_second` traces each second.
+    pub fn new(per_second: u32) -> Self {
+        RateLimitedSample {
+            per_second,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+}
+
+impl Sample for RateLimitedSample {
+    fn sample(&self, _: Option<&Context>, _: TraceId) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        if window.1 < self.per_second {
+            window.1 += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Respects the decision of the parent span, if there is one, and otherwise defers to the root
+/// sampler.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ParentBasedSample<S> {
+    root: S,
+}
+
+impl<S> ParentBasedSample<S> {
+    /// Returns a sampler deciding root spans with `root`.
+    pub fn new(root: S) -> Self {
+        ParentBasedSample { root }
+    }
+}
+
+impl<S: Sample> Sample for ParentBasedSample<S> {
+    fn sample(&self, parent: Option<&Context>, trace_id: TraceId) -> bool {
+        match parent {
+            Some(parent) => parent.sampled,
+            None => self.root.sample(None, trace_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probability() {
+        let rng = &mut rand::thread_rng();
+        let trace_id = TraceId::random(rng);
+        assert!(ProbabilitySample::new(1.).sample(None, trace_id));
+        assert!(!ProbabilitySample::new(0.).sample(None, trace_id));
+        let half = ProbabilitySample::new(0.5);
+        assert_eq!(half.sample(None, trace_id), half.sample(None, trace_id));
+        assert!(half.sample(None, TraceId(1)));
+        assert!(!half.sample(None, TraceId(u128::from(u64::max_value()))));
+    }
+
+    #[test]
+    fn rate_limited() {
+        let sampler = RateLimitedSample::new(2);
+        let trace_id = TraceId::default();
+        assert!(sampler.sample(None, trace_id));
+        assert!(sampler.sample(None, trace_id));
+        assert!(!sampler.sample(None, trace_id));
+    }
+
+    #[test]
+    fn parent_based() {
+        let sampler = ParentBasedSample::new(NeverSample);
+        let mut parent = Context::new_root();
+        parent.sampled = true;
+        assert!(sampler.sample(Some(&parent), parent.trace_id));
+        parent.sampled = false;
+        assert!(!sampler.sample(Some(&parent), parent.trace_id));
+        assert!(!sampler.sample(None, parent.trace_id));
+    }
+
+    #[test]
+    fn default_contexts_are_sampled() {
+        let sampler = ParentBasedSample::new(NeverSample);
+        let child = Context::default().new_child(&AlwaysSample);
+        assert!(Context::default().sampled);
+        assert!(sampler.sample(Some(&child), child.trace_id));
+    }
+}